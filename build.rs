@@ -1,4 +1,51 @@
-use std::{env, error::Error, fs, io::BufRead, path::Path, process::Command};
+use std::{
+    collections::HashMap, env, error::Error, fmt::Write as _, fs, io::BufRead, path::Path,
+    process::Command,
+};
+
+/// The userspace ABI header and the file that checks it against the libc headers are
+/// generated here, the Makefile installs them into the sysroot
+const ABI_OUTPUT_DIR: &str = "bin/abi";
+
+const SYSCALL_SOURCE: &str = "src/syscall.rs";
+
+/// Files with the ioctl numbers, flags and structs shared with userspace and the path of
+/// their module in the kernel
+const ABI_SOURCES: &[(&str, &str)] = &[
+    ("src/posix/mod.rs", "crate::posix"),
+    ("src/posix/termios.rs", "crate::posix::termios"),
+    ("src/posix/fb.rs", "crate::posix::fb"),
+    ("src/posix/input.rs", "crate::posix::input"),
+];
+
+/// Structs passed between the kernel and userspace, the libc header that has the libc
+/// version of the struct and its name there if libc has one
+const ABI_STRUCTS: &[(&str, Option<(&str, &str)>)] = &[
+    ("Timespec", Some(("time.h", "timespec"))),
+    ("Stat", Some(("sys/stat.h", "stat"))),
+    ("Termios", Some(("termios.h", "termios"))),
+    ("Winsize", Some(("sys/ioctl.h", "winsize"))),
+    ("FbBitfield", None),
+    ("FbVarScreeninfo", None),
+    ("FbFixScreeninfo", None),
+    ("InputEvent", None),
+];
+
+/// A struct of the kernel sources
+struct AbiStruct {
+    name: String,
+    module: String,
+    packed: bool,
+    /// Name and Rust type of every field
+    fields: Vec<(String, String)>,
+}
+
+/// The layout of a struct as rustc lays it out, the offsets are in the order of the fields
+struct AbiLayout {
+    size: usize,
+    align: usize,
+    offsets: Vec<usize>,
+}
 
 fn find_asm_files(files: &mut Vec<String>, path: String) {
     let entries = fs::read_dir(path).unwrap();
@@ -36,7 +83,8 @@ fn build_asm_files(src_files: &Vec<String>, obj_files: &mut Vec<String>) {
     }
 }
 
-fn parse_kernel_config() -> Vec<String> {
+/// Returns every option of the kernel config and whether it is enabled
+fn parse_kernel_config() -> Vec<(String, bool)> {
     const KERNEL_CONFIG_FILE_NAME: &str = "kernel.cfg";
     let config_file: Vec<Vec<String>> = fs::read(KERNEL_CONFIG_FILE_NAME)
         .expect("Failed to read kernel config file")
@@ -53,10 +101,11 @@ fn parse_kernel_config() -> Vec<String> {
 
         match l[1].as_str() {
             "yes" | "y" => {
-                options.push(l[0].clone());
+                options.push((l[0].clone(), true));
                 println!("CONFIG: {} enabled", l[0]);
             }
             "no" | "n" => {
+                options.push((l[0].clone(), false));
                 println!("CONFIG: {} disabled", l[0]);
             }
             _ => {
@@ -69,6 +118,374 @@ fn parse_kernel_config() -> Vec<String> {
     options
 }
 
+/// Parses an integer literal of the kernel sources, e.g. 0x10 or 0o644
+fn parse_int_literal(literal: &str) -> Option<u64> {
+    let literal = literal.replace('_', "");
+    if let Some(hex) = literal.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(octal) = literal.strip_prefix("0o") {
+        u64::from_str_radix(octal, 8).ok()
+    } else if let Some(binary) = literal.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2).ok()
+    } else {
+        literal.parse().ok()
+    }
+}
+
+/// Returns the `pub const` items of __source__ whose value is an integer literal
+fn parse_int_consts(source: &str) -> Vec<(String, u64)> {
+    source
+        .lines()
+        .filter_map(|line| {
+            let item = line.trim().strip_prefix("pub const ")?;
+            let (name, rest) = item.split_once(':')?;
+            let (_, value) = rest.split_once('=')?;
+            let value = parse_int_literal(value.trim().strip_suffix(';')?.trim())?;
+            Some((name.trim().to_string(), value))
+        })
+        .collect()
+}
+
+/// Returns the names of the entries of the syscall table __table__ in the order of their
+/// numbers, the name is the first string literal of an entry
+fn parse_syscall_table(source: &str, table: &str) -> Vec<String> {
+    let start = source
+        .find(&format!("static {}:", table))
+        .unwrap_or_else(|| panic!("{} not found in {}", table, SYSCALL_SOURCE));
+    let body = &source[start..];
+    let body = &body[body.find("&[").unwrap()..body.find("];").unwrap()];
+
+    body.split("::new(")
+        .skip(1)
+        .map(|entry| {
+            let name = entry.split('"').nth(1).expect("syscall without a name");
+            name.to_string()
+        })
+        .collect()
+}
+
+/// Parses the struct __name__ of __source__, None if it is not in the file
+fn parse_struct(source: &str, module: &str, name: &str) -> Option<AbiStruct> {
+    let lines: Vec<&str> = source.lines().collect();
+    let header = format!("pub struct {} {{", name);
+    let start = lines.iter().position(|line| line.trim() == header)?;
+
+    // the attributes are right above the struct
+    let packed = lines[..start]
+        .iter()
+        .rev()
+        .take_while(|line| line.trim().starts_with("#["))
+        .any(|line| line.contains("repr(") && line.contains("packed"));
+
+    let fields = lines[start + 1..]
+        .iter()
+        .map(|line| line.trim())
+        .take_while(|line| *line != "}")
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .map(|line| {
+            let field = line.strip_prefix("pub ").unwrap_or(line);
+            let (field_name, ty) = field.split_once(':').expect("invalid struct field");
+            let ty = ty.trim().trim_end_matches(',');
+            (field_name.trim().to_string(), ty.to_string())
+        })
+        .collect();
+
+    Some(AbiStruct {
+        name: name.to_string(),
+        module: module.to_string(),
+        packed,
+        fields,
+    })
+}
+
+/// Converts a Rust type name to snake case, e.g. FbVarScreeninfo to fb_var_screeninfo
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, ch) in name.chars().enumerate() {
+        if ch.is_ascii_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(ch.to_ascii_lowercase());
+    }
+    snake
+}
+
+/// Returns the size of an array type, e.g. [u8; NCCS]
+fn array_type<'a>(ty: &'a str, consts: &HashMap<String, u64>) -> Option<(&'a str, usize)> {
+    let inner = ty.strip_prefix('[')?.strip_suffix(']')?;
+    let (elem, len) = inner.split_once(';')?;
+    let len = len.trim();
+    let len = match parse_int_literal(len) {
+        Some(len) => len,
+        None => *consts
+            .get(len)
+            .unwrap_or_else(|| panic!("unknown array length {}", len)),
+    };
+    Some((elem.trim(), len as usize))
+}
+
+fn primitive_c_type(ty: &str) -> Option<(&'static str, usize)> {
+    match ty {
+        "u8" => Some(("uint8_t", 1)),
+        "i8" => Some(("int8_t", 1)),
+        "u16" => Some(("uint16_t", 2)),
+        "i16" => Some(("int16_t", 2)),
+        "u32" => Some(("uint32_t", 4)),
+        "i32" => Some(("int32_t", 4)),
+        "u64" | "usize" => Some(("uint64_t", 8)),
+        "i64" | "isize" => Some(("int64_t", 8)),
+        _ => None,
+    }
+}
+
+/// Returns the size and the alignment of __ty__
+fn type_layout(
+    ty: &str,
+    structs: &HashMap<String, AbiStruct>,
+    consts: &HashMap<String, u64>,
+) -> (usize, usize) {
+    if let Some((_, size)) = primitive_c_type(ty) {
+        return (size, size);
+    }
+
+    if let Some((elem, len)) = array_type(ty, consts) {
+        let (size, align) = type_layout(elem, structs, consts);
+        return (size * len, align);
+    }
+
+    let layout = struct_layout(
+        structs
+            .get(ty)
+            .unwrap_or_else(|| panic!("{} is not an ABI struct", ty)),
+        structs,
+        consts,
+    );
+    (layout.size, layout.align)
+}
+
+/// Lays out __abi_struct__ like rustc does with repr(C) and repr(C, packed)
+fn struct_layout(
+    abi_struct: &AbiStruct,
+    structs: &HashMap<String, AbiStruct>,
+    consts: &HashMap<String, u64>,
+) -> AbiLayout {
+    let mut size: usize = 0;
+    let mut struct_align = 1;
+    let mut offsets = Vec::new();
+    for (_, ty) in abi_struct.fields.iter() {
+        let (field_size, field_align) = type_layout(ty, structs, consts);
+        let align = if abi_struct.packed { 1 } else { field_align };
+        size = size.next_multiple_of(align);
+        offsets.push(size);
+        size += field_size;
+        struct_align = struct_align.max(align);
+    }
+
+    AbiLayout {
+        size: size.next_multiple_of(struct_align),
+        align: struct_align,
+        offsets,
+    }
+}
+
+/// Returns the C declaration of a field of type __ty__
+fn c_field(
+    name: &str,
+    ty: &str,
+    structs: &HashMap<String, AbiStruct>,
+    consts: &HashMap<String, u64>,
+) -> String {
+    if let Some((c_type, _)) = primitive_c_type(ty) {
+        return format!("{} {}", c_type, name);
+    }
+
+    if let Some((elem, len)) = array_type(ty, consts) {
+        return format!("{}[{}]", c_field(name, elem, structs, consts), len);
+    }
+
+    assert!(structs.contains_key(ty), "{} is not an ABI struct", ty);
+    format!("struct rook_{} {}", snake_case(ty), name)
+}
+
+/// Generates the ABI header for userspace, a C file that checks the libc headers against it
+/// and the assertions that check the layouts it was generated with against the kernel
+fn generate_abi() {
+    let syscall_source = fs::read_to_string(SYSCALL_SOURCE).expect("Failed to read syscall.rs");
+    println!("cargo:rerun-if-changed={}", SYSCALL_SOURCE);
+
+    let mut consts = Vec::new();
+    let mut structs = HashMap::new();
+    for (file, module) in ABI_SOURCES {
+        let source = fs::read_to_string(file).expect("Failed to read ABI source file");
+        println!("cargo:rerun-if-changed={}", file);
+
+        consts.extend(parse_int_consts(&source));
+        for (name, _) in ABI_STRUCTS {
+            if let Some(abi_struct) = parse_struct(&source, module, name) {
+                structs.insert(name.to_string(), abi_struct);
+            }
+        }
+    }
+    let const_values: HashMap<String, u64> = consts.iter().cloned().collect();
+
+    let abi_version = parse_int_consts(&syscall_source)
+        .into_iter()
+        .find(|(name, _)| name == "ROOK_ABI_VERSION")
+        .expect("ROOK_ABI_VERSION not found")
+        .1;
+
+    let mut header = String::new();
+    let mut check = String::new();
+    let mut asserts = String::new();
+    writeln!(
+        header,
+        "/* generated by build.rs from the kernel sources, do not edit */"
+    )
+    .unwrap();
+    writeln!(header, "#ifndef _ROOK_ABI_H\n#define _ROOK_ABI_H\n").unwrap();
+    writeln!(header, "#include <stddef.h>\n#include <stdint.h>\n").unwrap();
+    writeln!(header, "#define ROOK_ABI_VERSION {}\n", abi_version).unwrap();
+
+    writeln!(
+        check,
+        "/* generated by build.rs, it only compiles if libc matches the kernel */"
+    )
+    .unwrap();
+    writeln!(check, "#include \"rook_abi.h\"\n").unwrap();
+    writeln!(
+        asserts,
+        "// generated by build.rs, the layouts of rook_abi.h"
+    )
+    .unwrap();
+
+    for (i, name) in parse_syscall_table(&syscall_source, "SYSCALL_TABLE")
+        .iter()
+        .enumerate()
+    {
+        writeln!(header, "#define ROOK_SYS_{} {}", name, i).unwrap();
+    }
+    writeln!(header).unwrap();
+    for (i, name) in parse_syscall_table(&syscall_source, "ROOK_SYSCALL_TABLE")
+        .iter()
+        .enumerate()
+    {
+        writeln!(header, "#define ROOK_CALL_{} {}", name, i).unwrap();
+    }
+    writeln!(header).unwrap();
+
+    for (name, value) in consts.iter() {
+        writeln!(header, "#define ROOK_{} {:#x}", name, value).unwrap();
+    }
+    writeln!(header).unwrap();
+
+    for (name, libc_struct) in ABI_STRUCTS {
+        let abi_struct = structs
+            .get(*name)
+            .unwrap_or_else(|| panic!("ABI struct {} not found", name));
+        let layout = struct_layout(abi_struct, &structs, &const_values);
+        let c_name = format!("struct rook_{}", snake_case(name));
+        let rust_path = format!("{}::{}", abi_struct.module, abi_struct.name);
+
+        writeln!(header, "{} {{", c_name).unwrap();
+        for (field, ty) in abi_struct.fields.iter() {
+            writeln!(
+                header,
+                "    {};",
+                c_field(field, ty, &structs, &const_values)
+            )
+            .unwrap();
+        }
+        let attribute = if abi_struct.packed {
+            " __attribute__((packed))"
+        } else {
+            ""
+        };
+        writeln!(header, "}}{};", attribute).unwrap();
+
+        writeln!(
+            header,
+            "_Static_assert(sizeof({}) == {}, \"size of {}\");",
+            c_name, layout.size, c_name
+        )
+        .unwrap();
+        writeln!(
+            asserts,
+            "const _: () = assert!(core::mem::size_of::<{}>() == {});",
+            rust_path, layout.size
+        )
+        .unwrap();
+
+        for ((field, _), offset) in abi_struct.fields.iter().zip(layout.offsets.iter()) {
+            writeln!(
+                header,
+                "_Static_assert(offsetof({}, {}) == {}, \"offset of {}\");",
+                c_name, field, offset, field
+            )
+            .unwrap();
+            writeln!(
+                asserts,
+                "const _: () = assert!(core::mem::offset_of!({}, {}) == {});",
+                rust_path, field, offset
+            )
+            .unwrap();
+        }
+        writeln!(header).unwrap();
+
+        if let Some((libc_header, libc_name)) = libc_struct {
+            writeln!(check, "#include <{}>", libc_header).unwrap();
+            writeln!(
+                check,
+                "_Static_assert(sizeof(struct {}) == sizeof({}), \"size of struct {}\");",
+                libc_name, c_name, libc_name
+            )
+            .unwrap();
+            for (field, _) in abi_struct.fields.iter() {
+                writeln!(
+                    check,
+                    "_Static_assert(offsetof(struct {}, {}) == offsetof({}, {}), \"offset of {}\");",
+                    libc_name, field, c_name, field, field
+                )
+                .unwrap();
+            }
+            writeln!(check).unwrap();
+        }
+    }
+    writeln!(header, "#endif").unwrap();
+
+    // the constants libc defines too have to have the same value
+    writeln!(check, "#include <sys/ioctl.h>\n").unwrap();
+    for (name, _) in consts.iter() {
+        writeln!(
+            check,
+            "#ifdef {0}\n_Static_assert({0} == ROOK_{0}, \"value of {0}\");\n#endif",
+            name
+        )
+        .unwrap();
+    }
+
+    fs::create_dir_all(ABI_OUTPUT_DIR).expect("Failed to create the ABI output directory");
+    fs::write(format!("{}/rook_abi.h", ABI_OUTPUT_DIR), header).unwrap();
+    fs::write(format!("{}/rook_abi_check.c", ABI_OUTPUT_DIR), check).unwrap();
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("abi_layout.rs"), asserts).unwrap();
+}
+
+fn git_hash() -> String {
+    let output = Command::new("git")
+        .arg("rev-parse")
+        .arg("--short")
+        .arg("HEAD")
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            String::from(String::from_utf8_lossy(&output.stdout).trim())
+        }
+        _ => String::from("unknown"),
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut asm_source_files: Vec<String> = Vec::new();
     let mut asm_obj_files: Vec<String> = Vec::new();
@@ -76,11 +493,25 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     find_asm_files(&mut asm_source_files, String::from("src"));
     build_asm_files(&asm_source_files, &mut asm_obj_files);
 
+    generate_abi();
+
     let kernel_config = parse_kernel_config();
-    for flag in kernel_config {
+    for (flag, _) in kernel_config.iter().filter(|(_, enabled)| *enabled) {
         println!("cargo:rustc-cfg={}", flag);
     }
 
+    // the whole config is also passed to the kernel so it can be inspected at runtime
+    let config_str: Vec<String> = kernel_config
+        .iter()
+        .map(|(flag, enabled)| format!("{}={}", flag, if *enabled { "yes" } else { "no" }))
+        .collect();
+    println!(
+        "cargo:rustc-env=ROOK_KERNEL_CONFIG={}",
+        config_str.join(";")
+    );
+    println!("cargo:rustc-env=ROOK_GIT_HASH={}", git_hash());
+    println!("cargo:rustc-env=ROOK_TARGET={}", env::var("TARGET")?);
+
     let kernel_name = env::var("CARGO_PKG_NAME")?;
 
     for asm_file in asm_source_files {
@@ -93,6 +524,7 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     println!("cargo:rerun-if-changed=conf/linker.ld");
     println!("cargo:rerun-if-changed=kernel.cfg");
+    println!("cargo:rerun-if-changed=.git/HEAD");
 
     println!("cargo:rerun-if-env-changed=CARGO_PKG_NAME");
 
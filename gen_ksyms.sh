@@ -0,0 +1,29 @@
+#!/bin/bash
+# Writes the symbol map of the kernel into its .ksyms section, the panic handler resolves the
+# addresses of the stack trace with it. The section keeps its size so nothing else in the
+# image moves, the map has to fit in KSYMS_SIZE (src/ksyms.rs).
+set -e
+
+KERNEL=$1
+MAP=$2
+
+SIZE=$(objdump -h "$KERNEL" | awk '$2 == ".ksyms" { print $3 }')
+if [ -z "$SIZE" ]; then
+    echo "$KERNEL has no .ksyms section"
+    exit 1
+fi
+SIZE=$((16#$SIZE))
+
+# the functions sorted by address, without the hashes of the mangled names
+nm -n -C --defined-only "$KERNEL" \
+    | sed -n 's/^\([0-9a-f]*\) [tTwW] \(.*\)$/\1 \2/p' \
+    | sed 's/::h[0-9a-f]\{16\}$//' > "$MAP"
+
+# at least one zero has to follow the map
+if [ $(stat -c %s "$MAP") -ge $SIZE ]; then
+    echo "the symbol map does not fit in .ksyms, increase KSYMS_SIZE"
+    exit 1
+fi
+
+truncate -s $SIZE "$MAP"
+objcopy --update-section .ksyms="$MAP" "$KERNEL"
@@ -16,10 +16,12 @@ cat fdisk_inst.txt | fdisk -u $TESTIMGPATH
 mkdir -p /mnt/ark_disk
 
 losetup -o1048576 /dev/loop0 $TESTIMGPATH
-mkfs.fat -F32 /dev/loop0
+mkfs.fat -F32 -n ROOKROOT /dev/loop0
 mount -t vfat /dev/loop0 /mnt/rook_disk
 
 cp -RTf $SYSROOT /mnt/rook_disk
+# devfs and procfs are mounted on these, mount points have to exist
+mkdir -p /mnt/rook_disk/dev /mnt/rook_disk/proc /mnt/rook_disk/tmp
 mkdir -p /mnt/rook_disk/boot/limine
 
 #cp limine/BOOTX64.EFI /mnt/rook_disk/boot
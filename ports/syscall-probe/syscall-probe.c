@@ -0,0 +1,107 @@
+/*
+ * Probes the syscall entry and return paths of the kernel with arguments libc would never pass.
+ * Every check prints PASS or FAIL and the exit status is the number of failed checks.
+ *
+ * Entering the kernel from compatibility mode can not be probed because the GDT has no 32-bit
+ * user code segment, so userspace has no way to switch to it.
+ */
+#include <errno.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <rook_abi.h>
+
+#define NON_CANONICAL_ADDR 0x8000000000000000ul
+#define KERNEL_ADDR 0xffff800000000000ul
+#define RFLAGS_DF (1ul << 10)
+
+static int failures;
+
+static long raw_syscall3(unsigned long no, unsigned long a0, unsigned long a1, unsigned long a2) {
+    long ret;
+    asm volatile("int $0x80"
+                 : "=a"(ret)
+                 : "a"(no), "D"(a0), "S"(a1), "d"(a2)
+                 : "memory");
+    return ret;
+}
+
+static void check(const char *name, int ok) {
+    printf("%s %s\n", ok ? "PASS" : "FAIL", name);
+    if (!ok)
+        failures++;
+}
+
+static void check_errno(const char *name, long ret, long err) {
+    if (ret != -err)
+        printf("  %s returned %ld instead of %ld\n", name, ret, -err);
+    check(name, ret == -err);
+}
+
+static void probe_pointer_args(void) {
+    check_errno("write from a non-canonical buffer",
+                raw_syscall3(ROOK_SYS_write, 1, NON_CANONICAL_ADDR, 16), EFAULT);
+    check_errno("write from a kernel buffer",
+                raw_syscall3(ROOK_SYS_write, 1, KERNEL_ADDR, 16), EFAULT);
+    check_errno("read into a non-canonical buffer",
+                raw_syscall3(ROOK_SYS_read, 0, NON_CANONICAL_ADDR, 16), EFAULT);
+    check_errno("read into a buffer that wraps around",
+                raw_syscall3(ROOK_SYS_read, 0, 0x1000, ~0ul), EFAULT);
+}
+
+static void probe_syscall_numbers(void) {
+    check_errno("syscall number past the table", raw_syscall3(0xffff, 0, 0, 0), ENOSYS);
+    check_errno("syscall number with the upper half set",
+                raw_syscall3(0x100000000ul | ROOK_SYS_getpid, 0, 0, 0), ENOSYS);
+}
+
+/* every register except rax has to come back as it was, nothing from the kernel may leak */
+static void probe_returned_registers(void) {
+    register unsigned long rbx asm("rbx") = 0x1111111111111111ul;
+    register unsigned long rcx asm("rcx") = 0x2222222222222222ul;
+    register unsigned long rdx asm("rdx") = 0x3333333333333333ul;
+    register unsigned long rsi asm("rsi") = 0x4444444444444444ul;
+    register unsigned long rdi asm("rdi") = 0x5555555555555555ul;
+    register unsigned long r8 asm("r8") = 0x6666666666666666ul;
+    register unsigned long r9 asm("r9") = 0x7777777777777777ul;
+    register unsigned long r10 asm("r10") = 0x8888888888888888ul;
+    register unsigned long r11 asm("r11") = 0x9999999999999999ul;
+    unsigned long rax = ROOK_SYS_getpid;
+    unsigned long rflags;
+
+    /*
+     * the direction flag is set to check that the kernel clears it for itself and restores it,
+     * pushfq must not overwrite the red zone
+     */
+    asm volatile("std\n\t"
+                 "int $0x80\n\t"
+                 "leaq -128(%%rsp), %%rsp\n\t"
+                 "pushfq\n\t"
+                 "popq %[rflags]\n\t"
+                 "leaq 128(%%rsp), %%rsp\n\t"
+                 "cld"
+                 : "+a"(rax), "+r"(rbx), "+r"(rcx), "+r"(rdx), "+r"(rsi), "+r"(rdi), "+r"(r8),
+                   "+r"(r9), "+r"(r10), "+r"(r11), [rflags] "=r"(rflags)
+                 :
+                 : "memory");
+
+    check("rbx is preserved", rbx == 0x1111111111111111ul);
+    check("rcx is preserved", rcx == 0x2222222222222222ul);
+    check("rdx is preserved", rdx == 0x3333333333333333ul);
+    check("rsi is preserved", rsi == 0x4444444444444444ul);
+    check("rdi is preserved", rdi == 0x5555555555555555ul);
+    check("r8 is preserved", r8 == 0x6666666666666666ul);
+    check("r9 is preserved", r9 == 0x7777777777777777ul);
+    check("r10 is preserved", r10 == 0x8888888888888888ul);
+    check("r11 is preserved", r11 == 0x9999999999999999ul);
+    check("the direction flag is restored", (rflags & RFLAGS_DF) != 0);
+    check("getpid still works", (long)rax > 0);
+}
+
+int main(void) {
+    probe_pointer_args();
+    probe_syscall_numbers();
+    probe_returned_registers();
+
+    printf("%d failed\n", failures);
+    return failures;
+}
@@ -0,0 +1,156 @@
+//! The Multiple APIC Description Table, it lists the interrupt controllers of the system
+
+use alloc::vec::Vec;
+
+use crate::mm::PhysAddr;
+
+use super::{find_table, read_phys, SdtHeader, SDT_HEADER_SIZE};
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// The system also has 8259 PICs which have to be masked when the IO-APIC is used
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub phys: PhysAddr,
+    /// The first global system interrupt the IO-APIC handles
+    pub gsi_base: u32,
+}
+
+/// Polarity and trigger mode of an interrupt, the values are the ones the MPS
+/// INTI flags use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterruptFlags(pub u16);
+
+impl InterruptFlags {
+    /// ISA interrupts are active high and edge triggered unless they are overridden
+    pub const ISA_DEFAULT: InterruptFlags = InterruptFlags(0);
+
+    pub fn active_low(&self) -> bool {
+        self.0 & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.0 >> 2) & 0b11 == 0b11
+    }
+}
+
+/// An ISA IRQ that is connected to a different global system interrupt than its number
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: InterruptFlags,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// ACPI processor UID, 0xFF means every processor
+    pub processor_id: u8,
+    pub flags: InterruptFlags,
+    /// The LINT pin the NMI is connected to
+    pub lint: u8,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_phys: PhysAddr,
+    /// Local APIC IDs of the processors that are enabled or can be brought online
+    pub local_apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptSourceOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+    pub has_8259: bool,
+}
+
+impl Madt {
+    /// Returns the global system interrupt and the flags of the ISA IRQ __irq__
+    pub fn isa_irq(&self, irq: u8) -> (u32, InterruptFlags) {
+        match self.overrides.iter().find(|ovr| ovr.irq == irq) {
+            Some(ovr) => (ovr.gsi, ovr.flags),
+            None => (irq as u32, InterruptFlags::ISA_DEFAULT),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct MadtHeader {
+    sdt: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+fn read_at<T: Copy>(entry: PhysAddr, offset: u64) -> T {
+    read_phys(entry + PhysAddr::new(offset))
+}
+
+/// Parses the MADT, returns None if the firmware does not provide one
+pub fn parse() -> Option<Madt> {
+    let table = find_table(MADT_SIGNATURE)?;
+    let header: MadtHeader = read_phys(table);
+
+    let mut madt = Madt {
+        local_apic_phys: PhysAddr::new(header.local_apic_address as u64),
+        local_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+        nmis: Vec::new(),
+        has_8259: header.flags & MADT_PCAT_COMPAT != 0,
+    };
+
+    let end = header.sdt.length as u64;
+    let mut offset = (SDT_HEADER_SIZE + 8) as u64;
+    while offset + 2 <= end {
+        let entry = table + PhysAddr::new(offset);
+        let entry_type: u8 = read_at(entry, 0);
+        let len: u8 = read_at(entry, 1);
+        if len < 2 || offset + len as u64 > end {
+            warn!("ACPI: malformed MADT entry at offset {}", offset);
+            break;
+        }
+
+        match entry_type {
+            ENTRY_LOCAL_APIC => {
+                let flags: u32 = read_at(entry, 4);
+                if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    madt.local_apic_ids.push(read_at(entry, 3));
+                }
+            }
+            ENTRY_IO_APIC => madt.io_apics.push(IoApicEntry {
+                id: read_at(entry, 2),
+                phys: PhysAddr::new(read_at::<u32>(entry, 4) as u64),
+                gsi_base: read_at(entry, 8),
+            }),
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE => madt.overrides.push(InterruptSourceOverride {
+                irq: read_at(entry, 3),
+                gsi: read_at(entry, 4),
+                flags: InterruptFlags(read_at(entry, 8)),
+            }),
+            ENTRY_LOCAL_APIC_NMI => madt.nmis.push(LocalApicNmi {
+                processor_id: read_at(entry, 2),
+                flags: InterruptFlags(read_at(entry, 3)),
+                lint: read_at(entry, 5),
+            }),
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_phys = PhysAddr::new(read_at(entry, 4));
+            }
+            _ => {}
+        }
+
+        offset += len as u64;
+    }
+
+    Some(madt)
+}
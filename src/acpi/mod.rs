@@ -0,0 +1,115 @@
+//! Finding the ACPI tables the firmware provides, only static tables are parsed and AML is
+//! not interpreted
+
+pub mod madt;
+
+use spin::Once;
+
+use crate::mm::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+pub const SDT_HEADER_SIZE: usize = 36;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the rest is only present from revision 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// The header every system description table starts with
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// The root table, entries of the XSDT are 64 bits wide, the ones of the RSDT are 32 bits wide
+#[derive(Clone, Copy)]
+struct RootTable {
+    phys: PhysAddr,
+    entry_size: usize,
+}
+
+static ROOT_TABLE: Once<Option<RootTable>> = Once::new();
+
+/// Reads a value from physical memory, ACPI tables do not align their fields
+pub fn read_phys<T: Copy>(phys: PhysAddr) -> T {
+    unsafe { core::ptr::read_unaligned(phys.virt_addr().get() as *const T) }
+}
+
+/// Returns whether the bytes of the structure add up to zero
+fn checksum_valid(phys: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys.virt_addr().get() as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Finds the root table from the RSDP at __rsdp_phys__, the tables themselves are parsed later
+/// when they are needed
+pub fn init(rsdp_phys: PhysAddr) {
+    ROOT_TABLE.call_once(|| {
+        let rsdp: Rsdp = read_phys(rsdp_phys);
+        if &rsdp.signature != RSDP_SIGNATURE || !checksum_valid(rsdp_phys, 20) {
+            warn!("ACPI: invalid RSDP");
+            return None;
+        }
+
+        let revision = rsdp.revision;
+        let root = if revision >= 2 && rsdp.xsdt_address != 0 {
+            RootTable {
+                phys: PhysAddr::new(rsdp.xsdt_address),
+                entry_size: 8,
+            }
+        } else {
+            RootTable {
+                phys: PhysAddr::new(rsdp.rsdt_address as u64),
+                entry_size: 4,
+            }
+        };
+
+        let header: SdtHeader = read_phys(root.phys);
+        if !checksum_valid(root.phys, header.length as usize) {
+            warn!("ACPI: invalid root table");
+            return None;
+        }
+
+        log!("ACPI: revision {} root table at {}", revision, root.phys);
+        Some(root)
+    });
+}
+
+/// Returns the physical address of the first table with the signature __signature__
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let root = (*ROOT_TABLE.get()?)?;
+    let header: SdtHeader = read_phys(root.phys);
+    let entries = (header.length as usize).saturating_sub(SDT_HEADER_SIZE) / root.entry_size;
+
+    (0..entries)
+        .map(|idx| {
+            let entry = root.phys + PhysAddr::new((SDT_HEADER_SIZE + idx * root.entry_size) as u64);
+            match root.entry_size {
+                8 => PhysAddr::new(read_phys::<u64>(entry)),
+                _ => PhysAddr::new(read_phys::<u32>(entry) as u64),
+            }
+        })
+        .find(|&table| {
+            let header: SdtHeader = read_phys(table);
+            &header.signature == signature && checksum_valid(table, header.length as usize)
+        })
+}
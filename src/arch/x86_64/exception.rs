@@ -1,9 +1,21 @@
 use crate::{
-    arch::x86_64::{get_cr2, get_current_pml4, paging::PageFlags},
+    arch::x86_64::{
+        clac, disable_interrupts, enable_interrupts, get_cr2, get_current_pml4, paging::PageFlags,
+        smp, usercopy::USERSPACE_END, Rflags,
+    },
+    kgdb::{self, Trap},
+    ksyms::Symbolized,
     mm::{virt::PAGE_SIZE_4KIB, VirtAddr},
+    posix::SIGSEGV,
+    scheduler::{
+        proc::{self, get_process, StackGrowth},
+        thread::ThreadInner,
+        SCHEDULER,
+    },
+    syscalls,
 };
 
-use super::registers::RegisterState;
+use super::registers::{InterruptRegisters, RegisterState};
 
 extern "C" {
     pub fn __excp_div_by_zero();
@@ -38,12 +50,37 @@ bitflags::bitflags! {
         const WRITE = 1 << 1;
         const USER = 1 << 2;
         const RESERVED_WRITE = 1 << 3;
-        const INSTRUCTION_FETCH = 1 << 5;
-        const PROTECTION_KEY = 1 << 6;
-        const SHADOW_STACK = 1 << 7;
+        const INSTRUCTION_FETCH = 1 << 4;
+        const PROTECTION_KEY = 1 << 5;
+        const SHADOW_STACK = 1 << 6;
     }
 }
 
+#[repr(C)]
+struct ExceptionTableEntry {
+    fault_addr: u64,
+    fixup_addr: u64,
+}
+
+extern "C" {
+    static __ex_table: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+}
+
+/// Returns the fixup address of the instruction at `rip` if it is allowed to fault
+fn search_exception_table(rip: u64) -> Option<u64> {
+    let table = unsafe {
+        let start = &__ex_table as *const ExceptionTableEntry;
+        let end = &__ex_table_end as *const ExceptionTableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+
+    table
+        .iter()
+        .find(|entry| entry.fault_addr == rip)
+        .map(|entry| entry.fixup_addr)
+}
+
 #[no_mangle]
 pub static mut EXCEPTION_REG_STATE: RegisterState = RegisterState::zero();
 
@@ -53,18 +90,31 @@ pub extern "C" fn excp_div_by_zero() -> ! {
 }
 
 #[no_mangle]
-pub extern "C" fn excp_debug() -> ! {
-    panic!("excp_debug");
+pub extern "C" fn excp_debug(regs: &mut InterruptRegisters) {
+    // the debugger stub single-steps with the trap flag
+    if !kgdb::handle_exception(regs, Trap::Debug) {
+        panic!("excp_debug");
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn excp_non_maskable_interrutpt() -> ! {
-    panic!("excp_non_maskable_interrutpt");
+pub extern "C" fn excp_non_maskable_interrutpt(regs: &mut InterruptRegisters) {
+    // another CPU panicked and stops the others
+    if smp::is_halting() {
+        crate::hcf();
+    }
+
+    // the debugger stub stops the other CPUs while it runs
+    if !kgdb::park_cpu(regs) {
+        panic!("excp_non_maskable_interrutpt");
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn excp_breakpoint() -> ! {
-    panic!("excp_breakpoint");
+pub extern "C" fn excp_breakpoint(regs: &mut InterruptRegisters) {
+    if !kgdb::handle_exception(regs, Trap::Breakpoint) {
+        panic!("excp_breakpoint");
+    }
 }
 
 #[no_mangle]
@@ -119,8 +169,9 @@ pub extern "C" fn excp_general_protection_fault(error_code: u64) -> ! {
     panic!("GENERAL PROTECTION FAULT");
 }
 
+/// Returns 0 if the fault was resolved, otherwise the address execution should continue at
 #[no_mangle]
-pub extern "C" fn excp_page_fault(error_code: u64) {
+pub extern "C" fn excp_page_fault(error_code: u64, rip: u64, rflags: u64) -> u64 {
     let pml4 = get_current_pml4();
 
     let page_fault_flags = PageFaultFlags::from_bits(error_code as u32).unwrap();
@@ -130,11 +181,23 @@ pub extern "C" fn excp_page_fault(error_code: u64) {
     }
 
     let addr = VirtAddr::new(get_cr2());
-
+    let user = page_fault_flags.contains(PageFaultFlags::USER);
+    // whether the faulting code ran with interrupts enabled
+    let interrupts = Rflags::from_bits_truncate(rflags).contains(Rflags::INTERRUPT);
     let mut page_flags = match pml4.get_page_entry_from_virt(addr) {
         Some((_, page_flags)) => page_flags,
         None => {
+            if user {
+                handle_user_page_fault(addr, page_fault_flags);
+                return 0;
+            }
+
+            if let Some(fixup_addr) = usercopy_fixup(addr, rip, interrupts) {
+                return fixup_addr;
+            }
+
             error!("{}", unsafe { EXCEPTION_REG_STATE });
+            error!("faulting instruction: {}", Symbolized(rip as usize));
             panic!("PAGE FAULT virt: {} flags: {:?}", addr, page_fault_flags)
         }
     };
@@ -146,7 +209,25 @@ pub extern "C" fn excp_page_fault(error_code: u64) {
         page_flags.insert(PageFlags::PRESENT);
 
         pml4.map_range(start_virt, end_virt, page_flags);
-        return;
+        return 0;
+    }
+
+    if page_fault_flags.contains(PageFaultFlags::WRITE)
+        && page_flags.contains(PageFlags::COPY_ON_WRITE)
+    {
+        let page_virt = addr - VirtAddr::new(addr.get() % PAGE_SIZE_4KIB);
+        pml4.break_copy_on_write(page_virt);
+        return 0;
+    }
+
+    if user {
+        handle_user_page_fault(addr, page_fault_flags);
+        return 0;
+    }
+
+    // the fault happened while copying from or to userspace
+    if let Some(fixup_addr) = usercopy_fixup(addr, rip, interrupts) {
+        return fixup_addr;
     }
 
     let page_present = page_fault_flags.contains(PageFaultFlags::PRESENT);
@@ -154,21 +235,109 @@ pub extern "C" fn excp_page_fault(error_code: u64) {
 
     let write_read_only_page = page_fault_flags.contains(PageFaultFlags::WRITE)
         && !page_flags.contains(PageFlags::READ_WRITE);
+    let execute_no_execute_page = page_fault_flags.contains(PageFaultFlags::INSTRUCTION_FETCH)
+        && page_flags.contains(PageFlags::NO_EXECUTE);
+    // SMEP and SMAP faults happen in the kernel on pages that belong to userspace
+    let kernel_user_page =
+        !page_fault_flags.contains(PageFaultFlags::USER) && page_flags.contains(PageFlags::USER);
 
     error!("ERROR FLAGS: {:?}", page_fault_flags);
     error!("PAGE FLAGS: {:?}", page_flags);
     error!("{}", unsafe { EXCEPTION_REG_STATE });
+    error!("faulting instruction: {}", Symbolized(rip as usize));
 
     if !page_present {
         error!("tried to access a non present page");
     } else if write_read_only_page {
         error!("tried to write to a read-only page");
+    } else if execute_no_execute_page {
+        error!("tried to execute a non-executable page");
+    } else if kernel_user_page {
+        error!("the kernel tried to access a user page outside of usercopy");
     } else {
         unreachable!()
     }
 
     panic!("PAGE FAULT");
-    // TODO: SIGSEGV
+}
+
+/// Handles a page fault of userspace that the page tables do not resolve, the stack is grown
+/// if the fault is in the guard gap under it, otherwise the process is killed with SIGSEGV.
+/// The thread is marked as being in the kernel while it waits for the locks so it can be
+/// preempted.
+fn handle_user_page_fault(addr: VirtAddr, page_fault_flags: PageFaultFlags) {
+    // userspace can set the AC flag, the kernel must not run with it
+    clac();
+
+    let pid = {
+        let thread_lock = SCHEDULER.get_current_thread().expect("No threads running");
+        let mut thread = thread_lock.lock();
+        match &mut thread.inner {
+            ThreadInner::User(data) => {
+                data.in_kernelspace = true;
+                data.pid
+            }
+            ThreadInner::Kernel(_) => unreachable!(),
+        }
+    };
+    enable_interrupts();
+
+    let proc = get_process(pid).unwrap();
+    let growth = proc.lock().grow_stack(addr.get() as usize);
+    match growth {
+        StackGrowth::Grown => {}
+        StackGrowth::LimitExceeded => {
+            warn!("PID {}: stack overflow at {}, killing it", pid, addr);
+            syscalls::proc::exit::kill(proc, SIGSEGV);
+        }
+        StackGrowth::NotStack => {
+            warn!(
+                "PID {}: segmentation fault at {} flags: {:?}, killing it",
+                pid, addr, page_fault_flags
+            );
+            syscalls::proc::exit::kill(proc, SIGSEGV);
+        }
+    }
+    drop(proc);
+
+    disable_interrupts();
+    let thread_lock = SCHEDULER.get_current_thread().expect("No threads running");
+    let mut thread = thread_lock.lock();
+    if let ThreadInner::User(data) = &mut thread.inner {
+        data.in_kernelspace = false;
+    }
+}
+
+/// Returns the address a usercopy that faulted at __rip__ continues at, 0 if the stack of the
+/// process was grown because the copy reached into the guard gap under it and the access can
+/// be retried. Returns None if the faulting instruction is not a usercopy.
+///
+/// Syscalls copy user memory with interrupts enabled and without the lock of the process held,
+/// the lock is waited for with interrupts enabled so a thread holding it can run. Copies made
+/// with interrupts disabled, like the loader of executables that holds the lock, never grow
+/// the stack, the memory they access is always mapped.
+fn usercopy_fixup(addr: VirtAddr, rip: u64, interrupts: bool) -> Option<u64> {
+    let fixup_addr = search_exception_table(rip)?;
+    if addr.get() >= USERSPACE_END || !interrupts {
+        return Some(fixup_addr);
+    }
+
+    let proc = match proc::current_pid().and_then(get_process) {
+        Some(proc) => proc,
+        None => return Some(fixup_addr),
+    };
+
+    // the copy runs with the AC flag set, iretq restores it
+    clac();
+    enable_interrupts();
+    let growth = proc.lock().grow_stack(addr.get() as usize);
+    drop(proc);
+    disable_interrupts();
+
+    match growth {
+        StackGrowth::Grown => Some(0),
+        _ => Some(fixup_addr),
+    }
 }
 
 #[no_mangle]
@@ -29,25 +29,6 @@ bits 64
     mov [EXCEPTION_REG_STATE + 18 * 8], rax
 %endmacro
 
-%macro restore_gprs 0
-    mov rax, [EXCEPTION_REG_STATE +  0 * 8]
-    mov rbx, [EXCEPTION_REG_STATE +  1 * 8]
-    mov rcx, [EXCEPTION_REG_STATE +  2 * 8]
-    mov rdx, [EXCEPTION_REG_STATE +  3 * 8]
-    mov rsi, [EXCEPTION_REG_STATE +  4 * 8]
-    mov rdi, [EXCEPTION_REG_STATE +  5 * 8]
-    mov r8,  [EXCEPTION_REG_STATE +  6 * 8]
-    mov r9,  [EXCEPTION_REG_STATE +  7 * 8]
-    mov r10, [EXCEPTION_REG_STATE +  8 * 8]
-    mov r11, [EXCEPTION_REG_STATE +  9 * 8]
-    mov r12, [EXCEPTION_REG_STATE + 10 * 8]
-    mov r13, [EXCEPTION_REG_STATE + 11 * 8]
-    mov r14, [EXCEPTION_REG_STATE + 12 * 8]
-    mov r15, [EXCEPTION_REG_STATE + 13 * 8]
-    mov rbp, [EXCEPTION_REG_STATE + 14 * 8]
-%endmacro
-
-
 %macro save_iret_data 1
     push rax
 
@@ -114,22 +95,129 @@ global __excp_ %+ %1:function (%%end - __excp_ %+ %1)
 __excp_ %+ %1:
     cli
 
-    save_iret_data 1
+    ; the registers are restored from the stack because EXCEPTION_REG_STATE is shared by
+    ; every CPU and the handler can be preempted
+    push rbp
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    ; save_gprs clobbers rax but it is already on the stack
+    save_iret_data 16
     save_gprs
 
     ; error code
+    mov rdi, [rsp + 15 * 8]
+    ; rip
+    mov rsi, [rsp + 16 * 8]
+    ; rflags
+    mov rdx, [rsp + 18 * 8]
+
+    ; rbx is preserved by the handler
+    mov rbx, rsp
+    and rsp, ~0xF
+    call excp_ %+ %1
+    mov rsp, rbx
+
+    ; if the handler returned a fixup address continue execution there
+    test rax, rax
+    jz %%restore
+    mov [rsp + 16 * 8], rax
+%%restore:
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
     pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rbp
+
+    ; error code
+    add rsp, 8
+
+    iretq
+%%end:
+%endmacro
+
+; the handler gets the registers of the interrupted code as InterruptRegisters and can change
+; them before execution continues, e.g. the debugger stub
+%macro exception_handler_trap 1
+extern excp_ %+ %1
+global __excp_ %+ %1:function (%%end - __excp_ %+ %1)
+__excp_ %+ %1:
+    cli
+
+    ; the general registers are followed by the interrupt frame
+    push rbp
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    save_iret_data 15
+    save_gprs
+
+    mov rdi, rsp
+
+    ; rbx is preserved by the handler
+    mov rbx, rsp
+    and rsp, ~0xF
     call excp_ %+ %1
-    restore_gprs
+    mov rsp, rbx
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rbp
 
     iretq
 %%end:
 %endmacro
 
 exception_handler div_by_zero
-exception_handler debug
-exception_handler non_maskable_interrutpt
-exception_handler breakpoint
+exception_handler_trap debug
+exception_handler_trap non_maskable_interrutpt
+exception_handler_trap breakpoint
 exception_handler overflow
 exception_handler bound_range_exceeded
 exception_handler invalid_opcode
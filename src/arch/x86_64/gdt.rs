@@ -1,3 +1,5 @@
+use crate::limits::CPU_MAX;
+
 use super::tss::{TaskStateSegment, TSS};
 
 // Only the necessary values are defined
@@ -74,7 +76,10 @@ pub const GDT_USER_DATA: u64 = 4 * 0x8;
 pub const GDT_TSS_LOW: u64 = 5 * 0x8;
 pub const GDT_TSS_HIGH: u64 = 6 * 0x8;
 
-static mut GDT: [GDTEntry; 7] = [
+const GDT_ENTRIES: usize = 7;
+
+// every CPU has its own GDT because the TSS descriptor points to the TSS of the CPU
+const GDT_TEMPLATE: [GDTEntry; GDT_ENTRIES] = [
     GDTEntry::null(),
     GDTEntry::new(0x0, 0xffffffff, GDT_KERNEL_CODE_FLAGS),
     GDTEntry::new(0x0, 0xffffffff, GDT_KERNEL_DATA_FLAGS),
@@ -84,6 +89,8 @@ static mut GDT: [GDTEntry; 7] = [
     GDTEntry::null(), // TSS high
 ];
 
+static mut GDT: [[GDTEntry; GDT_ENTRIES]; CPU_MAX] = [GDT_TEMPLATE; CPU_MAX];
+
 pub const fn segment_selector(segment_idx: u64, priv_level: u64) -> u64 {
     assert!(segment_idx % 8 == 0);
     assert!(priv_level < 4);
@@ -96,28 +103,36 @@ pub struct GDTDescriptor {
     addr: u64,
 }
 
-#[no_mangle]
-static mut GDT_DESCRIPTOR: GDTDescriptor = GDTDescriptor { limit: 0, addr: 0 };
+const GDT_DESCRIPTOR_NULL: GDTDescriptor = GDTDescriptor { limit: 0, addr: 0 };
+
+static mut GDT_DESCRIPTOR: [GDTDescriptor; CPU_MAX] = [GDT_DESCRIPTOR_NULL; CPU_MAX];
 
 extern "C" {
-    fn load_gdt();
+    fn load_gdt(descriptor: *const GDTDescriptor);
 }
 
+/// Loads the GDT and the TSS of the bootstrap processor
 pub fn init() {
+    init_cpu(0);
+}
+
+/// Loads the GDT and the TSS of __cpu__, it has to run on that CPU
+pub fn init_cpu(cpu: usize) {
     unsafe {
-        let tss_ptr = &TSS as *const _ as u64;
-        let gdt_ptr = &GDT as *const _ as u64;
+        let tss_ptr = &TSS[cpu] as *const _ as u64;
+        let gdt = &mut GDT[cpu];
 
-        GDT[5] = GDTEntry::new(
+        gdt[5] = GDTEntry::new(
             tss_ptr as u32,
             core::mem::size_of::<TaskStateSegment>() as u32 - 1,
             GDT_TSS_FLAGS,
         );
-        GDT[6] = GDTEntry::new((tss_ptr >> 48) as u32, ((tss_ptr >> 32) & 0xFFFF) as u32, 0);
+        gdt[6] = GDTEntry::new((tss_ptr >> 48) as u32, ((tss_ptr >> 32) & 0xFFFF) as u32, 0);
 
-        GDT_DESCRIPTOR.limit = (GDT.len() * core::mem::size_of::<GDTEntry>()) as u16 - 1;
-        GDT_DESCRIPTOR.addr = gdt_ptr;
+        let descriptor = &mut GDT_DESCRIPTOR[cpu];
+        descriptor.limit = (gdt.len() * core::mem::size_of::<GDTEntry>()) as u16 - 1;
+        descriptor.addr = gdt.as_ptr() as u64;
 
-        load_gdt();
+        load_gdt(descriptor);
     }
 }
@@ -0,0 +1,91 @@
+//! The High Precision Event Timer, only its main counter is used as the clock source of the
+//! monotonic clock, timer interrupts still come from the PIT and the local APIC timers
+
+use spin::Once;
+
+use crate::{
+    acpi::{self, SDT_HEADER_SIZE},
+    mm::PhysAddr,
+    mmio::{Mmio, VolatileCell},
+};
+
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+
+/// Offset of the address of the registers in the HPET table, it is the address field of
+/// the generic address structure that follows the event timer block ID
+const HPET_BASE_ADDRESS_OFFSET: usize = SDT_HEADER_SIZE + 8;
+
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+const CONF_ENABLE: u64 = 1 << 0;
+
+/// The specification does not allow the counter to tick slower than this
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_NS: u128 = 1_000_000;
+
+#[repr(C)]
+struct HpetRegisters {
+    capabilities: VolatileCell<u64>,
+    _reserved0: u64,
+    configuration: VolatileCell<u64>,
+    _reserved1: u64,
+    interrupt_status: VolatileCell<u64>,
+    _reserved2: [u64; 25],
+    main_counter: VolatileCell<u64>,
+}
+
+struct Hpet {
+    regs: Mmio<HpetRegisters>,
+    /// Length of a counter tick in femtoseconds
+    period_fs: u64,
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// Starts the main counter of the HPET if the ACPI tables describe one, returns false if the
+/// system has no usable HPET
+pub fn init() -> bool {
+    let table = match acpi::find_table(HPET_SIGNATURE) {
+        Some(table) => table,
+        None => {
+            log!("HPET: not found");
+            return false;
+        }
+    };
+
+    let phys = PhysAddr::new(acpi::read_phys(
+        table + PhysAddr::new(HPET_BASE_ADDRESS_OFFSET as u64),
+    ));
+    let regs: Mmio<HpetRegisters> = unsafe { Mmio::from_phys(phys) };
+
+    let capabilities = regs.capabilities.read();
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        warn!("HPET: invalid counter period {}fs", period_fs);
+        return false;
+    }
+
+    // a 32 bit counter wraps around in a few minutes
+    if capabilities & CAP_COUNT_SIZE_64 == 0 {
+        warn!("HPET: the main counter is only 32 bits wide");
+        return false;
+    }
+
+    regs.configuration.update(|conf| conf & !CONF_ENABLE);
+    regs.main_counter.write(0);
+    regs.configuration.update(|conf| conf | CONF_ENABLE);
+
+    log!(
+        "HPET: at {} running at {}Hz",
+        phys,
+        1_000_000_000_000_000 / period_fs
+    );
+    HPET.call_once(|| Hpet { regs, period_fs });
+    true
+}
+
+/// Returns the nanoseconds since the HPET was started, None if there is no HPET
+pub fn nanoseconds() -> Option<u64> {
+    let hpet = HPET.get()?;
+    let counter = hpet.regs.main_counter.read() as u128;
+    Some((counter * hpet.period_fs as u128 / FS_PER_NS) as u64)
+}
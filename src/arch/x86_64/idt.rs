@@ -1,5 +1,9 @@
 const IDT_ENTRIES: usize = 256;
 
+/// Interrupt stack table entry of the interrupts that can switch threads, they run on a
+/// stack of the CPU so the stack of the thread switched away from is not in use anymore
+pub const SCHEDULER_IST: u8 = 1;
+
 use super::{
     exception::*,
     gdt::{segment_selector, GDT_KERNEL_CODE},
@@ -119,7 +123,14 @@ pub fn init() {
                 kernel_code_type,
             );
         }
+    }
+
+    load();
+}
 
+/// Loads the IDT on the current CPU, every CPU shares the same table
+pub fn load() {
+    unsafe {
         let idtr = IDTRValue {
             addr: IDT.as_ptr() as u64,
             size: (IDT_ENTRIES * core::mem::size_of::<IDTEntry>() - 1) as u16,
@@ -139,3 +150,14 @@ pub fn install_interrupt_handler(idx: usize, handler: u64, desc_type: IDTTypeAtt
         IDT[idx] = IDTEntry::new(handler, selector, 0, desc_type);
     }
 }
+
+/// Makes the interrupt __idx__ switch to the stack in the interrupt stack table entry __ist__
+/// of the TSS of the CPU it is delivered to
+pub fn set_interrupt_stack(idx: usize, ist: u8) {
+    assert!(idx < 256);
+    assert!(ist < 8);
+
+    unsafe {
+        IDT[idx].ist = ist;
+    }
+}
@@ -0,0 +1,134 @@
+//! The IO-APICs route external interrupts to the local APICs, every input pin is a global
+//! system interrupt(GSI) that is programmed through its redirection table entry
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::{
+    acpi::madt::{InterruptFlags, IoApicEntry},
+    mmio::{Mmio, VolatileCell},
+};
+
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL_BASE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+/// The registers are accessed indirectly, the index is written to `select` and the register
+/// is read or written through `window`
+#[repr(C)]
+struct IoApicRegisters {
+    select: VolatileCell<u32>,
+    _reserved: [u32; 3],
+    window: VolatileCell<u32>,
+}
+
+struct IoApic {
+    regs: Mmio<IoApicRegisters>,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        self.regs.select.write(reg);
+        self.regs.window.read()
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        self.regs.select.write(reg);
+        self.regs.window.write(value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.pins
+    }
+
+    fn redirection_reg(&self, gsi: u32) -> u32 {
+        IOREDTBL_BASE + (gsi - self.gsi_base) * 2
+    }
+}
+
+// the select register makes every access two steps so the IO-APICs are behind a lock
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// Registers the IO-APICs of the MADT and masks every pin
+pub fn init(entries: &[IoApicEntry]) {
+    let mut io_apics = IO_APICS.lock();
+    for entry in entries {
+        let io_apic = IoApic {
+            regs: unsafe { Mmio::from_phys(entry.phys) },
+            gsi_base: entry.gsi_base,
+            pins: 0,
+        };
+        let pins = ((io_apic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+        let io_apic = IoApic { pins, ..io_apic };
+
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + pins {
+            io_apic.write(io_apic.redirection_reg(gsi), REDIRECTION_MASKED);
+        }
+
+        log!(
+            "IOAPIC: id {} at {} handles GSI {}-{}",
+            entry.id,
+            entry.phys,
+            entry.gsi_base,
+            entry.gsi_base + pins - 1
+        );
+        io_apics.push(io_apic);
+    }
+}
+
+/// Returns whether an IO-APIC handles __gsi__
+pub fn has_gsi(gsi: u32) -> bool {
+    IO_APICS.lock().iter().any(|io_apic| io_apic.handles(gsi))
+}
+
+/// Routes __gsi__ to __vector__ on the CPU whose local APIC has the ID __lapic_id__,
+/// the pin stays masked until it is enabled
+pub fn route(gsi: u32, vector: u8, lapic_id: u32, flags: InterruptFlags) {
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .expect("GSI is not handled by any IO-APIC");
+
+    let mut low = vector as u32 | REDIRECTION_MASKED;
+    if flags.active_low() {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if flags.level_triggered() {
+        low |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+
+    let reg = io_apic.redirection_reg(gsi);
+    io_apic.write(reg + 1, lapic_id << 24);
+    io_apic.write(reg, low);
+}
+
+fn set_masked(gsi: u32, masked: bool) {
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .expect("GSI is not handled by any IO-APIC");
+
+    let reg = io_apic.redirection_reg(gsi);
+    let low = io_apic.read(reg);
+    let low = if masked {
+        low | REDIRECTION_MASKED
+    } else {
+        low & !REDIRECTION_MASKED
+    };
+    io_apic.write(reg, low);
+}
+
+pub fn mask(gsi: u32) {
+    set_masked(gsi, true);
+}
+
+pub fn unmask(gsi: u32) {
+    set_masked(gsi, false);
+}
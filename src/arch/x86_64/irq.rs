@@ -0,0 +1,106 @@
+//! Legacy ISA IRQs independent of the interrupt controller that delivers them. The IO-APIC is
+//! used if the MADT describes one, otherwise the IRQs go through the PIC. Either way IRQ
+//! __n__ arrives on the same interrupt vector so drivers only deal with IRQ numbers.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Once;
+
+use crate::{acpi::madt, cmdline};
+
+use super::{
+    idt::{self, IDTTypeAttr},
+    ioapic, lapic, pic,
+};
+
+pub const IRQ_COUNT: u8 = 16;
+
+/// The global system interrupt each ISA IRQ is connected to in IO-APIC mode
+static GSI_OF_IRQ: Once<[u32; IRQ_COUNT as usize]> = Once::new();
+static IO_APIC_MODE: AtomicBool = AtomicBool::new(false);
+
+fn io_apic_mode() -> bool {
+    IO_APIC_MODE.load(Ordering::Acquire)
+}
+
+/// Switches IRQ delivery to the IO-APIC if the system has one, the `noapic` command line
+/// flag keeps the PIC. It has to be called after the local APIC of the BSP is enabled and
+/// before any IRQ handler is installed.
+pub fn init() {
+    if cmdline::get("noapic").is_some() {
+        log!("IRQ: using the PIC because of noapic");
+        return;
+    }
+
+    let madt = match madt::parse() {
+        Some(madt) if !madt.io_apics.is_empty() => madt,
+        _ => {
+            log!("IRQ: no IO-APIC found, using the PIC");
+            return;
+        }
+    };
+
+    ioapic::init(&madt.io_apics);
+
+    let bsp_lapic_id = lapic::id();
+    let mut gsis = [0; IRQ_COUNT as usize];
+    for irq in 0..IRQ_COUNT {
+        let (gsi, flags) = madt.isa_irq(irq);
+        gsis[irq as usize] = gsi;
+        if ioapic::has_gsi(gsi) {
+            ioapic::route(gsi, vector(irq) as u8, bsp_lapic_id, flags);
+        }
+    }
+    GSI_OF_IRQ.call_once(|| gsis);
+
+    // the IO-APIC takes over from here, the PIC must not deliver anything anymore
+    pic::disable();
+    IO_APIC_MODE.store(true, Ordering::Release);
+    log!(
+        "IRQ: using the IO-APIC, {} interrupt source overrides",
+        madt.overrides.len()
+    );
+}
+
+fn gsi(irq: u8) -> u32 {
+    GSI_OF_IRQ
+        .get()
+        .expect("IO-APIC routes are not initialized")[irq as usize]
+}
+
+/// Returns the interrupt vector the IRQ is delivered on
+pub fn vector(irq: u8) -> usize {
+    pic::irq_vector(irq)
+}
+
+/// Installs __handler__ for the IRQ, the IRQ stays disabled until `enable` is called
+pub fn install_handler(irq: u8, handler: u64) {
+    assert!(irq < IRQ_COUNT);
+    let idt_type = IDTTypeAttr::INTERRUPT_GATE | IDTTypeAttr::RING0 | IDTTypeAttr::PRESENT;
+    idt::install_interrupt_handler(vector(irq), handler, idt_type, 0);
+}
+
+pub fn enable(irq: u8) {
+    if io_apic_mode() {
+        ioapic::unmask(gsi(irq));
+    } else {
+        pic::clear_irq(irq);
+    }
+}
+
+pub fn disable(irq: u8) {
+    if io_apic_mode() {
+        ioapic::mask(gsi(irq));
+    } else {
+        pic::set_irq(irq);
+    }
+}
+
+/// Signals the end of the IRQ to the controller that delivered it
+pub fn eoi(irq: u8) {
+    if io_apic_mode() {
+        lapic::eoi();
+    } else {
+        pic::send_irq_eoi(irq);
+    }
+}
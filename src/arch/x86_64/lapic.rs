@@ -0,0 +1,237 @@
+//! The local APIC every CPU has, it sends and acknowledges inter-processor interrupts,
+//! acknowledges the IRQs the IO-APIC delivers and drives the timer of the APs
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Once;
+
+use crate::{
+    mm::PhysAddr,
+    mmio::{Mmio, VolatileCell},
+};
+
+use super::{inb, outb, read_msr};
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DEST_SELF: u32 = 0b01 << 18;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// the timer is calibrated against channel 2 of the PIT, its gate and output are wired to the
+// keyboard controller port B
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_MODE_CMD_REG: u16 = 0x43;
+const PIT_PORT_B: u16 = 0x61;
+const PORT_B_CHANNEL2_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_CHANNEL2_OUT: u8 = 1 << 5;
+// channel 2, lobyte/hibyte, mode 0(interrupt on terminal count), binary
+const PIT_CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+const PIT_BASE_FREQUENCY: u32 = 1193182;
+const CALIBRATION_MS: u32 = 10;
+// how many times the PIT output is polled before giving up on it
+const CALIBRATION_SPINS_MAX: usize = 100_000_000;
+
+/// Every register is 32 bits wide and aligned to 16 bytes
+#[repr(C, align(16))]
+pub struct LapicRegister {
+    value: VolatileCell<u32>,
+    _reserved: [u32; 3],
+}
+
+impl LapicRegister {
+    #[inline]
+    pub fn read(&self) -> u32 {
+        self.value.read()
+    }
+
+    #[inline]
+    pub fn write(&self, value: u32) {
+        self.value.write(value)
+    }
+}
+
+#[repr(C)]
+pub struct LapicRegisters {
+    _reserved_0: [LapicRegister; 2],
+    pub id: LapicRegister,
+    pub version: LapicRegister,
+    _reserved_1: [LapicRegister; 4],
+    pub task_priority: LapicRegister,
+    pub arbitration_priority: LapicRegister,
+    pub processor_priority: LapicRegister,
+    pub eoi: LapicRegister,
+    pub remote_read: LapicRegister,
+    pub logical_destination: LapicRegister,
+    pub destination_format: LapicRegister,
+    pub spurious_vector: LapicRegister,
+    pub in_service: [LapicRegister; 8],
+    pub trigger_mode: [LapicRegister; 8],
+    pub interrupt_request: [LapicRegister; 8],
+    pub error_status: LapicRegister,
+    _reserved_2: [LapicRegister; 6],
+    pub lvt_cmci: LapicRegister,
+    pub icr_low: LapicRegister,
+    pub icr_high: LapicRegister,
+    pub lvt_timer: LapicRegister,
+    pub lvt_thermal: LapicRegister,
+    pub lvt_performance: LapicRegister,
+    pub lvt_lint0: LapicRegister,
+    pub lvt_lint1: LapicRegister,
+    pub lvt_error: LapicRegister,
+    pub timer_initial_count: LapicRegister,
+    pub timer_current_count: LapicRegister,
+    _reserved_3: [LapicRegister; 4],
+    pub timer_divide: LapicRegister,
+}
+
+const _: () = assert!(core::mem::size_of::<LapicRegisters>() == 0x3F0);
+
+// every CPU accesses its own local APIC at the same physical address
+static LAPIC: Once<Mmio<LapicRegisters>> = Once::new();
+
+/// Timer ticks per millisecond with the divider set to 16, 0 if the timer is not calibrated.
+/// The timers of every CPU run from the same bus clock so it is only measured on the BSP
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+fn regs() -> &'static LapicRegisters {
+    LAPIC.get().expect("Local APIC is not initialized")
+}
+
+/// Returns whether the local APIC registers have been mapped
+pub fn is_initialized() -> bool {
+    LAPIC.is_completed()
+}
+
+/// Enables the local APIC of the current CPU, interrupts the APIC can not deliver are sent
+/// to __spurious_vector__
+pub fn init(spurious_vector: u8) {
+    LAPIC.call_once(|| {
+        let base = read_msr(IA32_APIC_BASE_MSR) & APIC_BASE_ADDR_MASK;
+        unsafe { Mmio::from_phys(PhysAddr::new(base)) }
+    });
+
+    let lapic = regs();
+    // accept every interrupt
+    lapic.task_priority.write(0);
+    lapic
+        .spurious_vector
+        .write(SVR_APIC_ENABLE | spurious_vector as u32);
+}
+
+/// Returns the ID of the local APIC of the current CPU
+pub fn id() -> u32 {
+    regs().id.read() >> 24
+}
+
+/// Signals the end of an interrupt delivered by the local APIC
+pub fn eoi() {
+    regs().eoi.write(0);
+}
+
+fn send_icr(dest: u32, low: u32) {
+    let lapic = regs();
+    while lapic.icr_low.read() & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+
+    lapic.icr_high.write(dest << 24);
+    // writing the low half sends the interrupt
+    lapic.icr_low.write(low);
+}
+
+/// Sends the interrupt __vector__ to the CPU whose local APIC has the ID __lapic_id__
+pub fn send_ipi(lapic_id: u32, vector: u8) {
+    send_icr(
+        lapic_id,
+        ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | vector as u32,
+    );
+}
+
+/// Sends the interrupt __vector__ to the current CPU, it is delivered once the CPU enables
+/// interrupts
+pub fn send_self_ipi(vector: u8) {
+    send_icr(
+        0,
+        ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | ICR_DEST_SELF | vector as u32,
+    );
+}
+
+/// Sends a non-maskable interrupt to the CPU whose local APIC has the ID __lapic_id__, it is
+/// delivered even if the CPU has interrupts disabled
+pub fn send_nmi(lapic_id: u32) {
+    send_icr(lapic_id, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+}
+
+/// Measures the frequency of the local APIC timer with the PIT, the timer can only be
+/// started if this succeeded
+pub fn calibrate_timer() {
+    let lapic = regs();
+    let port_b = inb(PIT_PORT_B);
+
+    // the countdown starts when the gate is raised, the speaker stays disconnected
+    outb(
+        PIT_PORT_B,
+        port_b & !(PORT_B_CHANNEL2_GATE | PORT_B_SPEAKER),
+    );
+    outb(PIT_MODE_CMD_REG, PIT_CHANNEL2_ONESHOT);
+    let count = PIT_BASE_FREQUENCY / 1000 * CALIBRATION_MS;
+    outb(PIT_CHANNEL2_DATA, count as u8);
+    outb(PIT_CHANNEL2_DATA, (count >> 8) as u8);
+
+    lapic.timer_divide.write(TIMER_DIVIDE_BY_16);
+    lapic.lvt_timer.write(LVT_MASKED);
+    outb(
+        PIT_PORT_B,
+        (port_b & !PORT_B_SPEAKER) | PORT_B_CHANNEL2_GATE,
+    );
+    lapic.timer_initial_count.write(u32::MAX);
+
+    let mut spins = 0;
+    while inb(PIT_PORT_B) & PORT_B_CHANNEL2_OUT == 0 && spins < CALIBRATION_SPINS_MAX {
+        spins += 1;
+        core::hint::spin_loop();
+    }
+
+    let elapsed = u32::MAX - lapic.timer_current_count.read();
+    lapic.timer_initial_count.write(0);
+    outb(PIT_PORT_B, port_b);
+
+    if spins == CALIBRATION_SPINS_MAX {
+        warn!("LAPIC: PIT channel 2 did not count down, the timer is not calibrated");
+        return;
+    }
+
+    let ticks_per_ms = elapsed / CALIBRATION_MS;
+    TIMER_TICKS_PER_MS.store(ticks_per_ms, Ordering::Relaxed);
+    log!(
+        "LAPIC: timer calibrated, bus clock is {}MHz",
+        ticks_per_ms as u64 * 16 / 1000
+    );
+}
+
+/// Starts the timer of the current CPU, it fires __vector__ __hz__ times a second. Returns
+/// false if the timer is not calibrated
+pub fn start_timer(vector: u8, hz: u32) -> bool {
+    let ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::Relaxed);
+    if ticks_per_ms == 0 {
+        return false;
+    }
+
+    let lapic = regs();
+    lapic.timer_divide.write(TIMER_DIVIDE_BY_16);
+    lapic.lvt_timer.write(LVT_TIMER_PERIODIC | vector as u32);
+    let initial_count = ticks_per_ms as u64 * 1000 / hz as u64;
+    lapic.timer_initial_count.write(initial_count.max(1) as u32);
+    true
+}
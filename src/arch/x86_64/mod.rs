@@ -1,14 +1,23 @@
 pub mod exception;
 pub mod gdt;
+pub mod hpet;
 pub mod idt;
+pub mod ioapic;
+pub mod irq;
+pub mod lapic;
 pub mod paging;
 pub mod pic;
 pub mod registers;
+pub mod smp;
 pub mod stacktrace;
 pub mod syscall;
 pub mod tss;
+pub mod usercopy;
 
-use core::arch::asm;
+use core::{
+    arch::{asm, x86_64::__cpuid_count},
+    sync::atomic::{compiler_fence, AtomicBool, Ordering},
+};
 
 use crate::mm::{virt::PML4, PhysAddr, VirtAddr};
 
@@ -24,6 +33,8 @@ bitflags::bitflags! {
         const INTERRUPT = 1 << 9;
         const DIRECTION = 1 << 10;
         const OVERFLOW = 1 << 11;
+        /// I/O privilege level, code running at this ring or a more privileged one can use I/O instructions
+        const IOPL = 3 << 12;
         const NESTED_TASK = 1 << 14;
         const RESUME = 1 << 16;
         const VIRTUAL8086 = 1 << 17;
@@ -130,14 +141,61 @@ bitflags::bitflags! {
     }
 }
 
+const EFER_ADDR: u32 = 0xC0000080;
 const FS_BASE_ADDR: u32 = 0xC0000100;
 const GS_BASE_ADDR: u32 = 0xC0000101;
 
+/// EFER.NXE, the NX bit of the page table entries is reserved without it
+const EFER_NXE: u64 = 1 << 11;
+
+// CPUID.(EAX=7,ECX=0):EBX
+const CPUID_SMEP: u32 = 1 << 7;
+const CPUID_SMAP: u32 = 1 << 20;
+// CPUID.(EAX=0x80000001):EDX
+const CPUID_NX: u32 = 1 << 20;
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
 extern "C" {
     #[link_name = "x86_64_block_task"]
     pub fn block_task();
 }
 
+/// Returns whether the kernel has to use stac and clac to access user pages
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns whether the pages can be mapped with NO_EXECUTE
+pub fn nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Allows the kernel to access user pages until `clac`, it does nothing without SMAP
+#[inline]
+pub fn stac() {
+    if smap_enabled() {
+        // not nomem, the asm has to be a compiler barrier so user accesses are not moved
+        // outside of the window
+        unsafe {
+            asm!("stac", options(nostack));
+        }
+    }
+}
+
+/// Makes the kernel fault on accesses to user pages again, it does nothing without SMAP
+#[inline]
+pub fn clac() {
+    if smap_enabled() {
+        // not nomem, the asm has to be a compiler barrier so user accesses are not moved
+        // outside of the window
+        unsafe {
+            asm!("clac", options(nostack));
+        }
+    }
+}
+
 pub fn get_xcr0() -> XCR0Flags {
     let upper: u64;
     let lower: u64;
@@ -355,6 +413,18 @@ pub fn read_msr(addr: u32) -> u64 {
     (upper as u64) << 32 | lower as u64
 }
 
+/// Returns the value of the time stamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    let upper: u32;
+    let lower: u32;
+    unsafe {
+        asm!("rdtsc", out("edx") upper, out("eax") lower, options(nomem, nostack));
+    }
+
+    (upper as u64) << 32 | lower as u64
+}
+
 #[inline]
 pub fn set_fs_base(fs: VirtAddr) {
     write_msr(FS_BASE_ADDR, fs.get());
@@ -365,6 +435,38 @@ pub fn get_fs_base() -> VirtAddr {
     VirtAddr::new(read_msr(FS_BASE_ADDR))
 }
 
+/// Orders every load and store before the fence before every load and store after it
+#[inline]
+pub fn mfence() {
+    unsafe {
+        asm!("mfence", options(nostack, preserves_flags));
+    }
+}
+
+/// Orders every load before the fence before every load after it
+#[inline]
+pub fn lfence() {
+    unsafe {
+        asm!("lfence", options(nostack, preserves_flags));
+    }
+}
+
+/// Orders every store before the fence before every store after it, this must be used
+/// before notifying a device about descriptors written to DMA memory
+#[inline]
+pub fn sfence() {
+    unsafe {
+        asm!("sfence", options(nostack, preserves_flags));
+    }
+}
+
+/// Prevents the compiler from reordering memory accesses across the barrier,
+/// the CPU is free to reorder them
+#[inline]
+pub fn compiler_barrier() {
+    compiler_fence(Ordering::SeqCst);
+}
+
 pub fn init() {
     let mut cr0 = get_cr0();
     cr0.remove(CR0Flags::EM);
@@ -374,8 +476,30 @@ pub fn init() {
     let mut cr4 = get_cr4();
     cr4.insert(CR4Flags::OSFXSR);
     cr4.insert(CR4Flags::OSXMMEXCPT);
+
+    // the kernel must not run code from user pages and only touches them through usercopy,
+    // every CPU runs this so they are assumed to support the same features
+    let max_leaf = __cpuid_count(0, 0).eax;
+    let features = if max_leaf >= 7 {
+        __cpuid_count(7, 0).ebx
+    } else {
+        0
+    };
+    if features & CPUID_SMEP != 0 {
+        cr4.insert(CR4Flags::SMEP);
+    }
+    if features & CPUID_SMAP != 0 {
+        cr4.insert(CR4Flags::SMAP);
+        SMAP_ENABLED.store(true, Ordering::Relaxed);
+    }
     set_cr4(cr4);
 
+    let extended_features = __cpuid_count(0x8000_0001, 0).edx;
+    if extended_features & CPUID_NX != 0 {
+        write_msr(EFER_ADDR, read_msr(EFER_ADDR) | EFER_NXE);
+        NX_ENABLED.store(true, Ordering::Relaxed);
+    }
+
     fldcw(
         X87Flags::EXCEPTION_ALL
             | X87Flags::PRECISION_CONTROL_64B
@@ -1,3 +1,5 @@
+use super::nx_enabled;
+
 bitflags::bitflags! {
     /// Common flags
     pub struct PageFlags: u64 {
@@ -10,6 +12,11 @@ bitflags::bitflags! {
         const ACCESSED = 1 << 5;
         const DIRTY = 1 << 6;
         const ALLOC_ON_ACCESS = 1 << 9;
+        /// The frame is shared with another address space, it is copied on the first write
+        const COPY_ON_WRITE = 1 << 10;
+        /// Instructions can not be fetched from the page, only the pages get it, the page
+        /// tables above them are always executable
+        const NO_EXECUTE = 1 << 63;
     }
 
     pub struct PML1Flags: u64 {
@@ -24,6 +31,8 @@ bitflags::bitflags! {
         const PAGE_ATTRIBUTE_TABLE = 1 << 7;
         const GLOBAL = 1 << 8;
         const ALLOC_ON_ACCESS = 1 << 9;
+        const COPY_ON_WRITE = 1 << 10;
+        const NO_EXECUTE = 1 << 63;
     }
 
     pub struct PML2Flags: u64 {
@@ -37,6 +46,7 @@ bitflags::bitflags! {
         const DIRTY = 1 << 6;
         const PAGE_SIZE = 1 << 7;
         const ALLOC_ON_ACCESS = 1 << 9;
+        const NO_EXECUTE = 1 << 63;
     }
 
     pub struct PML3Flags: u64 {
@@ -50,6 +60,7 @@ bitflags::bitflags! {
         const DIRTY = 1 << 6;
         const PAGE_SIZE = 1 << 7;
         const ALLOC_ON_ACCESS = 1 << 9;
+        const NO_EXECUTE = 1 << 63;
     }
 
     pub struct PML4Flags: u64 {
@@ -62,6 +73,16 @@ bitflags::bitflags! {
         const ACCESSED = 1 << 5;
         const DIRTY = 1 << 6;
         const ALLOC_ON_ACCESS = 1 << 9;
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+/// Returns NO_EXECUTE if the CPU supports it, the bit is reserved otherwise
+pub fn no_execute() -> PageFlags {
+    if nx_enabled() {
+        PageFlags::NO_EXECUTE
+    } else {
+        PageFlags::NONE
     }
 }
 
@@ -71,7 +92,7 @@ impl PageFlags {
     }
 
     pub fn to_plm2_flags(&self) -> PML2Flags {
-        let mut flags = PML2Flags::from_bits(self.bits).unwrap();
+        let mut flags = PML2Flags::from_bits((*self - PageFlags::NO_EXECUTE).bits).unwrap();
         if self.contains(PageFlags::ALLOC_ON_ACCESS) {
             flags.remove(PML2Flags::ALLOC_ON_ACCESS);
             flags.insert(PML2Flags::PRESENT);
@@ -81,7 +102,7 @@ impl PageFlags {
     }
 
     pub fn to_plm3_flags(&self) -> PML3Flags {
-        let mut flags = PML3Flags::from_bits(self.bits).unwrap();
+        let mut flags = PML3Flags::from_bits((*self - PageFlags::NO_EXECUTE).bits).unwrap();
         if self.contains(PageFlags::ALLOC_ON_ACCESS) {
             flags.remove(PML3Flags::ALLOC_ON_ACCESS);
             flags.insert(PML3Flags::PRESENT);
@@ -91,7 +112,7 @@ impl PageFlags {
     }
 
     pub fn to_plm4_flags(&self) -> PML4Flags {
-        let mut flags = PML4Flags::from_bits(self.bits).unwrap();
+        let mut flags = PML4Flags::from_bits((*self - PageFlags::NO_EXECUTE).bits).unwrap();
         if self.contains(PageFlags::ALLOC_ON_ACCESS) {
             flags.remove(PML4Flags::ALLOC_ON_ACCESS);
             flags.insert(PML4Flags::PRESENT);
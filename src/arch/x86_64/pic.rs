@@ -1,6 +1,4 @@
-use crate::arch::x86_64::idt::IDTTypeAttr;
-
-use super::{idt, inb, outb};
+use super::{inb, outb};
 
 const PIC1_COMMAND: u16 = 0x20;
 const PIC1_DATA: u16 = 0x21;
@@ -95,12 +93,21 @@ pub fn clear_irq(irq: u8) {
 }
 
 pub fn send_irq_eoi(irq: u8) {
-    let port = if irq >= 8 { PIC2_COMMAND } else { PIC1_COMMAND };
-    outb(port, PIC_EOI);
+    // IRQs of the slave are cascaded through the master so both have to be acknowledged
+    if irq >= 8 {
+        outb(PIC2_COMMAND, PIC_EOI);
+    }
+    outb(PIC1_COMMAND, PIC_EOI);
 }
 
-pub fn install_irq_handler(irq: u8, handler: u64) {
+/// Returns the interrupt vector the IRQ is delivered on
+pub fn irq_vector(irq: u8) -> usize {
     assert!(irq < 16);
-    let idt_type = IDTTypeAttr::INTERRUPT_GATE | IDTTypeAttr::RING0 | IDTTypeAttr::PRESENT;
-    idt::install_interrupt_handler(IDT_IRQ_BASE + irq as usize, handler, idt_type, 0);
+    IDT_IRQ_BASE + irq as usize
+}
+
+/// Masks every IRQ of both PICs, used when the IO-APIC delivers the IRQs instead
+pub fn disable() {
+    outb(PIC1_DATA, 0xFF);
+    outb(PIC2_DATA, 0xFF);
 }
@@ -0,0 +1,457 @@
+//! Bringing up the application processors(APs) and the inter-processor interrupts the CPUs
+//! use to reschedule each other and to invalidate stale TLB entries.
+//!
+//! The bootloader parks the APs in its own memory, before that memory is unmapped they are
+//! moved into a spin loop in the kernel where they wait until the scheduler is ready.
+//! CPUs are numbered in the order the bootloader reports them, the BSP is always CPU 0.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use alloc::vec;
+use limine::{SmpInfo, SmpRequest};
+
+use crate::{
+    cmdline, latency, limits::CPU_MAX, mm::PhysAddr, scheduler::SCHEDULER, sync::InterruptMutex,
+    time,
+};
+
+use super::{
+    disable_interrupts, enable_interrupts, gdt, get_cr3,
+    idt::{self, IDTTypeAttr},
+    interrupts_enabled, lapic,
+    registers::{InterruptRegisters, RegisterState},
+    set_cr3, tss,
+};
+
+static SMP_INFO: SmpRequest = SmpRequest::new(0);
+
+/// Makes the CPU run the scheduler tick, it is sent when a thread queued on another CPU is
+/// woken up and by the BSP on every timer interrupt to CPUs without a local APIC timer
+pub const IPI_RESCHEDULE: u8 = 0xF0;
+/// Makes the CPU invalidate the TLB entries of the range of the current shootdown
+pub const IPI_TLB_SHOOTDOWN: u8 = 0xF1;
+/// The local APIC timer of the APs, it runs the scheduler tick just like the reschedule IPI
+pub const LAPIC_TIMER_VECTOR: u8 = 0xF2;
+const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const AP_BOOT_STACK_SIZE: usize = 16 * 1024;
+const INTERRUPT_STACK_SIZE: usize = 16 * 1024;
+
+// how many times we check whether the APs have reached the kernel before giving up on them
+const AP_WAIT_SPINS: usize = 100_000_000;
+
+// addresses at or above this are mapped in every address space
+const KERNEL_HALF_START: u64 = 0xFFFF_8000_0000_0000;
+
+// flushing more pages than this one by one is slower than reloading CR3
+const SHOOTDOWN_PAGES_MAX: u64 = 32;
+
+struct CpuInfo {
+    lapic_id: AtomicU32,
+    online: AtomicBool,
+    /// Physical address of the PML4 the CPU has loaded
+    pml4: AtomicU64,
+    /// Set by a CPU that changed mappings this CPU might have cached
+    tlb_flush_pending: AtomicBool,
+    /// The CPU is ticked by its own local APIC timer instead of the BSP
+    has_timer: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_INFO_INIT: CpuInfo = CpuInfo {
+    lapic_id: AtomicU32::new(0),
+    online: AtomicBool::new(false),
+    pml4: AtomicU64::new(0),
+    tlb_flush_pending: AtomicBool::new(false),
+    has_timer: AtomicBool::new(false),
+};
+
+static CPUS: [CpuInfo; CPU_MAX] = [CPU_INFO_INIT; CPU_MAX];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const LAPIC_UNKNOWN: AtomicU8 = AtomicU8::new(0);
+
+// local APIC IDs are 8 bits wide in xAPIC mode
+static LAPIC_TO_CPU: [AtomicU8; 256] = [LAPIC_UNKNOWN; 256];
+
+/// Number of CPUs the kernel can use including the ones that have not been started yet
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static ONLINE_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Set when a CPU panicked and stopped the others with an NMI
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+// the boot protocol between the BSP and the AP spin loop in smp.s, an AP leaves the loop
+// once AP_BOOT_LAPIC_ID is its local APIC ID and acknowledges it by setting it to u32::MAX
+#[no_mangle]
+static AP_PARKED: AtomicU64 = AtomicU64::new(0);
+#[no_mangle]
+static AP_BOOT_LAPIC_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+#[no_mangle]
+static AP_BOOT_STACK: AtomicU64 = AtomicU64::new(0);
+#[no_mangle]
+static AP_BOOT_CR3: AtomicU64 = AtomicU64::new(0);
+
+// only one shootdown is in flight at a time, the range is [start, end)
+static SHOOTDOWN_LOCK: InterruptMutex<()> = InterruptMutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_END: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    fn __ap_entry(info: *const SmpInfo) -> !;
+    fn __ipi_reschedule();
+    fn __ipi_tlb_shootdown();
+    fn __lapic_spurious();
+}
+
+/// Returns the number of the CPU the caller runs on, it is 0 until the local APIC is
+/// initialized
+pub fn current_cpu() -> usize {
+    if !lapic::is_initialized() {
+        return 0;
+    }
+
+    LAPIC_TO_CPU[lapic::id() as usize].load(Ordering::Relaxed) as usize
+}
+
+/// Returns the number of CPUs the kernel can use
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn is_online(cpu: usize) -> bool {
+    CPUS[cpu].online.load(Ordering::Acquire)
+}
+
+pub fn online_count() -> usize {
+    ONLINE_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn is_halting() -> bool {
+    HALTING.load(Ordering::Relaxed)
+}
+
+/// Moves the APs from the bootloader into the kernel, this has to be called before the
+/// memory of the bootloader is unmapped
+pub fn park_aps() {
+    let mut response = SMP_INFO.get_response();
+    let response = match response.get_mut() {
+        Some(response) => response,
+        None => {
+            warn!("SMP: bootloader did not start the APs");
+            return;
+        }
+    };
+
+    let bsp_lapic_id = response.bsp_lapic_id;
+    CPUS[0].lapic_id.store(bsp_lapic_id, Ordering::Relaxed);
+    LAPIC_TO_CPU[bsp_lapic_id as usize].store(0, Ordering::Relaxed);
+
+    let mut count = 1;
+    let mut waiting = 0;
+    for info in response.cpus().iter_mut() {
+        if info.lapic_id == bsp_lapic_id {
+            continue;
+        }
+
+        // the rest stay parked in the bootloader
+        if count == CPU_MAX {
+            warn!("SMP: only {} CPUs are used", CPU_MAX);
+            break;
+        }
+
+        CPUS[count].lapic_id.store(info.lapic_id, Ordering::Relaxed);
+        LAPIC_TO_CPU[info.lapic_id as usize].store(count as u8, Ordering::Relaxed);
+        count += 1;
+
+        unsafe {
+            let entry: extern "C" fn(*const SmpInfo) -> ! =
+                core::mem::transmute(__ap_entry as unsafe extern "C" fn(*const SmpInfo) -> !);
+            core::ptr::write_volatile(&mut info.goto_address, entry);
+        }
+        waiting += 1;
+    }
+
+    // the bootloader memory the APs run on is unmapped after this so we have to make sure
+    // every one of them has left it
+    let mut spins = 0;
+    while AP_PARKED.load(Ordering::Acquire) < waiting {
+        if spins == AP_WAIT_SPINS {
+            warn!(
+                "SMP: only {} of {} APs reached the kernel, not using them",
+                AP_PARKED.load(Ordering::Acquire),
+                waiting
+            );
+            return;
+        }
+
+        spins += 1;
+        core::hint::spin_loop();
+    }
+
+    CPU_COUNT.store(count, Ordering::Relaxed);
+}
+
+fn alloc_stack(size: usize) -> u64 {
+    let stack = vec![0u8; size].leak();
+    stack.as_ptr() as u64 + size as u64
+}
+
+/// Enables the local APIC of the BSP, calibrates its timer and installs the IPI handlers,
+/// this has to be called after the kernel heap is initialized
+pub fn init_bsp() {
+    lapic::init(SPURIOUS_VECTOR);
+    lapic::calibrate_timer();
+
+    unsafe {
+        tss::set_interrupt_stack(0, idt::SCHEDULER_IST, alloc_stack(INTERRUPT_STACK_SIZE));
+    }
+
+    let idt_type = IDTTypeAttr::INTERRUPT_GATE | IDTTypeAttr::RING0 | IDTTypeAttr::PRESENT;
+    idt::install_interrupt_handler(
+        IPI_RESCHEDULE as usize,
+        __ipi_reschedule as u64,
+        idt_type,
+        0,
+    );
+    idt::set_interrupt_stack(IPI_RESCHEDULE as usize, idt::SCHEDULER_IST);
+    idt::install_interrupt_handler(
+        LAPIC_TIMER_VECTOR as usize,
+        __ipi_reschedule as u64,
+        idt_type,
+        0,
+    );
+    idt::set_interrupt_stack(LAPIC_TIMER_VECTOR as usize, idt::SCHEDULER_IST);
+    idt::install_interrupt_handler(
+        IPI_TLB_SHOOTDOWN as usize,
+        __ipi_tlb_shootdown as u64,
+        idt_type,
+        0,
+    );
+    idt::install_interrupt_handler(
+        SPURIOUS_VECTOR as usize,
+        __lapic_spurious as u64,
+        idt_type,
+        0,
+    );
+
+    CPUS[0].pml4.store(get_cr3(), Ordering::Relaxed);
+    CPUS[0].online.store(true, Ordering::Release);
+}
+
+/// Starts the scheduler on the parked APs, the `nosmp` command line flag keeps them parked
+pub fn start_aps() {
+    if cmdline::get("nosmp").is_some() {
+        log!("SMP: APs are disabled by nosmp");
+        return;
+    }
+
+    for cpu in 1..cpu_count() {
+        unsafe {
+            tss::set_interrupt_stack(cpu, idt::SCHEDULER_IST, alloc_stack(INTERRUPT_STACK_SIZE));
+        }
+
+        AP_BOOT_STACK.store(alloc_stack(AP_BOOT_STACK_SIZE), Ordering::Relaxed);
+        AP_BOOT_CR3.store(get_cr3(), Ordering::Relaxed);
+        AP_BOOT_LAPIC_ID.store(
+            CPUS[cpu].lapic_id.load(Ordering::Relaxed),
+            Ordering::Release,
+        );
+
+        // the AP has read the boot variables once it acknowledges
+        while AP_BOOT_LAPIC_ID.load(Ordering::Acquire) != u32::MAX {
+            core::hint::spin_loop();
+        }
+
+        while !is_online(cpu) {
+            core::hint::spin_loop();
+        }
+    }
+
+    log!("SMP: {} CPUs online", ONLINE_COUNT.load(Ordering::Relaxed));
+}
+
+#[no_mangle]
+extern "C" fn ap_main(lapic_id: u32) -> ! {
+    let cpu = LAPIC_TO_CPU[lapic_id as usize].load(Ordering::Relaxed) as usize;
+
+    gdt::init_cpu(cpu);
+    idt::load();
+    super::init();
+    lapic::init(SPURIOUS_VECTOR);
+    // without a calibrated timer the BSP keeps forwarding its ticks
+    let has_timer = lapic::start_timer(LAPIC_TIMER_VECTOR, time::TICK_FREQUENCY as u32);
+    CPUS[cpu].has_timer.store(has_timer, Ordering::Relaxed);
+
+    CPUS[cpu].pml4.store(get_cr3(), Ordering::Relaxed);
+    CPUS[cpu].online.store(true, Ordering::Release);
+    ONLINE_COUNT.fetch_add(1, Ordering::Relaxed);
+
+    SCHEDULER.start_cpu(cpu);
+}
+
+/// Records the PML4 the current CPU is about to load so TLB shootdowns of that address
+/// space reach it, it has to be called before CR3 is written
+pub fn set_current_pml4(pml4: PhysAddr) {
+    CPUS[current_cpu()].pml4.store(pml4.get(), Ordering::SeqCst);
+}
+
+/// Returns whether another CPU has the address space __pml4__ loaded
+pub fn pml4_in_use(pml4: PhysAddr) -> bool {
+    let current = current_cpu();
+    (0..cpu_count()).any(|cpu| {
+        cpu != current && is_online(cpu) && CPUS[cpu].pml4.load(Ordering::SeqCst) == pml4.get()
+    })
+}
+
+/// Makes __cpu__ run the scheduler tick as soon as possible
+pub fn send_reschedule(cpu: usize) {
+    if cpu != current_cpu() && is_online(cpu) {
+        lapic::send_ipi(CPUS[cpu].lapic_id.load(Ordering::Relaxed), IPI_RESCHEDULE);
+    }
+}
+
+/// Makes the current CPU run the scheduler tick once it enables interrupts, without the local
+/// APIC it runs on the next timer tick
+pub fn reschedule_self() {
+    if lapic::is_initialized() {
+        lapic::send_self_ipi(IPI_RESCHEDULE);
+    }
+}
+
+/// Forwards the timer tick of the BSP to the CPUs that do not have their own timer
+pub fn broadcast_tick() {
+    if ONLINE_COUNT.load(Ordering::Relaxed) == 1 {
+        return;
+    }
+
+    for cpu in 1..cpu_count() {
+        if is_online(cpu) && !CPUS[cpu].has_timer.load(Ordering::Relaxed) {
+            lapic::send_ipi(CPUS[cpu].lapic_id.load(Ordering::Relaxed), IPI_RESCHEDULE);
+        }
+    }
+}
+
+/// Stops every other CPU, it is used by the panic handler
+pub fn halt_others() {
+    HALTING.store(true, Ordering::Relaxed);
+    if ONLINE_COUNT.load(Ordering::Relaxed) == 1 {
+        return;
+    }
+
+    nmi_others();
+}
+
+/// Sends an NMI to every other online CPU
+pub fn nmi_others() {
+    let current = current_cpu();
+    for cpu in 0..cpu_count() {
+        if cpu != current && is_online(cpu) {
+            lapic::send_nmi(CPUS[cpu].lapic_id.load(Ordering::Relaxed));
+        }
+    }
+}
+
+fn flush_local_range(start: u64, end: u64) {
+    if (end - start) / 4096 > SHOOTDOWN_PAGES_MAX {
+        // no pages are global so this flushes everything
+        set_cr3(get_cr3());
+        return;
+    }
+
+    let mut addr = start;
+    while addr < end {
+        super::flush_tlb_page(addr);
+        addr += 4096;
+    }
+}
+
+fn service_tlb_shootdown(cpu: usize) {
+    if CPUS[cpu].tlb_flush_pending.load(Ordering::Acquire) {
+        flush_local_range(
+            SHOOTDOWN_START.load(Ordering::Relaxed),
+            SHOOTDOWN_END.load(Ordering::Relaxed),
+        );
+        CPUS[cpu].tlb_flush_pending.store(false, Ordering::Release);
+    }
+}
+
+/// Flushes the TLB if another CPU is waiting for the current CPU to do so, it has to be called
+/// while spinning on a lock with interrupts disabled because the CPU waiting for the flush
+/// might hold the lock
+pub fn handle_pending_shootdown() {
+    if !interrupts_enabled() {
+        service_tlb_shootdown(current_cpu());
+    }
+}
+
+/// Invalidates the TLB entries of the pages in [start, end) of the address space __pml4__ on
+/// every CPU that might have cached them, the other CPUs acknowledge the flush from the IPI
+/// handler or while they spin on a lock
+pub fn flush_tlb_range(pml4: PhysAddr, start: u64, end: u64) {
+    let interrupts = interrupts_enabled();
+    disable_interrupts();
+
+    let kernel = start >= KERNEL_HALF_START;
+    if kernel || get_cr3() == pml4.get() {
+        flush_local_range(start, end);
+    }
+
+    if ONLINE_COUNT.load(Ordering::Relaxed) > 1 {
+        // the page tables have to be updated before we check which CPUs use them
+        core::sync::atomic::fence(Ordering::SeqCst);
+
+        // a CPU waiting for the lock could be the target of the current shootdown
+        let current = current_cpu();
+        let guard = loop {
+            match SHOOTDOWN_LOCK.try_lock() {
+                Some(guard) => break guard,
+                None => {
+                    service_tlb_shootdown(current);
+                    core::hint::spin_loop();
+                }
+            }
+        };
+
+        SHOOTDOWN_START.store(start, Ordering::Relaxed);
+        SHOOTDOWN_END.store(end, Ordering::Relaxed);
+
+        for cpu in 0..cpu_count() {
+            let info = &CPUS[cpu];
+            let uses_pml4 = kernel || info.pml4.load(Ordering::SeqCst) == pml4.get();
+            if cpu != current && is_online(cpu) && uses_pml4 {
+                info.tlb_flush_pending.store(true, Ordering::Release);
+                lapic::send_ipi(info.lapic_id.load(Ordering::Relaxed), IPI_TLB_SHOOTDOWN);
+            }
+        }
+
+        while CPUS
+            .iter()
+            .any(|info| info.tlb_flush_pending.load(Ordering::Acquire))
+        {
+            core::hint::spin_loop();
+        }
+
+        drop(guard);
+    }
+
+    if interrupts {
+        enable_interrupts();
+    }
+}
+
+#[no_mangle]
+extern "C" fn ipi_reschedule(interrupt_regs: &InterruptRegisters) -> *const RegisterState {
+    // the local APIC timer shares the handler, its ticks are counted as reschedule IPIs
+    let _latency = latency::irq_timer(IPI_RESCHEDULE as usize);
+    let next_regs = SCHEDULER.tick(interrupt_regs);
+    lapic::eoi();
+    next_regs
+}
+
+#[no_mangle]
+extern "C" fn ipi_tlb_shootdown() {
+    let _latency = latency::irq_timer(IPI_TLB_SHOOTDOWN as usize);
+    service_tlb_shootdown(current_cpu());
+    lapic::eoi();
+}
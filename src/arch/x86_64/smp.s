@@ -0,0 +1,120 @@
+bits 64
+
+extern ap_main
+extern ipi_reschedule
+extern x86_64_switch_task
+extern ipi_tlb_shootdown
+extern AP_PARKED
+extern AP_BOOT_LAPIC_ID
+extern AP_BOOT_STACK
+extern AP_BOOT_CR3
+
+section .text
+global __ap_entry:function (__ap_entry.end - __ap_entry)
+__ap_entry:
+    ; rdi = *SmpInfo of the CPU, it is in bootloader memory like the stack we were given
+    ; so only the local APIC ID is read and the stack is not used until it is replaced
+    cli
+    mov ebx, [rdi + 4]
+    lock inc qword [AP_PARKED]
+
+.wait:
+    pause
+    mov eax, [AP_BOOT_LAPIC_ID]
+    cmp eax, ebx
+    jne .wait
+
+    mov rax, [AP_BOOT_CR3]
+    mov cr3, rax
+    mov rsp, [AP_BOOT_STACK]
+    xor rbp, rbp
+
+    ; tell the BSP the boot variables can be reused
+    mov dword [AP_BOOT_LAPIC_ID], 0xFFFFFFFF
+
+    mov edi, ebx
+    call ap_main
+.end:
+
+global __ipi_reschedule:function (__ipi_reschedule.end - __ipi_reschedule)
+__ipi_reschedule:
+    ; push general purpose registers
+    push rbp
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    mov rdi, rsp
+    call ipi_reschedule
+
+    ; the handler returns the saved registers of the thread to switch to, the registers
+    ; pushed on the interrupt stack are left behind since the scheduler saved them already
+    test rax, rax
+    jz .resume
+    mov rdi, rax
+    jmp x86_64_switch_task
+
+.resume:
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rbp
+
+    iretq
+.end:
+
+global __ipi_tlb_shootdown:function (__ipi_tlb_shootdown.end - __ipi_tlb_shootdown)
+__ipi_tlb_shootdown:
+    ; only the caller saved registers are clobbered by the handler
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rax
+
+    call ipi_tlb_shootdown
+
+    pop rax
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+
+    iretq
+.end:
+
+global __lapic_spurious:function (__lapic_spurious.end - __lapic_spurious)
+__lapic_spurious:
+    ; spurious interrupts must not be acknowledged
+    iretq
+.end:
@@ -1,5 +1,7 @@
 use core::arch::asm;
 
+use crate::ksyms::Symbolized;
+
 const MAX_FRAMES: usize = 64;
 
 pub fn walk() {
@@ -15,7 +17,7 @@ pub fn walk() {
             return;
         }
         let func = unsafe { *(rbp as *const usize).add(1) };
-        error!("  {:#x}", func);
+        error!("  {}", Symbolized(func));
         rbp = unsafe { *(rbp as *const usize) };
     }
 }
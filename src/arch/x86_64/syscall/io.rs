@@ -1,20 +1,35 @@
-use alloc::{slice, sync::Arc};
-use spin::Mutex;
+use alloc::{sync::Arc, vec, vec::Vec};
 
 use crate::{
-    posix::{FileOpenFlags, FileOpenMode, Stat},
+    arch::x86_64::usercopy::{In, InOut, Out, UserIoVec, UserPtr, UserSlice},
+    kmsg,
+    limits::{OPEN_MAX, PATH_MAX},
+    posix::{
+        errno::{Errno, EINVAL, ENOENT},
+        FileOpenFlags, FileOpenMode, PollFd, Stat, Timespec, FD_SETSIZE,
+    },
     scheduler::proc::Process,
-    syscalls::{self},
+    sync::Mutex,
+    syscalls::{
+        self,
+        io::{pselect::FdSet, read::BOUNCE_BUFFER_SIZE},
+        proc::nanosleep::timespec_to_ms,
+    },
 };
 
-use super::utils;
-
 pub fn sys_write(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     let fd = args[0] as usize;
-    let len = args[2] as usize;
-    let buff = unsafe { slice::from_raw_parts(args[1] as *const u8, len) };
 
-    match syscalls::io::write::write(proc, fd, buff) {
+    let buff = match UserSlice::<In>::new(args[1], args[2] as usize) {
+        Ok(buff) => buff,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    let written = syscalls::io::write::write_chunked(proc, fd, buff.len(), None, |off, dst| {
+        buff.read_at(off, dst)
+    });
+
+    match written {
         Ok(n) => n as u64,
         Err(err) => err.into_inner_result() as u64,
     }
@@ -22,10 +37,101 @@ pub fn sys_write(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
 
 pub fn sys_read(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     let fd = args[0] as usize;
-    let len = args[2] as usize;
-    let buff = unsafe { slice::from_raw_parts_mut(args[1] as *mut u8, len) };
 
-    match syscalls::io::read::read(proc, fd, buff) {
+    // the data would be consumed before finding out that it can not be copied
+    let buff = match UserSlice::<Out>::new(args[1], args[2] as usize) {
+        Ok(buff) => buff,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    let read = syscalls::io::read::read_chunked(proc, fd, buff.len(), None, |off, src| {
+        buff.write_at(off, src)
+    });
+
+    match read {
+        Ok(n) => n as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_writev(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let fd = args[0] as usize;
+
+    let iov = match UserIoVec::<In>::new(args[1], args[2] as usize) {
+        Ok(iov) => iov,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    // the buffers are written as a single write so a write to a pipe that fits in the bounce
+    // buffer is not split between them
+    let written = syscalls::io::write::write_chunked(proc, fd, iov.len(), None, |off, dst| {
+        iov.read_at(off, dst)
+    });
+
+    match written {
+        Ok(n) => n as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_readv(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let fd = args[0] as usize;
+
+    let iov = match UserIoVec::<Out>::new(args[1], args[2] as usize) {
+        Ok(iov) => iov,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    // a single read fills the buffers in order
+    let read = syscalls::io::read::read_chunked(proc, fd, iov.len(), None, |off, src| {
+        iov.write_at(off, src)
+    });
+
+    match read {
+        Ok(n) => n as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_pread64(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let fd = args[0] as usize;
+    let off = match usize::try_from(args[3] as i64) {
+        Ok(off) => off,
+        Err(_) => return EINVAL.into_inner_result() as u64,
+    };
+
+    let buff = match UserSlice::<Out>::new(args[1], args[2] as usize) {
+        Ok(buff) => buff,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    let read = syscalls::io::pread::pread(proc, fd, buff.len(), off, |buff_off, src| {
+        buff.write_at(buff_off, src)
+    });
+
+    match read {
+        Ok(n) => n as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_pwrite64(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let fd = args[0] as usize;
+    let off = match usize::try_from(args[3] as i64) {
+        Ok(off) => off,
+        Err(_) => return EINVAL.into_inner_result() as u64,
+    };
+
+    let buff = match UserSlice::<In>::new(args[1], args[2] as usize) {
+        Ok(buff) => buff,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    let written = syscalls::io::pwrite::pwrite(proc, fd, buff.len(), off, |buff_off, dst| {
+        buff.read_at(buff_off, dst)
+    });
+
+    match written {
         Ok(n) => n as u64,
         Err(err) => err.into_inner_result() as u64,
     }
@@ -34,14 +140,16 @@ pub fn sys_read(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
 pub fn sys_openat(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     let dirfd = args[0] as isize;
 
-    let path = args[1] as *const u8;
-    let path_length = args[2] as usize;
+    let path = UserSlice::<In>::new(args[1], args[2] as usize);
 
     let flags = FileOpenFlags::from_bits_truncate(args[3] as u32);
     let mode = FileOpenMode::from_bits_truncate(args[4] as u32);
 
-    // TODO: copy path to kernelspace
-    let path = utils::get_userspace_string(path, path_length).unwrap();
+    let path = match path.and_then(|path| path.read_string(PATH_MAX)) {
+        Ok(Some(path)) => path,
+        Ok(None) => return ENOENT.into_inner_result() as u64,
+        Err(err) => return err.into_inner_result() as u64,
+    };
 
     match syscalls::io::openat::openat(proc, dirfd, &path, flags, mode) {
         Ok(n) => n as u64,
@@ -59,16 +167,23 @@ pub fn sys_close(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
 
 pub fn sys_fstatat(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     let fd = args[0] as isize;
-    let path = args[1] as *const u8;
-    let path_len = args[2] as usize;
-    // TODO: validate buff
-    let stat_buf = unsafe { (args[3] as *mut Stat).as_mut() }.unwrap();
+    let path = UserSlice::<In>::new(args[1], args[2] as usize);
+    let stat_ptr = UserPtr::<Stat, Out>::new(args[3]);
     let flag = args[4] as usize;
 
-    let path = utils::get_userspace_string(path, path_len);
+    let path = match path.and_then(|path| path.read_string(PATH_MAX)) {
+        Ok(path) => path,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    let mut stat_buf = Stat::zero();
+    if let Err(err) = syscalls::io::fstatat::fstatat(proc, fd, path.as_deref(), &mut stat_buf, flag)
+    {
+        return err.into_inner_result() as u64;
+    }
 
-    match syscalls::io::fstatat::fstatat(proc, fd, path.as_deref(), stat_buf, flag) {
-        Ok(_) => 0,
+    match stat_ptr.write(&stat_buf) {
+        Ok(()) => 0,
         Err(err) => err.into_inner_result() as u64,
     }
 }
@@ -84,6 +199,27 @@ pub fn sys_fcntl(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     }
 }
 
+pub fn sys_dup2(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let fd = args[0] as usize;
+    let new_fd = args[1] as usize;
+
+    match syscalls::io::dup::dup2(proc, fd, new_fd) {
+        Ok(fd) => fd as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_dup3(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let fd = args[0] as usize;
+    let new_fd = args[1] as usize;
+    let flags = args[2] as u32;
+
+    match syscalls::io::dup::dup3(proc, fd, new_fd, flags) {
+        Ok(fd) => fd as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
 pub fn sys_ioctl(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     let fd = args[0] as usize;
     let req = args[1] as usize;
@@ -95,9 +231,30 @@ pub fn sys_ioctl(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     }
 }
 
+pub fn sys_pipe2(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let fds_ptr = UserPtr::<[i32; 2], Out>::new(args[0]);
+    let flags = args[1] as u32;
+
+    let fds = match syscalls::io::pipe2::pipe2(proc.clone(), flags) {
+        Ok(fds) => fds,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match fds_ptr.write(&[fds[0] as i32, fds[1] as i32]) {
+        Ok(()) => 0,
+        Err(err) => {
+            // the caller never learns about the file descriptors so they are closed
+            for fd in fds {
+                syscalls::io::close::close(proc.clone(), fd).unwrap();
+            }
+            err.into_inner_result() as u64
+        }
+    }
+}
+
 pub fn sys_lseek(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     let fd = args[0] as usize;
-    let offset = args[1] as usize;
+    let offset = args[1] as isize;
     let whence = args[2] as usize;
 
     match syscalls::io::lseek::lseek(proc, fd, offset, whence) {
@@ -107,30 +264,256 @@ pub fn sys_lseek(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
 }
 
 pub fn sys_log(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
-    let message = args[0] as *const u8;
-    let message_len = args[1] as usize;
+    // the kernel log cuts longer messages anyway
+    let len = usize::min(args[1] as usize, kmsg::LOG_TEXT_MAX);
+    let message = UserSlice::<In>::new(args[0], len);
 
-    // TODO: error
-    let message = utils::get_userspace_string(message, message_len).unwrap();
+    let message = match message.and_then(|message| message.read_string(len)) {
+        Ok(Some(message)) => message,
+        Ok(None) => return 0,
+        Err(err) => return err.into_inner_result() as u64,
+    };
 
     syscalls::io::log::log(proc, &message).unwrap();
 
     0
 }
 
-pub fn sys_pselect(_proc: Arc<Mutex<Process>>, _args: [u64; 6]) -> u64 {
-    1
+/// Copies the newest messages of the kernel log that fit in the buffer, with a length of 0
+/// it returns the size of the whole log
+pub fn sys_dmesg(_proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let len = usize::min(args[1] as usize, kmsg::LOG_FORMATTED_MAX);
+    let mut buff = vec![0; len];
+    let copied = kmsg::read_all(&mut buff);
+    if len == 0 {
+        return copied as u64;
+    }
+
+    match UserSlice::<Out>::new(args[0], copied).and_then(|ptr| ptr.write(&buff[..copied])) {
+        Ok(()) => copied as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_poll(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let ptr = UserPtr::<PollFd, InOut>::new(args[0]);
+    let nfds = args[1] as usize;
+    // a negative timeout waits forever
+    let timeout = u64::try_from(args[2] as i32).ok();
+
+    if nfds > OPEN_MAX {
+        return EINVAL.into_inner_result() as u64;
+    }
+
+    let mut fds = Vec::with_capacity(nfds);
+    for i in 0..nfds {
+        match ptr.add(i).read() {
+            Ok(pollfd) => fds.push(pollfd),
+            Err(err) => return err.into_inner_result() as u64,
+        }
+    }
+
+    let ready = match syscalls::io::poll::poll(proc, &mut fds, timeout) {
+        Ok(ready) => ready,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    for (i, pollfd) in fds.iter().enumerate() {
+        if let Err(err) = ptr.add(i).write(pollfd) {
+            return err.into_inner_result() as u64;
+        }
+    }
+
+    ready as u64
+}
+
+/// Reads the words of the fd_set at __addr__ that hold the first __nfds__ bits, None if
+/// __addr__ is null
+fn read_fd_set(addr: u64, nfds: usize) -> Result<Option<FdSet>, Errno> {
+    let ptr = UserPtr::<u64, In>::new(addr);
+    if ptr.is_null() {
+        return Ok(None);
+    }
+
+    let mut set = [0; FD_SETSIZE / 64];
+    for (i, word) in set.iter_mut().take(nfds.div_ceil(64)).enumerate() {
+        *word = ptr.add(i).read()?;
+    }
+
+    Ok(Some(set))
+}
+
+fn write_fd_set(addr: u64, nfds: usize, set: &Option<FdSet>) -> Result<(), Errno> {
+    let set = match set {
+        Some(set) => set,
+        None => return Ok(()),
+    };
+
+    let ptr = UserPtr::<u64, Out>::new(addr);
+    for (i, word) in set.iter().take(nfds.div_ceil(64)).enumerate() {
+        ptr.add(i).write(word)?;
+    }
+
+    Ok(())
+}
+
+pub fn sys_pselect(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let nfds = args[0] as usize;
+    let timeout = UserPtr::<Timespec, In>::new(args[4]);
+    // there are no signals yet so the signal mask in args[5] is ignored
+
+    if nfds > FD_SETSIZE {
+        return EINVAL.into_inner_result() as u64;
+    }
+
+    let mut sets = [None; 3];
+    for (set, &addr) in sets.iter_mut().zip(&args[1..4]) {
+        *set = match read_fd_set(addr, nfds) {
+            Ok(set) => set,
+            Err(err) => return err.into_inner_result() as u64,
+        };
+    }
+
+    // a null timeout waits forever
+    let timeout = match timeout.is_null() {
+        true => None,
+        false => match timeout.read().and_then(|ts| timespec_to_ms(&ts)) {
+            Ok(ms) => Some(ms),
+            Err(err) => return err.into_inner_result() as u64,
+        },
+    };
+
+    let [mut read_set, mut write_set, mut except_set] = sets;
+    let ready = match syscalls::io::pselect::pselect(
+        proc,
+        nfds,
+        &mut read_set,
+        &mut write_set,
+        &mut except_set,
+        timeout,
+    ) {
+        Ok(ready) => ready,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    for (set, &addr) in [read_set, write_set, except_set].iter().zip(&args[1..4]) {
+        if let Err(err) = write_fd_set(addr, nfds, set) {
+            return err.into_inner_result() as u64;
+        }
+    }
+
+    ready as u64
 }
 
 pub fn sys_fd2path(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     let fd = args[0] as usize;
-    let ptr = args[1] as *mut u8;
-    let len = args[2] as usize;
+    let ptr = match UserSlice::<Out>::new(args[1], args[2] as usize) {
+        Ok(ptr) => ptr,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    // paths are never longer than PATH_MAX
+    let mut buff = vec![0; ptr.len().min(PATH_MAX)];
+    let n = match syscalls::io::fd2path::fd2path(proc, fd, &mut buff) {
+        Ok(n) => n,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match ptr.write(&buff[..n]) {
+        Ok(()) => n as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_getdents64(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let fd = args[0] as usize;
+    let ptr = match UserSlice::<Out>::new(args[1], args[2] as usize) {
+        Ok(ptr) => ptr,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    // the entries that do not fit in the bounce buffer are returned by the next call
+    let mut buff = vec![0; ptr.len().min(BOUNCE_BUFFER_SIZE)];
+    let n = match syscalls::io::getdents::getdents64(proc, fd, &mut buff) {
+        Ok(n) => n,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match ptr.write(&buff[..n]) {
+        Ok(()) => n as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_unlink(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let dirfd = args[0] as isize;
+    let path = UserSlice::<In>::new(args[1], args[2] as usize);
+
+    let path = match path.and_then(|path| path.read_string(PATH_MAX)) {
+        Ok(Some(path)) => path,
+        Ok(None) => return ENOENT.into_inner_result() as u64,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match syscalls::io::unlink::unlink(proc, dirfd, &path) {
+        Ok(()) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_rmdir(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let dirfd = args[0] as isize;
+    let path = UserSlice::<In>::new(args[1], args[2] as usize);
 
-    let buff = unsafe { slice::from_raw_parts_mut(ptr, len) };
+    let path = match path.and_then(|path| path.read_string(PATH_MAX)) {
+        Ok(Some(path)) => path,
+        Ok(None) => return ENOENT.into_inner_result() as u64,
+        Err(err) => return err.into_inner_result() as u64,
+    };
 
-    match syscalls::io::fd2path::fd2path(proc, fd, buff) {
-        Ok(val) => val as u64,
+    match syscalls::io::rmdir::rmdir(proc, dirfd, &path) {
+        Ok(()) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_rename(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let old_dirfd = args[0] as isize;
+    let old_path = UserSlice::<In>::new(args[1], args[2] as usize);
+    let new_dirfd = args[3] as isize;
+    let new_path = UserSlice::<In>::new(args[4], args[5] as usize);
+
+    let old_path = match old_path.and_then(|old_path| old_path.read_string(PATH_MAX)) {
+        Ok(Some(path)) => path,
+        Ok(None) => return ENOENT.into_inner_result() as u64,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    let new_path = match new_path.and_then(|new_path| new_path.read_string(PATH_MAX)) {
+        Ok(Some(path)) => path,
+        Ok(None) => return ENOENT.into_inner_result() as u64,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match syscalls::io::rename::rename(proc, old_dirfd, &old_path, new_dirfd, &new_path) {
+        Ok(()) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_mkdir(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let dirfd = args[0] as isize;
+    let path = UserSlice::<In>::new(args[1], args[2] as usize);
+    let mode = FileOpenMode::from_bits_truncate(args[3] as u32);
+
+    let path = match path.and_then(|path| path.read_string(PATH_MAX)) {
+        Ok(Some(path)) => path,
+        Ok(None) => return ENOENT.into_inner_result() as u64,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match syscalls::io::mkdir::mkdir(proc, dirfd, &path, mode) {
+        Ok(()) => 0,
         Err(err) => err.into_inner_result() as u64,
     }
 }
@@ -1,7 +1,6 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
-use crate::{scheduler::proc::Process, syscalls};
+use crate::{scheduler::proc::Process, sync::Mutex, syscalls};
 
 pub fn sys_mmap(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     let addr = args[0] as usize;
@@ -16,3 +15,24 @@ pub fn sys_mmap(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
         Err(err) => err.into_inner_result() as u64,
     }
 }
+
+pub fn sys_munmap(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let addr = args[0] as usize;
+    let len = args[1] as usize;
+
+    match syscalls::mm::munmap::munmap(proc, addr, len) {
+        Ok(_) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_mprotect(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let addr = args[0] as usize;
+    let len = args[1] as usize;
+    let prot = args[2] as u32;
+
+    match syscalls::mm::mprotect::mprotect(proc, addr, len, prot) {
+        Ok(_) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
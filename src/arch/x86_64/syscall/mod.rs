@@ -1,4 +1,3 @@
 pub mod io;
 pub mod mm;
 pub mod proc;
-pub mod utils;
@@ -1,21 +1,31 @@
-use core::ffi::{c_char, CStr};
-
 use alloc::{slice, string::String, sync::Arc, vec::Vec};
 use bitflags::bitflags;
-use spin::Mutex;
-
-use crate::{posix::Timeval, scheduler::proc::Process, syscalls};
 
-use super::utils;
+use crate::{
+    arch::x86_64::usercopy::{In, Out, UserPtr, UserSlice},
+    limits::{ARG_MAX, PATH_MAX},
+    posix::{
+        errno::{Errno, ENOENT},
+        Timespec, Timeval, FUTEX_CLOCK_REALTIME, FUTEX_PRIVATE_FLAG, FUTEX_WAIT,
+    },
+    scheduler::proc::Process,
+    sync::Mutex,
+    syscalls,
+};
 
 bitflags! {
     pub struct CloneFlags: u64 {
         const CLONE_FILES = 1 << 0;
         const CLONE_VM = 1 << 1;
         const CLONE_VFORK = 1 << 2;
+        /// The child is a thread of the calling process, it needs CLONE_VM and CLONE_FILES
+        const CLONE_THREAD = 1 << 3;
+        const CLONE_SETTLS = 1 << 4;
+        const CLONE_CHILD_CLEARTID = 1 << 5;
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct CloneArgs {
     pub flags: u64,
     pub pidfd: u64,
@@ -79,8 +89,48 @@ pub fn sys_setpgid(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     }
 }
 
+pub fn sys_setuid(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let uid = args[0] as usize;
+
+    match syscalls::proc::setuid::setuid(proc, uid) {
+        Ok(_) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_exit(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let status = args[0] as i32;
+    syscalls::proc::exit::exit_thread(proc, status)
+}
+
+pub fn sys_exit_group(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let status = args[0] as i32;
+    syscalls::proc::exit::exit(proc, status)
+}
+
+pub fn sys_wait4(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let pid = args[0] as isize;
+    let wstatus = UserPtr::<i32, Out>::new(args[1]);
+    let options = args[2] as u32;
+    // TODO: rusage
+
+    let (child_pid, status) = match syscalls::proc::wait4::wait4(proc, pid, options) {
+        Ok(res) => res,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    // the status is only stored if a child was reaped
+    if child_pid != 0 && !wstatus.is_null() {
+        if let Err(err) = wstatus.write(&status) {
+            return err.into_inner_result() as u64;
+        }
+    }
+
+    child_pid as u64
+}
+
 pub fn sys_clone(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
-    let clone_args = args[0] as *const CloneArgs;
+    let clone_args = UserPtr::<CloneArgs, In>::new(args[0]);
     let size = args[1] as usize;
 
     match syscalls::proc::clone::clone(proc, clone_args, size) {
@@ -90,15 +140,24 @@ pub fn sys_clone(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
 }
 
 pub fn sys_execve(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
-    let path = args[0] as *const u8;
-    let path_len = args[1] as usize;
-    let argv = args[2] as *const *const c_char;
-    let envp = args[3] as *const *const c_char;
-
-    let path = utils::get_userspace_string(path, path_len).unwrap();
-
-    let argv = unsafe { parse_c_char_array(argv) };
-    let envp = unsafe { parse_c_char_array(envp) };
+    let path = UserSlice::<In>::new(args[0], args[1] as usize);
+    let argv = UserPtr::new(args[2]);
+    let envp = UserPtr::new(args[3]);
+
+    let path = match path.and_then(|path| path.read_string(PATH_MAX)) {
+        Ok(Some(path)) => path,
+        Ok(None) => return ENOENT.into_inner_result() as u64,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    let argv = match read_string_array(argv) {
+        Ok(argv) => argv,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+    let envp = match read_string_array(envp) {
+        Ok(envp) => envp,
+        Err(err) => return err.into_inner_result() as u64,
+    };
 
     match syscalls::proc::execve::execve(proc, &path, &argv, &envp) {
         Ok(_) => 0,
@@ -106,24 +165,23 @@ pub fn sys_execve(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     }
 }
 
-unsafe fn parse_c_char_array(arr: *const *const c_char) -> Vec<String> {
+/// Reads a NULL terminated array of strings from userspace
+fn read_string_array(arr: UserPtr<UserPtr<u8, In>, In>) -> Result<Vec<String>, Errno> {
     let mut vec = Vec::new();
+    if arr.is_null() {
+        return Ok(vec);
+    }
 
-    // TODO: error handling
-    // TODO: work with bytes instead of strings
-
-    let mut ptr = arr;
-    let mut c_str = *ptr;
-    while !c_str.is_null() {
-        let str = CStr::from_ptr(c_str).to_str().unwrap();
-        let str = String::from(str);
-        vec.push(str);
+    loop {
+        let str_ptr = arr.add(vec.len()).read()?;
+        if str_ptr.is_null() {
+            break;
+        }
 
-        ptr = ptr.add(1);
-        c_str = *ptr;
+        vec.push(str_ptr.read_c_string(ARG_MAX)?);
     }
 
-    vec
+    Ok(vec)
 }
 
 pub fn sys_archctl(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
@@ -136,11 +194,147 @@ pub fn sys_archctl(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
     }
 }
 
+pub fn sys_nanosleep(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let req = UserPtr::<Timespec, In>::new(args[0]);
+    // there are no signals to interrupt the sleep so the remaining time is never written
+
+    let req = match req.read() {
+        Ok(req) => req,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match syscalls::proc::nanosleep::nanosleep(proc, &req) {
+        Ok(()) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_clock_nanosleep(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let clock_id = args[0] as u32;
+    let flags = args[1] as u32;
+    let req = UserPtr::<Timespec, In>::new(args[2]);
+
+    let req = match req.read() {
+        Ok(req) => req,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match syscalls::proc::nanosleep::clock_nanosleep(proc, clock_id, flags, &req) {
+        Ok(()) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_futex(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let addr = args[0];
+    let op = args[1] as u32;
+    let val = args[2] as u32;
+    let timeout_ptr = UserPtr::<Timespec, In>::new(args[3]);
+
+    // only FUTEX_WAIT has a timeout, without one it waits forever
+    let timeout = if op & !FUTEX_PRIVATE_FLAG & !FUTEX_CLOCK_REALTIME == FUTEX_WAIT
+        && !timeout_ptr.is_null()
+    {
+        let ms = timeout_ptr
+            .read()
+            .and_then(|ts| syscalls::proc::nanosleep::timespec_to_ms(&ts));
+        match ms {
+            Ok(ms) => Some(ms),
+            Err(err) => return err.into_inner_result() as u64,
+        }
+    } else {
+        None
+    };
+
+    match syscalls::proc::futex::futex(proc, addr, op, val, timeout) {
+        Ok(woken) => woken as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_clock_gettime(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let clock_id = args[0] as u32;
+    let tp_ptr = UserPtr::<Timespec, Out>::new(args[1]);
+
+    let mut tp = Timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if let Err(err) = syscalls::proc::clock_gettime::clock_gettime(proc, clock_id, &mut tp) {
+        return err.into_inner_result() as u64;
+    }
+
+    match tp_ptr.write(&tp) {
+        Ok(()) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
 pub fn sys_gettimeofday(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
-    // TODO: validate ptr
-    let tv = unsafe { (args[0] as *mut Timeval).as_mut().unwrap() };
+    let tv_ptr = UserPtr::<Timeval, Out>::new(args[0]);
+
+    let mut tv = Timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    if let Err(err) = syscalls::proc::gettimeofday::gettimeofday(proc, &mut tv) {
+        return err.into_inner_result() as u64;
+    }
+
+    match tv_ptr.write(&tv) {
+        Ok(()) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_rook_info(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let ptr = match UserSlice::<Out>::new(args[0], args[1] as usize) {
+        Ok(ptr) => ptr,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    // the buffer is only as long as the text so the length userspace passed is not allocated
+    let total = match syscalls::proc::rook_info::rook_info(proc.clone(), &mut []) {
+        Ok(n) => n,
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    let mut buff = vec![0; usize::min(ptr.len(), total)];
+    let copied = match syscalls::proc::rook_info::rook_info(proc, &mut buff) {
+        Ok(total) => usize::min(buff.len(), total),
+        Err(err) => return err.into_inner_result() as u64,
+    };
+
+    match ptr.write(&buff[..copied]) {
+        Ok(()) => total as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_sysconf(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let name = args[0] as usize;
+
+    match syscalls::proc::sysconf::sysconf(proc, name) {
+        Ok(val) => val as u64,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_ioperm(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let from = args[0] as usize;
+    let count = args[1] as usize;
+    let turn_on = args[2] != 0;
+
+    match syscalls::proc::ioperm::ioperm(proc, from, count, turn_on) {
+        Ok(_) => 0,
+        Err(err) => err.into_inner_result() as u64,
+    }
+}
+
+pub fn sys_iopl(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let level = args[0] as usize;
 
-    match syscalls::proc::gettimeofday::gettimeofday(proc, tv) {
+    match syscalls::proc::ioperm::iopl(proc, level) {
         Ok(_) => 0,
         Err(err) => err.into_inner_result() as u64,
     }
@@ -1,3 +1,7 @@
+use crate::limits::CPU_MAX;
+
+use super::smp::current_cpu;
+
 #[repr(C, packed)]
 pub struct TaskStateSegment {
     pub __reserved_0: u32,
@@ -15,6 +19,36 @@ pub struct TaskStateSegment {
     pub __reserved_2: u64,
     pub __reserved_3: u16,
     pub io_map_base_addr: u16,
+    /// A set bit denies access to the port in ring 3 unless IOPL allows it
+    pub io_bitmap: IoBitmap,
+    /// The CPU may read a byte past the bitmap, it must have every bit set
+    pub io_bitmap_end: u8,
+}
+
+/// The bitmap starts right after the fields defined by the architecture
+const IO_BITMAP_OFFSET: u16 = 104;
+
+/// Number of I/O ports, every port has a bit in the I/O permission bitmap
+pub const IO_PORT_COUNT: usize = 65536;
+pub const IO_BITMAP_SIZE: usize = IO_PORT_COUNT / 8;
+
+pub type IoBitmap = [u8; IO_BITMAP_SIZE];
+
+/// Every port is denied
+pub const IO_BITMAP_DENY_ALL: IoBitmap = [0xFF; IO_BITMAP_SIZE];
+
+/// Allows or denies access to `count` ports starting at `from`
+pub fn io_bitmap_set_range(bitmap: &mut IoBitmap, from: usize, count: usize, allow: bool) {
+    assert!(from + count <= IO_PORT_COUNT);
+
+    for port in from..from + count {
+        let mask = 1 << (port % 8);
+        if allow {
+            bitmap[port / 8] &= !mask;
+        } else {
+            bitmap[port / 8] |= mask;
+        }
+    }
 }
 
 impl TaskStateSegment {
@@ -34,9 +68,56 @@ impl TaskStateSegment {
             ist7: 0,
             __reserved_2: 0,
             __reserved_3: 0,
-            io_map_base_addr: 0,
+            io_map_base_addr: IO_BITMAP_OFFSET,
+            io_bitmap: IO_BITMAP_DENY_ALL,
+            io_bitmap_end: 0xFF,
         }
     }
 }
 
-pub static mut TSS: TaskStateSegment = TaskStateSegment::zero();
+const TSS_ZERO: TaskStateSegment = TaskStateSegment::zero();
+
+/// The TSS of every CPU, indexed by the CPU number
+pub static mut TSS: [TaskStateSegment; CPU_MAX] = [TSS_ZERO; CPU_MAX];
+
+/// Whether the I/O bitmap in the TSS of the CPU allows any port
+static mut IO_BITMAP_LOADED: [bool; CPU_MAX] = [false; CPU_MAX];
+
+/// Loads the I/O permissions of a thread into the TSS of the current CPU, None denies every
+/// port. Threads without permissions are the common case so the bitmap is only
+/// copied if it changes.
+pub unsafe fn load_io_bitmap(bitmap: Option<&IoBitmap>) {
+    let cpu = current_cpu();
+    match bitmap {
+        Some(bitmap) => {
+            TSS[cpu].io_bitmap = *bitmap;
+            IO_BITMAP_LOADED[cpu] = true;
+        }
+        None if IO_BITMAP_LOADED[cpu] => {
+            TSS[cpu].io_bitmap = IO_BITMAP_DENY_ALL;
+            IO_BITMAP_LOADED[cpu] = false;
+        }
+        None => {}
+    }
+}
+
+/// Sets the stack the current CPU uses when entering the kernel from userspace
+pub unsafe fn set_kernel_stack(stack_bottom: u64) {
+    TSS[current_cpu()].rsp0 = stack_bottom;
+}
+
+/// Sets the stack __cpu__ switches to for interrupts whose IDT entry selects the
+/// interrupt stack table entry __ist__
+pub unsafe fn set_interrupt_stack(cpu: usize, ist: u8, stack_bottom: u64) {
+    let tss = &mut TSS[cpu];
+    match ist {
+        1 => tss.ist1 = stack_bottom,
+        2 => tss.ist2 = stack_bottom,
+        3 => tss.ist3 = stack_bottom,
+        4 => tss.ist4 = stack_bottom,
+        5 => tss.ist5 = stack_bottom,
+        6 => tss.ist6 = stack_bottom,
+        7 => tss.ist7 = stack_bottom,
+        _ => panic!("invalid IST index {}", ist),
+    }
+}
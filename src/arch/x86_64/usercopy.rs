@@ -0,0 +1,374 @@
+//! User memory is only accessed through `UserPtr` and `UserSlice`, they carry the address
+//! userspace passed and whether the kernel may read or write it. The address is validated
+//! when the memory is accessed and the range of a slice also when it is created, faults while
+//! copying are turned into EFAULT. With SMAP the
+//! kernel faults on user pages outside of a `UserAccess`.
+
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem::{size_of, MaybeUninit},
+};
+
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::{
+    arch::x86_64::{clac, get_rflags, stac, Rflags},
+    limits::IOV_MAX,
+    posix::{
+        errno::{Errno, EFAULT, EINVAL, ENAMETOOLONG},
+        IoVec,
+    },
+};
+
+extern "C" {
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+// the lower half of the address space belongs to userspace
+pub const USERSPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Lets the kernel access user pages until it is dropped, the few places that can not go
+/// through `UserPtr` like the loader of executables use it directly. They can be nested, only
+/// the outermost one ends the access.
+pub struct UserAccess {
+    nested: bool,
+}
+
+impl UserAccess {
+    pub fn begin() -> UserAccess {
+        let nested = get_rflags().contains(Rflags::ALIGNMENT_CHECK);
+        stac();
+        UserAccess { nested }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if !self.nested {
+            clac();
+        }
+    }
+}
+
+/// Returns whether the range [addr, addr + len) is in the userspace half of the address space
+pub fn is_userspace_range(addr: u64, len: usize) -> bool {
+    match addr.checked_add(len as u64) {
+        Some(end) => addr != 0 && end <= USERSPACE_END,
+        None => false,
+    }
+}
+
+/// Copies `dst.len()` bytes from userspace into `dst`, returns EFAULT if `src` is not a valid
+/// userspace address or the memory it points to is not mapped
+fn copy_from_user(dst: &mut [u8], src: *const u8) -> Result<(), Errno> {
+    if dst.is_empty() {
+        return Ok(());
+    }
+
+    if !is_userspace_range(src as u64, dst.len()) {
+        return Err(EFAULT);
+    }
+
+    let _access = UserAccess::begin();
+    match unsafe { __copy_user(dst.as_mut_ptr(), src, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+/// Copies `src` to userspace, returns EFAULT if `dst` is not a valid userspace address
+/// or the memory it points to is not mapped
+fn copy_to_user(dst: *mut u8, src: &[u8]) -> Result<(), Errno> {
+    if src.is_empty() {
+        return Ok(());
+    }
+
+    if !is_userspace_range(dst as u64, src.len()) {
+        return Err(EFAULT);
+    }
+
+    let _access = UserAccess::begin();
+    match unsafe { __copy_user(dst, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+/// The kernel only reads the memory
+pub enum In {}
+/// The kernel only writes the memory
+pub enum Out {}
+/// The kernel reads and writes the memory
+pub enum InOut {}
+
+pub trait Readable {}
+pub trait Writable {}
+
+impl Readable for In {}
+impl Readable for InOut {}
+impl Writable for Out {}
+impl Writable for InOut {}
+
+/// A pointer to a `T` in userspace, `A` is the access the syscall needs
+#[repr(transparent)]
+pub struct UserPtr<T, A> {
+    addr: u64,
+    _marker: PhantomData<(*const T, A)>,
+}
+
+impl<T, A> UserPtr<T, A> {
+    pub const fn new(addr: u64) -> UserPtr<T, A> {
+        UserPtr {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    /// Returns a pointer to the `count`th element after this one
+    pub fn add(&self, count: usize) -> UserPtr<T, A> {
+        let off = (count as u64).wrapping_mul(size_of::<T>() as u64);
+        UserPtr::new(self.addr.wrapping_add(off))
+    }
+}
+
+impl<T: Copy, A: Readable> UserPtr<T, A> {
+    pub fn read(&self) -> Result<T, Errno> {
+        let mut val = MaybeUninit::<T>::uninit();
+        let buff =
+            unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+
+        copy_from_user(buff, self.addr as *const u8)?;
+
+        Ok(unsafe { val.assume_init() })
+    }
+}
+
+impl<T, A: Writable> UserPtr<T, A> {
+    pub fn write(&self, val: &T) -> Result<(), Errno> {
+        let buff =
+            unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(self.addr as *mut u8, buff)
+    }
+}
+
+impl<A: Readable> UserPtr<u8, A> {
+    /// Reads a NUL terminated string that is at most `max_len` bytes long without the NUL
+    pub fn read_c_string(&self, max_len: usize) -> Result<String, Errno> {
+        let mut buff = Vec::new();
+
+        loop {
+            let byte = self.add(buff.len()).read()?;
+            if byte == 0 {
+                break;
+            }
+
+            if buff.len() == max_len {
+                return Err(ENAMETOOLONG);
+            }
+
+            buff.push(byte);
+        }
+
+        String::from_utf8(buff).map_err(|_| EINVAL)
+    }
+}
+
+impl<T, A> Clone for UserPtr<T, A> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, A> Copy for UserPtr<T, A> {}
+
+impl<T, A> fmt::Debug for UserPtr<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UserPtr({:#x})", self.addr)
+    }
+}
+
+/// A buffer of `len` bytes in userspace, `A` is the access the syscall needs. The range is
+/// checked when it is created, the memory is copied in pieces at an offset so a syscall never
+/// has to allocate a buffer as long as userspace asked for.
+pub struct UserSlice<A> {
+    addr: u64,
+    len: usize,
+    _marker: PhantomData<A>,
+}
+
+impl<A> UserSlice<A> {
+    /// Returns EFAULT if the buffer is not in userspace. Empty buffers and null pointers are
+    /// accepted, the syscalls handle them.
+    pub fn new(addr: u64, len: usize) -> Result<UserSlice<A>, Errno> {
+        if addr != 0 && len != 0 && !is_userspace_range(addr, len) {
+            return Err(EFAULT);
+        }
+
+        Ok(UserSlice {
+            addr,
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the address of __count__ bytes at __off__, they have to be inside the buffer
+    fn range(&self, off: usize, count: usize) -> u64 {
+        assert!(off.checked_add(count).is_some_and(|end| end <= self.len));
+        self.addr + off as u64
+    }
+}
+
+impl<A: Readable> UserSlice<A> {
+    /// Copies the bytes at __off__ into __dst__
+    pub fn read_at(&self, off: usize, dst: &mut [u8]) -> Result<(), Errno> {
+        let addr = self.range(off, dst.len());
+        copy_from_user(dst, addr as *const u8)
+    }
+
+    /// Copies the buffer into a string, returns None if the address is null or the buffer
+    /// is empty and ENAMETOOLONG if it is longer than __max_len__
+    pub fn read_string(&self, max_len: usize) -> Result<Option<String>, Errno> {
+        if self.addr == 0 || self.len == 0 {
+            return Ok(None);
+        }
+
+        if self.len > max_len {
+            return Err(ENAMETOOLONG);
+        }
+
+        let mut buff = vec![0; self.len];
+        self.read_at(0, &mut buff)?;
+        match String::from_utf8(buff) {
+            Ok(str) => Ok(Some(str)),
+            Err(_) => Err(EINVAL),
+        }
+    }
+}
+
+impl<A: Writable> UserSlice<A> {
+    /// Copies `src` to the start of the buffer, `src` can not be longer than the buffer
+    pub fn write(&self, src: &[u8]) -> Result<(), Errno> {
+        self.write_at(0, src)
+    }
+
+    /// Copies `src` to __off__, it has to fit in the buffer
+    pub fn write_at(&self, off: usize, src: &[u8]) -> Result<(), Errno> {
+        let addr = self.range(off, src.len());
+        copy_to_user(addr as *mut u8, src)
+    }
+}
+
+impl<A> fmt::Debug for UserSlice<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UserSlice({:#x}, {})", self.addr, self.len)
+    }
+}
+
+/// The buffers of an iovec array in userspace, they are accessed as if they were a single
+/// buffer of `len` bytes
+pub struct UserIoVec<A> {
+    slices: Vec<UserSlice<A>>,
+    len: usize,
+}
+
+impl<A> UserIoVec<A> {
+    /// Reads the array of `count` iovecs at `addr`, returns EINVAL if there are more than
+    /// IOV_MAX buffers or their total length overflows and EFAULT if one of them is not in
+    /// userspace
+    pub fn new(addr: u64, count: usize) -> Result<UserIoVec<A>, Errno> {
+        if count > IOV_MAX {
+            return Err(EINVAL);
+        }
+
+        let array = UserPtr::<IoVec, In>::new(addr);
+        let mut slices = Vec::with_capacity(count);
+        let mut len: usize = 0;
+        for i in 0..count {
+            let iov = array.add(i).read()?;
+            len = len.checked_add(iov.iov_len as usize).ok_or(EINVAL)?;
+            if len > isize::MAX as usize {
+                return Err(EINVAL);
+            }
+
+            slices.push(UserSlice::new(iov.iov_base, iov.iov_len as usize)?);
+        }
+
+        Ok(UserIoVec { slices, len })
+    }
+
+    /// Returns the total length of the buffers
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Calls __f__ with the parts of the buffers that make up the __count__ bytes at __off__
+    /// of the whole, along with the offset of each part within those bytes
+    fn for_each_part<F>(&self, off: usize, count: usize, mut f: F) -> Result<(), Errno>
+    where
+        F: FnMut(&UserSlice<A>, usize, usize, usize) -> Result<(), Errno>,
+    {
+        assert!(off.checked_add(count).is_some_and(|end| end <= self.len));
+
+        let mut start = 0;
+        let mut done = 0;
+        for slice in self.slices.iter() {
+            if done == count {
+                break;
+            }
+
+            let end = start + slice.len;
+            if off + done < end {
+                let slice_off = off + done - start;
+                let part = (slice.len - slice_off).min(count - done);
+                f(slice, slice_off, done, part)?;
+                done += part;
+            }
+            start = end;
+        }
+
+        Ok(())
+    }
+}
+
+impl<A: Readable> UserIoVec<A> {
+    /// Copies the bytes at __off__ of the buffers taken as a whole into __dst__
+    pub fn read_at(&self, off: usize, dst: &mut [u8]) -> Result<(), Errno> {
+        self.for_each_part(off, dst.len(), |slice, slice_off, done, part| {
+            slice.read_at(slice_off, &mut dst[done..done + part])
+        })
+    }
+}
+
+impl<A: Writable> UserIoVec<A> {
+    /// Copies `src` to __off__ of the buffers taken as a whole, it has to fit in them
+    pub fn write_at(&self, off: usize, src: &[u8]) -> Result<(), Errno> {
+        self.for_each_part(off, src.len(), |slice, slice_off, done, part| {
+            slice.write_at(slice_off, &src[done..done + part])
+        })
+    }
+}
@@ -0,0 +1,30 @@
+bits 64
+
+; every entry of the exception table is a pair of (faulting instruction, fixup address)
+; if a page fault happens at the faulting instruction and it can't be resolved
+; the page fault handler continues execution at the fixup address
+%macro ex_table_entry 2
+section .ex_table progbits alloc noexec nowrite align=8
+    dq %1, %2
+section .text
+%endmacro
+
+section .text
+global __copy_user:function (__copy_user.end - __copy_user)
+__copy_user:
+    ; rdi = destination
+    ; rsi = source
+    ; rdx = length
+    ; returns the number of bytes that could not be copied
+    mov rcx, rdx
+.copy:
+    rep movsb
+    xor rax, rax
+    ret
+.fault:
+    ; rcx is the number of bytes left
+    mov rax, rcx
+    ret
+.end:
+
+ex_table_entry __copy_user.copy, __copy_user.fault
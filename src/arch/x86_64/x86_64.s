@@ -1,17 +1,13 @@
 bits 64
 
-extern GDT_DESCRIPTOR
 extern handle_syscall
 extern __block_current_thread
 
-section .data
-temp: dq 0
-thread_regs_ptr: dq 0
-
 section .text
 global load_gdt:function (load_gdt.end - load_gdt)
 load_gdt:
-    lgdt [GDT_DESCRIPTOR]
+    ; rdi = *GDTDescriptor of the CPU
+    lgdt [rdi]
     ; 0x08 is the kernel code segment
     push 0x08
     lea rax, [rel .reload_segments]
@@ -37,11 +33,16 @@ load_gdt:
 
 global x86_64_switch_task:function (x86_64_switch_task.end - x86_64_switch_task)
 x86_64_switch_task:
-    ; rdi = *RegisterState
+    ; rdi = *RegisterState, it is boxed in the thread we are switching to so it stays
+    ; valid while the thread runs. The scheduler already loaded the data segments and the
+    ; FS base of the thread, loading fs again would clear the FS base.
 
-    ; save rdi
-    mov rax, [rdi + 0x28]
-    mov [temp], rax
+    ; push iret params
+    push qword [rdi + 0x98] ; ss
+    push qword [rdi + 0xB8] ; rsp
+    push qword [rdi + 0xA8] ; rflags
+    push qword [rdi + 0xA0] ; cs
+    push qword [rdi + 0xB0] ; rip
 
     ; load general purpose registers
     mov rax, [rdi + 0x00]
@@ -49,7 +50,6 @@ x86_64_switch_task:
     mov rcx, [rdi + 0x10]
     mov rdx, [rdi + 0x18]
     mov rsi, [rdi + 0x20]
-    ; we will set rdi later
     mov r8,  [rdi + 0x30]
     mov r9,  [rdi + 0x38]
     mov r10, [rdi + 0x40]
@@ -59,57 +59,31 @@ x86_64_switch_task:
     mov r14, [rdi + 0x60]
     mov r15, [rdi + 0x68]
     mov rbp, [rdi + 0x70]
-
-    ; save rax before we set segments and push iret params
-    push rax
-
-    ; set segments
-    mov rax, [rdi + 0x78]
-    mov es, rax
-    mov rax, [rdi + 0x80]
-    mov ds, rax
-    mov rax, [rdi + 0x88]
-    mov fs, rax
-    mov rax, [rdi + 0x90]
-    mov gs, rax
-
-    ; push iret params
-    ; ss
-    mov rax, [rdi + 0x98]
-    push rax
-    ; rsp
-    mov rax, [rdi + 0xB8]
-    push rax
-    ; rflags
-    mov rax, [rdi + 0xA8]
-    push rax
-    ; cs
-    mov rax, [rdi + 0xA0]
-    push rax
-    ; rip
-    mov rax, [rdi + 0xB0]
-    push rax
-
-    ; load rax
-    mov rax, [rsp + 5 * 8]
-
-    ; load rdi
-    mov rdi, [temp]
+    ; rdi is loaded last
+    mov rdi, [rdi + 0x28]
 
     iretq
 .end:
 
 global __handle_syscall:function (__handle_syscall.end - __handle_syscall)
 __handle_syscall:
+    ; syscalls from userspace arrive on the kernel stack of the current thread because
+    ; the CPU switches to TSS.rsp0 of the CPU which the scheduler keeps up to date
+
     ; set segments
-    mov [temp], rax
+    push rax
     mov ax, 0x10
     mov es, ax
     mov ds, ax
     mov fs, ax
     mov gs, ax
     mov ss, ax
-    mov rax, [temp]
+    pop rax
+
+    ; userspace controls the direction and alignment check flags, the kernel expects both
+    ; of them to be cleared, the original flags are restored by iretq
+    push qword 0x2
+    popfq
 
     push rbp
     push r15
@@ -146,6 +120,8 @@ __handle_syscall:
     pop r15
     pop rbp
 
+    ; handle_syscall replaced the saved registers with the user state of the thread and rax
+    ; with the result so no kernel value reaches userspace
     iretq
 .end:
 
@@ -0,0 +1,156 @@
+//! The audit log records security relevant syscalls, it can be read from /proc/audit.
+//! Only the newest records are kept, the sequence numbers show how many were dropped.
+
+use alloc::{collections::VecDeque, format, string::String};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+
+use crate::{posix::errno::Errno, scheduler::proc::Process, time};
+
+/// Maximum number of records that are kept, the oldest one is dropped to make room
+const AUDIT_LOG_MAX: usize = 256;
+
+/// Opening a file under these directories for writing is recorded
+const SENSITIVE_PATHS: &[&str] = &["/bin", "/boot", "/etc", "/proc/sys", "/sbin"];
+
+static AUDIT_ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn is_enabled() -> bool {
+    AUDIT_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    AUDIT_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+pub enum AuditEvent {
+    Exec {
+        path: String,
+    },
+    SetUid {
+        uid: usize,
+    },
+    Mount {
+        device: String,
+        mount_point: String,
+        fs_type: String,
+    },
+    /// A sensitive file was opened for writing
+    OpenWrite {
+        path: String,
+    },
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditEvent::Exec { path } => write!(f, "type=exec path={}", path),
+            AuditEvent::SetUid { uid } => write!(f, "type=setuid uid={}", uid),
+            AuditEvent::Mount {
+                device,
+                mount_point,
+                fs_type,
+            } => write!(
+                f,
+                "type=mount dev={} dir={} fs={}",
+                device, mount_point, fs_type
+            ),
+            AuditEvent::OpenWrite { path } => write!(f, "type=open_write path={}", path),
+        }
+    }
+}
+
+struct AuditRecord {
+    seq: u64,
+    ms: u64,
+    /// The process that made the syscall, None if the kernel did it on its own
+    pid: Option<usize>,
+    uid: usize,
+    euid: usize,
+    event: AuditEvent,
+    result: Result<(), Errno>,
+}
+
+struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    next_seq: u64,
+}
+
+static AUDIT_LOG: Mutex<AuditLog> = Mutex::new(AuditLog {
+    records: VecDeque::new(),
+    next_seq: 0,
+});
+
+/// Returns whether writing to __path__ is recorded, __path__ has to be absolute
+pub fn is_sensitive_path(path: &str) -> bool {
+    SENSITIVE_PATHS.iter().any(|dir| {
+        path.strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Records __event__ done by __proc__ or by the kernel if it is None, __result__ is what the
+/// syscall returned
+pub fn record(proc: Option<&Process>, event: AuditEvent, result: Result<(), Errno>) {
+    if !is_enabled() {
+        return;
+    }
+
+    let (pid, uid, euid) = match proc {
+        Some(p) => (Some(p.pid), p.uid, p.euid),
+        None => (None, 0, 0),
+    };
+
+    let mut log = AUDIT_LOG.lock();
+    if log.records.len() == AUDIT_LOG_MAX {
+        log.records.pop_front();
+    }
+
+    let seq = log.next_seq;
+    log.next_seq += 1;
+    log.records.push_back(AuditRecord {
+        seq,
+        ms: time::elapsed().as_milliseconds(),
+        pid,
+        uid,
+        euid,
+        event,
+        result,
+    });
+}
+
+/// The contents of /proc/audit, one record per line from the oldest to the newest
+pub fn proc_audit() -> String {
+    let log = AUDIT_LOG.lock();
+    let mut s = String::new();
+    for record in log.records.iter() {
+        let pid = match record.pid {
+            Some(pid) => format!("{}", pid),
+            None => String::from("kernel"),
+        };
+        let result = match record.result {
+            Ok(()) => 0,
+            Err(errno) => errno.into_inner_result(),
+        };
+
+        writeln!(
+            s,
+            "{} {}.{:0>3} pid={} uid={} euid={} {} res={}",
+            record.seq,
+            record.ms / 1000,
+            record.ms % 1000,
+            pid,
+            record.uid,
+            record.euid,
+            record.event,
+            result
+        )
+        .unwrap();
+    }
+
+    s
+}
@@ -0,0 +1,281 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+
+use crate::mm::kalloc::{self, HeapTag};
+
+use super::{
+    sector_buf::{SectorBuf, SectorVec},
+    BlockDevice, BlockDeviceError, IORequest, LinearBlockAddress, BLOCK_SIZE,
+};
+
+/// Maximum number of blocks kept in memory
+const BLOCK_CACHE_CAPACITY: usize = 512;
+
+/// Maximum number of consecutive dirty blocks written back in one request
+const MAX_FLUSH_RUN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct CacheKey {
+    major: usize,
+    minor: usize,
+    lba: usize,
+}
+
+#[derive(Debug)]
+struct CachedBlock {
+    /// Device the block is written back to
+    device: Weak<BlockDevice>,
+    data: Box<SectorBuf>,
+    /// The block was modified and the device has not been updated yet
+    dirty: bool,
+    /// Key of the block in the LRU list
+    stamp: u64,
+}
+
+/// Write-back cache of the blocks of every block device, when the cache is full
+/// the least recently used block is evicted
+#[derive(Debug)]
+struct BlockCache {
+    blocks: BTreeMap<CacheKey, CachedBlock>,
+    /// Blocks ordered by the time they were last used, the first one is evicted first
+    lru: BTreeMap<u64, CacheKey>,
+    next_stamp: u64,
+}
+
+unsafe impl Send for BlockCache {}
+
+static BLOCK_CACHE: Mutex<BlockCache> = Mutex::new(BlockCache::new());
+
+impl CacheKey {
+    fn new(device: &BlockDevice, lba: usize) -> CacheKey {
+        CacheKey {
+            major: device.major,
+            minor: device.minor,
+            lba,
+        }
+    }
+
+    fn same_device(&self, device: &BlockDevice) -> bool {
+        self.major == device.major && self.minor == device.minor
+    }
+}
+
+impl BlockCache {
+    const fn new() -> BlockCache {
+        BlockCache {
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            next_stamp: 0,
+        }
+    }
+
+    /// Moves a block to the end of the LRU list
+    fn touch(&mut self, key: CacheKey) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+
+        let block = self.blocks.get_mut(&key).unwrap();
+        self.lru.remove(&block.stamp);
+        block.stamp = stamp;
+        self.lru.insert(stamp, key);
+    }
+
+    /// Returns the cached copy of a block and marks it used
+    fn get(&mut self, key: CacheKey) -> Option<&mut CachedBlock> {
+        if !self.blocks.contains_key(&key) {
+            return None;
+        }
+
+        self.touch(key);
+        self.blocks.get_mut(&key)
+    }
+
+    /// Puts a block in the cache, evicts the least recently used block if the cache is full
+    fn insert(
+        &mut self,
+        key: CacheKey,
+        device: &Arc<BlockDevice>,
+        data: &[u8],
+        dirty: bool,
+    ) -> Result<(), BlockDeviceError> {
+        if let Some(block) = self.get(key) {
+            block.data.copy_from_slice(data);
+            block.dirty |= dirty;
+            return Ok(());
+        }
+
+        if self.blocks.len() >= BLOCK_CACHE_CAPACITY {
+            self.evict()?;
+        }
+
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+
+        let mut block_data = Box::new(SectorBuf::zeroed());
+        block_data.copy_from_slice(data);
+
+        self.blocks.insert(
+            key,
+            CachedBlock {
+                device: Arc::downgrade(device),
+                data: block_data,
+                dirty,
+                stamp,
+            },
+        );
+        self.lru.insert(stamp, key);
+
+        Ok(())
+    }
+
+    /// Removes the least recently used block, it is written back first if it is dirty
+    fn evict(&mut self) -> Result<(), BlockDeviceError> {
+        let (_, key) = self.lru.pop_first().expect("Block cache is empty");
+        let mut block = self.blocks.remove(&key).unwrap();
+
+        if block.dirty {
+            // the device is gone if it can not be upgraded, there is nothing to write back to
+            if let Some(device) = block.device.upgrade() {
+                device
+                    .operations
+                    .write(block.data.request(LinearBlockAddress::new(key.lba)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes every dirty block of the device back, consecutive blocks are written together
+    fn flush(&mut self, device: &BlockDevice) -> Result<(), BlockDeviceError> {
+        let dirty_keys: Vec<CacheKey> = self
+            .blocks
+            .iter()
+            .filter(|(key, block)| key.same_device(device) && block.dirty)
+            .map(|(key, _)| *key)
+            .collect();
+
+        let mut idx = 0;
+        while idx < dirty_keys.len() {
+            let start = dirty_keys[idx].lba;
+            let mut count = 1;
+            while idx + count < dirty_keys.len()
+                && count < MAX_FLUSH_RUN
+                && dirty_keys[idx + count].lba == start + count
+            {
+                count += 1;
+            }
+
+            let mut buff = SectorVec::zeroed(count);
+            for (i, key) in dirty_keys[idx..idx + count].iter().enumerate() {
+                let block = &self.blocks[key];
+                buff[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE].copy_from_slice(&block.data[..]);
+            }
+
+            device
+                .operations
+                .write(buff.request(LinearBlockAddress::new(start)))?;
+
+            for key in dirty_keys[idx..idx + count].iter() {
+                self.blocks.get_mut(key).unwrap().dirty = false;
+            }
+
+            idx += count;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads blocks through the cache, the device is only accessed if a block is not cached
+pub fn read(device: &Arc<BlockDevice>, req: IORequest) -> Result<(), BlockDeviceError> {
+    let _tag = kalloc::tag(HeapTag::Blk);
+    let mut cache = BLOCK_CACHE.lock();
+    let start = req.lba.inner();
+
+    let all_cached =
+        (0..req.size).all(|i| cache.blocks.contains_key(&CacheKey::new(device, start + i)));
+
+    if !all_cached {
+        // TODO: only read the missing blocks
+        device.operations.read(IORequest::new(
+            LinearBlockAddress::new(start),
+            req.size,
+            &mut req.buff[..],
+        ))?;
+    }
+
+    for (i, buff) in req.buff.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+        let key = CacheKey::new(device, start + i);
+        match cache.get(key) {
+            // the cached copy is newer if the block is dirty
+            Some(block) => buff.copy_from_slice(&block.data[..]),
+            None => cache.insert(key, device, buff, false)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes blocks into the cache, they reach the device when they are flushed or evicted
+pub fn write(device: &Arc<BlockDevice>, req: IORequest) -> Result<(), BlockDeviceError> {
+    let _tag = kalloc::tag(HeapTag::Blk);
+    let mut cache = BLOCK_CACHE.lock();
+    let start = req.lba.inner();
+
+    for (i, buff) in req.buff.chunks_exact(BLOCK_SIZE).enumerate() {
+        cache.insert(CacheKey::new(device, start + i), device, buff, true)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the dirty blocks of a device back
+pub fn flush(device: &BlockDevice) -> Result<(), BlockDeviceError> {
+    BLOCK_CACHE.lock().flush(device)
+}
+
+/// Writes the dirty blocks of every device back
+pub fn flush_all() -> Result<(), BlockDeviceError> {
+    let mut cache = BLOCK_CACHE.lock();
+    let devices = {
+        let mut devices: Vec<Arc<BlockDevice>> = Vec::new();
+        for block in cache.blocks.values().filter(|block| block.dirty) {
+            let device = match block.device.upgrade() {
+                Some(device) => device,
+                None => continue,
+            };
+
+            if !devices.iter().any(|dev| Arc::ptr_eq(dev, &device)) {
+                devices.push(device);
+            }
+        }
+        devices
+    };
+
+    for device in devices {
+        cache.flush(&device)?;
+    }
+
+    Ok(())
+}
+
+/// Drops every cached block of a device without writing the dirty ones back,
+/// this must be called when the contents of the device change under the cache
+pub fn invalidate(device: &BlockDevice) {
+    let mut cache = BLOCK_CACHE.lock();
+    let BlockCache { blocks, lru, .. } = &mut *cache;
+
+    blocks.retain(|key, block| {
+        if !key.same_device(device) {
+            return true;
+        }
+
+        lru.remove(&block.stamp);
+        false
+    });
+}
@@ -0,0 +1,225 @@
+//! Device nodes of the block devices and their partitions under /dev, a disk is named after
+//! the prefix its driver chose and its index, e.g. hda, and its partitions are numbered from 1,
+//! e.g. hda1. The nodes read and write through the block cache at any byte offset.
+
+use alloc::{
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::{Mutex, Once};
+
+use crate::{
+    fs::{
+        devfs::{self, DevFsDevice},
+        errors::{FsIoctlError, FsReadError, FsStatError, FsWriteError},
+        path::Path,
+    },
+    posix::{Stat, S_IFBLK},
+};
+
+use super::{cache, sector_buf::SectorBuf, BlockDevice, LinearBlockAddress, Partition, BLOCK_SIZE};
+
+/// The devfs major of every block device node, the minor is the index of the node
+const BLOCK_NODE_MAJOR: u16 = 3;
+
+enum BlockNodeTarget {
+    Disk(Arc<BlockDevice>),
+    Partition(Arc<Partition>),
+}
+
+struct BlockNode {
+    name: String,
+    device: Arc<BlockDevice>,
+    /// First block of the node on the device
+    start: usize,
+    /// Size of the node in blocks
+    size: usize,
+    target: BlockNodeTarget,
+}
+
+struct BlockNodes(Vec<Arc<BlockNode>>);
+
+// the nodes are only accessed with the lock held
+unsafe impl Send for BlockNodes {}
+
+static BLOCK_NODES: Mutex<BlockNodes> = Mutex::new(BlockNodes(Vec::new()));
+static OPERATIONS_REGISTERED: Once<()> = Once::new();
+
+struct BlockNodeOperations;
+
+fn get_node(minor: u16) -> Option<Arc<BlockNode>> {
+    BLOCK_NODES.lock().0.get(minor as usize).cloned()
+}
+
+impl BlockNode {
+    /// Returns the number of bytes starting at __off__ that can be accessed out of __len__
+    fn clamp(&self, off: usize, len: usize) -> usize {
+        let size = self.size * BLOCK_SIZE;
+        len.min(size.saturating_sub(off))
+    }
+
+    fn read_block(&self, block: usize, buff: &mut SectorBuf) -> bool {
+        let lba = LinearBlockAddress::new(self.start + block);
+        cache::read(&self.device, buff.request(lba)).is_ok()
+    }
+
+    fn write_block(&self, block: usize, buff: &mut SectorBuf) -> bool {
+        let lba = LinearBlockAddress::new(self.start + block);
+        cache::write(&self.device, buff.request(lba)).is_ok()
+    }
+}
+
+impl DevFsDevice for BlockNodeOperations {
+    fn read(&self, minor: u16, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        let node = get_node(minor).ok_or(FsReadError::IoError)?;
+        let len = node.clamp(off, buff.len());
+
+        let mut block = SectorBuf::zeroed();
+        let mut done = 0;
+        while done < len {
+            let pos = off + done;
+            let block_off = pos % BLOCK_SIZE;
+            let count = (BLOCK_SIZE - block_off).min(len - done);
+
+            if !node.read_block(pos / BLOCK_SIZE, &mut block) {
+                return Err(FsReadError::IoError);
+            }
+            buff[done..done + count].copy_from_slice(&block[block_off..block_off + count]);
+            done += count;
+        }
+
+        Ok(len)
+    }
+
+    fn write(&self, minor: u16, off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        let node = get_node(minor).ok_or(FsWriteError::IoError)?;
+        let len = node.clamp(off, buff.len());
+        if len == 0 && !buff.is_empty() {
+            return Err(FsWriteError::NoSpace);
+        }
+
+        let mut block = SectorBuf::zeroed();
+        let mut done = 0;
+        while done < len {
+            let pos = off + done;
+            let block_off = pos % BLOCK_SIZE;
+            let count = (BLOCK_SIZE - block_off).min(len - done);
+
+            // the rest of a partially written block has to be kept
+            if count < BLOCK_SIZE && !node.read_block(pos / BLOCK_SIZE, &mut block) {
+                return Err(FsWriteError::IoError);
+            }
+            block[block_off..block_off + count].copy_from_slice(&buff[done..done + count]);
+            if !node.write_block(pos / BLOCK_SIZE, &mut block) {
+                return Err(FsWriteError::IoError);
+            }
+            done += count;
+        }
+
+        Ok(len)
+    }
+
+    fn ioctl(&self, _minor: u16, _req: usize, _arg: usize) -> Result<usize, FsIoctlError> {
+        Err(FsIoctlError::NotATerminal)
+    }
+
+    fn stat(&self, minor: u16, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        let size = get_node(minor).map_or(0, |node| node.size);
+
+        stat_buf.st_blksize = BLOCK_SIZE as u64;
+        stat_buf.st_blocks = size as u64;
+        stat_buf.st_size = (size * BLOCK_SIZE) as u64;
+        stat_buf.st_dev = 0;
+        stat_buf.st_rdev = (BLOCK_NODE_MAJOR as u64) << 8 | minor as u64;
+        stat_buf.st_gid = 0;
+        stat_buf.st_uid = 0;
+        stat_buf.st_nlink = 1;
+        stat_buf.st_mode = S_IFBLK | 0o660;
+
+        Ok(())
+    }
+}
+
+fn add_node(name: String, device: &Arc<BlockDevice>, target: BlockNodeTarget) {
+    let (start, size) = match &target {
+        BlockNodeTarget::Disk(dev) => (0, dev.size),
+        BlockNodeTarget::Partition(part) => (*part.start, part.size),
+    };
+
+    let minor = {
+        let mut nodes = BLOCK_NODES.lock();
+        nodes.0.push(Arc::new(BlockNode {
+            name: name.clone(),
+            device: device.clone(),
+            start,
+            size,
+            target,
+        }));
+        nodes.0.len() - 1
+    };
+
+    let path = format!("/{}", name);
+    if let Err(err) =
+        devfs::register_devfs_node(Path::new(&path).unwrap(), BLOCK_NODE_MAJOR, minor as u16)
+    {
+        warn!("BLK: failed to create /dev/{}: {:?}", name, err);
+    }
+}
+
+/// Creates the device nodes of a newly registered block device and of its partitions
+pub fn register_nodes(device: &Arc<BlockDevice>, partitions: &[Arc<Partition>]) {
+    OPERATIONS_REGISTERED.call_once(|| {
+        devfs::register_devfs_node_operations(BLOCK_NODE_MAJOR, Arc::new(BlockNodeOperations))
+            .unwrap();
+    });
+
+    let index = BLOCK_NODES
+        .lock()
+        .0
+        .iter()
+        .filter(|node| {
+            matches!(&node.target, BlockNodeTarget::Disk(dev)
+                if dev.node_prefix == device.node_prefix)
+        })
+        .count();
+    // hda, hdb, ..., hdz, hdaa, ...
+    let mut suffix = String::new();
+    let mut n = index;
+    loop {
+        suffix.insert(0, (b'a' + (n % 26) as u8) as char);
+        if n < 26 {
+            break;
+        }
+        n = n / 26 - 1;
+    }
+
+    let disk_name = format!("{}{}", device.node_prefix, suffix);
+    log!("BLK: {} is /dev/{}", device.name, disk_name);
+    add_node(
+        disk_name.clone(),
+        device,
+        BlockNodeTarget::Disk(device.clone()),
+    );
+
+    for part in partitions {
+        add_node(
+            format!("{}{}", disk_name, part.part_idx + 1),
+            device,
+            BlockNodeTarget::Partition(part.clone()),
+        );
+    }
+}
+
+/// Returns the partition whose device node is /dev/__name__
+pub fn partition_by_node(name: &str) -> Option<Weak<Partition>> {
+    BLOCK_NODES
+        .lock()
+        .0
+        .iter()
+        .find_map(|node| match &node.target {
+            BlockNodeTarget::Partition(part) if node.name == name => Some(Arc::downgrade(part)),
+            _ => None,
+        })
+}
@@ -1,17 +1,25 @@
 use core::{
     fmt::Debug,
     mem::size_of,
-    ops::{Add, Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use alloc::{
     boxed::Box,
+    string::String,
     sync::{Arc, Weak},
     vec::Vec,
 };
 use spin::Mutex;
 
-pub const BLOCK_SIZE: usize = 512;
+use self::{request::validate_request, sector_buf::SectorBuf};
+
+pub mod cache;
+pub mod devfs;
+mod request;
+pub mod sector_buf;
+
+pub use self::request::{BlockDeviceError, IORequest, LinearBlockAddress, BLOCK_SIZE};
 
 struct BlockDeviceManager {
     block_devices: Vec<Arc<BlockDevice>>,
@@ -53,68 +61,6 @@ struct MBREntry {
     lba_count: u32,
 }
 
-/// Represents a Linear Base Address(sector)
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
-#[repr(transparent)]
-pub struct LinearBlockAddress(usize);
-
-impl LinearBlockAddress {
-    /// Creates a new LBA from a usize
-    pub fn new(lba: usize) -> LinearBlockAddress {
-        LinearBlockAddress(lba)
-    }
-
-    /// Consumes an LBA and returns the inner usize
-    pub fn inner(self) -> usize {
-        self.0
-    }
-}
-
-impl Deref for LinearBlockAddress {
-    type Target = usize;
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for LinearBlockAddress {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
-impl Add for LinearBlockAddress {
-    type Output = LinearBlockAddress;
-    fn add(self, rhs: Self) -> Self::Output {
-        LinearBlockAddress(self.0 + rhs.0)
-    }
-}
-
-/// Represents either a write or read request to a block device
-#[derive(Debug)]
-pub struct IORequest<'a> {
-    /// Start LBA
-    pub lba: LinearBlockAddress,
-
-    /// Size of the request of LBAs
-    pub size: usize,
-
-    /// Buffer to write from/read to, must equal __size__ multiplied by the size
-    /// of an LBA of the target device
-    pub buff: &'a mut [u8],
-}
-
-impl<'a> IORequest<'a> {
-    pub fn new(lba: LinearBlockAddress, size: usize, buff: &'a mut [u8]) -> IORequest<'a> {
-        IORequest { lba, size, buff }
-    }
-}
-
-#[derive(Debug)]
-pub enum BlockDeviceError {
-    FailedToReadSectors,
-}
-
 pub trait BlockOperations: Send + Debug {
     /// Sends a read request
     fn read(&self, req: IORequest) -> Result<(), BlockDeviceError>;
@@ -129,13 +75,44 @@ pub struct BlockDevice {
     pub major: usize,
     pub minor: usize,
     pub name: &'static str,
+    /// Prefix of the names of the device nodes, e.g. hd for /dev/hda
+    pub node_prefix: &'static str,
     pub size: usize,
+    /// Incremented every time the medium is changed, file systems compare it to the value
+    /// they saw when they read their superblock
+    media_generation: AtomicUsize,
+}
+
+impl BlockDevice {
+    /// Returns the number of times the medium was changed since the device was registered
+    pub fn media_generation(&self) -> usize {
+        self.media_generation.load(Ordering::Acquire)
+    }
+
+    /// Notes a media change if the driver reported one
+    fn check_media<T>(&self, res: Result<T, BlockDeviceError>) -> Result<T, BlockDeviceError> {
+        if let Err(BlockDeviceError::MediaChanged) = res {
+            media_changed(self);
+        }
+
+        res
+    }
 }
 
-impl BlockDevice {}
+/// Must be called by the driver of a removable device when its medium is changed or removed.
+/// The cached blocks of the device are dropped and the file systems on it reload their state
+/// the next time they are used.
+pub fn media_changed(dev: &BlockDevice) {
+    warn!("BLK: medium of {} changed", dev.name);
+    cache::invalidate(dev);
+    dev.media_generation.fetch_add(1, Ordering::AcqRel);
+}
 
+/// Registers a block device and its partitions and creates their device nodes, the nodes
+/// are named __node_prefix__ followed by a letter
 pub fn register_blk(
     name: &'static str,
+    node_prefix: &'static str,
     major: usize,
     size: usize,
     operations: Box<dyn BlockOperations>,
@@ -154,11 +131,13 @@ pub fn register_blk(
         major,
         minor,
         name,
+        node_prefix,
         size,
+        media_generation: AtomicUsize::new(0),
     };
 
     let rc = Arc::new(dev);
-    let mut parts = parse_partition_table(rc.clone())
+    let parts = parse_partition_table(rc.clone())
         .into_iter()
         .map(Arc::new)
         .collect::<Vec<Arc<Partition>>>();
@@ -167,8 +146,11 @@ pub fn register_blk(
         log!("{:?}", part);
     }
 
-    blk_dev_manager.block_devices.push(rc);
-    blk_dev_manager.partitions.append(&mut parts);
+    blk_dev_manager.block_devices.push(rc.clone());
+    blk_dev_manager.partitions.extend(parts.iter().cloned());
+    drop(blk_dev_manager);
+
+    devfs::register_nodes(&rc, &parts);
 }
 
 pub fn get_partition(major: usize, minor: usize, part_idx: usize) -> Option<Weak<Partition>> {
@@ -181,34 +163,23 @@ pub fn get_partition(major: usize, minor: usize, part_idx: usize) -> Option<Weak
     part.map(Arc::downgrade)
 }
 
-/// Sends a read request to the target block device
-pub fn blk_read(block_device: &BlockDevice, req: IORequest) -> Result<(), BlockDeviceError> {
-    assert_eq!(req.size % BLOCK_SIZE, 0, "Invalid buffer size");
-    assert_ne!(req.size, 0, "Invalid buffer size");
-    assert_eq!(
-        req.buff.len(),
-        req.size * BLOCK_SIZE,
-        "Invalid buffer and buffer size"
-    );
-    assert!(req.lba.0 < block_device.size, "Invalid LBA");
-    assert!(req.lba.0 + req.size < block_device.size, "Invalid LBA");
-
-    block_device.operations.read(req)
+/// Returns every partition of every block device
+pub fn partitions() -> Vec<Arc<Partition>> {
+    BLOCK_DEVICE_MANAGER.lock().partitions.clone()
 }
 
-/// Sends a write request to the target block device
-pub fn blk_write(block_device: &BlockDevice, req: IORequest) -> Result<(), BlockDeviceError> {
-    assert_eq!(req.size % BLOCK_SIZE, 0, "Invalid buffer size");
-    assert_ne!(req.size, 0, "Invalid buffer size");
-    assert_eq!(
-        req.buff.len(),
-        req.size * BLOCK_SIZE,
-        "Invalid buffer and buffer size"
-    );
-    assert!(req.lba.0 < block_device.size, "Invalid LBA");
-    assert!(req.lba.0 + req.size < block_device.size, "Invalid LBA");
-
-    block_device.operations.write(req)
+/// Sends a read request to the target block device through the block cache
+pub fn blk_read(block_device: &Arc<BlockDevice>, req: IORequest) -> Result<(), BlockDeviceError> {
+    validate_request(&req, block_device.size)?;
+
+    block_device.check_media(cache::read(block_device, req))
+}
+
+/// Sends a write request to the target block device through the block cache
+pub fn blk_write(block_device: &Arc<BlockDevice>, req: IORequest) -> Result<(), BlockDeviceError> {
+    validate_request(&req, block_device.size)?;
+
+    block_device.check_media(cache::write(block_device, req))
 }
 
 #[derive(Debug)]
@@ -228,56 +199,74 @@ pub struct Partition {
 }
 
 impl Partition {
+    /// Returns the name of the partition, the name of the block device followed by
+    /// the partition index
+    pub fn name(&self) -> String {
+        match self.block_device.upgrade() {
+            Some(blk_dev) => format!("{}p{}", blk_dev.name, self.part_idx),
+            None => format!("(removed)p{}", self.part_idx),
+        }
+    }
+
+    /// Returns the block device the partition is on, it fails if the device was removed
+    fn device(&self) -> Result<Arc<BlockDevice>, BlockDeviceError> {
+        self.block_device
+            .upgrade()
+            .ok_or(BlockDeviceError::NoMedium)
+    }
+
+    /// Returns the media generation of the device the partition is on, None if the device
+    /// was removed
+    pub fn media_generation(&self) -> Option<usize> {
+        self.block_device
+            .upgrade()
+            .map(|blk_dev| blk_dev.media_generation())
+    }
+
     pub fn read(&self, req: IORequest) -> Result<(), BlockDeviceError> {
-        let block_dev = self.block_device.upgrade().unwrap();
-
-        assert_ne!(req.size, 0, "Invalid buffer size");
-        assert_eq!(
-            req.buff.len(),
-            req.size * BLOCK_SIZE,
-            "Invalid buffer and buffer size"
-        );
-        assert!(req.lba.0 < self.size, "Invalid LBA");
-        assert!(req.lba.0 + req.size < self.size, "Invalid LBA");
-
-        block_dev.operations.read(IORequest {
-            lba: self.start.clone() + req.lba,
-            size: req.size,
-            buff: req.buff,
-        })
+        let block_dev = self.device()?;
+
+        validate_request(&req, self.size)?;
+
+        block_dev.check_media(cache::read(
+            &block_dev,
+            IORequest {
+                lba: self.start.clone() + req.lba,
+                size: req.size,
+                buff: req.buff,
+            },
+        ))
     }
 
     pub fn write(&self, req: IORequest) -> Result<(), BlockDeviceError> {
-        let block_dev = self.block_device.upgrade().unwrap();
-
-        assert_ne!(req.size, 0, "Invalid buffer size");
-        assert_eq!(
-            req.buff.len(),
-            req.size * BLOCK_SIZE,
-            "Invalid buffer and buffer size"
-        );
-        assert!(req.lba.0 < self.size, "Invalid LBA");
-        assert!(req.lba.0 + req.size < self.size, "Invalid LBA");
-
-        block_dev.operations.write(IORequest {
-            lba: self.start.clone() + req.lba,
-            size: req.size,
-            buff: req.buff,
-        })
+        let block_dev = self.device()?;
+
+        validate_request(&req, self.size)?;
+
+        block_dev.check_media(cache::write(
+            &block_dev,
+            IORequest {
+                lba: self.start.clone() + req.lba,
+                size: req.size,
+                buff: req.buff,
+            },
+        ))
+    }
+
+    /// Writes the cached blocks of the device the partition is on back
+    pub fn flush(&self) -> Result<(), BlockDeviceError> {
+        let block_dev = self.device()?;
+        block_dev.check_media(cache::flush(&block_dev))
     }
 }
 
 fn parse_partition_table(dev: Arc<BlockDevice>) -> Vec<Partition> {
     log!("parse partition table {}", dev.name);
 
-    let mut buff: [u8; 512] = [0; 512];
+    let mut buff: SectorBuf = SectorBuf::zeroed();
 
     dev.operations
-        .read(IORequest {
-            lba: LinearBlockAddress::new(0),
-            size: 1,
-            buff: buff.as_mut_slice(),
-        })
+        .read(buff.request(LinearBlockAddress::new(0)))
         .unwrap();
 
     let mut partitions: Vec<Partition> = Vec::new();
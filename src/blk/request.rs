@@ -0,0 +1,170 @@
+//! Block requests and their validation, this file does not depend on the rest of the kernel
+//! so its tests can be run on the host with `make test`
+
+use core::ops::{Add, Deref, DerefMut};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Represents a Linear Base Address(sector)
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+#[repr(transparent)]
+pub struct LinearBlockAddress(usize);
+
+impl LinearBlockAddress {
+    /// Creates a new LBA from a usize
+    pub fn new(lba: usize) -> LinearBlockAddress {
+        LinearBlockAddress(lba)
+    }
+
+    /// Consumes an LBA and returns the inner usize
+    pub fn inner(self) -> usize {
+        self.0
+    }
+}
+
+impl Deref for LinearBlockAddress {
+    type Target = usize;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for LinearBlockAddress {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Add for LinearBlockAddress {
+    type Output = LinearBlockAddress;
+    fn add(self, rhs: Self) -> Self::Output {
+        LinearBlockAddress(self.0 + rhs.0)
+    }
+}
+
+/// Represents either a write or read request to a block device
+#[derive(Debug)]
+pub struct IORequest<'a> {
+    /// Start LBA
+    pub lba: LinearBlockAddress,
+
+    /// Size of the request of LBAs
+    pub size: usize,
+
+    /// Buffer to write from/read to, must equal __size__ multiplied by the size
+    /// of an LBA of the target device
+    pub buff: &'a mut [u8],
+}
+
+impl<'a> IORequest<'a> {
+    pub fn new(lba: LinearBlockAddress, size: usize, buff: &'a mut [u8]) -> IORequest<'a> {
+        IORequest { lba, size, buff }
+    }
+
+    /// Returns the last LBA of the request, the range of a request includes both __lba__ and
+    /// the returned LBA. Returns None if the request is empty or its end overflows.
+    pub fn last_lba(&self) -> Option<LinearBlockAddress> {
+        let count = self.size.checked_sub(1)?;
+        self.lba.0.checked_add(count).map(LinearBlockAddress)
+    }
+}
+
+/// Checks that __req__ fits in a block device or a partition of __size__ LBAs and that its
+/// buffer holds exactly the requested LBAs. The last LBA of the device is LBA __size__ - 1.
+pub(crate) fn validate_request(req: &IORequest, size: usize) -> Result<(), BlockDeviceError> {
+    let buff_size = req.size.checked_mul(BLOCK_SIZE);
+    if buff_size != Some(req.buff.len()) {
+        return Err(BlockDeviceError::InvalidRequest);
+    }
+
+    match req.last_lba() {
+        Some(last_lba) if last_lba.0 < size => Ok(()),
+        _ => Err(BlockDeviceError::InvalidRequest),
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockDeviceError {
+    FailedToReadSectors,
+    FailedToWriteSectors,
+    /// The request is empty, does not fit in the device or its buffer does not match its size
+    InvalidRequest,
+    /// The medium was changed since the last request, drivers of removable devices
+    /// report this once for every change
+    MediaChanged,
+    /// There is no medium in the device or the device was removed
+    NoMedium,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_SIZE: usize = 16;
+
+    fn validate(lba: usize, size: usize) -> Result<(), BlockDeviceError> {
+        let mut buff = [0; (DEVICE_SIZE + 1) * BLOCK_SIZE];
+        validate_request(
+            &IORequest::new(
+                LinearBlockAddress::new(lba),
+                size,
+                &mut buff[..size * BLOCK_SIZE],
+            ),
+            DEVICE_SIZE,
+        )
+    }
+
+    #[test]
+    fn first_lba() {
+        assert!(validate(0, 1).is_ok());
+        assert!(validate(0, DEVICE_SIZE).is_ok());
+    }
+
+    #[test]
+    fn last_lba() {
+        assert!(validate(DEVICE_SIZE - 1, 1).is_ok());
+        assert!(validate(DEVICE_SIZE - 4, 4).is_ok());
+    }
+
+    #[test]
+    fn one_past_the_end() {
+        assert!(validate(DEVICE_SIZE, 1).is_err());
+        assert!(validate(DEVICE_SIZE - 3, 4).is_err());
+        assert!(validate(0, DEVICE_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn empty_request() {
+        assert!(validate(0, 0).is_err());
+        assert!(validate(DEVICE_SIZE - 1, 0).is_err());
+    }
+
+    #[test]
+    fn overflowing_request() {
+        let mut buff = [0; 2 * BLOCK_SIZE];
+        let req = IORequest::new(LinearBlockAddress::new(usize::MAX), 2, &mut buff);
+        assert!(req.last_lba().is_none());
+        assert!(validate_request(&req, usize::MAX).is_err());
+
+        // the buffer size would overflow too
+        let req = IORequest::new(LinearBlockAddress::new(0), usize::MAX, &mut buff);
+        assert!(validate_request(&req, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn mismatched_buffer() {
+        let mut buff = [0; BLOCK_SIZE];
+        let req = IORequest::new(LinearBlockAddress::new(0), 2, &mut buff);
+        assert!(validate_request(&req, DEVICE_SIZE).is_err());
+    }
+
+    #[test]
+    fn last_lba_is_inclusive() {
+        let mut buff = [0; 3 * BLOCK_SIZE];
+        let req = IORequest::new(LinearBlockAddress::new(5), 3, &mut buff);
+        assert_eq!(req.last_lba(), Some(LinearBlockAddress::new(7)));
+
+        let req = IORequest::new(LinearBlockAddress::new(5), 0, &mut []);
+        assert_eq!(req.last_lba(), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! Buffers for whole blocks that are read from or written to block devices
+
+use core::{
+    ops::{Deref, DerefMut},
+    slice,
+};
+
+use alloc::{boxed::Box, vec};
+
+use super::{IORequest, LinearBlockAddress, BLOCK_SIZE};
+
+// the alignment of SectorBuf has to be a literal
+const _: () = assert!(BLOCK_SIZE == 512);
+
+/// __N__ blocks aligned to the block size so the buffer can be accessed as 16 or 32 bit words.
+///
+/// Buffers are always zeroed: `IORequest` takes a `&mut [u8]` which must never refer to
+/// uninitialized memory even if the request overwrites it, and zeroing a block is cheap
+/// compared to reading it from a device. Use `SectorVec` if the number of blocks is only
+/// known at runtime and `dma::DmaBuf` if a device accesses the buffer directly.
+#[derive(Debug, Clone)]
+#[repr(C, align(512))]
+pub struct SectorBuf<const N: usize = 1>([[u8; BLOCK_SIZE]; N]);
+
+impl<const N: usize> SectorBuf<N> {
+    pub const fn zeroed() -> SectorBuf<N> {
+        SectorBuf([[0; BLOCK_SIZE]; N])
+    }
+
+    /// Returns a request that reads or writes every block of the buffer starting at __lba__
+    pub fn request(&mut self, lba: LinearBlockAddress) -> IORequest<'_> {
+        IORequest::new(lba, N, self)
+    }
+}
+
+impl<const N: usize> Deref for SectorBuf<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_flattened()
+    }
+}
+
+impl<const N: usize> DerefMut for SectorBuf<N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_flattened_mut()
+    }
+}
+
+/// Blocks on the heap whose number is only known at runtime, e.g. a FAT cluster
+pub struct SectorVec(Box<[SectorBuf]>);
+
+impl SectorVec {
+    pub fn zeroed(count: usize) -> SectorVec {
+        SectorVec(vec![SectorBuf::zeroed(); count].into_boxed_slice())
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns a request that reads or writes every block of the buffer starting at __lba__
+    pub fn request(&mut self, lba: LinearBlockAddress) -> IORequest<'_> {
+        let count = self.block_count();
+        IORequest::new(lba, count, self)
+    }
+}
+
+impl Deref for SectorVec {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // a SectorBuf has no padding so the blocks are contiguous
+        unsafe { slice::from_raw_parts(self.0.as_ptr() as *const u8, self.0.len() * BLOCK_SIZE) }
+    }
+}
+
+impl DerefMut for SectorVec {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        let len = self.0.len() * BLOCK_SIZE;
+        unsafe { slice::from_raw_parts_mut(self.0.as_mut_ptr() as *mut u8, len) }
+    }
+}
+
+/// Reads a little endian u32 at __offset__ of __buff__
+pub fn read_u32_le(buff: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buff[offset..offset + 4].try_into().unwrap())
+}
+
+/// Writes __val__ as a little endian u32 at __offset__ of __buff__
+pub fn write_u32_le(buff: &mut [u8], offset: usize, val: u32) {
+    buff[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+}
@@ -0,0 +1,128 @@
+//! Durations of the boot stages, the summary is printed once the init process is started
+//! and can be read from /proc/bootstat later
+
+use alloc::{format, string::String};
+use core::fmt::Write;
+
+use crate::{arch::x86_64::rdtsc, sync::InterruptMutex, time};
+
+/// Maximum number of stages that are recorded, the rest are ignored
+const MAX_STAGES: usize = 64;
+
+/// The TSC frequency is only estimated if the system clock ran for at least this long
+/// between the recorded stages
+const MIN_CALIBRATION_MS: u64 = 10;
+
+#[derive(Clone, Copy)]
+struct BootStage {
+    name: &'static str,
+    driver: bool,
+    tsc: u64,
+    // the system clock only runs once the PIT driver is loaded
+    ms: u64,
+}
+
+#[derive(Clone, Copy)]
+struct BootStats {
+    start_tsc: u64,
+    stages: [BootStage; MAX_STAGES],
+    count: usize,
+}
+
+// the stages before the kernel heap is initialized are recorded too so nothing here allocates
+static BOOT_STATS: InterruptMutex<BootStats> = InterruptMutex::new(BootStats {
+    start_tsc: 0,
+    stages: [BootStage {
+        name: "",
+        driver: false,
+        tsc: 0,
+        ms: 0,
+    }; MAX_STAGES],
+    count: 0,
+});
+
+impl BootStats {
+    fn record(&mut self, name: &'static str, driver: bool) {
+        if self.count == MAX_STAGES {
+            return;
+        }
+
+        self.stages[self.count] = BootStage {
+            name,
+            driver,
+            tsc: rdtsc(),
+            ms: time::elapsed().as_milliseconds(),
+        };
+        self.count += 1;
+    }
+
+    /// Estimates the TSC frequency from the stages recorded while the system clock was running
+    fn cycles_per_ms(&self) -> Option<u64> {
+        let stages = &self.stages[..self.count];
+        let first = stages.iter().find(|stage| stage.ms > 0)?;
+        let last = stages.last()?;
+
+        let ms = last.ms - first.ms;
+        if ms < MIN_CALIBRATION_MS {
+            return None;
+        }
+
+        Some((last.tsc - first.tsc) / ms).filter(|&rate| rate > 0)
+    }
+
+    fn summary(&self) -> String {
+        let cycles_per_ms = self.cycles_per_ms();
+        let duration = |cycles: u64| match cycles_per_ms {
+            Some(rate) => {
+                let us = cycles * 1000 / rate;
+                format!("{:>6}.{:03} ms", us / 1000, us % 1000)
+            }
+            None => format!("{:>12} cycles", cycles),
+        };
+
+        let mut s = String::new();
+        let mut prev_tsc = self.start_tsc;
+        for stage in &self.stages[..self.count] {
+            let name = if stage.driver {
+                format!("driver {}", stage.name)
+            } else {
+                String::from(stage.name)
+            };
+            writeln!(s, "{:<24} {}", name, duration(stage.tsc - prev_tsc)).unwrap();
+            prev_tsc = stage.tsc;
+        }
+        writeln!(s, "{:<24} {}", "total", duration(prev_tsc - self.start_tsc)).unwrap();
+
+        s
+    }
+}
+
+/// Marks the start of the boot, this has to be called before any stage is recorded
+pub fn init() {
+    BOOT_STATS.lock().start_tsc = rdtsc();
+}
+
+/// Marks the end of a boot stage, a stage lasts from the end of the previous one
+pub fn stage_done(name: &'static str) {
+    BOOT_STATS.lock().record(name, false);
+}
+
+/// Marks the end of loading a driver, failed loads are recorded too
+pub fn driver_loaded(name: &'static str) {
+    BOOT_STATS.lock().record(name, true);
+}
+
+/// Prints the duration of every boot stage
+pub fn print_summary() {
+    // the stats are copied so the heap is not used with interrupts disabled
+    let stats = *BOOT_STATS.lock();
+    for line in stats.summary().lines() {
+        log!("boot: {}", line);
+    }
+}
+
+/// Generates the contents of /proc/bootstat
+pub fn proc_bootstat() -> String {
+    let stats = *BOOT_STATS.lock();
+    stats.summary()
+}
@@ -0,0 +1,55 @@
+//! The kernel command line passed by the bootloader, it consists of space separated
+//! `key=value` options and flags without a value
+
+use alloc::string::{String, ToString};
+use spin::Mutex;
+
+/// Maximum length of the command line in bytes, the rest is ignored
+const CMDLINE_MAX: usize = 1024;
+
+struct CommandLine {
+    buff: [u8; CMDLINE_MAX],
+    len: usize,
+}
+
+// the command line is copied before the kernel heap is initialized and the
+// bootloader's memory is unmapped
+static CMDLINE: Mutex<CommandLine> = Mutex::new(CommandLine {
+    buff: [0; CMDLINE_MAX],
+    len: 0,
+});
+
+impl CommandLine {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buff[..self.len]).unwrap_or("")
+    }
+}
+
+pub fn init(cmdline: &[u8]) {
+    let mut inner = CMDLINE.lock();
+    let len = usize::min(cmdline.len(), CMDLINE_MAX);
+    if len < cmdline.len() {
+        warn!("kernel command line is truncated to {} bytes", CMDLINE_MAX);
+    }
+
+    inner.buff[..len].copy_from_slice(&cmdline[..len]);
+    inner.len = len;
+}
+
+/// Returns the value of __key__, flags without a value have an empty value
+pub fn get(key: &str) -> Option<String> {
+    let inner = CMDLINE.lock();
+    inner
+        .as_str()
+        .split_ascii_whitespace()
+        .map(|opt| opt.split_once('=').unwrap_or((opt, "")))
+        .find(|(k, _)| *k == key)
+        .map(|(_, val)| val.to_string())
+}
+
+/// Generates the contents of /proc/cmdline
+pub fn proc_cmdline() -> String {
+    let mut s = CMDLINE.lock().as_str().to_string();
+    s.push('\n');
+    s
+}
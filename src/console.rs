@@ -1,277 +1,166 @@
 use alloc::{sync::Arc, vec::Vec};
-use spin::Mutex;
 
 use crate::{
+    cmdline,
     drivers::ps2::{
         self,
-        keyboard::{KeyEvent, PS2KeyboardEventHandler, PS2_KEY_BACKSPACE},
+        keyboard::{KeyEvent, KeyModifiers, PS2KeyboardEventHandler, PS2_KEY_BACKSPACE},
     },
-    framebuffer,
+    drivers::serial::{self, SerialInputHandler},
     fs::{
         devfs::{self, DevFsDevice},
-        errors::{FsIoctlError, FsReadError, FsStatError, FsWriteError},
+        errors::{FsIoctlError, FsPathError, FsReadError, FsStatError, FsWriteError},
         path::Path,
     },
-    posix::{
-        termios::{
-            Termios, Winsize, ECHO, ICANON, ISIG, NCCS, TCGETS, TCSETS, TIOCGPGRP, TIOCGWINSZ,
-            TIOCSPGRP, TIOCSWINSZ,
-        },
-        S_IFCHR,
-    },
-    sync::InterruptMutex,
+    posix::{PollEvents, Stat},
+    scheduler::{wait_queue::Waiter, SCHEDULER},
+    tty::{self, fbterm::FramebufferTerminal, serial::SerialTerminal, Tty, TtyBackend},
 };
 
+const TTY_DEVICE_MAJOR: u16 = 4;
 const ALTERNATE_TTY_DEVICE_MAJOR: u16 = 5;
 
-struct StdinBuffer {
-    current_line: Vec<u8>,
-    buffer: Vec<u8>,
-    buffer_idx: usize,
-}
+/// The minors of the TTYs of major 4, the same as on Linux
+const FRAMEBUFFER_TTY_MINOR: u16 = 0;
+const SERIAL_TTY_MINOR: u16 = 64;
 
-struct Terminal {
-    width: usize,
-    height: usize,
-    x: usize,
-    y: usize,
+/// The default VERASE character, the backspace key sends it
+const DEL: u8 = 0x7F;
+
+/// Input of the TTY drawn on the framebuffer, it comes from the PS/2 keyboard
+struct KeyboardInput {
+    tty: Arc<Tty>,
 }
 
-struct ConsoleState {
-    termios: Termios,
-    controlling_process_group: usize,
+/// Input of the TTY on COM1
+struct SerialInput {
+    tty: Arc<Tty>,
 }
 
-struct Console {
-    state: Mutex<ConsoleState>,
-    stdin_buffer: InterruptMutex<StdinBuffer>,
-    terminal: Mutex<Terminal>,
+/// The TTYs of major 4, the operations are passed to the TTY of the minor
+struct TtyDevices {
+    ttys: Vec<(u16, Arc<Tty>)>,
 }
 
-impl StdinBuffer {
-    /// Creates a new StdinBuffer instance
-    fn new() -> Self {
-        StdinBuffer {
-            current_line: Vec::new(),
-            buffer: Vec::new(),
-            buffer_idx: 0,
-        }
+impl TtyDevices {
+    fn tty(&self, minor: u16) -> Option<&Arc<Tty>> {
+        self.ttys
+            .iter()
+            .find(|(tty_minor, _)| *tty_minor == minor)
+            .map(|(_, tty)| tty)
     }
+}
 
-    /// Appends the current line to the end of the buffer, clears the current line
-    fn add_line_to_buffer(&mut self) {
-        if self.current_line.is_empty() {
-            return;
+impl DevFsDevice for TtyDevices {
+    fn read(&self, minor: u16, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        match self.tty(minor) {
+            Some(tty) => tty.read(minor, off, buff),
+            None => Err(FsReadError::IoError),
         }
-
-        self.buffer.append(&mut self.current_line);
     }
 
-    /// Adds a char to the end of the current line
-    fn add_char_to_line(&mut self, ch: u8) {
-        assert!(ch != 0);
-        self.current_line.push(ch);
-
-        if ch == b'\n' {
-            self.add_line_to_buffer();
+    fn write(&self, minor: u16, off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        match self.tty(minor) {
+            Some(tty) => tty.write(minor, off, buff),
+            None => Err(FsWriteError::IoError),
         }
     }
 
-    /// Removes a char from the end of the current line, returns whether a char was removed
-    fn remove_char_from_end(&mut self) -> bool {
-        if self.current_line.is_empty() {
-            return false;
+    fn ioctl(&self, minor: u16, req: usize, arg: usize) -> Result<usize, FsIoctlError> {
+        match self.tty(minor) {
+            Some(tty) => tty.ioctl(minor, req, arg),
+            None => Err(FsIoctlError::InvalidArgument),
         }
-
-        self.current_line.remove(self.current_line.len() - 1);
-        true
-    }
-
-    /// Moves bytes from the beginning of the buffer to another buffer,
-    /// then moves the remaining bytes to the front
-    fn move_to_other_buffer(&mut self, size: usize, dst: &mut [u8]) {
-        assert!(size <= self.buffer.len());
-        assert!(size <= dst.len());
-
-        let src = &self.buffer[..size];
-        let dst = &mut dst[..size];
-
-        dst.copy_from_slice(src);
-
-        self.buffer.drain(..size);
     }
-}
 
-impl Terminal {
-    /// Creates a new Terminal instance
-    fn new() -> Self {
-        Terminal {
-            x: 0,
-            y: 0,
-            width: 80,
-            height: 25,
+    fn stat(&self, minor: u16, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        match self.tty(minor) {
+            Some(tty) => tty.stat(minor, stat_buf),
+            None => Err(FsStatError::BadPath(FsPathError::NoSuchFileOrDirectory)),
         }
     }
 
-    /// Writes a char to the screen, jumps to the start of the next line
-    /// if the end of the line is reached or a newline char is written
-    fn write_char(&mut self, ch: u8) {
-        if ch == b'\n' {
-            self.x = 0;
-            self.y += 1;
-        } else {
-            framebuffer::draw_character(ch as char, self.x, self.y, true);
-
-            self.x += 1;
-            if self.x >= self.width {
-                self.y += 1;
-                self.x = 0;
-            }
+    fn poll(&self, minor: u16, off: usize, waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        match self.tty(minor) {
+            Some(tty) => tty.poll(minor, off, waiter),
+            None => PollEvents::empty(),
         }
-
-        // TODO: scrolling
     }
+}
 
-    /// Remove the char at the cursor and moves the cursor back by 1
-    fn backspace(&mut self) {
-        if self.x == 0 && self.y > 0 {
-            self.y -= 1;
-        } else if self.x > 0 {
-            self.x -= 1;
+impl PS2KeyboardEventHandler for KeyboardInput {
+    fn key_event(&self, ev: KeyEvent) {
+        if !ev.pressed {
+            return;
         }
-        framebuffer::draw_character(' ', self.x, self.y, true);
-    }
-}
 
-impl ConsoleState {
-    fn new() -> Self {
-        ConsoleState {
-            termios: Termios {
-                c_iflag: 0,
-                c_oflag: 0,
-                c_cflag: 0,
-                c_lflag: (ISIG | ICANON | ECHO) as u32,
-                c_cc: [0; NCCS],
-            },
-            controlling_process_group: 1,
+        if ev.key == PS2_KEY_BACKSPACE {
+            self.tty.input_char(DEL);
+        } else if ev.modifiers.contains(KeyModifiers::MOD_CTRL) && ev.ch.is_ascii_alphabetic() {
+            // e.g. Ctrl+C is ETX
+            self.tty.input_char(ev.ch.to_ascii_uppercase() - b'@');
+        } else if ev.ch != 0 {
+            self.tty.input_char(ev.ch);
         }
     }
 }
 
-impl DevFsDevice for Console {
-    fn read(&self, _minor: u16, _off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
-        loop {
-            let buffer = self.stdin_buffer.lock();
-            if !buffer.buffer.is_empty() {
-                break;
-            }
+impl SerialInputHandler for SerialInput {
+    fn receive_byte(&self, byte: u8) {
+        match byte {
+            // terminals send carriage return when enter is pressed
+            b'\r' => self.tty.input_char(b'\n'),
+            // backspace and DEL both erase
+            0x08 | DEL => self.tty.input_char(DEL),
+            0 => (),
+            ch => self.tty.input_char(ch),
         }
-
-        // FIXME: interrupt locking because an keyboard interrupt could cause a deadlock here
-        let mut stdin_buffer = self.stdin_buffer.lock();
-        let bytes_to_read = usize::min(buff.len(), stdin_buffer.buffer.len());
-
-        stdin_buffer.move_to_other_buffer(bytes_to_read, buff);
-
-        Ok(bytes_to_read)
     }
+}
 
-    fn write(&self, _minor: u16, _off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
-        let mut terminal = self.terminal.lock();
-        for &ch in buff {
-            terminal.write_char(ch);
-        }
+/// Creates the TTYs, /dev/tty0 is drawn on the framebuffer and /dev/ttyS0 is on COM1.
+/// /dev/console is /dev/tty0 unless the console=ttyS0 command line option is given, by
+/// default tty0 is mirrored to COM1 and ttyS0 is the same TTY.
+pub fn init() {
+    let has_serial = cfg!(serial_module) && serial::is_present();
+    let serial_console = has_serial && cmdline::get("console").is_some_and(|con| con == "ttyS0");
 
-        Ok(buff.len())
+    let mut backends: Vec<Arc<dyn TtyBackend>> = vec![Arc::new(FramebufferTerminal::new())];
+    if has_serial && !serial_console {
+        backends.push(Arc::new(SerialTerminal));
     }
-
-    fn ioctl(&self, _minor: u16, req: usize, arg: usize) -> Result<usize, FsIoctlError> {
-        let mut state = self.state.lock();
-        match req {
-            TCGETS => {
-                let ptr = arg as *mut Termios;
-                unsafe {
-                    ptr.write(state.termios);
-                }
-            }
-            TCSETS => {
-                let ptr = arg as *const Termios;
-                state.termios = unsafe { ptr.read() };
-            }
-            TIOCGPGRP => {
-                let ptr = arg as *mut u32;
-                unsafe {
-                    ptr.write(state.controlling_process_group as u32);
-                }
-            }
-            TIOCSPGRP => {
-                let ptr = arg as *const u32;
-                state.controlling_process_group = unsafe { ptr.read() } as usize;
-            }
-            TIOCGWINSZ => {
-                let terminal = self.terminal.lock();
-                let ptr = arg as *mut Winsize;
-                unsafe {
-                    (*ptr).ws_col = terminal.width as u16;
-                    (*ptr).ws_row = terminal.height as u16;
-                }
-            }
-            TIOCSWINSZ => {
-                let mut terminal = self.terminal.lock();
-                let ptr = arg as *const Winsize;
-                unsafe {
-                    terminal.width = (*ptr).ws_col as usize;
-                    terminal.height = (*ptr).ws_row as usize;
-                }
-            }
-            _ => panic!("unimplemented ioctl req {}", req),
+    let framebuffer_tty = Arc::new(Tty::new(backends));
+
+    let mut ttys = vec![(FRAMEBUFFER_TTY_MINOR, framebuffer_tty.clone())];
+    let mut console = framebuffer_tty.clone();
+    if has_serial {
+        let serial_tty = match serial_console {
+            true => Arc::new(Tty::new(vec![Arc::new(SerialTerminal)])),
+            false => framebuffer_tty.clone(),
+        };
+        if serial_console {
+            console = serial_tty.clone();
         }
 
-        Ok(0)
+        ttys.push((SERIAL_TTY_MINOR, serial_tty.clone()));
+        serial::set_input_handler(Some(Arc::new(SerialInput { tty: serial_tty })));
     }
 
-    fn stat(&self, _minor: u16, stat_buf: &mut crate::posix::Stat) -> Result<(), FsStatError> {
-        // TODO
-        stat_buf.st_blksize = 4096;
-        stat_buf.st_blocks = 0;
-        stat_buf.st_size = 0;
-        stat_buf.st_dev = 0;
-        stat_buf.st_gid = 0;
-        stat_buf.st_uid = 0;
-        stat_buf.st_nlink = 1;
-        stat_buf.st_mode = S_IFCHR | 0o666;
-
-        Ok(())
-    }
-}
-
-impl PS2KeyboardEventHandler for Console {
-    fn key_event(&self, ev: KeyEvent) {
-        if !ev.pressed {
-            return;
-        }
-
-        let mut terminal = self.terminal.lock();
-        let mut buff = self.stdin_buffer.lock();
-
-        if ev.key == PS2_KEY_BACKSPACE {
-            let not_empty = buff.remove_char_from_end();
-            if not_empty {
-                terminal.backspace();
-            }
-        } else if ev.ch != 0 {
-            buff.add_char_to_line(ev.ch);
-            terminal.write_char(ev.ch);
-        }
+    devfs::register_devfs_node_operations(TTY_DEVICE_MAJOR, Arc::new(TtyDevices { ttys })).unwrap();
+    devfs::register_devfs_node(
+        Path::new("/tty0").unwrap(),
+        TTY_DEVICE_MAJOR,
+        FRAMEBUFFER_TTY_MINOR,
+    )
+    .unwrap();
+    if has_serial {
+        devfs::register_devfs_node(
+            Path::new("/ttyS0").unwrap(),
+            TTY_DEVICE_MAJOR,
+            SERIAL_TTY_MINOR,
+        )
+        .unwrap();
     }
-}
-
-pub fn init() {
-    let con = Arc::new(Console {
-        state: Mutex::new(ConsoleState::new()),
-        stdin_buffer: InterruptMutex::new(StdinBuffer::new()),
-        terminal: Mutex::new(Terminal::new()),
-    });
 
     devfs::register_devfs_node(
         Path::new("/console").unwrap(),
@@ -279,7 +168,11 @@ pub fn init() {
         1,
     )
     .unwrap();
-    devfs::register_devfs_node_operations(ALTERNATE_TTY_DEVICE_MAJOR, con.clone()).unwrap();
+    devfs::register_devfs_node_operations(ALTERNATE_TTY_DEVICE_MAJOR, console).unwrap();
+
+    SCHEDULER.create_kernel_thread(tty::signal_thread);
 
-    ps2::keyboard::set_key_event_handler(Some(con));
+    ps2::keyboard::set_key_event_handler(Some(Arc::new(KeyboardInput {
+        tty: framebuffer_tty,
+    })));
 }
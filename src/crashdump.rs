@@ -0,0 +1,216 @@
+//! The crash dump, a machine-parseable report the panic handler writes to COM1 after the
+//! human-readable one so crashes of unattended runs can be debugged afterwards. It is not
+//! written to a disk, the disk drivers wait for interrupts and the thread that panicked could
+//! hold their locks.
+//!
+//! The dump starts with a "rook-crashdump begin" line and ends with a "rook-crashdump end"
+//! line that has the number of records in between, a dump without it was cut off. Every
+//! record is a line with its type followed by key=value fields, a field with free-form text
+//! is always the last one:
+//!
+//! - panic cpu= msg=
+//! - regs source=current rsp= rbp= cr0= cr2= cr3= cr4=, the registers of the panic handler
+//! - regs source=exception rax= ... rip= rsp= rflags=, the registers saved by the last
+//!   exception, they belong to the panic if an exception caused it
+//! - kstack rsp= start= end=, the kernel stack of the current thread, start and end are 0
+//!   if it is unknown
+//! - stack addr= val=, comma separated words of the kernel stack from the stack pointer up
+//! - thread tid= state= cpu= rip= mode= pid=
+//! - runqueue cpu= current= idle= queued=
+//! - log seq= pri= ts= msg=, the kernel log ring oldest first
+
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    arch::x86_64::{
+        exception::EXCEPTION_REG_STATE, get_cr0, get_cr2, get_cr3, get_cr4,
+        registers::GeneralRegisters, smp,
+    },
+    cmdline, drivers, kmsg,
+    scheduler::SCHEDULER,
+};
+
+/// Version of the format, it is increased when a record changes
+const CRASH_DUMP_VERSION: u32 = 1;
+/// At most this many bytes of the stack are dumped above the stack pointer
+const STACK_DUMP_MAX: u64 = 16 * 1024;
+/// Number of words in a stack record
+const STACK_RECORD_WORDS: u64 = 4;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Writes straight to COM1, the log is not used so the dump is not mixed with other output
+struct SerialWriter {
+    lines: usize,
+}
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.lines += 1;
+            }
+            drivers::serial::write(byte);
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the line breaks of a text field so the record stays on a single line
+struct SingleLine<'a>(&'a mut dyn Write);
+
+impl Write for SingleLine<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            self.0.write_char(if ch.is_control() { ' ' } else { ch })?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_registers(w: &mut dyn Write) -> fmt::Result {
+    let (rsp, rbp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp);
+    }
+    writeln!(
+        w,
+        "regs source=current rsp={:#x} rbp={:#x} cr0={:#x} cr2={:#x} cr3={:#x} cr4={:#x}",
+        rsp,
+        rbp,
+        get_cr0().bits(),
+        get_cr2(),
+        get_cr3(),
+        get_cr4().bits()
+    )?;
+
+    let regs = unsafe { EXCEPTION_REG_STATE };
+    let GeneralRegisters {
+        rax,
+        rbx,
+        rcx,
+        rdx,
+        rsi,
+        rdi,
+        r8,
+        r9,
+        r10,
+        r11,
+        r12,
+        r13,
+        r14,
+        r15,
+        rbp,
+    } = regs.general;
+    let (rip, rsp, rflags) = (regs.rip, regs.rsp, regs.rflags);
+    let fields = [
+        ("rax", rax),
+        ("rbx", rbx),
+        ("rcx", rcx),
+        ("rdx", rdx),
+        ("rsi", rsi),
+        ("rdi", rdi),
+        ("r8", r8),
+        ("r9", r9),
+        ("r10", r10),
+        ("r11", r11),
+        ("r12", r12),
+        ("r13", r13),
+        ("r14", r14),
+        ("r15", r15),
+        ("rbp", rbp),
+        ("rip", rip),
+        ("rsp", rsp),
+        ("rflags", rflags),
+    ];
+
+    write!(w, "regs source=exception")?;
+    for (name, val) in fields {
+        write!(w, " {}={:#x}", name, val)?;
+    }
+    writeln!(w)
+}
+
+/// Dumps the kernel stack of the current thread from the stack pointer up, nothing is dumped
+/// if the panic handler does not run on it, e.g. on the stack of an interrupt
+fn write_stack(w: &mut dyn Write) -> fmt::Result {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp);
+    }
+
+    let (start, end) = match SCHEDULER.try_current_kernel_stack() {
+        Some((start, end)) => (start.get(), end.get()),
+        None => (0, 0),
+    };
+    writeln!(w, "kstack rsp={:#x} start={:#x} end={:#x}", rsp, start, end)?;
+    if rsp < start || rsp >= end {
+        return Ok(());
+    }
+
+    let dump_end = u64::min(end, rsp + STACK_DUMP_MAX);
+    let mut addr = rsp & !7;
+    while addr < dump_end {
+        write!(w, "stack addr={:#x} val=", addr)?;
+        for idx in 0..STACK_RECORD_WORDS {
+            let word_addr = addr + idx * 8;
+            if word_addr >= dump_end {
+                break;
+            }
+
+            let val = unsafe { *(word_addr as *const u64) };
+            let separator = if idx == 0 { "" } else { "," };
+            write!(w, "{}{:#x}", separator, val)?;
+        }
+        writeln!(w)?;
+        addr += STACK_RECORD_WORDS * 8;
+    }
+
+    Ok(())
+}
+
+fn write_records(w: &mut dyn Write, info: &PanicInfo) -> fmt::Result {
+    write!(w, "panic cpu={} msg=", smp::current_cpu())?;
+    write!(SingleLine(w), "{}", info)?;
+    writeln!(w)?;
+
+    write_registers(w)?;
+    write_stack(w)?;
+    SCHEDULER.crash_dump(w)?;
+    kmsg::crash_dump(w)
+}
+
+/// Writes the crash dump of the panic described by __info__ to COM1, it is called by the
+/// panic handler once the other CPUs are halted
+pub fn write(info: &PanicInfo) {
+    if !cfg!(serial_module) || !drivers::serial::is_present() || !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut writer = SerialWriter { lines: 0 };
+    writeln!(
+        writer,
+        "rook-crashdump begin version={}",
+        CRASH_DUMP_VERSION
+    )
+    .ok();
+    writer.lines = 0;
+
+    write_records(&mut writer, info).ok();
+
+    let records = writer.lines;
+    writeln!(writer, "rook-crashdump end records={}", records).ok();
+}
+
+/// crashdump=off turns the crash dump off
+pub fn init() {
+    let enabled = cmdline::get("crashdump").map_or(true, |val| val != "off");
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
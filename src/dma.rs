@@ -1,26 +1,73 @@
-use crate::mm::{PhysAddr, VirtAddr};
+use core::{
+    ops::{Deref, DerefMut},
+    slice,
+};
 
-//static CURRENT_POINTER: Mutex<VirtAddr> = Mutex::new(DMA_START);
+use crate::mm::{phys::PHYS_ALLOCATOR, PhysAddr, VirtAddr};
 
-// FIXME: implement a better way to allocate dma regions
-pub fn alloc(_size: usize, _phys_align: usize) -> (PhysAddr, VirtAddr) {
-    /*
-    let mut pointer = CURRENT_POINTER.lock();
+/// Devices that can only do 32 bit DMA can not address memory above 4 GiB
+const DMA_MAX_ADDR: PhysAddr = PhysAddr::new(0x1_0000_0000);
 
-    let in_pages = size / 4096;
+/// Allocates physically contiguous memory below 4 GiB for DMA, the memory is accessed
+/// through the HHDM
+pub fn alloc(size: usize, phys_align: usize) -> (PhysAddr, VirtAddr) {
     assert!(size % 4096 == 0);
+    let in_pages = size / 4096;
+
+    let phys = PHYS_ALLOCATOR
+        .lock()
+        .alloc_in_range(in_pages, phys_align, PhysAddr::zero(), DMA_MAX_ADDR)
+        .expect("out of memory below 4 GiB for DMA");
+
+    (phys, phys.virt_addr())
+}
+
+/// Zeroed memory from `alloc` that is freed when the buffer is dropped, e.g. the sectors
+/// a bus mastering controller transfers to or from
+pub struct DmaBuf {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+}
+
+impl DmaBuf {
+    /// Allocates __size__ bytes rounded up to whole pages
+    pub fn new(size: usize, phys_align: usize) -> DmaBuf {
+        let size = size.next_multiple_of(4096);
+        let (phys, virt) = alloc(size, phys_align);
+
+        // the frames still hold whatever they were last used for
+        unsafe {
+            core::ptr::write_bytes(virt.get() as *mut u8, 0, size);
+        }
 
-    let phys = phys::alloc_multiple_align(in_pages, phys_align);
-    let virt = *pointer;
+        DmaBuf { phys, virt, size }
+    }
 
-    *pointer = virt + VirtAddr::new(size as u64);
+    /// Returns the address the device has to be given
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+}
 
-    for i in 0..in_pages {
-        let v = virt + VirtAddr::new(i as u64 * 4096);
-        let p = phys + PhysAddr::new(i as u64 * 4096);
-        virt::map(v, p, PML1Flags::READ_WRITE);
+impl Deref for DmaBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { slice::from_raw_parts(self.virt.get() as *const u8, self.size) }
     }
+}
 
-    (phys, virt)*/
-    todo!()
+impl DerefMut for DmaBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { slice::from_raw_parts_mut(self.virt.get() as *mut u8, self.size) }
+    }
+}
+
+impl Drop for DmaBuf {
+    fn drop(&mut self) {
+        PHYS_ALLOCATOR
+            .lock()
+            .free_multiple(self.phys, self.size / 4096);
+    }
 }
@@ -1,10 +1,83 @@
 bits 64
 
-extern ata_interrupt
+extern ata_primary_interrupt
+extern ata_secondary_interrupt
 
 section .text
-global __ata_interrupt:function (__ata_interrupt.end - __ata_interrupt)
-__ata_interrupt:
-    cli
-    call ata_interrupt
-.end:
\ No newline at end of file
+global __ata_primary_interrupt:function (__ata_primary_interrupt.end - __ata_primary_interrupt)
+__ata_primary_interrupt:
+    push rbp
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    call ata_primary_interrupt
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rbp
+
+    iretq
+.end:
+
+global __ata_secondary_interrupt:function (__ata_secondary_interrupt.end - __ata_secondary_interrupt)
+__ata_secondary_interrupt:
+    push rbp
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    call ata_secondary_interrupt
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rbp
+
+    iretq
+.end:
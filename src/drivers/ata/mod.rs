@@ -1,12 +1,19 @@
-use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::{boxed::Box, vec::Vec};
 use spin::Mutex;
 
 use crate::{
-    arch::x86_64::{inb, inw, outb, outw},
-    blk::{self, LinearBlockAddress},
+    arch::x86_64::{inb, interrupts_enabled, inw, irq, outb, outw},
+    blk::{
+        self,
+        sector_buf::{read_u32_le, SectorBuf},
+        LinearBlockAddress,
+    },
+    latency,
     pci::{self, PCIDevice},
+    scheduler::wait_queue::{Waiter, WakeReason},
+    time,
 };
 
 bitflags::bitflags! {
@@ -42,9 +49,9 @@ const ID_SERIAL: isize = 0x14;
 const ID_MODEL: isize = 0x36;
 const ID_CAPABILITIES: isize = 0x62;
 const ID_FIELDVALID: isize = 0x6A;
-const ID_MAX_LBA: isize = 0x78;
+const ID_MAX_LBA: usize = 0x78;
 const ID_COMMANDSETS: isize = 0xA4;
-const ID_MAX_LBA_EXT: isize = 0xC8;
+const ID_MAX_LBA_EXT: usize = 0xC8;
 
 const CMD_READ_PIO: u8 = 0x20;
 const CMD_READ_PIO_EXT: u8 = 0x24;
@@ -71,6 +78,14 @@ const REG_DRIVE: u16 = 0x06;
 const REG_COMMAND: u16 = 0x07;
 const REG_STATUS: u16 = 0x07;
 
+// registers relative to the control port
+const REG_ALT_STATUS: u16 = 0x00;
+const REG_DEVICE_CONTROL: u16 = 0x00;
+
+const CTRL_NIEN: u8 = 1 << 1;
+const CTRL_SRST: u8 = 1 << 2;
+const CTRL_HOB: u8 = 1 << 7;
+
 const ST_ERROR: u8 = 1 << 0;
 const ST_INDEX: u8 = 1 << 1;
 const ST_CORRECTED_DATA: u8 = 1 << 2;
@@ -82,11 +97,31 @@ const ST_BUSY: u8 = 1 << 7;
 
 const SECTOR_SIZE: usize = 512;
 
+/// Time a disk has to finish a step of a command
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+
+/// Time a disk has to answer IDENTIFY, nonexistent disks should not hold up the boot
+const IDENTIFY_TIMEOUT_MS: u64 = 1000;
+
+/// How many times a command is sent before the error is reported to the blk layer
+const MAX_COMMAND_ATTEMPTS: usize = 3;
+
+// set by the interrupt handlers and cleared when the waiting command sees them
+static PRIMARY_BUS_IRQ_RECEIVED: AtomicBool = AtomicBool::new(false);
+static SECONDARY_BUS_IRQ_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+// the thread waiting for the command of the bus blocks on its waiter
+static PRIMARY_BUS_WAITER: Waiter = Waiter::new();
+static SECONDARY_BUS_WAITER: Waiter = Waiter::new();
+
 pub const ATA_PRIMARY_BUS_PORT: u16 = 0x1F0;
 pub const ATA_PRIMARY_BUS_CONTROL_PORT: u16 = 0x3F6;
 pub const ATA_SECONDARY_BUS_PORT: u16 = 0x170;
 pub const ATA_SECONDARY_BUS_CONTROL_PORT: u16 = 0x376;
 
+pub const ATA_PRIMARY_BUS_IRQ: u8 = 14;
+pub const ATA_SECONDARY_BUS_IRQ: u8 = 15;
+
 pub const ATA_MASTER_DISK: u8 = 0xA0;
 pub const ATA_SLAVE_DISK: u8 = 0xB0;
 
@@ -108,10 +143,40 @@ struct PhysicalRegionDescriptor {
     reserved: u16,
 }
 
-#[derive(Debug)]
-struct ATABus {
+/// I/O ports of an ATA bus
+#[derive(Debug, Clone, Copy)]
+struct BusConfig {
+    /// Base of the command block registers
     bus_port: u16,
+
+    /// Device control/alternate status register
     control_port: u16,
+
+    /// IRQ the disks on the bus raise, the bus is polled if it is None
+    irq: Option<u8>,
+}
+
+#[derive(Debug)]
+enum ATACommandError {
+    /// The disk did not finish in time
+    Timeout,
+
+    /// DF was set in the status register
+    DiskFault,
+
+    /// ERR was set in the status register, contains the error register
+    DiskError(ATAError),
+
+    /// The disk finished without requesting data
+    NoDataRequest,
+}
+
+#[derive(Debug)]
+struct ATABus {
+    config: BusConfig,
+
+    /// Whether the bus was found floating, no disks are attached to it
+    floating: bool,
 }
 
 /// Describes an ATA controller, a controller can have 4 disks
@@ -153,7 +218,8 @@ struct ATADisk {
 }
 
 extern "C" {
-    fn __ata_interrupt();
+    fn __ata_primary_interrupt();
+    fn __ata_secondary_interrupt();
 }
 
 static ATA_CONTROLLERS: Mutex<Vec<ATAController>> = Mutex::new(Vec::new());
@@ -163,51 +229,142 @@ impl blk::BlockOperations for ATADisk {
         let mut controllers = ATA_CONTROLLERS.lock();
         let controller = &mut controllers[self.controller_idx];
 
-        controller.read(
-            self.primary_bus,
-            self.master_disk,
-            req.lba,
-            req.size,
-            req.buff,
-        );
+        controller
+            .read(
+                self.primary_bus,
+                self.master_disk,
+                req.lba,
+                req.size,
+                req.buff,
+            )
+            .map_err(|_| blk::BlockDeviceError::FailedToReadSectors)
+    }
 
-        Ok(())
+    fn write(&self, req: blk::IORequest) -> Result<(), blk::BlockDeviceError> {
+        let mut controllers = ATA_CONTROLLERS.lock();
+        let controller = &mut controllers[self.controller_idx];
+
+        controller
+            .write(
+                self.primary_bus,
+                self.master_disk,
+                req.lba,
+                req.size,
+                req.buff,
+            )
+            .map_err(|_| blk::BlockDeviceError::FailedToWriteSectors)
     }
+}
 
-    fn write(&self, _req: blk::IORequest) -> Result<(), blk::BlockDeviceError> {
-        Ok(())
+impl BusConfig {
+    const LEGACY_PRIMARY: BusConfig = BusConfig {
+        bus_port: ATA_PRIMARY_BUS_PORT,
+        control_port: ATA_PRIMARY_BUS_CONTROL_PORT,
+        irq: Some(ATA_PRIMARY_BUS_IRQ),
+    };
+
+    const LEGACY_SECONDARY: BusConfig = BusConfig {
+        bus_port: ATA_SECONDARY_BUS_PORT,
+        control_port: ATA_SECONDARY_BUS_CONTROL_PORT,
+        irq: Some(ATA_SECONDARY_BUS_IRQ),
+    };
+
+    /// Creates the config of a bus in PCI native mode from its BARs
+    fn from_bars(bus_bar: u32, control_bar: u32) -> BusConfig {
+        BusConfig {
+            bus_port: (bus_bar & 0xFFFC) as u16,
+            // the BAR describes a 4 byte region and the control register is the 3rd byte
+            control_port: (control_bar & 0xFFFC) as u16 + 2,
+            // TODO: handle the interrupt line of the PCI device
+            irq: None,
+        }
+    }
+}
+
+/// Returns the flag the interrupt handler of __irq__ sets
+fn irq_received_flag(irq: u8) -> &'static AtomicBool {
+    match irq {
+        ATA_PRIMARY_BUS_IRQ => &PRIMARY_BUS_IRQ_RECEIVED,
+        ATA_SECONDARY_BUS_IRQ => &SECONDARY_BUS_IRQ_RECEIVED,
+        _ => unreachable!(),
+    }
+}
+
+/// Returns the waiter the interrupt handler of __irq__ wakes
+fn irq_waiter(irq: u8) -> &'static Waiter {
+    match irq {
+        ATA_PRIMARY_BUS_IRQ => &PRIMARY_BUS_WAITER,
+        ATA_SECONDARY_BUS_IRQ => &SECONDARY_BUS_WAITER,
+        _ => unreachable!(),
     }
 }
 
 impl ATABus {
+    fn new(config: BusConfig) -> ATABus {
+        let mut bus = ATABus {
+            config,
+            floating: false,
+        };
+
+        // a bus with nothing attached reads as all ones
+        bus.floating = bus.read_ctrl8(REG_ALT_STATUS) == 0xFF;
+        if !bus.floating {
+            // interrupts are enabled after the disks were identified
+            bus.set_interrupts_enabled(false);
+        }
+
+        bus
+    }
+
     #[inline]
     fn write_io8(&self, reg: u16, val: u8) {
-        outb(self.bus_port + reg, val);
+        outb(self.config.bus_port + reg, val);
     }
 
     #[inline]
     fn read_io8(&self, reg: u16) -> u8 {
-        inb(self.bus_port + reg)
+        inb(self.config.bus_port + reg)
     }
 
     #[inline]
     fn write_io16(&self, reg: u16, val: u16) {
-        outw(self.bus_port + reg, val);
+        outw(self.config.bus_port + reg, val);
     }
 
     #[inline]
     fn read_io16(&self, reg: u16) -> u16 {
-        inw(self.bus_port + reg)
+        inw(self.config.bus_port + reg)
     }
 
     #[inline]
     fn write_ctrl8(&self, reg: u16, val: u8) {
-        outb(self.bus_port + reg, val);
+        outb(self.config.control_port + reg, val);
     }
 
     #[inline]
     fn read_ctrl8(&self, reg: u16) -> u8 {
-        inb(self.bus_port + reg)
+        inb(self.config.control_port + reg)
+    }
+
+    /// Sets or clears nIEN, while it is set the disks on the bus do not send interrupts
+    fn set_interrupts_enabled(&self, enabled: bool) {
+        self.write_ctrl8(REG_DEVICE_CONTROL, if enabled { 0 } else { CTRL_NIEN });
+    }
+
+    /// Resets both disks on the bus, the master disk is selected afterwards.
+    /// Interrupts are left disabled
+    fn software_reset(&self) -> Result<(), ATACommandError> {
+        self.write_ctrl8(REG_DEVICE_CONTROL, CTRL_SRST | CTRL_NIEN);
+        // SRST has to be held for at least 5us
+        for _ in 0..13 {
+            self.wait_400ns();
+        }
+        self.write_ctrl8(REG_DEVICE_CONTROL, CTRL_NIEN);
+
+        self.wait_400ns();
+        self.poll_status(COMMAND_TIMEOUT_MS, |_| true)?;
+
+        Ok(())
     }
 
     fn select_disk(&mut self, master_selected: bool) {
@@ -274,34 +431,150 @@ impl ATABus {
         }
     }
 
-    /// Read the status register 15 times then return the last one
+    /// Read the alternate status register 15 times then return the last one,
+    /// unlike the status register reading it does not acknowledge interrupts
     fn wait_400ns(&self) -> u8 {
         for _ in 0..14 {
-            self.read_io8(REG_STATUS);
+            self.read_ctrl8(REG_ALT_STATUS);
         }
 
-        self.read_io8(REG_STATUS)
+        self.read_ctrl8(REG_ALT_STATUS)
     }
 
-    fn wait_until_not_busy(&self) {
-        loop {
+    /// Polls the alternate status register until BSY is cleared and __ready__ returns true
+    fn poll_status<F>(&self, timeout_ms: u64, ready: F) -> Result<u8, ATACommandError>
+    where
+        F: Fn(u8) -> bool,
+    {
+        let deadline = time::elapsed().as_milliseconds() + timeout_ms;
+
+        // the clock does not advance while interrupts are disabled so the number
+        // of polls is limited too, one poll takes at least 400ns
+        for _ in 0..timeout_ms * 2500 {
             let status = self.wait_400ns();
-            if status & ST_BUSY == 0 {
-                return;
+            if status & ST_BUSY == 0 && ready(status) {
+                return Ok(status);
+            }
+
+            if time::elapsed().as_milliseconds() > deadline {
+                break;
+            }
+        }
+
+        Err(ATACommandError::Timeout)
+    }
+
+    /// Forgets the interrupts that were raised before the next command
+    fn clear_pending_interrupt(&self) {
+        if let Some(irq) = self.config.irq {
+            irq_received_flag(irq).store(false, Ordering::Release);
+        }
+    }
+
+    /// Blocks until the disk raises an interrupt, the bus is polled if it has no IRQ or
+    /// interrupts are disabled. The status register is read to acknowledge the interrupt,
+    /// it is returned if neither ERR or DF is set
+    fn wait_for_interrupt(&self, timeout_ms: u64) -> Result<u8, ATACommandError> {
+        match self.config.irq {
+            Some(irq) if interrupts_enabled() => {
+                let flag = irq_received_flag(irq);
+                let waiter = irq_waiter(irq);
+                let deadline = time::elapsed().as_milliseconds() + timeout_ms;
+                // the waiter can still be woken by an interrupt of an earlier command so the
+                // flag decides whether this one completed
+                while !flag.swap(false, Ordering::Acquire) {
+                    if waiter.block_until(Some(deadline)) == WakeReason::TimedOut {
+                        // the interrupt could have arrived together with the timeout
+                        match flag.swap(false, Ordering::Acquire) {
+                            true => break,
+                            false => return Err(ATACommandError::Timeout),
+                        }
+                    }
+                }
+            }
+            _ => {
+                self.poll_status(timeout_ms, |_| true)?;
+            }
+        }
+
+        let status = self.read_io8(REG_STATUS);
+        self.check_status(status)
+    }
+
+    fn check_status(&self, status: u8) -> Result<u8, ATACommandError> {
+        if status & ST_DISK_FAULT > 0 {
+            return Err(ATACommandError::DiskFault);
+        }
+
+        if status & ST_ERROR > 0 {
+            let err = ATAError::from_bits_truncate(self.read_io8(REG_ERROR));
+            return Err(ATACommandError::DiskError(err));
+        }
+
+        Ok(status)
+    }
+
+    /// Calls __command__ until it succeeds or fails MAX_COMMAND_ATTEMPTS times,
+    /// the bus is reset after a timeout or a disk fault in case the disk got stuck
+    fn retry_command<F>(&mut self, mut command: F) -> Result<(), ATACommandError>
+    where
+        F: FnMut(&mut ATABus) -> Result<(), ATACommandError>,
+    {
+        let mut attempt = 1;
+        loop {
+            let err = match command(self) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+
+            warn!(
+                "ATA: command failed on bus {:?} (attempt {}/{}): {:?}",
+                self.config, attempt, MAX_COMMAND_ATTEMPTS, err
+            );
+
+            if attempt == MAX_COMMAND_ATTEMPTS {
+                return Err(err);
+            }
+
+            if let ATACommandError::Timeout | ATACommandError::DiskFault = err {
+                self.software_reset()?;
+                self.set_interrupts_enabled(self.config.irq.is_some());
             }
+
+            attempt += 1;
         }
     }
 
-    fn read(&mut self, master_disk: bool, lba: LinearBlockAddress, count: usize, buff: &mut [u8]) {
+    fn read(
+        &mut self,
+        master_disk: bool,
+        lba: LinearBlockAddress,
+        count: usize,
+        buff: &mut [u8],
+    ) -> Result<(), ATACommandError> {
         assert!(count < 256);
+        let lba = lba.inner();
+        self.retry_command(|bus| {
+            bus.try_read(master_disk, LinearBlockAddress::new(lba), count, buff)
+        })
+    }
+
+    fn try_read(
+        &mut self,
+        master_disk: bool,
+        lba: LinearBlockAddress,
+        count: usize,
+        buff: &mut [u8],
+    ) -> Result<(), ATACommandError> {
         self.select_disk(master_disk);
-        self.wait_until_not_busy();
+        self.poll_status(COMMAND_TIMEOUT_MS, |_| true)?;
 
         let sector_count = if count == u16::MAX as usize { 0 } else { count };
 
         let is_lba48 = lba > LinearBlockAddress::new(0x0FFFFFFF);
         self.write_lba(master_disk, is_lba48, lba, sector_count);
 
+        self.clear_pending_interrupt();
         self.write_io8(
             REG_COMMAND,
             if is_lba48 {
@@ -314,17 +587,94 @@ impl ATABus {
         let out_buff = &mut buff[0..count * 512];
 
         for i in 0..count {
-            self.wait_until_not_busy();
+            // the disk raises an interrupt when a sector can be read
+            let status = self.wait_for_interrupt(COMMAND_TIMEOUT_MS)?;
+            if status & ST_DATA_REQUEST_READY == 0 {
+                return Err(ATACommandError::NoDataRequest);
+            }
+
             for j in 0..256 {
                 let idx = i * 512 + j * 2;
                 let val = self.read_io16(REG_DATA);
                 out_buff[idx + 0] = val as u8;
                 out_buff[idx + 1] = (val >> 8) as u8;
             }
+        }
+
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        master_disk: bool,
+        lba: LinearBlockAddress,
+        count: usize,
+        buff: &[u8],
+    ) -> Result<(), ATACommandError> {
+        assert!(count < 256);
+        let lba = lba.inner();
+        self.retry_command(|bus| {
+            bus.try_write(master_disk, LinearBlockAddress::new(lba), count, buff)
+        })
+    }
+
+    fn try_write(
+        &mut self,
+        master_disk: bool,
+        lba: LinearBlockAddress,
+        count: usize,
+        buff: &[u8],
+    ) -> Result<(), ATACommandError> {
+        self.select_disk(master_disk);
+        self.poll_status(COMMAND_TIMEOUT_MS, |_| true)?;
+
+        let is_lba48 = lba > LinearBlockAddress::new(0x0FFFFFFF);
+        self.write_lba(master_disk, is_lba48, lba, count);
+
+        self.clear_pending_interrupt();
+        self.write_io8(
+            REG_COMMAND,
+            if is_lba48 {
+                CMD_WRITE_PIO_EXT
+            } else {
+                CMD_WRITE_PIO
+            },
+        );
+
+        // no interrupt is raised before the first sector
+        let status = self.poll_status(COMMAND_TIMEOUT_MS, |status| {
+            status & (ST_DATA_REQUEST_READY | ST_ERROR | ST_DISK_FAULT) > 0
+        })?;
+        self.check_status(status)?;
+
+        let in_buff = &buff[0..count * 512];
+
+        for i in 0..count {
+            for j in 0..256 {
+                let idx = i * 512 + j * 2;
+                let val = in_buff[idx] as u16 | (in_buff[idx + 1] as u16) << 8;
+                self.write_io16(REG_DATA, val);
+            }
 
-            // status must be read after reading the sector
-            self.read_io16(REG_STATUS);
+            // the disk raises an interrupt when it has taken the sector
+            let status = self.wait_for_interrupt(COMMAND_TIMEOUT_MS)?;
+            if i + 1 < count && status & ST_DATA_REQUEST_READY == 0 {
+                return Err(ATACommandError::NoDataRequest);
+            }
         }
+
+        self.clear_pending_interrupt();
+        self.write_io8(
+            REG_COMMAND,
+            if is_lba48 {
+                CMD_FLUSH_CACHE_EXT
+            } else {
+                CMD_FLUSH_CACHE
+            },
+        );
+        self.wait_for_interrupt(COMMAND_TIMEOUT_MS)?;
+
+        Ok(())
     }
 
     /// Returns the size of the disk in LBAs if the disk is
@@ -338,41 +688,36 @@ impl ATABus {
 
         self.write_io8(REG_COMMAND, CMD_IDENTIFY);
 
-        let mut status = self.read_io8(REG_STATUS);
+        let status = self.read_io8(REG_STATUS);
         if status == 0 {
             return None;
         }
 
-        while self.read_io8(REG_STATUS) & ST_BUSY > 0 {
-            let lba1 = self.read_io8(REG_LBA1);
-            let lba2 = self.read_io8(REG_LBA2);
-            if lba1 != 0 || lba2 != 0 {
-                // TODO: ATAPI
-                return None;
-            }
-        }
+        self.poll_status(IDENTIFY_TIMEOUT_MS, |_| true).ok()?;
 
-        while (status & ST_DATA_REQUEST_READY) == 0 && (status & ST_ERROR) == 0 {
-            status = self.read_io8(REG_STATUS);
+        let lba1 = self.read_io8(REG_LBA1);
+        let lba2 = self.read_io8(REG_LBA2);
+        if lba1 != 0 || lba2 != 0 {
+            // TODO: ATAPI
+            return None;
         }
 
+        let status = self
+            .poll_status(IDENTIFY_TIMEOUT_MS, |status| {
+                status & (ST_DATA_REQUEST_READY | ST_ERROR) > 0
+            })
+            .ok()?;
+
         if status & ST_ERROR > 0 {
             return None;
         }
 
-        let mut device_data: [MaybeUninit<u8>; SECTOR_SIZE] =
-            unsafe { MaybeUninit::uninit().assume_init() };
-
-        let ptr = device_data.as_mut_ptr() as *mut u16;
-        for i in 0..SECTOR_SIZE / 2 {
-            unsafe {
-                let addr = ptr.offset(i as isize);
-                let data = self.read_io16(REG_DATA);
-                *addr = data;
-            }
+        let mut device_data: SectorBuf = SectorBuf::zeroed();
+        for word in device_data.chunks_exact_mut(2) {
+            word.copy_from_slice(&self.read_io16(REG_DATA).to_le_bytes());
         }
 
-        let max_lba = unsafe { *((device_data.as_ptr()).offset(ID_MAX_LBA) as *const u32) };
+        let max_lba = read_u32_le(&device_data, ID_MAX_LBA);
 
         Some(max_lba as usize)
     }
@@ -386,13 +731,29 @@ impl ATAController {
         lba: LinearBlockAddress,
         count: usize,
         buff: &mut [u8],
-    ) {
+    ) -> Result<(), ATACommandError> {
+        let bus = if primary_bus {
+            &mut self.primary_bus
+        } else {
+            &mut self.secondary_bus
+        };
+        bus.read(master_disk, lba, count, buff)
+    }
+
+    fn write(
+        &mut self,
+        primary_bus: bool,
+        master_disk: bool,
+        lba: LinearBlockAddress,
+        count: usize,
+        buff: &[u8],
+    ) -> Result<(), ATACommandError> {
         let bus = if primary_bus {
             &mut self.primary_bus
         } else {
             &mut self.secondary_bus
         };
-        bus.read(master_disk, lba, count, buff);
+        bus.write(master_disk, lba, count, buff)
     }
 }
 
@@ -404,26 +765,26 @@ fn init_controller(controllers: &mut Vec<ATAController>, pci_device: &PCIDevice)
     let secondary_bus_pci_native =
         pci_device.prog_if & ATAProgIf::SECONDARY_CHANNEL_PCI_NATIVE.bits > 0;
 
-    let primary_bus_ports = if primary_bus_pci_native {
+    let primary_bus_config = if primary_bus_pci_native {
         unsafe {
-            (
-                (pci_device.specific.type0.bar0 & 0xFFF0) as u16,
-                (pci_device.specific.type0.bar1 & 0xFFF0) as u16,
+            BusConfig::from_bars(
+                pci_device.specific.type0.bar0,
+                pci_device.specific.type0.bar1,
             )
         }
     } else {
-        (ATA_PRIMARY_BUS_PORT, ATA_PRIMARY_BUS_CONTROL_PORT)
+        BusConfig::LEGACY_PRIMARY
     };
 
-    let secondary_bus_ports = if secondary_bus_pci_native {
+    let secondary_bus_config = if secondary_bus_pci_native {
         unsafe {
-            (
-                (pci_device.specific.type0.bar2 & 0xFFF0) as u16,
-                (pci_device.specific.type0.bar3 & 0xFFF0) as u16,
+            BusConfig::from_bars(
+                pci_device.specific.type0.bar2,
+                pci_device.specific.type0.bar3,
             )
         }
     } else {
-        (ATA_SECONDARY_BUS_PORT, ATA_SECONDARY_BUS_CONTROL_PORT)
+        BusConfig::LEGACY_SECONDARY
     };
 
     //let primary_dma = dma::alloc(16 * 4096, 0x10000);
@@ -431,24 +792,30 @@ fn init_controller(controllers: &mut Vec<ATAController>, pci_device: &PCIDevice)
 
     let mut controller = ATAController {
         index: controllers.len(),
-        primary_bus: ATABus {
-            bus_port: primary_bus_ports.0,
-            control_port: primary_bus_ports.1,
-        },
-        secondary_bus: ATABus {
-            bus_port: secondary_bus_ports.0,
-            control_port: primary_bus_ports.1,
-        },
+        primary_bus: ATABus::new(primary_bus_config),
+        secondary_bus: ATABus::new(secondary_bus_config),
     };
 
     for bus in 0..=1 {
-        for disk in 0..=1 {
-            let ata_bus = if bus == 0 {
-                &mut controller.primary_bus
-            } else {
-                &mut controller.secondary_bus
-            };
+        let ata_bus = if bus == 0 {
+            &mut controller.primary_bus
+        } else {
+            &mut controller.secondary_bus
+        };
+
+        if ata_bus.floating {
+            if cfg!(ata_debug) {
+                log!("ATA: bus {:?} is floating", ata_bus.config);
+            }
+            continue;
+        }
 
+        if let Err(err) = ata_bus.software_reset() {
+            warn!("ATA: failed to reset bus {:?}: {:?}", ata_bus.config, err);
+            continue;
+        }
+
+        for disk in 0..=1 {
             if let Some(disk_size) = ata_bus.try_identify(disk == 0) {
                 let bus_str = match bus {
                     0 => "primary",
@@ -478,6 +845,17 @@ fn init_controller(controllers: &mut Vec<ATAController>, pci_device: &PCIDevice)
                 disks.push(identified_disk);
             }
         }
+
+        if let Some(irq) = ata_bus.config.irq {
+            let handler = match irq {
+                ATA_PRIMARY_BUS_IRQ => __ata_primary_interrupt,
+                _ => __ata_secondary_interrupt,
+            };
+
+            irq::install_handler(irq, handler as usize as u64);
+            irq::enable(irq);
+            ata_bus.set_interrupts_enabled(true);
+        }
     }
 
     controllers.push(controller);
@@ -505,7 +883,7 @@ fn init_controllers(devices: Vec<&PCIDevice>) {
     }
 
     for disk in disks {
-        blk::register_blk("ATA", 1, disk.size, Box::new(disk));
+        blk::register_blk("ATA", "hd", 1, disk.size, Box::new(disk));
     }
 }
 
@@ -520,8 +898,19 @@ pub fn init() -> bool {
     true
 }
 
+fn handle_interrupt(irq: u8) {
+    let _latency = latency::irq_timer(irq::vector(irq));
+    irq_received_flag(irq).store(true, Ordering::Release);
+    irq_waiter(irq).wake();
+    irq::eoi(irq);
+}
+
+#[no_mangle]
+fn ata_primary_interrupt() {
+    handle_interrupt(ATA_PRIMARY_BUS_IRQ);
+}
+
 #[no_mangle]
-fn ata_interrupt() {
-    log!("ata interrupt");
-    loop {}
+fn ata_secondary_interrupt() {
+    handle_interrupt(ATA_SECONDARY_BUS_IRQ);
 }
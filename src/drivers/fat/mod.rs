@@ -1,18 +1,29 @@
-use core::mem::{transmute, MaybeUninit};
-
-use alloc::{boxed::Box, string::String, sync::Weak, vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 
 use crate::{
-    blk::{IORequest, LinearBlockAddress, Partition, BLOCK_SIZE},
+    blk::{
+        sector_buf::{read_u32_le, write_u32_le, SectorBuf, SectorVec},
+        BlockDeviceError, IORequest, LinearBlockAddress, Partition, BLOCK_SIZE,
+    },
     fs::{
         errors::{
-            FsCloseError, FsInitError, FsIoctlError, FsOpenError, FsPathError, FsReadError,
-            FsStatError, FsWriteError,
+            FsCloseError, FsCreateError, FsInitError, FsIoctlError, FsOpenError, FsPathError,
+            FsReadDirError, FsReadError, FsRemoveError, FsRenameError, FsStatError, FsWriteError,
         },
         inode::FSInode,
         path::Path,
-        FileSystemInner, FileSystemSkeleton, VFS,
+        DirEntry, FileSystemInner, FileSystemSkeleton, FsckMode, FsckReport, Revalidation,
+        VolumeInfo, VFS,
     },
+    mm::slab,
     posix::{Stat, S_IFDIR, S_IFREG},
     utils::slot_allocator::SlotAllocator,
 };
@@ -60,6 +71,13 @@ struct ExtendedBIOSPB {
 
 const MAGIC_NUMBER: [u8; 2] = [0x55, 0xAA];
 
+/// The volume id and the volume label of the extended BPB are valid
+const EXTENDED_BOOT_SIGNATURE: u8 = 0x29;
+/// Only the volume id of the extended BPB is valid
+const EXTENDED_BOOT_SIGNATURE_ID_ONLY: u8 = 0x28;
+/// Label of volumes that do not have one
+const NO_VOLUME_LABEL: &str = "NO NAME";
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug)]
 struct ShortDirectoryEntry {
@@ -86,11 +104,14 @@ const DIR_ENT_ARCHIVE: u8 = 1 << 5;
 const DIR_ENT_LONG_NAME: u8 =
     DIR_ENT_READ_ONLY | DIR_ENT_HIDDEN | DIR_ENT_SYSTEM | DIR_ENT_VOLUME_ID;
 
+const DIR_ENT_UNUSED: u8 = 0xE5;
+
 const DIR_ENTRIES_PER_SECTOR: usize = BLOCK_SIZE / core::mem::size_of::<ShortDirectoryEntry>();
 const LONG_DIR_ENTRY_LAST_ENTRY_MARKER: u8 = 0x40;
 const MAX_FILENAME_LENGTH: usize = 256;
 // TODO: utf-16
 const CHARS_PER_LONG_ENTRY: usize = 26;
+const UCS2_CHARS_PER_LONG_ENTRY: usize = CHARS_PER_LONG_ENTRY / 2;
 
 const FAT_ENTRIES_PER_BLOCK: usize = BLOCK_SIZE / core::mem::size_of::<u32>();
 
@@ -150,6 +171,8 @@ impl DirectoryIndex {
 struct ClusterIndex(usize);
 
 const MAX_VALID_CLUSTER: usize = 0x0FFFFFF7;
+const CLUSTER_BAD: usize = 0x0FFFFFF7;
+const CLUSTER_END_OF_CHAIN: usize = 0x0FFFFFFF;
 
 impl ClusterIndex {
     #[inline]
@@ -174,50 +197,117 @@ struct FATFileSystem {
     reserved_sector_count: usize,
     sectors_per_cluster: usize,
     fat_count: usize,
+    sectors_per_fat: usize,
     data_sectors_start: usize,
     root_cluster: ClusterIndex,
+    volume: VolumeInfo,
 
     inode_table: SlotAllocator<DirectoryIndex>,
+    /// Bumped every time the inode table is reset, it is stored in the upper half of every
+    /// inode so inodes handed out before a reload are recognized as stale
+    inode_generation: u32,
+
+    /// Media generation of the device when the boot sector was read
+    media_generation: usize,
+    /// Set when a block request fails or the medium is changed, nothing is read from or
+    /// written to the partition until the file system is revalidated
+    unavailable: AtomicBool,
+}
+
+/// Reads the boot sector of the partition and checks its signature
+fn read_boot_sector(part: &Partition) -> Result<SectorBuf, FsInitError> {
+    let mut boot_sector: SectorBuf = SectorBuf::zeroed();
+
+    part.read(boot_sector.request(LinearBlockAddress::new(0)))
+        .map_err(|_| FsInitError::InvalidSuperBlock)?;
+
+    if boot_sector[510..] != MAGIC_NUMBER {
+        return Err(FsInitError::InvalidMagic);
+    }
+
+    Ok(boot_sector)
+}
+
+fn parse_boot_sector(boot_sector: &SectorBuf) -> (&BIOSPBLegacy, &ExtendedBIOSPB) {
+    let bios_parameter_data: &BIOSPBLegacy = unsafe {
+        (boot_sector.as_ptr() as *const BIOSPBLegacy)
+            .as_ref()
+            .unwrap()
+    };
+
+    let extended_bpd: &ExtendedBIOSPB = unsafe {
+        (boot_sector
+            .as_ptr()
+            .add(core::mem::size_of::<BIOSPBLegacy>()) as *const ExtendedBIOSPB)
+            .as_ref()
+            .unwrap()
+    };
+
+    (bios_parameter_data, extended_bpd)
+}
+
+/// Returns the volume id formatted like a UUID and the volume label of the extended BPB
+fn volume_info(extended_bpd: &ExtendedBIOSPB) -> VolumeInfo {
+    let volume_id = extended_bpd.volume_id;
+    let label = match extended_bpd.signature {
+        EXTENDED_BOOT_SIGNATURE => core::str::from_utf8(&extended_bpd.volume_label)
+            .ok()
+            .map(|label| label.trim_end())
+            .filter(|label| !label.is_empty() && *label != NO_VOLUME_LABEL)
+            .map(|label| label.to_string()),
+        _ => None,
+    };
+
+    VolumeInfo {
+        label,
+        uuid: format!("{:04X}-{:04X}", volume_id >> 16, volume_id & 0xFFFF),
+    }
 }
 
 impl FATFileSystem {
     pub fn new(part: Weak<Partition>) -> Result<FATFileSystem, FsInitError> {
-        let p = part.upgrade().unwrap();
+        let p = part.upgrade().ok_or(FsInitError::InvalidSuperBlock)?;
 
-        let mut bios_parameter_block: [u8; BLOCK_SIZE] = unsafe {
-            transmute(MaybeUninit::<[MaybeUninit<u8>; BLOCK_SIZE]>::uninit().assume_init())
+        let mut fs = FATFileSystem {
+            partition: part,
+            sector_count: 0,
+            reserved_sector_count: 0,
+            data_sectors_start: 0,
+            sectors_per_cluster: 0,
+            fat_count: 0,
+            sectors_per_fat: 0,
+            root_cluster: ClusterIndex(0),
+            volume: VolumeInfo {
+                label: None,
+                uuid: String::new(),
+            },
+            inode_table: SlotAllocator::new(None),
+            inode_generation: 0,
+            media_generation: 0,
+            unavailable: AtomicBool::new(true),
         };
+        fs.load(&p)?;
 
-        p.read(IORequest::new(
-            LinearBlockAddress::new(0),
-            1,
-            &mut bios_parameter_block[..],
-        ))
-        .unwrap();
+        Ok(fs)
+    }
 
-        if bios_parameter_block[510..] != MAGIC_NUMBER {
-            return Err(FsInitError::InvalidMagic);
-        }
+    /// Reads the boot sector and sets up the state derived from it, this is done when the
+    /// file system is mounted and again when it is revalidated after a media change. Every
+    /// inode that was handed out before is forgotten.
+    fn load(&mut self, part: &Partition) -> Result<(), FsInitError> {
+        // the generation is read first so a change while loading is noticed later
+        self.media_generation = part
+            .media_generation()
+            .ok_or(FsInitError::InvalidSuperBlock)?;
 
-        let bios_parameter_data: &BIOSPBLegacy = unsafe {
-            (bios_parameter_block.as_ptr() as *const BIOSPBLegacy)
-                .as_ref()
-                .unwrap()
-        };
+        let boot_sector = read_boot_sector(part)?;
+        let (bios_parameter_data, extended_bpd) = parse_boot_sector(&boot_sector);
 
         if bios_parameter_data.root_dir_entries != 0 {
             log!("FAT: non FAT-32 FAT filesystem detected");
             return Err(FsInitError::InvalidSuperBlock);
         }
 
-        let extended_bpd: &ExtendedBIOSPB = unsafe {
-            (bios_parameter_block
-                .as_ptr()
-                .add(core::mem::size_of::<BIOSPBLegacy>()) as *const ExtendedBIOSPB)
-                .as_ref()
-                .unwrap()
-        };
-
         let lba_count = match bios_parameter_data.total_sectors_small {
             0 => bios_parameter_data.total_sectors_large as usize,
             n => n as usize,
@@ -234,22 +324,120 @@ impl FATFileSystem {
         // this is always zero on FAT-32
         let root_dir_sectors = 0;
 
-        let mut fs = FATFileSystem {
-            partition: part,
-            sector_count: lba_count,
-            reserved_sector_count,
-            data_sectors_start: reserved_sector_count + (fat_count * fat_size) + root_dir_sectors,
-            sectors_per_cluster: bios_parameter_data.sectors_per_cluster as usize,
-            fat_count,
-            root_cluster: ClusterIndex(extended_bpd.root_dir_cluster as usize),
-            inode_table: SlotAllocator::new(None),
+        self.sector_count = lba_count;
+        self.reserved_sector_count = reserved_sector_count;
+        self.data_sectors_start = reserved_sector_count + (fat_count * fat_size) + root_dir_sectors;
+        self.sectors_per_cluster = bios_parameter_data.sectors_per_cluster as usize;
+        self.fat_count = fat_count;
+        self.sectors_per_fat = fat_size;
+        self.root_cluster = ClusterIndex(extended_bpd.root_dir_cluster as usize);
+        self.volume = volume_info(extended_bpd);
+
+        log!(
+            "FAT: volume {} label: {}",
+            self.volume.uuid,
+            self.volume.label.as_deref().unwrap_or("none")
+        );
+
+        // root inode, it stays 0 so the VFS can keep using it across reloads
+        self.inode_table = SlotAllocator::new(None);
+        self.inode_generation = self.inode_generation.wrapping_add(1);
+        self.inode_table
+            .allocate(Some(0), DirectoryIndex::new(ClusterIndex(0), 0));
+        self.unavailable.store(false, Ordering::Relaxed);
+
+        // partial cluster reads and writes go through a bounce buffer of a whole cluster
+        let cluster_layout = core::alloc::Layout::array::<SectorBuf>(self.sectors_per_cluster);
+        if let Ok(layout) = cluster_layout {
+            if slab::create_cache("fat_cluster", layout).is_none() {
+                warn!("FAT: failed to create the cluster buffer cache");
+            }
+        }
+
+        let orphans = self.free_orphaned_clusters();
+        if orphans > 0 {
+            warn!("FAT: freed {} orphaned clusters", orphans);
+        }
+
+        match self.available() {
+            true => Ok(()),
+            false => Err(FsInitError::InvalidSuperBlock),
+        }
+    }
+
+    /// Returns the partition if its medium is the one the boot sector was read from and no
+    /// request failed since, otherwise the file system becomes unavailable
+    fn medium(&self) -> Option<Arc<Partition>> {
+        match self.partition.upgrade() {
+            Some(part)
+                if self.available() && part.media_generation() == Some(self.media_generation) =>
+            {
+                Some(part)
+            }
+            _ => {
+                self.mark_unavailable();
+                None
+            }
+        }
+    }
+
+    fn available(&self) -> bool {
+        !self.unavailable.load(Ordering::Relaxed)
+    }
+
+    fn mark_unavailable(&self) {
+        if !self.unavailable.swap(true, Ordering::Relaxed) {
+            warn!(
+                "FAT: volume {} is unavailable until it is revalidated",
+                self.volume.uuid
+            );
+        }
+    }
+
+    /// Reads blocks of the partition. If the medium is unavailable the buffer is zeroed, the
+    /// callers see empty directories and the operation fails once it returns to the VFS.
+    fn read_blocks(&self, req: IORequest) {
+        let IORequest { lba, size, buff } = req;
+        let res = match self.medium() {
+            Some(part) => part.read(IORequest::new(lba, size, &mut buff[..])),
+            None => Err(BlockDeviceError::NoMedium),
         };
 
-        // root inode
-        fs.inode_table
-            .allocate(Some(0), DirectoryIndex::new(ClusterIndex(0), 0));
+        if res.is_err() {
+            self.mark_unavailable();
+            buff.fill(0);
+        }
+    }
 
-        Ok(fs)
+    /// Writes blocks of the partition, nothing is written once the medium is unavailable so
+    /// a new medium is never overwritten with the state of the old one
+    fn write_blocks(&self, req: IORequest) {
+        let res = match self.medium() {
+            Some(part) => part.write(req),
+            None => Err(BlockDeviceError::NoMedium),
+        };
+
+        if res.is_err() {
+            self.mark_unavailable();
+        }
+    }
+
+    /// Runs a file system operation, __err__ is returned if the medium is unavailable before
+    /// it starts or if a block request failed while it ran
+    fn on_medium<T, E>(
+        &mut self,
+        err: impl Fn() -> E,
+        op: impl FnOnce(&mut Self) -> Result<T, E>,
+    ) -> Result<T, E> {
+        if self.medium().is_none() {
+            return Err(err());
+        }
+
+        let res = op(self);
+        match self.available() {
+            true => res,
+            false => Err(err()),
+        }
     }
 
     #[inline]
@@ -271,30 +459,341 @@ impl FATFileSystem {
     fn get_fat_entry(&self, cluster: ClusterIndex) -> ClusterIndex {
         let (table_lba_idx, table_idx) = cluster.fat_position();
 
-        let p = self.partition.upgrade().unwrap();
-        let mut sector_data: [u8; BLOCK_SIZE] = unsafe {
-            transmute(MaybeUninit::<[MaybeUninit<u8>; BLOCK_SIZE]>::uninit().assume_init())
-        };
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
 
         let table_lba = self.fat_table_lba(table_lba_idx);
-        p.read(IORequest::new(table_lba, 1, &mut sector_data[..]))
-            .unwrap();
-
-        // TODO: do this safely
-        let val = unsafe {
-            let ptr = (sector_data.as_ptr() as *const u32).add(table_idx);
-            ptr.read()
-        } as usize;
+        self.read_blocks(sector_data.request(table_lba));
+
+        // the chain ends if the FAT could not be read
+        if !self.available() {
+            return ClusterIndex(CLUSTER_END_OF_CHAIN);
+        }
+
+        let val = read_u32_le(&sector_data, table_idx * core::mem::size_of::<u32>()) as usize;
         ClusterIndex(val & 0x0FFFFFFF)
     }
 
+    /// Writes the specified cluster to every copy of the File Allocation Table
+    fn set_fat_entry(&self, cluster: ClusterIndex, val: ClusterIndex) {
+        let (table_lba_idx, table_idx) = cluster.fat_position();
+
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+
+        let table_lba = self.fat_table_lba(table_lba_idx);
+        self.read_blocks(sector_data.request(table_lba.clone()));
+
+        let offset = table_idx * core::mem::size_of::<u32>();
+        let old = read_u32_le(&sector_data, offset);
+        // the highest 4 bits are reserved and must be preserved
+        let new = (old & 0xF0000000) | (val.0 as u32 & 0x0FFFFFFF);
+        write_u32_le(&mut sector_data, offset, new);
+
+        let table_lba = table_lba.inner();
+        for fat in 0..self.fat_count {
+            let lba = LinearBlockAddress::new(table_lba + fat * self.sectors_per_fat);
+            self.write_blocks(sector_data.request(lba));
+        }
+    }
+
+    /// Returns the number of clusters including the 2 reserved ones
+    fn cluster_count(&self) -> usize {
+        (self.sector_count - self.data_sectors_start) / self.sectors_per_cluster + 2
+    }
+
+    /// Finds a free cluster, marks it as the end of a chain and zeroes it
+    fn allocate_cluster(&self) -> Option<ClusterIndex> {
+        // TODO: use the next free cluster hint in FSInfo
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        let cluster_count = self.cluster_count();
+
+        for block_idx in 0..cluster_count.div_ceil(FAT_ENTRIES_PER_BLOCK) {
+            let table_lba = self.fat_table_lba(block_idx);
+            self.read_blocks(sector_data.request(table_lba));
+            if !self.available() {
+                return None;
+            }
+
+            for i in 0..FAT_ENTRIES_PER_BLOCK {
+                let cluster = block_idx * FAT_ENTRIES_PER_BLOCK + i;
+                if cluster < 2 {
+                    continue;
+                } else if cluster >= cluster_count {
+                    return None;
+                }
+
+                let offset = i * core::mem::size_of::<u32>();
+                let val = read_u32_le(&sector_data, offset);
+                if val & 0x0FFFFFFF != 0 {
+                    continue;
+                }
+
+                let cluster = ClusterIndex(cluster);
+                self.set_fat_entry(cluster, ClusterIndex(CLUSTER_END_OF_CHAIN));
+                self.zero_cluster(cluster);
+                return Some(cluster);
+            }
+        }
+
+        None
+    }
+
+    fn zero_cluster(&self, cluster: ClusterIndex) {
+        let mut data = SectorVec::zeroed(self.sectors_per_cluster);
+        self.write_blocks(data.request(self.cluster_start_lba(cluster)));
+    }
+
+    /// Writes the modified blocks back to the disk
+    fn sync(&self) {
+        if let Some(p) = self.medium() {
+            if p.flush().is_err() {
+                self.mark_unavailable();
+            }
+        }
+    }
+
+    /// Makes sure every write before the barrier reaches the disk before any write after it.
+    /// The block cache writes dirty blocks back in LBA order which would put the FAT before
+    /// the data clusters, so the steps of an ordered update are separated by barriers.
+    fn write_barrier(&self) {
+        self.sync();
+    }
+
+    /// Allocates __count__ zeroed clusters linked into a chain that no directory entry refers
+    /// to yet, if the update is interrupted the chain is freed at the next mount
+    fn allocate_cluster_chain(&self, count: usize) -> Option<Vec<ClusterIndex>> {
+        let mut chain: Vec<ClusterIndex> = Vec::with_capacity(count);
+        for _ in 0..count {
+            let cluster = match self.allocate_cluster() {
+                Some(cluster) => cluster,
+                None => {
+                    if let Some(start) = chain.first() {
+                        self.free_cluster_chain(*start);
+                    }
+                    return None;
+                }
+            };
+
+            if let Some(prev) = chain.last() {
+                self.set_fat_entry(*prev, cluster);
+            }
+            chain.push(cluster);
+        }
+
+        Some(chain)
+    }
+
+    /// Marks every cluster of a cluster chain free
+    fn free_cluster_chain(&self, start: ClusterIndex) {
+        // TODO: update the free cluster count in FSInfo
+        let mut cluster = start;
+        while cluster.0 >= 2 && cluster.valid_cluster() {
+            let next = self.get_fat_entry(cluster);
+            self.set_fat_entry(cluster, ClusterIndex(0));
+            cluster = next;
+        }
+    }
+
+    #[inline]
+    fn cluster_marked(cluster: ClusterIndex, reachable: &[u64]) -> bool {
+        reachable[cluster.0 / 64] & (1 << (cluster.0 % 64)) != 0
+    }
+
+    /// Marks the clusters of a chain in __reachable__, stops at clusters that are already
+    /// marked so cross-linked or looping chains are only walked once
+    fn mark_cluster_chain(&self, start: ClusterIndex, reachable: &mut [u64]) {
+        let mut cluster = start;
+        while cluster.0 >= 2 && cluster.valid_cluster() && cluster.0 < self.cluster_count() {
+            if Self::cluster_marked(cluster, reachable) {
+                return;
+            }
+
+            reachable[cluster.0 / 64] |= 1 << (cluster.0 % 64);
+            cluster = self.get_fat_entry(cluster);
+        }
+    }
+
+    /// Calls __f__ with every short entry of the directory except . and .., the volume label
+    /// and long name entries, along with the LBA of the sector and the index of the entry in
+    /// it. Every sector of the clusters is read unlike in for_each_dir_ent, missing an entry
+    /// here would free or repair the clusters of a live file.
+    fn for_each_short_dir_ent<F>(&self, dir_start_cluster: ClusterIndex, mut f: F)
+    where
+        F: FnMut(&ShortDirectoryEntry, &LinearBlockAddress, usize),
+    {
+        const ENT_SIZE: usize = core::mem::size_of::<ShortDirectoryEntry>();
+
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        let cluster_count = self.cluster_count();
+
+        let mut cluster = dir_start_cluster;
+        // a chain can not be longer than the number of clusters unless it loops
+        for _ in 0..cluster_count {
+            if cluster.0 < 2 || cluster.0 >= cluster_count {
+                return;
+            }
+
+            let start_lba = self.cluster_start_lba(cluster).inner();
+            for sector in 0..self.sectors_per_cluster {
+                let lba = LinearBlockAddress::new(start_lba + sector);
+                self.read_blocks(sector_data.request(lba.clone()));
+
+                for i in 0..DIR_ENTRIES_PER_SECTOR {
+                    let offset = i * ENT_SIZE;
+                    match sector_data[offset] {
+                        // end of directory entries
+                        0 => return,
+                        // unused or the . and .. entries
+                        DIR_ENT_UNUSED | b'.' => continue,
+                        _ => {}
+                    }
+
+                    let ent = unsafe {
+                        (sector_data.as_ptr().add(offset) as *const ShortDirectoryEntry)
+                            .read_unaligned()
+                    };
+                    if ent.attr == DIR_ENT_LONG_NAME || ent.attr & DIR_ENT_VOLUME_ID > 0 {
+                        continue;
+                    }
+
+                    f(&ent, &lba, i);
+                }
+            }
+
+            cluster = self.get_fat_entry(cluster);
+        }
+    }
+
+    /// Marks the clusters of the directory starting at __dir_start_cluster__ and of every
+    /// file and directory in it recursively
+    fn mark_reachable_clusters(&self, dir_start_cluster: ClusterIndex, reachable: &mut [u64]) {
+        let mut dirs = Vec::from([dir_start_cluster]);
+
+        while let Some(dir) = dirs.pop() {
+            // a directory that was already visited is a loop in a corrupted volume
+            if dir.0 < 2 || dir.0 >= self.cluster_count() || Self::cluster_marked(dir, reachable) {
+                continue;
+            }
+            self.mark_cluster_chain(dir, reachable);
+
+            self.for_each_short_dir_ent(dir, |ent, _, _| {
+                let data_cluster = ClusterIndex(Self::fuse_cluster_parts(
+                    ent.cluster_low,
+                    ent.cluster_high,
+                ) as usize);
+                if ent.attr & DIR_ENT_DIRECTORY > 0 {
+                    dirs.push(data_cluster);
+                } else {
+                    self.mark_cluster_chain(data_cluster, reachable);
+                }
+            });
+        }
+    }
+
+    /// Follows the chain starting at __start__ and marks its clusters in __owned__. A link to
+    /// a free, reserved, bad or out of range cluster or to a cluster that another chain
+    /// already owns is an error, which is repaired by ending the chain at the cluster before
+    /// it. Returns the number of clusters in the valid part of the chain.
+    fn check_cluster_chain(
+        &self,
+        start: ClusterIndex,
+        owned: &mut [u64],
+        mode: FsckMode,
+        report: &mut FsckReport,
+    ) -> usize {
+        let cluster_count = self.cluster_count();
+        let mut prev: Option<ClusterIndex> = None;
+        let mut cluster = start;
+        let mut len = 0;
+
+        // every value above the bad cluster marker ends the chain
+        while cluster.0 <= CLUSTER_BAD {
+            let problem = if cluster.0 < 2 || cluster.0 >= cluster_count || cluster.0 == CLUSTER_BAD
+            {
+                "invalid"
+            } else if Self::cluster_marked(cluster, owned) {
+                "cross-linked"
+            } else {
+                ""
+            };
+
+            if !problem.is_empty() {
+                report.errors += 1;
+                match prev {
+                    Some(prev) => {
+                        warn!(
+                            "FAT: cluster {} links to {} cluster {}",
+                            prev.0, problem, cluster.0
+                        );
+                        if mode == FsckMode::Repair {
+                            self.set_fat_entry(prev, ClusterIndex(CLUSTER_END_OF_CHAIN));
+                            report.repaired += 1;
+                        }
+                    }
+                    // the directory entry would have to be changed, the data of the file is
+                    // not known to be lost so it is left to the user
+                    None => warn!("FAT: chain starts at {} cluster {}", problem, cluster.0),
+                }
+                break;
+            }
+
+            owned[cluster.0 / 64] |= 1 << (cluster.0 % 64);
+            len += 1;
+            prev = Some(cluster);
+            cluster = self.get_fat_entry(cluster);
+        }
+
+        len
+    }
+
+    /// Frees the clusters that are allocated in the FAT but not reachable from any directory
+    /// entry. Ordered updates allocate clusters before linking them so an interrupted write
+    /// leaves orphaned chains behind but never an entry that points to free clusters.
+    /// Returns the number of freed clusters.
+    fn free_orphaned_clusters(&self) -> usize {
+        let cluster_count = self.cluster_count();
+        let mut reachable: Vec<u64> = alloc::vec![0; cluster_count.div_ceil(64)];
+        self.mark_reachable_clusters(self.root_cluster, &mut reachable);
+
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        let mut orphans: Vec<ClusterIndex> = Vec::new();
+
+        for block_idx in 0..cluster_count.div_ceil(FAT_ENTRIES_PER_BLOCK) {
+            self.read_blocks(sector_data.request(self.fat_table_lba(block_idx)));
+
+            for i in 0..FAT_ENTRIES_PER_BLOCK {
+                let cluster = block_idx * FAT_ENTRIES_PER_BLOCK + i;
+                if cluster < 2 {
+                    continue;
+                } else if cluster >= cluster_count {
+                    break;
+                }
+
+                let val = read_u32_le(&sector_data, i * core::mem::size_of::<u32>()) as usize;
+                let val = val & 0x0FFFFFFF;
+                let cluster = ClusterIndex(cluster);
+                if val != 0 && val != CLUSTER_BAD && !Self::cluster_marked(cluster, &reachable) {
+                    orphans.push(cluster);
+                }
+            }
+        }
+
+        for cluster in orphans.iter() {
+            self.set_fat_entry(*cluster, ClusterIndex(0));
+        }
+        if !orphans.is_empty() {
+            self.sync();
+        }
+
+        orphans.len()
+    }
+
     fn parse_short_dir_ent_filename(filename: &[u8; 11]) -> String {
         let filebase = &filename[..8];
-        let filename_len = filebase.iter().position(|c| *c == b' ').unwrap();
+        let filename_len = filebase.iter().position(|c| *c == b' ').unwrap_or(8);
         let filebase_str = core::str::from_utf8(&filebase[..filename_len]).unwrap();
 
         let extension = &filename[8..];
-        let extension_len = extension.iter().position(|c| *c == b' ').unwrap();
+        let extension_len = extension.iter().position(|c| *c == b' ').unwrap_or(3);
         let extension_str = core::str::from_utf8(&extension[..extension_len]).unwrap();
 
         // TODO: make this work without allocation
@@ -312,23 +811,19 @@ impl FATFileSystem {
         u32::from_le_bytes([low as u8, (low >> 8) as u8, high as u8, (high >> 8) as u8])
     }
 
-    fn find_dir_ent(
-        &self,
-        dir_start_cluster: ClusterIndex,
-        filename: &str,
-    ) -> Option<DirectoryEntry> {
-        let p = self.partition.upgrade().unwrap();
-        let mut sector_data: [u8; BLOCK_SIZE] = unsafe {
-            transmute(MaybeUninit::<[MaybeUninit<u8>; BLOCK_SIZE]>::uninit().assume_init())
-        };
+    /// Calls __f__ with the name and the entry of every file in the directory until it returns true
+    fn for_each_dir_ent<F>(&self, dir_start_cluster: ClusterIndex, mut f: F)
+    where
+        F: FnMut(&str, DirectoryEntry) -> bool,
+    {
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
 
         let mut long_file_name = String::with_capacity(MAX_FILENAME_LENGTH);
         let mut cluster = dir_start_cluster;
 
         while cluster.valid_cluster() {
             let sector = self.cluster_start_lba(cluster);
-            p.read(IORequest::new(sector, 1, &mut sector_data[..]))
-                .unwrap();
+            self.read_blocks(sector_data.request(sector));
 
             // TODO: check the other sectors of the directory
             for i in 0..DIR_ENTRIES_PER_SECTOR {
@@ -337,7 +832,7 @@ impl FATFileSystem {
                 // first byte of the entry
                 let long_entry = match sector_data[offset] {
                     // end of directory entries
-                    0 => return None,
+                    0 => return,
                     // unused
                     0xE5 => continue,
                     // attribute
@@ -383,26 +878,19 @@ impl FATFileSystem {
                             .unwrap()
                     };
 
+                    // the volume label is not a file
+                    if ent.attr & DIR_ENT_VOLUME_ID > 0 {
+                        long_file_name.clear();
+                        continue;
+                    }
+
                     let ent_type = if ent.attr & DIR_ENT_DIRECTORY > 0 {
                         DirectoryEntryType::Directory
                     } else {
                         DirectoryEntryType::File(ent.file_size as usize)
                     };
 
-                    if !long_file_name.is_empty() {
-                        if long_file_name != filename {
-                            long_file_name.clear();
-                            continue;
-                        }
-                    } else {
-                        // TODO: test this
-                        let full = &Self::parse_short_dir_ent_filename(&ent.name);
-                        if full != filename {
-                            continue;
-                        }
-                    };
-
-                    return Some(DirectoryEntry {
+                    let dir_ent = DirectoryEntry {
                         data_cluster_start: ClusterIndex(Self::fuse_cluster_parts(
                             ent.cluster_low,
                             ent.cluster_high,
@@ -410,8 +898,315 @@ impl FATFileSystem {
                         ent_type,
                         directory_cluster: cluster,
                         directory_cluster_index: i,
-                    });
+                    };
+
+                    let stop = if !long_file_name.is_empty() {
+                        f(&long_file_name, dir_ent)
+                    } else {
+                        // TODO: test this
+                        f(&Self::parse_short_dir_ent_filename(&ent.name), dir_ent)
+                    };
+
+                    if stop {
+                        return;
+                    }
+
+                    long_file_name.clear();
+                }
+            }
+
+            cluster = self.get_fat_entry(cluster);
+        }
+    }
+
+    fn find_dir_ent(
+        &self,
+        dir_start_cluster: ClusterIndex,
+        filename: &str,
+    ) -> Option<DirectoryEntry> {
+        let mut found = None;
+        self.for_each_dir_ent(dir_start_cluster, |name, ent| {
+            if name != filename {
+                return false;
+            }
+
+            found = Some(ent);
+            true
+        });
+
+        found
+    }
+
+    /// Returns whether a directory has any entries besides . and ..
+    fn dir_is_empty(&self, dir_start_cluster: ClusterIndex) -> bool {
+        let mut empty = true;
+        self.for_each_dir_ent(dir_start_cluster, |name, _| {
+            if name == "." || name == ".." {
+                return false;
+            }
+
+            empty = false;
+            true
+        });
+
+        empty
+    }
+
+    /// Reads the sector a directory entry is in
+    fn read_dir_ent_sector(&self, ent: &DirectoryEntry, sector_data: &mut SectorBuf) {
+        let lba = self.cluster_start_lba(ent.directory_cluster);
+        self.read_blocks(sector_data.request(lba));
+    }
+
+    fn write_dir_ent_sector(&self, ent: &DirectoryEntry, sector_data: &mut SectorBuf) {
+        let lba = self.cluster_start_lba(ent.directory_cluster);
+        self.write_blocks(sector_data.request(lba));
+    }
+
+    /// Calls __f__ with the short entry of a directory entry then writes it back to the disk
+    fn update_short_dir_ent<F>(&self, ent: &DirectoryEntry, f: F)
+    where
+        F: FnOnce(&mut ShortDirectoryEntry),
+    {
+        let lba = self.cluster_start_lba(ent.directory_cluster);
+        self.update_short_dir_ent_at(lba, ent.directory_cluster_index, f);
+    }
+
+    /// Calls __f__ with the short entry at __index__ of the sector at __lba__ then writes it
+    /// back to the disk
+    fn update_short_dir_ent_at<F>(&self, lba: LinearBlockAddress, index: usize, f: F)
+    where
+        F: FnOnce(&mut ShortDirectoryEntry),
+    {
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        self.read_blocks(sector_data.request(lba.clone()));
+
+        let offset = index * core::mem::size_of::<ShortDirectoryEntry>();
+        let short_ent_ptr =
+            unsafe { sector_data.as_mut_ptr().add(offset) as *mut ShortDirectoryEntry };
+
+        let mut short_ent = unsafe { short_ent_ptr.read_unaligned() };
+        f(&mut short_ent);
+        unsafe { short_ent_ptr.write_unaligned(short_ent) };
+
+        self.write_blocks(sector_data.request(lba));
+    }
+
+    /// Returns a copy of the short entry of a directory entry as it is on the disk
+    fn read_short_dir_ent(&self, ent: &DirectoryEntry) -> ShortDirectoryEntry {
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        self.read_dir_ent_sector(ent, &mut sector_data);
+
+        let offset = ent.directory_cluster_index * core::mem::size_of::<ShortDirectoryEntry>();
+        unsafe { (sector_data.as_ptr().add(offset) as *const ShortDirectoryEntry).read_unaligned() }
+    }
+
+    /// Marks a directory entry and the long file name entries before it unused
+    fn clear_dir_ent(&self, ent: &DirectoryEntry) {
+        const ENT_SIZE: usize = core::mem::size_of::<ShortDirectoryEntry>();
+
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        self.read_dir_ent_sector(ent, &mut sector_data);
+
+        let mut idx = ent.directory_cluster_index;
+        sector_data[idx * ENT_SIZE] = DIR_ENT_UNUSED;
+
+        while idx > 0 {
+            let offset = (idx - 1) * ENT_SIZE;
+            if sector_data[offset] == DIR_ENT_UNUSED
+                || sector_data[offset + 0xB] != DIR_ENT_LONG_NAME
+            {
+                break;
+            }
+
+            sector_data[offset] = DIR_ENT_UNUSED;
+            idx -= 1;
+        }
+
+        self.write_dir_ent_sector(ent, &mut sector_data);
+    }
+
+    /// Returns whether any entry in the directory has __short_name__ as its 8.3 name
+    fn short_name_exists(&self, dir_start_cluster: ClusterIndex, short_name: &[u8; 11]) -> bool {
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        let mut cluster = dir_start_cluster;
+
+        while cluster.valid_cluster() {
+            let sector = self.cluster_start_lba(cluster);
+            self.read_blocks(sector_data.request(sector));
+
+            for i in 0..DIR_ENTRIES_PER_SECTOR {
+                let offset = i * core::mem::size_of::<ShortDirectoryEntry>();
+                match sector_data[offset] {
+                    0 => return false,
+                    DIR_ENT_UNUSED => continue,
+                    _ if sector_data[offset + 0xB] == DIR_ENT_LONG_NAME => continue,
+                    _ => {
+                        if &sector_data[offset..offset + 11] == short_name {
+                            return true;
+                        }
+                    }
+                }
+            }
+
+            cluster = self.get_fat_entry(cluster);
+        }
+
+        false
+    }
+
+    /// Creates a unique 8.3 name for a long file name in the form of BASIS~N.EXT
+    fn generate_short_name(&self, dir_start_cluster: ClusterIndex, name: &str) -> [u8; 11] {
+        fn short_name_chars(s: &str) -> impl Iterator<Item = u8> + '_ {
+            s.bytes()
+                .filter(|c| c.is_ascii_alphanumeric() || b"$%'-_@~`!(){}^#&".contains(c))
+                .map(|c| c.to_ascii_uppercase())
+        }
+
+        let (base, extension) = match name.rfind('.') {
+            Some(idx) if idx > 0 => (&name[..idx], &name[idx + 1..]),
+            _ => (name, ""),
+        };
+
+        let mut short_name = [b' '; 11];
+        for (i, c) in short_name_chars(extension).take(3).enumerate() {
+            short_name[8 + i] = c;
+        }
+
+        let basis: Vec<u8> = short_name_chars(base).take(6).collect();
+        for n in 1..1_000_000 {
+            let tail = format!("~{}", n);
+            let basis_len = usize::min(basis.len(), 8 - tail.len());
+
+            short_name[..8].fill(b' ');
+            short_name[..basis_len].copy_from_slice(&basis[..basis_len]);
+            short_name[basis_len..basis_len + tail.len()].copy_from_slice(tail.as_bytes());
+
+            if !self.short_name_exists(dir_start_cluster, &short_name) {
+                break;
+            }
+        }
+
+        short_name
+    }
+
+    fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+        short_name.iter().fold(0u8, |sum, &c| {
+            ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c)
+        })
+    }
+
+    /// Creates a long file name entry that holds the part of the name starting at
+    /// (order - 1) * 13 characters
+    fn create_long_dir_ent(name: &[u8], order: u8, last: bool, checksum: u8) -> LongDirectoryEntry {
+        let start = (order as usize - 1) * UCS2_CHARS_PER_LONG_ENTRY;
+
+        // the name is terminated by a null character and padded with 0xFFFF
+        let mut chars = [0xFFFFu16; UCS2_CHARS_PER_LONG_ENTRY];
+        for (i, ch) in chars.iter_mut().enumerate() {
+            match (start + i).cmp(&name.len()) {
+                core::cmp::Ordering::Less => *ch = name[start + i] as u16,
+                core::cmp::Ordering::Equal => *ch = 0,
+                core::cmp::Ordering::Greater => break,
+            }
+        }
+
+        let mut bytes = [0u8; CHARS_PER_LONG_ENTRY];
+        for (i, ch) in chars.iter().enumerate() {
+            bytes[i * 2..i * 2 + 2].copy_from_slice(&ch.to_le_bytes());
+        }
+
+        LongDirectoryEntry {
+            order: if last {
+                order | LONG_DIR_ENTRY_LAST_ENTRY_MARKER
+            } else {
+                order
+            },
+            name1: bytes[0..10].try_into().unwrap(),
+            attr: DIR_ENT_LONG_NAME,
+            ent_type: 0,
+            checksum,
+            name2: bytes[10..22].try_into().unwrap(),
+            cluster_low: 0,
+            name3: bytes[22..26].try_into().unwrap(),
+        }
+    }
+
+    /// Writes __short_ent__ with __name__ as its long file name to the first free slots of
+    /// the directory, the name field of __short_ent__ is replaced with a generated 8.3 name.
+    /// Returns None if the directory has no room for the entries
+    fn add_dir_ent(
+        &self,
+        dir_start_cluster: ClusterIndex,
+        name: &str,
+        mut short_ent: ShortDirectoryEntry,
+    ) -> Option<DirectoryEntry> {
+        const ENT_SIZE: usize = core::mem::size_of::<ShortDirectoryEntry>();
+
+        short_ent.name = self.generate_short_name(dir_start_cluster, name);
+        let checksum = Self::short_name_checksum(&short_ent.name);
+
+        let name = name.as_bytes();
+        let long_ent_count = name.len().div_ceil(UCS2_CHARS_PER_LONG_ENTRY);
+        let needed = long_ent_count + 1;
+
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        let mut cluster = dir_start_cluster;
+
+        // TODO: extend the directory with a new cluster if it is full
+        while cluster.valid_cluster() {
+            let sector = self.cluster_start_lba(cluster);
+            self.read_blocks(sector_data.request(sector.clone()));
+
+            let mut free_run = 0;
+            for i in 0..DIR_ENTRIES_PER_SECTOR {
+                match sector_data[i * ENT_SIZE] {
+                    0 | DIR_ENT_UNUSED => free_run += 1,
+                    _ => free_run = 0,
+                }
+
+                if free_run < needed {
+                    continue;
+                }
+
+                // long entries are stored in reverse order before the short entry
+                let first = i + 1 - needed;
+                for order in 1..=long_ent_count {
+                    let long_ent = Self::create_long_dir_ent(
+                        name,
+                        order as u8,
+                        order == long_ent_count,
+                        checksum,
+                    );
+                    let slot = first + long_ent_count - order;
+                    unsafe {
+                        (sector_data.as_mut_ptr().add(slot * ENT_SIZE) as *mut LongDirectoryEntry)
+                            .write_unaligned(long_ent);
+                    }
+                }
+
+                unsafe {
+                    (sector_data.as_mut_ptr().add(i * ENT_SIZE) as *mut ShortDirectoryEntry)
+                        .write_unaligned(short_ent);
                 }
+
+                self.write_blocks(sector_data.request(sector));
+
+                let ent_type = if short_ent.attr & DIR_ENT_DIRECTORY > 0 {
+                    DirectoryEntryType::Directory
+                } else {
+                    DirectoryEntryType::File(short_ent.file_size as usize)
+                };
+
+                return Some(DirectoryEntry {
+                    ent_type,
+                    data_cluster_start: ClusterIndex(Self::fuse_cluster_parts(
+                        short_ent.cluster_low,
+                        short_ent.cluster_high,
+                    ) as usize),
+                    directory_cluster: cluster,
+                    directory_cluster_index: i,
+                });
             }
 
             cluster = self.get_fat_entry(cluster);
@@ -420,14 +1215,90 @@ impl FATFileSystem {
         None
     }
 
-    fn get_dir_ent(&self, dir_cluster: ClusterIndex, index: usize) -> DirectoryEntry {
-        let p = self.partition.upgrade().unwrap();
-        let mut block_data: [u8; BLOCK_SIZE] = unsafe {
-            transmute(MaybeUninit::<[MaybeUninit<u8>; BLOCK_SIZE]>::uninit().assume_init())
+    /// Creates a short entry that points to __data_cluster__, the name is filled in by add_dir_ent
+    fn new_short_dir_ent(attr: u8, data_cluster: ClusterIndex) -> ShortDirectoryEntry {
+        // TODO: timestamps
+        ShortDirectoryEntry {
+            name: [b' '; 11],
+            attr,
+            reserved: 0,
+            create_time_tenth: 0,
+            create_time: 0,
+            create_date: 0,
+            last_acc_date: 0,
+            cluster_high: (data_cluster.0 >> 16) as u16,
+            write_time: 0,
+            write_date: 0,
+            cluster_low: data_cluster.0 as u16,
+            file_size: 0,
+        }
+    }
+
+    /// Returns the first cluster of the directory a new file at __path__ goes into and
+    /// the name of the new file
+    fn find_parent_for_create<'a>(
+        &self,
+        path: Path<'a>,
+    ) -> Result<(ClusterIndex, &'a str), FsCreateError> {
+        if path.components_left() == 0 {
+            return Err(FsCreateError::AlreadyExists);
+        }
+
+        let parent_comps = path.components_left() - 1;
+        let parent_cluster = self
+            .find_dir_cluster(path.clone().shorten(parent_comps))
+            .map_err(FsCreateError::BadPath)?;
+        let name = path.last().unwrap();
+
+        if self.find_dir_ent(parent_cluster, name).is_some() {
+            return Err(FsCreateError::AlreadyExists);
+        }
+
+        Ok((parent_cluster, name))
+    }
+
+    /// Points the .. entry of a directory to its new parent
+    fn set_parent_dir_ent(&self, dir_start_cluster: ClusterIndex, parent: ClusterIndex) {
+        const ENT_SIZE: usize = core::mem::size_of::<ShortDirectoryEntry>();
+
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        let sector = self.cluster_start_lba(dir_start_cluster);
+        self.read_blocks(sector_data.request(sector.clone()));
+
+        // .. refers to the root directory with cluster 0
+        let parent = if parent.0 == self.root_cluster.0 {
+            0
+        } else {
+            parent.0 as u32
         };
 
+        for i in 0..DIR_ENTRIES_PER_SECTOR {
+            let offset = i * ENT_SIZE;
+            if sector_data[offset] == 0 {
+                break;
+            }
+
+            if &sector_data[offset..offset + 11] != b"..         " {
+                continue;
+            }
+
+            let ent =
+                unsafe { &mut *(sector_data.as_mut_ptr().add(offset) as *mut ShortDirectoryEntry) };
+            ent.cluster_low = parent as u16;
+            ent.cluster_high = (parent >> 16) as u16;
+
+            self.write_blocks(sector_data.request(sector));
+            return;
+        }
+
+        warn!("FAT: directory has no .. entry");
+    }
+
+    fn get_dir_ent(&self, dir_cluster: ClusterIndex, index: usize) -> DirectoryEntry {
+        let mut block_data: SectorBuf = SectorBuf::zeroed();
+
         let lba = self.cluster_start_lba(dir_cluster);
-        p.read(IORequest::new(lba, 1, &mut block_data[..])).unwrap();
+        self.read_blocks(block_data.request(lba));
 
         let mut offset = index * core::mem::size_of::<ShortDirectoryEntry>();
 
@@ -479,8 +1350,26 @@ impl FATFileSystem {
         }
     }
 
+    /// Returns None if the inode table is full
+    fn allocate_inode(&mut self, file: &DirectoryEntry) -> Option<FSInode> {
+        let inode = self.inode_table.allocate(
+            None,
+            DirectoryIndex::new(file.directory_cluster, file.directory_cluster_index),
+        )?;
+        let generation = (self.inode_generation as u64) << 32;
+        Some(FSInode(generation | inode as u64))
+    }
+
+    /// Returns the slot of `inode` in the inode table, or `None` if it was handed out before the
+    /// file system was last reloaded
+    fn inode_slot(&self, inode: FSInode) -> Option<usize> {
+        let slot = (inode.0 & 0xffff_ffff) as usize;
+        let generation = (inode.0 >> 32) as u32;
+        (slot == 0 || generation == self.inode_generation).then_some(slot)
+    }
+
     fn get_dir_index_from_inode(&self, inode: FSInode) -> Option<&DirectoryIndex> {
-        self.inode_table.get(inode.0 as usize)
+        self.inode_table.get(self.inode_slot(inode)?)
     }
 
     fn find_file(&self, mut path: Path) -> Option<DirectoryEntry> {
@@ -512,34 +1401,45 @@ impl FATFileSystem {
 
         self.find_dir_ent(start_cluster, path.next().unwrap())
     }
+
+    /// Returns the first cluster of the directory at __path__
+    fn find_dir_cluster(&self, path: Path) -> Result<ClusterIndex, FsPathError> {
+        if path.components_left() == 0 {
+            return Ok(self.root_cluster);
+        }
+
+        match self.find_file(path) {
+            Some(DirectoryEntry {
+                ent_type: DirectoryEntryType::Directory,
+                data_cluster_start,
+                ..
+            }) => Ok(data_cluster_start),
+            Some(_) => Err(FsPathError::NotADirectory),
+            None => Err(FsPathError::NoSuchFileOrDirectory),
+        }
+    }
 }
 
-impl FileSystemInner for FATFileSystem {
-    fn open(&mut self, path: Path) -> Result<FSInode, FsOpenError> {
+impl FATFileSystem {
+    fn open_path(&mut self, path: Path) -> Result<FSInode, FsOpenError> {
         if path.components_left() == 0 {
             return Ok(FSInode::new(0));
         }
 
         match self.find_file(path) {
-            Some(file) => {
-                let inode = self
-                    .inode_table
-                    .allocate(
-                        None,
-                        DirectoryIndex::new(file.directory_cluster, file.directory_cluster_index),
-                    )
-                    .unwrap();
-                Ok(FSInode(inode as u64))
-            }
+            Some(file) => self.allocate_inode(&file).ok_or(FsOpenError::NoSpace),
             None => Err(FsOpenError::BadPath(FsPathError::NoSuchFileOrDirectory)),
         }
     }
 
-    fn stat(&mut self, inode: FSInode, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+    fn stat_inode(&mut self, inode: FSInode, stat_buf: &mut Stat) -> Result<(), FsStatError> {
         let (file_size, file_type) = if inode == FSInode(0) {
             (0, S_IFDIR)
         } else {
-            let dir_index = self.get_dir_index_from_inode(inode).expect("Invalid inode");
+            // inodes from before a media change are stale
+            let dir_index = self
+                .get_dir_index_from_inode(inode)
+                .ok_or(FsStatError::BadPath(FsPathError::NoDevice))?;
             let file = self.get_dir_ent(dir_index.cluster, dir_index.cluster_index);
 
             match file.ent_type {
@@ -559,16 +1459,19 @@ impl FileSystemInner for FATFileSystem {
         Ok(())
     }
 
-    fn close(&mut self, inode: FSInode) -> Result<(), FsCloseError> {
+    fn close_inode(&mut self, inode: FSInode) -> Result<(), FsCloseError> {
         if inode == FSInode(0) {
             return Ok(());
         }
 
-        self.inode_table.deallocate(inode.0 as usize);
+        // a stale inode's slot may already belong to an inode of the current generation
+        if let Some(slot) = self.inode_slot(inode) {
+            self.inode_table.deallocate(slot);
+        }
         Ok(())
     }
 
-    fn read(
+    fn read_file(
         &mut self,
         inode: FSInode,
         offset: usize,
@@ -576,16 +1479,18 @@ impl FileSystemInner for FATFileSystem {
     ) -> Result<usize, FsReadError> {
         assert!(inode != FSInode(0));
 
-        let part = self.partition.upgrade().unwrap();
-
-        let dir_index = self.get_dir_index_from_inode(inode).expect("Invalid inode");
+        let dir_index = self
+            .get_dir_index_from_inode(inode)
+            .ok_or(FsReadError::IoError)?;
         let file = self.get_dir_ent(dir_index.cluster, dir_index.cluster_index);
 
         let lba = offset / BLOCK_SIZE;
         let mut cluster = file.data_cluster_start;
         for _ in 0..lba {
             cluster = self.get_fat_entry(cluster);
-            assert!(cluster.valid_cluster());
+            if !cluster.valid_cluster() {
+                return Err(FsReadError::IoError);
+            }
         }
 
         let mut buff_left = buff.len();
@@ -601,7 +1506,9 @@ impl FileSystemInner for FATFileSystem {
         let mut start_off = offset % cluster_size;
 
         while size_left > 0 && buff_left > 0 {
-            assert!(cluster.valid_cluster());
+            if !cluster.valid_cluster() {
+                return Err(FsReadError::IoError);
+            }
 
             let read = (if start_off > 0 {
                 cluster_size - start_off
@@ -615,22 +1522,15 @@ impl FileSystemInner for FATFileSystem {
             let sub_buff = &mut buff[total_read..total_read + read];
 
             if read == cluster_size {
-                part.read(IORequest {
+                self.read_blocks(IORequest {
                     lba: self.cluster_start_lba(cluster),
                     buff: &mut sub_buff[..],
                     size: self.sectors_per_cluster,
-                })
-                .unwrap();
+                });
             } else {
                 // TODO
-                let mut sector_buff = vec![0; cluster_size];
-
-                part.read(IORequest {
-                    lba: self.cluster_start_lba(cluster),
-                    buff: &mut sector_buff[..],
-                    size: self.sectors_per_cluster,
-                })
-                .unwrap();
+                let mut sector_buff = SectorVec::zeroed(self.sectors_per_cluster);
+                self.read_blocks(sector_buff.request(self.cluster_start_lba(cluster)));
 
                 sub_buff.copy_from_slice(&sector_buff[..read]);
             }
@@ -646,19 +1546,465 @@ impl FileSystemInner for FATFileSystem {
         Ok(total_read)
     }
 
-    fn write(
+    /// Writes are ordered so an interrupted write never leaves a directory entry that refers
+    /// to unwritten or free clusters: new clusters are allocated and the data is written
+    /// first, then the clusters are linked to the chain of the file and the directory entry
+    /// with the new size is updated last
+    fn write_file(
         &mut self,
         inode: FSInode,
-        _offset: usize,
-        _buff: &[u8],
+        offset: usize,
+        buff: &[u8],
     ) -> Result<usize, FsWriteError> {
         assert!(inode != FSInode(0));
-        todo!()
+
+        let dir_index = self
+            .get_dir_index_from_inode(inode)
+            .ok_or(FsWriteError::IoError)?;
+        let file = self.get_dir_ent(dir_index.cluster, dir_index.cluster_index);
+        assert!(file.ent_type != DirectoryEntryType::Directory);
+
+        let old_size = file.file_size();
+        let end = offset
+            .checked_add(buff.len())
+            .ok_or(FsWriteError::InvalidArgument)?;
+        let new_size = old_size.max(end);
+        // the size is stored as 32 bits in the directory entry
+        if new_size > u32::MAX as usize {
+            return Err(FsWriteError::NoSpace);
+        }
+
+        let cluster_size = self.sectors_per_cluster * BLOCK_SIZE;
+
+        let mut chain: Vec<ClusterIndex> = Vec::new();
+        let mut cluster = file.data_cluster_start;
+        while cluster.0 >= 2 && cluster.valid_cluster() {
+            chain.push(cluster);
+            cluster = self.get_fat_entry(cluster);
+        }
+        let old_cluster_count = chain.len();
+
+        // 1. allocate the new clusters, nothing refers to them yet
+        let needed = new_size.div_ceil(cluster_size);
+        if needed > old_cluster_count {
+            let new_clusters = self
+                .allocate_cluster_chain(needed - old_cluster_count)
+                .ok_or(FsWriteError::NoSpace)?;
+            chain.extend(new_clusters);
+        }
+
+        // 2. write the data, the gap between the old end of the file and __offset__ is zeroed
+        let mut cluster_data = SectorVec::zeroed(self.sectors_per_cluster);
+        let start = offset.min(old_size);
+        let mut pos = start;
+        while pos < end {
+            let cluster = chain[pos / cluster_size];
+            let lba = self.cluster_start_lba(cluster);
+            let cluster_off = pos % cluster_size;
+            let len = (cluster_size - cluster_off).min(end - pos);
+
+            if len < cluster_size {
+                self.read_blocks(cluster_data.request(lba.clone()));
+            }
+
+            for (i, byte) in cluster_data[cluster_off..cluster_off + len]
+                .iter_mut()
+                .enumerate()
+            {
+                let file_pos = pos + i;
+                *byte = if file_pos < offset {
+                    0
+                } else {
+                    buff[file_pos - offset]
+                };
+            }
+
+            self.write_blocks(cluster_data.request(lba));
+            pos += len;
+        }
+        self.write_barrier();
+
+        // 3. link the new clusters to the end of the chain of the file
+        if old_cluster_count > 0 && chain.len() > old_cluster_count {
+            self.set_fat_entry(chain[old_cluster_count - 1], chain[old_cluster_count]);
+            self.write_barrier();
+        }
+
+        // 4. update the directory entry, an empty file gets its first cluster here
+        if new_size != old_size || old_cluster_count == 0 {
+            let first_cluster = chain[0].0 as u32;
+            self.update_short_dir_ent(&file, |short_ent| {
+                short_ent.cluster_low = first_cluster as u16;
+                short_ent.cluster_high = (first_cluster >> 16) as u16;
+                short_ent.file_size = new_size as u32;
+            });
+        }
+        self.sync();
+
+        Ok(buff.len())
+    }
+
+    /// Checks the cluster chains of every file and directory, cross-linked clusters and links
+    /// to clusters that are not allocated end the chain and files that are larger than their
+    /// chain are shrunk when repairing. Orphaned clusters were already freed when the file
+    /// system was created.
+    fn check_volume(&mut self, mode: FsckMode) -> Option<FsckReport> {
+        let cluster_size = self.sectors_per_cluster * BLOCK_SIZE;
+        let mut report = FsckReport::default();
+        let mut owned: Vec<u64> = alloc::vec![0; self.cluster_count().div_ceil(64)];
+        // the location and the correct size of the entries of files larger than their chain
+        let mut size_fixes: Vec<(LinearBlockAddress, usize, u32)> = Vec::new();
+
+        let mut dirs: Vec<ClusterIndex> = Vec::new();
+        if self.check_cluster_chain(self.root_cluster, &mut owned, mode, &mut report) > 0 {
+            dirs.push(self.root_cluster);
+        }
+
+        while let Some(dir) = dirs.pop() {
+            self.for_each_short_dir_ent(dir, |ent, lba, index| {
+                let start = ClusterIndex(
+                    Self::fuse_cluster_parts(ent.cluster_low, ent.cluster_high) as usize,
+                );
+                let is_dir = ent.attr & DIR_ENT_DIRECTORY > 0;
+                let file_size = ent.file_size as usize;
+
+                let len = if start.0 == 0 {
+                    if is_dir {
+                        report.errors += 1;
+                        warn!("FAT: directory has no clusters");
+                    }
+                    0
+                } else {
+                    self.check_cluster_chain(start, &mut owned, mode, &mut report)
+                };
+
+                if is_dir {
+                    // directories whose first cluster is cross-linked are not walked so
+                    // a loop in the directory tree is only visited once
+                    if len > 0 {
+                        dirs.push(start);
+                    }
+                } else if file_size > len * cluster_size {
+                    report.errors += 1;
+                    warn!(
+                        "FAT: file size {} is larger than its {} clusters",
+                        file_size, len
+                    );
+                    size_fixes.push((lba.clone(), index, (len * cluster_size) as u32));
+                }
+            });
+        }
+
+        if mode == FsckMode::Repair {
+            for (lba, index, size) in size_fixes {
+                self.update_short_dir_ent_at(lba, index, |short_ent| short_ent.file_size = size);
+                report.repaired += 1;
+            }
+            self.sync();
+        }
+
+        Some(report)
+    }
+
+    fn read_dir_entries(&mut self, path: Path) -> Result<Vec<DirEntry>, FsReadDirError> {
+        let dir_start_cluster = self
+            .find_dir_cluster(path)
+            .map_err(FsReadDirError::BadPath)?;
+
+        let mut files = Vec::new();
+        self.for_each_dir_ent(dir_start_cluster, |name, ent| {
+            if name != "." && name != ".." {
+                files.push((String::from(name), ent));
+            }
+            false
+        });
+
+        let mut entries = Vec::with_capacity(files.len());
+        for (name, ent) in files {
+            match self.allocate_inode(&ent) {
+                Some(inode) => entries.push(DirEntry { name, inode }),
+                None => {
+                    // the caller never sees the inodes handed out so far
+                    for ent in entries {
+                        self.close_inode(ent.inode).unwrap();
+                    }
+                    return Err(FsReadDirError::NoSpace);
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn remove_path(&mut self, path: Path) -> Result<(), FsRemoveError> {
+        if path.components_left() == 0 {
+            return Err(FsRemoveError::Busy);
+        }
+
+        let ent = self
+            .find_file(path)
+            .ok_or(FsRemoveError::BadPath(FsPathError::NoSuchFileOrDirectory))?;
+
+        if ent.ent_type == DirectoryEntryType::Directory
+            && !self.dir_is_empty(ent.data_cluster_start)
+        {
+            return Err(FsRemoveError::DirectoryNotEmpty);
+        }
+
+        // the entry is cleared first so an interrupted removal leaks clusters
+        // instead of leaving an entry that points to free clusters
+        self.clear_dir_ent(&ent);
+        self.write_barrier();
+        self.free_cluster_chain(ent.data_cluster_start);
+        self.sync();
+
+        Ok(())
+    }
+
+    fn rename_path(&mut self, old_path: Path, new_path: Path) -> Result<(), FsRenameError> {
+        if old_path.components_left() == 0 || new_path.components_left() == 0 {
+            return Err(FsRenameError::Busy);
+        }
+
+        let old_ent = self
+            .find_file(old_path)
+            .ok_or(FsRenameError::BadPath(FsPathError::NoSuchFileOrDirectory))?;
+        let old_is_dir = old_ent.ent_type == DirectoryEntryType::Directory;
+
+        let new_dir_comps = new_path.components_left() - 1;
+        let new_dir_cluster = self
+            .find_dir_cluster(new_path.clone().shorten(new_dir_comps))
+            .map_err(FsRenameError::BadPath)?;
+        let new_name = new_path.last().unwrap();
+
+        if let Some(existing) = self.find_dir_ent(new_dir_cluster, new_name) {
+            if existing.directory_cluster.0 == old_ent.directory_cluster.0
+                && existing.directory_cluster_index == old_ent.directory_cluster_index
+            {
+                return Ok(());
+            }
+
+            match (
+                old_is_dir,
+                existing.ent_type == DirectoryEntryType::Directory,
+            ) {
+                (false, true) => return Err(FsRenameError::IsADirectory),
+                (true, false) => return Err(FsRenameError::BadPath(FsPathError::NotADirectory)),
+                (true, true) if !self.dir_is_empty(existing.data_cluster_start) => {
+                    return Err(FsRenameError::DirectoryNotEmpty)
+                }
+                _ => {}
+            }
+
+            self.clear_dir_ent(&existing);
+            self.write_barrier();
+            self.free_cluster_chain(existing.data_cluster_start);
+        }
+
+        // the new entry is written before the old one is cleared so an interrupted rename
+        // leaves the file in both directories instead of neither
+        let short_ent = self.read_short_dir_ent(&old_ent);
+        self.add_dir_ent(new_dir_cluster, new_name, short_ent)
+            .ok_or(FsRenameError::NoSpace)?;
+        self.write_barrier();
+        self.clear_dir_ent(&old_ent);
+
+        if old_is_dir {
+            self.set_parent_dir_ent(old_ent.data_cluster_start, new_dir_cluster);
+        }
+        self.sync();
+
+        Ok(())
+    }
+
+    fn create_file(&mut self, path: Path) -> Result<FSInode, FsCreateError> {
+        let (parent_cluster, name) = self.find_parent_for_create(path)?;
+
+        // empty files have no clusters
+        let short_ent = Self::new_short_dir_ent(DIR_ENT_ARCHIVE, ClusterIndex(0));
+        let ent = self
+            .add_dir_ent(parent_cluster, name, short_ent)
+            .ok_or(FsCreateError::NoSpace)?;
+        self.sync();
+
+        self.allocate_inode(&ent).ok_or(FsCreateError::NoSpace)
+    }
+
+    fn make_dir(&mut self, path: Path) -> Result<(), FsCreateError> {
+        const ENT_SIZE: usize = core::mem::size_of::<ShortDirectoryEntry>();
+
+        let (parent_cluster, name) = self.find_parent_for_create(path)?;
+
+        let cluster = self.allocate_cluster().ok_or(FsCreateError::NoSpace)?;
+
+        // .. refers to the root directory with cluster 0
+        let parent_ref = if parent_cluster.0 == self.root_cluster.0 {
+            ClusterIndex(0)
+        } else {
+            parent_cluster
+        };
+
+        let mut dot = Self::new_short_dir_ent(DIR_ENT_DIRECTORY, cluster);
+        dot.name = *b".          ";
+        let mut dot_dot = Self::new_short_dir_ent(DIR_ENT_DIRECTORY, parent_ref);
+        dot_dot.name = *b"..         ";
+
+        // the cluster was zeroed so the rest of the entries mark the end of the directory
+        let mut sector_data: SectorBuf = SectorBuf::zeroed();
+        unsafe {
+            (sector_data.as_mut_ptr() as *mut ShortDirectoryEntry).write_unaligned(dot);
+            (sector_data.as_mut_ptr().add(ENT_SIZE) as *mut ShortDirectoryEntry)
+                .write_unaligned(dot_dot);
+        }
+
+        self.write_blocks(sector_data.request(self.cluster_start_lba(cluster)));
+        // the directory has to be complete before an entry refers to it
+        self.write_barrier();
+
+        let short_ent = Self::new_short_dir_ent(DIR_ENT_DIRECTORY, cluster);
+        if self.add_dir_ent(parent_cluster, name, short_ent).is_none() {
+            self.free_cluster_chain(cluster);
+            self.sync();
+            return Err(FsCreateError::NoSpace);
+        }
+        self.sync();
+
+        Ok(())
+    }
+
+    fn truncate_file(&mut self, inode: FSInode) -> Result<(), FsWriteError> {
+        assert!(inode != FSInode(0));
+
+        let dir_index = self
+            .get_dir_index_from_inode(inode)
+            .ok_or(FsWriteError::IoError)?;
+        let file = self.get_dir_ent(dir_index.cluster, dir_index.cluster_index);
+        assert!(file.ent_type != DirectoryEntryType::Directory);
+
+        // the entry is updated first so it never points to free clusters
+        self.update_short_dir_ent(&file, |short_ent| {
+            short_ent.cluster_low = 0;
+            short_ent.cluster_high = 0;
+            short_ent.file_size = 0;
+        });
+        self.write_barrier();
+        self.free_cluster_chain(file.data_cluster_start);
+        self.sync();
+
+        Ok(())
+    }
+}
+
+impl FileSystemInner for FATFileSystem {
+    fn open(&mut self, path: Path) -> Result<FSInode, FsOpenError> {
+        self.on_medium(
+            || FsOpenError::BadPath(FsPathError::NoDevice),
+            |fs| fs.open_path(path),
+        )
+    }
+
+    fn stat(&mut self, inode: FSInode, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        self.on_medium(
+            || FsStatError::BadPath(FsPathError::NoDevice),
+            |fs| fs.stat_inode(inode, stat_buf),
+        )
+    }
+
+    fn close(&mut self, inode: FSInode) -> Result<(), FsCloseError> {
+        // nothing is read, the inode is freed even if the medium is unavailable
+        self.close_inode(inode)
+    }
+
+    fn read(&mut self, inode: FSInode, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        self.on_medium(|| FsReadError::IoError, |fs| fs.read_file(inode, off, buff))
+    }
+
+    fn write(&mut self, inode: FSInode, off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        self.on_medium(
+            || FsWriteError::IoError,
+            |fs| fs.write_file(inode, off, buff),
+        )
+    }
+
+    fn check(&mut self, mode: FsckMode) -> Option<FsckReport> {
+        self.on_medium(|| (), |fs| Ok(fs.check_volume(mode)))
+            .ok()
+            .flatten()
     }
 
     fn ioctl(&mut self, _inode: FSInode, _req: usize, _arg: usize) -> Result<usize, FsIoctlError> {
         todo!()
     }
+
+    fn read_dir(&mut self, path: Path) -> Result<Vec<DirEntry>, FsReadDirError> {
+        self.on_medium(
+            || FsReadDirError::BadPath(FsPathError::NoDevice),
+            |fs| fs.read_dir_entries(path),
+        )
+    }
+
+    fn remove(&mut self, path: Path) -> Result<(), FsRemoveError> {
+        self.on_medium(
+            || FsRemoveError::BadPath(FsPathError::NoDevice),
+            |fs| fs.remove_path(path),
+        )
+    }
+
+    fn rename(&mut self, old_path: Path, new_path: Path) -> Result<(), FsRenameError> {
+        self.on_medium(
+            || FsRenameError::BadPath(FsPathError::NoDevice),
+            |fs| fs.rename_path(old_path, new_path),
+        )
+    }
+
+    fn create(&mut self, path: Path) -> Result<FSInode, FsCreateError> {
+        self.on_medium(
+            || FsCreateError::BadPath(FsPathError::NoDevice),
+            |fs| fs.create_file(path),
+        )
+    }
+
+    fn mkdir(&mut self, path: Path) -> Result<(), FsCreateError> {
+        self.on_medium(
+            || FsCreateError::BadPath(FsPathError::NoDevice),
+            |fs| fs.make_dir(path),
+        )
+    }
+
+    fn truncate(&mut self, inode: FSInode) -> Result<(), FsWriteError> {
+        self.on_medium(|| FsWriteError::IoError, |fs| fs.truncate_file(inode))
+    }
+
+    /// The boot sector is read again if the medium was changed or a request failed, the
+    /// file system is usable again if it still holds a FAT-32 volume
+    fn revalidate(&mut self) -> Revalidation {
+        if self.medium().is_some() {
+            return Revalidation::Unchanged;
+        }
+
+        let part = match self.partition.upgrade() {
+            Some(part) => part,
+            None => return Revalidation::NoMedium,
+        };
+
+        let old_uuid = self.volume.uuid.clone();
+        match self.load(&part) {
+            Ok(()) => {
+                if self.volume.uuid != old_uuid {
+                    log!(
+                        "FAT: volume {} was replaced by {}",
+                        old_uuid,
+                        self.volume.uuid
+                    );
+                }
+                Revalidation::Reloaded
+            }
+            Err(_) => {
+                self.mark_unavailable();
+                Revalidation::NoMedium
+            }
+        }
+    }
 }
 
 fn create_fs(part: Weak<Partition>) -> Result<Box<dyn FileSystemInner>, FsInitError> {
@@ -668,10 +2014,28 @@ fn create_fs(part: Weak<Partition>) -> Result<Box<dyn FileSystemInner>, FsInitEr
     }
 }
 
+fn probe_fs(part: &Arc<Partition>) -> Option<VolumeInfo> {
+    let boot_sector = read_boot_sector(part).ok()?;
+    let (bios_parameter_data, extended_bpd) = parse_boot_sector(&boot_sector);
+
+    // FAT12/FAT16 volumes have a fixed size root directory
+    if bios_parameter_data.root_dir_entries != 0 {
+        return None;
+    }
+
+    match extended_bpd.signature {
+        EXTENDED_BOOT_SIGNATURE | EXTENDED_BOOT_SIGNATURE_ID_ONLY => {
+            Some(volume_info(extended_bpd))
+        }
+        _ => None,
+    }
+}
+
 pub fn init() -> bool {
     let mut vfs = VFS.write();
     vfs.register_fs_skeleton(FileSystemSkeleton {
         new: create_fs,
+        probe: probe_fs,
         name: "fat32",
     })
     .is_ok()
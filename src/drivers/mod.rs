@@ -1,6 +1,11 @@
 use alloc::vec::Vec;
 use spin::Mutex;
 
+use crate::{
+    bootstat,
+    mm::kalloc::{self, HeapTag},
+};
+
 #[cfg(ata_module)]
 mod ata;
 
@@ -17,6 +22,9 @@ pub mod fat;
 #[cfg(ps2_module)]
 pub mod ps2;
 
+#[cfg(virtio_module)]
+pub mod virtio;
+
 // FIXME: dont include assembly files associated with disabled modules in the build
 
 #[derive(Debug)]
@@ -45,7 +53,11 @@ impl KernelModule {
     }
 
     fn load(&mut self) {
-        let success = (self.init)();
+        let success = {
+            let _tag = kalloc::tag(HeapTag::Drivers);
+            (self.init)()
+        };
+        bootstat::driver_loaded(self.name);
         if success {
             self.load_state = KernelModuleLoadStatus::Loaded;
             if cfg!(driver_manager_debug) {
@@ -79,6 +91,9 @@ pub fn init() {
 
     #[cfg(ps2_module)]
     modules.push(KernelModule::new(ps2::init, "ps2"));
+
+    #[cfg(virtio_module)]
+    modules.push(KernelModule::new(virtio::init, "virtio"));
 }
 
 pub fn preload_driver(name: &str) {
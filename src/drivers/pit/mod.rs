@@ -1,8 +1,6 @@
-use crate::arch::x86_64::registers::InterruptRegisters;
-use crate::arch::x86_64::{
-    outb,
-    pic::{self, clear_irq, send_irq_eoi, set_irq},
-};
+use crate::arch::x86_64::registers::{InterruptRegisters, RegisterState};
+use crate::arch::x86_64::{idt, irq, outb, smp};
+use crate::latency;
 use crate::scheduler::SCHEDULER;
 use crate::time;
 
@@ -41,7 +39,7 @@ extern "C" {
     fn __pit_timer_interrupt();
 }
 
-const TIMER_FREQUENCY: usize = 1000;
+const TIMER_FREQUENCY: usize = time::TICK_FREQUENCY;
 
 pub fn init() -> bool {
     assert!(TIMER_FREQUENCY >= 19 && TIMER_FREQUENCY <= TIMER_BASE_FREQUENCY);
@@ -59,7 +57,9 @@ pub fn init() -> bool {
     outb(PIT_CHANNEL0_DATA, (reload_value & 0xff) as u8);
     outb(PIT_CHANNEL0_DATA, (reload_value >> 8) as u8);
 
-    pic::install_irq_handler(TIMER_IRQ, __pit_timer_interrupt as u64);
+    irq::install_handler(TIMER_IRQ, __pit_timer_interrupt as u64);
+    // the tick can switch threads
+    idt::set_interrupt_stack(irq::vector(TIMER_IRQ), idt::SCHEDULER_IST);
     log!("timer initialized, running at {}Hz", TIMER_FREQUENCY);
     enable();
 
@@ -67,19 +67,24 @@ pub fn init() -> bool {
 }
 
 #[no_mangle]
-fn pit_timer_interrupt(interrupt_regs: &mut InterruptRegisters) {
+extern "C" fn pit_timer_interrupt(interrupt_regs: &InterruptRegisters) -> *const RegisterState {
+    let _latency = latency::irq_timer(irq::vector(TIMER_IRQ));
     // FIXME: figure out a better way to calculate how many milliseconds we want to advance the clock
     let ms_passed = 1000 / TIMER_FREQUENCY;
     time::advance(ms_passed as u64);
 
-    SCHEDULER.tick(interrupt_regs);
-    send_irq_eoi(TIMER_IRQ);
+    // only the BSP receives the timer interrupt, the other CPUs use their local APIC timer or
+    // are ticked with an IPI
+    smp::broadcast_tick();
+    let next_regs = SCHEDULER.tick(interrupt_regs);
+    irq::eoi(TIMER_IRQ);
+    next_regs
 }
 
 pub fn enable() {
-    clear_irq(TIMER_IRQ);
+    irq::enable(TIMER_IRQ);
 }
 
 pub fn disable() {
-    set_irq(TIMER_IRQ);
+    irq::disable(TIMER_IRQ);
 }
@@ -1,6 +1,7 @@
 bits 64
 
 extern pit_timer_interrupt
+extern x86_64_switch_task
 
 section .text
 global __pit_timer_interrupt:function (__pit_timer_interrupt.end - __pit_timer_interrupt)
@@ -25,6 +26,14 @@ __pit_timer_interrupt:
     mov rdi, rsp
     call pit_timer_interrupt
 
+    ; the handler returns the saved registers of the thread to switch to, the registers
+    ; pushed on the interrupt stack are left behind since the scheduler saved them already
+    test rax, rax
+    jz .resume
+    mov rdi, rax
+    jmp x86_64_switch_task
+
+.resume:
     pop rax
     pop rbx
     pop rcx
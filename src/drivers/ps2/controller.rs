@@ -40,6 +40,26 @@ pub enum PS2ControllerError {
     ConfigFileReadFailed,
     SelfTestFailed,
     DataBufferWriteFailed,
+    /// The device did not acknowledge a command
+    DeviceCommandFailed,
+    /// The device reported that its self test failed
+    DeviceSelfTestFailed,
+    /// The device sent an ID that belongs to neither a keyboard nor a mouse
+    UnknownDevice(u8),
+}
+
+/// A port of the controller, either port can have a keyboard or a mouse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    First = 0,
+    Second = 1,
+}
+
+/// What is plugged into a port, found out by `identify_device`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Keyboard = 1,
+    Mouse = 2,
 }
 
 const DATA_REGISTER_PORT: u16 = 0x60;
@@ -65,8 +85,23 @@ const CMD_NEXT_BYTE_SECOND_PORT: u8 = 0xD4;
 const SELF_TEST_SUCCESS: u8 = 0x55;
 
 const DEVICE_CMD_RESET: u8 = 0xFF;
-const DEVICE_RESET_SUCCESS: u8 = 0xFA;
-const DEVICE_RESET_FAILURE: u8 = 0xFC;
+const DEVICE_CMD_IDENTIFY: u8 = 0xF2;
+const DEVICE_CMD_DISABLE_SCANNING: u8 = 0xF5;
+
+/// The first byte of the ID of a keyboard, the second one is the type of the keyboard
+const DEVICE_ID_KEYBOARD: u8 = 0xAB;
+/// Sent by a few keyboards instead of `DEVICE_ID_KEYBOARD`
+const DEVICE_ID_KEYBOARD_ALT: u8 = 0xAC;
+const DEVICE_ID_MOUSE: u8 = 0x00;
+const DEVICE_ID_WHEEL_MOUSE: u8 = 0x03;
+const DEVICE_ID_5BUTTON_MOUSE: u8 = 0x04;
+
+/// Sent by a device after its self test, also when it is plugged in or resets by itself
+pub const DEVICE_SELF_TEST_PASSED: u8 = 0xAA;
+pub const DEVICE_SELF_TEST_FAILED: u8 = 0xFC;
+
+const DEVICE_ACK: u8 = 0xFA;
+const DEVICE_RESEND: u8 = 0xFE;
 
 fn read_status() -> StatusRegisterFlags {
     let status = inb(STATUS_REGISTER_PORT);
@@ -145,7 +180,112 @@ fn write_data_second_port(val: u8) -> Result<(), PS2ControllerError> {
     write_data_buffer(val)
 }
 
-pub fn init() -> Result<(bool, bool), PS2ControllerError> {
+/// Discards the bytes the devices sent that were not read yet
+pub fn flush_output_buffer() {
+    while read_status().contains(StatusRegisterFlags::OUTPUT_BUFFER_FULL) {
+        inb(DATA_REGISTER_PORT);
+    }
+}
+
+/// Sends a command or its parameter to a device with __write__ and waits for the
+/// acknowledgement
+fn write_device(
+    write: fn(u8) -> Result<(), PS2ControllerError>,
+    val: u8,
+) -> Result<(), PS2ControllerError> {
+    const RETRIES: usize = 3;
+    for _ in 0..RETRIES {
+        write(val)?;
+        match read_data_buffer() {
+            Ok(DEVICE_ACK) => return Ok(()),
+            Ok(DEVICE_RESEND) => continue,
+            _ => break,
+        }
+    }
+
+    Err(PS2ControllerError::DeviceCommandFailed)
+}
+
+/// Sends a command or its parameter to the device on __port__, it must be called with
+/// interrupts disabled or the interrupts of the ports masked so the response is not taken by
+/// an interrupt handler
+pub fn write_port_device(port: Port, val: u8) -> Result<(), PS2ControllerError> {
+    match port {
+        Port::First => write_device(write_data_first_port, val),
+        Port::Second => write_device(write_data_second_port, val),
+    }
+}
+
+/// Resets the device on __port__ and waits for the result of its self test, the same way as
+/// `write_port_device` is called. Fails if there is no device on the port.
+pub fn reset_device(port: Port) -> Result<(), PS2ControllerError> {
+    // the self test takes up to 500 ms, longer than a single read waits for the response
+    const SELF_TEST_READS: usize = 10;
+
+    write_port_device(port, DEVICE_CMD_RESET)?;
+
+    for _ in 0..SELF_TEST_READS {
+        match read_data_buffer() {
+            Ok(DEVICE_SELF_TEST_PASSED) => return Ok(()),
+            Ok(DEVICE_SELF_TEST_FAILED) => return Err(PS2ControllerError::DeviceSelfTestFailed),
+            _ => continue,
+        }
+    }
+
+    Err(PS2ControllerError::DeviceCommandFailed)
+}
+
+/// Returns whether the device on __port__ is a keyboard or a mouse from the ID it sends, the
+/// same way as `write_port_device` is called. The device does not send anything afterwards
+/// until its driver enables the scanning again.
+pub fn identify_device(port: Port) -> Result<DeviceType, PS2ControllerError> {
+    // a keyboard would send its scancodes between the bytes of the ID otherwise
+    write_port_device(port, DEVICE_CMD_DISABLE_SCANNING)?;
+    write_port_device(port, DEVICE_CMD_IDENTIFY)?;
+
+    // the ID of a mouse is a single byte and the ancient AT keyboards send none, so the
+    // reads time out
+    let mut id = [0; 2];
+    let mut len = 0;
+    while len < id.len() {
+        match read_data_buffer() {
+            Ok(byte) => id[len] = byte,
+            Err(_) => break,
+        }
+        len += 1;
+    }
+
+    // the controller translates the ID of a keyboard on the first port, except the first
+    // byte, a mouse on the first port only works with the translation turned off
+    match id[..len] {
+        [] => Ok(DeviceType::Keyboard),
+        [DEVICE_ID_KEYBOARD | DEVICE_ID_KEYBOARD_ALT, _] => Ok(DeviceType::Keyboard),
+        [DEVICE_ID_MOUSE | DEVICE_ID_WHEEL_MOUSE | DEVICE_ID_5BUTTON_MOUSE] => {
+            Ok(DeviceType::Mouse)
+        }
+        _ => Err(PS2ControllerError::UnknownDevice(id[0])),
+    }
+}
+
+/// Enables or disables the clock of __port__, a disabled port does not send anything
+pub fn set_port_enabled(port: Port, enabled: bool) {
+    send_command(match (port, enabled) {
+        (Port::First, true) => CMD_ENABLE_FIRST_PORT,
+        (Port::First, false) => CMD_DISABLE_FIRST_PORT,
+        (Port::Second, true) => CMD_ENABLE_SECOND_PORT,
+        (Port::Second, false) => CMD_DISABLE_SECOND_PORT,
+    });
+}
+
+/// Returns whether the controller translates the scancodes of the first port to set 1
+pub fn translation_enabled() -> bool {
+    read_config_byte().is_ok_and(|cfg| cfg.contains(ConfigByteFlags::FIRST_PORT_TRANSLATION))
+}
+
+/// Initializes the controller, returns which ports work. The devices are reset by their
+/// drivers, a port works even if nothing is plugged into it. The scancodes of the first port
+/// are translated to set 1 if __translate__ is true.
+pub fn init(translate: bool) -> Result<(bool, bool), PS2ControllerError> {
     // disable both channels
     send_command(CMD_DISABLE_FIRST_PORT);
     send_command(CMD_DISABLE_SECOND_PORT);
@@ -186,7 +326,7 @@ pub fn init() -> Result<(bool, bool), PS2ControllerError> {
         send_command(CMD_DISABLE_SECOND_PORT);
     }
 
-    let (mut first_port_working, mut second_port_working) = (
+    let (first_port_working, second_port_working) = (
         send_command_response(CMD_TEST_FIRST_PORT).map_or(false, |n| n == 0),
         dual_channel && send_command_response(CMD_TEST_SECOND_PORT).map_or(false, |n| n == 0),
     );
@@ -203,26 +343,10 @@ pub fn init() -> Result<(bool, bool), PS2ControllerError> {
         config_byte.insert(ConfigByteFlags::SECOND_PORT_INTERRUPT_ENABLED);
     }
 
-    // reset devices
-    first_port_working &= write_data_first_port(DEVICE_CMD_RESET).map_or(false, |_| {
-        let res = read_data_buffer().unwrap_or(DEVICE_RESET_FAILURE);
-        res == DEVICE_RESET_SUCCESS
-    });
-
-    second_port_working &= write_data_second_port(DEVICE_CMD_RESET).map_or(false, |_| {
-        send_command(CMD_NEXT_BYTE_SECOND_PORT);
-        let res = read_data_buffer().unwrap_or(DEVICE_RESET_FAILURE);
-        res == DEVICE_RESET_SUCCESS
-    });
-
-    read_data_buffer().unwrap();
-
-    config_byte.insert(ConfigByteFlags::FIRST_PORT_TRANSLATION);
+    config_byte.set(ConfigByteFlags::FIRST_PORT_TRANSLATION, translate);
 
     // enable interrupts
     write_config_byte(config_byte)?;
 
-    // TODO: maybe disable interrupts for a channel that failed to reset
-
     Ok((first_port_working, second_port_working))
 }
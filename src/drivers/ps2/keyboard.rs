@@ -2,9 +2,18 @@ use alloc::sync::Arc;
 use bitflags::bitflags;
 use spin::Mutex;
 
-use crate::arch::x86_64::pic::send_irq_eoi;
-
-use super::{controller::read_data_buffer, FIRST_PORT_IRQ};
+use crate::{
+    input::{self, InputDevice},
+    posix::input::{
+        EV_KEY, KEY_DOWN, KEY_END, KEY_HOME, KEY_LEFT, KEY_LEFTMETA, KEY_RIGHT, KEY_RIGHTALT,
+        KEY_RIGHTCTRL, KEY_RIGHTMETA, KEY_UP,
+    },
+};
+
+use super::{
+    controller::{Port, DEVICE_SELF_TEST_FAILED, DEVICE_SELF_TEST_PASSED},
+    layout,
+};
 
 bitflags! {
     pub struct KeyModifiers: u8 {
@@ -29,40 +38,57 @@ pub trait PS2KeyboardEventHandler {
     fn key_event(&self, ev: KeyEvent);
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
 struct PS2Keyboard {
+    port: Port,
+    scancode_set: ScancodeSet,
     extended_mode: bool,
+    /// A set 2 release prefix was received
+    releasing: bool,
+    /// Remaining bytes of the pause key sequence
+    skip_bytes: usize,
     keys: [bool; 256],
     modifiers: KeyModifiers,
     key_event_handler: Option<Arc<dyn PS2KeyboardEventHandler>>,
+    device: Option<Arc<InputDevice>>,
 }
 
 unsafe impl Send for PS2Keyboard {}
 unsafe impl Sync for PS2Keyboard {}
 
-static KEYBOARD: Mutex<PS2Keyboard> = Mutex::new(PS2Keyboard {
-    extended_mode: false,
-    keys: [false; 256],
-    modifiers: KeyModifiers::empty(),
-    key_event_handler: None,
-});
-
-const SCANCODE_SET1: &[u8] = &[
-    0, 0, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', b'-', b'=', 8, b'\t', b'q',
-    b'w', b'e', b'r', b't', b'y', b'u', b'i', b'o', b'p', b'[', b']', b'\n', 0, b'a', b's', b'd',
-    b'f', b'g', b'h', b'j', b'k', b'l', b';', b'\'', b'`', 0, b'\\', b'z', b'x', b'c', b'v', b'b',
-    b'n', b'm', b',', b'.', b'/', 0, 0, 0, b' ', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-];
-
-const SCANCODE_SET1_SHIFT: &[u8] = &[
-    0, 0, b'!', b'@', b'#', b'$', b'%', b'^', b'&', b'*', b'(', b')', b'_', b'+', 8, b'\t', b'Q',
-    b'W', b'E', b'R', b'T', b'Y', b'U', b'I', b'O', b'P', b'{', b'}', b'\n', 0, b'A', b'S', b'D',
-    b'F', b'G', b'H', b'J', b'K', b'L', b':', b'"', b'~', 0, b'|', b'Z', b'X', b'C', b'V', b'B',
-    b'N', b'M', b'<', b'>', b'?', 0, 0, 0, b' ', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+/// A keyboard on either port, indexed by the port
+static KEYBOARDS: [Mutex<PS2Keyboard>; 2] = [
+    Mutex::new(PS2Keyboard::new(Port::First)),
+    Mutex::new(PS2Keyboard::new(Port::Second)),
 ];
 
 const SCANCODE_SET1_EXTENDED: u8 = 0xE0;
+/// Starts the sequence of the pause key in both sets, the key has no break code
+const SCANCODE_PAUSE: u8 = 0xE1;
+const SCANCODE_SET1_PAUSE_LEN: usize = 6;
+const SCANCODE_SET2_PAUSE_LEN: usize = 8;
+
+/// Sent before the scancode of a released key in set 2
+const SCANCODE_SET2_RELEASE: u8 = 0xF0;
+
+/// The set 1 scancodes of the set 2 scancodes, the same table the controller uses when it
+/// translates. The keys with an 0xE0 prefix are translated the same way.
+const SCANCODE_SET2_TO_SET1: &[u8] = &[
+    0x00, 0x43, 0x00, 0x3F, 0x3D, 0x3B, 0x3C, 0x58, 0x00, 0x44, 0x42, 0x40, 0x3E, 0x0F, 0x29, 0x00,
+    0x00, 0x38, 0x2A, 0x00, 0x1D, 0x10, 0x02, 0x00, 0x00, 0x00, 0x2C, 0x1F, 0x1E, 0x11, 0x03, 0x5B,
+    0x00, 0x2E, 0x2D, 0x20, 0x12, 0x05, 0x04, 0x5C, 0x00, 0x39, 0x2F, 0x21, 0x14, 0x13, 0x06, 0x5D,
+    0x00, 0x31, 0x30, 0x23, 0x22, 0x15, 0x07, 0x00, 0x00, 0x00, 0x32, 0x24, 0x16, 0x08, 0x09, 0x00,
+    0x00, 0x33, 0x25, 0x17, 0x18, 0x0B, 0x0A, 0x00, 0x00, 0x34, 0x35, 0x26, 0x27, 0x19, 0x0C, 0x00,
+    0x00, 0x00, 0x28, 0x00, 0x1A, 0x0D, 0x00, 0x00, 0x3A, 0x36, 0x1C, 0x1B, 0x00, 0x2B, 0x00, 0x00,
+    0x00, 0x56, 0x00, 0x00, 0x00, 0x00, 0x0E, 0x00, 0x00, 0x4F, 0x00, 0x4B, 0x47, 0x00, 0x00, 0x00,
+    0x52, 0x53, 0x50, 0x4C, 0x4D, 0x48, 0x01, 0x45, 0x57, 0x4E, 0x51, 0x4A, 0x37, 0x49, 0x46, 0x00,
+    0x00, 0x00, 0x00, 0x41,
+];
 
 const SCANCODE_SET1_LSHIFT: u8 = 0x2A;
 const SCANCODE_SET1_RSHIFT: u8 = 0x36;
@@ -160,6 +186,99 @@ pub const PS2_KEY_HOME: u8 = 0x48;
 pub const PS2_KEY_END: u8 = 0x49;
 
 impl PS2Keyboard {
+    const fn new(port: Port) -> PS2Keyboard {
+        PS2Keyboard {
+            port,
+            scancode_set: ScancodeSet::Set1,
+            extended_mode: false,
+            releasing: false,
+            skip_bytes: 0,
+            keys: [false; 256],
+            modifiers: KeyModifiers::empty(),
+            key_event_handler: None,
+            device: None,
+        }
+    }
+
+    fn receive_byte(&mut self, byte: u8) {
+        if self.is_self_test_result(byte) {
+            // the keyboard was plugged in or reset itself, it has to be set up again
+            self.release_keys();
+            super::device_reset(self.port);
+            return;
+        }
+
+        if self.skip_bytes > 0 {
+            self.skip_bytes -= 1;
+            return;
+        }
+
+        if byte == SCANCODE_PAUSE {
+            self.skip_bytes = match self.scancode_set {
+                ScancodeSet::Set1 => SCANCODE_SET1_PAUSE_LEN - 1,
+                ScancodeSet::Set2 => SCANCODE_SET2_PAUSE_LEN - 1,
+            };
+            return;
+        }
+
+        match self.scancode_set {
+            ScancodeSet::Set1 => self.key_event(byte),
+            ScancodeSet::Set2 => self.set2_byte(byte),
+        }
+    }
+
+    /// Returns whether __byte__ is the self test result the keyboard sends after it resets
+    /// instead of a scancode
+    fn is_self_test_result(&self, byte: u8) -> bool {
+        if self.skip_bytes > 0 || self.extended_mode {
+            return false;
+        }
+
+        match (byte, self.scancode_set) {
+            (DEVICE_SELF_TEST_FAILED, _) => true,
+            (DEVICE_SELF_TEST_PASSED, ScancodeSet::Set2) => !self.releasing,
+            // in set 1 it is also the release of the left shift, which has to be pressed
+            (DEVICE_SELF_TEST_PASSED, ScancodeSet::Set1) => !self.keys[PS2_KEY_LEFT_SHIFT as usize],
+            _ => false,
+        }
+    }
+
+    /// Releases every pressed key and forgets the partially received scancodes, e.g. when
+    /// the keyboard is unplugged with keys held down
+    fn release_keys(&mut self) {
+        if let Some(device) = &self.device {
+            for (key, _) in self.keys.iter().enumerate().filter(|(_, &pressed)| pressed) {
+                device.report(EV_KEY, linux_keycode(key as u8), 0);
+            }
+            device.sync();
+        }
+
+        self.keys = [false; 256];
+        self.modifiers = KeyModifiers::empty();
+        self.extended_mode = false;
+        self.releasing = false;
+        self.skip_bytes = 0;
+    }
+
+    /// Decodes set 2 by turning the scancodes into set 1 ones
+    fn set2_byte(&mut self, byte: u8) {
+        match byte {
+            SCANCODE_SET2_RELEASE => self.releasing = true,
+            // the prefix is the same in both sets
+            SCANCODE_SET1_EXTENDED => self.key_event(byte),
+            _ => {
+                let releasing = core::mem::take(&mut self.releasing);
+                match SCANCODE_SET2_TO_SET1.get(byte as usize) {
+                    Some(&scancode) if scancode != 0 => {
+                        self.key_event(if releasing { scancode | 0x80 } else { scancode })
+                    }
+                    // e.g. an acknowledgement
+                    _ => self.extended_mode = false,
+                }
+            }
+        }
+    }
+
     fn key_event(&mut self, scancode: u8) {
         if scancode == SCANCODE_SET1_EXTENDED {
             self.extended_mode = true;
@@ -192,8 +311,20 @@ impl PS2Keyboard {
             PS2_KEY_NONE + scancode
         };
 
+        let repeated = pressed && self.keys[key as usize];
         self.keys[key as usize] = pressed;
 
+        if let Some(device) = &self.device {
+            // 2 is an autorepeated press
+            let value = match (pressed, repeated) {
+                (true, true) => 2,
+                (true, false) => 1,
+                (false, _) => 0,
+            };
+            device.report(EV_KEY, linux_keycode(key), value);
+            device.sync();
+        }
+
         match key {
             PS2_KEY_LEFT_SHIFT | PS2_KEY_RIGHT_SHIFT => {
                 let (lshift, rshift) = (
@@ -249,25 +380,67 @@ impl PS2Keyboard {
             shifted = !shifted;
         }
 
-        if shifted {
-            SCANCODE_SET1_SHIFT[key as usize]
-        } else {
-            SCANCODE_SET1[key as usize]
-        }
+        layout::current_layout().get_char(key, shifted)
+    }
+}
+
+/// Returns the Linux key code of __key__, the keys without a prefix have the same codes as
+/// their scancodes in set 1
+fn linux_keycode(key: u8) -> u16 {
+    match key {
+        PS2_KEY_LEFT_SUPER => KEY_LEFTMETA,
+        PS2_KEY_RIGHT_SUPER => KEY_RIGHTMETA,
+        PS2_KEY_RIGHT_CTRL => KEY_RIGHTCTRL,
+        PS2_KEY_RIGHT_ALT => KEY_RIGHTALT,
+        PS2_KEY_UP_ARROW => KEY_UP,
+        PS2_KEY_LEFT_ARROW => KEY_LEFT,
+        PS2_KEY_DOWN_ARROW => KEY_DOWN,
+        PS2_KEY_RIGHT_ARROW => KEY_RIGHT,
+        PS2_KEY_HOME => KEY_HOME,
+        PS2_KEY_END => KEY_END,
+        key => key as u16,
     }
 }
 
-#[no_mangle]
-fn handle_key_event() {
-    let scancode = read_data_buffer().unwrap();
+/// Called once the keyboard on __port__ is set up, the input device is created the first time
+/// a keyboard is found on the port. __scancode_set__ is the set the controller passes on.
+pub fn init(port: Port, scancode_set: ScancodeSet) {
+    let registered = KEYBOARDS[port as usize].lock().device.clone();
+    let device = match registered {
+        Some(device) => {
+            device.reconnect();
+            device
+        }
+        None => input::register_device(match scancode_set {
+            ScancodeSet::Set1 => "AT Translated Set 2 keyboard",
+            ScancodeSet::Set2 => "AT Raw Set 2 keyboard",
+        }),
+    };
+
+    let mut keyboard = KEYBOARDS[port as usize].lock();
+    keyboard.release_keys();
+    keyboard.scancode_set = scancode_set;
+    keyboard.device = Some(device);
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    keyboard.key_event(scancode);
+/// Called when the keyboard on __port__ could not be set up again after it reset or another
+/// device was plugged in instead, it was unplugged
+pub fn disconnect(port: Port) {
+    let mut keyboard = KEYBOARDS[port as usize].lock();
+    keyboard.release_keys();
+    if let Some(device) = &keyboard.device {
+        device.disconnect();
+    }
+}
 
-    send_irq_eoi(FIRST_PORT_IRQ);
+/// Called by the interrupt handler of __port__ with the byte the keyboard sent
+pub fn receive_byte(port: Port, byte: u8) {
+    KEYBOARDS[port as usize].lock().receive_byte(byte);
 }
 
+/// Sets the handler of the key events of the keyboards on both ports
 pub fn set_key_event_handler(event_handler: Option<Arc<dyn PS2KeyboardEventHandler>>) {
-    let mut keyboard = KEYBOARD.lock();
-    keyboard.key_event_handler = event_handler;
+    for keyboard in KEYBOARDS.iter() {
+        keyboard.lock().key_event_handler = event_handler.clone();
+    }
 }
@@ -0,0 +1,170 @@
+//! Keyboard layouts, a layout maps the keys to the characters they produce. The keys are
+//! numbered like the scancodes of set 1 so the tables are indexed by them. The layout can be
+//! chosen with the keyboard_layout option and switched at runtime by writing its name to
+//! /dev/kbdlayout, reading it lists the layouts with the current one in brackets.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    cmdline,
+    fs::{
+        context,
+        devfs::{self, DevFsDevice},
+        errors::{FsIoctlError, FsReadError, FsStatError, FsWriteError},
+        path::Path,
+    },
+    posix::{Stat, S_IFCHR},
+};
+
+/// The misc major of Linux
+const KEYBOARD_LAYOUT_MAJOR: u16 = 10;
+
+pub struct KeyboardLayout {
+    pub name: &'static str,
+    normal: &'static [u8],
+    shifted: &'static [u8],
+}
+
+impl KeyboardLayout {
+    /// Returns the character of __key__, 0 if it does not produce one
+    pub fn get_char(&self, key: u8, shifted: bool) -> u8 {
+        let table = match shifted {
+            true => self.shifted,
+            false => self.normal,
+        };
+        table.get(key as usize).copied().unwrap_or(0)
+    }
+}
+
+const US_NORMAL: &[u8] = &[
+    0, 0, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', b'-', b'=', 8, b'\t', b'q',
+    b'w', b'e', b'r', b't', b'y', b'u', b'i', b'o', b'p', b'[', b']', b'\n', 0, b'a', b's', b'd',
+    b'f', b'g', b'h', b'j', b'k', b'l', b';', b'\'', b'`', 0, b'\\', b'z', b'x', b'c', b'v', b'b',
+    b'n', b'm', b',', b'.', b'/', 0, 0, 0, b' ',
+];
+
+const US_SHIFTED: &[u8] = &[
+    0, 0, b'!', b'@', b'#', b'$', b'%', b'^', b'&', b'*', b'(', b')', b'_', b'+', 8, b'\t', b'Q',
+    b'W', b'E', b'R', b'T', b'Y', b'U', b'I', b'O', b'P', b'{', b'}', b'\n', 0, b'A', b'S', b'D',
+    b'F', b'G', b'H', b'J', b'K', b'L', b':', b'"', b'~', 0, b'|', b'Z', b'X', b'C', b'V', b'B',
+    b'N', b'M', b'<', b'>', b'?', 0, 0, 0, b' ',
+];
+
+const DVORAK_NORMAL: &[u8] = &[
+    0, 0, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', b'[', b']', 8, b'\t', b'\'',
+    b',', b'.', b'p', b'y', b'f', b'g', b'c', b'r', b'l', b'/', b'=', b'\n', 0, b'a', b'o', b'e',
+    b'u', b'i', b'd', b'h', b't', b'n', b's', b'-', b'`', 0, b'\\', b';', b'q', b'j', b'k', b'x',
+    b'b', b'm', b'w', b'v', b'z', 0, 0, 0, b' ',
+];
+
+const DVORAK_SHIFTED: &[u8] = &[
+    0, 0, b'!', b'@', b'#', b'$', b'%', b'^', b'&', b'*', b'(', b')', b'{', b'}', 8, b'\t', b'"',
+    b'<', b'>', b'P', b'Y', b'F', b'G', b'C', b'R', b'L', b'?', b'+', b'\n', 0, b'A', b'O', b'E',
+    b'U', b'I', b'D', b'H', b'T', b'N', b'S', b'_', b'~', 0, b'|', b':', b'Q', b'J', b'K', b'X',
+    b'B', b'M', b'W', b'V', b'Z', 0, 0, 0, b' ',
+];
+
+pub static LAYOUTS: [KeyboardLayout; 2] = [
+    KeyboardLayout {
+        name: "us",
+        normal: US_NORMAL,
+        shifted: US_SHIFTED,
+    },
+    KeyboardLayout {
+        name: "dvorak",
+        normal: DVORAK_NORMAL,
+        shifted: DVORAK_SHIFTED,
+    },
+];
+
+// an index into LAYOUTS, the interrupt handler reads it so it is not behind a lock
+static CURRENT_LAYOUT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn current_layout() -> &'static KeyboardLayout {
+    &LAYOUTS[CURRENT_LAYOUT.load(Ordering::Relaxed)]
+}
+
+/// Switches to the layout called __name__, returns false if there is no such layout
+pub fn set_layout(name: &str) -> bool {
+    match LAYOUTS.iter().position(|layout| layout.name == name) {
+        Some(idx) => {
+            CURRENT_LAYOUT.store(idx, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The contents of /dev/kbdlayout
+fn layout_list() -> String {
+    let current = current_layout().name;
+    let names: Vec<String> = LAYOUTS
+        .iter()
+        .map(|layout| match layout.name == current {
+            true => format!("[{}]", layout.name),
+            false => String::from(layout.name),
+        })
+        .collect();
+
+    format!("{}\n", names.join(" "))
+}
+
+struct KeyboardLayoutDevice;
+
+impl DevFsDevice for KeyboardLayoutDevice {
+    fn read(&self, _minor: u16, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        let contents = layout_list();
+        let contents = contents.as_bytes();
+        if off >= contents.len() {
+            return Ok(0);
+        }
+
+        let len = usize::min(buff.len(), contents.len() - off);
+        buff[..len].copy_from_slice(&contents[off..off + len]);
+        Ok(len)
+    }
+
+    fn write(&self, _minor: u16, _off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        if context::current().euid != 0 {
+            return Err(FsWriteError::PermissionDenied);
+        }
+
+        let name = core::str::from_utf8(buff).map_err(|_| FsWriteError::InvalidArgument)?;
+        match set_layout(name.trim()) {
+            true => Ok(buff.len()),
+            false => Err(FsWriteError::InvalidArgument),
+        }
+    }
+
+    fn ioctl(&self, _minor: u16, _req: usize, _arg: usize) -> Result<usize, FsIoctlError> {
+        Err(FsIoctlError::NotATerminal)
+    }
+
+    fn stat(&self, minor: u16, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        stat_buf.st_blksize = 4096;
+        stat_buf.st_blocks = 0;
+        stat_buf.st_size = 0;
+        stat_buf.st_dev = 0;
+        stat_buf.st_rdev = (KEYBOARD_LAYOUT_MAJOR as u64) << 8 | minor as u64;
+        stat_buf.st_gid = 0;
+        stat_buf.st_uid = 0;
+        stat_buf.st_nlink = 1;
+        stat_buf.st_mode = S_IFCHR | 0o644;
+
+        Ok(())
+    }
+}
+
+/// Selects the layout given on the command line and creates /dev/kbdlayout
+pub fn init() {
+    if let Some(name) = cmdline::get("keyboard_layout") {
+        if !set_layout(&name) {
+            warn!("PS2: unknown keyboard layout {}", name);
+        }
+    }
+
+    devfs::register_devfs_node(Path::new("/kbdlayout").unwrap(), KEYBOARD_LAYOUT_MAJOR, 0).unwrap();
+    devfs::register_devfs_node_operations(KEYBOARD_LAYOUT_MAJOR, Arc::new(KeyboardLayoutDevice))
+        .unwrap();
+}
@@ -1,43 +1,249 @@
-use crate::arch::x86_64::{
-    disable_interrupts, enable_interrupts,
-    pic::{self, clear_irq},
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::{
+    arch::x86_64::{disable_interrupts, enable_interrupts, irq},
+    cmdline, latency,
+    scheduler::{wait_queue::Waiter, SCHEDULER},
 };
 
+use controller::{DeviceType, PS2ControllerError, Port, DEVICE_SELF_TEST_PASSED};
+use keyboard::ScancodeSet;
+
 mod controller;
 pub mod keyboard;
+mod layout;
+mod mouse;
 
 const FIRST_PORT_IRQ: u8 = 1;
 const SECOND_PORT_IRQ: u8 = 12;
 
+const KEYBOARD_CMD_SCANCODE_SET: u8 = 0xF0;
+const KEYBOARD_CMD_ENABLE_SCANNING: u8 = 0xF4;
+
+/// `PORT_DEVICE` of a port with nothing plugged in
+const NO_DEVICE: u8 = 0;
+
+/// Whether a port passed its test, only those have an interrupt handler
+static PORT_WORKING: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// Set when the device on a port has to be set up again
+static PORT_RESET: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// The `DeviceType` of the device on a port or `NO_DEVICE`, the interrupt handler of the port
+/// passes the bytes to its driver
+static PORT_DEVICE: [AtomicU8; 2] = [AtomicU8::new(NO_DEVICE), AtomicU8::new(NO_DEVICE)];
+static HOTPLUG_WAITER: Waiter = Waiter::new();
+
+fn port_device(port: Port) -> Option<DeviceType> {
+    match PORT_DEVICE[port as usize].load(Ordering::Relaxed) {
+        NO_DEVICE => None,
+        t if t == DeviceType::Keyboard as u8 => Some(DeviceType::Keyboard),
+        _ => Some(DeviceType::Mouse),
+    }
+}
+
+fn set_port_device(port: Port, device_type: Option<DeviceType>) {
+    let val = device_type.map_or(NO_DEVICE, |t| t as u8);
+    PORT_DEVICE[port as usize].store(val, Ordering::Relaxed);
+}
+
+/// Makes the keyboard on __port__ send set 2, the controller passes it on untranslated
+fn select_scancode_set2(port: Port) -> ScancodeSet {
+    let res = controller::write_port_device(port, KEYBOARD_CMD_SCANCODE_SET)
+        .and_then(|_| controller::write_port_device(port, 2));
+    if let Err(err) = res {
+        warn!("PS2: failed to select scancode set 2: {:?}", err);
+    }
+
+    ScancodeSet::Set2
+}
+
 extern "C" {
     fn __ps2_first_interrupt();
+    fn __ps2_second_interrupt();
 }
 
-pub fn init() -> bool {
-    disable_interrupts();
+/// Sets up the keyboard on __port__ once it was reset and identified, it must be called like
+/// `mouse::init`
+fn init_keyboard(port: Port) -> Result<(), PS2ControllerError> {
+    // only the scancodes of the first port are translated
+    let scancode_set = match port == Port::First && controller::translation_enabled() {
+        true => ScancodeSet::Set1,
+        false => select_scancode_set2(port),
+    };
+    controller::write_port_device(port, KEYBOARD_CMD_ENABLE_SCANNING)?;
+    keyboard::init(port, scancode_set);
 
-    let res = match controller::init() {
-        Ok(ports) => {
-            match ports {
-                (false, false) => false,
-                (first, _second) => {
-                    // TODO: don't assume the first port is the keyboard
-                    assert!(first);
+    Ok(())
+}
+
+/// Resets the device on __port__, finds out what it is and sets it up with its driver. It must
+/// be called like `mouse::init`.
+fn setup_device(port: Port) -> Result<DeviceType, PS2ControllerError> {
+    controller::reset_device(port)?;
+    // a mouse sends its ID after the self test result
+    controller::flush_output_buffer();
+
+    let device_type = controller::identify_device(port)?;
+    match device_type {
+        DeviceType::Keyboard => init_keyboard(port)?,
+        DeviceType::Mouse => mouse::init(port)?,
+    }
+
+    Ok(device_type)
+}
+
+fn disconnect_device(port: Port, device_type: DeviceType) {
+    match device_type {
+        DeviceType::Keyboard => keyboard::disconnect(port),
+        DeviceType::Mouse => mouse::disconnect(port),
+    }
+}
 
-                    pic::install_irq_handler(FIRST_PORT_IRQ, __ps2_first_interrupt as usize as u64);
-                    clear_irq(FIRST_PORT_IRQ);
+fn port_irq(port: Port) -> u8 {
+    match port {
+        Port::First => FIRST_PORT_IRQ,
+        Port::Second => SECOND_PORT_IRQ,
+    }
+}
+
+/// Passes the byte the device on __port__ sent to its driver
+fn port_interrupt(port: Port) {
+    let _latency = latency::irq_timer(irq::vector(port_irq(port)));
+    // the byte could have been read while the interrupt was masked
+    if let Ok(byte) = controller::read_data_buffer() {
+        match port_device(port) {
+            Some(DeviceType::Keyboard) => keyboard::receive_byte(port, byte),
+            Some(DeviceType::Mouse) => mouse::receive_byte(port, byte),
+            // a device was plugged into the empty port
+            None if byte == DEVICE_SELF_TEST_PASSED => device_reset(port),
+            None => (),
+        }
+    }
+
+    irq::eoi(port_irq(port));
+}
+
+#[no_mangle]
+fn handle_first_port_interrupt() {
+    port_interrupt(Port::First);
+}
 
-                    true
-                }
+#[no_mangle]
+fn handle_second_port_interrupt() {
+    port_interrupt(Port::Second);
+}
+
+/// Called by the driver of the device on __port__ when the device sent a self test result by
+/// itself, the hot-plug thread sets the device up again
+fn device_reset(port: Port) {
+    PORT_RESET[port as usize].store(true, Ordering::Relaxed);
+    HOTPLUG_WAITER.wake();
+}
+
+/// Sets up the device on __port__ again, with the interrupts masked and the other port
+/// disabled so nothing else reads the responses of the device
+fn reinit_device(port: Port) {
+    let other = match port {
+        Port::First => Port::Second,
+        Port::Second => Port::First,
+    };
+    let other_working = PORT_WORKING[other as usize].load(Ordering::Relaxed);
+
+    irq::disable(port_irq(port));
+    if other_working {
+        irq::disable(port_irq(other));
+        controller::set_port_enabled(other, false);
+    }
+
+    // another kind of device could have been plugged in
+    let previous = port_device(port);
+    let current = match setup_device(port) {
+        Ok(device_type) => Some(device_type),
+        Err(err) => {
+            warn!(
+                "PS2: failed to set up the device on the {:?} port: {:?}",
+                port, err
+            );
+            None
+        }
+    };
+    if let Some(previous) = previous.filter(|&previous| Some(previous) != current) {
+        disconnect_device(port, previous);
+    }
+    set_port_device(port, current);
+
+    controller::flush_output_buffer();
+    if other_working {
+        controller::set_port_enabled(other, true);
+        irq::enable(port_irq(other));
+    }
+    irq::enable(port_irq(port));
+}
+
+/// Sets up the devices that were plugged in or reset themselves, it runs as a kernel thread.
+/// PS/2 has no way to tell that a device was unplugged, a device counts as removed when it
+/// can not be set up again.
+fn hotplug_thread() {
+    loop {
+        HOTPLUG_WAITER.block();
+
+        for port in [Port::First, Port::Second] {
+            if PORT_RESET[port as usize].swap(false, Ordering::Relaxed) {
+                reinit_device(port);
             }
         }
+    }
+}
+
+pub fn init() -> bool {
+    // ps2_scancode_set=2 turns off the translation of the controller, some controllers can
+    // not translate at all
+    let translate = cmdline::get("ps2_scancode_set").map_or(true, |set| set != "2");
+
+    disable_interrupts();
+
+    let (first, second) = match controller::init(translate) {
+        Ok(ports) => ports,
         Err(err) => {
+            enable_interrupts();
             log!("PS2: initialization failed: {:?}", err);
-            false
+            return false;
         }
     };
 
+    // the interrupt handlers are installed even if nothing is plugged in, a device sends its
+    // self test result once it is
+    for (port, working) in [(Port::First, first), (Port::Second, second)] {
+        if !working {
+            continue;
+        }
+
+        match setup_device(port) {
+            Ok(device_type) => {
+                log!("PS2: {:?} found on the {:?} port", device_type, port);
+                set_port_device(port, Some(device_type));
+            }
+            Err(err) => log!("PS2: no device on the {:?} port: {:?}", port, err),
+        }
+
+        let handler = match port {
+            Port::First => __ps2_first_interrupt as usize as u64,
+            Port::Second => __ps2_second_interrupt as usize as u64,
+        };
+        irq::install_handler(port_irq(port), handler);
+        irq::enable(port_irq(port));
+    }
+
+    controller::flush_output_buffer();
+    PORT_WORKING[Port::First as usize].store(first, Ordering::Relaxed);
+    PORT_WORKING[Port::Second as usize].store(second, Ordering::Relaxed);
+
     enable_interrupts();
 
-    res
+    // a keyboard can be plugged into either port later
+    if first || second {
+        SCHEDULER.create_kernel_thread(hotplug_thread);
+        layout::init();
+    }
+
+    first || second
 }
@@ -0,0 +1,227 @@
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::{
+    input::{self, InputDevice},
+    posix::input::{BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_KEY, EV_REL, REL_WHEEL, REL_X, REL_Y},
+};
+
+use super::controller::{
+    read_data_buffer, write_port_device, PS2ControllerError, Port, DEVICE_SELF_TEST_PASSED,
+};
+
+const MOUSE_CMD_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+const MOUSE_CMD_GET_DEVICE_ID: u8 = 0xF2;
+const MOUSE_CMD_ENABLE_REPORTING: u8 = 0xF4;
+
+/// Setting these sample rates in a row switches an IntelliMouse to the mode with a scroll
+/// wheel, after that it reports this device ID
+const INTELLIMOUSE_MAGIC: [u8; 3] = [200, 100, 80];
+const INTELLIMOUSE_ID: u8 = 3;
+
+const DEFAULT_SAMPLE_RATE: u8 = 100;
+
+/// The device ID a mouse sends after its self test result
+const MOUSE_ID: u8 = 0;
+
+const PACKET_LEFT_BUTTON: u8 = 1 << 0;
+const PACKET_RIGHT_BUTTON: u8 = 1 << 1;
+const PACKET_MIDDLE_BUTTON: u8 = 1 << 2;
+/// Always set in the first byte of a packet, used to find the start of the packets
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+const BUTTONS: [(u8, u16); 3] = [
+    (PACKET_LEFT_BUTTON, BTN_LEFT),
+    (PACKET_RIGHT_BUTTON, BTN_RIGHT),
+    (PACKET_MIDDLE_BUTTON, BTN_MIDDLE),
+];
+
+struct PS2Mouse {
+    port: Port,
+    packet: [u8; 4],
+    received: usize,
+    /// Whether the packets have a fourth byte with the movement of the scroll wheel
+    has_wheel: bool,
+    buttons: u8,
+    device: Option<Arc<InputDevice>>,
+}
+
+/// A mouse on either port, indexed by the port
+static MICE: [Mutex<PS2Mouse>; 2] = [
+    Mutex::new(PS2Mouse::new(Port::First)),
+    Mutex::new(PS2Mouse::new(Port::Second)),
+];
+
+impl PS2Mouse {
+    const fn new(port: Port) -> PS2Mouse {
+        PS2Mouse {
+            port,
+            packet: [0; 4],
+            received: 0,
+            has_wheel: false,
+            buttons: 0,
+            device: None,
+        }
+    }
+
+    fn packet_size(&self) -> usize {
+        if self.has_wheel {
+            4
+        } else {
+            3
+        }
+    }
+
+    fn receive_byte(&mut self, byte: u8) {
+        // the mouse was plugged in or reset itself, a packet starting like this would have
+        // the overflow bit set and be dropped anyway
+        if self.received == 1 && self.packet[0] == DEVICE_SELF_TEST_PASSED && byte == MOUSE_ID {
+            self.release_buttons();
+            super::device_reset(self.port);
+            return;
+        }
+
+        // a byte was lost if the first byte of a packet does not look like one, the bytes are
+        // dropped until the packets line up again
+        if self.received == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received == self.packet_size() {
+            self.received = 0;
+            self.decode_packet();
+        }
+    }
+
+    /// Releases the pressed buttons and drops the partially received packet
+    fn release_buttons(&mut self) {
+        if let Some(device) = &self.device {
+            for (bit, code) in BUTTONS {
+                if self.buttons & bit != 0 {
+                    device.report(EV_KEY, code, 0);
+                }
+            }
+            device.sync();
+        }
+
+        self.buttons = 0;
+        self.received = 0;
+    }
+
+    fn decode_packet(&mut self) {
+        let device = match &self.device {
+            Some(device) => device,
+            None => return,
+        };
+
+        let flags = self.packet[0];
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+            return;
+        }
+
+        // the movement is a 9 bit two's complement number, the sign is in the first byte
+        let mut dx = self.packet[1] as i32;
+        if flags & PACKET_X_SIGN != 0 {
+            dx -= 0x100;
+        }
+        let mut dy = self.packet[2] as i32;
+        if flags & PACKET_Y_SIGN != 0 {
+            dy -= 0x100;
+        }
+        let dz = match self.has_wheel {
+            true => self.packet[3] as i8 as i32,
+            false => 0,
+        };
+
+        for (bit, code) in BUTTONS {
+            if (flags ^ self.buttons) & bit != 0 {
+                device.report(EV_KEY, code, (flags & bit != 0) as i32);
+            }
+        }
+        self.buttons = flags & (PACKET_LEFT_BUTTON | PACKET_RIGHT_BUTTON | PACKET_MIDDLE_BUTTON);
+
+        // the mouse counts upwards movement and scrolling down as positive, the input events
+        // count them the other way around
+        if dx != 0 {
+            device.report(EV_REL, REL_X, dx);
+        }
+        if dy != 0 {
+            device.report(EV_REL, REL_Y, -dy);
+        }
+        if dz != 0 {
+            device.report(EV_REL, REL_WHEEL, -dz);
+        }
+
+        device.sync();
+    }
+}
+
+fn set_sample_rate(port: Port, rate: u8) -> Result<(), PS2ControllerError> {
+    write_port_device(port, MOUSE_CMD_SET_SAMPLE_RATE)?;
+    write_port_device(port, rate)
+}
+
+/// Enables the scroll wheel if the mouse has one, returns whether it has
+fn enable_scroll_wheel(port: Port) -> Result<bool, PS2ControllerError> {
+    for rate in INTELLIMOUSE_MAGIC {
+        set_sample_rate(port, rate)?;
+    }
+
+    write_port_device(port, MOUSE_CMD_GET_DEVICE_ID)?;
+    let id = read_data_buffer().map_err(|_| PS2ControllerError::DeviceCommandFailed)?;
+
+    set_sample_rate(port, DEFAULT_SAMPLE_RATE)?;
+    Ok(id == INTELLIMOUSE_ID)
+}
+
+/// Configures the mouse on __port__ once it was reset and identified and starts its data
+/// reporting, the input device is created the first time a mouse is found on the port. It must
+/// be called with interrupts disabled or the interrupts of the ports masked.
+pub fn init(port: Port) -> Result<(), PS2ControllerError> {
+    write_port_device(port, MOUSE_CMD_SET_DEFAULTS)?;
+    let has_wheel = enable_scroll_wheel(port)?;
+    write_port_device(port, MOUSE_CMD_ENABLE_REPORTING)?;
+
+    let name = match has_wheel {
+        true => "ImPS/2 Generic Wheel Mouse",
+        false => "PS/2 Generic Mouse",
+    };
+
+    let registered = MICE[port as usize].lock().device.clone();
+    let device = match registered {
+        Some(device) => {
+            device.reconnect();
+            device
+        }
+        None => input::register_device(name),
+    };
+
+    let mut mouse = MICE[port as usize].lock();
+    mouse.release_buttons();
+    mouse.has_wheel = has_wheel;
+    mouse.device = Some(device);
+
+    Ok(())
+}
+
+/// Called when the mouse on __port__ could not be set up again after it reset or another
+/// device was plugged in instead, it was unplugged
+pub fn disconnect(port: Port) {
+    let mut mouse = MICE[port as usize].lock();
+    mouse.release_buttons();
+    if let Some(device) = &mouse.device {
+        device.disconnect();
+    }
+}
+
+/// Called by the interrupt handler of __port__ with the byte the mouse sent
+pub fn receive_byte(port: Port, byte: u8) {
+    MICE[port as usize].lock().receive_byte(byte);
+}
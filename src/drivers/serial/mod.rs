@@ -1,4 +1,12 @@
-use crate::arch::x86_64::{inb, outb};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    arch::x86_64::{inb, irq, outb},
+    latency,
+    sync::InterruptMutex,
+    utils::ring_buffer::ByteRingBuffer,
+};
 
 const COM1: u16 = 0x3F8;
 const COM2: u16 = 0x2F8;
@@ -16,42 +24,148 @@ const LINE_CONTROL_REG: u16 = 0x3;
 const MODEM_CONTROL_REG: u16 = 0x4;
 const LINE_STATUS_REG: u16 = 0x5;
 
+const INTERRUPT_DATA_AVAILABLE: u8 = 1 << 0;
+
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+
+const COM1_IRQ: u8 = 4;
+
+/// Number of received bytes that are kept until there is a handler for them
+const RX_BUFFER_SIZE: usize = 1024;
+
+/// Receives the bytes read from the port, it is called from the interrupt handler
+pub trait SerialInputHandler {
+    fn receive_byte(&self, byte: u8);
+}
+
+struct SerialInput {
+    handler: Option<Arc<dyn SerialInputHandler>>,
+    /// Bytes that were received but not passed to a handler yet, if it fills up the new
+    /// bytes are dropped
+    rx_buffer: ByteRingBuffer<RX_BUFFER_SIZE>,
+}
+
+unsafe impl Send for SerialInput {}
+
+// locked in the interrupt handler so it is locked with interrupts disabled everywhere
+static COM1_INPUT: InterruptMutex<SerialInput> = InterruptMutex::new(SerialInput {
+    handler: None,
+    rx_buffer: ByteRingBuffer::new(0),
+});
+
+/// Set once the chip passed the loopback test
+static COM1_PRESENT: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn __serial_com1_interrupt();
+}
+
 // TODO: implement the whole driver
 
-pub fn init() -> bool {
+/// Sets up the chip at __base__ without interrupts, returns false if it does not exist
+fn setup_port(base: u16) -> bool {
     // enable reg
-    outb(COM1 + INTERRUPT_ENABLE_REG, 0);
+    outb(base + INTERRUPT_ENABLE_REG, 0);
 
     // set dlab
-    outb(COM1 + LINE_CONTROL_REG, 0x80);
+    outb(base + LINE_CONTROL_REG, 0x80);
 
     // set baud rate to 3
-    outb(COM1 + DATA_REG, 0x3);
-    outb(COM1 + INTERRUPT_ENABLE_REG, 0x0);
+    outb(base + DATA_REG, 0x3);
+    outb(base + INTERRUPT_ENABLE_REG, 0x0);
 
     // disable dlab, 8 bits, no parity, one stop bit
-    outb(COM1 + LINE_CONTROL_REG, 0x03);
+    outb(base + LINE_CONTROL_REG, 0x03);
 
     // enable fifo
-    outb(COM1 + FIFO_CONTROL_REG, 0xC7);
+    outb(base + FIFO_CONTROL_REG, 0xC7);
 
     // IRQs enabled, RTS/DSR set
-    outb(COM1 + MODEM_CONTROL_REG, 0x0B);
+    outb(base + MODEM_CONTROL_REG, 0x0B);
 
     // test if the chip exists
-    outb(COM1 + MODEM_CONTROL_REG, 0x1E);
-    outb(COM1 + DATA_REG, 0xAE);
+    outb(base + MODEM_CONTROL_REG, 0x1E);
+    outb(base + DATA_REG, 0xAE);
 
-    if inb(COM1 + DATA_REG) != 0xAE {
+    if inb(base + DATA_REG) != 0xAE {
         return false;
     }
 
     // set to normal mode
-    outb(COM1 + MODEM_CONTROL_REG, 0x0F);
+    outb(base + MODEM_CONTROL_REG, 0x0F);
+    true
+}
 
+pub fn init() -> bool {
+    if !setup_port(COM1) {
+        return false;
+    }
+
+    // raise an interrupt when a byte is received
+    irq::install_handler(COM1_IRQ, __serial_com1_interrupt as usize as u64);
+    outb(COM1 + INTERRUPT_ENABLE_REG, INTERRUPT_DATA_AVAILABLE);
+    irq::enable(COM1_IRQ);
+
+    COM1_PRESENT.store(true, Ordering::Relaxed);
     true
 }
 
+/// Sets up COM1 without interrupts so the panic handler can write to it before the driver is
+/// loaded, returns false if it does not exist
+pub fn init_polled() -> bool {
+    setup_port(COM1)
+}
+
+/// Returns whether COM1 exists and was initialized
+pub fn is_present() -> bool {
+    COM1_PRESENT.load(Ordering::Relaxed)
+}
+
+fn is_data_ready() -> bool {
+    inb(COM1 + LINE_STATUS_REG) & LINE_STATUS_DATA_READY > 0
+}
+
+impl SerialInput {
+    /// Passes the buffered bytes to the handler, they stay buffered if there is none
+    fn deliver(&mut self) {
+        let handler = match &self.handler {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        while let Some(byte) = self.rx_buffer.pop() {
+            handler.receive_byte(byte);
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn serial_com1_interrupt() {
+    let _latency = latency::irq_timer(irq::vector(COM1_IRQ));
+    let mut input = COM1_INPUT.lock();
+
+    // read every byte from the fifo even if the buffer is full so the interrupt is cleared
+    while is_data_ready() {
+        let byte = inb(COM1 + DATA_REG);
+        let _ = input.rx_buffer.push(byte);
+    }
+    input.deliver();
+
+    irq::eoi(COM1_IRQ);
+}
+
+/// Sets the handler that receives the bytes read from COM1, the bytes received while there
+/// was no handler are passed to it right away. The previous handler is returned so it is not
+/// freed with the input locked.
+pub fn set_input_handler(
+    handler: Option<Arc<dyn SerialInputHandler>>,
+) -> Option<Arc<dyn SerialInputHandler>> {
+    let mut input = COM1_INPUT.lock();
+    let prev = core::mem::replace(&mut input.handler, handler);
+    input.deliver();
+    prev
+}
+
 fn is_transmit_empty() -> bool {
     inb(COM1 + LINE_STATUS_REG) & 0x20 > 0
 }
@@ -60,3 +174,52 @@ pub fn write(data: u8) {
     while !is_transmit_empty() {}
     outb(COM1 + DATA_REG, data);
 }
+
+/// The I/O ports of ttyS0 to ttyS3
+const COM_PORTS: [u16; 4] = [COM1, COM2, COM3, COM4];
+
+/// A serial port that is used without interrupts, e.g. by the debugger stub while the rest
+/// of the kernel is stopped
+#[derive(Clone, Copy)]
+pub struct PolledPort {
+    base: u16,
+}
+
+impl PolledPort {
+    /// Sets up ttyS__index__, returns None if the chip does not exist. ttyS0 belongs to the
+    /// driver so it can not be opened.
+    pub fn open(index: usize) -> Option<PolledPort> {
+        let base = match index {
+            1..=3 => COM_PORTS[index],
+            _ => return None,
+        };
+
+        match setup_port(base) {
+            true => Some(PolledPort { base }),
+            false => None,
+        }
+    }
+
+    pub fn write(&self, byte: u8) {
+        while inb(self.base + LINE_STATUS_REG) & 0x20 == 0 {}
+        outb(self.base + DATA_REG, byte);
+    }
+
+    /// Returns the next received byte if there is one
+    pub fn try_read(&self) -> Option<u8> {
+        match inb(self.base + LINE_STATUS_REG) & LINE_STATUS_DATA_READY {
+            0 => None,
+            _ => Some(inb(self.base + DATA_REG)),
+        }
+    }
+
+    /// Waits until a byte is received
+    pub fn read(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
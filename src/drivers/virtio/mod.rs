@@ -0,0 +1,583 @@
+//! Shared code of the virtio drivers, it discovers the virtio PCI devices, negotiates
+//! features, sets up the virtqueues and dispatches interrupts. The drivers of the
+//! individual device types only implement their device specific logic.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::{
+    arch::x86_64::irq,
+    latency,
+    mm::{PhysAddr, VirtAddr},
+    mmio::{Mmio, VolatileCell},
+    pci::{self, PCIDevice},
+    sync::InterruptMutex,
+};
+
+use self::queue::Virtqueue;
+
+pub mod queue;
+
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+/// Device IDs of modern devices are 0x1040 + the device type
+const VIRTIO_MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+/// Transitional devices use 0x1000..0x103F, the device type is in the subsystem ID
+const VIRTIO_TRANSITIONAL_DEVICE_ID_BASE: u16 = 0x1000;
+const VIRTIO_DEVICE_ID_LAST: u16 = 0x107F;
+
+const PCI_COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+const PCI_COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// offsets of the fields of struct virtio_pci_cap
+const VIRTIO_PCI_CAP_CFG_TYPE_OFF: u8 = 3;
+const VIRTIO_PCI_CAP_BAR_OFF: u8 = 4;
+const VIRTIO_PCI_CAP_OFFSET_OFF: u8 = 8;
+const VIRTIO_PCI_CAP_LENGTH_OFF: u8 = 12;
+const VIRTIO_PCI_NOTIFY_CAP_MULT_OFF: u8 = 16;
+
+/// The device complies with the virtio 1.0 specification
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// Features every driver negotiates if the device offers them
+const VIRTIO_TRANSPORT_FEATURES: u64 = VIRTIO_F_VERSION_1;
+
+/// Maximum number of IRQ lines the interrupt stubs exist for
+const VIRTIO_IRQ_LINES: usize = 16;
+
+bitflags::bitflags! {
+    pub struct DeviceStatus: u8 {
+        const ACKNOWLEDGE = 1 << 0;
+        const DRIVER = 1 << 1;
+        const DRIVER_OK = 1 << 2;
+        const FEATURES_OK = 1 << 3;
+        const DEVICE_NEEDS_RESET = 1 << 6;
+        const FAILED = 1 << 7;
+    }
+
+    pub struct IsrStatus: u8 {
+        /// One of the virtqueues has used buffers
+        const QUEUE = 1 << 0;
+        /// The device configuration changed
+        const CONFIG = 1 << 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Gpu,
+    Input,
+    Other(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VirtioError {
+    /// A required virtio PCI capability is missing
+    MissingCapability,
+    /// The capability points to an I/O BAR or an unimplemented BAR
+    UnsupportedBar,
+    /// The device did not accept the negotiated features
+    FeaturesRejected,
+    /// The device does not have a queue with the index
+    QueueUnavailable,
+    InvalidQueueSize,
+    QueueFull,
+    EmptyChain,
+    /// The device has no legacy interrupt line
+    NoInterruptLine,
+}
+
+/// The common configuration structure, the 64 bit fields are split because the
+/// specification only requires 32 bit accesses to work
+#[repr(C)]
+struct CommonConfig {
+    device_feature_select: VolatileCell<u32>,
+    device_feature: VolatileCell<u32>,
+    driver_feature_select: VolatileCell<u32>,
+    driver_feature: VolatileCell<u32>,
+    msix_config: VolatileCell<u16>,
+    num_queues: VolatileCell<u16>,
+    device_status: VolatileCell<u8>,
+    config_generation: VolatileCell<u8>,
+    queue_select: VolatileCell<u16>,
+    queue_size: VolatileCell<u16>,
+    queue_msix_vector: VolatileCell<u16>,
+    queue_enable: VolatileCell<u16>,
+    queue_notify_off: VolatileCell<u16>,
+    queue_desc_lo: VolatileCell<u32>,
+    queue_desc_hi: VolatileCell<u32>,
+    queue_driver_lo: VolatileCell<u32>,
+    queue_driver_hi: VolatileCell<u32>,
+    queue_device_lo: VolatileCell<u32>,
+    queue_device_hi: VolatileCell<u32>,
+}
+
+/// A virtio PCI device that is not claimed by a driver yet or is owned by one
+pub struct VirtioDevice {
+    pub bus: u8,
+    pub dev: u8,
+    pub function: u8,
+    pub device_type: DeviceType,
+    /// Legacy interrupt line, None if the device has no interrupt pin
+    pub irq: Option<u8>,
+
+    common: Mmio<CommonConfig>,
+    isr: Mmio<VolatileCell<u8>>,
+    notify_base: VirtAddr,
+    notify_off_multiplier: u32,
+    device_cfg: Option<VirtAddr>,
+
+    features: u64,
+}
+
+/// Called with a device of the type the driver handles, returns whether the driver took it
+pub type VirtioProbe = fn(VirtioDevice) -> bool;
+
+/// Called from the interrupt handler with the ISR status of the device that raised it
+pub type VirtioInterruptHandler = fn(IsrStatus);
+
+struct VirtioDriver {
+    device_type: DeviceType,
+    probe: VirtioProbe,
+}
+
+struct InterruptHandler {
+    irq: u8,
+    isr: Mmio<VolatileCell<u8>>,
+    handler: VirtioInterruptHandler,
+}
+
+/// Devices no driver has claimed yet
+static UNCLAIMED_DEVICES: Mutex<Vec<VirtioDevice>> = Mutex::new(Vec::new());
+
+static DRIVERS: Mutex<Vec<VirtioDriver>> = Mutex::new(Vec::new());
+
+// the handlers are used from interrupt context
+static INTERRUPT_HANDLERS: InterruptMutex<Vec<InterruptHandler>> = InterruptMutex::new(Vec::new());
+
+extern "C" {
+    static __virtio_irq_stubs: [u64; VIRTIO_IRQ_LINES];
+}
+
+impl DeviceType {
+    fn from_u16(val: u16) -> DeviceType {
+        match val {
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::Entropy,
+            16 => DeviceType::Gpu,
+            18 => DeviceType::Input,
+            _ => DeviceType::Other(val),
+        }
+    }
+}
+
+/// A virtio PCI capability, it describes where a configuration structure is
+#[derive(Debug, Clone, Copy)]
+struct VirtioCapability {
+    cfg_type: u8,
+    bar: u8,
+    offset: u32,
+    length: u32,
+    /// Offset of the capability in the PCI configuration space
+    cap_off: u8,
+}
+
+impl VirtioDevice {
+    fn read_capabilities(pci_device: &PCIDevice) -> Vec<VirtioCapability> {
+        let (bus, dev, func) = (pci_device.bus, pci_device.dev, pci_device.function);
+        pci_device
+            .vendor_capabilities()
+            .map(|cap_off| VirtioCapability {
+                cfg_type: pci::read_config8(bus, dev, func, cap_off + VIRTIO_PCI_CAP_CFG_TYPE_OFF),
+                bar: pci::read_config8(bus, dev, func, cap_off + VIRTIO_PCI_CAP_BAR_OFF),
+                offset: pci::read_config32(bus, dev, func, cap_off + VIRTIO_PCI_CAP_OFFSET_OFF),
+                length: pci::read_config32(bus, dev, func, cap_off + VIRTIO_PCI_CAP_LENGTH_OFF),
+                cap_off,
+            })
+            .collect()
+    }
+
+    /// Returns the physical address of a memory BAR
+    fn bar_address(pci_device: &PCIDevice, bar: u8) -> Result<PhysAddr, VirtioError> {
+        let bars = unsafe {
+            let header = &pci_device.specific.type0;
+            [
+                header.bar0,
+                header.bar1,
+                header.bar2,
+                header.bar3,
+                header.bar4,
+                header.bar5,
+            ]
+        };
+
+        let idx = bar as usize;
+        if idx >= bars.len() || bars[idx] == 0 || bars[idx] & 1 == 1 {
+            return Err(VirtioError::UnsupportedBar);
+        }
+
+        let low = (bars[idx] & !0xF) as u64;
+        let addr = match (bars[idx] >> 1) & 0b11 {
+            // 64 bit BAR, the upper half is in the next BAR
+            0b10 if idx + 1 < bars.len() => low | (bars[idx + 1] as u64) << 32,
+            0b00 => low,
+            _ => return Err(VirtioError::UnsupportedBar),
+        };
+
+        Ok(PhysAddr::new(addr))
+    }
+
+    fn capability_address(
+        pci_device: &PCIDevice,
+        cap: &VirtioCapability,
+    ) -> Result<VirtAddr, VirtioError> {
+        let bar = Self::bar_address(pci_device, cap.bar)?;
+        // TODO: map the BARs uncacheable instead of going through the HHDM
+        Ok((bar + PhysAddr::new(cap.offset as u64)).virt_addr())
+    }
+
+    fn from_pci(pci_device: &PCIDevice) -> Result<VirtioDevice, VirtioError> {
+        let (bus, dev, func) = (pci_device.bus, pci_device.dev, pci_device.function);
+
+        let device_type = if pci_device.device_id >= VIRTIO_MODERN_DEVICE_ID_BASE {
+            DeviceType::from_u16(pci_device.device_id - VIRTIO_MODERN_DEVICE_ID_BASE)
+        } else {
+            DeviceType::from_u16(unsafe { pci_device.specific.type0.subsystem_id })
+        };
+
+        let caps = Self::read_capabilities(pci_device);
+        // the first capability of each type is the preferred one
+        let find_cap = |cfg_type: u8| caps.iter().find(|cap| cap.cfg_type == cfg_type);
+
+        let common_cap =
+            find_cap(VIRTIO_PCI_CAP_COMMON_CFG).ok_or(VirtioError::MissingCapability)?;
+        let notify_cap =
+            find_cap(VIRTIO_PCI_CAP_NOTIFY_CFG).ok_or(VirtioError::MissingCapability)?;
+        let isr_cap = find_cap(VIRTIO_PCI_CAP_ISR_CFG).ok_or(VirtioError::MissingCapability)?;
+
+        let device_cfg = match find_cap(VIRTIO_PCI_CAP_DEVICE_CFG) {
+            Some(cap) if cap.length > 0 => Some(Self::capability_address(pci_device, cap)?),
+            _ => None,
+        };
+
+        let notify_off_multiplier = pci::read_config32(
+            bus,
+            dev,
+            func,
+            notify_cap.cap_off + VIRTIO_PCI_NOTIFY_CAP_MULT_OFF,
+        );
+
+        let interrupt_pin = unsafe { pci_device.specific.type0.interrupt_pin };
+        let interrupt_line = unsafe { pci_device.specific.type0.interrupt_line };
+        let irq = if interrupt_pin != 0 && (interrupt_line as usize) < VIRTIO_IRQ_LINES {
+            Some(interrupt_line)
+        } else {
+            None
+        };
+
+        // the BARs must be decoded and the device must be able to access the rings
+        let command = pci::read_config16(bus, dev, func, pci::DEVICE_COMMAND_OFF);
+        pci::write_config16(
+            bus,
+            dev,
+            func,
+            pci::DEVICE_COMMAND_OFF,
+            (command | PCI_COMMAND_MEMORY_SPACE | PCI_COMMAND_BUS_MASTER)
+                & !PCI_COMMAND_INTERRUPT_DISABLE,
+        );
+
+        unsafe {
+            Ok(VirtioDevice {
+                bus,
+                dev,
+                function: func,
+                device_type,
+                irq,
+                common: Mmio::new(Self::capability_address(pci_device, common_cap)?),
+                isr: Mmio::new(Self::capability_address(pci_device, isr_cap)?),
+                notify_base: Self::capability_address(pci_device, notify_cap)?,
+                notify_off_multiplier,
+                device_cfg,
+                features: 0,
+            })
+        }
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.common.device_status.read())
+    }
+
+    fn add_status(&self, status: DeviceStatus) {
+        let new_status = self.status() | status;
+        self.common.device_status.write(new_status.bits());
+    }
+
+    /// Resets the device, every queue is disabled and the features are cleared
+    pub fn reset(&mut self) {
+        self.common.device_status.write(0);
+        // the reset is complete once the status reads back as 0
+        while self.common.device_status.read() != 0 {
+            core::hint::spin_loop();
+        }
+        self.features = 0;
+    }
+
+    /// Marks the device as failed, the driver gave up on it
+    pub fn fail(&self) {
+        self.add_status(DeviceStatus::FAILED);
+    }
+
+    pub fn device_features(&self) -> u64 {
+        self.common.device_feature_select.write(0);
+        let low = self.common.device_feature.read() as u64;
+        self.common.device_feature_select.write(1);
+        let high = self.common.device_feature.read() as u64;
+        high << 32 | low
+    }
+
+    fn write_driver_features(&self, features: u64) {
+        self.common.driver_feature_select.write(0);
+        self.common.driver_feature.write(features as u32);
+        self.common.driver_feature_select.write(1);
+        self.common.driver_feature.write((features >> 32) as u32);
+    }
+
+    /// Resets the device and negotiates the intersection of the features the driver
+    /// supports and the device offers, the transport features are added automatically.
+    /// The queues can be set up after this returns, the negotiated features are returned.
+    pub fn negotiate_features(&mut self, driver_features: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.add_status(DeviceStatus::ACKNOWLEDGE);
+        self.add_status(DeviceStatus::DRIVER);
+
+        let device_features = self.device_features();
+        if device_features & VIRTIO_F_VERSION_1 == 0 {
+            // legacy only devices use a different register layout
+            self.fail();
+            return Err(VirtioError::FeaturesRejected);
+        }
+
+        let features = device_features & (driver_features | VIRTIO_TRANSPORT_FEATURES);
+        self.write_driver_features(features);
+
+        self.add_status(DeviceStatus::FEATURES_OK);
+        if !self.status().contains(DeviceStatus::FEATURES_OK) {
+            self.fail();
+            return Err(VirtioError::FeaturesRejected);
+        }
+
+        self.features = features;
+        Ok(features)
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn queue_count(&self) -> u16 {
+        self.common.num_queues.read()
+    }
+
+    /// Allocates a virtqueue and gives it to the device, the size is capped at the
+    /// maximum size of the queue the device supports
+    pub fn setup_queue(&self, index: u16, size: u16) -> Result<Virtqueue, VirtioError> {
+        if index >= self.queue_count() {
+            return Err(VirtioError::QueueUnavailable);
+        }
+
+        self.common.queue_select.write(index);
+        let max_size = self.common.queue_size.read();
+        if max_size == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+
+        let queue = Virtqueue::new(index, u16::min(size, max_size))?;
+
+        let split = |addr: PhysAddr| (addr.get() as u32, (addr.get() >> 32) as u32);
+        let (desc_lo, desc_hi) = split(queue.desc_table_phys());
+        let (driver_lo, driver_hi) = split(queue.avail_ring_phys());
+        let (device_lo, device_hi) = split(queue.used_ring_phys());
+
+        self.common.queue_size.write(queue.size());
+        self.common.queue_desc_lo.write(desc_lo);
+        self.common.queue_desc_hi.write(desc_hi);
+        self.common.queue_driver_lo.write(driver_lo);
+        self.common.queue_driver_hi.write(driver_hi);
+        self.common.queue_device_lo.write(device_lo);
+        self.common.queue_device_hi.write(device_hi);
+        self.common.queue_enable.write(1);
+
+        Ok(queue)
+    }
+
+    /// Tells the device that the queue has new buffers if the device wants to know
+    pub fn notify(&self, queue: &Virtqueue) {
+        if !queue.should_notify() {
+            return;
+        }
+
+        self.common.queue_select.write(queue.index());
+        let notify_off = self.common.queue_notify_off.read() as u64;
+        let addr = self.notify_base + VirtAddr::new(notify_off * self.notify_off_multiplier as u64);
+
+        let reg = unsafe { &*(addr.get() as *const VolatileCell<u16>) };
+        reg.write(queue.index());
+    }
+
+    /// Finishes the initialization, the device starts processing the queues after this
+    pub fn driver_ok(&self) {
+        self.add_status(DeviceStatus::DRIVER_OK);
+    }
+
+    /// Returns a reference to the device specific configuration structure
+    ///
+    /// # Safety
+    /// `T` must match the layout of the configuration structure of the device type
+    pub unsafe fn device_config<T>(&self) -> Option<Mmio<T>> {
+        self.device_cfg.map(|addr| Mmio::new(addr))
+    }
+
+    /// Calls the handler whenever the device raises an interrupt
+    pub fn register_interrupt_handler(
+        &self,
+        handler: VirtioInterruptHandler,
+    ) -> Result<(), VirtioError> {
+        let irq = self.irq.ok_or(VirtioError::NoInterruptLine)?;
+
+        let mut handlers = INTERRUPT_HANDLERS.lock();
+        // several devices can share a line, the stub only has to be installed once
+        if !handlers.iter().any(|h| h.irq == irq) {
+            let stub = unsafe { __virtio_irq_stubs[irq as usize] };
+            irq::install_handler(irq, stub);
+            irq::enable(irq);
+        }
+
+        handlers.push(InterruptHandler {
+            irq,
+            isr: unsafe { Mmio::new(self.isr.addr()) },
+            handler,
+        });
+
+        Ok(())
+    }
+}
+
+/// Makes the driver handle every device of the type, the devices that were
+/// discovered before the driver was registered are probed immediately
+pub fn register_driver(device_type: DeviceType, probe: VirtioProbe) {
+    DRIVERS.lock().push(VirtioDriver { device_type, probe });
+
+    let matching: Vec<VirtioDevice> = {
+        let mut devices = UNCLAIMED_DEVICES.lock();
+        let (matching, rest) = core::mem::take(&mut *devices)
+            .into_iter()
+            .partition(|device| device.device_type == device_type);
+        *devices = rest;
+        matching
+    };
+
+    for device in matching {
+        claim_device(device);
+    }
+}
+
+/// Gives the device to the driver of its type, it is kept unclaimed if there is none
+fn claim_device(device: VirtioDevice) {
+    let probes: Vec<VirtioProbe> = DRIVERS
+        .lock()
+        .iter()
+        .filter(|driver| driver.device_type == device.device_type)
+        .map(|driver| driver.probe)
+        .collect();
+
+    let location = (device.bus, device.dev, device.function);
+    let device_type = device.device_type;
+
+    // the device is moved into the probe function so only the first driver can get it
+    if let Some(probe) = probes.first() {
+        if !probe(device) && cfg!(virtio_debug) {
+            log!(
+                "VIRTIO: driver rejected {:?} device at {:#x}:{:#x}.{}",
+                device_type,
+                location.0,
+                location.1,
+                location.2
+            );
+        }
+        return;
+    }
+
+    if cfg!(virtio_debug) {
+        log!(
+            "VIRTIO: no driver for {:?} device at {:#x}:{:#x}.{}",
+            device_type,
+            location.0,
+            location.1,
+            location.2
+        );
+    }
+    UNCLAIMED_DEVICES.lock().push(device);
+}
+
+fn discover_devices(devices: Vec<&PCIDevice>) {
+    for pci_device in devices {
+        if pci_device.header_type != 0
+            || !(VIRTIO_TRANSITIONAL_DEVICE_ID_BASE..=VIRTIO_DEVICE_ID_LAST)
+                .contains(&pci_device.device_id)
+        {
+            continue;
+        }
+
+        match VirtioDevice::from_pci(pci_device) {
+            Ok(device) => {
+                if cfg!(virtio_debug) {
+                    log!(
+                        "VIRTIO: found {:?} device at {:#x}:{:#x}.{}",
+                        device.device_type,
+                        device.bus,
+                        device.dev,
+                        device.function
+                    );
+                }
+                claim_device(device)
+            }
+            Err(err) => warn!(
+                "VIRTIO: failed to initialize device at {:#x}:{:#x}.{}: {:?}",
+                pci_device.bus, pci_device.dev, pci_device.function, err
+            ),
+        }
+    }
+}
+
+pub fn init() -> bool {
+    pci::match_vendor_devices(VIRTIO_VENDOR_ID, discover_devices);
+    true
+}
+
+#[no_mangle]
+extern "C" fn virtio_interrupt(irq: u8) {
+    let _latency = latency::irq_timer(irq::vector(irq));
+    {
+        let handlers = INTERRUPT_HANDLERS.lock();
+        for handler in handlers.iter().filter(|h| h.irq == irq) {
+            // reading the ISR status acknowledges the interrupt of the device
+            let status = IsrStatus::from_bits_truncate(handler.isr.read());
+            if !status.is_empty() {
+                (handler.handler)(status);
+            }
+        }
+    }
+
+    irq::eoi(irq);
+}
@@ -0,0 +1,289 @@
+use core::mem::size_of;
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::x86_64::{mfence, sfence},
+    mm::{
+        phys::{FRAME_SIZE, PHYS_ALLOCATOR},
+        PhysAddr, VirtAddr,
+    },
+    mmio::VolatileCell,
+};
+
+use super::VirtioError;
+
+/// The buffer continues in the descriptor in the next field
+const VIRTQ_DESC_F_NEXT: u16 = 1 << 0;
+/// The device writes the buffer instead of reading it
+const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
+
+/// The device does not need to be notified about new buffers
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1 << 0;
+
+/// Maximum size of a split virtqueue
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+#[repr(C)]
+#[derive(Debug)]
+struct Descriptor {
+    addr: VolatileCell<u64>,
+    len: VolatileCell<u32>,
+    flags: VolatileCell<u16>,
+    next: VolatileCell<u16>,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct UsedElement {
+    id: VolatileCell<u32>,
+    len: VolatileCell<u32>,
+}
+
+/// A buffer given to the device, it must be physically contiguous
+#[derive(Debug, Clone, Copy)]
+pub struct QueueBuffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// The device writes the buffer, otherwise it only reads it
+    pub device_writable: bool,
+}
+
+/// A chain of buffers the device has finished processing
+#[derive(Debug, Clone, Copy)]
+pub struct UsedChain {
+    /// Value that was passed to `Virtqueue::add` along with the chain
+    pub token: u64,
+    /// Number of bytes the device wrote into the device writable buffers
+    pub written: u32,
+}
+
+/// A split virtqueue, the descriptor table, the available ring and the used ring
+/// are allocated in one physically contiguous region
+#[derive(Debug)]
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+
+    phys_start: PhysAddr,
+    pages: usize,
+
+    desc_table: VirtAddr,
+    avail_ring: VirtAddr,
+    used_ring: VirtAddr,
+
+    /// First descriptor of the free list, the free descriptors are linked by their next field
+    free_head: u16,
+    free_count: u16,
+
+    /// Index of the next entry of the avail ring, the device only sees it after it is published
+    avail_idx: u16,
+    /// Index of the next used ring entry the driver has not processed yet
+    last_used_idx: u16,
+
+    /// Token and length of every chain in flight indexed by the head descriptor
+    chains: Vec<Option<(u64, u16)>>,
+}
+
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    const fn desc_table_size(size: u16) -> usize {
+        size_of::<Descriptor>() * size as usize
+    }
+
+    const fn avail_ring_size(size: u16) -> usize {
+        // flags, idx, ring[size], used_event
+        size_of::<u16>() * (3 + size as usize)
+    }
+
+    const fn used_ring_size(size: u16) -> usize {
+        // flags, idx, ring[size], avail_event
+        size_of::<u16>() * 3 + size_of::<UsedElement>() * size as usize
+    }
+
+    pub fn new(index: u16, size: u16) -> Result<Virtqueue, VirtioError> {
+        if size == 0 || size > MAX_QUEUE_SIZE || !size.is_power_of_two() {
+            return Err(VirtioError::InvalidQueueSize);
+        }
+
+        // the used ring has to be 4 byte aligned, the others are aligned by their position
+        let avail_off = Self::desc_table_size(size);
+        let used_off = (avail_off + Self::avail_ring_size(size)).next_multiple_of(4);
+        let total_size = used_off + Self::used_ring_size(size);
+        let pages = total_size.div_ceil(FRAME_SIZE);
+
+        let phys_start = PHYS_ALLOCATOR.lock().alloc_multiple(pages, FRAME_SIZE);
+        let virt_start = phys_start.virt_addr();
+
+        unsafe {
+            core::ptr::write_bytes(virt_start.get() as *mut u8, 0, pages * FRAME_SIZE);
+        }
+
+        let queue = Virtqueue {
+            index,
+            size,
+            phys_start,
+            pages,
+            desc_table: virt_start,
+            avail_ring: virt_start + VirtAddr::new(avail_off as u64),
+            used_ring: virt_start + VirtAddr::new(used_off as u64),
+            free_head: 0,
+            free_count: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+            chains: vec![None; size as usize],
+        };
+
+        for i in 0..size {
+            queue.descriptor(i).next.write((i + 1) % size);
+        }
+
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn desc_table_phys(&self) -> PhysAddr {
+        self.phys_start
+    }
+
+    pub fn avail_ring_phys(&self) -> PhysAddr {
+        self.phys_start + PhysAddr::new((self.avail_ring - self.desc_table).get())
+    }
+
+    pub fn used_ring_phys(&self) -> PhysAddr {
+        self.phys_start + PhysAddr::new((self.used_ring - self.desc_table).get())
+    }
+
+    fn descriptor(&self, idx: u16) -> &Descriptor {
+        assert!(idx < self.size);
+        unsafe { &*(self.desc_table.get() as *const Descriptor).add(idx as usize) }
+    }
+
+    fn avail_field(&self, idx: usize) -> &VolatileCell<u16> {
+        unsafe { &*(self.avail_ring.get() as *const VolatileCell<u16>).add(idx) }
+    }
+
+    fn used_flags(&self) -> u16 {
+        unsafe { (*(self.used_ring.get() as *const VolatileCell<u16>)).read() }
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe { (*(self.used_ring.get() as *const VolatileCell<u16>).add(1)).read() }
+    }
+
+    fn used_element(&self, idx: u16) -> &UsedElement {
+        let ring = self.used_ring.get() as usize + 2 * size_of::<u16>();
+        unsafe { &*(ring as *const UsedElement).add((idx % self.size) as usize) }
+    }
+
+    /// Returns the number of descriptors that are not in use
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    /// Puts a chain of buffers in the available ring, the device only processes it after
+    /// it is notified. The token is returned by `pop_used` once the device is done.
+    pub fn add(&mut self, buffers: &[QueueBuffer], token: u64) -> Result<u16, VirtioError> {
+        if buffers.is_empty() {
+            return Err(VirtioError::EmptyChain);
+        }
+
+        if buffers.len() > self.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut idx = head;
+        for (i, buff) in buffers.iter().enumerate() {
+            let desc = self.descriptor(idx);
+            desc.addr.write(buff.addr.get());
+            desc.len.write(buff.len);
+
+            let mut flags = 0;
+            if buff.device_writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if i != buffers.len() - 1 {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            desc.flags.write(flags);
+
+            // the next field of a free descriptor already points to the next free one
+            idx = desc.next.read();
+        }
+
+        self.free_head = idx;
+        self.free_count -= buffers.len() as u16;
+        self.chains[head as usize] = Some((token, buffers.len() as u16));
+
+        // ring[avail_idx % size] is after the flags and idx fields
+        let ring_idx = 2 + (self.avail_idx % self.size) as usize;
+        self.avail_field(ring_idx).write(head);
+
+        // the descriptors and the ring entry must be visible before the index is updated
+        sfence();
+
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.avail_field(1).write(self.avail_idx);
+
+        Ok(head)
+    }
+
+    /// Returns whether the device asked to be notified about new buffers
+    pub fn should_notify(&self) -> bool {
+        // the new avail index must be visible before the flags are checked
+        mfence();
+        self.used_flags() & VIRTQ_USED_F_NO_NOTIFY == 0
+    }
+
+    /// Returns whether the device has finished chains that were not popped yet
+    pub fn has_used(&self) -> bool {
+        self.used_idx() != self.last_used_idx
+    }
+
+    /// Takes the next chain the device has finished and frees its descriptors
+    pub fn pop_used(&mut self) -> Option<UsedChain> {
+        if !self.has_used() {
+            return None;
+        }
+
+        // the used element must not be read before the index
+        mfence();
+
+        let elem = self.used_element(self.last_used_idx);
+        let head = elem.id.read() as u16;
+        let written = elem.len.read();
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        let (token, len) = self.chains[head as usize]
+            .take()
+            .expect("Device returned a descriptor that is not in use");
+
+        // link the chain back into the free list
+        let mut tail = head;
+        for _ in 1..len {
+            tail = self.descriptor(tail).next.read();
+        }
+        self.descriptor(tail).next.write(self.free_head);
+        self.free_head = head;
+        self.free_count += len;
+
+        Some(UsedChain { token, written })
+    }
+}
+
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        PHYS_ALLOCATOR
+            .lock()
+            .free_multiple(self.phys_start, self.pages);
+    }
+}
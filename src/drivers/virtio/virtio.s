@@ -0,0 +1,62 @@
+bits 64
+
+extern virtio_interrupt
+
+; every legacy IRQ line gets a stub that passes the line number to virtio_interrupt
+%macro VIRTIO_IRQ_STUB 1
+global __virtio_irq%1:function (__virtio_irq%1.end - __virtio_irq%1)
+__virtio_irq%1:
+    push rbp
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    mov rdi, %1
+    call virtio_interrupt
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rbp
+
+    iretq
+.end:
+%endmacro
+
+section .text
+%assign i 0
+%rep 16
+VIRTIO_IRQ_STUB i
+%assign i i+1
+%endrep
+
+section .data
+global __virtio_irq_stubs
+__virtio_irq_stubs:
+%assign i 0
+%rep 16
+    dq __virtio_irq%+i
+%assign i i+1
+%endrep
@@ -0,0 +1,75 @@
+//! Entropy of the kernel, e.g. for the stack protector canary. RDRAND is used when the CPU
+//! has it, the pool is also stirred with the time stamp counter on every request so the
+//! values differ between boots even without it.
+
+use core::{
+    arch::{asm, x86_64::__cpuid_count},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::arch::x86_64::rdtsc;
+
+// CPUID.(EAX=1):ECX
+const CPUID_RDRAND: u32 = 1 << 30;
+
+/// RDRAND can fail when the hardware runs out of entropy, it is retried this many times
+const RDRAND_RETRIES: usize = 10;
+
+static POOL: AtomicU64 = AtomicU64::new(0);
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+
+fn rdrand() -> Option<u64> {
+    if !HAS_RDRAND.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    for _ in 0..RDRAND_RETRIES {
+        let val: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) val, out(reg_byte) ok, options(nomem, nostack));
+        }
+
+        if ok != 0 {
+            return Some(val);
+        }
+    }
+
+    None
+}
+
+/// The finalizer of SplitMix64, every bit of the input affects every bit of the output
+fn mix(mut val: u64) -> u64 {
+    val = (val ^ (val >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    val = (val ^ (val >> 27)).wrapping_mul(0x94d049bb133111eb);
+    val ^ (val >> 31)
+}
+
+/// Seeds the pool, __boot_time__ is the UNIX time the bootloader reported
+pub fn init(boot_time: u64) {
+    HAS_RDRAND.store(
+        __cpuid_count(1, 0).ecx & CPUID_RDRAND != 0,
+        Ordering::Relaxed,
+    );
+
+    let seed = mix(boot_time ^ rdtsc()) ^ rdrand().unwrap_or(0);
+    POOL.store(seed, Ordering::Relaxed);
+}
+
+/// Returns 64 random bits, they are good enough for canaries and address randomization but
+/// not for cryptography without RDRAND
+pub fn random_u64() -> u64 {
+    // every caller gets a different state even if they race
+    let state = POOL
+        .fetch_add(0x9e3779b97f4a7c15 ^ rdtsc(), Ordering::Relaxed)
+        .wrapping_add(0x9e3779b97f4a7c15);
+    mix(state) ^ rdrand().unwrap_or(0)
+}
+
+/// Fills __buff__ with random bytes like getentropy does
+pub fn get_entropy(buff: &mut [u8]) {
+    for chunk in buff.chunks_mut(8) {
+        let bytes = random_u64().to_ne_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
@@ -1,10 +1,39 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use alloc::{collections::BTreeMap, slice};
 use spin::Mutex;
 
-use crate::mm::VirtAddr;
+use crate::{
+    cmdline,
+    mm::{PhysAddr, VirtAddr},
+};
+
+use self::font::{GlyphCache, MAX_FONT_WIDTH};
 
+pub mod devfs;
 mod font;
 
+/// A 24 bit RGB color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    pub const fn new(red: u8, green: u8, blue: u8) -> Color {
+        Color { red, green, blue }
+    }
+}
+
+/// Colors of the text of the kernel console
+pub const DEFAULT_FOREGROUND: Color = Color::new(0xcf, 0xcf, 0xcf);
+pub const DEFAULT_BACKGROUND: Color = Color::new(0, 0, 0);
+
 #[derive(Debug, PartialEq)]
 pub enum FramebufferMode {
     Text,
@@ -17,6 +46,9 @@ pub struct Framebuffer {
     /// Virtual address of the video memory
     buffer: VirtAddr,
 
+    /// Physical address of the video memory
+    buffer_phys: PhysAddr,
+
     /// Current mode of the framebuffer
     mode: FramebufferMode,
 
@@ -58,6 +90,9 @@ pub struct Framebuffer {
 
     /// Unicode code-point to glyph translation table
     unicode_glyph_table: Option<BTreeMap<char, usize>>,
+
+    /// Glyphs prerendered in the colors of the kernel console
+    glyph_cache: Option<GlyphCache>,
 }
 
 unsafe impl Send for Framebuffer {}
@@ -66,6 +101,7 @@ impl Framebuffer {
     const fn new() -> Self {
         Framebuffer {
             buffer: VirtAddr::zero(),
+            buffer_phys: PhysAddr::zero(),
             mode: FramebufferMode::Graphics,
             width: 0,
             height: 0,
@@ -80,6 +116,7 @@ impl Framebuffer {
             text_columns: 0,
             text_rows: 0,
             unicode_glyph_table: None,
+            glyph_cache: None,
         }
     }
 
@@ -99,7 +136,45 @@ impl Framebuffer {
         buff[y_off + x_off] = blue;
     }
 
-    fn draw_glyph(&self, glyph_idx: usize, x: usize, y: usize, clear_background: bool) {
+    /// Copies the pixels of a glyph row into video memory starting at __x__ and __y__
+    fn draw_pixel_row(&self, x: usize, y: usize, pixels: &[u32]) {
+        let offset = y * self.pitch + x * (self.bits_per_pixel / 8);
+        assert!(offset + pixels.len() * 4 <= self.size());
+
+        unsafe {
+            let dst = (self.buffer.get() as *mut u8).add(offset);
+            core::ptr::copy_nonoverlapping(pixels.as_ptr() as *const u8, dst, pixels.len() * 4);
+        }
+    }
+
+    /// Draws a glyph with its top left corner at __x__ and __y__, the background pixels are
+    /// only drawn if __bg__ is given
+    fn draw_glyph(&self, glyph_idx: usize, x: usize, y: usize, fg: Color, bg: Option<Color>) {
+        let bg = match bg {
+            Some(bg) => bg,
+            None => {
+                self.draw_glyph_foreground(glyph_idx, x, y, fg);
+                return;
+            }
+        };
+
+        // glyphs in other colors are rendered row by row so they are still written a row at once
+        let mut row_pixels = [0; MAX_FONT_WIDTH];
+        for row in 0..self.font_height {
+            let pixels = match self.cached_glyph_row(glyph_idx, row, fg, bg) {
+                Some(pixels) => pixels,
+                None => {
+                    let pixels = &mut row_pixels[..self.font_width];
+                    self.render_glyph_row(glyph_idx, row, fg, bg, pixels);
+                    pixels
+                }
+            };
+            self.draw_pixel_row(x, y + row, pixels);
+        }
+    }
+
+    /// Draws only the foreground pixels of a glyph, the background shows through
+    fn draw_glyph_foreground(&self, glyph_idx: usize, x: usize, y: usize, fg: Color) {
         let bitmap = self.get_glyph_bitmap(glyph_idx);
 
         let mut yy = y;
@@ -111,15 +186,13 @@ impl Framebuffer {
             let row = &bitmap[row_offset..row_offset_end];
 
             for (col_byte, byte) in row.iter().enumerate().take(self.font_pixel_row_size) {
-                let remaining_bits = self.font_height - col_byte * 8;
+                let remaining_bits = self.font_width - col_byte * 8;
                 let cols = usize::min(8, remaining_bits);
 
                 for col in 0..cols {
                     let mask = 1 << (7 - col);
                     if byte & mask > 0 {
-                        self.draw_pixel(xx, yy, 0xcf, 0xcf, 0xcf);
-                    } else if clear_background {
-                        self.draw_pixel(xx, yy, 0, 0, 0);
+                        self.draw_pixel(xx, yy, fg.red, fg.green, fg.blue);
                     }
                     xx += 1;
                 }
@@ -129,7 +202,7 @@ impl Framebuffer {
         }
     }
 
-    fn draw_character(&self, c: char, col: usize, row: usize, clear_background: bool) {
+    fn draw_character(&self, c: char, col: usize, row: usize, fg: Color, bg: Option<Color>) {
         let x = col * self.font_width;
         let y = row * self.font_height;
         let glyph = match &self.unicode_glyph_table {
@@ -143,14 +216,73 @@ impl Framebuffer {
                 }
             }
         };
-        self.draw_glyph(glyph, x, y, clear_background);
+        self.draw_glyph(glyph, x, y, fg, bg);
+    }
+
+    /// Fills the pixel rows from __y__ to __y__ + __height__ with __color__ between __x__ and
+    /// __x__ + __width__
+    fn fill_rect(&self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        for yy in y..y + height {
+            for xx in x..x + width {
+                self.draw_pixel(xx, yy, color.red, color.green, color.blue);
+            }
+        }
+    }
+
+    /// Moves the text rows up by __count__ rows, the rows at the bottom are filled with __bg__
+    fn scroll_text(&self, count: usize, bg: Color) {
+        let count = usize::min(count, self.text_rows);
+        let text_height = self.text_rows * self.font_height;
+        let moved_height = text_height - count * self.font_height;
+
+        // the rows overlap so they are moved like memmove does
+        let buff = self.buffer.get() as *mut u8;
+        unsafe {
+            core::ptr::copy(
+                buff.add(count * self.font_height * self.pitch),
+                buff,
+                moved_height * self.pitch,
+            );
+        }
+
+        self.fill_rect(0, moved_height, self.width, text_height - moved_height, bg);
     }
 }
 
 static FRAMEBUFFER: Mutex<Framebuffer> = Mutex::new(Framebuffer::new());
 
+/// PID of the process that has exclusive access to the framebuffer, 0 if the kernel console owns it.
+/// It is kept outside of FRAMEBUFFER so it can be revoked without locking, e.g. while panicking
+static FRAMEBUFFER_OWNER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub enum FramebufferOwnershipError {
+    /// Another process already owns the framebuffer
+    AlreadyOwned,
+    /// The process does not own the framebuffer
+    NotOwner,
+}
+
+/// Geometry of the video memory
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    pub phys: PhysAddr,
+    pub width: usize,
+    pub height: usize,
+    pub pitch: usize,
+    pub bits_per_pixel: usize,
+}
+
+impl FramebufferInfo {
+    /// Size of the video memory in bytes
+    pub fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+}
+
 pub fn init(
     buff_addr: VirtAddr,
+    buff_phys: PhysAddr,
     pixel_width: usize,
     pixel_height: usize,
     pitch: usize,
@@ -160,15 +292,36 @@ pub fn init(
 
     let mut fb = FRAMEBUFFER.lock();
     fb.buffer = buff_addr;
+    fb.buffer_phys = buff_phys;
     fb.width = pixel_width;
     fb.pitch = pitch;
     fb.height = pixel_height;
     fb.bits_per_pixel = bits_per_pixel;
 }
 
+/// Returns whether the framebuffer was set up, it was if somebody holds its lock
+pub fn is_initialized() -> bool {
+    match FRAMEBUFFER.try_lock() {
+        Some(fb) => fb.buffer != VirtAddr::zero(),
+        None => true,
+    }
+}
+
+/// Loads the console font, the glyphs are prerendered in the console colors unless
+/// fb_glyph_cache=off is given
 pub fn init_font() {
     let mut fb = FRAMEBUFFER.lock();
     fb.init_font();
+
+    if cmdline::get("fb_glyph_cache").is_some_and(|val| val == "off") {
+        return;
+    }
+
+    fb.init_glyph_cache(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+    let size = fb.glyph_cache.as_ref().map_or(0, |cache| cache.size());
+    // logging draws on the console
+    drop(fb);
+    log!("framebuffer: glyph cache uses {} KiB", size / 1024);
 }
 
 pub fn draw_pixel(x: usize, y: usize, red: u8, green: u8, blue: u8) {
@@ -177,8 +330,194 @@ pub fn draw_pixel(x: usize, y: usize, red: u8, green: u8, blue: u8) {
     fb.draw_pixel(x, y, red, green, blue);
 }
 
-pub fn draw_character(ch: char, col: usize, row: usize, clear_background: bool) {
+/// Draws a character of the kernel console, does nothing while a process owns the framebuffer
+pub fn draw_character(ch: char, col: usize, row: usize, fg: Color, bg: Color) {
+    if owner().is_some() {
+        return;
+    }
+
+    let fb = FRAMEBUFFER.lock();
+    assert!(fb.mode == FramebufferMode::Graphics);
+    fb.draw_character(ch, col, row, fg, Some(bg));
+}
+
+/// Fills __count__ character cells of the kernel console starting at __col__ in __row__ with
+/// __bg__, does nothing while a process owns the framebuffer
+pub fn clear_characters(col: usize, row: usize, count: usize, bg: Color) {
+    if owner().is_some() {
+        return;
+    }
+
     let fb = FRAMEBUFFER.lock();
     assert!(fb.mode == FramebufferMode::Graphics);
-    fb.draw_character(ch, col, row, clear_background);
+    fb.fill_rect(
+        col * fb.font_width,
+        row * fb.font_height,
+        count * fb.font_width,
+        fb.font_height,
+        bg,
+    );
+}
+
+/// Scrolls the text of the kernel console up by __count__ rows, the new rows are filled with
+/// __bg__. Does nothing while a process owns the framebuffer.
+pub fn scroll_text(count: usize, bg: Color) {
+    if owner().is_some() {
+        return;
+    }
+
+    let fb = FRAMEBUFFER.lock();
+    assert!(fb.mode == FramebufferMode::Graphics);
+    fb.scroll_text(count, bg);
+}
+
+/// Returns the number of columns and rows of text that fit on the screen, zero before the
+/// font is loaded
+pub fn text_size() -> (usize, usize) {
+    let fb = FRAMEBUFFER.lock();
+    (fb.text_columns, fb.text_rows)
+}
+
+pub fn info() -> FramebufferInfo {
+    let fb = FRAMEBUFFER.lock();
+    FramebufferInfo {
+        phys: fb.buffer_phys,
+        width: fb.width,
+        height: fb.height,
+        pitch: fb.pitch,
+        bits_per_pixel: fb.bits_per_pixel,
+    }
+}
+
+/// Copies the video memory at __off__ into __buff__, returns the number of bytes copied
+pub fn read_video_memory(off: usize, buff: &mut [u8]) -> usize {
+    let fb = FRAMEBUFFER.lock();
+    let len = buff.len().min(fb.size().saturating_sub(off));
+    if len == 0 {
+        return 0;
+    }
+
+    let video = unsafe { slice::from_raw_parts(fb.buffer.get() as *const u8, fb.size()) };
+    buff[..len].copy_from_slice(&video[off..off + len]);
+    len
+}
+
+/// Copies __buff__ into the video memory at __off__, returns the number of bytes copied
+pub fn write_video_memory(off: usize, buff: &[u8]) -> usize {
+    let fb = FRAMEBUFFER.lock();
+    let len = buff.len().min(fb.size().saturating_sub(off));
+    if len == 0 {
+        return 0;
+    }
+
+    let video = unsafe { slice::from_raw_parts_mut(fb.buffer.get() as *mut u8, fb.size()) };
+    video[off..off + len].copy_from_slice(&buff[..len]);
+    len
+}
+
+/// Returns the PID of the process that owns the framebuffer
+pub fn owner() -> Option<usize> {
+    match FRAMEBUFFER_OWNER.load(Ordering::Acquire) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+/// Gives a process exclusive access to the framebuffer, the kernel console stops drawing
+/// until the ownership is released or revoked
+pub fn acquire_ownership(pid: usize) -> Result<(), FramebufferOwnershipError> {
+    assert!(pid != 0);
+    match FRAMEBUFFER_OWNER.compare_exchange(0, pid, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(()),
+        Err(owner) if owner == pid => Ok(()),
+        Err(_) => Err(FramebufferOwnershipError::AlreadyOwned),
+    }
+}
+
+/// Gives the framebuffer back to the kernel console
+pub fn release_ownership(pid: usize) -> Result<(), FramebufferOwnershipError> {
+    match FRAMEBUFFER_OWNER.compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(FramebufferOwnershipError::NotOwner),
+    }
+}
+
+/// Takes the framebuffer away from its owner regardless of which process it is,
+/// returns the PID of the previous owner
+pub fn revoke_ownership() -> Option<usize> {
+    match FRAMEBUFFER_OWNER.swap(0, Ordering::AcqRel) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+/// Minimal text renderer used while panicking, it draws straight into video memory so it does
+/// not depend on the state of the console or the terminal
+pub struct PanicWriter {
+    col: usize,
+    row: usize,
+}
+
+/// Takes the framebuffer away from everyone and clears it for the panic writer, returns None
+/// if the framebuffer is not initialized yet. If the panic happened before the console font
+/// was loaded the glyphs are drawn by their index, which is the same as the ASCII code.
+///
+/// # Safety
+/// Must only be called while panicking with interrupts disabled, the framebuffer lock is
+/// taken away from whoever held it
+pub unsafe fn panic_writer() -> Option<PanicWriter> {
+    FRAMEBUFFER.force_unlock();
+    revoke_ownership();
+
+    let mut fb = FRAMEBUFFER.lock();
+    if fb.buffer == VirtAddr::zero() {
+        return None;
+    }
+
+    if fb.text_columns == 0 {
+        fb.load_font_header();
+    }
+
+    if fb.text_columns == 0 || fb.text_rows == 0 {
+        return None;
+    }
+
+    fb.mode = FramebufferMode::Graphics;
+    core::ptr::write_bytes(fb.buffer.get() as *mut u8, 0, fb.size());
+
+    Some(PanicWriter { col: 0, row: 0 })
+}
+
+impl fmt::Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let fb = FRAMEBUFFER.lock();
+
+        for c in s.chars() {
+            if c == '\n' {
+                self.col = 0;
+                self.row += 1;
+            } else {
+                if self.col == fb.text_columns {
+                    self.col = 0;
+                    self.row += 1;
+                }
+
+                // there is no scrolling, the output continues at the top of the screen
+                if self.row == fb.text_rows {
+                    self.row = 0;
+                }
+
+                fb.draw_character(
+                    c,
+                    self.col,
+                    self.row,
+                    DEFAULT_FOREGROUND,
+                    Some(DEFAULT_BACKGROUND),
+                );
+                self.col += 1;
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,146 @@
+//! The framebuffer device /dev/fb0, userspace can query the geometry of the screen with the
+//! Linux fbdev ioctls and map the video memory to draw on it directly. The kernel console
+//! keeps drawing until the process switches its TTY to graphics mode with KDSETMODE.
+
+use alloc::sync::Arc;
+
+use crate::{
+    arch::x86_64::usercopy::{In, Out, UserPtr},
+    fs::{
+        devfs::{self, DevFsDevice},
+        errors::{FsIoctlError, FsMmapError, FsReadError, FsStatError, FsWriteError},
+        path::Path,
+    },
+    mm::{virt::PAGE_SIZE_4KIB, PhysAddr},
+    posix::{
+        fb::{
+            FbBitfield, FbFixScreeninfo, FbVarScreeninfo, FBIOGET_FSCREENINFO, FBIOGET_VSCREENINFO,
+            FBIOPUT_VSCREENINFO, FB_TYPE_PACKED_PIXELS, FB_VISUAL_TRUECOLOR,
+        },
+        Stat, S_IFCHR,
+    },
+};
+
+use super::FramebufferInfo;
+
+const FRAMEBUFFER_DEVICE_MAJOR: u16 = 29;
+
+struct FramebufferDevice;
+
+fn var_screeninfo(info: &FramebufferInfo) -> FbVarScreeninfo {
+    // the pixels are stored as blue, green, red and an unused byte
+    let channel = |offset| FbBitfield {
+        offset,
+        length: 8,
+        msb_right: 0,
+    };
+
+    FbVarScreeninfo {
+        xres: info.width as u32,
+        yres: info.height as u32,
+        xres_virtual: info.width as u32,
+        yres_virtual: info.height as u32,
+        bits_per_pixel: info.bits_per_pixel as u32,
+        red: channel(16),
+        green: channel(8),
+        blue: channel(0),
+        // the physical size of the screen is not known
+        height: u32::MAX,
+        width: u32::MAX,
+        ..Default::default()
+    }
+}
+
+fn fix_screeninfo(info: &FramebufferInfo) -> FbFixScreeninfo {
+    let mut id = [0; 16];
+    id[..4].copy_from_slice(b"rook");
+
+    FbFixScreeninfo {
+        id,
+        smem_start: info.phys.get(),
+        smem_len: info.size() as u32,
+        fb_type: FB_TYPE_PACKED_PIXELS,
+        visual: FB_VISUAL_TRUECOLOR,
+        line_length: info.pitch as u32,
+        ..Default::default()
+    }
+}
+
+impl DevFsDevice for FramebufferDevice {
+    fn read(&self, _minor: u16, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        Ok(super::read_video_memory(off, buff))
+    }
+
+    fn write(&self, _minor: u16, off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        match super::write_video_memory(off, buff) {
+            0 if !buff.is_empty() => Err(FsWriteError::NoSpace),
+            len => Ok(len),
+        }
+    }
+
+    fn ioctl(&self, _minor: u16, req: usize, arg: usize) -> Result<usize, FsIoctlError> {
+        let info = super::info();
+        match req {
+            FBIOGET_VSCREENINFO => {
+                let ptr = UserPtr::<FbVarScreeninfo, Out>::new(arg as u64);
+                ptr.write(&var_screeninfo(&info))
+                    .map_err(|_| FsIoctlError::BadAddress)?;
+            }
+            FBIOGET_FSCREENINFO => {
+                let ptr = UserPtr::<FbFixScreeninfo, Out>::new(arg as u64);
+                ptr.write(&fix_screeninfo(&info))
+                    .map_err(|_| FsIoctlError::BadAddress)?;
+            }
+            FBIOPUT_VSCREENINFO => {
+                // the mode set by the bootloader can not be changed, only a request for the
+                // current one succeeds
+                let ptr = UserPtr::<FbVarScreeninfo, In>::new(arg as u64);
+                let var = ptr.read().map_err(|_| FsIoctlError::BadAddress)?;
+                let current = var_screeninfo(&info);
+                if var.xres != current.xres
+                    || var.yres != current.yres
+                    || var.bits_per_pixel != current.bits_per_pixel
+                {
+                    return Err(FsIoctlError::InvalidArgument);
+                }
+            }
+            _ => return Err(FsIoctlError::InvalidArgument),
+        }
+
+        Ok(0)
+    }
+
+    fn stat(&self, minor: u16, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        let size = super::info().size();
+
+        stat_buf.st_blksize = PAGE_SIZE_4KIB;
+        stat_buf.st_blocks = 0;
+        stat_buf.st_size = size as u64;
+        stat_buf.st_dev = 0;
+        stat_buf.st_rdev = (FRAMEBUFFER_DEVICE_MAJOR as u64) << 8 | minor as u64;
+        stat_buf.st_gid = 0;
+        stat_buf.st_uid = 0;
+        stat_buf.st_nlink = 1;
+        stat_buf.st_mode = S_IFCHR | 0o660;
+
+        Ok(())
+    }
+
+    fn mmap(&self, _minor: u16, off: usize, len: usize) -> Result<PhysAddr, FsMmapError> {
+        let info = super::info();
+
+        // the last page is mapped whole even if the video memory ends before it
+        let size = info.size().next_multiple_of(PAGE_SIZE_4KIB as usize);
+        match off.checked_add(len) {
+            Some(end) if end <= size => Ok(info.phys + PhysAddr::new(off as u64)),
+            _ => Err(FsMmapError::InvalidRange),
+        }
+    }
+}
+
+/// Creates /dev/fb0
+pub fn init() {
+    devfs::register_devfs_node(Path::new("/fb0").unwrap(), FRAMEBUFFER_DEVICE_MAJOR, 0).unwrap();
+    devfs::register_devfs_node_operations(FRAMEBUFFER_DEVICE_MAJOR, Arc::new(FramebufferDevice))
+        .unwrap();
+}
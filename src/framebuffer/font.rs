@@ -1,7 +1,7 @@
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, vec, vec::Vec};
 use encode_unicode::Utf8Char;
 
-use super::Framebuffer;
+use super::{Color, Framebuffer};
 
 // https://wiki.osdev.org/PC_Screen_Font
 // https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html
@@ -23,6 +23,34 @@ const FONT_DATA: &[u8] = core::include_bytes!("../default8x16.psfu");
 
 const PSF_FLAGS_HAS_UNICODE_TABLE: u32 = 1 << 0;
 
+/// Glyphs wider than this are not supported, a pixel row of a glyph is rendered on the stack
+pub const MAX_FONT_WIDTH: usize = 32;
+
+/// Every glyph of the font rendered in one color pair, the pixels of a glyph are stored row by
+/// row so a row can be copied into video memory with a single copy
+#[derive(Debug)]
+pub struct GlyphCache {
+    fg: Color,
+    bg: Color,
+    pixels: Vec<u32>,
+}
+
+impl GlyphCache {
+    pub fn matches(&self, fg: Color, bg: Color) -> bool {
+        self.fg == fg && self.bg == bg
+    }
+
+    /// Size of the cache in bytes
+    pub fn size(&self) -> usize {
+        self.pixels.len() * core::mem::size_of::<u32>()
+    }
+}
+
+/// Returns the value of a 32 bit pixel with __color__
+pub fn pixel_value(color: Color) -> u32 {
+    (color.red as u32) << 16 | (color.green as u32) << 8 | color.blue as u32
+}
+
 impl Framebuffer {
     pub fn get_glyph_bitmap(&self, glyph_idx: usize) -> &'static [u8] {
         assert!(glyph_idx < self.font_glyph_count);
@@ -34,7 +62,63 @@ impl Framebuffer {
         &FONT_DATA[offset..end_offset]
     }
 
-    pub fn init_font(&mut self) {
+    /// Renders pixel __row__ of glyph __glyph_idx__ into __out__, it has to be as long as the
+    /// width of the font
+    pub fn render_glyph_row(
+        &self,
+        glyph_idx: usize,
+        row: usize,
+        fg: Color,
+        bg: Color,
+        out: &mut [u32],
+    ) {
+        let bitmap = self.get_glyph_bitmap(glyph_idx);
+        let row_offset = row * self.font_pixel_row_size;
+        let row = &bitmap[row_offset..row_offset + self.font_pixel_row_size];
+        let (fg, bg) = (pixel_value(fg), pixel_value(bg));
+
+        for (col, pixel) in out.iter_mut().enumerate() {
+            let mask = 1 << (7 - col % 8);
+            *pixel = if row[col / 8] & mask > 0 { fg } else { bg };
+        }
+    }
+
+    /// Renders every glyph in __fg__ on __bg__ so drawing them is only copying pixel rows
+    pub fn init_glyph_cache(&mut self, fg: Color, bg: Color) {
+        let glyph_pixels = self.font_width * self.font_height;
+        let mut pixels = vec![0; self.font_glyph_count * glyph_pixels];
+
+        for (glyph_idx, glyph) in pixels.chunks_exact_mut(glyph_pixels).enumerate() {
+            for (row, out) in glyph.chunks_exact_mut(self.font_width).enumerate() {
+                self.render_glyph_row(glyph_idx, row, fg, bg, out);
+            }
+        }
+
+        self.glyph_cache = Some(GlyphCache { fg, bg, pixels });
+    }
+
+    /// Returns the pixel row __row__ of __glyph_idx__ from the glyph cache if it was rendered
+    /// in __fg__ on __bg__
+    pub fn cached_glyph_row(
+        &self,
+        glyph_idx: usize,
+        row: usize,
+        fg: Color,
+        bg: Color,
+    ) -> Option<&[u32]> {
+        let cache = self.glyph_cache.as_ref()?;
+        if !cache.matches(fg, bg) {
+            return None;
+        }
+
+        let start = (glyph_idx * self.font_height + row) * self.font_width;
+        Some(&cache.pixels[start..start + self.font_width])
+    }
+
+    /// Reads the glyph geometry from the header of the console font, glyphs can be drawn by
+    /// their index from now on. Nothing is allocated so the panic handler can use it before the
+    /// heap exists. Returns whether the font has a unicode table.
+    pub fn load_font_header(&mut self) -> bool {
         let font_header = &(unsafe { FONT_DATA.align_to::<PSFHeader>().1 })[0];
 
         let magic = font_header.magic;
@@ -46,12 +130,19 @@ impl Framebuffer {
         self.font_glyph_size = font_header.glyph_size as usize;
         self.font_glyph_table_start_offset = font_header.header_size as usize;
         self.font_pixel_row_size = self.font_width.div_ceil(8);
+        assert!(
+            self.font_width <= MAX_FONT_WIDTH,
+            "Console font is too wide"
+        );
 
         self.text_columns = self.width / self.font_width;
         self.text_rows = self.height / self.font_height;
 
-        let has_unicode_table = font_header.flags & PSF_FLAGS_HAS_UNICODE_TABLE > 0;
-        if !has_unicode_table {
+        font_header.flags & PSF_FLAGS_HAS_UNICODE_TABLE > 0
+    }
+
+    pub fn init_font(&mut self) {
+        if !self.load_font_header() {
             return;
         }
 
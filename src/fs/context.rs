@@ -0,0 +1,54 @@
+//! The credentials the VFS acts with on behalf of the current thread. The syscall entry stores
+//! them in the thread-local storage of the thread so the file systems can use them without
+//! the process being passed down to them. Kernel threads act as root.
+
+use alloc::sync::Arc;
+
+use crate::scheduler::{proc::Process, tls::ThreadLocal};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsContext {
+    pub pid: usize,
+    pub uid: usize,
+    pub euid: usize,
+    pub gid: usize,
+    pub egid: usize,
+}
+
+static FS_CONTEXT: ThreadLocal<FsContext> = ThreadLocal::new("fs context");
+
+const KERNEL_CONTEXT: FsContext = FsContext {
+    pid: 0,
+    uid: 0,
+    euid: 0,
+    gid: 0,
+    egid: 0,
+};
+
+impl FsContext {
+    fn of_process(proc: &Process) -> FsContext {
+        FsContext {
+            pid: proc.pid,
+            uid: proc.uid,
+            euid: proc.euid,
+            gid: proc.gid,
+            egid: proc.egid,
+        }
+    }
+}
+
+/// Makes the current thread act with the credentials of __proc__, the stored context is only
+/// replaced if they changed since the last syscall
+pub fn enter(proc: &Process) {
+    let context = FsContext::of_process(proc);
+    if FS_CONTEXT.get().is_some_and(|current| *current == context) {
+        return;
+    }
+
+    FS_CONTEXT.set(Some(context));
+}
+
+/// Returns the context of the current thread
+pub fn current() -> Arc<FsContext> {
+    FS_CONTEXT.get().unwrap_or_else(|| Arc::new(KERNEL_CONTEXT))
+}
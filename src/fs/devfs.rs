@@ -7,11 +7,18 @@ use alloc::{
 use hashbrown::HashMap;
 use spin::{Lazy, Mutex};
 
-use crate::posix::Stat;
+use crate::{
+    mm::PhysAddr,
+    posix::{MountFlags, PollEvents, Stat, S_IFDIR},
+    scheduler::wait_queue::Waiter,
+};
 
 use super::{
-    inode::FSInode, path::Path, FileSystem, FileSystemInner, FsCloseError, FsIoctlError,
-    FsOpenError, FsPathError, FsReadError, FsStatError, FsWriteError, VFS,
+    errors::{FsMmapError, FsReadDirError},
+    inode::FSInode,
+    path::Path,
+    DirEntry, FileSystem, FileSystemInner, FsCloseError, FsCreateError, FsIoctlError, FsOpenError,
+    FsPathError, FsReadError, FsRemoveError, FsRenameError, FsStatError, FsWriteError, VFS,
 };
 
 pub trait DevFsDevice {
@@ -22,17 +29,36 @@ pub trait DevFsDevice {
     fn ioctl(&self, minor: u16, req: usize, arg: usize) -> Result<usize, FsIoctlError>;
 
     fn stat(&self, minor: u16, stat_buf: &mut Stat) -> Result<(), FsStatError>;
+
+    /// Returns the events the device is ready for at the offset __off__, if __waiter__ is
+    /// given it is woken once that changes
+    fn poll(&self, _minor: u16, _off: usize, _waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        PollEvents::POLLIN | PollEvents::POLLOUT
+    }
+
+    /// Returns the offset the next read from __off__ continues at, see
+    /// `FileSystemInner::resync_offset`
+    fn resync_offset(&self, _minor: u16, off: usize) -> usize {
+        off
+    }
+
+    /// Returns the physical address of the device memory at __off__, the next __len__ bytes
+    /// have to be contiguous
+    fn mmap(&self, _minor: u16, _off: usize, _len: usize) -> Result<PhysAddr, FsMmapError> {
+        Err(FsMmapError::NotSupported)
+    }
 }
 
 #[derive(Debug)]
 enum DeviceFileTreeNode {
-    Directory(Vec<(String, DeviceFileTreeNode)>),
+    Directory(FSInode, Vec<(String, DeviceFileTreeNode)>),
     File(FSInode),
 }
 
 struct DeviceFileSystemInner {
     pub root_node: DeviceFileTreeNode,
     pub major_operations: HashMap<u16, Arc<dyn DevFsDevice>>,
+    next_directory_index: u64,
 }
 
 unsafe impl Send for DeviceFileSystemInner {}
@@ -54,8 +80,9 @@ struct DeviceFileSystem {}
 impl DeviceFileSystemInner {
     fn new() -> DeviceFileSystemInner {
         DeviceFileSystemInner {
-            root_node: DeviceFileTreeNode::Directory(Vec::new()),
+            root_node: DeviceFileTreeNode::Directory(directory_inode(0), Vec::new()),
             major_operations: HashMap::new(),
+            next_directory_index: 1,
         }
     }
 }
@@ -67,8 +94,7 @@ impl FileSystemInner for DeviceFileSystem {
         let node = inner.get_node(path).map_err(FsOpenError::BadPath)?;
 
         match node {
-            DeviceFileTreeNode::Directory(_) => panic!("not implemented"),
-            DeviceFileTreeNode::File(inode) => Ok(*inode),
+            DeviceFileTreeNode::Directory(inode, _) | DeviceFileTreeNode::File(inode) => Ok(*inode),
         }
     }
 
@@ -78,43 +104,147 @@ impl FileSystemInner for DeviceFileSystem {
     }
 
     fn stat(&mut self, inode: FSInode, stat_buf: &mut Stat) -> Result<(), FsStatError> {
-        let mut inner = DEVFS_INNER.lock();
+        if is_directory_inode(inode) {
+            stat_buf.st_ino = inode.0;
+            stat_buf.st_blksize = 4096;
+            stat_buf.st_blocks = 0;
+            stat_buf.st_size = 0;
+            stat_buf.st_dev = 0;
+            stat_buf.st_rdev = 0;
+            stat_buf.st_gid = 0;
+            stat_buf.st_uid = 0;
+            stat_buf.st_nlink = 1;
+            stat_buf.st_mode = S_IFDIR | 0o755;
+            return Ok(());
+        }
 
         let (major, minor) = inode_to_dev_number(inode);
-        let ops = inner.major_operations.get_mut(&major).unwrap();
+        let ops = device_operations(major);
 
         ops.stat(minor, stat_buf)
     }
 
     fn read(&mut self, inode: FSInode, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
-        // TODO: check if inode is valid
-        let mut inner = DEVFS_INNER.lock();
-
+        if is_directory_inode(inode) {
+            return Err(FsReadError::BadFileDescriptor);
+        }
         let (major, minor) = inode_to_dev_number(inode);
-        let ops = inner.major_operations.get_mut(&major).unwrap();
+        let ops = device_operations(major);
 
         ops.read(minor, off, buff)
     }
 
     fn write(&mut self, inode: FSInode, off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
-        // TODO: check if inode is valid
-        let mut inner = DEVFS_INNER.lock();
-
+        if is_directory_inode(inode) {
+            return Err(FsWriteError::BadFileDescriptor);
+        }
         let (major, minor) = inode_to_dev_number(inode);
-        let ops = inner.major_operations.get_mut(&major).unwrap();
+        let ops = device_operations(major);
 
         ops.write(minor, off, buff)
     }
 
     fn ioctl(&mut self, inode: FSInode, req: usize, arg: usize) -> Result<usize, FsIoctlError> {
-        // TODO: check if inode is valid
-        let mut inner = DEVFS_INNER.lock();
-
+        if is_directory_inode(inode) {
+            return Err(FsIoctlError::NotATerminal);
+        }
         let (major, minor) = inode_to_dev_number(inode);
-        let ops = inner.major_operations.get_mut(&major).unwrap();
+        let ops = device_operations(major);
 
         ops.ioctl(minor, req, arg)
     }
+
+    fn poll(&mut self, inode: FSInode, off: usize, waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        if is_directory_inode(inode) {
+            return PollEvents::POLLIN | PollEvents::POLLOUT;
+        }
+
+        let (major, minor) = inode_to_dev_number(inode);
+        let ops = device_operations(major);
+
+        ops.poll(minor, off, waiter)
+    }
+
+    fn resync_offset(&mut self, inode: FSInode, off: usize) -> usize {
+        if is_directory_inode(inode) {
+            return off;
+        }
+
+        let (major, minor) = inode_to_dev_number(inode);
+        let ops = device_operations(major);
+
+        ops.resync_offset(minor, off)
+    }
+
+    fn mmap(&mut self, inode: FSInode, off: usize, len: usize) -> Result<PhysAddr, FsMmapError> {
+        if is_directory_inode(inode) {
+            return Err(FsMmapError::NotSupported);
+        }
+
+        let (major, minor) = inode_to_dev_number(inode);
+        let ops = device_operations(major);
+
+        ops.mmap(minor, off, len)
+    }
+
+    fn read_dir(&mut self, path: Path) -> Result<Vec<DirEntry>, FsReadDirError> {
+        let mut inner = DEVFS_INNER.lock();
+
+        let node = inner.get_node(path).map_err(FsReadDirError::BadPath)?;
+
+        match node {
+            DeviceFileTreeNode::Directory(_, entries) => Ok(entries
+                .iter()
+                .map(|(name, ent)| match ent {
+                    DeviceFileTreeNode::Directory(inode, _) | DeviceFileTreeNode::File(inode) => {
+                        DirEntry {
+                            name: name.clone(),
+                            inode: *inode,
+                        }
+                    }
+                })
+                .collect()),
+            DeviceFileTreeNode::File(_) => Err(FsReadDirError::BadPath(FsPathError::NotADirectory)),
+        }
+    }
+
+    // device nodes are managed by the drivers
+    fn remove(&mut self, _path: Path) -> Result<(), FsRemoveError> {
+        Err(FsRemoveError::Busy)
+    }
+
+    fn rename(&mut self, _old_path: Path, _new_path: Path) -> Result<(), FsRenameError> {
+        Err(FsRenameError::Busy)
+    }
+
+    fn create(&mut self, _path: Path) -> Result<FSInode, FsCreateError> {
+        Err(FsCreateError::BadPath(FsPathError::PermissionDenied))
+    }
+
+    fn mkdir(&mut self, _path: Path) -> Result<(), FsCreateError> {
+        Err(FsCreateError::BadPath(FsPathError::PermissionDenied))
+    }
+
+    // devices don't store anything
+    fn truncate(&mut self, _inode: FSInode) -> Result<(), FsWriteError> {
+        Ok(())
+    }
+
+    // devices can be registered at any time
+    fn cache_negative_entries(&self) -> bool {
+        false
+    }
+}
+
+/// Returns the operations of __major__, the devfs is not kept locked while they run because
+/// reads can block
+fn device_operations(major: u16) -> Arc<dyn DevFsDevice> {
+    DEVFS_INNER
+        .lock()
+        .major_operations
+        .get(&major)
+        .unwrap()
+        .clone()
 }
 
 impl DeviceFileSystemInner {
@@ -135,7 +265,7 @@ impl DeviceFileSystemInner {
             let comp = path.next().unwrap();
             match node {
                 DeviceFileTreeNode::File(_) => return Err(FsPathError::NotADirectory),
-                DeviceFileTreeNode::Directory(ref mut entries) => {
+                DeviceFileTreeNode::Directory(_, ref mut entries) => {
                     let new_node = entries.iter_mut().find(|ent| ent.0 == comp);
                     match new_node {
                         Some(n) => node = &mut n.1,
@@ -147,7 +277,7 @@ impl DeviceFileSystemInner {
 
         let last_element = path.next().unwrap();
         match node {
-            DeviceFileTreeNode::Directory(entries) => {
+            DeviceFileTreeNode::Directory(_, entries) => {
                 let last_node = entries.iter_mut().find(|ent| ent.0 == *last_element);
                 match last_node {
                     Some(n) => Ok(&mut n.1),
@@ -158,6 +288,64 @@ impl DeviceFileSystemInner {
             DeviceFileTreeNode::File(_) => unreachable!(),
         }
     }
+
+    /// Inserts a file node with __inode__ at the path made of __components__, the directories
+    /// in the path that do not exist yet are created
+    fn insert_node(&mut self, components: &[String], inode: FSInode) -> Result<(), DevFsError> {
+        let (last_element, dirs) = components.split_last().unwrap();
+        let mut next_directory_index = self.next_directory_index;
+        let mut node = &mut self.root_node;
+
+        for comp in dirs {
+            match node {
+                DeviceFileTreeNode::File(_) => {
+                    return Err(DevFsError::BadPath(FsPathError::NotADirectory))
+                }
+                DeviceFileTreeNode::Directory(_, ref mut entries) => {
+                    let pos = match entries.iter().position(|ent| ent.0 == *comp) {
+                        Some(pos) => pos,
+                        None => {
+                            let dir_inode = directory_inode(next_directory_index);
+                            next_directory_index += 1;
+                            entries.push((
+                                comp.clone(),
+                                DeviceFileTreeNode::Directory(dir_inode, Vec::new()),
+                            ));
+                            entries.len() - 1
+                        }
+                    };
+                    node = &mut entries[pos].1;
+                }
+            }
+        }
+
+        let res = match node {
+            DeviceFileTreeNode::Directory(_, entries) => {
+                if entries.iter().any(|ent| ent.0 == *last_element) {
+                    Err(DevFsError::AlreadyExists)
+                } else {
+                    entries.push((last_element.clone(), DeviceFileTreeNode::File(inode)));
+                    Ok(())
+                }
+            }
+            DeviceFileTreeNode::File(_) => Err(DevFsError::BadPath(FsPathError::NotADirectory)),
+        };
+
+        // the directories created on the way stay even if the node could not be inserted
+        self.next_directory_index = next_directory_index;
+        res
+    }
+}
+
+// device inodes only use the low 28 bits
+const DIRECTORY_INODE_BIT: u64 = 1 << 32;
+
+fn directory_inode(index: u64) -> FSInode {
+    FSInode::new(DIRECTORY_INODE_BIT | index)
+}
+
+fn is_directory_inode(inode: FSInode) -> bool {
+    inode.0 & DIRECTORY_INODE_BIT != 0
 }
 
 fn dev_number_to_inode(major: u16, minor: u16) -> FSInode {
@@ -170,45 +358,38 @@ fn inode_to_dev_number(inode: FSInode) -> (u16, u16) {
     (major as u16, minor as u16)
 }
 
-pub fn register_devfs_node(mut path: Path, major: u16, minor: u16) -> Result<(), DevFsError> {
-    let inode = dev_number_to_inode(major, minor);
+/// A node registered before /dev was mounted
+struct PendingDevfsNode {
+    components: Vec<String>,
+    inode: FSInode,
+}
 
-    let mut inner = DEVFS_INNER.lock();
-    let mut node = &mut inner.root_node;
+// registrations made before /dev is mounted, None once it is mounted and they were applied
+static PENDING_NODES: Mutex<Option<Vec<PendingDevfsNode>>> = Mutex::new(Some(Vec::new()));
 
-    if path.components_left() == 0 {
-        return Err(DevFsError::AlreadyExists);
-    }
+/// Creates a device node at __path__ and the directories leading to it. Before /dev is
+/// mounted the node is queued and created when it is, after that it shows up immediately.
+pub fn register_devfs_node(path: Path, major: u16, minor: u16) -> Result<(), DevFsError> {
+    let inode = dev_number_to_inode(major, minor);
+    let components: Vec<String> = path.map(|comp| comp.to_string()).collect();
 
-    while path.components_left() > 1 {
-        let comp = path.next().unwrap();
-        match node {
-            DeviceFileTreeNode::File(_) => {
-                return Err(DevFsError::BadPath(FsPathError::NotADirectory))
-            }
-            DeviceFileTreeNode::Directory(ref mut entries) => {
-                let new_node = entries.iter_mut().find(|ent| ent.0 == comp);
-                match new_node {
-                    Some(n) => node = &mut n.1,
-                    None => return Err(DevFsError::BadPath(FsPathError::NoSuchFileOrDirectory)),
-                }
-            }
-        }
+    if components.is_empty() {
+        return Err(DevFsError::AlreadyExists);
     }
 
-    let last_element = path.next().unwrap();
-    match node {
-        DeviceFileTreeNode::Directory(entries) => {
-            let last_node = entries.iter_mut().find(|ent| ent.0 == *last_element);
-            match last_node {
-                Some(_) => return Err(DevFsError::AlreadyExists),
-                None => entries.push((last_element.to_string(), DeviceFileTreeNode::File(inode))),
+    let mut pending = PENDING_NODES.lock();
+    match pending.as_mut() {
+        Some(queue) => {
+            if queue.iter().any(|node| node.components == components) {
+                return Err(DevFsError::AlreadyExists);
             }
+            queue.push(PendingDevfsNode { components, inode });
+            Ok(())
         }
-        DeviceFileTreeNode::File(_) => return Err(DevFsError::BadPath(FsPathError::NotADirectory)),
+        // directories of the devfs are not cached negatively, so the VFS finds the new node
+        // on the next lookup
+        None => DEVFS_INNER.lock().insert_node(&components, inode),
     }
-
-    Ok(())
 }
 
 pub fn register_devfs_node_operations(
@@ -225,13 +406,31 @@ pub fn register_devfs_node_operations(
 }
 
 pub fn init() {
-    let mut vfs = VFS.write();
-    vfs.mount_special(
-        "/dev",
-        FileSystem {
-            name: "devfs",
-            inner: Box::new(DeviceFileSystem {}),
-        },
-    )
-    .unwrap();
+    {
+        let mut vfs = VFS.write();
+        vfs.mount_special(
+            "/dev",
+            FileSystem {
+                name: "devfs",
+                inner: Box::new(DeviceFileSystem {}),
+            },
+            MountFlags::empty(),
+        )
+        .unwrap();
+    }
+
+    // the queue is kept locked while it is replayed so a registration made meanwhile can not
+    // overtake the queued ones
+    let mut pending = PENDING_NODES.lock();
+    let queue = pending.take().unwrap();
+    let mut inner = DEVFS_INNER.lock();
+    for node in queue {
+        if let Err(err) = inner.insert_node(&node.components, node.inode) {
+            warn!(
+                "devfs: failed to create /dev/{}: {:?}",
+                node.components.join("/"),
+                err
+            );
+        }
+    }
 }
@@ -1,55 +1,188 @@
-use crate::posix::errno::{Errno, EACCES, ENOENT, ENOTDIR};
+use crate::posix::errno::{
+    Errno, EACCES, EAGAIN, EBADF, EBUSY, EEXIST, EFAULT, EINTR, EINVAL, EIO, EISDIR, ENODEV,
+    ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENOTTY, ENXIO, EPERM, EPIPE, EROFS, ESPIPE, EXDEV,
+};
 
 use super::path::PathParseError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum FsPathError {
     // TODO: normal path errors
     PermissionDenied,
     NoSuchFileOrDirectory,
     NotADirectory,
     ParseError(PathParseError),
+    /// The medium of the file system was removed or can not be read
+    NoDevice,
 }
 
 #[derive(Debug)]
-pub enum FsReadError {}
+pub enum FsReadError {
+    /// The file was not opened for reading
+    BadFileDescriptor,
+    /// There is nothing to read and the file was opened with O_NONBLOCK
+    WouldBlock,
+    /// The device failed to read the data
+    IoError,
+    /// The file is a pipe which has no offset to read from
+    IllegalSeek,
+    /// A signal was sent to the process while it was waiting for data
+    Interrupted,
+    /// The buffer is too small for the records the file is read in
+    InvalidArgument,
+    /// The device was removed, e.g. an unplugged keyboard
+    NoDevice,
+}
 
 #[derive(Debug)]
-pub enum FsWriteError {}
+pub enum FsWriteError {
+    /// The file was not opened for writing
+    BadFileDescriptor,
+    /// The file system was mounted read-only
+    ReadOnlyFileSystem,
+    /// The read end of the pipe is closed
+    BrokenPipe,
+    /// There is no space to write and the file was opened with O_NONBLOCK
+    WouldBlock,
+    /// The file does not accept the written data
+    InvalidArgument,
+    /// The file can not be written even though the file system is writable
+    PermissionDenied,
+    /// There are no free blocks left on the device
+    NoSpace,
+    /// The device failed to write the data
+    IoError,
+    /// The file is a pipe which has no offset to write to
+    IllegalSeek,
+}
 
 #[derive(Debug)]
 pub enum FsOpenError {
     BadPath(FsPathError),
+    /// O_CREAT and O_EXCL were specified but the file exists
+    AlreadyExists,
+    /// O_TRUNC was specified for a directory
+    IsADirectory,
+    NoSpace,
+    ReadOnlyFileSystem,
+}
+
+#[derive(Debug)]
+pub enum FsCreateError {
+    BadPath(FsPathError),
+    AlreadyExists,
+    NoSpace,
+    ReadOnlyFileSystem,
 }
 
 #[derive(Debug)]
 pub enum FsCloseError {}
 
+#[derive(Debug)]
+pub enum FsReadDirError {
+    BadPath(FsPathError),
+    /// The file system can not hand out inodes for more entries
+    NoSpace,
+}
+
 #[derive(Debug)]
 pub enum FsStatError {
     BadPath(FsPathError),
 }
 
 #[derive(Debug)]
-pub enum FsIoctlError {}
+pub enum FsRemoveError {
+    BadPath(FsPathError),
+    /// unlink was called on a directory
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// The file is open or is a mount point
+    Busy,
+    ReadOnlyFileSystem,
+}
+
+#[derive(Debug)]
+pub enum FsRenameError {
+    BadPath(FsPathError),
+    /// The old and new paths are on different file systems
+    CrossDevice,
+    /// A file would replace a directory
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// One of the files is open or is a mount point
+    Busy,
+    /// A directory would be moved into itself
+    InvalidArgument,
+    NoSpace,
+    ReadOnlyFileSystem,
+}
 
 #[derive(Debug)]
-pub enum FsSeekError {}
+pub enum FsIoctlError {
+    InvalidArgument,
+    DeviceBusy,
+    PermissionDenied,
+    BadAddress,
+    /// The file is not a terminal or device, e.g. a pipe
+    NotATerminal,
+}
+
+#[derive(Debug)]
+pub enum FsMmapError {
+    /// The file can not be mapped, e.g. a regular file or a pipe
+    NotSupported,
+    /// The range is outside of the device
+    InvalidRange,
+}
 
 #[derive(Debug)]
+pub enum FsSeekError {
+    /// The file is a pipe
+    IllegalSeek,
+    /// The offset would be negative or too large
+    InvalidArgument,
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum FsInitError {
     InvalidSkeleton,
     InvalidMagic,
     InvalidSuperBlock,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum FsMountError {
     BadPath(FsPathError),
-    PathAlreadyInUse,
+    /// The mount point is not a directory
+    NotADirectory,
+    /// A file system is already mounted on the mount point
+    AlreadyMounted,
+    /// The partition is already mounted somewhere else
+    DeviceAlreadyMounted,
+    /// The mount point has entries that the mounted file system would hide
+    DirectoryNotEmpty,
+    /// A file descriptor points to the mount point or to something under it
+    MountPointBusy,
+    /// MS_REMOUNT was given but no file system is mounted on the path
+    NotMounted,
     FileSystemInitFailed(FsInitError),
 }
 
+impl Into<Errno> for FsMountError {
+    fn into(self) -> Errno {
+        match self {
+            FsMountError::BadPath(path) => path.into(),
+            FsMountError::NotADirectory => ENOTDIR,
+            FsMountError::AlreadyMounted => EBUSY,
+            FsMountError::DeviceAlreadyMounted => EBUSY,
+            FsMountError::DirectoryNotEmpty => ENOTEMPTY,
+            FsMountError::MountPointBusy => EBUSY,
+            FsMountError::NotMounted => EINVAL,
+            FsMountError::FileSystemInitFailed(_) => EINVAL,
+        }
+    }
+}
+
 impl Into<Errno> for FsPathError {
     fn into(self) -> Errno {
         match self {
@@ -57,6 +190,67 @@ impl Into<Errno> for FsPathError {
             FsPathError::NotADirectory => ENOTDIR,
             FsPathError::PermissionDenied => EACCES,
             FsPathError::ParseError(err) => err.into(),
+            FsPathError::NoDevice => ENODEV,
+        }
+    }
+}
+
+impl Into<Errno> for FsReadError {
+    fn into(self) -> Errno {
+        match self {
+            FsReadError::BadFileDescriptor => EBADF,
+            FsReadError::WouldBlock => EAGAIN,
+            FsReadError::IoError => EIO,
+            FsReadError::IllegalSeek => ESPIPE,
+            FsReadError::Interrupted => EINTR,
+            FsReadError::InvalidArgument => EINVAL,
+            FsReadError::NoDevice => ENODEV,
+        }
+    }
+}
+
+impl Into<Errno> for FsWriteError {
+    fn into(self) -> Errno {
+        match self {
+            FsWriteError::BadFileDescriptor => EBADF,
+            FsWriteError::ReadOnlyFileSystem => EROFS,
+            FsWriteError::BrokenPipe => EPIPE,
+            FsWriteError::WouldBlock => EAGAIN,
+            FsWriteError::InvalidArgument => EINVAL,
+            FsWriteError::PermissionDenied => EACCES,
+            FsWriteError::NoSpace => ENOSPC,
+            FsWriteError::IoError => EIO,
+            FsWriteError::IllegalSeek => ESPIPE,
+        }
+    }
+}
+
+impl Into<Errno> for FsIoctlError {
+    fn into(self) -> Errno {
+        match self {
+            FsIoctlError::InvalidArgument => EINVAL,
+            FsIoctlError::DeviceBusy => EBUSY,
+            FsIoctlError::PermissionDenied => EPERM,
+            FsIoctlError::BadAddress => EFAULT,
+            FsIoctlError::NotATerminal => ENOTTY,
+        }
+    }
+}
+
+impl Into<Errno> for FsMmapError {
+    fn into(self) -> Errno {
+        match self {
+            FsMmapError::NotSupported => ENODEV,
+            FsMmapError::InvalidRange => ENXIO,
+        }
+    }
+}
+
+impl Into<Errno> for FsSeekError {
+    fn into(self) -> Errno {
+        match self {
+            FsSeekError::IllegalSeek => ESPIPE,
+            FsSeekError::InvalidArgument => EINVAL,
         }
     }
 }
@@ -68,3 +262,62 @@ impl Into<Errno> for FsStatError {
         }
     }
 }
+
+impl Into<Errno> for FsReadDirError {
+    fn into(self) -> Errno {
+        match self {
+            FsReadDirError::BadPath(path) => path.into(),
+            FsReadDirError::NoSpace => ENOSPC,
+        }
+    }
+}
+
+impl Into<Errno> for FsRemoveError {
+    fn into(self) -> Errno {
+        match self {
+            FsRemoveError::BadPath(path) => path.into(),
+            FsRemoveError::IsADirectory => EISDIR,
+            FsRemoveError::DirectoryNotEmpty => ENOTEMPTY,
+            FsRemoveError::Busy => EBUSY,
+            FsRemoveError::ReadOnlyFileSystem => EROFS,
+        }
+    }
+}
+
+impl Into<Errno> for FsRenameError {
+    fn into(self) -> Errno {
+        match self {
+            FsRenameError::BadPath(path) => path.into(),
+            FsRenameError::CrossDevice => EXDEV,
+            FsRenameError::IsADirectory => EISDIR,
+            FsRenameError::DirectoryNotEmpty => ENOTEMPTY,
+            FsRenameError::Busy => EBUSY,
+            FsRenameError::InvalidArgument => EINVAL,
+            FsRenameError::NoSpace => ENOSPC,
+            FsRenameError::ReadOnlyFileSystem => EROFS,
+        }
+    }
+}
+
+impl Into<Errno> for FsOpenError {
+    fn into(self) -> Errno {
+        match self {
+            FsOpenError::BadPath(path) => path.into(),
+            FsOpenError::AlreadyExists => EEXIST,
+            FsOpenError::IsADirectory => EISDIR,
+            FsOpenError::NoSpace => ENOSPC,
+            FsOpenError::ReadOnlyFileSystem => EROFS,
+        }
+    }
+}
+
+impl Into<Errno> for FsCreateError {
+    fn into(self) -> Errno {
+        match self {
+            FsCreateError::BadPath(path) => path.into(),
+            FsCreateError::AlreadyExists => EEXIST,
+            FsCreateError::NoSpace => ENOSPC,
+            FsCreateError::ReadOnlyFileSystem => EROFS,
+        }
+    }
+}
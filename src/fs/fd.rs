@@ -1,16 +1,27 @@
-use alloc::sync::Weak;
+use alloc::sync::{Arc, Weak};
 use spin::Mutex;
 
-use crate::posix::{FileOpenFlags, Stat};
+use crate::{
+    mm::{page_cache, PhysAddr},
+    posix::{FileOpenFlags, PollEvents, Stat, S_IFMT, S_IFREG},
+    scheduler::wait_queue::Waiter,
+};
 
 use super::{
-    errors::FsSeekError, FsIoctlError, FsReadError, FsStatError, FsWriteError, SeekWhence, VFSNode,
-    VFSNodeType,
+    errors::{FsMmapError, FsSeekError},
+    pipe::PipeEnd,
+    FsIoctlError, FsReadError, FsStatError, FsWriteError, SeekWhence, VFSNode, VFSNodeType,
 };
 
+#[derive(Debug, Clone)]
+pub enum FileDescriptorTarget {
+    Node(Weak<Mutex<VFSNode>>),
+    Pipe(Arc<PipeEnd>),
+}
+
 #[derive(Debug, Clone)]
 pub struct FileDescriptor {
-    pub vnode: Weak<Mutex<VFSNode>>,
+    pub target: FileDescriptorTarget,
     pub offset: usize,
     pub flags: FileOpenFlags,
 }
@@ -23,12 +34,50 @@ impl Drop for FileDescriptor {
 }
 
 impl FileDescriptor {
+    /// Returns the VFS node of the file, None for pipes
+    pub fn vnode(&self) -> Option<Arc<Mutex<VFSNode>>> {
+        match &self.target {
+            FileDescriptorTarget::Node(vnode) => Some(vnode.upgrade().unwrap()),
+            FileDescriptorTarget::Pipe(_) => None,
+        }
+    }
+
+    /// Returns whether the file descriptor refers to a regular file, reading one never blocks
+    pub fn is_regular_file(&self) -> bool {
+        let mut stat_buf = Stat::zero();
+        self.stat(&mut stat_buf).is_ok() && stat_buf.st_mode & S_IFMT == S_IFREG
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.flags.contains(FileOpenFlags::O_NONBLOCK)
+    }
+
     pub fn read(&mut self, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        if !self.flags.readable() {
+            return Err(FsReadError::BadFileDescriptor);
+        }
+
         if buff.is_empty() {
             return Ok(0);
         }
 
-        let vnode = self.vnode.upgrade().unwrap();
+        if let FileDescriptorTarget::Pipe(end) = &self.target {
+            return end.read(buff, self.nonblocking());
+        }
+
+        self.offset = self.resync_offset(self.offset);
+        let read = self.read_at(self.offset, buff)?;
+        self.offset += read;
+
+        Ok(read)
+    }
+
+    fn resync_offset(&self, off: usize) -> usize {
+        let vnode = match &self.target {
+            FileDescriptorTarget::Node(vnode) => vnode.upgrade().unwrap(),
+            FileDescriptorTarget::Pipe(_) => return off,
+        };
+
         let vnode = vnode.lock();
 
         let file_data = match &vnode.node_type {
@@ -40,37 +89,133 @@ impl FileDescriptor {
         let mut mount = mount_lock.lock();
         let fs = mount.get_fs().unwrap();
 
-        let read = fs.inner.read(file_data.inode, self.offset, buff)?;
-        self.offset += read;
+        fs.inner.resync_offset(file_data.inode, off)
+    }
 
-        Ok(read)
+    /// Reads from __off__ without moving the offset of the file descriptor, pipes can not
+    /// be read this way
+    pub fn read_at(&self, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        if !self.flags.readable() {
+            return Err(FsReadError::BadFileDescriptor);
+        }
+
+        let vnode = match &self.target {
+            FileDescriptorTarget::Node(vnode) => vnode.upgrade().unwrap(),
+            FileDescriptorTarget::Pipe(_) => return Err(FsReadError::IllegalSeek),
+        };
+
+        if buff.is_empty() {
+            return Ok(0);
+        }
+
+        let vnode = vnode.lock();
+
+        let file_data = match &vnode.node_type {
+            VFSNodeType::File(data) => data,
+            _ => unreachable!(),
+        };
+
+        let mount_lock = file_data.mount.upgrade().unwrap();
+        let mut mount = mount_lock.lock();
+        let fs = mount.get_fs().unwrap();
+
+        fs.inner.read(file_data.inode, off, buff)
     }
 
     pub fn write(&mut self, buff: &[u8]) -> Result<usize, FsWriteError> {
+        if !self.flags.writable() {
+            return Err(FsWriteError::BadFileDescriptor);
+        }
+
+        if buff.is_empty() {
+            return Ok(0);
+        }
+
+        if let FileDescriptorTarget::Pipe(end) = &self.target {
+            return end.write(buff, self.nonblocking());
+        }
+
+        let off = match self.flags.contains(FileOpenFlags::O_APPEND) {
+            true => None,
+            false => Some(self.offset),
+        };
+        let (off, written) = self.write_file(off, buff)?;
+        self.offset = off + written;
+
+        Ok(written)
+    }
+
+    /// Writes to __off__ without moving the offset of the file descriptor, pipes can not
+    /// be written this way
+    pub fn write_at(&self, off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        if !self.flags.writable() {
+            return Err(FsWriteError::BadFileDescriptor);
+        }
+
+        if let FileDescriptorTarget::Pipe(_) = &self.target {
+            return Err(FsWriteError::IllegalSeek);
+        }
+
         if buff.is_empty() {
             return Ok(0);
         }
 
-        let vnode = self.vnode.upgrade().unwrap();
+        self.write_file(Some(off), buff).map(|(_, written)| written)
+    }
+
+    /// Writes __buff__ to __off__ or to the end of the file if __off__ is None, returns the
+    /// offset the data was written to and the number of bytes written
+    fn write_file(&self, off: Option<usize>, buff: &[u8]) -> Result<(usize, usize), FsWriteError> {
+        let vnode = match &self.target {
+            FileDescriptorTarget::Node(vnode) => vnode.upgrade().unwrap(),
+            FileDescriptorTarget::Pipe(_) => unreachable!(),
+        };
         let vnode = vnode.lock();
 
         let file_data = match &vnode.node_type {
             VFSNodeType::File(data) => data,
             _ => unreachable!(),
         };
+        // only regular files can be in the page cache, get_path locks the parents and the
+        // mount can be one of them
+        let cached_path = (vnode.stat.st_mode & S_IFMT == S_IFREG).then(|| vnode.get_path());
 
         let mount_lock = file_data.mount.upgrade().unwrap();
         let mut mount = mount_lock.lock();
+        if mount.is_read_only_mount() {
+            return Err(FsWriteError::ReadOnlyFileSystem);
+        }
         let fs = mount.get_fs().unwrap();
 
-        let read = fs.inner.write(file_data.inode, self.offset, buff)?;
-        self.offset += read;
+        // the size is read with the mount locked so appending writes do not overwrite each
+        // other
+        let off = match off {
+            Some(off) => off,
+            None => {
+                let mut stat_buf = Stat::zero();
+                fs.inner.stat(file_data.inode, &mut stat_buf).unwrap();
+                stat_buf.st_size as usize
+            }
+        };
 
-        Ok(read)
+        let written = fs.inner.write(file_data.inode, off, buff)?;
+        // processes that are already running the file keep the pages they mapped, the
+        // ones that exec it later read the new contents
+        if let Some(path) = cached_path {
+            page_cache::invalidate(&path);
+        }
+
+        Ok((off, written))
     }
 
     pub fn stat(&self, stat_buf: &mut Stat) -> Result<(), FsStatError> {
-        let vnode = self.vnode.upgrade().unwrap();
+        let vnode = match &self.target {
+            FileDescriptorTarget::Node(vnode) => vnode.upgrade().unwrap(),
+            FileDescriptorTarget::Pipe(end) => {
+                end.stat(stat_buf);
+                return Ok(());
+            }
+        };
         let vnode = vnode.lock();
 
         let file_data = match &vnode.node_type {
@@ -86,7 +231,10 @@ impl FileDescriptor {
     }
 
     pub fn ioctl(&self, req: usize, arg: usize) -> Result<usize, FsIoctlError> {
-        let vnode = self.vnode.upgrade().unwrap();
+        let vnode = match &self.target {
+            FileDescriptorTarget::Node(vnode) => vnode.upgrade().unwrap(),
+            FileDescriptorTarget::Pipe(_) => return Err(FsIoctlError::NotATerminal),
+        };
         let vnode = vnode.lock();
 
         let file_data = match &vnode.node_type {
@@ -101,18 +249,82 @@ impl FileDescriptor {
         fs.inner.ioctl(file_data.inode, req, arg)
     }
 
-    pub fn lseek(&mut self, offset: usize, whence: SeekWhence) -> Result<usize, FsSeekError> {
-        let new_off = match whence {
-            SeekWhence::Set => offset,
-            SeekWhence::Cur => self.offset + offset,
+    /// Returns the physical address of the memory that backs the file at __off__, only
+    /// devices can be mapped
+    pub fn mmap(&self, off: usize, len: usize) -> Result<PhysAddr, FsMmapError> {
+        let vnode = match &self.target {
+            FileDescriptorTarget::Node(vnode) => vnode.upgrade().unwrap(),
+            FileDescriptorTarget::Pipe(_) => return Err(FsMmapError::NotSupported),
+        };
+        let vnode = vnode.lock();
+
+        let file_data = match &vnode.node_type {
+            VFSNodeType::File(data) => data,
+            _ => unreachable!(),
+        };
+
+        let mount_lock = file_data.mount.upgrade().unwrap();
+        let mut mount = mount_lock.lock();
+        let fs = mount.get_fs().unwrap();
+
+        fs.inner.mmap(file_data.inode, off, len)
+    }
+
+    /// Returns the events the file is ready for, if __waiter__ is given it is woken once that
+    /// changes. Only the events the file descriptor was opened for are reported, errors and
+    /// hangups are always reported.
+    pub fn poll(&self, waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        let events = match &self.target {
+            FileDescriptorTarget::Pipe(end) => end.poll(waiter),
+            FileDescriptorTarget::Node(vnode) => {
+                let vnode = vnode.upgrade().unwrap();
+                let vnode = vnode.lock();
+
+                let file_data = match &vnode.node_type {
+                    VFSNodeType::File(data) => data,
+                    _ => unreachable!(),
+                };
+
+                let mount_lock = file_data.mount.upgrade().unwrap();
+                let mut mount = mount_lock.lock();
+                let fs = mount.get_fs().unwrap();
+
+                fs.inner.poll(file_data.inode, self.offset, waiter)
+            }
+        };
+
+        let mut mask = PollEvents::POLLERR | PollEvents::POLLHUP;
+        if self.flags.readable() {
+            mask |= PollEvents::POLLIN | PollEvents::POLLPRI;
+        }
+        if self.flags.writable() {
+            mask |= PollEvents::POLLOUT;
+        }
+
+        events & mask
+    }
+
+    /// Moves the offset of the file descriptor to __offset__ relative to __whence__, the
+    /// offset can be past the end of the file but not before its start
+    pub fn lseek(&mut self, offset: isize, whence: SeekWhence) -> Result<usize, FsSeekError> {
+        if let FileDescriptorTarget::Pipe(_) = self.target {
+            return Err(FsSeekError::IllegalSeek);
+        }
+
+        let base = match whence {
+            SeekWhence::Set => 0,
+            SeekWhence::Cur => self.offset,
             SeekWhence::End => {
-                // TODO: normal SeekWhence::End
                 let mut buff = Stat::zero();
                 self.stat(&mut buff).unwrap();
-                buff.st_size as usize + offset
+                buff.st_size as usize
             }
         };
 
+        let new_off = base
+            .checked_add_signed(offset)
+            .filter(|&off| off <= isize::MAX as usize)
+            .ok_or(FsSeekError::InvalidArgument)?;
         self.offset = new_off;
 
         Ok(new_off)
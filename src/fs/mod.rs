@@ -2,34 +2,50 @@ use core::fmt::Debug;
 
 use alloc::{
     boxed::Box,
-    collections::BTreeMap,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
     string::{String, ToString},
     sync::{Arc, Weak},
     vec::Vec,
 };
-use spin::{Mutex, RwLock};
+use spin::{Mutex, Once, RwLock};
 
 use crate::{
     blk::Partition,
-    posix::{FileOpenFlags, Stat},
+    mm::{
+        kalloc::{self, HeapTag},
+        page_cache,
+        slab::{self, SlabCacheId},
+        PhysAddr,
+    },
+    posix::{
+        FileOpenFlags, MountFlags, PollEvents, Stat, DT_BLK, DT_CHR, DT_DIR, DT_FIFO, DT_LNK,
+        DT_REG, DT_SOCK, DT_UNKNOWN,
+    },
+    scheduler::wait_queue::Waiter,
 };
 
 use self::{
     errors::{
-        FsCloseError, FsInitError, FsIoctlError, FsOpenError, FsPathError, FsReadError,
-        FsStatError, FsWriteError,
+        FsCloseError, FsCreateError, FsInitError, FsIoctlError, FsMmapError, FsOpenError,
+        FsPathError, FsReadDirError, FsReadError, FsRemoveError, FsRenameError, FsStatError,
+        FsWriteError,
     },
-    fd::FileDescriptor,
+    fd::{FileDescriptor, FileDescriptorTarget},
     inode::FSInode,
     path::Path,
 };
 
+pub mod context;
 pub mod devfs;
 pub mod errors;
 pub mod fd;
 pub mod inode;
 pub mod mount;
 pub mod path;
+pub mod pipe;
+pub mod poll;
+pub mod procfs;
+pub mod tmpfs;
 
 pub enum SeekWhence {
     Set,
@@ -51,11 +67,130 @@ pub trait FileSystemInner: Debug {
     fn stat(&mut self, inode: FSInode, stat_buf: &mut Stat) -> Result<(), FsStatError>;
 
     fn ioctl(&mut self, inode: FSInode, req: usize, arg: usize) -> Result<usize, FsIoctlError>;
+
+    /// Returns the events the file is ready for at the offset __off__, if __waiter__ is given
+    /// it is woken once that changes. Files on disk can always be read and written without
+    /// blocking.
+    fn poll(&mut self, _inode: FSInode, _off: usize, _waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        PollEvents::POLLIN | PollEvents::POLLOUT
+    }
+
+    /// Returns the offset the next read from __off__ continues at, it is called before every
+    /// read that moves the offset. Only files whose old data is dropped move it, e.g. the
+    /// reader of /dev/kmsg that fell behind the log continues at the oldest record.
+    fn resync_offset(&mut self, _inode: FSInode, off: usize) -> usize {
+        off
+    }
+
+    /// Returns the physical address of the memory at __off__ that backs the next __len__
+    /// bytes of a device that can be mapped directly, e.g. the framebuffer
+    fn mmap(&mut self, _inode: FSInode, _off: usize, _len: usize) -> Result<PhysAddr, FsMmapError> {
+        Err(FsMmapError::NotSupported)
+    }
+
+    /// Returns every entry of a directory except . and .., the inodes are opened
+    fn read_dir(&mut self, path: Path) -> Result<Vec<DirEntry>, FsReadDirError>;
+
+    /// Removes a file or an empty directory, the VFS makes sure that it is not open
+    fn remove(&mut self, path: Path) -> Result<(), FsRemoveError>;
+
+    /// Moves a file or a directory, replacing __new_path__ if it exists
+    fn rename(&mut self, old_path: Path, new_path: Path) -> Result<(), FsRenameError>;
+
+    /// Creates an empty regular file, returns the inode
+    fn create(&mut self, path: Path) -> Result<FSInode, FsCreateError>;
+
+    /// Creates an empty directory
+    fn mkdir(&mut self, path: Path) -> Result<(), FsCreateError>;
+
+    /// Removes the contents of a regular file
+    fn truncate(&mut self, inode: FSInode) -> Result<(), FsWriteError>;
+
+    /// Whether the VFS is allowed to remember that a file does not exist, file systems
+    /// whose contents can change without going through the VFS should return false
+    fn cache_negative_entries(&self) -> bool {
+        true
+    }
+
+    /// Checks the consistency of the file system before it is mounted, simple problems
+    /// are repaired if __mode__ is FsckMode::Repair. Returns None if the file system
+    /// has no checker.
+    fn check(&mut self, _mode: FsckMode) -> Option<FsckReport> {
+        None
+    }
+
+    /// Checks whether the medium under the file system was changed since it was last read,
+    /// called by the VFS before a path on the file system is looked up. File systems on
+    /// media that can not change are always unchanged.
+    fn revalidate(&mut self) -> Revalidation {
+        Revalidation::Unchanged
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckMode {
+    /// Only report the problems
+    Check,
+    /// Repair the problems that can be repaired without losing data that is still reachable
+    Repair,
+}
+
+/// The result of revalidating a file system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Revalidation {
+    /// The medium did not change, the cached nodes are still valid
+    Unchanged,
+    /// The medium changed and the file system reloaded its state, every inode it returned
+    /// before is invalid
+    Reloaded,
+    /// The medium was removed or can not be read, the file system can not be used until
+    /// it is revalidated again
+    NoMedium,
+}
+
+/// The result of a file system check
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsckReport {
+    /// The number of problems found
+    pub errors: usize,
+    /// The number of problems that were repaired, always zero with FsckMode::Check
+    pub repaired: usize,
+}
+
+impl FsckReport {
+    /// Returns whether every problem that was found was repaired
+    pub fn clean(&self) -> bool {
+        self.errors == self.repaired
+    }
+}
+
+#[derive(Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: FSInode,
+}
+
+/// Directory entry as seen through the VFS
+#[derive(Debug)]
+pub struct VFSDirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/// Identifiers of a file system that can be read without mounting it
+#[derive(Debug, Clone)]
+pub struct VolumeInfo {
+    pub label: Option<String>,
+    pub uuid: String,
 }
 
 #[derive(Debug)]
 pub struct FileSystemSkeleton {
     pub new: fn(part: Weak<Partition>) -> Result<Box<dyn FileSystemInner>, FsInitError>,
+    /// Returns the identifiers of the file system on the partition, None if the partition
+    /// does not contain this type of file system
+    pub probe: fn(part: &Arc<Partition>) -> Option<VolumeInfo>,
     pub name: &'static str,
 }
 
@@ -74,6 +209,24 @@ pub enum FileType {
     FIFO,
     Link,
     Socket,
+    /// The mode has no valid file type, e.g. a corrupted node of a file system
+    Unknown,
+}
+
+impl FileType {
+    /// Returns the d_type value of getdents for the file type
+    pub const fn dirent_type(&self) -> u8 {
+        match self {
+            FileType::FIFO => DT_FIFO,
+            FileType::CharacterDevice => DT_CHR,
+            FileType::Directory => DT_DIR,
+            FileType::BlockDevice => DT_BLK,
+            FileType::RegularFile => DT_REG,
+            FileType::Link => DT_LNK,
+            FileType::Socket => DT_SOCK,
+            FileType::Unknown => DT_UNKNOWN,
+        }
+    }
 }
 
 pub struct VirtualFileSystem {
@@ -81,12 +234,18 @@ pub struct VirtualFileSystem {
     // the root vnode only has one owner but it needs to be an Arc
     // for file descriptors to be able to point to it with a Weak
     root: Option<Arc<Node>>,
+    mounts: Vec<mount::MountEntry>,
 }
 
 #[derive(Debug)]
 pub struct VFSDirectoryData {
     mount: Weak<Mutex<VFSNode>>,
     entries: RwLock<BTreeMap<String, Arc<Node>>>,
+    // names that were looked up but do not exist in the directory
+    negative_entries: RwLock<BTreeSet<String>>,
+    cache_negative_entries: bool,
+    // every entry of the directory is in the cache
+    populated: bool,
 }
 
 #[derive(Debug)]
@@ -99,6 +258,7 @@ pub struct VFSFileData {
 pub struct VFSMountData {
     fs: FileSystem,
     dir: VFSDirectoryData,
+    flags: MountFlags,
 }
 
 #[derive(Debug)]
@@ -119,20 +279,64 @@ pub struct VFSNode {
 
 type Node = Mutex<VFSNode>;
 
+/// The nodes are created and freed whenever a path is looked up that is not cached, so they
+/// are reused from a slab cache
+static VFS_NODE_CACHE: Once<Option<SlabCacheId>> = Once::new();
+
 impl VFSDirectoryData {
-    fn new(mount: Weak<Node>) -> VFSDirectoryData {
+    fn new(mount: Weak<Node>, cache_negative_entries: bool) -> VFSDirectoryData {
         VFSDirectoryData {
             entries: RwLock::new(BTreeMap::new()),
+            negative_entries: RwLock::new(BTreeSet::new()),
+            cache_negative_entries,
+            populated: false,
             mount,
         }
     }
+
+    /// Returns true if we know that __name__ does not exist without asking the file system
+    fn is_negative_entry(&self, name: &str) -> bool {
+        if !self.cache_negative_entries {
+            return false;
+        }
+
+        self.populated || self.negative_entries.read().contains(name)
+    }
+
+    fn add_negative_entry(&self, name: &str) {
+        if self.cache_negative_entries {
+            self.negative_entries.write().insert(name.to_string());
+        }
+    }
+
+    /// Inserts a node into the cache, this must be called when a file is created
+    fn insert_entry(&self, name: &str, node: Arc<Node>) {
+        self.negative_entries.write().remove(name);
+        self.entries.write().insert(name.to_string(), node);
+    }
+
+    /// Removes a node from the cache, this must be called when a file is removed
+    fn remove_entry(&self, name: &str) -> Option<Arc<Node>> {
+        let node = self.entries.write().remove(name);
+        self.add_negative_entry(name);
+        node
+    }
+
+    /// Forgets that __name__ does not exist, this must be called when a file is created
+    /// without inserting its node into the cache
+    fn invalidate_entry(&mut self, name: &str) {
+        self.negative_entries.write().remove(name);
+        self.populated = false;
+    }
 }
 
 impl VFSMountData {
-    fn new(fs: FileSystem) -> VFSMountData {
+    fn new(fs: FileSystem, flags: MountFlags) -> VFSMountData {
+        let cache_negative_entries = fs.inner.cache_negative_entries();
         VFSMountData {
             fs,
-            dir: VFSDirectoryData::new(Weak::new()),
+            dir: VFSDirectoryData::new(Weak::new(), cache_negative_entries),
+            flags,
         }
     }
 }
@@ -151,6 +355,13 @@ impl VFSNode {
         }
     }
 
+    fn is_read_only_mount(&self) -> bool {
+        match &self.node_type {
+            VFSNodeType::MountPoint(mount) => mount.flags.contains(MountFlags::MS_RDONLY),
+            _ => false,
+        }
+    }
+
     pub fn get_path(&self) -> String {
         // TODO: optimize
         let mut str = String::new();
@@ -183,6 +394,18 @@ impl VFSNode {
         matches!(self.node_type, VFSNodeType::Directory(_))
     }
 
+    /// Returns the number of directories between the node and the root
+    fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut parent = self.parent.clone();
+        while let Some(p) = parent.upgrade() {
+            depth += 1;
+            parent = p.lock().parent.clone();
+        }
+
+        depth
+    }
+
     fn get_dir_data(&mut self) -> Option<&mut VFSDirectoryData> {
         match &mut self.node_type {
             VFSNodeType::File(_) => None,
@@ -210,18 +433,29 @@ pub fn dir_get_entry(
         if let Some(node) = entries.get(name) {
             return Ok(node.clone());
         }
+
+        if dir_data.is_negative_entry(name) {
+            return Err(FsPathError::NoSuchFileOrDirectory);
+        }
     }
 
     // unlock because the parent directory can be the current mount too and create_new_node causes a deadlock if parent is locked
 
-    let node = VirtualFileSystem::create_new_node(name, &parent, current_mount, subpath)
-        .map_err(|_| FsPathError::NoSuchFileOrDirectory)?;
+    let res = VirtualFileSystem::create_new_node(name, &parent, current_mount, subpath);
 
     let mut dir = parent.lock();
     let dir_data = dir.get_dir_data().ok_or(FsPathError::NotADirectory)?;
-    let mut entries = dir_data.entries.write();
 
-    entries.insert(name.to_string(), node.clone());
+    let node = match res {
+        Ok(node) => node,
+        Err(FsOpenError::BadPath(FsPathError::NoSuchFileOrDirectory)) => {
+            dir_data.add_negative_entry(name);
+            return Err(FsPathError::NoSuchFileOrDirectory);
+        }
+        Err(_) => return Err(FsPathError::NoSuchFileOrDirectory),
+    };
+
+    dir_data.insert_entry(name, node.clone());
 
     Ok(node)
 }
@@ -231,6 +465,7 @@ impl VirtualFileSystem {
         VirtualFileSystem {
             root: None,
             fs_skeletons: Vec::new(),
+            mounts: Vec::new(),
         }
     }
 
@@ -246,12 +481,30 @@ impl VirtualFileSystem {
         // normal subpath
         let inode = fs.inner.open(subpath)?;
 
+        Ok(Self::create_node_from_inode(
+            name, parent, mount_lock, fs, inode,
+        ))
+    }
+
+    fn create_node_from_inode(
+        name: &str,
+        parent: &Arc<Node>,
+        mount_lock: &Arc<Node>,
+        fs: &mut FileSystem,
+        inode: FSInode,
+    ) -> Arc<Node> {
+        let _tag = kalloc::tag(HeapTag::Fs);
+        VFS_NODE_CACHE.call_once(|| slab::create_cache("vfs_node", slab::arc_layout::<Node>()));
+
         let mut stat_buf: Stat = Stat::zero();
         fs.inner.stat(inode, &mut stat_buf).unwrap();
 
         let mount_weak = Arc::downgrade(mount_lock);
         let node_type = match stat_buf.file_type() {
-            FileType::Directory => VFSNodeType::Directory(VFSDirectoryData::new(mount_weak)),
+            FileType::Directory => VFSNodeType::Directory(VFSDirectoryData::new(
+                mount_weak,
+                fs.inner.cache_negative_entries(),
+            )),
             _ => VFSNodeType::File(VFSFileData::new(mount_weak, inode)),
         };
 
@@ -262,7 +515,111 @@ impl VirtualFileSystem {
             stat: stat_buf,
         };
 
-        Ok(Arc::new(Mutex::new(node)))
+        Arc::new(Mutex::new(node))
+    }
+
+    /// Returns the mount point the node is on
+    fn get_node_mount(node_lock: &Arc<Node>) -> Arc<Node> {
+        let node = node_lock.lock();
+        match &node.node_type {
+            VFSNodeType::File(file) => file.mount.upgrade().unwrap(),
+            VFSNodeType::Directory(dir) => dir.mount.upgrade().unwrap(),
+            VFSNodeType::MountPoint(_) => node_lock.clone(),
+        }
+    }
+
+    /// Returns the path relative to the mount point
+    fn get_mount_subpath<'a>(path: Path<'a>, mount_lock: &Arc<Node>) -> Path<'a> {
+        let mut subpath = path;
+        let mount_depth = mount_lock.lock().depth();
+        for _ in 0..mount_depth {
+            subpath.next();
+        }
+
+        subpath
+    }
+
+    /// Returns whether a file descriptor points to the node or to anything under it,
+    /// directories with a file system mounted under them are in use too
+    fn node_in_use(node_lock: &Arc<Node>) -> bool {
+        let children: Vec<Arc<Node>> = {
+            let node = node_lock.lock();
+            match &node.node_type {
+                VFSNodeType::File(_) => Vec::new(),
+                VFSNodeType::Directory(dir) => dir.entries.read().values().cloned().collect(),
+                VFSNodeType::MountPoint(_) => return true,
+            }
+        };
+
+        // file descriptors and the cached children point to the node with a Weak
+        Arc::weak_count(node_lock) > children.len() || children.iter().any(Self::node_in_use)
+    }
+
+    /// Closes the inodes of a node that was dropped from the cache and of everything under it
+    fn close_node(node_lock: &Arc<Node>, fs: &mut FileSystem) {
+        let node = node_lock.lock();
+        match &node.node_type {
+            VFSNodeType::File(file) => fs.inner.close(file.inode).unwrap(),
+            VFSNodeType::Directory(dir) => {
+                for child in dir.entries.read().values() {
+                    Self::close_node(child, fs);
+                }
+            }
+            VFSNodeType::MountPoint(_) => unreachable!(),
+        }
+    }
+
+    /// Drops a node from the cache of its parent after it was removed or renamed
+    fn forget_node(parent_lock: &Arc<Node>, name: &str, mount_lock: &Arc<Node>) {
+        let node = {
+            let mut parent = parent_lock.lock();
+            parent.get_dir_data().unwrap().remove_entry(name)
+        };
+
+        if let Some(node_lock) = node {
+            let mut mount = mount_lock.lock();
+            Self::close_node(&node_lock, mount.get_fs().unwrap());
+        }
+    }
+
+    /// Drops the cached nodes under a directory of a file system that forgot its inodes, the
+    /// directories that lead to other mounts are kept. Returns whether anything was kept.
+    fn invalidate_nodes(dir: &mut VFSDirectoryData) -> bool {
+        dir.negative_entries.write().clear();
+        dir.populated = false;
+
+        let mut entries = dir.entries.write();
+        entries.retain(|_, node_lock| {
+            let mut node = node_lock.lock();
+            match &mut node.node_type {
+                VFSNodeType::File(_) => false,
+                VFSNodeType::Directory(dir) => Self::invalidate_nodes(dir),
+                VFSNodeType::MountPoint(_) => true,
+            }
+        });
+
+        !entries.is_empty()
+    }
+
+    /// Revalidates the file system mounted on __mount_lock__. If its medium changed the
+    /// cached nodes under the mount are dropped so nothing is served from the old medium,
+    /// file descriptors that point to them become invalid.
+    fn revalidate_mount(mount_lock: &Arc<Node>) -> Result<(), FsPathError> {
+        let mut mount = mount_lock.lock();
+        let revalidation = mount.get_fs().unwrap().inner.revalidate();
+        if revalidation == Revalidation::Unchanged {
+            return Ok(());
+        }
+
+        let path = mount.get_path();
+        Self::invalidate_nodes(mount.get_dir_data().unwrap());
+        drop(mount);
+        page_cache::invalidate_under(&path);
+
+        match revalidation {
+            Revalidation::NoMedium => Err(FsPathError::NoDevice),
+            _ => Ok(()),
+        }
     }
 
     fn traverse_path(
@@ -270,11 +627,13 @@ impl VirtualFileSystem {
         path: &mut Path,
         components_to_leave_out: usize,
     ) -> Result<Arc<Node>, FsPathError> {
+        let _tag = kalloc::tag(HeapTag::Fs);
         let root_node = self.root.as_ref().expect("Root filesystem is not mounted");
         let mut current_node = root_node.clone();
         let mut current_mount = root_node.clone();
         let mut remaining_path = path.clone();
         let mut subpath_comp_count = 0;
+        Self::revalidate_mount(&current_mount)?;
 
         while path.components_left() > components_to_leave_out {
             subpath_comp_count += 1;
@@ -286,9 +645,10 @@ impl VirtualFileSystem {
                 remaining_path.clone().shorten(subpath_comp_count),
             )?;
 
-            let node = current_node.lock();
-            if node.is_mount_point() {
+            let is_mount_point = current_node.lock().is_mount_point();
+            if is_mount_point {
                 current_mount = current_node.clone();
+                Self::revalidate_mount(&current_mount)?;
                 remaining_path = path.clone();
                 subpath_comp_count = 0;
             }
@@ -304,17 +664,248 @@ impl VirtualFileSystem {
     ) -> Result<Box<FileDescriptor>, FsOpenError> {
         let mut path =
             Path::new(path).map_err(|err| FsOpenError::BadPath(FsPathError::ParseError(err)))?;
-        let node = self
-            .traverse_path(&mut path, 0)
-            .map_err(FsOpenError::BadPath)?;
+        let full_path = path.clone();
+
+        let node = match self.traverse_path(&mut path, 0) {
+            Ok(_) if flags.contains(FileOpenFlags::O_CREAT | FileOpenFlags::O_EXCL) => {
+                return Err(FsOpenError::AlreadyExists)
+            }
+            Ok(node) => node,
+            Err(FsPathError::NoSuchFileOrDirectory) if flags.contains(FileOpenFlags::O_CREAT) => {
+                self.create(full_path).map_err(|err| match err {
+                    FsCreateError::BadPath(err) => FsOpenError::BadPath(err),
+                    FsCreateError::AlreadyExists => FsOpenError::AlreadyExists,
+                    FsCreateError::NoSpace => FsOpenError::NoSpace,
+                    FsCreateError::ReadOnlyFileSystem => FsOpenError::ReadOnlyFileSystem,
+                })?
+            }
+            Err(err) => return Err(FsOpenError::BadPath(err)),
+        };
+
+        if flags.contains(FileOpenFlags::O_TRUNC) && flags.writable() {
+            Self::truncate(&node)?;
+        }
 
         Ok(Box::new(FileDescriptor {
-            vnode: Arc::downgrade(&node),
+            target: FileDescriptorTarget::Node(Arc::downgrade(&node)),
             offset: 0,
             flags,
         }))
     }
 
+    /// Returns the directory a new file at __path__ goes into, the mount the directory
+    /// is on and the name of the new file
+    fn get_parent_for_create<'a>(
+        &mut self,
+        path: Path<'a>,
+    ) -> Result<(Arc<Node>, Arc<Node>, &'a str), FsCreateError> {
+        if path.components_left() == 0 {
+            return Err(FsCreateError::AlreadyExists);
+        }
+
+        let mut path = path;
+        let parent_lock = self
+            .traverse_path(&mut path, 1)
+            .map_err(FsCreateError::BadPath)?;
+        let name = path.next().unwrap();
+
+        if parent_lock.lock().is_file() {
+            return Err(FsCreateError::BadPath(FsPathError::NotADirectory));
+        }
+
+        let mount_lock = Self::get_node_mount(&parent_lock);
+        if mount_lock.lock().is_read_only_mount() {
+            return Err(FsCreateError::ReadOnlyFileSystem);
+        }
+
+        Ok((parent_lock, mount_lock, name))
+    }
+
+    /// Creates an empty regular file and puts it in the cache
+    fn create(&mut self, path: Path) -> Result<Arc<Node>, FsCreateError> {
+        let full_path = path.clone();
+        let (parent_lock, mount_lock, name) = self.get_parent_for_create(path)?;
+
+        let subpath = Self::get_mount_subpath(full_path, &mount_lock);
+        let node = {
+            let mut mount = mount_lock.lock();
+            let fs = mount.get_fs().unwrap();
+            let inode = fs.inner.create(subpath)?;
+            Self::create_node_from_inode(name, &parent_lock, &mount_lock, fs, inode)
+        };
+
+        let mut parent = parent_lock.lock();
+        parent
+            .get_dir_data()
+            .unwrap()
+            .insert_entry(name, node.clone());
+
+        Ok(node)
+    }
+
+    /// Removes the contents of a regular file
+    fn truncate(node_lock: &Arc<Node>) -> Result<(), FsOpenError> {
+        let mount_lock = Self::get_node_mount(node_lock);
+
+        let mut node = node_lock.lock();
+        let inode = match &node.node_type {
+            VFSNodeType::File(file) => file.inode,
+            VFSNodeType::Directory(_) | VFSNodeType::MountPoint(_) => {
+                return Err(FsOpenError::IsADirectory)
+            }
+        };
+        // get_path locks the parents, the mount can be one of them
+        let path = node.get_path();
+
+        let mut mount = mount_lock.lock();
+        if mount.is_read_only_mount() {
+            return Err(FsOpenError::ReadOnlyFileSystem);
+        }
+
+        let fs = mount.get_fs().unwrap();
+        fs.inner.truncate(inode).map_err(|err| match err {
+            FsWriteError::ReadOnlyFileSystem | FsWriteError::PermissionDenied => {
+                FsOpenError::ReadOnlyFileSystem
+            }
+            FsWriteError::BadFileDescriptor
+            | FsWriteError::InvalidArgument
+            | FsWriteError::BrokenPipe
+            | FsWriteError::WouldBlock
+            | FsWriteError::NoSpace
+            | FsWriteError::IoError
+            | FsWriteError::IllegalSeek => unreachable!(),
+        })?;
+
+        // processes that are already running the file keep the pages they mapped
+        page_cache::invalidate(&path);
+
+        // the size in the cached stat is stale now
+        fs.inner.stat(inode, &mut node.stat).unwrap();
+
+        Ok(())
+    }
+
+    /// Reads every entry of a directory from the file system in one pass and puts them
+    /// in the node cache, after this lookups of nonexistent files don't hit the disk
+    pub fn populate_dir(&mut self, path: &str) -> Result<(), FsReadDirError> {
+        let _tag = kalloc::tag(HeapTag::Fs);
+        let mut path =
+            Path::new(path).map_err(|err| FsReadDirError::BadPath(FsPathError::ParseError(err)))?;
+        let full_path = path.clone();
+        let dir_lock = self
+            .traverse_path(&mut path, 0)
+            .map_err(FsReadDirError::BadPath)?;
+
+        let (mount_lock, existing_names) = {
+            let mut dir = dir_lock.lock();
+            let mount = match &dir.node_type {
+                VFSNodeType::File(_) => {
+                    return Err(FsReadDirError::BadPath(FsPathError::NotADirectory))
+                }
+                VFSNodeType::Directory(dir_data) => dir_data.mount.upgrade().unwrap(),
+                VFSNodeType::MountPoint(_) => dir_lock.clone(),
+            };
+
+            let dir_data = dir.get_dir_data().unwrap();
+            if dir_data.populated {
+                return Ok(());
+            }
+
+            let names: BTreeSet<String> = dir_data.entries.read().keys().cloned().collect();
+            (mount, names)
+        };
+
+        let subpath = Self::get_mount_subpath(full_path, &mount_lock);
+
+        let new_nodes: Vec<(String, Arc<Node>)> = {
+            let mut mount = mount_lock.lock();
+            let fs = mount.get_fs().unwrap();
+
+            let entries = fs.inner.read_dir(subpath)?;
+            let mut new_nodes = Vec::with_capacity(entries.len());
+            for ent in entries {
+                if existing_names.contains(&ent.name) {
+                    fs.inner.close(ent.inode).unwrap();
+                    continue;
+                }
+
+                let node =
+                    Self::create_node_from_inode(&ent.name, &dir_lock, &mount_lock, fs, ent.inode);
+                new_nodes.push((ent.name, node));
+            }
+
+            new_nodes
+        };
+
+        // an entry could have been looked up while the mount was unlocked, the node created
+        // here for it is dropped and its inode closed
+        let mut duplicates = Vec::new();
+        {
+            let mut dir = dir_lock.lock();
+            let dir_data = dir.get_dir_data().unwrap();
+            {
+                let mut entries = dir_data.entries.write();
+                for (name, node) in new_nodes {
+                    match entries.entry(name) {
+                        Entry::Vacant(entry) => {
+                            entry.insert(node);
+                        }
+                        Entry::Occupied(_) => duplicates.push(node),
+                    }
+                }
+            }
+
+            // everything that is not in the cache now does not exist
+            if dir_data.cache_negative_entries {
+                dir_data.negative_entries.write().clear();
+                dir_data.populated = true;
+            }
+        }
+
+        if !duplicates.is_empty() {
+            let mut mount = mount_lock.lock();
+            let fs = mount.get_fs().unwrap();
+            for node in &duplicates {
+                Self::close_node(node, fs);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns every entry of a directory except . and .., including the file systems
+    /// mounted in the directory
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<VFSDirEntry>, FsReadDirError> {
+        self.populate_dir(path)?;
+
+        let mut path =
+            Path::new(path).map_err(|err| FsReadDirError::BadPath(FsPathError::ParseError(err)))?;
+        let dir_lock = self
+            .traverse_path(&mut path, 0)
+            .map_err(FsReadDirError::BadPath)?;
+
+        let mut dir = dir_lock.lock();
+        let dir_data = dir.get_dir_data().unwrap();
+        let entries = dir_data.entries.read();
+
+        Ok(entries
+            .iter()
+            .map(|(name, node_lock)| {
+                let node = node_lock.lock();
+                let file_type = match node.node_type {
+                    VFSNodeType::File(_) => node.stat.file_type(),
+                    VFSNodeType::Directory(_) | VFSNodeType::MountPoint(_) => FileType::Directory,
+                };
+
+                VFSDirEntry {
+                    name: name.clone(),
+                    inode: node.stat.st_ino,
+                    file_type,
+                }
+            })
+            .collect())
+    }
+
     pub fn stat(&mut self, path: &str, stat_buf: &mut Stat) -> Result<(), FsStatError> {
         let mut path =
             Path::new(path).map_err(|err| FsStatError::BadPath(FsPathError::ParseError(err)))?;
@@ -325,6 +916,198 @@ impl VirtualFileSystem {
 
         Ok(())
     }
+
+    /// Creates an empty directory
+    pub fn mkdir(&mut self, path: &str) -> Result<(), FsCreateError> {
+        let path =
+            Path::new(path).map_err(|err| FsCreateError::BadPath(FsPathError::ParseError(err)))?;
+        let full_path = path.clone();
+        let (parent_lock, mount_lock, name) = self.get_parent_for_create(path)?;
+
+        let subpath = Self::get_mount_subpath(full_path, &mount_lock);
+        {
+            let mut mount = mount_lock.lock();
+            mount.get_fs().unwrap().inner.mkdir(subpath)?;
+        }
+
+        // the node is created on the first lookup
+        let mut parent = parent_lock.lock();
+        parent.get_dir_data().unwrap().invalidate_entry(name);
+
+        Ok(())
+    }
+
+    /// Removes a file, directories can only be removed with rmdir
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsRemoveError> {
+        self.remove(path, false)
+    }
+
+    /// Removes an empty directory
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FsRemoveError> {
+        self.remove(path, true)
+    }
+
+    fn remove(&mut self, path: &str, directory: bool) -> Result<(), FsRemoveError> {
+        let mut path =
+            Path::new(path).map_err(|err| FsRemoveError::BadPath(FsPathError::ParseError(err)))?;
+        let full_path = path.clone();
+        let node_lock = self
+            .traverse_path(&mut path, 0)
+            .map_err(FsRemoveError::BadPath)?;
+
+        let (parent_lock, name) = {
+            let node = node_lock.lock();
+            match (&node.node_type, directory) {
+                (VFSNodeType::MountPoint(_), _) => return Err(FsRemoveError::Busy),
+                (VFSNodeType::File(_), true) => {
+                    return Err(FsRemoveError::BadPath(FsPathError::NotADirectory))
+                }
+                (VFSNodeType::Directory(_), false) => return Err(FsRemoveError::IsADirectory),
+                _ => {}
+            }
+
+            (node.parent.upgrade().unwrap(), node.name.clone())
+        };
+
+        if Self::node_in_use(&node_lock) {
+            return Err(FsRemoveError::Busy);
+        }
+
+        let mount_lock = Self::get_node_mount(&node_lock);
+        // get_mount_subpath locks the mount
+        let subpath = Self::get_mount_subpath(full_path, &mount_lock);
+        {
+            let mut mount = mount_lock.lock();
+            if mount.is_read_only_mount() {
+                return Err(FsRemoveError::ReadOnlyFileSystem);
+            }
+
+            mount.get_fs().unwrap().inner.remove(subpath)?;
+        }
+
+        page_cache::invalidate(&node_lock.lock().get_path());
+        Self::forget_node(&parent_lock, &name, &mount_lock);
+
+        Ok(())
+    }
+
+    /// Moves a file or a directory, if __new_path__ exists it is replaced
+    pub fn rename(&mut self, old_path: &str, new_path: &str) -> Result<(), FsRenameError> {
+        let mut old_path = Path::new(old_path)
+            .map_err(|err| FsRenameError::BadPath(FsPathError::ParseError(err)))?;
+        let old_full_path = old_path.clone();
+        let old_lock = self
+            .traverse_path(&mut old_path, 0)
+            .map_err(FsRenameError::BadPath)?;
+
+        let mut new_path = Path::new(new_path)
+            .map_err(|err| FsRenameError::BadPath(FsPathError::ParseError(err)))?;
+        let new_full_path = new_path.clone();
+        if new_path.components_left() == 0 {
+            return Err(FsRenameError::Busy);
+        }
+
+        let new_parent_lock = self
+            .traverse_path(&mut new_path, 1)
+            .map_err(FsRenameError::BadPath)?;
+        let new_name = new_path.next().unwrap();
+
+        let (old_parent_lock, old_name, old_is_dir) = {
+            let old = old_lock.lock();
+            if old.is_mount_point() {
+                return Err(FsRenameError::Busy);
+            }
+
+            (
+                old.parent.upgrade().unwrap(),
+                old.name.clone(),
+                old.is_dirile(),
+            )
+        };
+
+        if new_parent_lock.lock().is_file() {
+            return Err(FsRenameError::BadPath(FsPathError::NotADirectory));
+        }
+
+        let mount_lock = Self::get_node_mount(&old_lock);
+        if !Arc::ptr_eq(&mount_lock, &Self::get_node_mount(&new_parent_lock)) {
+            return Err(FsRenameError::CrossDevice);
+        }
+
+        // a directory can not be moved under itself
+        let mut ancestor = Some(new_parent_lock.clone());
+        while let Some(node_lock) = ancestor {
+            if Arc::ptr_eq(&node_lock, &old_lock) {
+                return Err(FsRenameError::InvalidArgument);
+            }
+            ancestor = node_lock.lock().parent.upgrade();
+        }
+
+        match self.traverse_path(&mut new_full_path.clone(), 0) {
+            Ok(existing_lock) => {
+                if Arc::ptr_eq(&existing_lock, &old_lock) {
+                    return Ok(());
+                }
+
+                let existing = existing_lock.lock();
+                match &existing.node_type {
+                    VFSNodeType::MountPoint(_) => return Err(FsRenameError::Busy),
+                    VFSNodeType::File(_) if old_is_dir => {
+                        return Err(FsRenameError::BadPath(FsPathError::NotADirectory))
+                    }
+                    VFSNodeType::Directory(_) if !old_is_dir => {
+                        return Err(FsRenameError::IsADirectory)
+                    }
+                    _ => {}
+                }
+                drop(existing);
+
+                if Self::node_in_use(&existing_lock) {
+                    return Err(FsRenameError::Busy);
+                }
+            }
+            Err(FsPathError::NoSuchFileOrDirectory) => {}
+            Err(err) => return Err(FsRenameError::BadPath(err)),
+        }
+
+        if Self::node_in_use(&old_lock) {
+            return Err(FsRenameError::Busy);
+        }
+
+        // get_mount_subpath locks the mount
+        let old_subpath = Self::get_mount_subpath(old_full_path, &mount_lock);
+        let new_subpath = Self::get_mount_subpath(new_full_path, &mount_lock);
+        {
+            let mut mount = mount_lock.lock();
+            if mount.is_read_only_mount() {
+                return Err(FsRenameError::ReadOnlyFileSystem);
+            }
+
+            mount
+                .get_fs()
+                .unwrap()
+                .inner
+                .rename(old_subpath, new_subpath)?;
+        }
+
+        // the cached pages of the moved file and of the file it replaced are found by path
+        page_cache::invalidate(&old_lock.lock().get_path());
+        page_cache::invalidate(&format!(
+            "{}/{}",
+            new_parent_lock.lock().get_path(),
+            new_name
+        ));
+        Self::forget_node(&old_parent_lock, &old_name, &mount_lock);
+        Self::forget_node(&new_parent_lock, new_name, &mount_lock);
+
+        let mut new_parent = new_parent_lock.lock();
+        new_parent
+            .get_dir_data()
+            .unwrap()
+            .invalidate_entry(new_name);
+
+        Ok(())
+    }
 }
 
 pub static VFS: RwLock<VirtualFileSystem> = RwLock::new(VirtualFileSystem::new());
@@ -1,62 +1,190 @@
 use alloc::{
-    string::ToString,
+    string::{String, ToString},
     sync::{Arc, Weak},
 };
 use spin::Mutex;
 
-use crate::{blk::Partition, posix::Stat};
+use crate::{
+    audit::{self, AuditEvent},
+    blk::{self, Partition},
+    cmdline,
+    posix::{MountFlags, Stat},
+};
 
 use super::{
-    errors::FsMountError, path::Path, FileSystem, FileSystemSkeleton, FsInitError, FsPathError,
-    Node, VFSMountData, VFSNode, VFSNodeType, VirtualFileSystem,
+    errors::{FsMountError, FsReadDirError},
+    path::Path,
+    FileSystem, FileSystemSkeleton, FsInitError, FsPathError, FsckMode, Node, VFSMountData,
+    VFSNode, VFSNodeType, VirtualFileSystem, VolumeInfo, VFS,
 };
 
-fn create_mount_point_node(name: &str, parent: Weak<Node>, fs: FileSystem) -> Arc<Node> {
+/// An entry of the mount table
+#[derive(Debug, Clone)]
+pub struct MountEntry {
+    /// The device the file system was mounted from, the name of the file system
+    /// for special file systems
+    pub device: String,
+    pub mount_point: String,
+    pub fs_type: &'static str,
+    pub flags: MountFlags,
+}
+
+fn create_mount_point_node(
+    name: &str,
+    parent: Weak<Node>,
+    fs: FileSystem,
+    flags: MountFlags,
+) -> Arc<Node> {
     let node = VFSNode {
         name: name.to_string(),
         parent,
         stat: Stat::zero(),
-        node_type: VFSNodeType::MountPoint(VFSMountData::new(fs)),
+        node_type: VFSNodeType::MountPoint(VFSMountData::new(fs, flags)),
     };
 
     Arc::new(Mutex::new(node))
 }
 
+/// Returns whether file systems are checked before they are mounted, `fsck` on the
+/// command line only reports problems and `fsck=repair` also repairs them
+fn fsck_mode() -> Option<FsckMode> {
+    match cmdline::get("fsck")?.as_str() {
+        "" => Some(FsckMode::Check),
+        "repair" => Some(FsckMode::Repair),
+        mode => {
+            warn!("VFS: unknown fsck mode {}", mode);
+            None
+        }
+    }
+}
+
+/// Checks the file system on __device__, returns false if it has problems that were
+/// not repaired
+fn check_filesystem(fs: &mut FileSystem, device: &str, mode: FsckMode) -> bool {
+    let report = match fs.inner.check(mode) {
+        Some(report) => report,
+        None => {
+            log!("VFS: {} file systems can not be checked", fs.name);
+            return true;
+        }
+    };
+
+    if report.errors == 0 {
+        log!("VFS: {} is clean", device);
+    } else {
+        warn!(
+            "VFS: {} has {} errors, {} repaired",
+            device, report.errors, report.repaired
+        );
+    }
+
+    report.clean()
+}
+
+/// Mounts are recorded in the audit log, they are only done by the kernel for now
+fn audit_mount(device: &str, path: &str, fs_type: &str, res: Result<(), FsMountError>) {
+    let event = AuditEvent::Mount {
+        device: device.to_string(),
+        mount_point: path.to_string(),
+        fs_type: fs_type.to_string(),
+    };
+    audit::record(None, event, res.map_err(|err| err.into()));
+}
+
 impl VirtualFileSystem {
-    fn mount_internal(&mut self, path: &str, filesystem: FileSystem) -> Result<(), FsMountError> {
-        let mut path =
+    /// Mounts __filesystem__ on __path__ which has to be an empty directory that nothing
+    /// is mounted on yet
+    fn mount_internal(
+        &mut self,
+        path: &str,
+        device: String,
+        filesystem: FileSystem,
+        flags: MountFlags,
+    ) -> Result<(), FsMountError> {
+        let entry = MountEntry {
+            device,
+            mount_point: path.to_string(),
+            fs_type: filesystem.name,
+            flags,
+        };
+        let mut parsed_path =
             Path::new(path).map_err(|err| FsMountError::BadPath(FsPathError::ParseError(err)))?;
 
-        if path.components_left() == 0 {
+        if parsed_path.components_left() == 0 {
             return match self.root {
-                Some(_) => Err(FsMountError::PathAlreadyInUse),
+                Some(_) => Err(FsMountError::AlreadyMounted),
                 None => {
-                    self.root = Some(create_mount_point_node("", Weak::new(), filesystem));
+                    self.root = Some(create_mount_point_node("", Weak::new(), filesystem, flags));
+                    self.mounts.push(entry);
                     Ok(())
                 }
             };
         }
 
-        let parent_lock = self
-            .traverse_path(&mut path, 1)
+        let node_lock = self
+            .traverse_path(&mut parsed_path, 0)
             .map_err(FsMountError::BadPath)?;
+        {
+            let node = node_lock.lock();
+            if node.is_mount_point() {
+                return Err(FsMountError::AlreadyMounted);
+            }
+            if !node.is_dirile() {
+                return Err(FsMountError::NotADirectory);
+            }
+        }
 
-        let name = path.next().unwrap();
+        // the entries would be hidden by the mounted file system
+        let entries = self.read_dir(path).map_err(|err| match err {
+            FsReadDirError::BadPath(err) => FsMountError::BadPath(err),
+            // the directory has entries if it ran out of inodes for them
+            FsReadDirError::NoSpace => FsMountError::DirectoryNotEmpty,
+        })?;
+        if !entries.is_empty() {
+            return Err(FsMountError::DirectoryNotEmpty);
+        }
 
-        let mut parent = parent_lock.lock();
+        // the node of the directory is replaced so nothing can point to it anymore
+        if Self::node_in_use(&node_lock) {
+            return Err(FsMountError::MountPointBusy);
+        }
 
-        let dir_data = parent
-            .get_dir_data()
-            .ok_or(FsMountError::BadPath(FsPathError::NotADirectory))?;
-        let mut entries = dir_data.entries.write();
-
-        match entries.get(name) {
-            Some(_) => return Err(FsMountError::PathAlreadyInUse),
-            None => entries.insert(
-                name.to_string(),
-                create_mount_point_node(name, Arc::downgrade(&parent_lock), filesystem),
-            ),
+        let (parent_lock, name) = {
+            let node = node_lock.lock();
+            (node.parent.upgrade().unwrap(), node.name.clone())
         };
+        let mut parent = parent_lock.lock();
+        parent.get_dir_data().unwrap().insert_entry(
+            &name,
+            create_mount_point_node(&name, Arc::downgrade(&parent_lock), filesystem, flags),
+        );
+        self.mounts.push(entry);
+
+        Ok(())
+    }
+
+    /// Changes the flags of the file system mounted on __path__
+    fn remount(&mut self, path: &str, flags: MountFlags) -> Result<(), FsMountError> {
+        let flags = flags - MountFlags::MS_REMOUNT;
+        let mut parsed_path =
+            Path::new(path).map_err(|err| FsMountError::BadPath(FsPathError::ParseError(err)))?;
+        let node_lock = self
+            .traverse_path(&mut parsed_path, 0)
+            .map_err(FsMountError::BadPath)?;
+
+        match &mut node_lock.lock().node_type {
+            VFSNodeType::MountPoint(mount) => mount.flags = flags,
+            _ => return Err(FsMountError::NotMounted),
+        }
+
+        if let Some(entry) = self
+            .mounts
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.mount_point == path)
+        {
+            entry.flags = flags;
+        }
 
         Ok(())
     }
@@ -65,6 +193,7 @@ impl VirtualFileSystem {
         &mut self,
         path: &str,
         filesystem: FileSystem,
+        flags: MountFlags,
     ) -> Result<(), FsMountError> {
         if cfg!(vfs_debug) {
             log!(
@@ -74,7 +203,14 @@ impl VirtualFileSystem {
             );
         }
 
-        self.mount_internal(path, filesystem)
+        let fs_type = filesystem.name;
+        let res = if flags.contains(MountFlags::MS_REMOUNT) {
+            self.remount(path, flags)
+        } else {
+            self.mount_internal(path, fs_type.to_string(), filesystem, flags)
+        };
+        audit_mount(fs_type, path, fs_type, res);
+        res
     }
 
     pub fn mount(
@@ -82,29 +218,96 @@ impl VirtualFileSystem {
         path: &str,
         part: Weak<Partition>,
         fs_name: &str,
+        flags: MountFlags,
     ) -> Result<(), FsMountError> {
-        if cfg!(vfs_debug) {
-            let blk_dev_name = {
-                let part = part.upgrade().unwrap();
+        let device = {
+            let part = part.upgrade().unwrap();
+            if cfg!(vfs_debug) {
                 let blk_dev = part.block_device.upgrade().unwrap();
-                format!(
-                    "device: {} major: {} minor: {} part: {}",
-                    blk_dev.name, blk_dev.major, blk_dev.minor, part.part_idx
-                )
-            };
-            log!(
-                "VFS: attempting to mount {}({}) filesystem to {} ",
-                fs_name,
-                blk_dev_name,
-                path
-            );
+                log!(
+                    "VFS: attempting to mount {}(device: {} major: {} minor: {} part: {}) filesystem to {} ",
+                    fs_name,
+                    blk_dev.name,
+                    blk_dev.major,
+                    blk_dev.minor,
+                    part.part_idx,
+                    path
+                );
+            }
+            part.name()
+        };
+
+        if flags.contains(MountFlags::MS_REMOUNT) {
+            let res = self.remount(path, flags);
+            audit_mount(&device, path, fs_name, res);
+            return res;
+        }
+
+        if self.mounts.iter().any(|entry| entry.device == device) {
+            let err = FsMountError::DeviceAlreadyMounted;
+            audit_mount(&device, path, fs_name, Err(err));
+            return Err(err);
+        }
+
+        let mut fs = match self.create_new_filesystem(fs_name, part) {
+            Ok(fs) => fs,
+            Err(err) => {
+                let err = FsMountError::FileSystemInitFailed(err);
+                audit_mount(&device, path, fs_name, Err(err));
+                return Err(err);
+            }
+        };
+
+        let mut flags = flags;
+        if let Some(mode) = fsck_mode() {
+            if !check_filesystem(&mut fs, &device, mode) {
+                warn!("VFS: mounting {} read-only because it has errors", device);
+                flags |= MountFlags::MS_RDONLY;
+            }
+        }
+
+        let fs_type = fs.name;
+        let res = self.mount_internal(path, device.clone(), fs, flags);
+        audit_mount(&device, path, fs_type, res);
+        res
+    }
+
+    /// Returns the mount table in the order the file systems were mounted
+    pub fn mounts(&self) -> &[MountEntry] {
+        &self.mounts
+    }
+
+    /// Returns the name and the identifiers of the file system on the partition
+    pub fn probe_partition(&self, part: &Arc<Partition>) -> Option<(&'static str, VolumeInfo)> {
+        self.fs_skeletons
+            .iter()
+            .find_map(|skel| (skel.probe)(part).map(|info| (skel.name, info)))
+    }
+
+    /// Finds the partition described by __spec__ which is either LABEL=<label>,
+    /// UUID=<uuid> or the path of its device node, returns the partition and the name of the
+    /// file system on it
+    pub fn find_partition(&self, spec: &str) -> Option<(Weak<Partition>, &'static str)> {
+        if let Some(node) = spec.strip_prefix("/dev/") {
+            let part = blk::devfs::partition_by_node(node)?;
+            let (fs_name, _) = self.probe_partition(&part.upgrade()?)?;
+            return Some((part, fs_name));
         }
 
-        let fs = self
-            .create_new_filesystem(fs_name, part)
-            .map_err(|err| FsMountError::FileSystemInitFailed(err))?;
+        let matches = |info: &VolumeInfo| {
+            if let Some(label) = spec.strip_prefix("LABEL=") {
+                info.label.as_deref() == Some(label)
+            } else if let Some(uuid) = spec.strip_prefix("UUID=") {
+                info.uuid.eq_ignore_ascii_case(uuid)
+            } else {
+                false
+            }
+        };
 
-        self.mount_internal(path, fs)
+        blk::partitions().iter().find_map(|part| {
+            let (fs_name, info) = self.probe_partition(part)?;
+            matches(&info).then(|| (Arc::downgrade(part), fs_name))
+        })
     }
 
     /// Finds the skeleton file system for __skel_name__ and creates a new instance of it
@@ -145,3 +348,46 @@ impl VirtualFileSystem {
         Ok(())
     }
 }
+
+/// Generates the contents of /proc/mounts
+pub fn proc_mounts() -> String {
+    let vfs = VFS.read();
+    let mut s = String::new();
+    for entry in vfs.mounts() {
+        let options = if entry.flags.contains(MountFlags::MS_RDONLY) {
+            "ro"
+        } else {
+            "rw"
+        };
+        s.push_str(&format!(
+            "{} {} {} {} 0 0\n",
+            entry.device, entry.mount_point, entry.fs_type, options
+        ));
+    }
+    s
+}
+
+/// Generates the contents of /proc/blkid, the file systems of the partitions that could
+/// be identified
+pub fn proc_blkid() -> String {
+    let vfs = VFS.read();
+    let mut s = String::new();
+    for part in blk::partitions() {
+        let (fs_name, info) = match vfs.probe_partition(&part) {
+            Some(probed) => probed,
+            None => continue,
+        };
+
+        s.push_str(&format!(
+            "{}: TYPE=\"{}\" UUID=\"{}\"",
+            part.name(),
+            fs_name,
+            info.uuid
+        ));
+        if let Some(label) = info.label {
+            s.push_str(&format!(" LABEL=\"{}\"", label));
+        }
+        s.push('\n');
+    }
+    s
+}
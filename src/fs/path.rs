@@ -1,9 +1,9 @@
-use crate::posix::errno::{Errno, ENAMETOOLONG};
+use crate::{
+    limits::{NAME_MAX, PATH_MAX},
+    posix::errno::{Errno, ENAMETOOLONG},
+};
 
-pub const PATH_COMPONENT_MAX: usize = 256;
-pub const PATH_FULL_MAX: usize = 4096;
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum PathParseError {
     PathComponentTooLong,
     PathTooLong,
@@ -30,7 +30,7 @@ impl<'a> Path<'a> {
             "Paths given to the path parser must be absolute",
         );
 
-        if buff.len() > PATH_FULL_MAX {
+        if buff.len() > PATH_MAX {
             return Err(PathParseError::PathTooLong);
         }
 
@@ -39,7 +39,7 @@ impl<'a> Path<'a> {
             if comp.is_empty() {
                 continue;
             }
-            if comp.len() > PATH_COMPONENT_MAX {
+            if comp.len() > NAME_MAX {
                 return Err(PathParseError::PathComponentTooLong);
             }
 
@@ -76,7 +76,7 @@ impl<'a> Iterator for Path<'a> {
         let end = self.buff.find('/').unwrap_or(self.buff.len());
 
         let segment = &self.buff[..end];
-        debug_assert!(segment.len() < PATH_COMPONENT_MAX);
+        debug_assert!(segment.len() < NAME_MAX);
 
         let next_start_idx = if self.components_left > 1 {
             end + 1
@@ -0,0 +1,216 @@
+//! Anonymous pipes created by the pipe2 syscall
+
+use core::{
+    fmt::Debug,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{collections::VecDeque, sync::Arc};
+use spin::Mutex;
+
+use crate::{
+    limits::{PAGE_SIZE, PIPE_BUF},
+    posix::{PollEvents, Stat, S_IFIFO},
+    scheduler::wait_queue::{WaitQueue, Waiter},
+};
+
+use super::{poll::PollQueue, FsReadError, FsWriteError};
+
+/// Number of bytes a pipe holds before writers have to wait for readers
+const PIPE_CAPACITY: usize = 16 * PAGE_SIZE;
+
+// pipes have no inode on a file system, they are numbered separately for stat and fd2path
+static NEXT_PIPE_ID: AtomicUsize = AtomicUsize::new(1);
+
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    // the ends are shared by every file descriptor referring to them so they are closed once
+    reader_closed: bool,
+    writer_closed: bool,
+}
+
+struct Pipe {
+    id: usize,
+    buffer: Mutex<PipeBuffer>,
+    // readers waiting for data or for the write end to close
+    read_queue: WaitQueue,
+    // writers waiting for space or for the read end to close
+    write_queue: WaitQueue,
+    // pollers of either end, woken whenever the state of the pipe changes
+    poll_queue: PollQueue,
+}
+
+/// One end of a pipe, it is closed once every file descriptor referring to it is dropped
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    write: bool,
+}
+
+/// Creates a pipe, returns the read and the write end
+pub fn create() -> (Arc<PipeEnd>, Arc<PipeEnd>) {
+    let pipe = Arc::new(Pipe {
+        id: NEXT_PIPE_ID.fetch_add(1, Ordering::Relaxed),
+        buffer: Mutex::new(PipeBuffer {
+            data: VecDeque::new(),
+            reader_closed: false,
+            writer_closed: false,
+        }),
+        read_queue: WaitQueue::new(),
+        write_queue: WaitQueue::new(),
+        poll_queue: PollQueue::new(),
+    });
+
+    let read_end = Arc::new(PipeEnd {
+        pipe: pipe.clone(),
+        write: false,
+    });
+    let write_end = Arc::new(PipeEnd { pipe, write: true });
+
+    (read_end, write_end)
+}
+
+impl PipeEnd {
+    pub fn id(&self) -> usize {
+        self.pipe.id
+    }
+
+    /// Waits until there is data in the pipe unless __nonblock__ is set, returns 0 once the
+    /// pipe is empty and the write end is closed
+    pub fn read(&self, buff: &mut [u8], nonblock: bool) -> Result<usize, FsReadError> {
+        loop {
+            let mut buffer = self.pipe.buffer.lock();
+            if !buffer.data.is_empty() {
+                let len = usize::min(buff.len(), buffer.data.len());
+                for (dst, src) in buff.iter_mut().zip(buffer.data.drain(..len)) {
+                    *dst = src;
+                }
+
+                drop(buffer);
+                self.pipe.write_queue.wake_all();
+                self.pipe.poll_queue.wake_all();
+                return Ok(len);
+            }
+
+            if buffer.writer_closed {
+                return Ok(0);
+            }
+
+            if nonblock {
+                return Err(FsReadError::WouldBlock);
+            }
+
+            self.pipe.read_queue.sleep(buffer);
+        }
+    }
+
+    /// Waits until the whole buffer is written unless __nonblock__ is set, writes of at most
+    /// PIPE_BUF bytes are never split
+    pub fn write(&self, buff: &[u8], nonblock: bool) -> Result<usize, FsWriteError> {
+        let mut written = 0;
+        loop {
+            let mut buffer = self.pipe.buffer.lock();
+            if buffer.reader_closed {
+                // TODO: SIGPIPE
+                return match written {
+                    0 => Err(FsWriteError::BrokenPipe),
+                    _ => Ok(written),
+                };
+            }
+
+            let space = PIPE_CAPACITY - buffer.data.len();
+            let count = if buff.len() <= PIPE_BUF && space < buff.len() {
+                0
+            } else {
+                usize::min(space, buff.len() - written)
+            };
+
+            if count > 0 {
+                buffer.data.extend(&buff[written..written + count]);
+                written += count;
+
+                drop(buffer);
+                self.pipe.read_queue.wake_all();
+                self.pipe.poll_queue.wake_all();
+                if written == buff.len() || nonblock {
+                    return Ok(written);
+                }
+                continue;
+            }
+
+            if nonblock {
+                return match written {
+                    0 => Err(FsWriteError::WouldBlock),
+                    _ => Ok(written),
+                };
+            }
+
+            self.pipe.write_queue.sleep(buffer);
+        }
+    }
+
+    /// Returns the events the end is ready for, if __waiter__ is given it is woken once
+    /// that changes. The read end is readable if there is data or the write end is closed,
+    /// the write end is writable if PIPE_BUF bytes fit without blocking.
+    pub fn poll(&self, waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        if let Some(waiter) = waiter {
+            self.pipe.poll_queue.register(waiter);
+        }
+
+        let buffer = self.pipe.buffer.lock();
+        let mut events = PollEvents::empty();
+        if self.write {
+            if buffer.reader_closed {
+                events |= PollEvents::POLLERR;
+            } else if PIPE_CAPACITY - buffer.data.len() >= PIPE_BUF {
+                events |= PollEvents::POLLOUT;
+            }
+        } else {
+            if !buffer.data.is_empty() {
+                events |= PollEvents::POLLIN;
+            }
+            if buffer.writer_closed {
+                events |= PollEvents::POLLHUP;
+            }
+        }
+
+        events
+    }
+
+    pub fn stat(&self, stat_buf: &mut Stat) {
+        let buffer = self.pipe.buffer.lock();
+        *stat_buf = Stat::zero();
+        stat_buf.st_ino = self.pipe.id as u64;
+        stat_buf.st_mode = S_IFIFO | 0o600;
+        stat_buf.st_nlink = 1;
+        stat_buf.st_size = buffer.data.len() as u64;
+        stat_buf.st_blksize = PIPE_BUF as u64;
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut buffer = self.pipe.buffer.lock();
+        if self.write {
+            buffer.writer_closed = true;
+            // readers waiting for data get EOF
+            drop(buffer);
+            self.pipe.read_queue.wake_all();
+            self.pipe.poll_queue.wake_all();
+        } else {
+            buffer.reader_closed = true;
+            // writers waiting for space get EPIPE
+            drop(buffer);
+            self.pipe.write_queue.wake_all();
+            self.pipe.poll_queue.wake_all();
+        }
+    }
+}
+
+impl Debug for PipeEnd {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PipeEnd")
+            .field("id", &self.pipe.id)
+            .field("write", &self.write)
+            .finish()
+    }
+}
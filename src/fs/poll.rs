@@ -0,0 +1,48 @@
+//! Readiness of file descriptors for poll and select, every source of events has a PollQueue
+//! that the waiters of the pollers are registered on
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use crate::{scheduler::wait_queue::Waiter, sync::InterruptMutex};
+
+/// Waiters interested in the readiness of a file, e.g. a pipe or the TTY
+pub struct PollQueue {
+    waiters: InterruptMutex<Vec<Weak<Waiter>>>,
+}
+
+impl PollQueue {
+    /// Adds __waiter__ to the queue unless it is already on it. The queue only holds weak
+    /// references, the waiters that are gone are dropped here.
+    pub fn register(&self, waiter: &Arc<Waiter>) {
+        let mut waiters = self.waiters.lock();
+        waiters.retain(|entry| entry.strong_count() > 0);
+
+        if !waiters
+            .iter()
+            .any(|entry| entry.as_ptr() == Arc::as_ptr(waiter))
+        {
+            waiters.push(Arc::downgrade(waiter));
+        }
+    }
+
+    /// Wakes every waiter on the queue, this can be called from an interrupt handler because
+    /// nothing is freed. The waiters stay registered until they are gone.
+    pub fn wake_all(&self) {
+        let waiters = self.waiters.lock();
+        for entry in waiters.iter() {
+            // the weak reference keeps the allocation alive even if this is the last strong one
+            if let Some(waiter) = entry.upgrade() {
+                waiter.wake();
+            }
+        }
+    }
+
+    pub const fn new() -> PollQueue {
+        PollQueue {
+            waiters: InterruptMutex::new(Vec::new()),
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use spin::Mutex;
+
+use crate::{
+    audit, bootstat, cmdline, kconfig, latency, mm,
+    posix::{MountFlags, Stat, S_IFDIR, S_IFREG},
+    scheduler,
+    scheduler::proc::{self, Process},
+    sysctl::{self, SysctlError},
+};
+
+use super::{
+    context, errors::FsReadDirError, inode::FSInode, path::Path, DirEntry, FileSystem,
+    FileSystemInner, FsCloseError, FsCreateError, FsIoctlError, FsOpenError, FsPathError,
+    FsReadError, FsRemoveError, FsRenameError, FsStatError, FsWriteError, VFS,
+};
+
+/// Generates the contents of a procfs file, it is called every time the file is read
+pub type ProcFsGenerator = fn() -> String;
+
+/// Generates the contents of a file in the directory of a process
+pub type ProcFsProcessGenerator = fn(&Process) -> String;
+
+struct ProcFsFile {
+    name: String,
+    generate: ProcFsGenerator,
+}
+
+struct ProcFsProcessFile {
+    name: String,
+    generate: ProcFsProcessGenerator,
+}
+
+struct ProcFileSystemInner {
+    // the inode of a file is its index + 1
+    files: Vec<ProcFsFile>,
+    // files that are present in the directory of every process
+    process_files: Vec<ProcFsProcessFile>,
+}
+
+static PROCFS_INNER: Mutex<ProcFileSystemInner> = Mutex::new(ProcFileSystemInner {
+    files: Vec::new(),
+    process_files: Vec::new(),
+});
+
+/// The inodes of process directories start here, the pid is stored from bit 16 and the
+/// index of the process file + 1 in the low 16 bits, 0 stands for the directory itself
+const PROCESS_INODE_BASE: u64 = 1 << 32;
+const PROCESS_INODE_PID_SHIFT: u64 = 16;
+
+fn process_inode(pid: usize, file: Option<usize>) -> FSInode {
+    let file = file.map_or(0, |idx| idx as u64 + 1);
+    FSInode::new(PROCESS_INODE_BASE + ((pid as u64) << PROCESS_INODE_PID_SHIFT) + file)
+}
+
+/// Returns the pid and the process file index of the inode
+fn parse_process_inode(inode: FSInode) -> Option<(usize, Option<usize>)> {
+    if inode.0 < PROCESS_INODE_BASE {
+        return None;
+    }
+
+    let val = inode.0 - PROCESS_INODE_BASE;
+    let pid = (val >> PROCESS_INODE_PID_SHIFT) as usize;
+    let file = (val & ((1 << PROCESS_INODE_PID_SHIFT) - 1)) as usize;
+    Some((pid, file.checked_sub(1)))
+}
+
+/// The sysctls in /proc/sys, the inode of a sysctl file is its index + 1 after
+/// SYSCTL_INODE_BASE. A directory has the depth of its path from bit 16 and its
+/// `sysctl::dir_id` in the low 16 bits after SYSCTL_DIR_INODE_BASE.
+const SYSCTL_INODE_BASE: u64 = 1 << 30;
+const SYSCTL_DIR_INODE_BASE: u64 = 1 << 31;
+const SYSCTL_DIR_DEPTH_SHIFT: u64 = 16;
+
+/// Returns the inode of the sysctl file or directory __name__ relative to /proc/sys
+fn sysctl_inode(name: &str) -> Option<FSInode> {
+    if let Some(idx) = sysctl::find(name) {
+        return Some(FSInode::new(SYSCTL_INODE_BASE + idx as u64 + 1));
+    }
+
+    let depth = match name.is_empty() {
+        true => 0,
+        false => name.split('/').count() as u64,
+    };
+    let id = sysctl::dir_id(name)? as u64;
+    Some(FSInode::new(
+        SYSCTL_DIR_INODE_BASE + (depth << SYSCTL_DIR_DEPTH_SHIFT) + id,
+    ))
+}
+
+/// Returns the index of the sysctl if the inode is a sysctl file, None if it is a
+/// directory in /proc/sys. Returns Err if the inode is not in /proc/sys.
+fn parse_sysctl_inode(inode: FSInode) -> Result<Option<usize>, ()> {
+    match inode.0 {
+        SYSCTL_INODE_BASE..SYSCTL_DIR_INODE_BASE => {
+            Ok(Some((inode.0 - SYSCTL_INODE_BASE - 1) as usize))
+        }
+        SYSCTL_DIR_INODE_BASE..PROCESS_INODE_BASE => Ok(None),
+        _ => Err(()),
+    }
+}
+
+/// Returns the path relative to /proc/sys if __path__ is in it
+fn sysctl_path(path: &Path) -> Option<String> {
+    let mut path = path.clone();
+    match path.next() {
+        Some("sys") => Some(path.collect::<Vec<_>>().join("/")),
+        _ => None,
+    }
+}
+
+/// Returns the pid if __name__ is the directory of a running process
+fn parse_pid(name: &str) -> Option<usize> {
+    let pid = name.parse::<usize>().ok()?;
+    proc::get_process(pid).map(|_| pid)
+}
+
+#[derive(Debug)]
+pub enum ProcFsError {
+    AlreadyExists,
+}
+
+#[derive(Debug)]
+struct ProcFileSystem {}
+
+impl ProcFileSystemInner {
+    fn get_file(&self, inode: FSInode) -> &ProcFsFile {
+        &self.files[inode.0 as usize - 1]
+    }
+
+    fn find_file(&self, name: &str) -> Option<FSInode> {
+        self.files
+            .iter()
+            .position(|file| file.name == name)
+            .map(|idx| FSInode::new(idx as u64 + 1))
+    }
+
+    fn find_process_file(&self, name: &str) -> Option<usize> {
+        self.process_files.iter().position(|file| file.name == name)
+    }
+}
+
+impl FileSystemInner for ProcFileSystem {
+    fn open(&mut self, mut path: Path) -> Result<FSInode, FsOpenError> {
+        const NOT_FOUND: FsOpenError = FsOpenError::BadPath(FsPathError::NoSuchFileOrDirectory);
+
+        if let Some(name) = sysctl_path(&path) {
+            return sysctl_inode(&name).ok_or(NOT_FOUND);
+        }
+
+        let inner = PROCFS_INNER.lock();
+        match path.components_left() {
+            1 => {
+                let name = path.next().unwrap();
+                match inner.find_file(name) {
+                    Some(inode) => Ok(inode),
+                    None => parse_pid(name)
+                        .map(|pid| process_inode(pid, None))
+                        .ok_or(NOT_FOUND),
+                }
+            }
+            2 => {
+                let pid = parse_pid(path.next().unwrap()).ok_or(NOT_FOUND)?;
+                let file = inner
+                    .find_process_file(path.next().unwrap())
+                    .ok_or(NOT_FOUND)?;
+                Ok(process_inode(pid, Some(file)))
+            }
+            _ => Err(NOT_FOUND),
+        }
+    }
+
+    fn close(&mut self, _inode: FSInode) -> Result<(), FsCloseError> {
+        Ok(())
+    }
+
+    fn stat(&mut self, inode: FSInode, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        // the size is not known until the file is generated
+        stat_buf.st_ino = inode.0;
+        stat_buf.st_blksize = 4096;
+        stat_buf.st_blocks = 0;
+        stat_buf.st_size = 0;
+        stat_buf.st_dev = 0;
+        stat_buf.st_gid = 0;
+        stat_buf.st_uid = 0;
+        stat_buf.st_nlink = 1;
+        stat_buf.st_mode = match (parse_process_inode(inode), parse_sysctl_inode(inode)) {
+            (Some((_, None)), _) | (_, Ok(None)) => S_IFDIR | 0o555,
+            (_, Ok(Some(idx))) if sysctl::is_writable(idx) => S_IFREG | 0o644,
+            _ => S_IFREG | 0o444,
+        };
+
+        Ok(())
+    }
+
+    fn read(&mut self, inode: FSInode, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        let contents = match parse_process_inode(inode) {
+            Some((pid, Some(file))) => {
+                let generate = PROCFS_INNER.lock().process_files[file].generate;
+                // the process might have exited since the file was opened
+                match proc::get_process(pid) {
+                    Some(proc) => generate(&proc.lock()),
+                    None => return Ok(0),
+                }
+            }
+            Some((_, None)) => return Err(FsReadError::BadFileDescriptor),
+            None => match parse_sysctl_inode(inode) {
+                Ok(Some(idx)) => sysctl::read_text(idx),
+                Ok(None) => return Err(FsReadError::BadFileDescriptor),
+                Err(()) => {
+                    let generate = PROCFS_INNER.lock().get_file(inode).generate;
+                    generate()
+                }
+            },
+        };
+        let contents = contents.as_bytes();
+
+        if off >= contents.len() {
+            return Ok(0);
+        }
+
+        let size = usize::min(buff.len(), contents.len() - off);
+        buff[..size].copy_from_slice(&contents[off..off + size]);
+
+        Ok(size)
+    }
+
+    fn write(&mut self, inode: FSInode, off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        // only sysctls can be written, the whole value has to be written at once
+        let idx = match parse_sysctl_inode(inode) {
+            Ok(Some(idx)) => idx,
+            _ => return Err(FsWriteError::ReadOnlyFileSystem),
+        };
+
+        // the sysctls change the whole system, e.g. they can turn off the audit log
+        if context::current().euid != 0 {
+            return Err(FsWriteError::PermissionDenied);
+        }
+
+        if off != 0 {
+            return Err(FsWriteError::InvalidArgument);
+        }
+
+        let text = core::str::from_utf8(buff).map_err(|_| FsWriteError::InvalidArgument)?;
+        sysctl::write_text(idx, text).map_err(|err| match err {
+            SysctlError::ReadOnly => FsWriteError::PermissionDenied,
+            _ => FsWriteError::InvalidArgument,
+        })?;
+
+        Ok(buff.len())
+    }
+
+    fn ioctl(&mut self, _inode: FSInode, _req: usize, _arg: usize) -> Result<usize, FsIoctlError> {
+        Err(FsIoctlError::InvalidArgument)
+    }
+
+    fn read_dir(&mut self, mut path: Path) -> Result<Vec<DirEntry>, FsReadDirError> {
+        const NOT_FOUND: FsReadDirError =
+            FsReadDirError::BadPath(FsPathError::NoSuchFileOrDirectory);
+
+        if let Some(dir) = sysctl_path(&path) {
+            let entries = sysctl::read_dir(&dir).ok_or(NOT_FOUND)?;
+            return Ok(entries
+                .into_iter()
+                .map(|entry| {
+                    let name = match dir.is_empty() {
+                        true => entry.name.clone(),
+                        false => format!("{}/{}", dir, entry.name),
+                    };
+                    DirEntry {
+                        inode: sysctl_inode(&name).unwrap(),
+                        name: entry.name,
+                    }
+                })
+                .collect());
+        }
+
+        let inner = PROCFS_INNER.lock();
+        match path.components_left() {
+            0 => {
+                let mut entries: Vec<DirEntry> = inner
+                    .files
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, file)| DirEntry {
+                        name: file.name.clone(),
+                        inode: FSInode::new(idx as u64 + 1),
+                    })
+                    .collect();
+                // processes are locked while listing them
+                drop(inner);
+
+                entries.push(DirEntry {
+                    name: "sys".to_string(),
+                    inode: sysctl_inode("").unwrap(),
+                });
+
+                entries.extend(proc::processes().into_iter().map(|proc| {
+                    let pid = proc.lock().pid;
+                    DirEntry {
+                        name: pid.to_string(),
+                        inode: process_inode(pid, None),
+                    }
+                }));
+
+                Ok(entries)
+            }
+            1 => {
+                let pid = parse_pid(path.next().unwrap()).ok_or(NOT_FOUND)?;
+                Ok(inner
+                    .process_files
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, file)| DirEntry {
+                        name: file.name.clone(),
+                        inode: process_inode(pid, Some(idx)),
+                    })
+                    .collect())
+            }
+            _ => Err(NOT_FOUND),
+        }
+    }
+
+    fn remove(&mut self, _path: Path) -> Result<(), FsRemoveError> {
+        Err(FsRemoveError::ReadOnlyFileSystem)
+    }
+
+    fn rename(&mut self, _old_path: Path, _new_path: Path) -> Result<(), FsRenameError> {
+        Err(FsRenameError::ReadOnlyFileSystem)
+    }
+
+    fn create(&mut self, _path: Path) -> Result<FSInode, FsCreateError> {
+        Err(FsCreateError::ReadOnlyFileSystem)
+    }
+
+    fn mkdir(&mut self, _path: Path) -> Result<(), FsCreateError> {
+        Err(FsCreateError::ReadOnlyFileSystem)
+    }
+
+    fn truncate(&mut self, inode: FSInode) -> Result<(), FsWriteError> {
+        // sysctls are opened with O_TRUNC by shell redirections, there is nothing to remove
+        match parse_sysctl_inode(inode) {
+            Ok(Some(_)) => Ok(()),
+            _ => Err(FsWriteError::ReadOnlyFileSystem),
+        }
+    }
+
+    // files can be registered at any time
+    fn cache_negative_entries(&self) -> bool {
+        false
+    }
+}
+
+/// Adds a file to the root of procfs, its contents are generated by __generate__ on every read
+pub fn register_procfs_file(name: &str, generate: ProcFsGenerator) -> Result<(), ProcFsError> {
+    let mut inner = PROCFS_INNER.lock();
+    if inner.find_file(name).is_some() {
+        return Err(ProcFsError::AlreadyExists);
+    }
+
+    inner.files.push(ProcFsFile {
+        name: name.to_string(),
+        generate,
+    });
+
+    Ok(())
+}
+
+/// Adds a file to the directory of every process, its contents are generated by
+/// __generate__ on every read
+pub fn register_procfs_process_file(
+    name: &str,
+    generate: ProcFsProcessGenerator,
+) -> Result<(), ProcFsError> {
+    let mut inner = PROCFS_INNER.lock();
+    if inner.find_process_file(name).is_some() {
+        return Err(ProcFsError::AlreadyExists);
+    }
+
+    inner.process_files.push(ProcFsProcessFile {
+        name: name.to_string(),
+        generate,
+    });
+
+    Ok(())
+}
+
+pub fn init() {
+    // the sysctls are writable, every other file refuses writes by itself
+    let mut vfs = VFS.write();
+    vfs.mount_special(
+        "/proc",
+        FileSystem {
+            name: "procfs",
+            inner: Box::new(ProcFileSystem {}),
+        },
+        MountFlags::empty(),
+    )
+    .unwrap();
+
+    register_procfs_file("config", kconfig::config_text).unwrap();
+    register_procfs_file("mounts", super::mount::proc_mounts).unwrap();
+    register_procfs_file("meminfo", mm::meminfo).unwrap();
+    register_procfs_file("vmlayout", mm::proc_vmlayout).unwrap();
+    register_procfs_file("slabinfo", mm::slab::proc_slabinfo).unwrap();
+    register_procfs_file("blkid", super::mount::proc_blkid).unwrap();
+    register_procfs_file("cmdline", cmdline::proc_cmdline).unwrap();
+    register_procfs_file("bootstat", bootstat::proc_bootstat).unwrap();
+    register_procfs_file("audit", audit::proc_audit).unwrap();
+    register_procfs_file("schedstat", scheduler::proc_schedstat).unwrap();
+    register_procfs_file("latency", latency::proc_latency).unwrap();
+
+    register_procfs_process_file("maps", proc::proc_maps).unwrap();
+    register_procfs_process_file("status", proc::proc_status).unwrap();
+    register_procfs_process_file("cmdline", proc::proc_cmdline).unwrap();
+}
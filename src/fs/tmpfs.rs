@@ -0,0 +1,408 @@
+//! A file system that keeps its files in pages allocated from the kernel heap, nothing is
+//! written to a disk and the files are gone once it is unmounted. It is mounted on /tmp so
+//! there is a writable file system no matter what the root file system supports.
+
+use alloc::{
+    boxed::Box,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    mm::phys::{FRAME_SIZE, PHYS_ALLOCATOR},
+    posix::{MountFlags, Stat, S_IFDIR, S_IFREG},
+};
+
+use super::{
+    context, errors::FsReadDirError, inode::FSInode, path::Path, DirEntry, FileSystem,
+    FileSystemInner, FsCloseError, FsCreateError, FsIoctlError, FsOpenError, FsPathError,
+    FsReadError, FsRemoveError, FsRenameError, FsStatError, FsWriteError, VFS,
+};
+
+const TMPFS_PAGE_SIZE: usize = FRAME_SIZE;
+const ROOT_INODE: u64 = 0;
+
+#[derive(Debug)]
+enum TmpFsNode {
+    /// The entries of the directory and their inodes
+    Directory(BTreeMap<String, u64>),
+    /// The contents of the file, the last page is only used up to the size
+    File { pages: Vec<Box<[u8]>>, size: usize },
+}
+
+#[derive(Debug)]
+struct TmpFileSystem {
+    nodes: BTreeMap<u64, TmpFsNode>,
+    /// The user and group that own the nodes, the root directory is owned by root
+    owners: BTreeMap<u64, (usize, usize)>,
+    next_inode: u64,
+    /// Number of times the inode of a file was handed out and not closed yet
+    open_counts: BTreeMap<u64, usize>,
+    /// Files that were removed while they were open, they are freed on the last close
+    orphans: BTreeSet<u64>,
+    /// Number of pages the files of the file system use
+    used_pages: usize,
+    /// Writes fail with ENOSPC once the files would use more pages than this
+    max_pages: usize,
+}
+
+impl TmpFileSystem {
+    fn new(max_pages: usize) -> TmpFileSystem {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INODE, TmpFsNode::Directory(BTreeMap::new()));
+
+        TmpFileSystem {
+            nodes,
+            owners: BTreeMap::new(),
+            next_inode: ROOT_INODE + 1,
+            open_counts: BTreeMap::new(),
+            orphans: BTreeSet::new(),
+            used_pages: 0,
+            max_pages,
+        }
+    }
+
+    /// Returns the inode of the file or directory at __path__
+    fn lookup(&self, path: Path) -> Result<u64, FsPathError> {
+        let mut inode = ROOT_INODE;
+        for name in path {
+            inode = match &self.nodes[&inode] {
+                TmpFsNode::Directory(entries) => *entries
+                    .get(name)
+                    .ok_or(FsPathError::NoSuchFileOrDirectory)?,
+                TmpFsNode::File { .. } => return Err(FsPathError::NotADirectory),
+            };
+        }
+
+        Ok(inode)
+    }
+
+    /// Returns the inode of the directory that contains __path__ and the name of the last
+    /// component, __path__ can not be the root
+    fn lookup_parent<'a>(&self, path: Path<'a>) -> Result<(u64, &'a str), FsPathError> {
+        let comps = path.components_left();
+        assert!(comps > 0);
+
+        let parent = self.lookup(path.clone().shorten(comps - 1))?;
+        match self.nodes[&parent] {
+            TmpFsNode::Directory(_) => Ok((parent, path.last().unwrap())),
+            TmpFsNode::File { .. } => Err(FsPathError::NotADirectory),
+        }
+    }
+
+    fn dir_entries(&mut self, inode: u64) -> &mut BTreeMap<String, u64> {
+        match self.nodes.get_mut(&inode) {
+            Some(TmpFsNode::Directory(entries)) => entries,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Adds __node__ to the directory __parent__ as __name__, returns its inode
+    fn add_node(&mut self, parent: u64, name: &str, node: TmpFsNode) -> u64 {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+
+        // the node is owned by the effective user and group of the process creating it
+        let context = context::current();
+        self.nodes.insert(inode, node);
+        self.owners.insert(inode, (context.euid, context.egid));
+        self.dir_entries(parent).insert(name.to_string(), inode);
+        inode
+    }
+
+    /// Counts an inode that is handed out, only files are counted since the VFS does not keep
+    /// the inodes of directories
+    fn hand_out(&mut self, inode: u64) -> FSInode {
+        if let Some(TmpFsNode::File { .. }) = self.nodes.get(&inode) {
+            *self.open_counts.entry(inode).or_default() += 1;
+        }
+        FSInode::new(inode)
+    }
+
+    /// Frees a node that was removed from its directory, or leaves it to the last close if
+    /// it is still open
+    fn unlink_node(&mut self, inode: u64) {
+        if self.open_counts.contains_key(&inode) {
+            self.orphans.insert(inode);
+        } else {
+            self.free_node(inode);
+        }
+    }
+
+    /// Frees the node, it has to be removed from its directory already
+    fn free_node(&mut self, inode: u64) {
+        self.owners.remove(&inode);
+        if let Some(TmpFsNode::File { pages, .. }) = self.nodes.remove(&inode) {
+            self.used_pages -= pages.len();
+        }
+    }
+
+    fn is_empty_dir(&self, inode: u64) -> bool {
+        matches!(self.nodes.get(&inode), Some(TmpFsNode::Directory(entries)) if entries.is_empty())
+    }
+}
+
+impl FileSystemInner for TmpFileSystem {
+    fn open(&mut self, path: Path) -> Result<FSInode, FsOpenError> {
+        let inode = self.lookup(path).map_err(FsOpenError::BadPath)?;
+        Ok(self.hand_out(inode))
+    }
+
+    fn close(&mut self, inode: FSInode) -> Result<(), FsCloseError> {
+        if let Entry::Occupied(mut count) = self.open_counts.entry(inode.0) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+                if self.orphans.remove(&inode.0) {
+                    self.free_node(inode.0);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, inode: FSInode, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        let (pages, size) = match self.nodes.get(&inode.0) {
+            Some(TmpFsNode::File { pages, size }) => (pages, *size),
+            _ => return Err(FsReadError::BadFileDescriptor),
+        };
+
+        let len = buff.len().min(size.saturating_sub(off));
+        let mut done = 0;
+        while done < len {
+            let pos = off + done;
+            let page_off = pos % TMPFS_PAGE_SIZE;
+            let count = (TMPFS_PAGE_SIZE - page_off).min(len - done);
+
+            let page = &pages[pos / TMPFS_PAGE_SIZE];
+            buff[done..done + count].copy_from_slice(&page[page_off..page_off + count]);
+            done += count;
+        }
+
+        Ok(len)
+    }
+
+    fn write(&mut self, inode: FSInode, off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        let free_pages = self.max_pages - self.used_pages;
+        let (pages, size) = match self.nodes.get_mut(&inode.0) {
+            Some(TmpFsNode::File { pages, size }) => (pages, size),
+            _ => return Err(FsWriteError::BadFileDescriptor),
+        };
+
+        // the write is cut short at the page the file system runs out of space
+        let end = off
+            .checked_add(buff.len())
+            .ok_or(FsWriteError::InvalidArgument)?;
+        let max_end = (pages.len() + free_pages) * TMPFS_PAGE_SIZE;
+        let len = end.min(max_end).saturating_sub(off);
+        if len == 0 {
+            return Err(FsWriteError::NoSpace);
+        }
+
+        // pages skipped by writing past the end of the file are zeroed
+        let needed_pages = (off + len).div_ceil(TMPFS_PAGE_SIZE);
+        let new_pages = needed_pages.saturating_sub(pages.len());
+        while pages.len() < needed_pages {
+            pages.push(vec![0; TMPFS_PAGE_SIZE].into_boxed_slice());
+        }
+
+        let mut done = 0;
+        while done < len {
+            let pos = off + done;
+            let page_off = pos % TMPFS_PAGE_SIZE;
+            let count = (TMPFS_PAGE_SIZE - page_off).min(len - done);
+
+            let page = &mut pages[pos / TMPFS_PAGE_SIZE];
+            page[page_off..page_off + count].copy_from_slice(&buff[done..done + count]);
+            done += count;
+        }
+
+        *size = (*size).max(off + len);
+        self.used_pages += new_pages;
+
+        Ok(len)
+    }
+
+    fn stat(&mut self, inode: FSInode, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        let (mode, size, pages) = match self.nodes.get(&inode.0) {
+            Some(TmpFsNode::Directory(_)) => (S_IFDIR | 0o777, 0, 0),
+            Some(TmpFsNode::File { pages, size }) => (S_IFREG | 0o777, *size, pages.len()),
+            None => return Err(FsStatError::BadPath(FsPathError::NoSuchFileOrDirectory)),
+        };
+        let (uid, gid) = self.owners.get(&inode.0).copied().unwrap_or((0, 0));
+
+        stat_buf.st_ino = inode.0;
+        stat_buf.st_blksize = TMPFS_PAGE_SIZE as u64;
+        stat_buf.st_blocks = pages as u64;
+        stat_buf.st_size = size as u64;
+        stat_buf.st_dev = 0;
+        stat_buf.st_gid = gid as u32;
+        stat_buf.st_uid = uid as u32;
+        // removed files that are still open have no links
+        stat_buf.st_nlink = if self.orphans.contains(&inode.0) {
+            0
+        } else {
+            1
+        };
+        stat_buf.st_mode = mode;
+
+        Ok(())
+    }
+
+    fn ioctl(&mut self, _inode: FSInode, _req: usize, _arg: usize) -> Result<usize, FsIoctlError> {
+        Err(FsIoctlError::NotATerminal)
+    }
+
+    fn read_dir(&mut self, path: Path) -> Result<Vec<DirEntry>, FsReadDirError> {
+        let inode = self.lookup(path).map_err(FsReadDirError::BadPath)?;
+        let entries: Vec<(String, u64)> = match &self.nodes[&inode] {
+            TmpFsNode::Directory(entries) => entries
+                .iter()
+                .map(|(name, &inode)| (name.clone(), inode))
+                .collect(),
+            TmpFsNode::File { .. } => {
+                return Err(FsReadDirError::BadPath(FsPathError::NotADirectory))
+            }
+        };
+
+        // the VFS closes the inodes of the entries just like opened ones
+        Ok(entries
+            .into_iter()
+            .map(|(name, inode)| DirEntry {
+                name,
+                inode: self.hand_out(inode),
+            })
+            .collect())
+    }
+
+    fn remove(&mut self, path: Path) -> Result<(), FsRemoveError> {
+        if path.components_left() == 0 {
+            return Err(FsRemoveError::Busy);
+        }
+
+        let (parent, name) = self.lookup_parent(path).map_err(FsRemoveError::BadPath)?;
+        let inode = *self
+            .dir_entries(parent)
+            .get(name)
+            .ok_or(FsRemoveError::BadPath(FsPathError::NoSuchFileOrDirectory))?;
+
+        if matches!(self.nodes[&inode], TmpFsNode::Directory(_)) && !self.is_empty_dir(inode) {
+            return Err(FsRemoveError::DirectoryNotEmpty);
+        }
+
+        self.dir_entries(parent).remove(name);
+        self.unlink_node(inode);
+
+        Ok(())
+    }
+
+    fn rename(&mut self, old_path: Path, new_path: Path) -> Result<(), FsRenameError> {
+        if old_path.components_left() == 0 || new_path.components_left() == 0 {
+            return Err(FsRenameError::Busy);
+        }
+
+        let (old_parent, old_name) = self
+            .lookup_parent(old_path)
+            .map_err(FsRenameError::BadPath)?;
+        let inode = *self
+            .dir_entries(old_parent)
+            .get(old_name)
+            .ok_or(FsRenameError::BadPath(FsPathError::NoSuchFileOrDirectory))?;
+        let old_is_dir = matches!(self.nodes[&inode], TmpFsNode::Directory(_));
+
+        let (new_parent, new_name) = self
+            .lookup_parent(new_path)
+            .map_err(FsRenameError::BadPath)?;
+
+        if let Some(&existing) = self.dir_entries(new_parent).get(new_name) {
+            if existing == inode {
+                return Ok(());
+            }
+
+            let existing_is_dir = matches!(self.nodes[&existing], TmpFsNode::Directory(_));
+            match (old_is_dir, existing_is_dir) {
+                (false, true) => return Err(FsRenameError::IsADirectory),
+                (true, false) => return Err(FsRenameError::BadPath(FsPathError::NotADirectory)),
+                (true, true) if !self.is_empty_dir(existing) => {
+                    return Err(FsRenameError::DirectoryNotEmpty)
+                }
+                _ => {}
+            }
+
+            self.unlink_node(existing);
+        }
+
+        self.dir_entries(old_parent).remove(old_name);
+        self.dir_entries(new_parent)
+            .insert(new_name.to_string(), inode);
+
+        Ok(())
+    }
+
+    fn create(&mut self, path: Path) -> Result<FSInode, FsCreateError> {
+        if path.components_left() == 0 {
+            return Err(FsCreateError::AlreadyExists);
+        }
+
+        let (parent, name) = self.lookup_parent(path).map_err(FsCreateError::BadPath)?;
+        if self.dir_entries(parent).contains_key(name) {
+            return Err(FsCreateError::AlreadyExists);
+        }
+
+        let node = TmpFsNode::File {
+            pages: Vec::new(),
+            size: 0,
+        };
+        let inode = self.add_node(parent, name, node);
+        Ok(self.hand_out(inode))
+    }
+
+    fn mkdir(&mut self, path: Path) -> Result<(), FsCreateError> {
+        if path.components_left() == 0 {
+            return Err(FsCreateError::AlreadyExists);
+        }
+
+        let (parent, name) = self.lookup_parent(path).map_err(FsCreateError::BadPath)?;
+        if self.dir_entries(parent).contains_key(name) {
+            return Err(FsCreateError::AlreadyExists);
+        }
+
+        self.add_node(parent, name, TmpFsNode::Directory(BTreeMap::new()));
+        Ok(())
+    }
+
+    fn truncate(&mut self, inode: FSInode) -> Result<(), FsWriteError> {
+        let freed = match self.nodes.get_mut(&inode.0) {
+            Some(TmpFsNode::File { pages, size }) => {
+                *size = 0;
+                core::mem::take(pages).len()
+            }
+            _ => return Err(FsWriteError::BadFileDescriptor),
+        };
+        self.used_pages -= freed;
+
+        Ok(())
+    }
+}
+
+pub fn init() {
+    // like on other systems the files can use at most half of the memory
+    let (total_frames, _) = PHYS_ALLOCATOR.lock().usage();
+    let max_pages = total_frames * FRAME_SIZE / TMPFS_PAGE_SIZE / 2;
+
+    let mut vfs = VFS.write();
+    let res = vfs.mount_special(
+        "/tmp",
+        FileSystem {
+            name: "tmpfs",
+            inner: Box::new(TmpFileSystem::new(max_pages)),
+        },
+        MountFlags::empty(),
+    );
+    if let Err(err) = res {
+        warn!("TMPFS: failed to mount on /tmp: {:?}", err);
+    }
+}
@@ -0,0 +1,169 @@
+//! The event nodes /dev/input/eventN, a read returns whole `InputEvent` records and blocks
+//! until at least one is queued. Every reader consumes from the same queue so the events are
+//! split between the processes reading the same node.
+
+use alloc::{format, sync::Arc};
+use core::{mem::size_of, slice};
+use spin::Once;
+
+use crate::{
+    arch::x86_64::usercopy::{Out, UserPtr, UserSlice},
+    fs::{
+        devfs::{self, DevFsDevice},
+        errors::{FsIoctlError, FsReadError, FsStatError, FsWriteError},
+        path::Path,
+    },
+    posix::{
+        input::{InputEvent, EVIOCGNAME, EVIOCGVERSION, EV_VERSION, IOC_SIZE_MASK, IOC_SIZE_SHIFT},
+        PollEvents, Stat, S_IFCHR,
+    },
+    scheduler::{proc, wait_queue::Waiter},
+};
+
+use super::InputDevice;
+
+const INPUT_DEVICE_MAJOR: u16 = 13;
+/// The minor of event0, the same as on Linux
+const EVENT_MINOR_BASE: u16 = 64;
+
+struct EventDevice;
+
+static OPERATIONS_REGISTERED: Once = Once::new();
+
+fn device(minor: u16) -> Result<Arc<InputDevice>, ()> {
+    let index = minor.checked_sub(EVENT_MINOR_BASE).ok_or(())?;
+    super::get_device(index as usize).ok_or(())
+}
+
+impl DevFsDevice for EventDevice {
+    fn read(&self, minor: u16, _off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        let device = device(minor).map_err(|_| FsReadError::IoError)?;
+        let max_events = buff.len() / size_of::<InputEvent>();
+        if max_events == 0 {
+            return Err(FsReadError::InvalidArgument);
+        }
+
+        let pid = proc::current_pid();
+        let waiter = Arc::new(Waiter::new());
+        loop {
+            // registered before checking so events arriving in between are not missed
+            device.poll_queue.register(&waiter);
+            proc::register_signal_waiter(&waiter);
+
+            {
+                let mut events = device.events.lock();
+                if !events.is_empty() {
+                    let count = usize::min(events.len(), max_events);
+                    for (i, event) in events.iter().take(count).enumerate() {
+                        let bytes = unsafe {
+                            slice::from_raw_parts(
+                                event as *const InputEvent as *const u8,
+                                size_of::<InputEvent>(),
+                            )
+                        };
+                        let start = i * size_of::<InputEvent>();
+                        buff[start..start + bytes.len()].copy_from_slice(bytes);
+                    }
+                    events.consume(count);
+
+                    return Ok(count * size_of::<InputEvent>());
+                }
+            }
+
+            if !device.is_connected() {
+                return Err(FsReadError::NoDevice);
+            }
+
+            if pid.is_some_and(|pid| proc::pending_signal(pid).is_some()) {
+                return Err(FsReadError::Interrupted);
+            }
+
+            waiter.block();
+        }
+    }
+
+    // injecting events is not supported
+    fn write(&self, _minor: u16, _off: usize, _buff: &[u8]) -> Result<usize, FsWriteError> {
+        Err(FsWriteError::InvalidArgument)
+    }
+
+    fn ioctl(&self, minor: u16, req: usize, arg: usize) -> Result<usize, FsIoctlError> {
+        let device = device(minor).map_err(|_| FsIoctlError::InvalidArgument)?;
+
+        if req == EVIOCGVERSION {
+            let ptr = UserPtr::<u32, Out>::new(arg as u64);
+            ptr.write(&EV_VERSION)
+                .map_err(|_| FsIoctlError::BadAddress)?;
+            return Ok(0);
+        }
+
+        // the size of the buffer is encoded in the request
+        if req & !(IOC_SIZE_MASK << IOC_SIZE_SHIFT) == EVIOCGNAME {
+            let len = (req >> IOC_SIZE_SHIFT) & IOC_SIZE_MASK;
+            if len == 0 {
+                return Ok(0);
+            }
+
+            // the name is truncated to the buffer and always null terminated
+            let mut name = device.name().as_bytes().to_vec();
+            name.truncate(len - 1);
+            name.push(0);
+            UserSlice::<Out>::new(arg as u64, name.len())
+                .and_then(|ptr| ptr.write(&name))
+                .map_err(|_| FsIoctlError::BadAddress)?;
+            return Ok(name.len());
+        }
+
+        Err(FsIoctlError::InvalidArgument)
+    }
+
+    fn stat(&self, minor: u16, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        stat_buf.st_blksize = 4096;
+        stat_buf.st_blocks = 0;
+        stat_buf.st_size = 0;
+        stat_buf.st_dev = 0;
+        stat_buf.st_rdev = (INPUT_DEVICE_MAJOR as u64) << 8 | minor as u64;
+        stat_buf.st_gid = 0;
+        stat_buf.st_uid = 0;
+        stat_buf.st_nlink = 1;
+        stat_buf.st_mode = S_IFCHR | 0o660;
+
+        Ok(())
+    }
+
+    fn poll(&self, minor: u16, _off: usize, waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        let device = match device(minor) {
+            Ok(device) => device,
+            Err(_) => return PollEvents::empty(),
+        };
+
+        if let Some(waiter) = waiter {
+            device.poll_queue.register(waiter);
+        }
+
+        let mut events = PollEvents::empty();
+        if !device.events.lock().is_empty() {
+            events |= PollEvents::POLLIN;
+        }
+        if !device.is_connected() {
+            events |= PollEvents::POLLHUP;
+        }
+
+        events
+    }
+}
+
+/// Creates the /dev/input/eventN node of __device__
+pub fn register_node(device: &InputDevice) {
+    OPERATIONS_REGISTERED.call_once(|| {
+        devfs::register_devfs_node_operations(INPUT_DEVICE_MAJOR, Arc::new(EventDevice)).unwrap();
+    });
+
+    let path = format!("/input/event{}", device.index());
+    let minor = EVENT_MINOR_BASE + device.index() as u16;
+    if let Err(err) =
+        devfs::register_devfs_node(Path::new(&path).unwrap(), INPUT_DEVICE_MAJOR, minor)
+    {
+        warn!("INPUT: failed to create /dev{}: {:?}", path, err);
+    }
+}
@@ -0,0 +1,117 @@
+//! Input devices like the keyboard and the mouse. The drivers report the events of their
+//! devices here, from their interrupt handlers, and userspace reads them as Linux input events
+//! from /dev/input/eventN. A device that is unplugged keeps its node, reading it fails with
+//! ENODEV until the device is plugged in again.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::{
+    fs::poll::PollQueue,
+    posix::input::{InputEvent, EV_SYN, SYN_DROPPED, SYN_REPORT},
+    sync::InterruptMutex,
+    time,
+    utils::ring_buffer::RingBuffer,
+};
+
+pub mod devfs;
+
+const EVENT_QUEUE_SIZE: usize = 256;
+
+pub struct InputDevice {
+    name: &'static str,
+    index: usize,
+    events: InterruptMutex<RingBuffer<InputEvent, EVENT_QUEUE_SIZE>>,
+    poll_queue: PollQueue,
+    connected: AtomicBool,
+}
+
+static INPUT_DEVICES: Mutex<Vec<Arc<InputDevice>>> = Mutex::new(Vec::new());
+
+impl InputDevice {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Marks the device as removed, the readers get the events that are still queued and
+    /// then ENODEV. This does not allocate so it can be called from an interrupt handler.
+    pub fn disconnect(&self) {
+        if self.connected.swap(false, Ordering::Relaxed) {
+            log!("INPUT: {} removed", self.name);
+        }
+
+        self.poll_queue.wake_all();
+    }
+
+    /// Marks a removed device as connected again, e.g. when the same keyboard is plugged in
+    pub fn reconnect(&self) {
+        if !self.connected.swap(true, Ordering::Relaxed) {
+            log!("INPUT: {} connected", self.name);
+        }
+    }
+
+    /// Queues an event, the readers are woken once the packet is ended with `sync`. This does
+    /// not allocate so it can be called from an interrupt handler.
+    pub fn report(&self, event_type: u16, code: u16, value: i32) {
+        let ns = time::realtime_ns();
+        let event = InputEvent {
+            time_sec: (ns / 1_000_000_000) as i64,
+            time_usec: (ns % 1_000_000_000 / 1000) as i64,
+            event_type,
+            code,
+            value,
+        };
+
+        let mut events = self.events.lock();
+        if events.push(event).is_err() {
+            // like Linux the queued events are dropped, the readers discard the events after
+            // SYN_DROPPED until the next SYN_REPORT
+            events.clear();
+            let _ = events.push(InputEvent {
+                code: SYN_DROPPED,
+                ..event
+            });
+            let _ = events.push(event);
+        }
+    }
+
+    /// Ends the packet of the events reported since the last call and wakes up the readers
+    pub fn sync(&self) {
+        self.report(EV_SYN, SYN_REPORT, 0);
+        self.poll_queue.wake_all();
+    }
+}
+
+/// Registers an input device and creates its /dev/input/eventN node, the node is created
+/// even if /dev is not mounted yet. It allocates so it can not be called from an interrupt
+/// handler.
+pub fn register_device(name: &'static str) -> Arc<InputDevice> {
+    let device = {
+        let mut devices = INPUT_DEVICES.lock();
+        let device = Arc::new(InputDevice {
+            name,
+            index: devices.len(),
+            events: InterruptMutex::new(RingBuffer::new(InputEvent::default())),
+            poll_queue: PollQueue::new(),
+            connected: AtomicBool::new(true),
+        });
+        devices.push(device.clone());
+        device
+    };
+
+    devfs::register_node(&device);
+    device
+}
+
+pub fn get_device(index: usize) -> Option<Arc<InputDevice>> {
+    INPUT_DEVICES.lock().get(index).cloned()
+}
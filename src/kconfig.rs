@@ -0,0 +1,41 @@
+//! Compile-time configuration of the kernel, build.rs passes the options of kernel.cfg
+//! along with the git revision and the target triple
+
+use alloc::string::String;
+
+/// Every option of kernel.cfg in name=yes or name=no form separated by semicolons
+const KERNEL_CONFIG: &str = env!("ROOK_KERNEL_CONFIG");
+
+/// Short hash of the git revision the kernel was built from
+pub const GIT_HASH: &str = env!("ROOK_GIT_HASH");
+
+/// Target triple the kernel was built for
+pub const TARGET: &str = env!("ROOK_TARGET");
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Returns every option of the kernel config and whether it is enabled
+pub fn options() -> impl Iterator<Item = (&'static str, bool)> {
+    KERNEL_CONFIG
+        .split(';')
+        .filter_map(|opt| opt.split_once('='))
+        .map(|(name, val)| (name, val == "yes"))
+}
+
+/// Returns whether a kernel config option is enabled
+pub fn is_enabled(name: &str) -> bool {
+    options().any(|(opt, enabled)| opt == name && enabled)
+}
+
+/// Formats the kernel config the same way kernel.cfg is formatted,
+/// the build information is prepended as comments
+pub fn config_text() -> String {
+    let mut text = format!("# rook {} ({})\n# target: {}\n", VERSION, GIT_HASH, TARGET);
+
+    for (name, enabled) in options() {
+        text.push_str(name);
+        text.push_str(if enabled { "=yes\n" } else { "=no\n" });
+    }
+
+    text
+}
@@ -0,0 +1,1075 @@
+//! A remote stub for gdb on a serial port. kgdb=ttyS<n> starts it, gdb connects with
+//! "target remote" to the other end of the port. The stub runs when a breakpoint is hit, when a
+//! single step finished or when gdb sends a break (Ctrl-C), the other CPUs are stopped with an
+//! NMI until the kernel continues. Every thread of the scheduler is a thread in gdb, the
+//! registers of the ones that are not running are the ones saved when they were switched away
+//! from. gdb sends a break as a single byte while the kernel runs, the port is polled for it
+//! on every timer tick.
+//!
+//! With QEMU the second serial port can be a TCP socket:
+//! "-serial stdio -serial tcp::1234,server,nowait" and the kernel command line
+//! "kgdb=ttyS1 kgdbwait", gdb then connects with "target remote :1234".
+//!
+//! Only the packets gdb needs are implemented: the registers are read and written with g and G,
+//! memory with m and M, breakpoints are software breakpoints set with Z0 and z0. gdb removes
+//! the breakpoints every time the kernel stops, so stepping over one is left to it. Breakpoints
+//! in userspace are not handled by the stub.
+
+use core::{
+    fmt::{self, Write},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    arch::x86_64::{
+        get_cr0, get_current_pml4_phys,
+        registers::{GeneralRegisters, InterruptRegisters, IretRegisters, RegisterState},
+        set_cr0, smp,
+        usercopy::UserAccess,
+        CR0Flags, Rflags,
+    },
+    cmdline,
+    drivers::serial::PolledPort,
+    limits::CPU_MAX,
+    mm::PhysAddr,
+    scheduler::{
+        thread::{Thread, ThreadID, ThreadInner},
+        SCHEDULER,
+    },
+};
+
+/// Size of the packets in both directions, gdb is told about it with qSupported
+const PACKET_MAX: usize = 4096;
+const BREAKPOINTS_MAX: usize = 32;
+/// Number of threads in a reply to qfThreadInfo or qsThreadInfo
+const THREADS_PER_PACKET: usize = 256;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+const INT3: u8 = 0xCC;
+/// Sent by gdb to stop the kernel while it runs
+const BREAK_IN: u8 = 0x03;
+
+/// The signals reported to gdb
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The id of the only thread gdb sees if the scheduler is locked
+const FALLBACK_THREAD_ID: usize = 1;
+
+/// `OWNER` if the kernel is running
+const NO_OWNER: usize = usize::MAX;
+/// Number of times the stub checks whether the other CPUs stopped before it gives up waiting
+const PARK_WAIT_SPINS: usize = 100_000_000;
+
+/// Which exception entered the stub
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Breakpoint,
+    Debug,
+}
+
+static PORT: Once<PolledPort> = Once::new();
+/// The CPU that runs the stub, it stays the owner while it single-steps
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+/// Set while the owner single-steps, the other CPUs stay stopped
+static STEPPING: AtomicBool = AtomicBool::new(false);
+/// Set when gdb sent a break, the stop is reported as SIGINT
+static BREAK_IN_PENDING: AtomicBool = AtomicBool::new(false);
+/// Number of CPUs stopped by the NMI
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NMI_NOT_PENDING: AtomicBool = AtomicBool::new(false);
+/// Set for a CPU before the stub sends it the NMI, the NMIs of others are not for the stub
+static NMI_PENDING: [AtomicBool; CPU_MAX] = [NMI_NOT_PENDING; CPU_MAX];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_FRAME: AtomicPtr<InterruptRegisters> = AtomicPtr::new(ptr::null_mut());
+/// The registers of the stopped CPUs, the threads they run have no saved registers
+static STOPPED_FRAMES: [AtomicPtr<InterruptRegisters>; CPU_MAX] = [NO_FRAME; CPU_MAX];
+
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    packet: [0; PACKET_MAX],
+    session: Session {
+        reply: Reply {
+            buff: [0; PACKET_MAX],
+            len: 0,
+        },
+        breakpoints: [None; BREAKPOINTS_MAX],
+        stopped_thread: None,
+        selected_thread: None,
+        signal: SIGTRAP,
+        thread_cursor: 0,
+        step_restore_interrupts: false,
+    },
+});
+
+/// The state of the stub, it is only used by the owner
+struct Stub {
+    packet: [u8; PACKET_MAX],
+    session: Session,
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// The byte the int3 replaced
+    orig: u8,
+}
+
+/// What the kernel does once the stub returns
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct Session {
+    reply: Reply,
+    breakpoints: [Option<Breakpoint>; BREAKPOINTS_MAX],
+    /// The thread running on the CPU that entered the stub
+    stopped_thread: Option<ThreadID>,
+    /// The thread whose registers g and G access, None is the stopped thread
+    selected_thread: Option<ThreadID>,
+    signal: u8,
+    /// Number of threads qfThreadInfo and qsThreadInfo already sent
+    thread_cursor: usize,
+    /// Interrupts are masked while single-stepping so the step does not end up in an
+    /// interrupt handler, set if they have to be unmasked afterwards
+    step_restore_interrupts: bool,
+}
+
+/// A reply packet, the bytes that do not fit are dropped
+struct Reply {
+    buff: [u8; PACKET_MAX],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < self.buff.len() {
+            self.buff[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_hex_byte(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xF) as usize]);
+    }
+
+    /// Pushes the first __size__ bytes of __val__ in little endian, the byte order of the
+    /// registers
+    fn push_hex_le(&mut self, val: u64, size: usize) {
+        for byte in &val.to_le_bytes()[..size] {
+            self.push_hex_byte(*byte);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == self.buff.len()
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+
+        Ok(())
+    }
+}
+
+/// The registers of a thread in the order of the g packet of amd64, the x87 and SSE
+/// registers are left out
+struct GdbRegisters {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8 to r15 and rip
+    gprs: [u64; 17],
+    eflags: u64,
+    /// cs, ss, ds, es, fs and gs
+    segments: [u64; 6],
+}
+
+impl GdbRegisters {
+    /// Size of the registers in the g packet
+    const PACKET_SIZE: usize = 17 * 8 + 4 + 6 * 4;
+
+    fn from_frame(frame: &InterruptRegisters) -> GdbRegisters {
+        let GeneralRegisters {
+            rax,
+            rbx,
+            rcx,
+            rdx,
+            rsi,
+            rdi,
+            r8,
+            r9,
+            r10,
+            r11,
+            r12,
+            r13,
+            r14,
+            r15,
+            rbp,
+        } = frame.general;
+        let IretRegisters {
+            rip,
+            cs,
+            rflags,
+            rsp,
+            ss,
+        } = frame.iret;
+
+        GdbRegisters {
+            gprs: [
+                rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip,
+            ],
+            eflags: rflags,
+            segments: [cs, ss, 0, 0, 0, 0],
+        }
+    }
+
+    fn from_saved(regs: &RegisterState) -> GdbRegisters {
+        let mut gdb_regs = GdbRegisters::from_frame(&InterruptRegisters {
+            general: regs.general,
+            iret: IretRegisters {
+                rip: regs.rip,
+                cs: regs.selectors.cs,
+                rflags: regs.rflags,
+                rsp: regs.rsp,
+                ss: regs.selectors.ss,
+            },
+        });
+        let selectors = regs.selectors;
+        gdb_regs.segments[2..].copy_from_slice(&[
+            selectors.ds,
+            selectors.es,
+            selectors.fs,
+            selectors.gs,
+        ]);
+        gdb_regs
+    }
+
+    /// Parses the registers of a G packet, None if it is too short
+    fn parse(hex: &[u8]) -> Option<GdbRegisters> {
+        if hex.len() < GdbRegisters::PACKET_SIZE * 2 {
+            return None;
+        }
+
+        let mut regs = GdbRegisters {
+            gprs: [0; 17],
+            eflags: 0,
+            segments: [0; 6],
+        };
+        let mut fields = hex.chunks(8 * 2);
+        for reg in regs.gprs.iter_mut() {
+            *reg = parse_hex_le(fields.next()?)?;
+        }
+
+        let mut fields = hex[17 * 8 * 2..].chunks(4 * 2);
+        regs.eflags = parse_hex_le(fields.next()?)?;
+        for reg in regs.segments.iter_mut() {
+            *reg = parse_hex_le(fields.next()?)?;
+        }
+
+        Some(regs)
+    }
+
+    fn write_packet(&self, reply: &mut Reply) {
+        for reg in self.gprs {
+            reply.push_hex_le(reg, 8);
+        }
+        reply.push_hex_le(self.eflags, 4);
+        for reg in self.segments {
+            reply.push_hex_le(reg, 4);
+        }
+    }
+
+    /// Changes the registers the interrupted code continues with, the segments stay the same
+    fn write_frame(&self, frame: &mut InterruptRegisters) {
+        let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip] =
+            self.gprs;
+        frame.general = GeneralRegisters {
+            rax,
+            rbx,
+            rcx,
+            rdx,
+            rsi,
+            rdi,
+            r8,
+            r9,
+            r10,
+            r11,
+            r12,
+            r13,
+            r14,
+            r15,
+            rbp,
+        };
+        frame.iret.rip = rip;
+        frame.iret.rsp = rsp;
+        frame.iret.rflags = self.eflags;
+    }
+}
+
+fn hex_digit(ch: u8) -> Option<u8> {
+    match ch {
+        b'0'..=b'9' => Some(ch - b'0'),
+        b'a'..=b'f' => Some(ch - b'a' + 10),
+        b'A'..=b'F' => Some(ch - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a big endian hex number, addresses and lengths are sent like this
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+
+    hex.iter()
+        .try_fold(0, |val, &ch| Some(val << 4 | hex_digit(ch)? as u64))
+}
+
+/// Parses the value of a register, the bytes are in little endian
+fn parse_hex_le(hex: &[u8]) -> Option<u64> {
+    if hex.len() % 2 != 0 || hex.len() > 16 {
+        return None;
+    }
+
+    let mut val = 0;
+    for (idx, byte) in hex.chunks(2).enumerate() {
+        let byte = hex_digit(byte[0])? << 4 | hex_digit(byte[1])?;
+        val |= (byte as u64) << (idx * 8);
+    }
+
+    Some(val)
+}
+
+/// Parses "addr,len", the arguments of m, M, Z and z
+fn parse_addr_len(args: &[u8]) -> Option<(u64, usize)> {
+    let comma = args.iter().position(|&ch| ch == b',')?;
+    let addr = parse_hex(&args[..comma])?;
+    let len = parse_hex(&args[comma + 1..])?;
+    Some((addr, len as usize))
+}
+
+/// Returns the id gdb uses for the thread, gdb does not allow 0
+fn gdb_thread_id(tid: Option<ThreadID>) -> usize {
+    tid.map_or(FALLBACK_THREAD_ID, |tid| tid.0 + 1)
+}
+
+/// Parses a thread id of gdb, 0 and -1 mean any thread and are the stopped one
+fn parse_thread_id(hex: &[u8]) -> Option<Option<ThreadID>> {
+    if hex == b"-1" {
+        return Some(None);
+    }
+
+    match parse_hex(hex)? as usize {
+        0 => Some(None),
+        id => Some(Some(ThreadID(id - 1))),
+    }
+}
+
+/// Returns whether the page of __addr__ is mapped in the current address space. The tables
+/// are walked directly because gdb reads whatever it likes and the stub must neither fault
+/// nor take locks.
+fn is_page_mapped(addr: u64) -> bool {
+    const PRESENT: u64 = 1 << 0;
+    const HUGE_PAGE: u64 = 1 << 7;
+
+    // non-canonical addresses fault with a general protection fault
+    if ((addr as i64) << 16 >> 16) as u64 != addr {
+        return false;
+    }
+
+    let mut table = get_current_pml4_phys();
+    for level in (0..4).rev() {
+        let index = (addr >> (12 + 9 * level)) & 0o777;
+        let entry = unsafe { *(table.virt_addr().get() as *const u64).add(index as usize) };
+        if entry & PRESENT == 0 {
+            return false;
+        }
+
+        // the 1 GiB and 2 MiB pages
+        if (level == 1 || level == 2) && entry & HUGE_PAGE != 0 {
+            return true;
+        }
+
+        table = PhysAddr::new(entry & 0x000f_ffff_ffff_f000);
+    }
+
+    true
+}
+
+fn is_range_mapped(addr: u64, len: usize) -> bool {
+    let end = match addr.checked_add(len as u64) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut page = addr & !0xFFF;
+    while page < end {
+        if !is_page_mapped(page) {
+            return false;
+        }
+        page += 0x1000;
+    }
+
+    true
+}
+
+/// Writes to memory even if it is read-only, e.g. the code when a breakpoint is set. The
+/// memory of the current process can be written too.
+fn write_memory(addr: u64, data: &[u8]) -> bool {
+    if !is_range_mapped(addr, data.len()) {
+        return false;
+    }
+
+    let cr0 = get_cr0();
+    set_cr0(cr0 - CR0Flags::WP);
+    let _access = UserAccess::begin();
+    unsafe {
+        ptr::copy_nonoverlapping(data.as_ptr(), addr as *mut u8, data.len());
+    }
+    set_cr0(cr0);
+
+    true
+}
+
+/// Receives a packet into __buff__ and acknowledges it, returns its length. The bytes that
+/// do not fit are dropped.
+fn receive_packet(port: &PolledPort, buff: &mut [u8]) -> usize {
+    'packet: loop {
+        // anything before the start of a packet is an acknowledgement or a break
+        while port.read() != b'$' {}
+
+        let mut len = 0;
+        let mut checksum: u8 = 0;
+        loop {
+            let byte = port.read();
+            match byte {
+                b'#' => break,
+                // gdb started over
+                b'$' => continue 'packet,
+                _ => {}
+            }
+
+            checksum = checksum.wrapping_add(byte);
+            if len < buff.len() {
+                buff[len] = byte;
+                len += 1;
+            }
+        }
+
+        let high = hex_digit(port.read());
+        let low = hex_digit(port.read());
+        match (high, low) {
+            (Some(high), Some(low)) if high << 4 | low == checksum => {
+                port.write(b'+');
+                return len;
+            }
+            _ => port.write(b'-'),
+        }
+    }
+}
+
+/// Sends a packet and waits until gdb acknowledges it
+fn send_packet(port: &PolledPort, data: &[u8]) {
+    let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    loop {
+        port.write(b'$');
+        for &byte in data {
+            port.write(byte);
+        }
+        port.write(b'#');
+
+        for digit in [checksum >> 4, checksum & 0xF] {
+            port.write(HEX_DIGITS[digit as usize]);
+        }
+
+        // resent if it was corrupted
+        if port.read() != b'-' {
+            return;
+        }
+    }
+}
+
+/// Returns the registers gdb sees for the thread __tid__, the ones of a running thread are
+/// the ones of its stopped CPU
+fn thread_registers(tid: ThreadID) -> Option<GdbRegisters> {
+    let mut regs = None;
+    SCHEDULER.try_for_each_thread(|thread, cpu| {
+        if thread.id != tid {
+            return;
+        }
+
+        let frame = cpu.map_or(ptr::null_mut(), |cpu| {
+            STOPPED_FRAMES[cpu].load(Ordering::Acquire)
+        });
+        regs = Some(match unsafe { frame.as_ref() } {
+            Some(frame) => GdbRegisters::from_frame(frame),
+            None => GdbRegisters::from_saved(saved_registers(thread)),
+        });
+    });
+
+    regs
+}
+
+/// Returns the registers a thread continues with when it is switched to
+fn saved_registers(thread: &Thread) -> &RegisterState {
+    match &thread.inner {
+        ThreadInner::Kernel(data) => &data.regs,
+        ThreadInner::User(data) if data.in_kernelspace => &data.kernel_regs,
+        ThreadInner::User(data) => &data.user_regs,
+    }
+}
+
+impl Session {
+    fn find_breakpoint(&self, addr: u64) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|bp| bp.is_some_and(|bp| bp.addr == addr))
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> bool {
+        if self.find_breakpoint(addr).is_some() {
+            return true;
+        }
+
+        let slot = match self.breakpoints.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        if !is_range_mapped(addr, 1) {
+            return false;
+        }
+
+        let orig = unsafe {
+            let _access = UserAccess::begin();
+            *(addr as *const u8)
+        };
+        if !write_memory(addr, &[INT3]) {
+            return false;
+        }
+
+        self.breakpoints[slot] = Some(Breakpoint { addr, orig });
+        true
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        let slot = match self.find_breakpoint(addr) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        let bp = self.breakpoints[slot].take().unwrap();
+        write_memory(bp.addr, &[bp.orig])
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for bp in self.breakpoints.iter_mut().filter_map(Option::take) {
+            write_memory(bp.addr, &[bp.orig]);
+        }
+    }
+
+    fn reply_error(&mut self, code: u8) {
+        self.reply.clear();
+        self.reply.push(b'E');
+        self.reply.push_hex_byte(code);
+    }
+
+    fn reply_ok(&mut self) {
+        self.reply.clear();
+        write!(self.reply, "OK").ok();
+    }
+
+    fn reply_stop(&mut self) {
+        self.reply.clear();
+        let id = gdb_thread_id(self.stopped_thread);
+        write!(self.reply, "T").ok();
+        self.reply.push_hex_byte(self.signal);
+        write!(self.reply, "thread:{:x};", id).ok();
+    }
+
+    fn read_registers(&mut self, frame: &InterruptRegisters) {
+        let regs = match self.selected_thread {
+            Some(tid) if Some(tid) != self.stopped_thread => match thread_registers(tid) {
+                Some(regs) => regs,
+                None => return self.reply_error(0x01),
+            },
+            _ => GdbRegisters::from_frame(frame),
+        };
+
+        self.reply.clear();
+        regs.write_packet(&mut self.reply);
+    }
+
+    /// Only the registers of the stopped thread can be changed
+    fn write_registers(&mut self, frame: &mut InterruptRegisters, args: &[u8]) {
+        if self
+            .selected_thread
+            .is_some_and(|tid| Some(tid) != self.stopped_thread)
+        {
+            return self.reply_error(0x01);
+        }
+
+        match GdbRegisters::parse(args) {
+            Some(regs) => {
+                regs.write_frame(frame);
+                self.reply_ok();
+            }
+            None => self.reply_error(0x01),
+        }
+    }
+
+    fn read_memory(&mut self, args: &[u8]) {
+        let (addr, len) = match parse_addr_len(args) {
+            Some(args) => args,
+            None => return self.reply_error(0x01),
+        };
+        // two hex digits per byte
+        let len = usize::min(len, PACKET_MAX / 2);
+        if !is_range_mapped(addr, len) {
+            return self.reply_error(0x14);
+        }
+
+        self.reply.clear();
+        // the memory of the current process can be read too
+        let _access = UserAccess::begin();
+        for idx in 0..len as u64 {
+            let byte = unsafe { *((addr + idx) as *const u8) };
+            self.reply.push_hex_byte(byte);
+        }
+    }
+
+    fn write_memory(&mut self, args: &[u8]) {
+        let colon = match args.iter().position(|&ch| ch == b':') {
+            Some(colon) => colon,
+            None => return self.reply_error(0x01),
+        };
+        let (addr, len) = match parse_addr_len(&args[..colon]) {
+            Some(args) => args,
+            None => return self.reply_error(0x01),
+        };
+        let hex = &args[colon + 1..];
+        if hex.len() != len * 2 {
+            return self.reply_error(0x01);
+        }
+
+        // decoded and written in chunks, the packet can be longer than the stack can hold
+        let mut chunk = [0; 64];
+        for (idx, hex_chunk) in hex.chunks(chunk.len() * 2).enumerate() {
+            let count = hex_chunk.len() / 2;
+            for (byte, digits) in chunk.iter_mut().zip(hex_chunk.chunks(2)) {
+                *byte = match parse_hex(digits) {
+                    Some(byte) => byte as u8,
+                    None => return self.reply_error(0x01),
+                };
+            }
+
+            let chunk_addr = addr + (idx * chunk.len()) as u64;
+            if !write_memory(chunk_addr, &chunk[..count]) {
+                return self.reply_error(0x14);
+            }
+        }
+
+        self.reply_ok();
+    }
+
+    /// Z0 and z0, only software breakpoints are supported
+    fn breakpoint_packet(&mut self, args: &[u8], insert: bool) {
+        let (kind, args) = match args {
+            [kind, b',', args @ ..] => (*kind, args),
+            _ => return self.reply_error(0x01),
+        };
+        if kind != b'0' {
+            self.reply.clear();
+            return;
+        }
+
+        // the argument after the address is the size of the breakpoint instruction
+        let addr = match parse_addr_len(args) {
+            Some((addr, _)) => addr,
+            None => return self.reply_error(0x01),
+        };
+
+        let done = match insert {
+            true => self.insert_breakpoint(addr),
+            false => self.remove_breakpoint(addr),
+        };
+        match done {
+            true => self.reply_ok(),
+            false => self.reply_error(0x0E),
+        }
+    }
+
+    /// Replies to qfThreadInfo and qsThreadInfo, the threads are sent in several packets
+    fn thread_list(&mut self, first: bool) {
+        if first {
+            self.thread_cursor = 0;
+        }
+
+        let skip = self.thread_cursor;
+        let mut sent = 0;
+        self.reply.clear();
+        self.reply.push(b'm');
+        let reply = &mut self.reply;
+        let mut idx = 0;
+        let listed = SCHEDULER.try_for_each_thread(|thread, _| {
+            if idx >= skip && sent < THREADS_PER_PACKET && !reply.is_full() {
+                if sent > 0 {
+                    reply.push(b',');
+                }
+                write!(reply, "{:x}", gdb_thread_id(Some(thread.id))).ok();
+                sent += 1;
+            }
+            idx += 1;
+        });
+
+        // the scheduler is locked, only the stopped CPU is shown
+        if !listed {
+            sent = match first {
+                true => {
+                    write!(self.reply, "{:x}", FALLBACK_THREAD_ID).ok();
+                    1
+                }
+                false => 0,
+            };
+        }
+
+        if sent == 0 {
+            self.reply.clear();
+            self.reply.push(b'l');
+        }
+        self.thread_cursor += sent;
+    }
+
+    /// Replies to qThreadExtraInfo, the text gdb shows next to the thread
+    fn thread_extra_info(&mut self, args: &[u8]) {
+        let tid = match parse_thread_id(args) {
+            Some(tid) => tid.or(self.stopped_thread),
+            None => return self.reply_error(0x01),
+        };
+
+        let mut info = Reply {
+            buff: [0; PACKET_MAX],
+            len: 0,
+        };
+        SCHEDULER.try_for_each_thread(|thread, cpu| {
+            if Some(thread.id) != tid {
+                return;
+            }
+
+            match &thread.inner {
+                ThreadInner::Kernel(_) => write!(info, "kernel {:?}", thread.state).ok(),
+                ThreadInner::User(data) => write!(info, "pid {} {:?}", data.pid, thread.state).ok(),
+            };
+            if let Some(cpu) = cpu {
+                write!(info, " on cpu {}", cpu).ok();
+            }
+        });
+
+        self.reply.clear();
+        for &byte in &info.buff[..usize::min(info.len, PACKET_MAX / 2)] {
+            self.reply.push_hex_byte(byte);
+        }
+    }
+
+    fn thread_alive(&mut self, args: &[u8]) {
+        let tid = match parse_thread_id(args) {
+            Some(Some(tid)) => tid,
+            Some(None) => return self.reply_ok(),
+            None => return self.reply_error(0x01),
+        };
+
+        let mut alive = Some(tid) == self.stopped_thread;
+        SCHEDULER.try_for_each_thread(|thread, _| alive |= thread.id == tid);
+        match alive {
+            true => self.reply_ok(),
+            false => self.reply_error(0x01),
+        }
+    }
+
+    fn query(&mut self, packet: &[u8]) {
+        self.reply.clear();
+        if packet.starts_with(b"qSupported") {
+            write!(self.reply, "PacketSize={:x}", PACKET_MAX).ok();
+            return;
+        }
+        if let Some(args) = packet.strip_prefix(b"qThreadExtraInfo,") {
+            return self.thread_extra_info(args);
+        }
+
+        match packet {
+            b"qAttached" => self.reply.push(b'1'),
+            b"qC" => {
+                write!(self.reply, "QC{:x}", gdb_thread_id(self.stopped_thread)).ok();
+            }
+            b"qfThreadInfo" => self.thread_list(true),
+            b"qsThreadInfo" => self.thread_list(false),
+            // unsupported queries get an empty reply
+            _ => {}
+        }
+    }
+
+    /// Handles a packet and leaves the reply in `reply`, returns how the kernel continues
+    /// if the packet resumes it
+    fn handle_packet(&mut self, frame: &mut InterruptRegisters, packet: &[u8]) -> Option<Resume> {
+        let (command, args) = match packet.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return None,
+        };
+
+        self.reply.clear();
+        match command {
+            b'?' => self.reply_stop(),
+            b'g' => self.read_registers(frame),
+            b'G' => self.write_registers(frame, args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'Z' => self.breakpoint_packet(args, true),
+            b'z' => self.breakpoint_packet(args, false),
+            b'c' | b's' => {
+                // the address to continue at is optional
+                if let Some(addr) = parse_hex(args) {
+                    frame.iret.rip = addr;
+                }
+
+                return Some(match command {
+                    b'c' => Resume::Continue,
+                    _ => Resume::Step,
+                });
+            }
+            b'D' => {
+                self.remove_all_breakpoints();
+                self.reply_ok();
+                return Some(Resume::Continue);
+            }
+            b'k' => {
+                self.remove_all_breakpoints();
+                return Some(Resume::Continue);
+            }
+            b'H' => match args.split_first() {
+                Some((b'g', id)) => match parse_thread_id(id) {
+                    Some(tid) => {
+                        self.selected_thread = tid;
+                        self.reply_ok();
+                    }
+                    None => self.reply_error(0x01),
+                },
+                // every thread continues, the thread of c and s is ignored
+                Some((b'c', _)) => self.reply_ok(),
+                _ => self.reply_error(0x01),
+            },
+            b'T' => self.thread_alive(args),
+            b'q' => self.query(packet),
+            _ => {}
+        }
+
+        None
+    }
+
+    /// Talks to gdb until it resumes the kernel
+    fn run(
+        &mut self,
+        port: &PolledPort,
+        packet: &mut [u8],
+        frame: &mut InterruptRegisters,
+    ) -> Resume {
+        // gdb is told the kernel stopped, unless this is the first stop and gdb asks for it
+        self.reply_stop();
+        send_packet(port, &self.reply.buff[..self.reply.len]);
+
+        loop {
+            let len = receive_packet(port, packet);
+            let resume = self.handle_packet(frame, &packet[..len]);
+
+            // k has no reply, c and s are answered by the next stop
+            let replies = match resume {
+                None => true,
+                Some(_) => packet.first() == Some(&b'D'),
+            };
+            if replies {
+                send_packet(port, &self.reply.buff[..self.reply.len]);
+            }
+
+            if let Some(resume) = resume {
+                return resume;
+            }
+        }
+    }
+}
+
+/// Stops the other CPUs with an NMI and waits until they are parked in `park_cpu`
+fn stop_others() {
+    let current = smp::current_cpu();
+    let others = smp::online_count() - 1;
+    if others == 0 {
+        return;
+    }
+
+    for cpu in 0..smp::cpu_count() {
+        if cpu != current && smp::is_online(cpu) {
+            NMI_PENDING[cpu].store(true, Ordering::Release);
+        }
+    }
+    smp::nmi_others();
+
+    // a CPU that does not stop in time is shown as running
+    for _ in 0..PARK_WAIT_SPINS {
+        if PARKED.load(Ordering::Acquire) >= others {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Called by the NMI handler, keeps the CPU stopped while the stub runs. Returns false if the
+/// NMI was not sent by the stub.
+pub fn park_cpu(frame: &mut InterruptRegisters) -> bool {
+    let cpu = smp::current_cpu();
+    if !NMI_PENDING[cpu].swap(false, Ordering::AcqRel) {
+        return false;
+    }
+
+    STOPPED_FRAMES[cpu].store(frame, Ordering::Release);
+    PARKED.fetch_add(1, Ordering::AcqRel);
+
+    while OWNER.load(Ordering::Acquire) != NO_OWNER {
+        core::hint::spin_loop();
+    }
+
+    STOPPED_FRAMES[cpu].store(ptr::null_mut(), Ordering::Release);
+    PARKED.fetch_sub(1, Ordering::AcqRel);
+    true
+}
+
+/// Called by the handlers of the breakpoint and the debug exception, returns false if the
+/// exception is not for the stub
+pub fn handle_exception(frame: &mut InterruptRegisters, trap: Trap) -> bool {
+    let port = match PORT.get() {
+        Some(port) => port,
+        None => return false,
+    };
+    if frame.iret.cs & 3 != 0 {
+        return false;
+    }
+
+    let cpu = smp::current_cpu();
+    let stepping = OWNER.load(Ordering::Acquire) == cpu && STEPPING.load(Ordering::Acquire);
+    match (trap, stepping) {
+        // someone else set the trap flag
+        (Trap::Debug, false) => return false,
+        // the step ended, also if the stepped instruction was an int3, the other CPUs are
+        // still stopped
+        (_, true) => STEPPING.store(false, Ordering::Release),
+        // another CPU that entered the stub first stops this one with an NMI meanwhile
+        (Trap::Breakpoint, false) => {
+            while OWNER
+                .compare_exchange(NO_OWNER, cpu, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+            stop_others();
+        }
+    }
+
+    let mut stub = STUB.lock();
+    let Stub { packet, session } = &mut *stub;
+
+    if stepping {
+        frame.iret.rflags &= !Rflags::TRAP.bits();
+        if core::mem::take(&mut session.step_restore_interrupts) {
+            frame.iret.rflags |= Rflags::INTERRUPT.bits();
+        }
+    }
+
+    // the original instruction runs once gdb removed the breakpoint
+    if trap == Trap::Breakpoint && session.find_breakpoint(frame.iret.rip - 1).is_some() {
+        frame.iret.rip -= 1;
+    }
+
+    session.signal = match BREAK_IN_PENDING.swap(false, Ordering::AcqRel) {
+        true => SIGINT,
+        false => SIGTRAP,
+    };
+    session.stopped_thread = SCHEDULER
+        .try_get_current_thread()
+        .and_then(|thread| thread.try_lock().map(|thread| thread.id));
+    session.selected_thread = None;
+
+    let resume = session.run(port, packet, frame);
+    drop(stub);
+
+    match resume {
+        Resume::Step => {
+            let interrupts = frame.iret.rflags & Rflags::INTERRUPT.bits() != 0;
+            STUB.lock().session.step_restore_interrupts = interrupts;
+            frame.iret.rflags |= Rflags::TRAP.bits();
+            frame.iret.rflags &= !Rflags::INTERRUPT.bits();
+            STEPPING.store(true, Ordering::Release);
+        }
+        Resume::Continue => OWNER.store(NO_OWNER, Ordering::Release),
+    }
+
+    true
+}
+
+/// Stops the kernel and enters the stub
+pub fn breakpoint() {
+    unsafe {
+        core::arch::asm!("int3");
+    }
+}
+
+/// Stops the kernel if gdb sent a break, it is called on every timer tick
+pub fn poll_break_in() {
+    let port = match PORT.get() {
+        Some(port) => port,
+        None => return,
+    };
+
+    // gdb only sends other bytes while the kernel is stopped
+    if port.try_read() == Some(BREAK_IN) {
+        BREAK_IN_PENDING.store(true, Ordering::Release);
+        breakpoint();
+    }
+}
+
+/// kgdb=ttyS<n> starts the stub, ttyS0 is the log and the console so it can not be used.
+/// kgdbwait stops the kernel right away so breakpoints can be set before the drivers load.
+pub fn init() {
+    let name = match cmdline::get("kgdb") {
+        Some(name) => name,
+        None => return,
+    };
+
+    let port = match name.strip_prefix("ttyS").and_then(|idx| idx.parse().ok()) {
+        Some(idx) => PolledPort::open(idx),
+        None => None,
+    };
+    let port = match port {
+        Some(port) => port,
+        None => {
+            warn!("KGDB: can not use {} for the stub", name);
+            return;
+        }
+    };
+
+    PORT.call_once(|| port);
+    log!("KGDB: stub running on {}", name);
+
+    if cmdline::get("kgdbwait").is_some() {
+        log!("KGDB: waiting for gdb");
+        breakpoint();
+    }
+}
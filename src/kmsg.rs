@@ -0,0 +1,368 @@
+//! The kernel log ring. Every message of the logger is kept in a fixed-size ring of records
+//! so the early boot messages can still be read once userspace runs, the oldest records are
+//! overwritten once the ring is full. The ring is read one record at a time through
+//! /dev/kmsg and all at once through the dmesg call of the rook syscall.
+//!
+//! A record of /dev/kmsg is "priority,sequence,timestamp,-;message\n" like on Linux, the
+//! timestamp is in microseconds since boot. The offset of a reader is the number of bytes of
+//! the records before the one it reads next, a reader that fell behind continues at the
+//! oldest record that is still in the ring.
+
+use alloc::{string::String, sync::Arc};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    fs::{
+        devfs::{self, DevFsDevice},
+        errors::{FsIoctlError, FsReadError, FsStatError, FsWriteError},
+        path::Path,
+        poll::PollQueue,
+    },
+    logger::{self, LogLevel},
+    posix::{PollEvents, Stat, S_IFCHR},
+    scheduler::{proc, wait_queue::Waiter},
+    sync::InterruptMutex,
+    time,
+    utils::ring_buffer::RingBuffer,
+};
+
+/// Number of records kept in the ring
+pub const LOG_RECORDS_MAX: usize = 512;
+/// Longer messages are truncated
+pub const LOG_TEXT_MAX: usize = 232;
+/// Upper bound of the length of a formatted record, the text and the fields in front of it
+const LOG_RECORD_FORMATTED_MAX: usize = LOG_TEXT_MAX + 64;
+/// Upper bound of the length of the whole log formatted by dmesg
+pub const LOG_FORMATTED_MAX: usize = LOG_RECORDS_MAX * LOG_RECORD_FORMATTED_MAX;
+
+const KMSG_DEVICE_MAJOR: u16 = 1;
+/// The minor of /dev/kmsg, the same as on Linux
+const KMSG_DEVICE_MINOR: u16 = 11;
+
+#[derive(Clone, Copy)]
+struct LogRecord {
+    sequence: u64,
+    /// Offset of the record in /dev/kmsg
+    position: usize,
+    timestamp_us: u64,
+    level: LogLevel,
+    len: usize,
+    text: [u8; LOG_TEXT_MAX],
+}
+
+impl LogRecord {
+    const EMPTY: LogRecord = LogRecord {
+        sequence: 0,
+        position: 0,
+        timestamp_us: 0,
+        level: LogLevel::Log,
+        len: 0,
+        text: [0; LOG_TEXT_MAX],
+    };
+
+    fn text(&self) -> &[u8] {
+        &self.text[..self.len]
+    }
+
+    /// Formats the record the way /dev/kmsg returns it, returns the length
+    fn format_kmsg(&self, buff: &mut [u8]) -> usize {
+        let mut writer = BufferWriter::new(buff);
+        write!(
+            writer,
+            "{},{},{},-;",
+            self.level.syslog_priority(),
+            self.sequence,
+            self.timestamp_us
+        )
+        .ok();
+        writer.write_bytes(self.text());
+        writer.write_bytes(b"\n");
+        writer.len
+    }
+
+    /// Formats the record the way dmesg prints it, "<priority>[seconds.microseconds] message\n"
+    fn format_dmesg(&self, buff: &mut [u8]) -> usize {
+        let mut writer = BufferWriter::new(buff);
+        write!(
+            writer,
+            "<{}>[{:5}.{:06}] ",
+            self.level.syslog_priority(),
+            self.timestamp_us / 1_000_000,
+            self.timestamp_us % 1_000_000
+        )
+        .ok();
+        writer.write_bytes(self.text());
+        writer.write_bytes(b"\n");
+        writer.len
+    }
+
+    fn kmsg_len(&self) -> usize {
+        self.format_kmsg(&mut [0; LOG_RECORD_FORMATTED_MAX])
+    }
+
+    fn dmesg_len(&self) -> usize {
+        self.format_dmesg(&mut [0; LOG_RECORD_FORMATTED_MAX])
+    }
+}
+
+/// Formats into a fixed buffer, the output that does not fit is dropped. Nothing is
+/// allocated so messages can be recorded in interrupt handlers and before the heap exists.
+struct BufferWriter<'a> {
+    buff: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BufferWriter<'a> {
+    fn new(buff: &'a mut [u8]) -> BufferWriter<'a> {
+        BufferWriter { buff, len: 0 }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let count = usize::min(bytes.len(), self.buff.len() - self.len);
+        self.buff[self.len..self.len + count].copy_from_slice(&bytes[..count]);
+        self.len += count;
+    }
+}
+
+impl Write for BufferWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+struct KernelLog {
+    records: RingBuffer<LogRecord, LOG_RECORDS_MAX>,
+    next_sequence: u64,
+    /// Offset of the next record in /dev/kmsg
+    next_position: usize,
+}
+
+impl KernelLog {
+    /// Returns the first record at or after the offset __off__
+    fn find(&self, off: usize) -> Option<&LogRecord> {
+        self.records.iter().find(|record| record.position >= off)
+    }
+}
+
+static KERNEL_LOG: InterruptMutex<KernelLog> = InterruptMutex::new(KernelLog {
+    records: RingBuffer::new(LogRecord::EMPTY),
+    next_sequence: 0,
+    next_position: 0,
+});
+
+static READERS: PollQueue = PollQueue::new();
+/// Set when a record is added, the readers are woken on the next timer tick because messages
+/// are also logged with the scheduler locked
+static READERS_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Adds a message to the ring, the logger records every message regardless of the log level
+pub fn record(level: LogLevel, args: fmt::Arguments) {
+    let mut text = [0; LOG_TEXT_MAX];
+    let mut writer = BufferWriter::new(&mut text);
+    writer.write_fmt(args).ok();
+    let len = writer.len;
+
+    // every record is a single line
+    for byte in text[..len].iter_mut() {
+        if *byte < b' ' {
+            *byte = b' ';
+        }
+    }
+
+    let timestamp_us = time::monotonic_ns() / 1000;
+
+    // the panic could have happened while the log was locked
+    let mut log = match logger::is_panicking() {
+        true => match KERNEL_LOG.try_lock() {
+            Some(log) => log,
+            None => return,
+        },
+        false => KERNEL_LOG.lock(),
+    };
+
+    let record = LogRecord {
+        sequence: log.next_sequence,
+        position: log.next_position,
+        timestamp_us,
+        level,
+        len,
+        text,
+    };
+    log.next_sequence += 1;
+    log.next_position += record.kmsg_len();
+    log.records.push_overwrite(record);
+
+    READERS_PENDING.store(true, Ordering::Relaxed);
+}
+
+/// Wakes the readers of /dev/kmsg if a record was added since the last call, it is called on
+/// every timer tick
+pub fn wake_readers() {
+    if READERS_PENDING.swap(false, Ordering::Relaxed) {
+        READERS.wake_all();
+    }
+}
+
+/// Copies the newest records that fit in __buff__ formatted the way dmesg prints them, oldest
+/// first. Returns the number of bytes copied, or the length of the whole log if __buff__ is
+/// empty.
+pub fn read_all(buff: &mut [u8]) -> usize {
+    let log = KERNEL_LOG.lock();
+    let mut remaining: usize = log.records.iter().map(LogRecord::dmesg_len).sum();
+    if buff.is_empty() {
+        return remaining;
+    }
+
+    let mut copied = 0;
+    for record in log.records.iter() {
+        // the oldest records are dropped until the rest fits
+        if remaining > buff.len() {
+            remaining -= record.dmesg_len();
+            continue;
+        }
+
+        copied += record.format_dmesg(&mut buff[copied..]);
+    }
+
+    copied
+}
+
+/// Writes the records in the ring as records of the crash dump, oldest first. The ring is
+/// taken even if it is locked.
+pub fn crash_dump(w: &mut dyn Write) -> fmt::Result {
+    let (log, _) = unsafe { KERNEL_LOG.force_lock() };
+    for record in log.records.iter() {
+        // a message can be truncated in the middle of a character
+        let text = match core::str::from_utf8(record.text()) {
+            Ok(text) => text,
+            Err(err) => core::str::from_utf8(&record.text()[..err.valid_up_to()]).unwrap_or(""),
+        };
+
+        writeln!(
+            w,
+            "log seq={} pri={} ts={} msg={}",
+            record.sequence,
+            record.level.syslog_priority(),
+            record.timestamp_us,
+            text
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Returns the level and the message of a line written to /dev/kmsg, the level can be given
+/// as a syslog priority in front of the message, e.g. "<4>message"
+fn parse_priority(line: &[u8]) -> (LogLevel, &[u8]) {
+    if let [b'<', priority @ b'0'..=b'7', b'>', message @ ..] = line {
+        return (LogLevel::from_syslog_priority(priority - b'0'), message);
+    }
+
+    (LogLevel::Log, line)
+}
+
+struct KmsgDevice;
+
+impl DevFsDevice for KmsgDevice {
+    fn read(&self, _minor: u16, off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        let pid = proc::current_pid();
+        let waiter = Arc::new(Waiter::new());
+        loop {
+            // registered before checking so records added in between are not missed
+            READERS.register(&waiter);
+            proc::register_signal_waiter(&waiter);
+
+            if let Some(record) = KERNEL_LOG.lock().find(off) {
+                let mut formatted = [0; LOG_RECORD_FORMATTED_MAX];
+                let len = record.format_kmsg(&mut formatted);
+                // a record is never split between reads
+                if buff.len() < len {
+                    return Err(FsReadError::InvalidArgument);
+                }
+
+                buff[..len].copy_from_slice(&formatted[..len]);
+                return Ok(len);
+            }
+
+            if pid.is_some_and(|pid| proc::pending_signal(pid).is_some()) {
+                return Err(FsReadError::Interrupted);
+            }
+
+            waiter.block();
+        }
+    }
+
+    /// Every line written is logged as a message of the process
+    fn write(&self, _minor: u16, _off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        let pid = proc::current_pid().unwrap_or(0);
+        for line in buff.split(|&byte| byte == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+
+            let (level, message) = parse_priority(line);
+            let message = String::from_utf8_lossy(message);
+            match level {
+                LogLevel::Error => error!("process {}: {}", pid, message),
+                LogLevel::Warn => warn!("process {}: {}", pid, message),
+                LogLevel::Log => log!("process {}: {}", pid, message),
+                LogLevel::Debug => debug!("process {}: {}", pid, message),
+            }
+        }
+
+        Ok(buff.len())
+    }
+
+    fn ioctl(&self, _minor: u16, _req: usize, _arg: usize) -> Result<usize, FsIoctlError> {
+        Err(FsIoctlError::InvalidArgument)
+    }
+
+    fn stat(&self, minor: u16, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        // seeking to the end skips every record that is already in the log
+        stat_buf.st_size = KERNEL_LOG.lock().next_position as u64;
+        stat_buf.st_blksize = 4096;
+        stat_buf.st_blocks = 0;
+        stat_buf.st_dev = 0;
+        stat_buf.st_rdev = (KMSG_DEVICE_MAJOR as u64) << 8 | minor as u64;
+        stat_buf.st_gid = 0;
+        stat_buf.st_uid = 0;
+        stat_buf.st_nlink = 1;
+        stat_buf.st_mode = S_IFCHR | 0o644;
+
+        Ok(())
+    }
+
+    fn poll(&self, _minor: u16, off: usize, waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        if let Some(waiter) = waiter {
+            READERS.register(waiter);
+        }
+
+        match KERNEL_LOG.lock().find(off) {
+            Some(_) => PollEvents::POLLIN | PollEvents::POLLOUT,
+            None => PollEvents::POLLOUT,
+        }
+    }
+
+    fn resync_offset(&self, _minor: u16, off: usize) -> usize {
+        let log = KERNEL_LOG.lock();
+        match log.find(off) {
+            Some(record) => record.position,
+            None => log.next_position,
+        }
+    }
+}
+
+/// Creates /dev/kmsg
+pub fn init() {
+    devfs::register_devfs_node_operations(KMSG_DEVICE_MAJOR, Arc::new(KmsgDevice)).unwrap();
+    devfs::register_devfs_node(
+        Path::new("/kmsg").unwrap(),
+        KMSG_DEVICE_MAJOR,
+        KMSG_DEVICE_MINOR,
+    )
+    .unwrap();
+}
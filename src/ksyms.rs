@@ -0,0 +1,79 @@
+//! The symbol map of the kernel, it resolves the addresses of stack traces to function names.
+//! The kernel is linked with an empty .ksyms section and gen_ksyms.sh writes the map into it
+//! after linking: one "address name\n" line per function, sorted by address. The rest of the
+//! section is zeroed. A kernel without the map prints raw addresses.
+
+use core::{fmt, slice};
+
+use crate::mm::virt;
+
+/// Size of the .ksyms section, the symbol map has to fit in it
+const KSYMS_SIZE: usize = 2 * 1024 * 1024;
+
+/// Reserves the section, it is only read through the linker symbols because the compiler
+/// assumes the contents are still zero
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+extern "C" {
+    static __ksyms: u8;
+    static __ksyms_end: u8;
+}
+
+fn symbol_map() -> &'static [u8] {
+    unsafe {
+        let start = &__ksyms as *const u8;
+        let end = &__ksyms_end as *const u8;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Returns the address and the name of a line of the map
+fn parse_line(line: &[u8]) -> Option<(usize, &[u8])> {
+    let space = line.iter().position(|&byte| byte == b' ')?;
+    let addr = core::str::from_utf8(&line[..space]).ok()?;
+    let addr = usize::from_str_radix(addr, 16).ok()?;
+    Some((addr, &line[space + 1..]))
+}
+
+/// Returns the name of the function __addr__ is in and the offset of __addr__ in it
+pub fn resolve(addr: usize) -> Option<(&'static str, usize)> {
+    let (image_start, image_end) = virt::kernel_image();
+    if addr < image_start.get() as usize || addr >= image_end.get() as usize {
+        return None;
+    }
+
+    let mut found = None;
+    for line in symbol_map().split(|&byte| byte == b'\n') {
+        // the map ends where the zeroes start
+        if line.first().map_or(true, |&byte| byte == 0) {
+            break;
+        }
+
+        let (sym_addr, name) = match parse_line(line) {
+            Some(sym) => sym,
+            None => continue,
+        };
+        if sym_addr > addr {
+            break;
+        }
+        found = Some((sym_addr, name));
+    }
+
+    let (sym_addr, name) = found?;
+    let name = core::str::from_utf8(name).unwrap_or("?");
+    Some((name, addr - sym_addr))
+}
+
+/// Formats an address as "0x... function+0x..." if the function is known
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, "{:#x} {}+{:#x}", self.0, name, offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Latency histograms of the syscalls and the interrupt handlers, every syscall number and
+//! interrupt vector has its own histogram. They are updated from interrupt handlers so they
+//! are made of atomics only, /proc/latency prints the ones that have samples.
+
+use alloc::{format, string::String};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{syscall, time};
+
+/// Bucket 0 counts the samples under 1us, bucket n the ones in [2^(n-1), 2^n) us and the last
+/// one everything from about 4 seconds up
+const BUCKETS: usize = 24;
+
+/// Syscall numbers from here on are not recorded
+const MAX_SYSCALLS: usize = 128;
+const MAX_VECTORS: usize = 256;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+impl Histogram {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [Self::ZERO; BUCKETS],
+            count: AtomicU64::new(0),
+            total_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+        }
+    }
+
+    fn bucket(ns: u64) -> usize {
+        let us = ns / 1000;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        bucket.min(BUCKETS - 1)
+    }
+
+    fn record(&self, ns: u64) {
+        self.buckets[Self::bucket(ns)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.total_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+    }
+
+    /// Writes the count, the average, the maximum and the nonempty buckets on one line
+    fn write_line(&self, buf: &mut String, name: &str) {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return;
+        }
+
+        let avg_us = self.total_ns.load(Ordering::Relaxed) / count / 1000;
+        let max_us = self.max_ns.load(Ordering::Relaxed) / 1000;
+        let _ = write!(
+            buf,
+            "{:<16} count: {} avg: {}us max: {}us |",
+            name, count, avg_us, max_us
+        );
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            let samples = bucket.load(Ordering::Relaxed);
+            if samples > 0 {
+                let _ = write!(buf, " <{}us: {}", bucket_limit_us(i), samples);
+            }
+        }
+        buf.push('\n');
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_HISTOGRAM: Histogram = Histogram::new();
+static SYSCALL_LATENCY: [Histogram; MAX_SYSCALLS] = [EMPTY_HISTOGRAM; MAX_SYSCALLS];
+static IRQ_LATENCY: [Histogram; MAX_VECTORS] = [EMPTY_HISTOGRAM; MAX_VECTORS];
+
+/// Returns the exclusive upper limit of bucket __i__, the last bucket has none
+fn bucket_limit_us(i: usize) -> String {
+    if i == BUCKETS - 1 {
+        return String::from("inf");
+    }
+    format!("{}", 1u64 << i)
+}
+
+/// Measures the time until it is dropped and records it in its histogram
+pub struct LatencyTimer {
+    histogram: &'static Histogram,
+    start_ns: u64,
+}
+
+impl Drop for LatencyTimer {
+    fn drop(&mut self) {
+        let ns = time::monotonic_ns().saturating_sub(self.start_ns);
+        self.histogram.record(ns);
+    }
+}
+
+fn start(histogram: Option<&'static Histogram>) -> Option<LatencyTimer> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    histogram.map(|histogram| LatencyTimer {
+        histogram,
+        start_ns: time::monotonic_ns(),
+    })
+}
+
+/// Starts measuring the syscall __number__
+pub fn syscall_timer(number: usize) -> Option<LatencyTimer> {
+    start(SYSCALL_LATENCY.get(number))
+}
+
+/// Starts measuring the handler of interrupt __vector__
+pub fn irq_timer(vector: usize) -> Option<LatencyTimer> {
+    start(IRQ_LATENCY.get(vector))
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Clears every histogram, a handler running at the same time could leave a sample behind
+pub fn reset() {
+    for histogram in SYSCALL_LATENCY.iter().chain(IRQ_LATENCY.iter()) {
+        histogram.reset();
+    }
+}
+
+pub fn proc_latency() -> String {
+    let mut buf = String::from("syscalls:\n");
+    for (number, histogram) in SYSCALL_LATENCY.iter().enumerate() {
+        let name = match syscall::syscall_name(number) {
+            Some(name) => String::from(name),
+            None => format!("{}", number),
+        };
+        histogram.write_line(&mut buf, &name);
+    }
+
+    buf.push_str("interrupts:\n");
+    for (vector, histogram) in IRQ_LATENCY.iter().enumerate() {
+        histogram.write_line(&mut buf, &format!("{:#x}", vector));
+    }
+
+    buf
+}
@@ -0,0 +1,106 @@
+//! Limits of the kernel, the ones userspace may depend on can be queried with sysconf
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::mm::phys::FRAME_SIZE;
+
+/// Maximum number of processes that can exist at the same time
+pub const PROCESS_MAX: usize = 1024;
+
+/// Maximum number of threads that can exist at the same time, kernel threads included,
+/// every thread has its own kernel stack so this also bounds the kernel stack regions
+pub const THREAD_MAX: usize = 4096;
+
+/// Maximum number of CPUs the kernel uses, the rest are left parked
+pub const CPU_MAX: usize = 16;
+
+/// Maximum number of file descriptors a process can have open
+pub const OPEN_MAX: usize = 256;
+
+// the fs/open_max sysctl can lower the limit at runtime
+static OPEN_MAX_CURRENT: AtomicUsize = AtomicUsize::new(OPEN_MAX);
+
+/// Returns the number of file descriptors a process can have open at the moment
+pub fn open_max() -> usize {
+    OPEN_MAX_CURRENT.load(Ordering::Relaxed)
+}
+
+/// Sets the number of file descriptors a process can have open, returns false if __max__ is
+/// 0 or more than OPEN_MAX. Processes keep the descriptors they already have.
+pub fn set_open_max(max: usize) -> bool {
+    if max == 0 || max > OPEN_MAX {
+        return false;
+    }
+
+    OPEN_MAX_CURRENT.store(max, Ordering::Relaxed);
+    true
+}
+
+/// Size the stack of a process can grow to by default in bytes
+pub const STACK_MAX: usize = 8 * 1024 * 1024;
+
+/// Largest stack limit that can be set in bytes
+pub const STACK_MAX_LIMIT: usize = 1024 * 1024 * 1024;
+
+// the vm/stack_max_kb sysctl can change the limit at runtime
+static STACK_MAX_CURRENT: AtomicUsize = AtomicUsize::new(STACK_MAX);
+
+/// Returns the size the stack of a process can grow to, it is the size of the address space
+/// reserved for the stack when a process execs
+pub fn stack_max() -> usize {
+    STACK_MAX_CURRENT.load(Ordering::Relaxed)
+}
+
+/// Sets the size the stack of a process can grow to, it is rounded down to a page. Returns
+/// false if __max__ is smaller than a page or larger than STACK_MAX_LIMIT. Processes keep
+/// the limit they were started with.
+pub fn set_stack_max(max: usize) -> bool {
+    let max = max - max % PAGE_SIZE;
+    if max == 0 || max > STACK_MAX_LIMIT {
+        return false;
+    }
+
+    STACK_MAX_CURRENT.store(max, Ordering::Relaxed);
+    true
+}
+
+/// Maximum length of a path in bytes
+pub const PATH_MAX: usize = 4096;
+
+/// Maximum length of a path component in bytes
+pub const NAME_MAX: usize = 256;
+
+/// Maximum length of a single argument or environment string passed to execve
+pub const ARG_MAX: usize = 128 * 1024;
+
+/// Size of a page in bytes
+pub const PAGE_SIZE: usize = FRAME_SIZE;
+
+/// Maximum number of buffers readv and writev accept
+pub const IOV_MAX: usize = 1024;
+
+/// Writes to a pipe of at most this many bytes are not interleaved with other writes
+pub const PIPE_BUF: usize = PAGE_SIZE;
+
+// names accepted by the sysconf syscall
+pub const SC_CHILD_MAX: usize = 1;
+pub const SC_OPEN_MAX: usize = 2;
+pub const SC_PAGE_SIZE: usize = 3;
+pub const SC_THREAD_THREADS_MAX: usize = 4;
+pub const SC_PATH_MAX: usize = 5;
+pub const SC_NAME_MAX: usize = 6;
+pub const SC_IOV_MAX: usize = 7;
+
+/// Returns the value of a limit by its sysconf name
+pub fn sysconf(name: usize) -> Option<usize> {
+    match name {
+        SC_CHILD_MAX => Some(PROCESS_MAX),
+        SC_OPEN_MAX => Some(open_max()),
+        SC_PAGE_SIZE => Some(PAGE_SIZE),
+        SC_THREAD_THREADS_MAX => Some(THREAD_MAX),
+        SC_PATH_MAX => Some(PATH_MAX),
+        SC_NAME_MAX => Some(NAME_MAX),
+        SC_IOV_MAX => Some(IOV_MAX),
+        _ => None,
+    }
+}
@@ -1,19 +1,91 @@
-use core::fmt;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
 
-use crate::{drivers, sync::InterruptMutex, time};
+use crate::{
+    drivers,
+    framebuffer::{self, PanicWriter},
+    kmsg,
+    sync::InterruptMutex,
+    time,
+};
 
 pub const USE_ANSI_CODES: bool = true;
 pub const LOG_DEBUG: bool = true;
 
+static SERIAL_OUTPUT: AtomicBool = AtomicBool::new(true);
+
+/// Severity of a log message, messages less severe than the log level are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Log = 2,
+    Debug = 3,
+}
+
+impl LogLevel {
+    /// Returns the syslog priority of the level, /dev/kmsg and dmesg report it
+    pub fn syslog_priority(self) -> u8 {
+        match self {
+            LogLevel::Error => 3,
+            LogLevel::Warn => 4,
+            LogLevel::Log => 6,
+            LogLevel::Debug => 7,
+        }
+    }
+
+    /// Returns the level of the syslog priority __priority__, the ones more severe than an
+    /// error are errors too
+    pub fn from_syslog_priority(priority: u8) -> LogLevel {
+        match priority {
+            0..=3 => LogLevel::Error,
+            4 => LogLevel::Warn,
+            5 | 6 => LogLevel::Log,
+            _ => LogLevel::Debug,
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+pub fn log_level() -> u8 {
+    LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Sets the least severe level that is still printed, returns false if __level__ is not a
+/// valid `LogLevel`
+pub fn set_log_level(level: u8) -> bool {
+    if level > LogLevel::Debug as u8 {
+        return false;
+    }
+
+    LOG_LEVEL.store(level, Ordering::Relaxed);
+    true
+}
+
+/// Enables or disables mirroring the kernel log to COM1
+pub fn set_serial_output(enabled: bool) {
+    SERIAL_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn serial_output_enabled() -> bool {
+    SERIAL_OUTPUT.load(Ordering::Relaxed)
+}
+
 struct Writer {
     newline: bool,
+    /// The log is also drawn on the screen after a panic
+    panic_writer: Option<PanicWriter>,
 }
 
 unsafe impl Send for Writer {}
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        if cfg!(serial_module)
+        if cfg!(serial_module) && serial_output_enabled()
         /*&& drivers::is_loaded("serial")*/
         {
             for c in s.bytes() {
@@ -21,18 +93,60 @@ impl fmt::Write for Writer {
             }
         }
 
+        if let Some(panic_writer) = &mut self.panic_writer {
+            panic_writer.write_str(s)?;
+        }
+
         Ok(())
     }
 }
 
-static WRITER: InterruptMutex<Writer> = InterruptMutex::new(Writer { newline: false });
+static WRITER: InterruptMutex<Writer> = InterruptMutex::new(Writer {
+    newline: false,
+    panic_writer: None,
+});
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Makes the log usable from the panic handler even if the panic happened while the log or
+/// the framebuffer was locked, the log is drawn directly on the screen from now on.
+/// Returns false if the kernel is already panicking.
+pub fn enter_panic_mode() -> bool {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    // a panic early in the boot would otherwise be written to a port nobody set up
+    if cfg!(serial_module) && !drivers::serial::is_present() {
+        drivers::serial::init_polled();
+    }
+
+    unsafe {
+        WRITER.force_unlock();
+        WRITER.lock().panic_writer = framebuffer::panic_writer();
+    }
+
+    true
+}
+
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
 
 fn print(args: fmt::Arguments) {
     let mut writer = WRITER.lock();
     fmt::Write::write_fmt(&mut *writer, args).ok();
 }
 
-pub fn print_log(name: &str, color: [u8; 3], args: fmt::Arguments) {
+pub fn print_log(level: LogLevel, name: &str, color: [u8; 3], args: fmt::Arguments) {
+    // every message is kept in the log ring, the log level only decides what is printed
+    kmsg::record(level, args);
+
+    // everything is printed once the kernel panics
+    if level as u8 > log_level() && !is_panicking() {
+        return;
+    }
+
     let time = time::elapsed();
 
     if USE_ANSI_CODES {
@@ -52,24 +166,50 @@ pub fn print_log(name: &str, color: [u8; 3], args: fmt::Arguments) {
 
 #[macro_export]
 macro_rules! log {
-    ($($t:tt)*) => { $crate::logger::print_log("log", [40, 100, 190],  format_args!($($t)*)) };
+    ($($t:tt)*) => {
+        $crate::logger::print_log(
+            $crate::logger::LogLevel::Log,
+            "log",
+            [40, 100, 190],
+            format_args!($($t)*),
+        )
+    };
 }
 
 #[macro_export]
 macro_rules! warn {
-    ($($t:tt)*) => { $crate::logger::print_log("warn", [210, 200, 20], format_args!($($t)*)) };
+    ($($t:tt)*) => {
+        $crate::logger::print_log(
+            $crate::logger::LogLevel::Warn,
+            "warn",
+            [210, 200, 20],
+            format_args!($($t)*),
+        )
+    };
 }
 
 #[macro_export]
 macro_rules! debug {
     ($($t:tt)*) => {
         if $crate::logger::LOG_DEBUG {
-            $crate::logger::print_log("dbg", [175, 100, 200], format_args!($($t)*))
+            $crate::logger::print_log(
+                $crate::logger::LogLevel::Debug,
+                "dbg",
+                [175, 100, 200],
+                format_args!($($t)*),
+            )
         }
     };
 }
 
 #[macro_export]
 macro_rules! error {
-    ($($t:tt)*) => { $crate::logger::print_log("error", [160, 15, 15], format_args!($($t)*)) };
+    ($($t:tt)*) => {
+        $crate::logger::print_log(
+            $crate::logger::LogLevel::Error,
+            "error",
+            [160, 15, 15],
+            format_args!($($t)*),
+        )
+    };
 }
@@ -16,33 +16,53 @@ extern crate alloc;
 
 #[macro_use]
 mod logger;
+mod acpi;
 mod arch;
+mod audit;
 mod blk;
+mod bootstat;
+mod cmdline;
 mod console;
+mod crashdump;
 mod dma;
 mod drivers;
+mod entropy;
 mod framebuffer;
 mod fs;
+mod input;
+mod kconfig;
+mod kgdb;
+mod kmsg;
+mod ksyms;
+mod latency;
+mod limits;
 mod mm;
+mod mmio;
 mod pci;
 mod posix;
 mod scheduler;
+mod stack_protector;
 mod sync;
 mod syscall;
 mod syscalls;
+mod sysctl;
 mod time;
+mod tty;
 mod utils;
 
 use alloc::slice;
 use arch::x86_64::{self, gdt};
 use fs::VFS;
-use limine::{BootTimeRequest, FramebufferRequest, HhdmRequest, MemmapRequest};
-use scheduler::SCHEDULER;
+use limine::{
+    BootTimeRequest, FramebufferRequest, HhdmRequest, KernelFileRequest, MemmapRequest, RsdpRequest,
+};
+use scheduler::{thread::ThreadInner, SCHEDULER};
 
 use crate::{
-    arch::x86_64::{disable_interrupts, get_current_pml4, idt, pic, stacktrace},
-    fs::devfs,
-    mm::{virt::HDDM_VIRT_START, VirtAddr},
+    arch::x86_64::{disable_interrupts, get_current_pml4, idt, irq, pic, smp, stacktrace},
+    fs::{devfs, procfs, tmpfs},
+    mm::{virt::HDDM_VIRT_START, PhysAddr, VirtAddr},
+    posix::MountFlags,
     scheduler::proc,
 };
 
@@ -50,6 +70,8 @@ static MMAP_INFO: MemmapRequest = MemmapRequest::new(0);
 static HHDM_INFO: HhdmRequest = HhdmRequest::new(0);
 static BOOT_TIME_INFO: BootTimeRequest = BootTimeRequest::new(0);
 static FRAMEBUFFER_INFO: FramebufferRequest = FramebufferRequest::new(0);
+static KERNEL_FILE_INFO: KernelFileRequest = KernelFileRequest::new(0);
+static RSDP_INFO: RsdpRequest = RsdpRequest::new(0);
 
 #[no_mangle]
 fn vmm_setup() {
@@ -88,6 +110,7 @@ fn vmm_setup() {
 
     framebuffer::init(
         VirtAddr::new(HDDM_VIRT_START.get() + buff_phys),
+        PhysAddr::new(buff_phys),
         fb.width as usize,
         fb.height as usize,
         fb.pitch as usize,
@@ -104,12 +127,37 @@ fn vmm_setup() {
 
 #[no_mangle]
 fn kernel_init() -> ! {
+    bootstat::init();
+
     let boot_time = BOOT_TIME_INFO
         .get_response()
         .get()
         .expect("BOOT TIME request failed")
         .boot_time;
 
+    let kernel_file = KERNEL_FILE_INFO
+        .get_response()
+        .get()
+        .and_then(|resp| resp.kernel_file.get());
+    if let Some(cmdline) = kernel_file.and_then(|file| file.cmdline.to_str()) {
+        cmdline::init(cmdline.to_bytes());
+    }
+
+    // newer bootloaders report the RSDP as a higher half address, older ones as a physical one
+    let hhdm = HHDM_INFO.get_response().get().unwrap().offset;
+    match RSDP_INFO
+        .get_response()
+        .get()
+        .and_then(|resp| resp.address.as_ptr())
+    {
+        Some(rsdp) if rsdp as u64 >= hhdm => acpi::init(PhysAddr::new(rsdp as u64 - hhdm)),
+        Some(rsdp) => acpi::init(PhysAddr::new(rsdp as u64)),
+        None => warn!("ACPI: no RSDP, the legacy PIC is used"),
+    }
+
+    // the APs are parked in bootloader memory until they are moved into the kernel
+    smp::park_aps();
+
     // only unmap it after every we executed every request
     let pml4 = get_current_pml4();
     pml4.unmap_limine_pages();
@@ -120,12 +168,21 @@ fn kernel_init() -> ! {
 
     idt::init();
     pic::init();
+    bootstat::stage_done("gdt/idt/pic");
 
     time::init(boot_time as u64);
+    entropy::init(boot_time as u64);
+    // kernel_init never returns so its canary is not checked after the guard changes
+    stack_protector::init();
 
     mm::kalloc::init(&pml4);
 
     mm::phys::init_page_descriptors();
+    bootstat::stage_done("mm");
+
+    smp::init_bsp();
+    irq::init();
+    time::init_clock_source();
 
     SCHEDULER.init(&pml4);
     SCHEDULER.create_kernel_thread(main_init_thread);
@@ -133,41 +190,150 @@ fn kernel_init() -> ! {
 }
 
 fn main_init_thread() {
+    bootstat::stage_done("scheduler start");
+
     drivers::init();
 
     drivers::preload_driver("serial");
     drivers::preload_driver("pit");
+    crashdump::init();
+    kgdb::init();
+
+    // the PIT has to run before the APs start, the ones without a calibrated local APIC timer
+    // are scheduled by the ticks the BSP forwards to them
+    smp::start_aps();
+    bootstat::stage_done("smp");
 
     pci::init();
+    bootstat::stage_done("pci scan");
 
     drivers::load_drivers();
 
     {
         let mut vfs = VFS.write();
-        let part = blk::get_partition(1, 0, 0).unwrap();
-        vfs.mount("/", part, "fat32").unwrap();
+        // root=LABEL=<label> or root=UUID=<uuid> selects the root partition
+        let (part, fs_name) = match cmdline::get("root") {
+            Some(spec) => vfs
+                .find_partition(&spec)
+                .unwrap_or_else(|| panic!("root partition {} not found", spec)),
+            None => (blk::get_partition(1, 0, 0).unwrap(), "fat32"),
+        };
+        vfs.mount("/", part, fs_name, MountFlags::empty()).unwrap();
     }
+    bootstat::stage_done("root mount");
 
     devfs::init();
-    console::init();
+    sysctl::init();
+    procfs::init();
+    tmpfs::init();
 
-    // we have to initialize the font after kalloc has been initialized
+    // we have to initialize the font after kalloc has been initialized, the console needs
+    // the size of the screen in characters
     framebuffer::init_font();
+    console::init();
+    framebuffer::devfs::init();
+    kmsg::init();
 
     syscall::init();
+    bootstat::stage_done("vfs and console");
+
+    if cfg!(ksm) {
+        SCHEDULER.create_kernel_thread(mm::ksm::ksm_thread);
+    }
 
     proc::load_base_process("/bin/rose");
+    bootstat::stage_done("init exec");
+    bootstat::print_summary();
 }
 
 #[panic_handler]
 fn rust_panic(info: &core::panic::PanicInfo) -> ! {
     disable_interrupts();
 
-    stacktrace::walk();
+    // the output of a panic while panicking could only make things worse
+    if logger::is_panicking() {
+        hcf();
+    }
+
+    init_early_framebuffer();
+    if !logger::enter_panic_mode() {
+        hcf();
+    }
+
+    smp::halt_others();
+
     error!("{}", info);
+    stacktrace::walk();
+    dump_current_thread();
+    // a deadlock shows up as threads that are stuck, so everything is dumped
+    proc::dump_processes();
+    SCHEDULER.dump_threads();
+    crashdump::write(info);
     hcf();
 }
 
+/// Points the framebuffer at the one set up by Limine if the kernel panicked before vmm_setup
+/// initialized it, the page tables of Limine still map it at that point
+fn init_early_framebuffer() {
+    if framebuffer::is_initialized() {
+        return;
+    }
+
+    let framebuffer = match FRAMEBUFFER_INFO.get_response().get() {
+        Some(framebuffer) if framebuffer.framebuffer_count > 0 => framebuffer,
+        _ => return,
+    };
+
+    let fb = &framebuffer.framebuffers()[0];
+    let buff_addr = match fb.address.as_ptr() {
+        Some(addr) if fb.bpp == 32 => addr as u64,
+        _ => return,
+    };
+
+    // only the virtual address is used while panicking
+    let buff_phys = match HHDM_INFO.get_response().get() {
+        Some(hhdm) => PhysAddr::new(buff_addr - hhdm.offset),
+        None => PhysAddr::zero(),
+    };
+
+    framebuffer::init(
+        VirtAddr::new(buff_addr),
+        buff_phys,
+        fb.width as usize,
+        fb.height as usize,
+        fb.pitch as usize,
+        fb.bpp as usize,
+    );
+}
+
+fn dump_current_thread() {
+    let thread_lock = match SCHEDULER.try_get_current_thread() {
+        Some(thread) => thread,
+        None => {
+            error!("current thread: none or the scheduler is locked");
+            return;
+        }
+    };
+
+    let thread = match thread_lock.try_lock() {
+        Some(thread) => thread,
+        None => {
+            error!("current thread: locked");
+            return;
+        }
+    };
+
+    match &thread.inner {
+        ThreadInner::User(data) => error!(
+            "current thread: {:?} {:?} user pid: {}",
+            thread.id, thread.state, data.pid
+        ),
+        ThreadInner::Kernel(_) => {
+            error!("current thread: {:?} {:?} kernel", thread.id, thread.state)
+        }
+    }
+}
+
 /// Die, spectacularly.
 pub fn hcf() -> ! {
     loop {
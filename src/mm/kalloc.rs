@@ -1,33 +1,136 @@
-use core::alloc::{GlobalAlloc, Layout};
-use spin::Mutex;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+use spin::{Mutex, MutexGuard};
 
 use crate::{
-    arch::x86_64::{get_current_pml4, paging::PageFlags},
+    arch::x86_64::{
+        disable_interrupts, enable_interrupts, get_current_pml4, interrupts_enabled,
+        paging::PageFlags, smp,
+    },
+    cmdline, entropy,
+    limits::CPU_MAX,
     utils,
 };
 
 use super::{
-    virt::{KERNEL_HEAP_START, PML4},
+    phys::PHYS_ALLOCATOR,
+    slab::{SlabCache, SlabCacheId, SlabCacheStats, SLAB_CACHES_MAX},
+    virt::{KERNEL_HEAP_END, KERNEL_HEAP_START, PAGE_ENTRIES, PAGE_SIZE_4KIB, PML4},
     VirtAddr,
 };
 
 const KERNEL_HEAP_BASE_SIZE: usize = 1024 * 1024; // 1024 KiB
+/// The heap starts a random number of pages after KERNEL_HEAP_START, less than this many
+/// bytes, 64 GiB
+const KERNEL_HEAP_SLIDE_MAX: usize = 1 << 36;
+const KERNEL_HEAP_MAX_SIZE: usize =
+    (KERNEL_HEAP_END.get() - KERNEL_HEAP_START.get()) as usize - KERNEL_HEAP_SLIDE_MAX;
 const MINIMUM_REGION_SIZE: usize = 8;
 
+/// The size the heap can grow to, it can be lowered with the kernel_heap_max_kb command line
+/// option or the vm/kernel_heap_max_kb sysctl. Lowering it below the current size does not
+/// shrink the heap, it just can not grow anymore.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(KERNEL_HEAP_MAX_SIZE);
+
+/// Address of the first byte of the heap, chosen at boot so the heap is not at the same
+/// address on every boot
+static HEAP_START: AtomicU64 = AtomicU64::new(KERNEL_HEAP_START.get());
+
+/// The subsystem an allocation is accounted to, the allocations made while a guard returned
+/// by `tag` is alive get its tag
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapTag {
+    Other,
+    Fs,
+    Blk,
+    Drivers,
+    Sched,
+}
+
+pub const HEAP_TAG_COUNT: usize = 5;
+
+impl HeapTag {
+    pub fn from_index(index: usize) -> HeapTag {
+        match index {
+            1 => HeapTag::Fs,
+            2 => HeapTag::Blk,
+            3 => HeapTag::Drivers,
+            4 => HeapTag::Sched,
+            _ => HeapTag::Other,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeapTag::Other => "other",
+            HeapTag::Fs => "fs",
+            HeapTag::Blk => "blk",
+            HeapTag::Drivers => "drivers",
+            HeapTag::Sched => "sched",
+        }
+    }
+}
+
+/// Heap usage of a tag, sizes are in bytes and do not include region headers
+#[derive(Debug, Clone, Copy)]
+pub struct TagUsage {
+    pub used: usize,
+    /// The most the tag has used at once
+    pub peak: usize,
+    pub allocations: usize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const TAG_OTHER: AtomicU8 = AtomicU8::new(HeapTag::Other as u8);
+
+// the tag of the thread running on each CPU, the scheduler saves it when it switches away
+// from a thread and restores it when it switches back
+static CURRENT_TAGS: [AtomicU8; CPU_MAX] = [TAG_OTHER; CPU_MAX];
+
+/// Restores the previous tag when it is dropped
+pub struct HeapTagGuard {
+    prev: HeapTag,
+}
+
 #[derive(Clone, Copy)]
 struct Node {
     size: usize,
     allocated: bool,
+    tag: u8,
+    /// The index of the slab cache the region belongs to plus one, 0 if it is not a cached
+    /// object
+    slab: u8,
 }
 
 struct KernelAllocator;
 
+/// Usage of the kernel heap, sizes are in bytes and do not include region headers
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    /// The largest size the heap has been
+    pub peak_size: usize,
+    pub limit: usize,
+    pub used: usize,
+    pub free: usize,
+    pub largest_free: usize,
+    pub regions: usize,
+}
+
 struct KernelAllocatorInner {
     current_size: usize,
+    peak_size: usize,
     allocated_nodes: usize,
     initialized: bool,
+    tags: [TagUsage; HEAP_TAG_COUNT],
+    slab_caches: [Option<SlabCache>; SLAB_CACHES_MAX],
 }
 
+const NO_SLAB_CACHE: Option<SlabCache> = None;
+
 impl Node {
     fn next(&self) -> Option<&mut Node> {
         assert_ne!(self.size, 0);
@@ -45,38 +148,58 @@ unsafe impl Send for KernelAllocatorInner {}
 static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator;
 static KERNEL_ALLOCATOR_INNER: Mutex<KernelAllocatorInner> = Mutex::new(KernelAllocatorInner {
     current_size: 0,
+    peak_size: 0,
     allocated_nodes: 0,
     initialized: false, // FIXME: this ^^
+    tags: [TagUsage {
+        used: 0,
+        peak: 0,
+        allocations: 0,
+    }; HEAP_TAG_COUNT],
+    slab_caches: [NO_SLAB_CACHE; SLAB_CACHES_MAX],
 });
 
 impl KernelAllocatorInner {
     fn head() -> &'static mut Node {
-        unsafe { (KERNEL_HEAP_START.get() as *mut Node).as_mut().unwrap() }
+        unsafe { (heap_start().get() as *mut Node).as_mut().unwrap() }
     }
 
-    fn heap_end(&self) -> VirtAddr {
-        VirtAddr::new(KERNEL_HEAP_START.get() + self.current_size as u64)
+    fn header(addr: usize) -> &'static mut Node {
+        let header_addr = addr - core::mem::size_of::<Node>();
+        unsafe { (header_addr as *mut Node).as_mut().unwrap() }
     }
 
-    fn extend_heap(&mut self, min_size: usize) -> usize {
-        let pml4 = get_current_pml4();
+    fn heap_end(&self) -> VirtAddr {
+        VirtAddr::new(heap_start().get() + self.current_size as u64)
+    }
 
-        let mut size = self.current_size;
-        while size < min_size {
-            size *= 2;
+    /// Maps the pages needed for at least `min_size` more bytes after the end of the heap,
+    /// returns the number of bytes the heap grew by or None if it can not grow
+    fn extend_heap(&mut self, min_size: usize) -> Option<usize> {
+        let size = utils::align(min_size, PAGE_SIZE_4KIB as usize);
+        if self.current_size + size > heap_limit() {
+            return None;
         }
 
-        let newly_allocated_size = size - self.current_size;
-
-        debug!("{} {} {} {}", newly_allocated_size, size, min_size, self.current_size);
+        // map_range panics if it runs out of frames so make sure that there are enough
+        // for the pages and the page tables that might be needed to map them
+        let pages = size / PAGE_SIZE_4KIB as usize;
+        let table_pages = pages.div_ceil(PAGE_ENTRIES) + 2;
+        let (total_frames, used_frames) = PHYS_ALLOCATOR.lock().usage();
+        if total_frames - used_frames < pages + table_pages {
+            return None;
+        }
 
+        let pml4 = get_current_pml4();
         let start_virt = self.heap_end();
-        let end_virt = self.heap_end() + VirtAddr::new(newly_allocated_size as u64);
+        let end_virt = start_virt + VirtAddr::new(size as u64);
         let flags = PageFlags::READ_WRITE | PageFlags::PRESENT;
 
         pml4.map_range(start_virt, end_virt, flags);
+        self.current_size += size;
+        self.peak_size = usize::max(self.peak_size, self.current_size);
 
-        newly_allocated_size
+        Some(size)
     }
 
     ///
@@ -95,7 +218,10 @@ impl KernelAllocatorInner {
             assert!(heap_end.get() >= current_addr);
             // extend heap when we reach the end of the heap
             if heap_end.get() == current_addr {
-                let extended = self.extend_heap(self.current_size + size);
+                // the alignment padding and the header of the region that is split off
+                // after the allocation have to fit too
+                let needed = 2 * core::mem::size_of::<Node>() + size + align + MIN_SIZE;
+                let extended = self.extend_heap(needed)?;
                 current.size = extended - core::mem::size_of::<Node>();
                 current.allocated = false;
             }
@@ -170,11 +296,222 @@ impl KernelAllocatorInner {
         None
     }
 
+    /// Halves the heap while the last region is free and it covers more than three quarters
+    /// of the heap, the unmapped frames are given back to the physical allocator
+    fn shrink_heap(&mut self, last: &mut Node) {
+        const MIN_SIZE: usize = core::mem::size_of::<Node>() + MINIMUM_REGION_SIZE;
+
+        let pml4 = get_current_pml4();
+        let last_offset = (last as *const _ as u64 - heap_start().get()) as usize;
+
+        while self.current_size > KERNEL_HEAP_BASE_SIZE
+            && last_offset + MIN_SIZE <= self.current_size / 4
+        {
+            let new_size = usize::max(
+                utils::align(self.current_size / 2, PAGE_SIZE_4KIB as usize),
+                KERNEL_HEAP_BASE_SIZE,
+            );
+            let start_virt = heap_start() + VirtAddr::new(new_size as u64);
+
+            pml4.unmap_range(start_virt, self.heap_end());
+
+            self.current_size = new_size;
+            last.size = new_size - last_offset - core::mem::size_of::<Node>();
+        }
+    }
+
+    /// Merges the free regions that directly follow __region__ into it
+    fn merge_free_successors(&self, region: &mut Node) {
+        let heap_end = self.heap_end().get();
+        loop {
+            let next_addr =
+                region as *const _ as u64 + (core::mem::size_of::<Node>() + region.size) as u64;
+            if next_addr >= heap_end {
+                return;
+            }
+
+            let next = unsafe { (next_addr as *const Node).read() };
+            if next.allocated {
+                return;
+            }
+            region.size += core::mem::size_of::<Node>() + next.size;
+        }
+    }
+
+    /// Merges every run of free regions and unmaps all the pages of the free region at the end
+    /// of the heap, returns the number of bytes given back
+    fn trim(&mut self) -> usize {
+        const MIN_SIZE: usize = core::mem::size_of::<Node>() + MINIMUM_REGION_SIZE;
+
+        let heap_end = self.heap_end().get();
+        let mut current = KernelAllocatorInner::head();
+        loop {
+            if !current.allocated {
+                self.merge_free_successors(current);
+            }
+
+            let next_addr =
+                current as *const _ as u64 + (core::mem::size_of::<Node>() + current.size) as u64;
+            if next_addr >= heap_end {
+                break;
+            }
+            current = current.next().unwrap();
+        }
+
+        // the last region is in use, there is nothing to give back
+        if current.allocated {
+            return 0;
+        }
+
+        let last_offset = (current as *const _ as u64 - heap_start().get()) as usize;
+        let new_size = usize::max(
+            utils::align(last_offset + MIN_SIZE, PAGE_SIZE_4KIB as usize),
+            KERNEL_HEAP_BASE_SIZE,
+        );
+        if new_size >= self.current_size {
+            return 0;
+        }
+
+        let start_virt = heap_start() + VirtAddr::new(new_size as u64);
+        get_current_pml4().unmap_range(start_virt, self.heap_end());
+
+        let released = self.current_size - new_size;
+        self.current_size = new_size;
+        current.size = new_size - last_offset - core::mem::size_of::<Node>();
+        released
+    }
+
     fn free_region(&mut self, addr: usize) {
         let header_addr = addr - core::mem::size_of::<Node>();
         let region = unsafe { (header_addr as *mut Node).as_mut().unwrap() };
         assert!(region.allocated);
         region.allocated = false;
+
+        // a free region at the end of the heap lets the heap shrink
+        self.merge_free_successors(region);
+
+        let next_addr = header_addr + core::mem::size_of::<Node>() + region.size;
+        if next_addr as u64 == self.heap_end().get() {
+            self.shrink_heap(region);
+        }
+    }
+
+    /// Allocates a region for __layout__ accounted to the tag of the current CPU, the objects
+    /// of a slab cache with the same layout are reused first
+    fn alloc(&mut self, layout: Layout) -> Option<usize> {
+        let tag = current_tag();
+        let cache_index = self
+            .slab_caches
+            .iter()
+            .position(|cache| cache.as_ref().is_some_and(|cache| cache.matches(layout)));
+
+        let cached = match cache_index {
+            Some(index) => self.slab_caches[index].as_mut().unwrap().pop(),
+            None => None,
+        };
+        let addr = match cached {
+            Some(addr) => addr,
+            None => self.get_free_region(layout.size(), layout.align())?,
+        };
+
+        if let Some(index) = cache_index {
+            let cache = self.slab_caches[index].as_mut().unwrap();
+            cache.active += 1;
+            cache.allocations += 1;
+        }
+
+        let header = Self::header(addr);
+        header.tag = tag as u8;
+        header.slab = match cache_index {
+            Some(index) => index as u8 + 1,
+            None => 0,
+        };
+
+        let usage = &mut self.tags[tag as usize];
+        usage.used += header.size;
+        usage.peak = usize::max(usage.peak, usage.used);
+        usage.allocations += 1;
+
+        Some(addr)
+    }
+
+    /// Frees the region at __addr__, an object of a slab cache is put on the free list of the
+    /// cache unless the cache is full
+    fn free(&mut self, addr: usize) {
+        let header = Self::header(addr);
+        self.tags[header.tag as usize].used -= header.size;
+
+        if header.slab != 0 {
+            let cache = self.slab_caches[header.slab as usize - 1].as_mut().unwrap();
+            cache.active -= 1;
+            if cache.push(addr) {
+                return;
+            }
+        }
+
+        self.free_region(addr);
+    }
+
+    /// Frees the objects on the free lists of the slab caches, returns the number of bytes
+    /// given back to the heap
+    fn reclaim_slab_caches(&mut self) -> usize {
+        let mut freed = 0;
+        for index in 0..SLAB_CACHES_MAX {
+            while let Some(addr) = self.slab_caches[index]
+                .as_mut()
+                .and_then(|cache| cache.pop())
+            {
+                freed += Self::header(addr).size;
+                self.free_region(addr);
+            }
+        }
+
+        freed
+    }
+
+    fn create_slab_cache(&mut self, cache: SlabCache) -> Option<SlabCacheId> {
+        let mut free_slot = None;
+        for (index, slot) in self.slab_caches.iter().enumerate() {
+            match slot {
+                Some(existing) if existing.matches(cache.layout) => {
+                    return Some(SlabCacheId(index))
+                }
+                Some(_) => {}
+                None if free_slot.is_none() => free_slot = Some(index),
+                None => {}
+            }
+        }
+
+        let index = free_slot?;
+        self.slab_caches[index] = Some(cache);
+        Some(SlabCacheId(index))
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            size: self.current_size,
+            peak_size: self.peak_size,
+            limit: heap_limit(),
+            used: 0,
+            free: 0,
+            largest_free: 0,
+            regions: 0,
+        };
+
+        let heap_end = self.heap_end().get();
+        let mut current = KernelAllocatorInner::head();
+        while (current as *const _ as u64) < heap_end {
+            if current.allocated {
+                stats.used += current.size;
+            } else {
+                stats.free += current.size;
+                stats.largest_free = usize::max(stats.largest_free, current.size);
+            }
+            stats.regions += 1;
+            current = current.next().unwrap();
+        }
+
+        stats
     }
 
     pub fn init(&mut self, pml4: &PML4) {
@@ -182,9 +519,15 @@ impl KernelAllocatorInner {
 
         self.initialized = true;
         self.current_size = KERNEL_HEAP_BASE_SIZE;
+        self.peak_size = self.current_size;
+
+        // the command line can not be read without the heap so the slide can not be turned off
+        let slide_pages = KERNEL_HEAP_SLIDE_MAX / PAGE_SIZE_4KIB as usize;
+        let slide = (entropy::random_u64() as usize % slide_pages) * PAGE_SIZE_4KIB as usize;
+        HEAP_START.store(KERNEL_HEAP_START.get() + slide as u64, Ordering::Relaxed);
 
-        let start_virt = KERNEL_HEAP_START;
-        let end_virt = KERNEL_HEAP_START + VirtAddr::new(self.current_size as u64);
+        let start_virt = heap_start();
+        let end_virt = start_virt + VirtAddr::new(self.current_size as u64);
         let flags = PageFlags::READ_WRITE | PageFlags::PRESENT;
 
         pml4.map_range(start_virt, end_virt, flags);
@@ -195,27 +538,168 @@ impl KernelAllocatorInner {
     }
 }
 
+// shrinking the heap waits for the other CPUs to flush their TLBs with the lock held
+fn lock_inner() -> MutexGuard<'static, KernelAllocatorInner> {
+    loop {
+        if let Some(inner) = KERNEL_ALLOCATOR_INNER.try_lock() {
+            return inner;
+        }
+
+        smp::handle_pending_shootdown();
+        core::hint::spin_loop();
+    }
+}
+
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut inner = KERNEL_ALLOCATOR_INNER.lock();
+        let mut inner = lock_inner();
         assert!(inner.initialized);
 
-        let region = inner
-            .get_free_region(layout.size(), layout.align())
-            .expect("OUT OF MEMORY");
+        if let Some(region) = inner.alloc(layout) {
+            return region as *mut u8;
+        }
 
-        region as *mut u8
+        // the cached slab objects might be in the way of a large allocation
+        if inner.reclaim_slab_caches() == 0 {
+            return core::ptr::null_mut();
+        }
+        match inner.alloc(layout) {
+            Some(region) => region as *mut u8,
+            None => core::ptr::null_mut(),
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: core::alloc::Layout) {
-        let mut inner = KERNEL_ALLOCATOR_INNER.lock();
+        let mut inner = lock_inner();
         assert!(inner.initialized);
 
-        inner.free_region(ptr as usize);
+        inner.free(ptr as usize);
     }
 }
 
 pub fn init(pml4: &PML4) {
-    let mut data = KERNEL_ALLOCATOR_INNER.lock();
-    data.init(pml4);
+    lock_inner().init(pml4);
+
+    // reading the command line needs the heap
+    if let Some(kb) = cmdline::get("kernel_heap_max_kb") {
+        match kb.parse::<usize>() {
+            Ok(kb) if set_heap_limit(kb.saturating_mul(1024)) => {}
+            _ => warn!("KALLOC: invalid heap limit {} KiB", kb),
+        }
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    lock_inner().stats()
+}
+
+/// Returns the address the heap starts at
+pub fn heap_start() -> VirtAddr {
+    VirtAddr::new(HEAP_START.load(Ordering::Relaxed))
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Sets the size the heap can grow to in bytes, it is rounded down to a page. Returns false if
+/// it is smaller than the initial heap or larger than the virtual address range of the heap.
+pub fn set_heap_limit(limit: usize) -> bool {
+    let limit = limit - limit % PAGE_SIZE_4KIB as usize;
+    if !(KERNEL_HEAP_BASE_SIZE..=KERNEL_HEAP_MAX_SIZE).contains(&limit) {
+        return false;
+    }
+
+    HEAP_LIMIT.store(limit, Ordering::Relaxed);
+    true
+}
+
+/// Gives the free pages at the end of the heap back to the physical allocator, returns the
+/// number of bytes the heap shrank by. The cached slab objects are freed first.
+pub fn trim_heap() -> usize {
+    let mut inner = lock_inner();
+    inner.reclaim_slab_caches();
+    inner.trim()
+}
+
+/// Accounts the allocations of the current thread to __tag__ until the returned guard is
+/// dropped, the guards can be nested
+pub fn tag(tag: HeapTag) -> HeapTagGuard {
+    let prev = current_tag();
+    set_current_tag(tag);
+    HeapTagGuard { prev }
+}
+
+impl Drop for HeapTagGuard {
+    fn drop(&mut self) {
+        set_current_tag(self.prev);
+    }
+}
+
+pub fn current_tag() -> HeapTag {
+    HeapTag::from_index(CURRENT_TAGS[smp::current_cpu()].load(Ordering::Relaxed) as usize)
+}
+
+/// Sets the tag of the current CPU, the scheduler calls this with the tag of the thread it
+/// switches to
+pub fn set_current_tag(tag: HeapTag) {
+    // the thread must not be moved to another CPU between finding the CPU and the store
+    let interrupts = interrupts_enabled();
+    if interrupts {
+        disable_interrupts();
+    }
+
+    CURRENT_TAGS[smp::current_cpu()].store(tag as u8, Ordering::Relaxed);
+
+    if interrupts {
+        enable_interrupts();
+    }
+}
+
+pub fn tag_usage() -> [TagUsage; HEAP_TAG_COUNT] {
+    lock_inner().tags
+}
+
+pub fn create_slab_cache(cache: SlabCache) -> Option<SlabCacheId> {
+    lock_inner().create_slab_cache(cache)
+}
+
+pub fn reclaim_slab_caches() -> usize {
+    lock_inner().reclaim_slab_caches()
+}
+
+pub fn slab_cache_stats() -> [Option<SlabCacheStats>; SLAB_CACHES_MAX] {
+    let inner = lock_inner();
+    let mut stats = [None; SLAB_CACHES_MAX];
+    for (stat, cache) in stats.iter_mut().zip(inner.slab_caches.iter()) {
+        *stat = cache.as_ref().map(|cache| cache.stats());
+    }
+
+    stats
+}
+
+/// Called when an allocation fails, the stack trace printed by the panic handler shows
+/// which allocation site ran out of memory
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    error!(
+        "KALLOC: failed to allocate {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+
+    let stats = heap_stats();
+    error!(
+        "KALLOC: heap size: {} limit: {} used: {} free: {} largest free region: {} \
+         regions: {}",
+        stats.size, stats.limit, stats.used, stats.free, stats.largest_free, stats.regions
+    );
+
+    // the allocation could have failed while the physical allocator was locked
+    if let Some(phys_allocator) = PHYS_ALLOCATOR.try_lock() {
+        let (total_frames, used_frames) = phys_allocator.usage();
+        error!("KALLOC: physical frames used: {}/{}", used_frames, total_frames);
+    }
+
+    panic!("kernel heap exhausted");
 }
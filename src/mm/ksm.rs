@@ -0,0 +1,104 @@
+//! Samepage merging, identical pages of the read-only regions of processes are merged into
+//! a single frame. Userspace can not write these regions, `Process::mprotect` unshares the
+//! merged frames of a region before it makes the region writable.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::{
+    arch::x86_64::paging::PageFlags,
+    mm::{phys::FRAME_SIZE, PhysAddr},
+    scheduler::proc,
+    time,
+};
+
+/// Time between two merging passes
+const KSM_SCAN_INTERVAL_MS: u64 = 10_000;
+
+/// Number of pages that were merged into another frame since boot
+static MERGED_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+fn frame_contents(phys: PhysAddr) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys.virt_addr().get() as *const u8, FRAME_SIZE) }
+}
+
+/// FNV-1a hash of the contents of a frame
+fn hash_frame(phys: PhysAddr) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    frame_contents(phys)
+        .iter()
+        .fold(FNV_OFFSET_BASIS, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/// Merges the identical read-only pages of every process, returns the number of pages
+/// merged in this pass. Processes that are locked by someone else are skipped.
+pub fn merge_pages() -> usize {
+    let processes = proc::processes();
+    // every process is kept locked so none of the frames can be unmapped during the pass
+    let locked: Vec<_> = processes.iter().filter_map(|p| p.try_lock()).collect();
+
+    // frames that are kept, indexed by the hash of their contents
+    let mut frames: BTreeMap<u64, Vec<PhysAddr>> = BTreeMap::new();
+    let mut merged = 0;
+
+    for p in locked.iter() {
+        let pml4 = p.pml4();
+
+        for virt in p.read_only_pages() {
+            let (phys, flags) = match pml4.get_page_entry_from_virt(virt) {
+                Some(ent) => ent,
+                None => continue,
+            };
+
+            // pages that were not touched yet are not backed by a frame
+            if !flags.contains(PageFlags::PRESENT) || flags.contains(PageFlags::READ_WRITE) {
+                continue;
+            }
+
+            let candidates = frames.entry(hash_frame(phys)).or_default();
+
+            // the same frame can be mapped by several processes after a fork
+            if candidates.contains(&phys) {
+                continue;
+            }
+
+            // a different hash collides rarely so the contents have to be compared
+            let contents = frame_contents(phys);
+            match candidates
+                .iter()
+                .find(|&&frame| frame_contents(frame) == contents)
+            {
+                Some(&frame) => {
+                    pml4.replace_frame(virt, frame);
+                    merged += 1;
+                }
+                None => candidates.push(phys),
+            }
+        }
+    }
+
+    MERGED_PAGES.fetch_add(merged, Ordering::Relaxed);
+    merged
+}
+
+/// Returns the number of pages that were merged since boot
+pub fn merged_pages() -> usize {
+    MERGED_PAGES.load(Ordering::Relaxed)
+}
+
+/// Kernel thread that periodically merges pages
+pub fn ksm_thread() {
+    loop {
+        time::sleep_until(time::elapsed().as_milliseconds() + KSM_SCAN_INTERVAL_MS);
+
+        let merged = merge_pages();
+        if merged > 0 {
+            log!("KSM: merged {} pages", merged);
+        }
+    }
+}
@@ -1,10 +1,16 @@
 pub mod kalloc;
+pub mod ksm;
+pub mod page_cache;
 pub mod phys;
+pub mod slab;
 pub mod virt;
 
-use core::{fmt, ops};
+use core::{
+    fmt, ops,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
-use alloc::slice;
+use alloc::{slice, string::String};
 
 use crate::mm::virt::PAGE_ENTRIES;
 
@@ -145,3 +151,109 @@ impl fmt::Display for PhysAddr {
         write!(f, "{:#x}", self.0)
     }
 }
+
+/// How mmap treats mappings that might not be backed by enough memory, the pages of
+/// anonymous mappings are only allocated when they are first accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OvercommitPolicy {
+    /// Mappings larger than the physical memory are refused
+    Heuristic = 0,
+    /// Every mapping is allowed
+    Always = 1,
+    /// Mappings larger than the free physical memory are refused
+    Never = 2,
+}
+
+static OVERCOMMIT_POLICY: AtomicU8 = AtomicU8::new(OvercommitPolicy::Heuristic as u8);
+
+pub fn overcommit_policy() -> u8 {
+    OVERCOMMIT_POLICY.load(Ordering::Relaxed)
+}
+
+/// Sets the overcommit policy, returns false if __policy__ is not a valid `OvercommitPolicy`
+pub fn set_overcommit_policy(policy: u8) -> bool {
+    if policy > OvercommitPolicy::Never as u8 {
+        return false;
+    }
+
+    OVERCOMMIT_POLICY.store(policy, Ordering::Relaxed);
+    true
+}
+
+/// Returns whether a new mapping of __len__ bytes is allowed by the overcommit policy.
+/// The memory already promised to other mappings is not accounted for, so even the
+/// Never policy only checks the mapping against the currently free memory.
+pub fn can_commit(len: usize) -> bool {
+    let frames = len.div_ceil(FRAME_SIZE);
+    let (total_frames, used_frames) = phys::PHYS_ALLOCATOR.lock().usage();
+
+    match overcommit_policy() {
+        policy if policy == OvercommitPolicy::Always as u8 => true,
+        policy if policy == OvercommitPolicy::Never as u8 => frames <= total_frames - used_frames,
+        _ => frames <= total_frames,
+    }
+}
+
+/// Generates the contents of /proc/meminfo
+pub fn meminfo() -> String {
+    let (total_frames, used_frames) = phys::PHYS_ALLOCATOR.lock().usage();
+    let heap = kalloc::heap_stats();
+    let (cached_pages, _, _) = page_cache::stats();
+    let kib = |bytes: usize| bytes / 1024;
+
+    format!(
+        "MemTotal: {} kB\nMemFree: {} kB\nMemUsed: {} kB\nFramesTotal: {}\nFramesUsed: {}\n\
+         KsmMergedPages: {}\nPageCache: {} kB\nHeapSize: {} kB\nHeapPeak: {} kB\nHeapLimit: {} kB\n\
+         HeapUsed: {} kB\nHeapFree: {} kB\nHeapLargestFree: {} kB\nHeapRegions: {}\n",
+        kib(total_frames * FRAME_SIZE),
+        kib((total_frames - used_frames) * FRAME_SIZE),
+        kib(used_frames * FRAME_SIZE),
+        total_frames,
+        used_frames,
+        ksm::merged_pages(),
+        kib(cached_pages * FRAME_SIZE),
+        kib(heap.size),
+        kib(heap.peak_size),
+        kib(heap.limit),
+        kib(heap.used),
+        kib(heap.free),
+        kib(heap.largest_free),
+        heap.regions,
+    )
+}
+
+/// Generates the contents of /proc/vmlayout, the fixed regions of the kernel half of the
+/// address space and how much of the heap is mapped
+pub fn proc_vmlayout() -> String {
+    let mut text = String::new();
+    for region in virt::KERNEL_REGIONS.iter() {
+        text.push_str(&format!(
+            "{:#x}-{:#x} pml4[{}] {}\n",
+            region.start.get(),
+            region.end.get(),
+            region.start.pml4_index(),
+            region.name
+        ));
+    }
+
+    let (image_start, image_end) = virt::kernel_image();
+    text.push_str(&format!(
+        "{:#x}-{:#x} pml4[{}] kernel image\n",
+        image_start.get(),
+        image_end.get(),
+        image_start.pml4_index()
+    ));
+
+    let heap = kalloc::heap_stats();
+    let heap_start = kalloc::heap_start().get();
+    text.push_str(&format!(
+        "heap mapped: {:#x}-{:#x} peak: {:#x} limit: {:#x}\n",
+        heap_start,
+        heap_start + heap.size as u64,
+        heap_start + heap.peak_size as u64,
+        heap_start + heap.limit as u64
+    ));
+
+    text
+}
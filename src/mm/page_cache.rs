@@ -0,0 +1,134 @@
+//! Frames holding the pages of files that are mapped into processes, for now the program text
+//! of executables. Every process that runs the same file maps the same read-only frames so
+//! the text is only read from the disk and kept in memory once.
+
+use alloc::{collections::BTreeMap, string::String};
+use spin::Mutex;
+
+use super::{
+    phys::{FRAME_SIZE, PAGE_DESCRIPTOR_MANAGER, PHYS_ALLOCATOR},
+    PhysAddr,
+};
+
+/// The cache is shrunk once it has more pages than this, only the pages that are not mapped
+/// by any process can be dropped
+const PAGE_CACHE_MAX_PAGES: usize = 8192;
+
+struct PageCache {
+    /// The frames indexed by the path of the file and the offset of the page in it, the cache
+    /// holds a reference to every frame
+    pages: BTreeMap<(String, usize), PhysAddr>,
+    hits: usize,
+    misses: usize,
+}
+
+static PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache {
+    pages: BTreeMap::new(),
+    hits: 0,
+    misses: 0,
+});
+
+impl PageCache {
+    /// Drops the pages only the cache refers to
+    fn shrink(&mut self) {
+        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+        self.pages.retain(|_, phys| {
+            let unused = pgm.get_used_count(*phys) == 1;
+            if unused {
+                pgm.dec_used_count(*phys);
+            }
+            !unused
+        });
+    }
+}
+
+/// Returns the frame that holds the page of the file at __path__ starting at __offset__,
+/// __read__ fills a new frame if the page is not cached yet. The frame must only be mapped
+/// read-only. The caller gets a reference to the frame so it is not dropped from the cache
+/// before it is mapped, it has to be released or handed over to the mapping.
+pub fn get_page(
+    path: &str,
+    offset: usize,
+    read: impl FnOnce(&mut [u8]) -> Result<(), ()>,
+) -> Result<PhysAddr, ()> {
+    assert!(offset % FRAME_SIZE == 0);
+    let key = (String::from(path), offset);
+
+    {
+        let mut cache = PAGE_CACHE.lock();
+        if let Some(&phys) = cache.pages.get(&key) {
+            cache.hits += 1;
+            PAGE_DESCRIPTOR_MANAGER.lock().inc_used_count(phys);
+            return Ok(phys);
+        }
+        cache.misses += 1;
+    }
+
+    // the file is read without the cache locked, it can take a while
+    let phys = PHYS_ALLOCATOR.lock().alloc_single();
+    let page =
+        unsafe { core::slice::from_raw_parts_mut(phys.virt_addr().get() as *mut u8, FRAME_SIZE) };
+    page.fill(0);
+    if read(page).is_err() {
+        PHYS_ALLOCATOR.lock().free_single(phys);
+        return Err(());
+    }
+
+    let mut cache = PAGE_CACHE.lock();
+    if cache.pages.len() >= PAGE_CACHE_MAX_PAGES {
+        cache.shrink();
+    }
+
+    // someone else could have read the same page in the meantime
+    if let Some(&cached) = cache.pages.get(&key) {
+        PHYS_ALLOCATOR.lock().free_single(phys);
+        PAGE_DESCRIPTOR_MANAGER.lock().inc_used_count(cached);
+        return Ok(cached);
+    }
+
+    // one reference for the cache and one for the caller
+    {
+        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+        pgm.inc_used_count(phys);
+        pgm.inc_used_count(phys);
+    }
+    cache.pages.insert(key, phys);
+    Ok(phys)
+}
+
+/// Forgets the pages of the file at __path__, it has to be called whenever the file changes.
+/// Processes that map the pages keep the old contents.
+pub fn invalidate(path: &str) {
+    let mut cache = PAGE_CACHE.lock();
+    let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+    cache.pages.retain(|(file, _), phys| {
+        let stale = file == path;
+        if stale {
+            pgm.dec_used_count(*phys);
+        }
+        !stale
+    });
+}
+
+/// Forgets the pages of every file under the directory at __dir__, e.g. when the medium of
+/// a file system mounted there was changed
+pub fn invalidate_under(dir: &str) {
+    let mut cache = PAGE_CACHE.lock();
+    let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+    cache.pages.retain(|(file, _), phys| {
+        let stale = file
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'));
+        if stale {
+            pgm.dec_used_count(*phys);
+        }
+        !stale
+    });
+}
+
+/// Returns the number of cached pages, the number of lookups that found the page and the
+/// number of lookups that had to read it
+pub fn stats() -> (usize, usize, usize) {
+    let cache = PAGE_CACHE.lock();
+    (cache.pages.len(), cache.hits, cache.misses)
+}
@@ -1,5 +1,5 @@
 use alloc::vec::Vec;
-use limine::{MemmapResponse, MemoryMapEntryType};
+use limine::{MemmapEntry, MemmapResponse, MemoryMapEntryType};
 
 use spin::Mutex;
 
@@ -7,10 +7,7 @@ use crate::mm::PhysAddr;
 
 const MAX_SEGMENT_COUNT: usize = 16;
 pub const FRAME_SIZE: usize = 4096;
-// 16 GiB
-const MAX_FRAMES: usize = (16 * 1024 * 1024 * 1024) / FRAME_SIZE;
 const FRAMES_PER_BITMAP: usize = core::mem::size_of::<usize>() * 8;
-const BITMAP_SIZE: usize = MAX_FRAMES / FRAMES_PER_BITMAP;
 
 // TODO: locking?
 pub struct PageDescriptor {
@@ -23,12 +20,26 @@ impl PageDescriptor {
     }
 }
 
+/// Decides which segments an allocation can be served from
+#[derive(Debug, Clone, Copy)]
+pub enum AllocPolicy {
+    /// Any segment, lower addresses are used first
+    Any,
+    /// Only frames in the range [min, max) are used, e.g. for devices that can only
+    /// address the first 4 GiB
+    Range(PhysAddr, PhysAddr),
+    /// Frames in the range [min, max) are used while there are any, e.g. for memory local
+    /// to a NUMA node
+    Prefer(PhysAddr, PhysAddr),
+}
+
 #[derive(Clone, Copy)]
 struct PhysSegment {
     base: usize,
     len: usize, // in frames
     global_bitmap_base: usize,
     lowest_idx: usize,
+    free_frames: usize,
 }
 
 impl PhysSegment {
@@ -38,6 +49,7 @@ impl PhysSegment {
             len: 0,
             global_bitmap_base: 0,
             lowest_idx: 0,
+            free_frames: 0,
         }
     }
 }
@@ -54,13 +66,6 @@ macro_rules! get_page_desc {
     }};
 }
 
-macro_rules! get_page_desc_mut {
-    ($self: ident, $addr: expr) => {{
-        let idx = Self::phys_addr_to_index($addr);
-        $self.page_descriptors.get_mut(idx).unwrap()
-    }};
-}
-
 // TODO: atomic page descriptor?
 impl PageDescriptorManager {
     fn phys_addr_to_index(addr: PhysAddr) -> usize {
@@ -68,31 +73,48 @@ impl PageDescriptorManager {
         addr.0 as usize / FRAME_SIZE
     }
 
-    fn init(&mut self, frame_count: usize) {
+    fn init(&mut self, page_descriptors: Vec<PageDescriptor>) {
         self.initialized = true;
-        self.page_descriptors
-            .resize_with(frame_count, PageDescriptor::new);
+        self.page_descriptors = page_descriptors;
     }
 
+    // the zero frame stands for pages that are not backed by a frame yet so it is not counted,
+    // neither is device memory above the RAM, e.g. the framebuffer, that has no descriptor
     pub fn inc_used_count(&mut self, addr: PhysAddr) {
-        let page_desc = get_page_desc_mut!(self, addr);
-        page_desc.used_count += 1;
+        if addr == PhysAddr::zero() {
+            return;
+        }
+
+        let idx = Self::phys_addr_to_index(addr);
+        if let Some(page_desc) = self.page_descriptors.get_mut(idx) {
+            page_desc.used_count += 1;
+        }
     }
 
+    /// Decrements the number of users of the frame, the frame is freed once it has none
     pub fn dec_used_count(&mut self, addr: PhysAddr) {
-        let page_desc = get_page_desc_mut!(self, addr);
-        if page_desc.used_count > 1 {
-            page_desc.used_count -= 1;
-        } else {
+        if addr == PhysAddr::zero() {
+            return;
+        }
+
+        let idx = Self::phys_addr_to_index(addr);
+        let page_desc = match self.page_descriptors.get_mut(idx) {
+            Some(page_desc) => page_desc,
+            None => return,
+        };
+        if page_desc.used_count == 0 {
             warn!("used_count is 0 but we are trying to decrement it");
+            return;
         }
 
+        page_desc.used_count -= 1;
+
         if page_desc.used_count == 0 {
-            // TODO: free frame
+            PHYS_ALLOCATOR.lock().free_single(addr);
         }
     }
 
-    fn get_used_count(&self, addr: PhysAddr) -> usize {
+    pub fn get_used_count(&self, addr: PhysAddr) -> usize {
         let page_desc = get_page_desc!(self, addr);
         page_desc.used_count
     }
@@ -107,34 +129,80 @@ pub static PAGE_DESCRIPTOR_MANAGER: Mutex<PageDescriptorManager> =
 pub struct PhysAllocator {
     segments: [PhysSegment; MAX_SEGMENT_COUNT],
     segment_count: usize,
-    bitmap: [usize; BITMAP_SIZE],
+    /// The bitmap is stored in the first usable segment that is large enough to hold it,
+    /// it is accessed through the HHDM because the HHDM is moved after initialization
+    bitmap_phys: PhysAddr,
+    /// Length of the bitmap in `usize`s
+    bitmap_len: usize,
     total_frames: usize,
     used_frames: usize,
 }
 
+fn usable_entries(memmap: &MemmapResponse) -> impl Iterator<Item = &MemmapEntry> {
+    memmap
+        .memmap()
+        .iter()
+        .map(|entry| &**entry)
+        .filter(|entry| entry.typ == MemoryMapEntryType::Usable)
+}
+
 impl PhysAllocator {
+    fn bitmap(&self) -> &[usize] {
+        let virt = self.bitmap_phys.virt_addr();
+        unsafe { core::slice::from_raw_parts(virt.get() as *const usize, self.bitmap_len) }
+    }
+
+    fn bitmap_mut(&mut self) -> &mut [usize] {
+        let virt = self.bitmap_phys.virt_addr();
+        unsafe { core::slice::from_raw_parts_mut(virt.get() as *mut usize, self.bitmap_len) }
+    }
+
+    /// Finds a place for the bitmap, the allocator can not allocate memory for itself yet
+    /// so the bitmap is placed at the start of the first usable segment that can hold it
+    fn place_bitmap(&mut self, memmap: &MemmapResponse) -> usize {
+        // every segment starts in a new bitmap
+        self.bitmap_len = usable_entries(memmap)
+            .map(|entry| (entry.len as usize / FRAME_SIZE).div_ceil(FRAMES_PER_BITMAP))
+            .sum();
+
+        let bitmap_size = self.bitmap_len * core::mem::size_of::<usize>();
+        let bitmap_frames = bitmap_size.div_ceil(FRAME_SIZE);
+
+        let entry = usable_entries(memmap)
+            .find(|entry| entry.len as usize >= bitmap_frames * FRAME_SIZE)
+            .expect("no usable memory segment is large enough for the frame bitmap");
+        self.bitmap_phys = PhysAddr::new(entry.base);
+
+        self.bitmap_mut().fill(0);
+
+        log!(
+            "PFA: {} bytes allocated for the frame bitmap at {}",
+            bitmap_size,
+            self.bitmap_phys
+        );
+
+        bitmap_frames
+    }
+
     pub fn init(&mut self, memmap: &MemmapResponse) {
+        let bitmap_frames = self.place_bitmap(memmap);
+        let mut bitmap_segment = 0;
+
         let mut bitmap_base: usize = 0;
-        let mmap = memmap.entries.as_ptr();
-        for i in 0..memmap.entry_count {
-            let entry = unsafe {
-                // TODO: im not sure if theres a better way to do this
-                mmap.offset(i as isize)
-                    .as_ref()
-                    .expect("invalid memory map response")
-            };
+        for entry in usable_entries(memmap) {
+            assert!(entry.base % FRAME_SIZE as u64 == 0);
+            let frames = (entry.len / FRAME_SIZE as u64) as usize;
 
-            if entry.typ != MemoryMapEntryType::Usable {
-                continue;
+            if entry.base == self.bitmap_phys.get() {
+                bitmap_segment = self.segment_count;
             }
 
-            assert!(entry.base % FRAME_SIZE as u64 == 0);
-            let frames = (entry.len / FRAME_SIZE as u64) as usize;
             self.segments[self.segment_count] = PhysSegment {
                 base: entry.base as usize,
                 len: frames,
                 global_bitmap_base: bitmap_base,
                 lowest_idx: 0,
+                free_frames: frames,
             };
 
             self.segment_count += 1;
@@ -145,30 +213,22 @@ impl PhysAllocator {
             // sometimes the last isn't filled completely, so we mark the
             // unusable bits as allocated
             if rem_frames != 0 {
-                self.bitmap[bitmap_base] = usize::MAX << rem_frames;
+                self.bitmap_mut()[bitmap_base] = usize::MAX << rem_frames;
                 bitmap_base += 1;
             }
         }
-        self.used_frames = self.total_frames;
+        assert!(bitmap_base == self.bitmap_len);
+
+        self.mark_region_as_allocated(bitmap_segment, 0, bitmap_frames);
 
         self.print_available_memory();
     }
 
-    pub fn init_page_descriptors(&mut self) {
+    fn frame_count(&self) -> usize {
         let last_seg = &self.segments[self.segment_count - 1];
         let last_frame_addr = last_seg.base + last_seg.len * FRAME_SIZE;
 
-        let frame_count = last_frame_addr / FRAME_SIZE;
-        let size = frame_count * core::mem::size_of::<PageDescriptor>();
-
-        // FIXME: if the requested size is bigger than kalloc's initial heap size
-        // the kernel hangs
-        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
-        pgm.init(frame_count);
-
-        log!("{} bytes allocated for {} frames", size, frame_count);
-
-        // TODO: set currently used frames
+        last_frame_addr / FRAME_SIZE
     }
 
     fn print_available_memory(&self) {
@@ -204,9 +264,10 @@ impl PhysAllocator {
             segment.len / FRAMES_PER_BITMAP + 1
         };
 
+        let global_bitmap = self.bitmap();
         for bitmap_idx in 0..bitmap_count {
             let global_bitmap_idx = segment.global_bitmap_base + bitmap_idx;
-            let bitmap = self.bitmap[global_bitmap_idx];
+            let bitmap = global_bitmap[global_bitmap_idx];
 
             // if all the frames in the bitmap are set continue
             if bitmap == usize::MAX {
@@ -224,109 +285,96 @@ impl PhysAllocator {
         PhysAddr::new((segment.base + idx * FRAME_SIZE) as u64)
     }
 
-    fn segment_find_region(&self, segment_idx: usize, size: usize, align: usize) -> Option<usize> {
-        let mut current_count = 0;
-        let mut current_start = 0;
+    fn frame_is_used(&self, bitmap: &[usize], segment_idx: usize, idx: usize) -> bool {
+        let segment = &self.segments[segment_idx];
+        let global_bitmap_idx = segment.global_bitmap_base + idx / FRAMES_PER_BITMAP;
+        bitmap[global_bitmap_idx] & (1 << (idx % FRAMES_PER_BITMAP)) > 0
+    }
 
+    /// Returns the local index of `size` free frames in the segment between the physical
+    /// addresses `min` and `max`, the address of the first frame is aligned to `align`
+    fn segment_find_region(
+        &self,
+        segment_idx: usize,
+        size: usize,
+        align: usize,
+        min: usize,
+        max: usize,
+    ) -> Option<usize> {
         let segment = self.segments[segment_idx];
+        let segment_end = segment.base + segment.len * FRAME_SIZE;
 
-        let rem = segment.base % align;
-        let start_off_to_align = if rem == 0 { 0 } else { align - rem };
-        let start_off_in_pages = start_off_to_align / FRAME_SIZE;
-
-        let mut bitmaps = segment.len / FRAMES_PER_BITMAP;
-        if bitmaps > 0 {
-            bitmaps += 1;
+        if segment.free_frames < size || segment_end <= min || max <= segment.base {
+            return None;
         }
 
-        let mut step = size / FRAMES_PER_BITMAP;
-        if size % FRAMES_PER_BITMAP != 0 {
-            step += 1;
-        }
+        let start = usize::max(segment.base, min);
+        let end = usize::min(segment_end, max);
+        let last_idx = (end - segment.base) / FRAME_SIZE;
+
+        let aligned_idx = |addr: usize| (addr.next_multiple_of(align) - segment.base) / FRAME_SIZE;
 
-        let page_align = align >> 12;
-
-        'bm_loop: for bitmap_idx in (start_off_in_pages..bitmaps).step_by(step) {
-            let left = segment.len - bitmap_idx * FRAMES_PER_BITMAP;
-            let bits = usize::min(FRAMES_PER_BITMAP, left);
-            for bitmap_off in 0..bits {
-                let global_bitmap_idx = segment.global_bitmap_base + bitmap_idx;
-                // if the frame at bitmap_off is set then keep searching
-                if self.bitmap[global_bitmap_idx] & (1 << bitmap_off) > 0 {
-                    current_count = 0;
-                    continue;
-                }
-
-                if current_count == 0 {
-                    current_start = bitmap_idx * FRAMES_PER_BITMAP + bitmap_off;
-                    if current_start % page_align != 0 {
-                        continue 'bm_loop;
-                    }
-                }
-
-                current_count += 1;
-
-                if current_count == size {
-                    return Some(current_start);
-                }
+        let bitmap = self.bitmap();
+        let mut idx = aligned_idx(start);
+        while idx + size <= last_idx {
+            match (idx..idx + size).find(|&i| self.frame_is_used(bitmap, segment_idx, i)) {
+                Some(used_idx) => idx = aligned_idx(segment.base + (used_idx + 1) * FRAME_SIZE),
+                None => return Some(idx),
             }
         }
+
         None
     }
 
-    /// Returns a segment and a corresponding local bitmap index that satisfies
-    /// the size and alignment parameters
-    /// Returns  None if no such region was found
-    fn find_region(&self, size: usize, align: usize) -> Option<(usize, usize)> {
-        for seg_idx in 0..self.segment_count {
-            let ret = self.segment_find_region(seg_idx, size, align);
-            if let Some(bitmap_idx) = ret {
-                return Some((seg_idx, bitmap_idx));
-            }
-        }
+    /// Returns a segment and a corresponding local index that satisfies the size and
+    /// alignment parameters and the allocation policy
+    /// Returns None if no such region was found
+    fn find_region(
+        &self,
+        size: usize,
+        align: usize,
+        policy: AllocPolicy,
+    ) -> Option<(usize, usize)> {
+        let find_in_range = |min: usize, max: usize| {
+            (0..self.segment_count).find_map(|seg_idx| {
+                self.segment_find_region(seg_idx, size, align, min, max)
+                    .map(|idx| (seg_idx, idx))
+            })
+        };
 
-        None
+        match policy {
+            AllocPolicy::Any => find_in_range(0, usize::MAX),
+            AllocPolicy::Range(min, max) => find_in_range(min.get() as usize, max.get() as usize),
+            AllocPolicy::Prefer(min, max) => find_in_range(min.get() as usize, max.get() as usize)
+                .or_else(|| find_in_range(0, usize::MAX)),
+        }
     }
 
     /// Marks the specified region in the segment as allocated, no checks are performed
     fn mark_region_as_allocated(&mut self, segment_idx: usize, start_idx: usize, size: usize) {
-        let segment = self.segments[segment_idx];
-
-        let mut size_left = size;
-        let mut bitmap_idx = segment.global_bitmap_base + start_idx / FRAMES_PER_BITMAP;
-        let mut bitmap_off = start_idx % FRAMES_PER_BITMAP;
-
-        while size_left > 0 {
-            if bitmap_off == 0 && size_left >= FRAMES_PER_BITMAP {
-                self.bitmap[bitmap_idx] = usize::MAX;
+        self.segments[segment_idx].free_frames -= size;
+        self.used_frames += size;
 
-                bitmap_idx += 1;
-                size_left -= FRAMES_PER_BITMAP;
-                continue;
-            } else if size_left < FRAMES_PER_BITMAP {
-                let size = usize::MAX >> (FRAMES_PER_BITMAP - size_left);
-                self.bitmap[bitmap_idx] |= size << bitmap_off;
-
-                return;
-            } else {
-                self.bitmap[bitmap_idx] |= usize::MAX << bitmap_off;
+        let segment = self.segments[segment_idx];
+        let bitmap = self.bitmap_mut();
 
-                size_left = FRAMES_PER_BITMAP - bitmap_off;
-                bitmap_idx += 1;
-                bitmap_off = 0;
-            }
+        for idx in start_idx..start_idx + size {
+            let bitmap_idx = segment.global_bitmap_base + idx / FRAMES_PER_BITMAP;
+            bitmap[bitmap_idx] |= 1 << (idx % FRAMES_PER_BITMAP);
         }
     }
 
-    pub fn alloc_multiple(&mut self, size: usize, align: usize) -> PhysAddr {
+    /// Allocates `size` contiguous frames aligned to `align` from the segments the policy
+    /// allows, returns None if there is no such region
+    pub fn alloc_with_policy(
+        &mut self,
+        size: usize,
+        align: usize,
+        policy: AllocPolicy,
+    ) -> Option<PhysAddr> {
         assert!(align % 4096 == 0);
 
-        let region = self.find_region(size, align);
-        if region.is_none() {
-            panic!("OUT OF MEMORY");
-        }
-
-        let region = region.unwrap();
+        let region = self.find_region(size, align, policy)?;
 
         self.mark_region_as_allocated(region.0, region.1, size);
 
@@ -342,18 +390,105 @@ impl PhysAllocator {
             );
         }
 
-        addr
+        Some(addr)
+    }
+
+    /// Allocates `size` contiguous frames that are all in the range [min, max)
+    pub fn alloc_in_range(
+        &mut self,
+        size: usize,
+        align: usize,
+        min: PhysAddr,
+        max: PhysAddr,
+    ) -> Option<PhysAddr> {
+        self.alloc_with_policy(size, align, AllocPolicy::Range(min, max))
+    }
+
+    pub fn alloc_multiple(&mut self, size: usize, align: usize) -> PhysAddr {
+        match self.alloc_with_policy(size, align, AllocPolicy::Any) {
+            Some(addr) => addr,
+            None => panic!("OUT OF MEMORY"),
+        }
+    }
+
+    /// Returns the base address, the number of frames and the number of free frames of
+    /// every segment
+    pub fn segment_usage(&self) -> impl Iterator<Item = (PhysAddr, usize, usize)> + '_ {
+        self.segments[..self.segment_count].iter().map(|segment| {
+            (
+                PhysAddr::new(segment.base as u64),
+                segment.len,
+                segment.free_frames,
+            )
+        })
     }
 
     pub fn alloc_single(&mut self) -> PhysAddr {
         self.alloc_multiple(1, 0x1000)
     }
 
+    /// Returns the segment and the local index of the frame, None if the frame is not in
+    /// usable memory
+    fn find_frame(&self, addr: PhysAddr) -> Option<(usize, usize)> {
+        let addr = addr.get() as usize;
+        self.segments[..self.segment_count]
+            .iter()
+            .position(|segment| {
+                segment.base <= addr && addr < segment.base + segment.len * FRAME_SIZE
+            })
+            .map(|seg_idx| (seg_idx, (addr - self.segments[seg_idx].base) / FRAME_SIZE))
+    }
+
+    /// Frees `size` contiguous frames starting at `addr`, frames that are not in usable
+    /// memory, e.g. MMIO regions, are ignored
+    pub fn free_multiple(&mut self, addr: PhysAddr, size: usize) {
+        assert!(addr.is_aligned());
+
+        let (segment_idx, start_idx) = match self.find_frame(addr) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        let segment = self.segments[segment_idx];
+        assert!(start_idx + size <= segment.len);
+
+        let bitmap = self.bitmap_mut();
+        for idx in start_idx..start_idx + size {
+            let bitmap_idx = segment.global_bitmap_base + idx / FRAMES_PER_BITMAP;
+            let bit = 1 << (idx % FRAMES_PER_BITMAP);
+            if bitmap[bitmap_idx] & bit == 0 {
+                panic!(
+                    "PFA: double free of frame {:#x}",
+                    segment.base + idx * FRAME_SIZE
+                );
+            }
+
+            bitmap[bitmap_idx] &= !bit;
+        }
+
+        self.segments[segment_idx].free_frames += size;
+        self.used_frames -= size;
+
+        if cfg!(pfa_debug) {
+            log!("PFA: freed {} physical pages at {}", size, addr);
+        }
+    }
+
+    pub fn free_single(&mut self, addr: PhysAddr) {
+        self.free_multiple(addr, 1)
+    }
+
+    /// Returns the number of frames in usable memory and the number of allocated frames
+    pub fn usage(&self) -> (usize, usize) {
+        (self.total_frames, self.used_frames)
+    }
+
     pub const fn new_uninit() -> PhysAllocator {
         PhysAllocator {
             segments: [PhysSegment::new(); MAX_SEGMENT_COUNT],
             segment_count: 0,
-            bitmap: [0; BITMAP_SIZE],
+            bitmap_phys: PhysAddr::zero(),
+            bitmap_len: 0,
             total_frames: 0,
             used_frames: 0,
         }
@@ -368,6 +503,17 @@ pub fn init(memmap: &MemmapResponse) {
 }
 
 pub fn init_page_descriptors() {
-    let mut allocator = PHYS_ALLOCATOR.lock();
-    allocator.init_page_descriptors();
+    let frame_count = PHYS_ALLOCATOR.lock().frame_count();
+    let size = frame_count * core::mem::size_of::<PageDescriptor>();
+
+    // the descriptors are allocated without holding any memory manager locks because
+    // the kernel heap might have to grow which maps new pages
+    let mut page_descriptors = Vec::new();
+    page_descriptors.resize_with(frame_count, PageDescriptor::new);
+
+    PAGE_DESCRIPTOR_MANAGER.lock().init(page_descriptors);
+
+    log!("{} bytes allocated for {} frames", size, frame_count);
+
+    // TODO: set currently used frames
 }
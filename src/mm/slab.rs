@@ -0,0 +1,152 @@
+//! Caches of freed heap objects of a fixed layout. Like the kmalloc caches of Linux every
+//! allocation with the layout of a cache goes through it: a freed object is kept on the free
+//! list of its cache and handed out again by the next allocation with the same layout instead
+//! of splitting and merging heap regions. The cached objects are given back to the heap when
+//! the heap is trimmed or an allocation would fail otherwise.
+
+use alloc::{format, string::String};
+use core::alloc::Layout;
+
+use super::kalloc;
+
+/// Number of caches that can be created
+pub const SLAB_CACHES_MAX: usize = 16;
+/// Freed objects beyond this many are given back to the heap right away
+pub const SLAB_CACHED_MAX: usize = 128;
+
+/// Identifies a cache, the index of the cache in the allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabCacheId(pub usize);
+
+pub struct SlabCache {
+    pub name: &'static str,
+    pub layout: Layout,
+    /// The first free object, every free object stores the address of the next one
+    free_head: usize,
+    /// Objects handed out by the cache that have not been freed
+    pub active: usize,
+    /// Objects on the free list
+    pub cached: usize,
+    /// Objects handed out including the ones that were reused
+    pub allocations: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabCacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub active: usize,
+    pub cached: usize,
+    pub allocations: usize,
+}
+
+// the free objects are only accessed with the allocator locked
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, layout: Layout) -> SlabCache {
+        SlabCache {
+            name,
+            layout,
+            free_head: 0,
+            active: 0,
+            cached: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Whether an allocation of __layout__ goes through the cache
+    pub fn matches(&self, layout: Layout) -> bool {
+        self.layout == layout
+    }
+
+    /// Takes an object off the free list
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.free_head == 0 {
+            return None;
+        }
+
+        let addr = self.free_head;
+        self.free_head = unsafe { (addr as *const usize).read() };
+        self.cached -= 1;
+        Some(addr)
+    }
+
+    /// Puts a freed object on the free list, returns false if the cache is full
+    pub fn push(&mut self, addr: usize) -> bool {
+        if self.cached >= SLAB_CACHED_MAX {
+            return false;
+        }
+
+        unsafe { (addr as *mut usize).write(self.free_head) };
+        self.free_head = addr;
+        self.cached += 1;
+        true
+    }
+
+    pub fn stats(&self) -> SlabCacheStats {
+        SlabCacheStats {
+            name: self.name,
+            object_size: self.layout.size(),
+            active: self.active,
+            cached: self.cached,
+            allocations: self.allocations,
+        }
+    }
+}
+
+/// Returns the layout of the allocation of `Arc::new` for a value of type T
+pub const fn arc_layout<T>() -> Layout {
+    // the reference counts are stored in front of the value, this has the same layout as the
+    // ArcInner of alloc
+    #[repr(C)]
+    struct ArcInner<T> {
+        strong: usize,
+        weak: usize,
+        data: T,
+    }
+
+    Layout::new::<ArcInner<T>>()
+}
+
+/// Creates a cache for the allocations of __layout__ and returns it, if there already is a
+/// cache with the same layout that one is returned. Returns None if there is no cache left or
+/// the objects are too small to be cached.
+pub fn create_cache(name: &'static str, layout: Layout) -> Option<SlabCacheId> {
+    if layout.size() < core::mem::size_of::<usize>() {
+        return None;
+    }
+
+    kalloc::create_slab_cache(SlabCache::new(name, layout))
+}
+
+/// Gives every cached object back to the heap, returns the number of bytes freed
+pub fn reclaim() -> usize {
+    kalloc::reclaim_slab_caches()
+}
+
+/// Generates the contents of /proc/slabinfo, the caches followed by the heap usage of
+/// every subsystem
+pub fn proc_slabinfo() -> String {
+    let mut text = String::from("# name active cached objsize allocations\n");
+    // the stats are copied out first, formatting allocates
+    for cache in kalloc::slab_cache_stats().iter().flatten() {
+        text.push_str(&format!(
+            "{} {} {} {} {}\n",
+            cache.name, cache.active, cache.cached, cache.object_size, cache.allocations
+        ));
+    }
+
+    text.push_str("# tag used peak allocations\n");
+    for (tag, usage) in kalloc::tag_usage().iter().enumerate() {
+        text.push_str(&format!(
+            "{} {} {} {}\n",
+            kalloc::HeapTag::from_index(tag).name(),
+            usage.used,
+            usage.peak,
+            usage.allocations
+        ));
+    }
+
+    text
+}
@@ -1,15 +1,14 @@
 use crate::arch::x86_64::paging::{PML1Flags, PML2Flags, PML3Flags, PML4Flags, PageFlags};
-use crate::arch::x86_64::{flush_tlb_page, get_current_pml4_phys, set_cr3};
+use crate::arch::x86_64::{flush_tlb_page, get_current_pml4_phys, set_cr3, smp};
 use crate::mm::phys::{PAGE_DESCRIPTOR_MANAGER, PHYS_ALLOCATOR};
 use crate::mm::{PhysAddr, VirtAddr};
-use spin::RwLock;
-
-use super::phys::PageDescriptorManager;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
 
 mod utils;
 
 /// pml4[508] - physical memory(512GiB)
-/// pml4[509] - kernel thread stacks
+/// pml4[509] - kernel thread stacks(lower half), kernel stacks of user threads(upper half)
 /// pml4[510] - kernel heap
 /// pml4[511] - kernel
 
@@ -18,15 +17,70 @@ pub const HDDM_VIRT_START: VirtAddr = VirtAddr::new(0xfffffe0000000000);
 
 // pml4[509]
 pub const KERNEL_THREAD_STACKS_START: VirtAddr = VirtAddr::new(0xfffffe8000000000);
+pub const USER_THREAD_KERNEL_STACKS_START: VirtAddr = VirtAddr::new(0xfffffec000000000);
 
 // pml4[510]
 pub const KERNEL_HEAP_START: VirtAddr = VirtAddr::new(0xffffff0000000000);
+pub const KERNEL_HEAP_END: VirtAddr = VirtAddr::new(0xffffff8000000000);
 
 const HDDM_PML4_INDEX: u64 = 508;
 const KERNEL_THREAD_STACKS_PML4_INDEX: u64 = 509;
 const KERNEL_HEAP_PML4_INDEX: u64 = 510;
 const KERNEL_PML4_INDEX: u64 = 511;
 
+// the regions are shared between the address spaces by their PML4 entries, a region that
+// spills into the next entry would collide with the region there
+const _: () = assert!(HDDM_VIRT_START.pml4_index() == HDDM_PML4_INDEX);
+const _: () = assert!(KERNEL_THREAD_STACKS_START.pml4_index() == KERNEL_THREAD_STACKS_PML4_INDEX);
+const _: () =
+    assert!(USER_THREAD_KERNEL_STACKS_START.pml4_index() == KERNEL_THREAD_STACKS_PML4_INDEX);
+const _: () = assert!(KERNEL_THREAD_STACKS_START.get() < USER_THREAD_KERNEL_STACKS_START.get());
+const _: () = assert!(KERNEL_HEAP_START.pml4_index() == KERNEL_HEAP_PML4_INDEX);
+const _: () =
+    assert!(VirtAddr::new(KERNEL_HEAP_END.get() - 1).pml4_index() == KERNEL_HEAP_PML4_INDEX);
+
+/// Returns whether the PML4 entry __idx__ is shared by every address space
+const fn is_kernel_pml4_index(idx: u64) -> bool {
+    matches!(
+        idx,
+        HDDM_PML4_INDEX
+            | KERNEL_THREAD_STACKS_PML4_INDEX
+            | KERNEL_HEAP_PML4_INDEX
+            | KERNEL_PML4_INDEX
+    )
+}
+
+/// A fixed region of the kernel half of the address space, the end is exclusive
+pub struct KernelRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+}
+
+/// The fixed regions in the order of their addresses, the kernel image is not included
+pub const KERNEL_REGIONS: [KernelRegion; 4] = [
+    KernelRegion {
+        name: "hhdm",
+        start: HDDM_VIRT_START,
+        end: KERNEL_THREAD_STACKS_START,
+    },
+    KernelRegion {
+        name: "kernel thread stacks",
+        start: KERNEL_THREAD_STACKS_START,
+        end: USER_THREAD_KERNEL_STACKS_START,
+    },
+    KernelRegion {
+        name: "user thread kernel stacks",
+        start: USER_THREAD_KERNEL_STACKS_START,
+        end: KERNEL_HEAP_START,
+    },
+    KernelRegion {
+        name: "kernel heap",
+        start: KERNEL_HEAP_START,
+        end: KERNEL_HEAP_END,
+    },
+];
+
 pub const PAGE_ENTRIES: usize = 512;
 
 pub const PAGE_SIZE_4KIB: u64 = 4096;
@@ -34,6 +88,10 @@ pub const PAGE_SIZE_2MIB: u64 = PAGE_SIZE_4KIB * 512;
 
 pub static HHDM_START: RwLock<VirtAddr> = RwLock::new(VirtAddr::zero());
 
+/// Address spaces that were freed while another CPU still had them loaded, that CPU was
+/// switching away from an exiting thread
+static DYING_ADDRESS_SPACES: Mutex<Vec<PML4>> = Mutex::new(Vec::new());
+
 // TODO: support other arches, and abstract all virtual memory operations
 #[derive(Debug, Clone)]
 pub struct PML4(PhysAddr);
@@ -43,6 +101,11 @@ impl PML4 {
         Self(addr)
     }
 
+    /// Returns the physical address of the PML4 table
+    pub fn phys(&self) -> PhysAddr {
+        self.0
+    }
+
     // Initializes the virtual memory manager
     pub fn map_hhdm(&self, hhdm: VirtAddr) {
         let mut hhdm_start = HHDM_START.write();
@@ -50,7 +113,8 @@ impl PML4 {
     }
 
     /// This function unmaps a page in virtual memory
-    /// It does not deallocate the physical memory neither the page tables associated with it
+    /// It does not deallocate the physical memory neither the page tables associated with it,
+    /// the caller has to flush the TLB entry of the page
     fn unmap(&self, pml4_phys: PhysAddr, virt: VirtAddr) {
         assert!(virt.get() % 4096 == 0);
         // TODO: check if address is valid
@@ -70,27 +134,284 @@ impl PML4 {
         let pml2 = self
             .get_pml2(pml3.0, pml2_idx)
             .expect("Trying to unmap a not mapped page!");
-        let pml1 = self
-            .get_pml1(pml2.0, pml1_idx)
+        self.get_pml1(pml2.0, pml1_idx)
             .expect("Trying to unmap a not mapped page!");
 
         // FIXME: 2 MiB pages????
         let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
         self.map_pml1(
             &mut pgm,
-            pml1.0,
+            pml2.0,
             pml1_idx,
             PhysAddr::zero(),
             PML1Flags::NONE,
         );
 
-        flush_tlb_page(virt.get());
-
         if cfg!(vmm_debug) {
             log!("VMM: unmapped Virt {}", virt);
         }
     }
 
+    /// Maps the page to another frame keeping its flags, the previous frame is returned.
+    /// Returns None if the page is not mapped.
+    pub fn replace_frame(&self, virt: VirtAddr, phys: PhysAddr) -> Option<PhysAddr> {
+        assert!(virt.page_offset() == 0);
+
+        let pml4 = self.get_pml4(self.0, virt.pml4_index())?;
+        let pml3 = self.get_pml3(pml4.0, virt.pml3_index())?;
+        let pml2 = self.get_pml2(pml3.0, virt.pml2_index())?;
+        let (old_phys, flags) = self.get_pml1(pml2.0, virt.pml1_index())?;
+
+        {
+            let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+            pgm.dec_used_count(old_phys);
+            self.map_pml1(&mut pgm, pml2.0, virt.pml1_index(), phys, flags);
+        }
+
+        smp::flush_tlb_range(self.0, virt.get(), virt.get() + PAGE_SIZE_4KIB);
+
+        Some(old_phys)
+    }
+
+    /// Backs a page that is allocated on access with the frame __phys__, e.g. a frame of the
+    /// page cache that other address spaces map too. The page keeps its other flags. The
+    /// mapping takes over a reference to the frame the caller already holds.
+    pub fn map_frame(&self, virt: VirtAddr, phys: PhysAddr) {
+        assert!(virt.page_offset() == 0);
+
+        let pml1 = self
+            .get_pml4(self.0, virt.pml4_index())
+            .and_then(|pml4| self.get_pml3(pml4.0, virt.pml3_index()))
+            .and_then(|pml3| self.get_pml2(pml3.0, virt.pml2_index()))
+            .expect("page is not mapped")
+            .0;
+        let (old_phys, mut flags) = self.get_pml1(pml1, virt.pml1_index()).unwrap();
+        assert!(
+            old_phys == PhysAddr::zero(),
+            "page is already backed by a frame"
+        );
+
+        flags.remove(PML1Flags::ALLOC_ON_ACCESS);
+        flags.insert(PML1Flags::PRESENT);
+        {
+            let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+            self.map_pml1(&mut pgm, pml1, virt.pml1_index(), phys, flags);
+            // mapping the frame counted a new reference
+            pgm.dec_used_count(phys);
+        }
+
+        smp::flush_tlb_range(self.0, virt.get(), virt.get() + PAGE_SIZE_4KIB);
+    }
+
+    /// Maps the pages in the range [from, to) to the physical memory starting at __phys__,
+    /// e.g. the memory of a device. The frames are not allocated.
+    pub fn map_physical_range(
+        &self,
+        from: VirtAddr,
+        to: VirtAddr,
+        phys: PhysAddr,
+        flags: PageFlags,
+    ) {
+        assert!(phys.get() % PAGE_SIZE_4KIB == 0);
+
+        // the page tables are created with pages that are not backed by frames
+        let mut table_flags = flags | PageFlags::ALLOC_ON_ACCESS;
+        table_flags.remove(PageFlags::PRESENT);
+        self.map_range(from, to, table_flags);
+
+        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+        let mut addr = from;
+        while addr.get() < to.get() {
+            let pml1 = self
+                .get_pml4(self.0, addr.pml4_index())
+                .and_then(|pml4| self.get_pml3(pml4.0, addr.pml3_index()))
+                .and_then(|pml3| self.get_pml2(pml3.0, addr.pml2_index()))
+                .expect("page is not mapped")
+                .0;
+
+            let frame = phys + PhysAddr::new(addr.get() - from.get());
+            self.map_pml1(
+                &mut pgm,
+                pml1,
+                addr.pml1_index(),
+                frame,
+                flags.to_plm1_flags(),
+            );
+            addr = addr + VirtAddr::new(PAGE_SIZE_4KIB);
+        }
+
+        drop(pgm);
+        smp::flush_tlb_range(self.0, from.get(), to.get());
+    }
+
+    /// Changes the flags of the pages in the range [from, to) that are mapped, the frames
+    /// stay mapped. Pages that are not backed by a frame yet are still allocated on access.
+    pub fn protect_range(&self, from: VirtAddr, to: VirtAddr, flags: PageFlags) {
+        assert!(from.page_offset() == 0);
+        assert!(to.page_offset() == 0);
+        assert!(from.get() < to.get());
+
+        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+
+        let mut addr = from;
+        while addr.get() < to.get() {
+            let pml1 = self
+                .get_pml4(self.0, addr.pml4_index())
+                .and_then(|pml4| self.get_pml3(pml4.0, addr.pml3_index()))
+                .and_then(|pml3| self.get_pml2(pml3.0, addr.pml2_index()));
+
+            if let Some((pml1, _)) = pml1 {
+                if let Some((phys, _)) = self.get_pml1(pml1, addr.pml1_index()) {
+                    // pages that can not be accessed are neither present nor allocated on access
+                    let mut page_flags = flags;
+                    if flags.intersects(PageFlags::PRESENT | PageFlags::ALLOC_ON_ACCESS) {
+                        let backed = phys != PhysAddr::zero();
+                        page_flags.set(PageFlags::PRESENT, backed);
+                        page_flags.set(PageFlags::ALLOC_ON_ACCESS, !backed);
+                    }
+
+                    // the frame is remapped before its old mapping is dropped so it is not
+                    // freed in between
+                    let idx = addr.pml1_index();
+                    self.map_pml1(&mut pgm, pml1, idx, phys, page_flags.to_plm1_flags());
+                    pgm.dec_used_count(phys);
+                }
+            }
+
+            addr = addr + VirtAddr::new(PAGE_SIZE_4KIB);
+        }
+
+        // other CPUs have to acknowledge the flush so the lock can not be held
+        drop(pgm);
+        smp::flush_tlb_range(self.0, from.get(), to.get());
+    }
+
+    /// Gives the page a private copy of its frame if the frame is mapped more than once,
+    /// this has to be done before a shared page is made writable
+    pub fn unshare_page(&self, virt: VirtAddr) {
+        let phys = match self.get_page_entry_from_virt(virt) {
+            Some((phys, _)) if phys != PhysAddr::zero() => phys,
+            _ => return,
+        };
+
+        if PAGE_DESCRIPTOR_MANAGER.lock().get_used_count(phys) <= 1 {
+            return;
+        }
+
+        let copy = PHYS_ALLOCATOR.lock().alloc_single();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys.virt_addr().get() as *const u8,
+                copy.virt_addr().get() as *mut u8,
+                PAGE_SIZE_4KIB as usize,
+            );
+        }
+
+        self.replace_frame(virt, copy);
+    }
+
+    /// Gives a copy-on-write page its own frame and makes it writable
+    pub fn break_copy_on_write(&self, virt: VirtAddr) {
+        assert!(virt.page_offset() == 0);
+
+        self.unshare_page(virt);
+
+        let (_, mut flags) = self.get_page_entry_from_virt(virt).unwrap();
+        flags.remove(PageFlags::COPY_ON_WRITE);
+        flags.insert(PageFlags::READ_WRITE);
+        self.protect_range(virt, virt + VirtAddr::new(PAGE_SIZE_4KIB), flags);
+    }
+
+    /// Maps the frames of the pages in the range [from, to) into `other` at the same
+    /// addresses. With `copy_on_write` writable pages become read-only in both address spaces
+    /// and get their own frame on the first write, otherwise the frames stay shared and pages
+    /// that are not backed yet get a frame so both address spaces see the same memory.
+    pub fn share_range(&self, other: &PML4, from: VirtAddr, to: VirtAddr, copy_on_write: bool) {
+        assert!(from.page_offset() == 0);
+        assert!(to.page_offset() == 0);
+        assert!(from.get() < to.get());
+
+        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+        let mut phys_allocator = PHYS_ALLOCATOR.lock();
+
+        let mut addr = from;
+        while addr.get() < to.get() {
+            let pml1 = self
+                .get_pml4(self.0, addr.pml4_index())
+                .and_then(|pml4| self.get_pml3(pml4.0, addr.pml3_index()))
+                .and_then(|pml3| self.get_pml2(pml3.0, addr.pml2_index()));
+
+            let entry = pml1.and_then(|(pml1, _)| {
+                self.get_pml1(pml1, addr.pml1_index())
+                    .map(|(phys, flags)| (pml1, phys, flags))
+            });
+
+            if let Some((pml1, mut phys, mut flags)) = entry {
+                let idx = addr.pml1_index();
+                let backed = phys != PhysAddr::zero();
+
+                if copy_on_write && backed && flags.contains(PML1Flags::READ_WRITE) {
+                    flags.remove(PML1Flags::READ_WRITE);
+                    flags.insert(PML1Flags::COPY_ON_WRITE);
+                    self.map_pml1(&mut pgm, pml1, idx, phys, flags);
+                    pgm.dec_used_count(phys);
+                } else if !copy_on_write && !backed {
+                    phys = phys_allocator.alloc_single();
+                    if flags.contains(PML1Flags::ALLOC_ON_ACCESS) {
+                        flags.remove(PML1Flags::ALLOC_ON_ACCESS);
+                        flags.insert(PML1Flags::PRESENT);
+                    }
+                    self.map_pml1(&mut pgm, pml1, idx, phys, flags);
+                }
+
+                let table_flags = PageFlags::PRESENT | PageFlags::READ_WRITE | PageFlags::USER;
+                let other_pml3 = other.get_or_map_pml4(
+                    &mut pgm,
+                    &mut phys_allocator,
+                    other.0,
+                    addr.pml4_index(),
+                    table_flags.to_plm4_flags(),
+                );
+                let other_pml2 = other.get_or_map_pml3(
+                    &mut pgm,
+                    &mut phys_allocator,
+                    other_pml3,
+                    addr.pml3_index(),
+                    table_flags.to_plm3_flags(),
+                );
+                let other_pml1 = other.get_or_map_pml2(
+                    &mut pgm,
+                    &mut phys_allocator,
+                    other_pml2,
+                    addr.pml2_index(),
+                    table_flags.to_plm2_flags(),
+                );
+                other.map_pml1(&mut pgm, other_pml1, idx, phys, flags);
+            }
+
+            addr = addr + VirtAddr::new(PAGE_SIZE_4KIB);
+        }
+
+        drop(phys_allocator);
+        drop(pgm);
+        smp::flush_tlb_range(self.0, from.get(), to.get());
+    }
+
+    /// Unmaps the pages in the range [from, to)
+    pub fn unmap_range(&self, from: VirtAddr, to: VirtAddr) {
+        assert!(from.page_offset() == 0);
+        assert!(to.page_offset() == 0);
+        assert!(from.get() < to.get());
+
+        let mut addr = from;
+        while addr.get() < to.get() {
+            self.unmap(self.0, addr);
+            addr = addr + VirtAddr::new(PAGE_SIZE_4KIB);
+        }
+
+        smp::flush_tlb_range(self.0, from.get(), to.get());
+    }
+
     pub fn get_page_entry_from_virt(&self, virt: VirtAddr) -> Option<(PhysAddr, PageFlags)> {
         let pml4_idx = virt.pml4_index();
         let pml3_idx = virt.pml3_index();
@@ -265,50 +586,78 @@ impl PML4 {
         }
     }
 
-    fn update_frames(pgm: &mut PageDescriptorManager, phys: PhysAddr, depth_left: usize) {
-        let table = phys.as_mut_page_table();
-        for ent in table.iter_mut().filter(|ent| **ent != 0) {
-            let phys = PhysAddr::new(*ent & !0xFFF);
-            let mut flags = PageFlags::from_bits(*ent & 0xFFF).unwrap();
-            flags.set(PageFlags::READ_WRITE, false);
+    pub fn unmap_limine_pages(&self) {
+        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+        self.map_pml4(&mut pgm, self.0, 0, PhysAddr::zero(), PML4Flags::NONE);
+        self.map_pml4(&mut pgm, self.0, 1, PhysAddr::zero(), PML4Flags::NONE);
+        self.map_pml4(&mut pgm, self.0, 256, PhysAddr::zero(), PML4Flags::NONE);
+        self.map_pml4(&mut pgm, self.0, 257, PhysAddr::zero(), PML4Flags::NONE);
+    }
 
-            *ent = phys.0 | flags.bits();
-            pgm.inc_used_count(phys);
+    /// Frees the page tables of the part of the address space that is not shared with the
+    /// kernel and the PML4 itself, the frames mapped there lose a user and are freed if
+    /// nothing else maps them. No CPU may have the address space loaded.
+    pub fn destroy(&self) {
+        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
 
-            if depth_left > 0 {
-                Self::update_frames(pgm, phys, depth_left - 1);
+        for pml4_idx in 0..PAGE_ENTRIES as u64 {
+            if is_kernel_pml4_index(pml4_idx) {
+                continue;
             }
-        }
-    }
 
-    pub fn copy_page_tables(&self, new_pml4: PhysAddr) {
-        log!("COPY PAGE TABLES");
+            let pml3 = match self.get_pml4(self.0, pml4_idx) {
+                Some((pml3, _)) => pml3,
+                None => continue,
+            };
 
-        let this = self.0.as_mut_page_table();
-        let other = new_pml4.as_mut_page_table();
+            for pml3_idx in 0..PAGE_ENTRIES as u64 {
+                let pml2 = match self.get_pml3(pml3, pml3_idx) {
+                    Some((pml2, _)) => pml2,
+                    None => continue,
+                };
 
-        other.copy_from_slice(this);
+                for pml2_idx in 0..PAGE_ENTRIES as u64 {
+                    let pml1 = match self.get_pml2(pml2, pml2_idx) {
+                        Some((_, flags)) if flags.contains(PML2Flags::PAGE_SIZE) => {
+                            let zero = PhysAddr::zero();
+                            self.map_pml2_2mib(&mut pgm, pml2, pml2_idx, zero, PML2Flags::NONE);
+                            continue;
+                        }
+                        Some((pml1, _)) => pml1,
+                        None => continue,
+                    };
 
-        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
+                    for pml1_idx in 0..PAGE_ENTRIES as u64 {
+                        if self.get_pml1(pml1, pml1_idx).is_some() {
+                            let zero = PhysAddr::zero();
+                            self.map_pml1(&mut pgm, pml1, pml1_idx, zero, PML1Flags::NONE);
+                        }
+                    }
 
-        for ent in this.iter_mut().take(508).filter(|ent| **ent != 0) {
-            let phys = PhysAddr::new(*ent & !0xFFF);
-            let mut flags = PageFlags::from_bits(*ent & 0xFFF).unwrap();
-            flags.set(PageFlags::READ_WRITE, false);
+                    // clearing the entry frees the table once nothing maps it
+                    self.map_pml2(&mut pgm, pml2, pml2_idx, PhysAddr::zero(), PML2Flags::NONE);
+                }
 
-            *ent = phys.0 | flags.bits();
-            pgm.inc_used_count(phys);
+                self.map_pml3(&mut pgm, pml3, pml3_idx, PhysAddr::zero(), PML3Flags::NONE);
+            }
 
-            Self::update_frames(&mut pgm, phys, 2);
+            self.map_pml4(
+                &mut pgm,
+                self.0,
+                pml4_idx,
+                PhysAddr::zero(),
+                PML4Flags::NONE,
+            );
         }
-    }
 
-    pub fn unmap_limine_pages(&self) {
-        let mut pgm = PAGE_DESCRIPTOR_MANAGER.lock();
-        self.map_pml4(&mut pgm, self.0, 0, PhysAddr::zero(), PML4Flags::NONE);
-        self.map_pml4(&mut pgm, self.0, 1, PhysAddr::zero(), PML4Flags::NONE);
-        self.map_pml4(&mut pgm, self.0, 256, PhysAddr::zero(), PML4Flags::NONE);
-        self.map_pml4(&mut pgm, self.0, 257, PhysAddr::zero(), PML4Flags::NONE);
+        drop(pgm);
+
+        // the PML4 is not mapped anywhere so it is not counted
+        PHYS_ALLOCATOR.lock().free_single(self.0);
+
+        if cfg!(vmm_debug) {
+            log!("VMM: destroyed address space {}", self.0);
+        }
     }
 
     pub fn copy_pml4_higher_half_entries(&self, to: PhysAddr) {
@@ -355,6 +704,40 @@ extern "C" {
     static mut hddm_adjust_offset: u64;
 }
 
+/// Returns the range the kernel image is loaded at
+pub fn kernel_image() -> (VirtAddr, VirtAddr) {
+    unsafe {
+        (
+            VirtAddr::new(&__kernel_start as *const _ as u64),
+            VirtAddr::new(&__kernel_end as *const _ as u64),
+        )
+    }
+}
+
+/// Frees the address space of a process whose threads have exited, it is destroyed later by
+/// `free_dying_address_spaces` if another CPU has not switched away from it yet
+pub fn free_address_space(pml4: PML4) {
+    assert!(
+        get_current_pml4_phys() != pml4.phys(),
+        "freeing the loaded address space"
+    );
+
+    if smp::pml4_in_use(pml4.phys()) {
+        DYING_ADDRESS_SPACES.lock().push(pml4);
+    } else {
+        pml4.destroy();
+    }
+}
+
+/// Destroys the address spaces that have been freed and no CPU uses anymore
+pub fn free_dying_address_spaces() {
+    let dying = core::mem::take(&mut *DYING_ADDRESS_SPACES.lock());
+    for pml4 in dying {
+        free_address_space(pml4);
+    }
+}
+
 pub fn switch_pml4(pml4: &PML4) {
+    smp::set_current_pml4(pml4.0);
     set_cr3(pml4.0.get());
 }
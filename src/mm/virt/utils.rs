@@ -10,6 +10,9 @@ use crate::{
 
 use super::{PAGE_ENTRIES, PML4};
 
+/// The flags of an entry are in the lowest 12 bits and the NX bit
+const ENTRY_FLAGS_MASK: u64 = 0x8000_0000_0000_0fff;
+
 macro_rules! define_get_pml {
     ($name: ident, $fl: ty) => {
         pub fn $name(&self, table_phys: PhysAddr, index: u64) -> Option<(PhysAddr, $fl)> {
@@ -23,7 +26,7 @@ macro_rules! define_get_pml {
                 0 => None,
                 val => {
                     let phys = PhysAddr::new(val & 0x000ffffffffff000);
-                    let flags = <$fl>::from_bits(val & 0xFFF).unwrap();
+                    let flags = <$fl>::from_bits(val & ENTRY_FLAGS_MASK).unwrap();
 
                     Some((phys, flags))
                 }
@@ -50,6 +53,13 @@ macro_rules! define_map_pml {
             let ent = phys.get() | flags.bits();
             let unmap = ent == 0;
 
+            // when unmapping the frame of the previous entry loses a user
+            let phys = if unmap {
+                PhysAddr::new(table[index as usize] & 0x000ffffffffff000)
+            } else {
+                phys
+            };
+
             if pgm.initialized {
                 if $big_page {
                     for i in 0..512 {
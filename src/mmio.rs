@@ -0,0 +1,102 @@
+//! Volatile access to memory mapped device registers and to memory shared with devices
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::Deref,
+    ptr::{self, NonNull},
+};
+
+use crate::mm::{PhysAddr, VirtAddr};
+
+/// A value that is only ever accessed with volatile reads and writes so the compiler
+/// can not merge, reorder or elide the accesses, register layouts are built out of these
+#[repr(transparent)]
+pub struct VolatileCell<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for VolatileCell<T> {}
+
+impl<T: Copy> VolatileCell<T> {
+    pub const fn new(value: T) -> VolatileCell<T> {
+        VolatileCell {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.value.get()) }
+    }
+
+    #[inline]
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_volatile(self.value.get(), value) }
+    }
+
+    /// Reads the value, modifies it and writes it back, the read and the write are
+    /// separate accesses so this is not atomic
+    #[inline]
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for VolatileCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("VolatileCell").field(&self.read()).finish()
+    }
+}
+
+/// A register block of type `T` mapped at a fixed address, `T` should be a `#[repr(C)]`
+/// struct of `VolatileCell`s matching the layout the device defines
+pub struct Mmio<T> {
+    base: NonNull<T>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T> Send for Mmio<T> {}
+unsafe impl<T> Sync for Mmio<T> {}
+
+impl<T> Mmio<T> {
+    /// # Safety
+    /// `addr` must be mapped for the lifetime of the returned value, it must be suitably
+    /// aligned for `T` and it must point to registers that match the layout of `T`
+    pub unsafe fn new(addr: VirtAddr) -> Mmio<T> {
+        assert!(addr.get() as usize % core::mem::align_of::<T>() == 0);
+
+        Mmio {
+            base: NonNull::new(addr.get() as *mut T).expect("MMIO address is null"),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Accesses the registers through the higher half direct map
+    ///
+    /// # Safety
+    /// Same as `Mmio::new`
+    // TODO: the HHDM is mapped write-back, registers should be mapped uncacheable
+    pub unsafe fn from_phys(addr: PhysAddr) -> Mmio<T> {
+        Self::new(addr.virt_addr())
+    }
+
+    pub fn addr(&self) -> VirtAddr {
+        VirtAddr::new(self.base.as_ptr() as u64)
+    }
+}
+
+impl<T> Deref for Mmio<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.base.as_ref() }
+    }
+}
+
+impl<T> fmt::Debug for Mmio<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mmio").field("addr", &self.addr()).finish()
+    }
+}
@@ -0,0 +1,312 @@
+use alloc::{fmt, vec::Vec};
+
+use super::{read16, read32, read8};
+
+pub const CAP_ID_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+// every capability starts with its id and the offset of the next one
+const CAP_ID_OFF: u8 = 0x0;
+const CAP_NEXT_OFF: u8 = 0x1;
+
+// power management
+const PM_CAPABILITIES_OFF: u8 = 0x2;
+const PM_CONTROL_STATUS_OFF: u8 = 0x4;
+const PM_SIZE: u8 = 0x6;
+
+// MSI
+const MSI_MESSAGE_CONTROL_OFF: u8 = 0x2;
+const MSI_SIZE: u8 = 0x4;
+
+// MSI-X
+const MSIX_MESSAGE_CONTROL_OFF: u8 = 0x2;
+const MSIX_TABLE_OFF: u8 = 0x4;
+const MSIX_PBA_OFF: u8 = 0x8;
+const MSIX_SIZE: u8 = 0xC;
+
+// PCI Express
+const PCIE_CAPABILITIES_OFF: u8 = 0x2;
+const PCIE_DEVICE_CAPABILITIES_OFF: u8 = 0x4;
+const PCIE_LINK_CAPABILITIES_OFF: u8 = 0xC;
+const PCIE_LINK_STATUS_OFF: u8 = 0x12;
+const PCIE_SIZE: u8 = 0x14;
+
+/// A malformed list could be circular, there are at most 48 capabilities in the 192 bytes
+/// after the header
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PowerManagementCapability {
+    /// Offset of the capability in the configuration space
+    pub off: u8,
+    pub capabilities: u16,
+    pub control_status: u16,
+}
+
+impl PowerManagementCapability {
+    pub fn version(&self) -> u8 {
+        (self.capabilities & 0b111) as u8
+    }
+
+    pub fn supports_d1(&self) -> bool {
+        self.capabilities & (1 << 9) > 0
+    }
+
+    pub fn supports_d2(&self) -> bool {
+        self.capabilities & (1 << 10) > 0
+    }
+
+    /// Returns the current power state, 0 is D0 and 3 is D3hot
+    pub fn power_state(&self) -> u8 {
+        (self.control_status & 0b11) as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MSICapability {
+    /// Offset of the capability in the configuration space
+    pub off: u8,
+    pub message_control: u16,
+}
+
+impl MSICapability {
+    pub fn enabled(&self) -> bool {
+        self.message_control & 1 > 0
+    }
+
+    /// Returns the number of vectors the device can use
+    pub fn vectors(&self) -> usize {
+        1 << ((self.message_control >> 1) & 0b111)
+    }
+
+    /// Returns whether the message address is 64 bits wide
+    pub fn is_64bit(&self) -> bool {
+        self.message_control & (1 << 7) > 0
+    }
+
+    pub fn per_vector_masking(&self) -> bool {
+        self.message_control & (1 << 8) > 0
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MSIXCapability {
+    /// Offset of the capability in the configuration space
+    pub off: u8,
+    pub message_control: u16,
+    /// The BAR index in the lowest 3 bits and the offset of the vector table in that BAR
+    pub table: u32,
+    /// The BAR index in the lowest 3 bits and the offset of the pending bit array in that BAR
+    pub pending_bit_array: u32,
+}
+
+impl MSIXCapability {
+    pub fn enabled(&self) -> bool {
+        self.message_control & (1 << 15) > 0
+    }
+
+    pub fn function_masked(&self) -> bool {
+        self.message_control & (1 << 14) > 0
+    }
+
+    /// Returns the number of entries in the vector table
+    pub fn table_size(&self) -> usize {
+        (self.message_control & 0x7FF) as usize + 1
+    }
+
+    pub fn table_bar(&self) -> u8 {
+        (self.table & 0b111) as u8
+    }
+
+    pub fn table_offset(&self) -> u32 {
+        self.table & !0b111
+    }
+
+    pub fn pending_bit_array_bar(&self) -> u8 {
+        (self.pending_bit_array & 0b111) as u8
+    }
+
+    pub fn pending_bit_array_offset(&self) -> u32 {
+        self.pending_bit_array & !0b111
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PCIExpressCapability {
+    /// Offset of the capability in the configuration space
+    pub off: u8,
+    pub capabilities: u16,
+    pub device_capabilities: u32,
+    pub link_capabilities: u32,
+    pub link_status: u16,
+}
+
+impl PCIExpressCapability {
+    pub fn version(&self) -> u8 {
+        (self.capabilities & 0xF) as u8
+    }
+
+    /// Returns the device/port type, e.g. 0 is an endpoint and 4 is a root port
+    pub fn device_type(&self) -> u8 {
+        ((self.capabilities >> 4) & 0xF) as u8
+    }
+
+    /// Returns the maximum link speed, 1 is 2.5 GT/s, 2 is 5 GT/s and so on
+    pub fn max_link_speed(&self) -> u8 {
+        (self.link_capabilities & 0xF) as u8
+    }
+
+    pub fn max_link_width(&self) -> u8 {
+        ((self.link_capabilities >> 4) & 0x3F) as u8
+    }
+
+    /// Returns the negotiated link speed, encoded like `max_link_speed`
+    pub fn link_speed(&self) -> u8 {
+        (self.link_status & 0xF) as u8
+    }
+
+    pub fn link_width(&self) -> u8 {
+        ((self.link_status >> 4) & 0x3F) as u8
+    }
+}
+
+/// An entry of the capability list of a device, the ones drivers do not need parsed only
+/// record where they are
+#[derive(Clone, Copy, Debug)]
+pub enum PCICapability {
+    PowerManagement(PowerManagementCapability),
+    MSI(MSICapability),
+    MSIX(MSIXCapability),
+    PCIExpress(PCIExpressCapability),
+    /// A vendor specific capability, its layout is up to the driver
+    Vendor {
+        off: u8,
+    },
+    Other {
+        id: u8,
+        off: u8,
+    },
+}
+
+impl PCICapability {
+    /// Returns the offset of the capability in the configuration space
+    pub fn off(&self) -> u8 {
+        match self {
+            PCICapability::PowerManagement(cap) => cap.off,
+            PCICapability::MSI(cap) => cap.off,
+            PCICapability::MSIX(cap) => cap.off,
+            PCICapability::PCIExpress(cap) => cap.off,
+            PCICapability::Vendor { off } => *off,
+            PCICapability::Other { off, .. } => *off,
+        }
+    }
+
+    fn read(base_addr: u32, off: u8) -> PCICapability {
+        let id = read8(base_addr, off + CAP_ID_OFF);
+        // the fields of a malformed capability could be past the end of the configuration space
+        let size = match id {
+            CAP_ID_POWER_MANAGEMENT => PM_SIZE,
+            CAP_ID_MSI => MSI_SIZE,
+            CAP_ID_MSIX => MSIX_SIZE,
+            CAP_ID_PCI_EXPRESS => PCIE_SIZE,
+            _ => 0,
+        };
+        if off.checked_add(size).is_none() {
+            return PCICapability::Other { id, off };
+        }
+
+        match id {
+            CAP_ID_POWER_MANAGEMENT => PCICapability::PowerManagement(PowerManagementCapability {
+                off,
+                capabilities: read16(base_addr, off + PM_CAPABILITIES_OFF),
+                control_status: read16(base_addr, off + PM_CONTROL_STATUS_OFF),
+            }),
+            CAP_ID_MSI => PCICapability::MSI(MSICapability {
+                off,
+                message_control: read16(base_addr, off + MSI_MESSAGE_CONTROL_OFF),
+            }),
+            CAP_ID_MSIX => PCICapability::MSIX(MSIXCapability {
+                off,
+                message_control: read16(base_addr, off + MSIX_MESSAGE_CONTROL_OFF),
+                table: read32(base_addr, off + MSIX_TABLE_OFF),
+                pending_bit_array: read32(base_addr, off + MSIX_PBA_OFF),
+            }),
+            CAP_ID_PCI_EXPRESS => PCICapability::PCIExpress(PCIExpressCapability {
+                off,
+                capabilities: read16(base_addr, off + PCIE_CAPABILITIES_OFF),
+                device_capabilities: read32(base_addr, off + PCIE_DEVICE_CAPABILITIES_OFF),
+                link_capabilities: read32(base_addr, off + PCIE_LINK_CAPABILITIES_OFF),
+                link_status: read16(base_addr, off + PCIE_LINK_STATUS_OFF),
+            }),
+            CAP_ID_VENDOR => PCICapability::Vendor { off },
+            _ => PCICapability::Other { id, off },
+        }
+    }
+}
+
+impl fmt::Display for PCICapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PCICapability::PowerManagement(cap) => write!(
+                f,
+                "pm v{} d1: {} d2: {} state: D{}",
+                cap.version(),
+                cap.supports_d1(),
+                cap.supports_d2(),
+                cap.power_state()
+            ),
+            PCICapability::MSI(cap) => write!(
+                f,
+                "msi vectors: {} 64bit: {} masking: {} enabled: {}",
+                cap.vectors(),
+                cap.is_64bit(),
+                cap.per_vector_masking(),
+                cap.enabled()
+            ),
+            PCICapability::MSIX(cap) => write!(
+                f,
+                "msix table_size: {} table: bar{}+{:#x} pba: bar{}+{:#x} enabled: {}",
+                cap.table_size(),
+                cap.table_bar(),
+                cap.table_offset(),
+                cap.pending_bit_array_bar(),
+                cap.pending_bit_array_offset(),
+                cap.enabled()
+            ),
+            PCICapability::PCIExpress(cap) => write!(
+                f,
+                "pcie v{} type: {} link: x{} speed {} (max x{} speed {})",
+                cap.version(),
+                cap.device_type(),
+                cap.link_width(),
+                cap.link_speed(),
+                cap.max_link_width(),
+                cap.max_link_speed()
+            ),
+            PCICapability::Vendor { off } => write!(f, "vendor at {:#x}", off),
+            PCICapability::Other { id, off } => write!(f, "{:#x} at {:#x}", id, off),
+        }
+    }
+}
+
+/// Walks the capability list that starts at __first__ in the configuration space of a
+/// function
+pub fn read_capabilities(base_addr: u32, first: u8) -> Vec<PCICapability> {
+    let mut caps = Vec::new();
+    // the lowest 2 bits are reserved
+    let mut off = first & !0b11;
+    for _ in 0..MAX_CAPABILITIES {
+        // the capabilities can not be in the header
+        if off < 0x40 {
+            break;
+        }
+
+        caps.push(PCICapability::read(base_addr, off));
+        off = read8(base_addr, off + CAP_NEXT_OFF) & !0b11;
+    }
+
+    caps
+}
@@ -4,6 +4,7 @@ use core::fmt::Debug;
 pub enum Unclassified {
     NonVGACompatibleDevice,
     VGACompatbileDevice,
+    Unknown(u8),
 }
 
 impl Unclassified {
@@ -11,7 +12,7 @@ impl Unclassified {
         match subclass {
             0x0 => Self::NonVGACompatibleDevice,
             0x1 => Self::VGACompatbileDevice,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -28,6 +29,7 @@ pub enum MassStorageController {
     SerialAttachedSCSIController,
     NonVolatileMemoryContoller,
     Other,
+    Unknown(u8),
 }
 
 impl MassStorageController {
@@ -43,7 +45,7 @@ impl MassStorageController {
             0x7 => Self::SerialAttachedSCSIController,
             0x8 => Self::NonVolatileMemoryContoller,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -60,6 +62,7 @@ pub enum NetworkController {
     InfibandController,
     FabricController,
     Other,
+    Unknown(u8),
 }
 
 impl NetworkController {
@@ -75,7 +78,7 @@ impl NetworkController {
             0x7 => Self::InfibandController,
             0x8 => Self::FabricController,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -86,6 +89,7 @@ pub enum DisplayController {
     XGAController,
     _3DController,
     Other,
+    Unknown(u8),
 }
 
 impl DisplayController {
@@ -95,7 +99,7 @@ impl DisplayController {
             0x1 => Self::XGAController,
             0x2 => Self::_3DController,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -107,6 +111,7 @@ pub enum MultimediaController {
     ComputerTelephonyDevice,
     AudioDevice,
     Other,
+    Unknown(u8),
 }
 
 impl MultimediaController {
@@ -117,7 +122,7 @@ impl MultimediaController {
             0x2 => Self::ComputerTelephonyDevice,
             0x3 => Self::AudioDevice,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -127,6 +132,7 @@ pub enum MemoryController {
     RAMController,
     FlashController,
     Other,
+    Unknown(u8),
 }
 
 impl MemoryController {
@@ -135,7 +141,7 @@ impl MemoryController {
             0x0 => Self::RAMController,
             0x1 => Self::FlashController,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -154,6 +160,7 @@ pub enum Bridge {
     PCItoPCIBridge2,
     InfiniBandToPCIHostBridge,
     Other,
+    Unknown(u8),
 }
 
 impl Bridge {
@@ -171,7 +178,7 @@ impl Bridge {
             0x9 => Self::PCItoPCIBridge2,
             0xA => Self::InfiniBandToPCIHostBridge,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -185,6 +192,7 @@ pub enum SimpleCommunicationController {
     IEEE488_1_2Controller,
     SmartCardController,
     Other,
+    Unknown(u8),
 }
 
 impl SimpleCommunicationController {
@@ -197,7 +205,7 @@ impl SimpleCommunicationController {
             0x4 => Self::IEEE488_1_2Controller,
             0x5 => Self::SmartCardController,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -212,6 +220,7 @@ pub enum BaseSystemPeripheral {
     SDHostController,
     IOMMU,
     Other,
+    Unknown(u8),
 }
 
 impl BaseSystemPeripheral {
@@ -225,7 +234,7 @@ impl BaseSystemPeripheral {
             0x5 => Self::SDHostController,
             0x6 => Self::IOMMU,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -238,6 +247,7 @@ pub enum InputDeviceController {
     ScannerController,
     GameportController,
     Other,
+    Unknown(u8),
 }
 
 impl InputDeviceController {
@@ -249,7 +259,7 @@ impl InputDeviceController {
             0x3 => Self::ScannerController,
             0x4 => Self::GameportController,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -258,6 +268,7 @@ impl InputDeviceController {
 pub enum DockingStation {
     Generic,
     Other,
+    Unknown(u8),
 }
 
 impl DockingStation {
@@ -265,7 +276,7 @@ impl DockingStation {
         match subclass {
             0x0 => Self::Generic,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -281,6 +292,7 @@ pub enum Processor {
     MIPS,
     CoProcessor,
     Other,
+    Unknown(u8),
 }
 
 impl Processor {
@@ -295,7 +307,7 @@ impl Processor {
             0x30 => Self::MIPS,
             0x40 => Self::CoProcessor,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -313,6 +325,7 @@ pub enum SerialBusController {
     SERCOSInterface,
     CANBusController,
     Other,
+    Unknown(u8),
 }
 
 impl SerialBusController {
@@ -329,7 +342,7 @@ impl SerialBusController {
             0x8 => Self::SERCOSInterface,
             0x9 => Self::CANBusController,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -344,6 +357,7 @@ pub enum WirelessController {
     EthernetControllerA,
     EthernetControllerB,
     Other,
+    Unknown(u8),
 }
 
 impl WirelessController {
@@ -357,7 +371,7 @@ impl WirelessController {
             0x20 => Self::EthernetControllerA,
             0x21 => Self::EthernetControllerB,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -365,13 +379,14 @@ impl WirelessController {
 #[derive(Debug, PartialEq)]
 pub enum IntelligentController {
     I20,
+    Unknown(u8),
 }
 
 impl IntelligentController {
     pub fn from_subclass(subclass: u8) -> Self {
         match subclass {
             0x0 => Self::I20,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -382,6 +397,7 @@ pub enum SatelliteCommunicationController {
     SatelliteAudioController,
     SatelliteVoiceController,
     SatelliteDataController,
+    Unknown(u8),
 }
 
 impl SatelliteCommunicationController {
@@ -391,7 +407,7 @@ impl SatelliteCommunicationController {
             0x2 => Self::SatelliteAudioController,
             0x3 => Self::SatelliteVoiceController,
             0x4 => Self::SatelliteDataController,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -401,6 +417,7 @@ pub enum EncryptionController {
     NetworkAndComputingEncryptionDecryption,
     EntertainmentEncryptionDecryption,
     Other,
+    Unknown(u8),
 }
 
 impl EncryptionController {
@@ -409,7 +426,7 @@ impl EncryptionController {
             0x0 => Self::NetworkAndComputingEncryptionDecryption,
             0x10 => Self::EntertainmentEncryptionDecryption,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -421,6 +438,7 @@ pub enum SignalProcessingController {
     CommunicationSynchronizer,
     SignalProcessingManagement,
     Other,
+    Unknown(u8),
 }
 
 impl SignalProcessingController {
@@ -431,7 +449,7 @@ impl SignalProcessingController {
             0x10 => Self::CommunicationSynchronizer,
             0x20 => Self::SignalProcessingManagement,
             0x80 => Self::Other,
-            _ => unreachable!(),
+            _ => Self::Unknown(subclass),
         }
     }
 }
@@ -492,4 +510,6 @@ pub enum PCIClass {
     ProcessingAccelerator(ProcessingAccelerator),
     NonEssentialInstrumentation(NonEssentialInstrumentation),
     CoProcessor(CoProcessor),
+    /// Reserved or vendor specific class, (class code, subclass)
+    Unknown(u8, u8),
 }
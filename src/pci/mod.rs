@@ -1,8 +1,9 @@
-use self::class::*;
+use self::{capability::*, class::*};
 use crate::arch::x86_64::*;
 use alloc::{fmt, vec::Vec};
 use spin::Mutex;
 
+pub mod capability;
 pub mod class;
 
 #[derive(Clone, Copy, Debug)]
@@ -113,7 +114,7 @@ fn class_from_u8(classcode: u8, subclass: u8) -> PCIClass {
             subclass,
         )),
         0x40 => PCIClass::CoProcessor(CoProcessor::from_subclass(subclass)),
-        _ => unreachable!(),
+        _ => PCIClass::Unknown(classcode, subclass),
     }
 }
 
@@ -135,6 +136,47 @@ pub struct PCIDevice {
     pub bist: u8,
 
     pub specific: PCIDeviceExtended,
+
+    /// The capability list, it is read once when the device is enumerated
+    pub capabilities: Vec<PCICapability>,
+}
+
+impl PCIDevice {
+    pub fn power_management(&self) -> Option<&PowerManagementCapability> {
+        self.capabilities.iter().find_map(|cap| match cap {
+            PCICapability::PowerManagement(pm) => Some(pm),
+            _ => None,
+        })
+    }
+
+    pub fn msi(&self) -> Option<&MSICapability> {
+        self.capabilities.iter().find_map(|cap| match cap {
+            PCICapability::MSI(msi) => Some(msi),
+            _ => None,
+        })
+    }
+
+    pub fn msix(&self) -> Option<&MSIXCapability> {
+        self.capabilities.iter().find_map(|cap| match cap {
+            PCICapability::MSIX(msix) => Some(msix),
+            _ => None,
+        })
+    }
+
+    pub fn pci_express(&self) -> Option<&PCIExpressCapability> {
+        self.capabilities.iter().find_map(|cap| match cap {
+            PCICapability::PCIExpress(pcie) => Some(pcie),
+            _ => None,
+        })
+    }
+
+    /// Returns the offsets of the vendor specific capabilities in the order of the list
+    pub fn vendor_capabilities(&self) -> impl Iterator<Item = u8> + '_ {
+        self.capabilities.iter().filter_map(|cap| match cap {
+            PCICapability::Vendor { off } => Some(*off),
+            _ => None,
+        })
+    }
 }
 
 impl fmt::Display for PCIDevice {
@@ -154,6 +196,13 @@ impl fmt::Display for PCIDevice {
         write!(f, "header_type: {} ", self.header_type).unwrap();
         write!(f, "bist: {} ", self.bist).unwrap();
 
+        write!(f, "capabilities: [").unwrap();
+        for (idx, cap) in self.capabilities.iter().enumerate() {
+            let separator = if idx == 0 { "" } else { ", " };
+            write!(f, "{}{}", separator, cap).unwrap();
+        }
+        write!(f, "] ").unwrap();
+
         match self.header_type {
             0x0 => unsafe { write!(f, "{:?}", self.specific.type0) },
             0x1 => unsafe { write!(f, "{:?}", self.specific.type1) },
@@ -180,6 +229,9 @@ pub const DEVICE_LATENCY_TIMER_OFF: u8 = 0xD;
 pub const DEVICE_HEADER_TYPE_OFF: u8 = 0xE;
 pub const DEVICE_BIST_OFF: u8 = 0xF;
 
+/// Set in the status register if the device has a capability list
+pub const DEVICE_STATUS_CAP_LIST: u16 = 1 << 4;
+
 // header type 0
 pub const DEVICE_TYPE0_BAR0_OFF: u8 = 0x10;
 pub const DEVICE_TYPE0_BAR1_OFF: u8 = 0x14;
@@ -381,9 +433,44 @@ fn read_function(devices: &mut Vec<PCIDevice>, bus: u8, dev: u8, func: u8) {
     }
 
     let header_type = read8(base_addr, DEVICE_HEADER_TYPE_OFF) & 0b11;
+    if header_type > 0x2 {
+        warn!(
+            "PCI: {:#x}:{:#x}.{} has an invalid header type {:#x}",
+            bus, dev, func, header_type
+        );
+        return;
+    }
 
     let classcode = read8(base_addr, DEVICE_CLASS_CODE_OFF);
     let subclass = read8(base_addr, DEVICE_SUBCLASS_OFF);
+    let status = read16(base_addr, DEVICE_STATUS_OFF);
+
+    let specific = match header_type {
+        0x0 => PCIDeviceExtended {
+            type0: read_header_type0(base_addr),
+        },
+        0x1 => PCIDeviceExtended {
+            type1: read_header_type1(base_addr),
+        },
+        0x2 => PCIDeviceExtended {
+            type2: read_header_type2(base_addr),
+        },
+        _ => unreachable!(),
+    };
+
+    let capabilities = if status & DEVICE_STATUS_CAP_LIST > 0 {
+        let first = unsafe {
+            match header_type {
+                0x0 => specific.type0.capabilities_pointer,
+                0x1 => specific.type1.capability_pointer,
+                0x2 => specific.type2.capabilites_list_off,
+                _ => unreachable!(),
+            }
+        };
+        read_capabilities(base_addr, first)
+    } else {
+        Vec::new()
+    };
 
     let device = PCIDevice {
         bus,
@@ -392,7 +479,7 @@ fn read_function(devices: &mut Vec<PCIDevice>, bus: u8, dev: u8, func: u8) {
         vendor_id,
         device_id: read16(base_addr, DEVICE_ID_OFF),
         command: read16(base_addr, DEVICE_COMMAND_OFF),
-        status: read16(base_addr, DEVICE_STATUS_OFF),
+        status,
         revision_id: read8(base_addr, DEVICE_REVISION_ID_OFF),
         prog_if: read8(base_addr, DEVICE_PROG_IF_OFF),
         class: class_from_u8(classcode, subclass),
@@ -400,18 +487,8 @@ fn read_function(devices: &mut Vec<PCIDevice>, bus: u8, dev: u8, func: u8) {
         latency_timer: read8(base_addr, DEVICE_LATENCY_TIMER_OFF),
         header_type,
         bist: read8(base_addr, DEVICE_BIST_OFF),
-        specific: match header_type {
-            0x0 => PCIDeviceExtended {
-                type0: read_header_type0(base_addr),
-            },
-            0x1 => PCIDeviceExtended {
-                type1: read_header_type1(base_addr),
-            },
-            0x2 => PCIDeviceExtended {
-                type2: read_header_type2(base_addr),
-            },
-            _ => unreachable!(),
-        },
+        specific,
+        capabilities,
     };
 
     if let PCIClass::Bridge(ref bridge_type) = device.class {
@@ -460,6 +537,16 @@ pub fn match_devices(class: PCIClass, func: fn(Vec<&PCIDevice>)) {
     func(matched);
 }
 
+// TODO: avoid cloning
+pub fn match_vendor_devices(vendor_id: u16, func: fn(Vec<&PCIDevice>)) {
+    let devices = PCI_DEVICES.lock();
+    let matched: Vec<&PCIDevice> = devices
+        .iter()
+        .filter(|dev| dev.vendor_id == vendor_id)
+        .collect();
+    func(matched);
+}
+
 pub fn init() {
     let mut devices = PCI_DEVICES.lock();
     devices.clear();
@@ -481,6 +568,21 @@ pub fn init() {
     }
 }
 
+pub fn read_config8(bus: u8, dev: u8, func: u8, reg: u8) -> u8 {
+    let base_addr = construct_addr(bus, dev, func);
+    read8(base_addr, reg)
+}
+
+pub fn read_config16(bus: u8, dev: u8, func: u8, reg: u8) -> u16 {
+    let base_addr = construct_addr(bus, dev, func);
+    read16(base_addr, reg)
+}
+
+pub fn read_config32(bus: u8, dev: u8, func: u8, reg: u8) -> u32 {
+    let base_addr = construct_addr(bus, dev, func);
+    read32(base_addr, reg)
+}
+
 pub fn write_config8(bus: u8, dev: u8, func: u8, reg: u8, val: u8) {
     let base_addr = construct_addr(bus, dev, func);
     write8(base_addr, reg, val);
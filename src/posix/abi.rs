@@ -0,0 +1,4 @@
+//! Checks the structs shared with userspace against the layouts build.rs generated the
+//! userspace ABI header with, the kernel does not compile if the header would be stale.
+
+include!(concat!(env!("OUT_DIR"), "/abi_layout.rs"));
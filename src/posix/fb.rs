@@ -0,0 +1,78 @@
+//! The Linux framebuffer device interface, the structures have the same layout as in
+//! linux/fb.h
+
+pub const FBIOGET_VSCREENINFO: usize = 0x4600;
+pub const FBIOPUT_VSCREENINFO: usize = 0x4601;
+pub const FBIOGET_FSCREENINFO: usize = 0x4602;
+
+pub const FB_TYPE_PACKED_PIXELS: u32 = 0;
+pub const FB_VISUAL_TRUECOLOR: u32 = 2;
+
+/// Position of a color channel in a pixel
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FbBitfield {
+    pub offset: u32,
+    pub length: u32,
+    pub msb_right: u32,
+}
+
+/// Geometry and pixel format of the screen
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FbVarScreeninfo {
+    pub xres: u32,
+    pub yres: u32,
+    pub xres_virtual: u32,
+    pub yres_virtual: u32,
+    pub xoffset: u32,
+    pub yoffset: u32,
+    pub bits_per_pixel: u32,
+    pub grayscale: u32,
+    pub red: FbBitfield,
+    pub green: FbBitfield,
+    pub blue: FbBitfield,
+    pub transp: FbBitfield,
+    pub nonstd: u32,
+    pub activate: u32,
+    /// Height of the picture in mm
+    pub height: u32,
+    /// Width of the picture in mm
+    pub width: u32,
+    pub accel_flags: u32,
+    pub pixclock: u32,
+    pub left_margin: u32,
+    pub right_margin: u32,
+    pub upper_margin: u32,
+    pub lower_margin: u32,
+    pub hsync_len: u32,
+    pub vsync_len: u32,
+    pub sync: u32,
+    pub vmode: u32,
+    pub rotate: u32,
+    pub colorspace: u32,
+    pub reserved: [u32; 4],
+}
+
+/// Properties of the video memory that can not be changed
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FbFixScreeninfo {
+    pub id: [u8; 16],
+    /// Physical address of the video memory
+    pub smem_start: u64,
+    pub smem_len: u32,
+    pub fb_type: u32,
+    pub type_aux: u32,
+    pub visual: u32,
+    pub xpanstep: u16,
+    pub ypanstep: u16,
+    pub ywrapstep: u16,
+    /// Number of bytes per row
+    pub line_length: u32,
+    pub mmio_start: u64,
+    pub mmio_len: u32,
+    pub accel: u32,
+    pub capabilities: u16,
+    pub reserved: [u16; 2],
+}
@@ -0,0 +1,50 @@
+//! The Linux input event interface, the structures and codes are the same as in
+//! linux/input.h and linux/input-event-codes.h
+
+pub const EV_VERSION: u32 = 0x010001;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+
+pub const SYN_REPORT: u16 = 0;
+/// The event queue overflowed and events were lost since the previous SYN_REPORT
+pub const SYN_DROPPED: u16 = 3;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const KEY_RIGHTCTRL: u16 = 97;
+pub const KEY_RIGHTALT: u16 = 100;
+pub const KEY_HOME: u16 = 102;
+pub const KEY_UP: u16 = 103;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_END: u16 = 107;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_LEFTMETA: u16 = 125;
+pub const KEY_RIGHTMETA: u16 = 126;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+pub const EVIOCGVERSION: usize = 0x80044501;
+
+/// EVIOCGNAME(len) with the length bits cleared
+pub const EVIOCGNAME: usize = 0x80004506;
+pub const IOC_SIZE_SHIFT: usize = 16;
+pub const IOC_SIZE_MASK: usize = 0x3FFF;
+
+/// A single event, a group of events that happened at the same time is ended by an EV_SYN
+/// SYN_REPORT event
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InputEvent {
+    pub time_sec: i64,
+    pub time_usec: i64,
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
@@ -1,6 +1,9 @@
 use crate::fs::FileType;
 
+pub mod abi;
 pub mod errno;
+pub mod fb;
+pub mod input;
 pub mod termios;
 
 bitflags::bitflags! {
@@ -26,6 +29,55 @@ bitflags::bitflags! {
         const O_NOFOLLOW = 1 << 16;
         const O_CLOEXEC = 1 << 17;
     }
+
+    pub struct MemoryProtection: u32 {
+        const PROT_NONE = 0;
+        const PROT_READ = 1 << 0;
+        const PROT_WRITE = 1 << 1;
+        const PROT_EXEC = 1 << 2;
+    }
+
+    pub struct MapFlags: u32 {
+        const MAP_SHARED = 1 << 0;
+        const MAP_PRIVATE = 1 << 1;
+        const MAP_FIXED = 1 << 4;
+        const MAP_ANONYMOUS = 1 << 5;
+    }
+
+    pub struct MountFlags: u32 {
+        const MS_RDONLY = 1;
+        const MS_REMOUNT = 32;
+    }
+
+    pub struct WaitOptions: u32 {
+        const WNOHANG = 1;
+        const WUNTRACED = 2;
+        const WCONTINUED = 8;
+    }
+
+    pub struct PollEvents: i16 {
+        const POLLIN = 0x1;
+        const POLLPRI = 0x2;
+        const POLLOUT = 0x4;
+        const POLLERR = 0x8;
+        const POLLHUP = 0x10;
+        const POLLNVAL = 0x20;
+    }
+}
+
+impl FileOpenFlags {
+    pub const O_ACCMODE: u32 = 3;
+
+    /// Returns whether the file was opened for reading
+    pub fn readable(&self) -> bool {
+        self.bits() & Self::O_ACCMODE != Self::O_WRONLY.bits()
+    }
+
+    /// Returns whether the file was opened for writing
+    pub fn writable(&self) -> bool {
+        let mode = self.bits() & Self::O_ACCMODE;
+        mode == Self::O_WRONLY.bits() || mode == Self::O_RDWR.bits()
+    }
 }
 
 pub const F_DUPFD: usize = 1;
@@ -40,6 +92,9 @@ pub const F_SETLKW: usize = 9;
 pub const F_GETOWN: usize = 10;
 pub const F_SETOWN: usize = 11;
 
+/// The only file descriptor flag, F_GETFD and F_SETFD read and write it
+pub const FD_CLOEXEC: usize = 1;
+
 pub const S_IFMT: u32 = 0o170000;
 
 pub const S_IFDIR: u32 = 0o040000;
@@ -50,6 +105,38 @@ pub const S_IFIFO: u32 = 0o010000;
 pub const S_IFLNK: u32 = 0o120000;
 pub const S_IFSOCK: u32 = 0o140000;
 
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_FIFO: u8 = 1;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+pub const DT_SOCK: u8 = 12;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+/// The time given to clock_nanosleep is the deadline instead of the duration
+pub const TIMER_ABSTIME: u32 = 1;
+
+pub const FUTEX_WAIT: u32 = 0;
+pub const FUTEX_WAKE: u32 = 1;
+/// The futex is only used by the threads of one process
+pub const FUTEX_PRIVATE_FLAG: u32 = 128;
+pub const FUTEX_CLOCK_REALTIME: u32 = 256;
+
+// signals are not delivered yet, processes are only terminated with them
+pub const SIGINT: i32 = 2;
+pub const SIGQUIT: i32 = 3;
+pub const SIGKILL: i32 = 9;
+pub const SIGSEGV: i32 = 11;
+pub const SIGTSTP: i32 = 20;
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug)]
 pub struct Timespec {
@@ -64,6 +151,24 @@ pub struct Timeval {
     pub tv_usec: u64,
 }
 
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct IoVec {
+    pub iov_base: u64,
+    pub iov_len: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: i16,
+    pub revents: i16,
+}
+
+/// Number of file descriptors an fd_set of select has room for
+pub const FD_SETSIZE: usize = 1024;
+
 #[repr(C, packed)]
 #[derive(Debug, Clone)]
 pub struct Stat {
@@ -111,22 +216,16 @@ impl Stat {
     }
 
     pub const fn file_type(&self) -> FileType {
-        if self.st_mode & S_IFDIR > 0 {
-            FileType::Directory
-        } else if self.st_mode & S_IFREG > 0 {
-            FileType::RegularFile
-        } else if self.st_mode & S_IFCHR > 0 {
-            FileType::CharacterDevice
-        } else if self.st_mode & S_IFBLK > 0 {
-            FileType::BlockDevice
-        } else if self.st_mode & S_IFIFO > 0 {
-            FileType::FIFO
-        } else if self.st_mode & S_IFLNK > 0 {
-            FileType::Link
-        } else if self.st_mode & S_IFSOCK > 0 {
-            FileType::Socket
-        } else {
-            todo!()
+        // the type bits overlap, e.g. S_IFBLK includes the bits of S_IFDIR and S_IFCHR
+        match self.st_mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFREG => FileType::RegularFile,
+            S_IFCHR => FileType::CharacterDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::FIFO,
+            S_IFLNK => FileType::Link,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::Unknown,
         }
     }
 }
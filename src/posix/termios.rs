@@ -5,6 +5,12 @@ pub const TIOCSPGRP: usize = 0x5410;
 pub const TIOCGWINSZ: usize = 0x5413;
 pub const TIOCSWINSZ: usize = 0x5414;
 
+pub const KDSETMODE: usize = 0x4B3A;
+pub const KDGETMODE: usize = 0x4B3B;
+
+pub const KD_TEXT: usize = 0;
+pub const KD_GRAPHICS: usize = 1;
+
 pub const VINTR: usize = 0;
 pub const VQUIT: usize = 1;
 pub const VERASE: usize = 2;
@@ -89,15 +95,14 @@ pub const HUPCL: usize = 0o002000;
 pub const CLOCAL: usize = 0o004000;
 
 pub const ECHO: usize = 0x1;
+pub const ECHOE: usize = 0x2;
+pub const ECHOK: usize = 0x4;
+pub const ECHONL: usize = 0x8;
 pub const ICANON: usize = 0x10;
+pub const IEXTEN: usize = 0x20;
 pub const ISIG: usize = 0x40;
-
-pub const ECHOE: usize = 0o000020;
-pub const ECHOK: usize = 0o000040;
-pub const ECHONL: usize = 0o000100;
-pub const NOFLSH: usize = 0o000200;
-pub const TOSTOP: usize = 0o000400;
-pub const IEXTEN: usize = 0o100000;
+pub const NOFLSH: usize = 0x80;
+pub const TOSTOP: usize = 0x100;
 
 pub const TCOOFF: usize = 0;
 pub const TCOON: usize = 1;
@@ -1,22 +1,38 @@
 pub mod proc;
 pub mod queue;
 pub mod thread;
+pub mod tls;
+pub mod wait_queue;
 
 use crate::{
     arch::x86_64::{
         self, disable_interrupts,
         registers::{InterruptRegisters, RegisterState},
-        set_fs_base, set_segment_selectors,
+        set_fs_base, set_segment_selectors, smp, stacktrace,
+    },
+    cmdline,
+    limits::CPU_MAX,
+    mm::{
+        kalloc::{self, HeapTag},
+        virt::{self, switch_pml4, PML4},
+        VirtAddr,
     },
-    mm::{virt::PML4, VirtAddr},
     scheduler::thread::ThreadState,
-    sync::InterruptMutex,
+    sync::{self, InterruptMutex, Mutex},
+    time,
 };
 
-use core::arch::asm;
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
 
-use alloc::sync::{Arc, Weak};
-use spin::Mutex;
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+};
 
 use self::{
     queue::SchedulerThreadQueue,
@@ -26,12 +42,267 @@ use self::{
 // kernel thread IDs in the kernel are different from the PIDs of processes/threads
 // a thread may have both a kernel TID and a PID
 
-const TICKS_PER_THREAD_SWITCH: usize = 20;
+/// Default length of a time slice, it can be changed with the kernel/sched_slice_us sysctl
+/// and the sched_slice_us command line option
+const DEFAULT_TIME_SLICE_US: usize = 20_000;
+const MAX_TIME_SLICE_US: usize = 1_000_000;
+
+static TIME_SLICE_US: AtomicUsize = AtomicUsize::new(DEFAULT_TIME_SLICE_US);
+
+pub fn time_slice_us() -> usize {
+    TIME_SLICE_US.load(Ordering::Relaxed)
+}
+
+/// Sets the length of a time slice, returns false if __us__ is shorter than a timer tick or
+/// longer than a second. The slice ends on the first tick after it is used up.
+pub fn set_time_slice_us(us: usize) -> bool {
+    if !(time::TICK_US..=MAX_TIME_SLICE_US).contains(&us) {
+        return false;
+    }
+
+    TIME_SLICE_US.store(us, Ordering::Relaxed);
+    true
+}
+
+/// Returns the nanoseconds a thread may run for before it is switched away from
+fn time_slice_ns() -> u64 {
+    time_slice_us() as u64 * 1000
+}
+
+/// Logs every thread switch made by the timer tick, it can be toggled with the
+/// kernel/sched_tick_trace sysctl
+static TICK_TRACE: AtomicBool = AtomicBool::new(false);
+
+pub fn tick_trace_enabled() -> bool {
+    TICK_TRACE.load(Ordering::Relaxed)
+}
+
+pub fn set_tick_trace(enabled: bool) {
+    TICK_TRACE.store(enabled, Ordering::Relaxed);
+}
+
+/// What happens when a thread goes to sleep while it holds an InterruptMutex, the lock of a
+/// process or a thread, or has interrupts disabled. The CPU would run other threads with the
+/// lock held or with the interrupts enabled under the holder, which deadlocks sooner or later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicSleepCheck {
+    Off = 0,
+    /// Logs the offending thread and a stack trace
+    Warn = 1,
+    Panic = 2,
+}
+
+/// Set by the atomic_sleep_check=warn|panic command line option or the
+/// kernel/atomic_sleep_check sysctl
+static ATOMIC_SLEEP_CHECK: AtomicU8 = AtomicU8::new(AtomicSleepCheck::Off as u8);
+
+pub fn atomic_sleep_check() -> AtomicSleepCheck {
+    match ATOMIC_SLEEP_CHECK.load(Ordering::Relaxed) {
+        1 => AtomicSleepCheck::Warn,
+        2 => AtomicSleepCheck::Panic,
+        _ => AtomicSleepCheck::Off,
+    }
+}
+
+/// Sets the atomic sleep check mode by its number, returns false if it is invalid
+pub fn set_atomic_sleep_check(mode: u8) -> bool {
+    if mode > AtomicSleepCheck::Panic as u8 {
+        return false;
+    }
+
+    ATOMIC_SLEEP_CHECK.store(mode, Ordering::Relaxed);
+    true
+}
+
+/// The contents of /proc/schedstat, the thread switches of every online CPU by reason and
+/// the time it spent running threads and idling
+pub fn proc_schedstat() -> String {
+    let run_queues = SCHEDULER.run_queues.lock();
+    let mut s = String::new();
+    writeln!(s, "slice {}us", time_slice_us()).unwrap();
+    for (cpu, run_queue) in run_queues.cpus.iter().enumerate() {
+        if !run_queue.online {
+            continue;
+        }
+
+        let stats = run_queue.stats;
+        writeln!(
+            s,
+            "cpu{} expired {} voluntary {} preempted {} busy {}ns idle {}ns",
+            cpu,
+            stats.slice_expired,
+            stats.voluntary,
+            stats.preempted,
+            stats.busy_ns,
+            stats.idle_ns
+        )
+        .unwrap();
+    }
+
+    s
+}
+
+/// Why the current thread of a CPU is switched away from on the next tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SwitchReason {
+    /// The thread used up its time slice
+    SliceExpired,
+    /// The thread yielded or blocked
+    Voluntary,
+    /// A woken up or new thread has to run soon
+    Preempted,
+}
+
+/// Counts the thread switches of a CPU by their reason, switches away from the idle thread
+/// are not counted
+#[derive(Debug, Clone, Copy)]
+struct SwitchStats {
+    slice_expired: u64,
+    voluntary: u64,
+    preempted: u64,
+    /// Nanoseconds the CPU ran threads other than the idle thread for
+    busy_ns: u64,
+    idle_ns: u64,
+}
+
+impl SwitchStats {
+    fn record(&mut self, reason: SwitchReason) {
+        match reason {
+            SwitchReason::SliceExpired => self.slice_expired += 1,
+            SwitchReason::Voluntary => self.voluntary += 1,
+            SwitchReason::Preempted => self.preempted += 1,
+        }
+    }
+}
+
+/// The threads of a CPU, every thread that is ready to run and is not running is queued on
+/// exactly one CPU
+struct CpuRunQueue {
+    current: Option<ThreadID>,
+    /// Runs when no other thread is ready, it is never queued
+    idle: Option<ThreadID>,
+    queue: SchedulerThreadQueue,
+    /// Time of the monotonic clock when the current thread was switched to
+    switched_at_ns: u64,
+    /// Set if the next tick has to switch threads before the time slice is used up
+    pending_switch: Option<SwitchReason>,
+    stats: SwitchStats,
+    online: bool,
+}
+
+impl CpuRunQueue {
+    /// Returns the number of threads that want to run on the CPU
+    fn load(&self) -> usize {
+        let busy = self.current.is_some() && self.current != self.idle;
+        self.queue.len() + busy as usize
+    }
+
+    const fn new() -> Self {
+        CpuRunQueue {
+            current: None,
+            idle: None,
+            queue: SchedulerThreadQueue::new(),
+            switched_at_ns: 0,
+            pending_switch: None,
+            stats: SwitchStats {
+                slice_expired: 0,
+                voluntary: 0,
+                preempted: 0,
+                busy_ns: 0,
+                idle_ns: 0,
+            },
+            online: false,
+        }
+    }
+}
+
+struct RunQueues {
+    cpus: [CpuRunQueue; CPU_MAX],
+}
+
+impl RunQueues {
+    /// Returns whether a CPU is running the thread
+    fn is_current(&self, tid: ThreadID) -> bool {
+        self.cpus.iter().any(|cpu| cpu.current == Some(tid))
+    }
+
+    /// Returns the online CPU with the fewest threads
+    fn least_loaded(&self) -> usize {
+        self.cpus
+            .iter()
+            .enumerate()
+            .filter(|(_, cpu)| cpu.online)
+            .min_by_key(|(_, cpu)| cpu.load())
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
+    }
+
+    /// Queues a thread that is ready to run on __cpu__, with __next__ it runs after the current
+    /// thread of the CPU. Returns whether the CPU has to be rescheduled to run the thread soon.
+    fn enqueue(&mut self, cpu: usize, tid: ThreadID, next: bool) -> bool {
+        let run_queue = &mut self.cpus[cpu];
+        let idle = run_queue.current.is_none() || run_queue.current == run_queue.idle;
+
+        if next {
+            run_queue.queue.add_thread_next(tid);
+        } else {
+            run_queue.queue.add_thread(tid);
+        }
+
+        // make the next tick switch threads, a yield that is already pending takes precedence
+        if next || idle {
+            run_queue
+                .pending_switch
+                .get_or_insert(SwitchReason::Preempted);
+        }
+
+        next || idle
+    }
+
+    /// Moves a thread from the back of the longest queue of another CPU to __cpu__ if that
+    /// queue is longer by at least two threads or __cpu__ has nothing else to run
+    fn balance(&mut self, cpu: usize) {
+        let len = self.cpus[cpu].queue.len();
+        let busiest = self
+            .cpus
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| idx != cpu)
+            .max_by_key(|(_, other)| other.queue.len());
+
+        let victim = match busiest {
+            Some((idx, other)) if other.queue.len() > len + 1 => idx,
+            Some((idx, other)) if len == 0 && !other.queue.is_empty() => idx,
+            _ => return,
+        };
+
+        if let Some(tid) = self.cpus[victim].queue.pop_back() {
+            self.cpus[cpu].queue.add_thread(tid);
 
+            if cfg!(sched_debug) {
+                log!(
+                    "SCHED: CPU {} took thread {:#x} from CPU {}",
+                    cpu,
+                    tid.0,
+                    victim
+                );
+            }
+        }
+    }
+
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: CpuRunQueue = CpuRunQueue::new();
+        RunQueues {
+            cpus: [EMPTY; CPU_MAX],
+        }
+    }
+}
+
+// lock order: run_queues, thread_data, then the lock of a thread
 pub struct Scheduler {
     thread_data: InterruptMutex<SchedulerThreadData>,
-    queue: InterruptMutex<SchedulerThreadQueue>,
-    ticks: InterruptMutex<usize>,
+    run_queues: InterruptMutex<RunQueues>,
 }
 
 pub static SCHEDULER: Scheduler = Scheduler::new();
@@ -40,53 +311,65 @@ extern "C" {
     fn x86_64_switch_task(res: *const RegisterState) -> !;
 }
 
+fn idle_thread() {
+    debug!("in idle thread of CPU {}", smp::current_cpu());
+    loop {
+        x86_64::enable_interrupts();
+        unsafe {
+            asm!("hlt");
+        }
+    }
+}
+
 impl Scheduler {
     fn remove_thread(&self, tid: ThreadID) {
-        let mut queue = self.queue.lock();
+        let mut run_queues = self.run_queues.lock();
         let mut thread_data = self.thread_data.lock();
 
-        // check whether we are removing the current thread
-        // TODO: only lock once
-        assert!(*queue.front().unwrap() != tid);
+        // check whether we are removing a running thread
+        assert!(!run_queues.is_current(tid));
 
-        queue.remove_thread(tid);
+        for cpu in run_queues.cpus.iter_mut() {
+            cpu.queue.remove_thread(tid);
+        }
         thread_data.remove_thread(tid);
     }
 
-    fn block_thread(&self, tid: ThreadID) {
-        // we encapsulate the locks in a block so switching thread won't
-        // cause a deadlock
-        let is_current_thread: bool;
-        {
-            let mut queue = self.queue.lock();
-            let mut thread_data = self.thread_data.lock();
-
-            is_current_thread = {
-                let current_tid = *queue.front().expect("Thread queue is empty");
-                current_tid == tid
-            };
-
-            queue.remove_thread(tid);
-            thread_data.change_thread_state(tid, ThreadState::Busy);
-        }
-
-        if is_current_thread {
-            self.force_switch_thread();
-        }
+    pub fn block_current_thread(&self) {
+        self.prepare_to_block();
+        self.yield_current_thread();
     }
 
-    pub fn block_current_thread(&self) {
-        let tid = *self.queue.lock().front().unwrap();
-        self.block_thread(tid);
+    /// Marks the current thread as blocked without switching away from it, returns its TID.
+    /// The thread keeps running until it yields, the next tick saves its registers like any
+    /// other switch but does not schedule it again until it is woken up.
+    pub fn prepare_to_block(&self) -> ThreadID {
+        let run_queues = self.run_queues.lock();
+        let mut thread_data = self.thread_data.lock();
+
+        let tid = run_queues.cpus[smp::current_cpu()]
+            .current
+            .expect("No thread is running");
+        thread_data.change_thread_state(tid, ThreadState::Busy);
+        tid
     }
 
     pub fn get_current_thread(&self) -> Option<Arc<Mutex<Thread>>> {
-        match self.queue.lock().front() {
-            Some(&tid) => self.thread_data.lock().get_thread(tid),
+        let run_queues = self.run_queues.lock();
+        match run_queues.cpus[smp::current_cpu()].current {
+            Some(tid) => self.thread_data.lock().get_thread(tid),
             None => None,
         }
     }
 
+    /// Same as `get_current_thread` but it returns None instead of waiting if the scheduler
+    /// is locked
+    pub fn try_get_current_thread(&self) -> Option<Arc<Mutex<Thread>>> {
+        let run_queues = self.run_queues.try_lock()?;
+        let tid = run_queues.cpus[smp::current_cpu()].current?;
+        self.thread_data.try_lock()?.get_thread(tid)
+    }
+
     fn save_current_thread_regs(&self, int_regs: &InterruptRegisters) {
         let current_thread = match self.get_current_thread() {
             Some(thread) => thread,
@@ -94,13 +377,16 @@ impl Scheduler {
         };
 
         let mut current_thread = current_thread.lock();
+        current_thread.heap_tag = kalloc::current_tag();
 
-        // selectors don't change so there's no need to store them
+        // selectors don't change so there's no need to store them, rflags has to be kept
+        // because a thread can be switched away from while it is allowed to access user pages
         match &mut current_thread.inner {
             ThreadInner::Kernel(data) => {
                 data.regs.general = int_regs.general;
                 data.regs.rip = int_regs.iret.rip;
                 data.regs.rsp = int_regs.iret.rsp;
+                data.regs.rflags = int_regs.iret.rflags;
             }
             ThreadInner::User(data) => {
                 let regs = if data.in_kernelspace {
@@ -112,123 +398,168 @@ impl Scheduler {
                 regs.general = int_regs.general;
                 regs.rip = int_regs.iret.rip;
                 regs.rsp = int_regs.iret.rsp;
+                regs.rflags = int_regs.iret.rflags;
             }
         };
     }
 
-    /// Removes current thread and switches to the next one
+    /// Removes current thread and switches to the next one, the stack of the thread is freed
+    /// later by the reaper thread so the caller must not hold any references on its stack
     pub fn remove_current_thread(&self) -> ! {
-        // we encapsulate the locks in a block so switching thread won't
-        // cause a deadlock
         {
-            let mut queue = self.queue.lock();
+            let run_queues = self.run_queues.lock();
             let mut thread_data = self.thread_data.lock();
 
-            let tid = queue.pop_front().expect("Thread queue is empty");
-
+            let tid = run_queues.cpus[smp::current_cpu()]
+                .current
+                .expect("No thread is running");
             thread_data.remove_thread(tid);
         }
 
-        self.force_switch_thread();
-    }
-
-    pub fn run_thread(&self, tid: ThreadID) {
-        let mut thread_data = self.thread_data.lock();
-        thread_data.change_thread_state(tid, ThreadState::Running);
+        // the thread is not queued again, the reaper frees it once no CPU runs it anymore
+        loop {
+            self.yield_current_thread();
+        }
     }
 
-    fn next_thread(&self) -> Arc<Mutex<Thread>> {
-        let mut queue = self.queue.lock();
-        let thread_data = self.thread_data.lock();
+    /// Removes the user thread __thread__ of an exiting process if it is in userspace and no
+    /// CPU runs it, a thread that runs in userspace is switched away from by the next tick of
+    /// its CPU and not scheduled again. A thread in the kernel is left alone so it can release
+    /// what it holds, it exits when it returns from the kernel. Returns whether the thread
+    /// was removed.
+    pub fn remove_user_thread(&self, thread: &Arc<Mutex<Thread>>) -> bool {
+        let tid = thread.lock().id;
+        let reschedule = {
+            let mut run_queues = self.run_queues.lock();
+            let mut thread_data = self.thread_data.lock();
 
-        if !queue.is_empty() {
-            // pop off the current thread
-            // if this is none it means the front thread has been removed
-            queue.pop_front().expect("Thread queue is empty");
-        }
+            // the thread could have exited and its TID could belong to a new thread already
+            match thread_data.get_thread(tid) {
+                Some(current) if Arc::ptr_eq(&current, thread) => {}
+                _ => return false,
+            }
 
-        // if the queue is empty start at the front of the running threads
-        if queue.is_empty() {
-            match thread_data.running_threads.len() {
-                0 => panic!("Sentinel is not running"),
-                // if no other threads are running add the sentinel thread to the queue
-                1 => queue.add_thread(ThreadID(0)),
-                // otherwise add all running threads except the sentinel thread
-                _ => thread_data
-                    .running_threads
-                    .iter()
-                    .skip(1)
-                    .for_each(|&tid| queue.add_thread(tid)),
+            let mut thread = thread.lock();
+            let alive = matches!(thread.state, ThreadState::Running | ThreadState::Busy);
+            let cpu = thread.cpu;
+            let in_kernelspace = match &mut thread.inner {
+                ThreadInner::User(data) => {
+                    data.exit_pending = true;
+                    data.in_kernelspace
+                }
+                ThreadInner::Kernel(_) => return false,
             };
-        }
+            drop(thread);
 
-        let next_thread_id = *queue.front().expect("Thread queue is empty");
-        thread_data
-            .get_thread(next_thread_id)
-            .expect("Invalid next thread id")
+            if !alive || in_kernelspace {
+                return false;
+            }
+
+            if run_queues.is_current(tid) {
+                run_queues.cpus[cpu]
+                    .pending_switch
+                    .get_or_insert(SwitchReason::Preempted);
+                cpu
+            } else {
+                for cpu in run_queues.cpus.iter_mut() {
+                    cpu.queue.remove_thread(tid);
+                }
+                thread_data.remove_thread(tid);
+                return true;
+            }
+        };
+
+        smp::send_reschedule(reschedule);
+        false
     }
 
-    /// this function should only be called from a thread that is about to be removed or blocked
-    fn force_switch_thread(&self) -> ! {
-        disable_interrupts();
+    /// Queues a new thread on the CPU with the fewest threads
+    fn queue_new_thread(&self, run_queues: &mut RunQueues, thread: &Mutex<Thread>) -> usize {
+        let mut thread = thread.lock();
+        let cpu = run_queues.least_loaded();
+        thread.cpu = cpu;
+        run_queues.enqueue(cpu, thread.id, false);
+        cpu
+    }
 
-        // we encapsulate the locks in a block so switching thread won't
-        // cause a deadlock
-        let regs = {
-            let next_thread = self.next_thread();
-            let next_thread = next_thread.lock();
+    pub fn run_thread(&self, tid: ThreadID) {
+        let cpu = {
+            let mut run_queues = self.run_queues.lock();
+            let mut thread_data = self.thread_data.lock();
+            thread_data.change_thread_state(tid, ThreadState::Running);
 
-            unsafe {
-                x86_64::tss::TSS.rsp0 = next_thread.stack_bottom;
-            }
+            let thread = thread_data.get_thread(tid).expect("Invalid TID");
+            self.queue_new_thread(&mut run_queues, &thread)
+        };
 
-            let (regs, tls) = match &next_thread.inner {
-                ThreadInner::Kernel(data) => (&data.regs, VirtAddr::zero()),
-                ThreadInner::User(data) => (
-                    if data.in_kernelspace {
-                        &data.kernel_regs
-                    } else {
-                        &data.user_regs
-                    },
-                    data.tls,
-                ),
-            };
+        smp::send_reschedule(cpu);
+    }
 
-            set_segment_selectors(regs.selectors.es);
+    /// Wakes up a blocked thread, this can be called from an interrupt handler.
+    /// A thread that blocked has not used up its time slice so instead of putting it
+    /// at the back of the queue it is scheduled right after the current thread of its CPU and
+    /// that CPU is preempted right away. Every waiter is woken through this, e.g. the readers
+    /// of the keyboard and the threads waiting for an ATA interrupt.
+    pub fn wake_thread(&self, tid: ThreadID) {
+        let cpu = {
+            let mut run_queues = self.run_queues.lock();
+            let mut thread_data = self.thread_data.lock();
 
-            set_fs_base(tls);
+            thread_data.change_thread_state(tid, ThreadState::Running);
 
-            **regs
+            // the thread has not been switched away from yet so it just keeps running
+            if run_queues.is_current(tid) {
+                return;
+            }
+
+            let cpu = thread_data.get_thread(tid).expect("Invalid TID").lock().cpu;
+            run_queues.enqueue(cpu, tid, true);
+            cpu
         };
 
-        // TODO: dont copy registers
-        unsafe {
-            let ptr = &regs as *const RegisterState;
-            // push the registers on the stack and switch tasks
-            x86_64_switch_task(ptr);
+        // an interrupt handler mostly wakes a thread of its own CPU, the switch happens once
+        // the handler returns
+        if cpu == smp::current_cpu() {
+            smp::reschedule_self();
+        } else {
+            smp::send_reschedule(cpu);
         }
     }
 
-    pub fn tick(&self, int_regs: &mut InterruptRegisters) {
-        //println!("tick");
-        {
-            let mut ticks = self.ticks.lock();
-            *ticks += 1;
-            if *ticks < TICKS_PER_THREAD_SWITCH {
-                return;
-            }
+    fn next_thread(&self, cpu: usize) -> Arc<Mutex<Thread>> {
+        let mut run_queues = self.run_queues.lock();
+        let thread_data = self.thread_data.lock();
 
-            *ticks = 0;
+        let idle = run_queues.cpus[cpu].idle.expect("CPU has no idle thread");
+        if let Some(tid) = run_queues.cpus[cpu].current.take() {
+            // threads that blocked or exited are not queued again, neither are the ones of an
+            // exiting process that were switched away from in userspace
+            if tid != idle && thread_data.is_running(tid) && !thread_data.is_exit_pending(tid) {
+                run_queues.cpus[cpu].queue.add_thread(tid);
+            }
         }
 
-        self.save_current_thread_regs(int_regs);
+        run_queues.balance(cpu);
+
+        let next_thread_id = run_queues.cpus[cpu].queue.pop_front().unwrap_or(idle);
+        run_queues.cpus[cpu].current = Some(next_thread_id);
+        run_queues.cpus[cpu].switched_at_ns = time::monotonic_ns();
+
+        thread_data
+            .get_thread(next_thread_id)
+            .expect("Invalid next thread id")
+    }
 
-        let next_thread = self.next_thread();
-        let next_thread = next_thread.lock();
+    /// Makes __next_thread__ the current thread of __cpu__ and returns its saved registers.
+    /// They are boxed in the thread so the pointer stays valid while the thread runs, the
+    /// interrupt stubs restore them without copying them to the interrupt stack first.
+    fn switch_to(&self, cpu: usize, next_thread: &mut Thread) -> *const RegisterState {
+        next_thread.cpu = cpu;
 
-        //println!("switch thread {}", next_thread.id.0);
+        load_tss(next_thread);
+        load_address_space(next_thread);
+        kalloc::set_current_tag(next_thread.heap_tag);
 
-        // TODO: dont copy registers
         let (regs, tls) = match &next_thread.inner {
             ThreadInner::Kernel(data) => (&data.regs, VirtAddr::zero()),
             ThreadInner::User(data) => (
@@ -245,63 +576,420 @@ impl Scheduler {
 
         set_fs_base(tls);
 
-        int_regs.general = regs.general;
-        int_regs.iret.rip = regs.rip;
-        int_regs.iret.rsp = regs.rsp;
-        int_regs.iret.ss = regs.selectors.ss;
-        int_regs.iret.cs = regs.selectors.cs;
-        int_regs.iret.rflags = regs.rflags;
+        &**regs as *const RegisterState
+    }
+
+    /// Switches to the first thread of the CPU, this is only used when the scheduler is
+    /// started on a CPU
+    fn force_switch_thread(&self, cpu: usize) -> ! {
+        disable_interrupts();
+
+        // we encapsulate the locks in a block so switching thread won't
+        // cause a deadlock
+        let regs = {
+            let next_thread = self.next_thread(cpu);
+            let mut next_thread = next_thread.lock();
+            self.switch_to(cpu, &mut next_thread)
+        };
+
+        unsafe {
+            x86_64_switch_task(regs);
+        }
+    }
+
+    /// Runs on every timer tick of the CPU, the interrupt is delivered on the interrupt stack
+    /// of the CPU so the thread switched away from can be picked up by another CPU right away.
+    /// Returns the saved registers of the thread to switch to or null if the current thread
+    /// keeps running, the interrupt stub then restores __int_regs__ instead.
+    pub fn tick(&self, int_regs: &InterruptRegisters) -> *const RegisterState {
+        let cpu = smp::current_cpu();
+        {
+            let mut run_queues = self.run_queues.lock();
+            let run_queue = &mut run_queues.cpus[cpu];
+            let now = time::monotonic_ns();
+            let ran_for = now.saturating_sub(run_queue.switched_at_ns);
+
+            let reason = match run_queue.pending_switch.take() {
+                Some(reason) => reason,
+                None if ran_for >= time_slice_ns() => SwitchReason::SliceExpired,
+                None => return ptr::null(),
+            };
+
+            if run_queue.current != run_queue.idle {
+                run_queue.stats.record(reason);
+                run_queue.stats.busy_ns += ran_for;
+            } else {
+                run_queue.stats.idle_ns += ran_for;
+            }
+        }
+
+        self.save_current_thread_regs(int_regs);
+
+        let next_thread = self.next_thread(cpu);
+        let mut next_thread = next_thread.lock();
+
+        if tick_trace_enabled() {
+            log!(
+                "SCHED: tick switch to thread {:#x} on CPU {}",
+                next_thread.id.0,
+                cpu
+            );
+        }
+
+        self.switch_to(cpu, &mut next_thread)
+    }
+
+    /// Blocks the current thread for at least __ms__ milliseconds, the thread is woken up
+    /// by the first timer tick after that
+    pub fn sleep_current(&self, ms: u64) {
+        time::sleep_until(time::elapsed().as_milliseconds() + ms);
+    }
+
+    /// Gives up the rest of the time slice of the current thread, the CPU switches to the next
+    /// thread right away
+    pub fn yield_current_thread(&self) {
+        self.check_atomic_sleep();
+
+        // the IPI has to be taken at the hlt, a thread switched away from before it would
+        // halt until the next interrupt once it runs again
+        x86_64::disable_interrupts();
+        {
+            let mut run_queues = self.run_queues.lock();
+            run_queues.cpus[smp::current_cpu()].pending_switch = Some(SwitchReason::Voluntary);
+        }
+        smp::reschedule_self();
+
+        // sti only enables interrupts after the next instruction
+        unsafe {
+            asm!("sti; hlt");
+        }
+    }
+
+    /// Reports a thread that is about to sleep in atomic context if the check is enabled
+    fn check_atomic_sleep(&self) {
+        let mode = atomic_sleep_check();
+        if mode == AtomicSleepCheck::Off {
+            return;
+        }
+
+        let held_locks = sync::held_locks();
+        let interrupts_enabled = x86_64::interrupts_enabled();
+        if held_locks == 0 && interrupts_enabled {
+            return;
+        }
+
+        let tid = self
+            .try_get_current_thread()
+            .and_then(|thread| Some(thread.try_lock()?.id));
+        match mode {
+            AtomicSleepCheck::Panic => panic!(
+                "SCHED: thread {:?} sleeps in atomic context, locks held: {}, \
+                 interrupts enabled: {}",
+                tid, held_locks, interrupts_enabled
+            ),
+            _ => {
+                warn!(
+                    "SCHED: thread {:?} sleeps in atomic context, locks held: {}, \
+                     interrupts enabled: {}",
+                    tid, held_locks, interrupts_enabled
+                );
+                stacktrace::walk();
+            }
+        }
+    }
+
+    /// Frees the resources of the threads that have exited
+    fn reap_dead_threads(&self) {
+        let reaped = {
+            let run_queues = self.run_queues.lock();
+            let mut thread_data = self.thread_data.lock();
+            if !thread_data.has_dead_threads() {
+                return;
+            }
+
+            // a thread that exited is still on its stack until its CPU switches away from it
+            thread_data.reap_dead_threads(|tid| run_queues.is_current(tid))
+        };
+
+        // the threads are dropped here with the scheduler unlocked
+        drop(reaped);
     }
 
     pub fn start(&self) -> ! {
-        self.force_switch_thread();
+        self.force_switch_thread(0);
+    }
+
+    /// Starts scheduling threads on an AP, the CPU gets its own idle thread
+    pub fn start_cpu(&self, cpu: usize) -> ! {
+        {
+            let mut run_queues = self.run_queues.lock();
+            let mut thread_data = self.thread_data.lock();
+
+            let idle = thread_data.create_kernel_thread(idle_thread);
+            let idle = idle.upgrade().expect("Idle thread was freed");
+            let mut idle = idle.lock();
+            idle.cpu = cpu;
+
+            run_queues.cpus[cpu].idle = Some(idle.id);
+            run_queues.cpus[cpu].online = true;
+        }
+
+        self.force_switch_thread(cpu);
     }
 
     pub fn init(&self, pml4: &PML4) {
+        if let Some(us) = cmdline::get("sched_slice_us") {
+            match us.parse() {
+                Ok(us) if set_time_slice_us(us) => {}
+                _ => warn!("SCHED: invalid time slice {}us", us),
+            }
+        }
+        log!("SCHED: time slice is {}us", time_slice_us());
+
+        if let Some(mode) = cmdline::get("atomic_sleep_check") {
+            let mode = match mode.as_str() {
+                "warn" => AtomicSleepCheck::Warn,
+                "panic" => AtomicSleepCheck::Panic,
+                _ => {
+                    warn!("SCHED: invalid atomic sleep check mode {}", mode);
+                    AtomicSleepCheck::Off
+                }
+            };
+            ATOMIC_SLEEP_CHECK.store(mode as u8, Ordering::Relaxed);
+        }
+
+        let mut run_queues = self.run_queues.lock();
         let mut thread_data = self.thread_data.lock();
         thread_data.init(pml4);
 
-        // spawn sentinel thread
-        thread_data.create_kernel_thread(|| loop {
-            debug!("in sentinel thread");
-            loop {
-                x86_64::enable_interrupts();
-                unsafe {
-                    asm!("hlt");
-                }
-                // halt
-            }
+        // spawn the idle thread of the BSP
+        let idle = thread_data.create_kernel_thread(idle_thread);
+        let idle = idle.upgrade().expect("Idle thread was freed");
+        run_queues.cpus[0].idle = Some(idle.lock().id);
+        run_queues.cpus[0].online = true;
+
+        // spawn reaper thread
+        // a dead thread can't free its own stack because it is still running on it
+        let reaper = thread_data.create_kernel_thread(|| loop {
+            SCHEDULER.reap_dead_threads();
+            virt::free_dying_address_spaces();
+            SCHEDULER.yield_current_thread();
         });
+        let reaper = reaper.upgrade().expect("Reaper thread was freed");
+        self.queue_new_thread(&mut run_queues, &reaper);
     }
 
-    pub fn create_user_thread(&self, pid: usize) -> Weak<Mutex<Thread>> {
+    pub fn create_user_thread(&self, pid: usize, pml4: &PML4) -> Weak<Mutex<Thread>> {
+        let _tag = kalloc::tag(HeapTag::Sched);
         let mut thread_data = self.thread_data.lock();
-        thread_data.create_user_thread(pid)
+        thread_data.create_user_thread(pid, pml4)
     }
 
     pub fn create_kernel_thread(&self, f: fn()) -> Weak<Mutex<Thread>> {
-        let mut thread_data = self.thread_data.lock();
-        thread_data.create_kernel_thread(f)
+        let _tag = kalloc::tag(HeapTag::Sched);
+        let (weak, cpu) = {
+            let mut run_queues = self.run_queues.lock();
+            let mut thread_data = self.thread_data.lock();
+
+            let weak = thread_data.create_kernel_thread(f);
+            let thread = weak.upgrade().expect("Kernel thread was freed");
+            let cpu = self.queue_new_thread(&mut run_queues, &thread);
+            (weak, cpu)
+        };
+
+        smp::send_reschedule(cpu);
+        weak
     }
 
-    pub fn copy_user_thread(&self, pid: usize, tid: ThreadID) -> Weak<Mutex<Thread>> {
+    pub fn copy_user_thread(&self, pid: usize, tid: ThreadID, pml4: &PML4) -> Weak<Mutex<Thread>> {
         let mut thread_data = self.thread_data.lock();
-        thread_data.copy_user_thread(pid, tid)
+        thread_data.copy_user_thread(pid, tid, pml4)
+    }
+
+    pub fn can_create_thread(&self) -> bool {
+        self.thread_data.lock().can_create_thread()
     }
 
-    pub fn ticks(&self) -> usize {
-        *self.ticks.lock()
+    /// Logs every thread for the panic handler, the locks are broken into because the other
+    /// CPUs are halted and nothing would release them. The RIP is the one that was saved when
+    /// the thread was switched away from or entered the kernel, it is stale for the threads
+    /// that are running. Nothing is allocated because the panic could come from the heap.
+    pub fn dump_threads(&self) {
+        let (thread_data, held) = unsafe { self.thread_data.force_lock() };
+        let (run_queues, _) = unsafe { self.run_queues.force_lock() };
+        error!("threads:{}", if held { " (list was locked)" } else { "" });
+        error!("  TID STATE   CPU RIP                PID");
+        for thread_lock in thread_data.threads() {
+            let (thread, held) = unsafe { thread_lock.force_lock() };
+            let (rip, pid, in_kernel) = match &thread.inner {
+                ThreadInner::Kernel(data) => (data.regs.rip, None, true),
+                ThreadInner::User(data) if data.in_kernelspace => {
+                    (data.kernel_regs.rip, Some(data.pid), true)
+                }
+                ThreadInner::User(data) => (data.user_regs.rip, Some(data.pid), false),
+            };
+            let state = match thread.state {
+                ThreadState::None => "none",
+                ThreadState::Running => "running",
+                ThreadState::Busy => "busy",
+                ThreadState::Dead => "dead",
+            };
+            let on_cpu = run_queues
+                .cpus
+                .iter()
+                .any(|cpu| cpu.current == Some(thread.id));
+
+            match pid {
+                Some(pid) => error!(
+                    "{:>5} {:<7} {:>3}{} {:#018x} {}{}{}",
+                    thread.id.0,
+                    state,
+                    thread.cpu,
+                    if on_cpu { "*" } else { " " },
+                    rip,
+                    pid,
+                    if in_kernel { " (in kernel)" } else { "" },
+                    if held { " (locked)" } else { "" }
+                ),
+                None => error!(
+                    "{:>5} {:<7} {:>3}{} {:#018x} kernel{}",
+                    thread.id.0,
+                    state,
+                    thread.cpu,
+                    if on_cpu { "*" } else { " " },
+                    rip,
+                    if held { " (locked)" } else { "" }
+                ),
+            }
+        }
+    }
+
+    /// Writes the threads and the run queues of the online CPUs as records of the crash dump,
+    /// the locks are broken the same way as in `dump_threads`
+    pub fn crash_dump(&self, w: &mut dyn Write) -> fmt::Result {
+        let (thread_data, _) = unsafe { self.thread_data.force_lock() };
+        let (run_queues, _) = unsafe { self.run_queues.force_lock() };
+        for thread_lock in thread_data.threads() {
+            let (thread, _) = unsafe { thread_lock.force_lock() };
+            let (rip, pid, mode) = match &thread.inner {
+                ThreadInner::Kernel(data) => (data.regs.rip, None, "kernel"),
+                ThreadInner::User(data) if data.in_kernelspace => {
+                    (data.kernel_regs.rip, Some(data.pid), "kernel")
+                }
+                ThreadInner::User(data) => (data.user_regs.rip, Some(data.pid), "user"),
+            };
+
+            writeln!(
+                w,
+                "thread tid={} state={:?} cpu={} rip={:#x} mode={} pid={}",
+                thread.id.0,
+                thread.state,
+                thread.cpu,
+                rip,
+                mode,
+                pid.map_or(-1, |pid| pid as isize)
+            )?;
+        }
+
+        // -1 is no thread
+        let tid = |tid: Option<ThreadID>| tid.map_or(-1, |tid| tid.0 as isize);
+        for (cpu, run_queue) in run_queues.cpus.iter().enumerate() {
+            if !run_queue.online {
+                continue;
+            }
+
+            write!(
+                w,
+                "runqueue cpu={} current={} idle={} queued=",
+                cpu,
+                tid(run_queue.current),
+                tid(run_queue.idle)
+            )?;
+            for (idx, queued) in run_queue.queue.iter().enumerate() {
+                let separator = if idx == 0 { "" } else { "," };
+                write!(w, "{}{}", separator, queued.0)?;
+            }
+            writeln!(w)?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls __f__ with every thread and the CPU running it, the threads that are locked are
+    /// skipped. Returns false without calling __f__ if the scheduler is locked. Nothing is
+    /// forced open because the debugger stub resumes the kernel afterwards.
+    pub fn try_for_each_thread(&self, mut f: impl FnMut(&Thread, Option<usize>)) -> bool {
+        let thread_data = match self.thread_data.try_lock() {
+            Some(thread_data) => thread_data,
+            None => return false,
+        };
+        let run_queues = match self.run_queues.try_lock() {
+            Some(run_queues) => run_queues,
+            None => return false,
+        };
+
+        for thread_lock in thread_data.threads() {
+            let thread = match thread_lock.try_lock() {
+                Some(thread) => thread,
+                None => continue,
+            };
+            let cpu = run_queues
+                .cpus
+                .iter()
+                .position(|cpu| cpu.current == Some(thread.id));
+            f(&thread, cpu);
+        }
+
+        true
+    }
+
+    /// Returns the usable range of the kernel stack of the thread running on this CPU, None
+    /// if there is none or the scheduler is locked
+    pub fn try_current_kernel_stack(&self) -> Option<(VirtAddr, VirtAddr)> {
+        let thread = self.try_get_current_thread()?;
+        let thread = thread.try_lock()?;
+        Some(thread.kernel_stack_range())
+    }
+
+    /// Returns the nanoseconds the current thread has run for since it was switched to
+    pub fn current_run_time_ns(&self) -> u64 {
+        let switched_at = self.run_queues.lock().cpus[smp::current_cpu()].switched_at_ns;
+        time::monotonic_ns().saturating_sub(switched_at)
     }
 
     const fn new() -> Self {
         Scheduler {
             thread_data: InterruptMutex::new(SchedulerThreadData::new()),
-            queue: InterruptMutex::new(SchedulerThreadQueue::new()),
-            ticks: InterruptMutex::new(0),
+            run_queues: InterruptMutex::new(RunQueues::new()),
+        }
+    }
+}
+
+/// Makes interrupts and syscalls from userspace enter the kernel on the kernel stack of the thread
+/// and loads the I/O permissions of the thread, kernel threads never leave ring 0 so the
+/// previous values can be kept
+fn load_tss(thread: &Thread) {
+    if let ThreadInner::User(data) = &thread.inner {
+        unsafe {
+            x86_64::tss::set_kernel_stack(data.kernel_stack_bottom);
+            x86_64::tss::load_io_bitmap(data.io_bitmap.as_deref());
         }
     }
 }
 
+/// Switches to the address space of the thread, kernel threads run in the address space of
+/// the kernel so a process that exited is not kept loaded by the CPU
+fn load_address_space(thread: &Thread) {
+    let pml4 = match &thread.inner {
+        ThreadInner::Kernel(data) => &data.pml4,
+        ThreadInner::User(data) => &data.pml4,
+    };
+
+    if x86_64::get_current_pml4_phys() != pml4.phys() {
+        switch_pml4(pml4);
+    }
+}
+
 pub fn remove_current_thread_wrapper() {
     SCHEDULER.remove_current_thread();
 }
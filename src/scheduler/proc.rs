@@ -1,42 +1,147 @@
-use core::{alloc::Layout, slice};
+use core::slice;
 
 use crate::{
     arch::x86_64::{
         enable_interrupts, get_current_pml4,
-        paging::PageFlags,
+        paging::{self, PageFlags},
         syscall::proc::{CloneArgs, CloneFlags},
+        usercopy::{is_userspace_range, Out, UserAccess, UserSlice, USERSPACE_END},
     },
-    fs::{fd::FileDescriptor, VFS},
+    cmdline, entropy,
+    fs::{fd::FileDescriptor, poll::PollQueue, VFS},
+    limits::{self, OPEN_MAX, PROCESS_MAX},
     mm::{
+        page_cache,
         phys::PHYS_ALLOCATOR,
-        virt::{switch_pml4, PAGE_SIZE_4KIB, PML4},
-        VirtAddr,
+        virt::{self, switch_pml4, PAGE_SIZE_4KIB, PML4},
+        PhysAddr, VirtAddr,
     },
-    posix::{FileOpenFlags, Stat},
-    scheduler::{ThreadInner, SCHEDULER},
+    posix::{
+        errno::{Errno, ENOEXEC},
+        FileOpenFlags, Stat, SIGKILL, SIGTSTP,
+    },
+    scheduler::{
+        wait_queue::{WaitQueue, Waiter},
+        ThreadInner, SCHEDULER,
+    },
+    sync::{self, Mutex},
     utils::slot_allocator::SlotAllocator,
 };
 
 use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
 use elf::{
-    abi::{PF_X, PT_LOAD},
+    abi::{EI_NIDENT, PF_W, PF_X, PT_LOAD},
     endian::LittleEndian,
-    segment::ProgramHeader,
-    ElfBytes,
+    file::{parse_ident, Class, FileHeader},
+    segment::{ProgramHeader, SegmentTable},
 };
-use spin::Mutex;
+use spin::Once;
 
-use super::{Thread, ThreadID};
+use super::{thread::ThreadState, Thread, ThreadID};
 
 bitflags::bitflags! {
     pub struct MappedRegionFlags: u64 {
         const READ_WRITE = 1 << 0;
         const ALLOC_ON_ACCESS = 1 << 1;
         const EXECUTE = 1 << 2;
+        /// The region is reserved but it can not be accessed
+        const NO_ACCESS = 1 << 3;
+    }
+}
+
+/// End of the user stack, the stack of every executable ends a random number of pages below
+/// it. The last page of userspace is left unmapped.
+const STACK_AREA_END: usize = USERSPACE_END as usize - PAGE_SIZE_4KIB as usize;
+/// The stack is slid by less than this many pages, 16 GiB
+const STACK_RANDOM_PAGES: usize = 1 << 22;
+/// Size of the stack when a program starts, 64 KiB
+const STACK_INITIAL_PAGES: usize = 16;
+/// Faults at most this far under the stack grow it, accesses further down are not stack
+/// accesses. Nothing can be mapped this far under the lowest address the stack can grow to.
+const STACK_GUARD_GAP: usize = 256 * PAGE_SIZE_4KIB as usize;
+/// Lowest address mmap searches for free space from when no address is given
+const MMAP_AREA_START: usize = 0x1000;
+/// The start of the search is slid by less than this many pages, 1 TiB
+const MMAP_RANDOM_PAGES: usize = 1 << 28;
+
+/// Returns a random offset of less than __pages__ pages, it is zero if the randomization of
+/// the address space was turned off with aslr=off
+fn random_page_offset(pages: usize) -> usize {
+    static ASLR_ENABLED: Once<bool> = Once::new();
+    let enabled = *ASLR_ENABLED.call_once(|| cmdline::get("aslr").map_or(true, |val| val != "off"));
+    if !enabled {
+        return 0;
+    }
+
+    (entropy::random_u64() as usize % pages) * PAGE_SIZE_4KIB as usize
+}
+
+/// The stack of the main thread, it starts small and grows down on demand
+#[derive(Debug, Clone, Copy)]
+struct UserStack {
+    /// Lowest mapped address of the stack
+    bottom: usize,
+    /// The stack can not grow below this, the address space down to it and the guard gap
+    /// under it are reserved for the stack
+    limit: usize,
+}
+
+impl UserStack {
+    /// Returns whether the range [start, end) overlaps the space reserved for the stack
+    fn reserves(&self, start: usize, end: usize) -> bool {
+        start < self.bottom && self.limit - STACK_GUARD_GAP < end
+    }
+}
+
+/// The result of a page fault under the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackGrowth {
+    /// The stack was grown, the access can be retried
+    Grown,
+    /// The address is not in the guard gap under the stack
+    NotStack,
+    /// The stack would grow past the stack limit of the process
+    LimitExceeded,
+}
+
+/// An entry of the file descriptor table, descriptors created by dup share __file__
+#[derive(Debug, Clone)]
+struct FileDescriptorSlot {
+    file: Arc<spin::Mutex<FileDescriptor>>,
+    /// FD_CLOEXEC, execve closes the descriptor
+    close_on_exec: bool,
+}
+
+/// What the memory of a region comes from, decides how the region is copied on fork
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionBacking {
+    /// Private memory that is allocated on access, copied on write after fork
+    Anonymous,
+    /// Private copy of a part of a file made when the region was mapped, copied on write
+    /// after fork
+    File { path: String, offset: usize },
+    /// Memory that stays shared with the children created by fork
+    Shared,
+    /// Memory of a device mapped through its device file, e.g. the framebuffer. The pages
+    /// map the device memory directly so they are shared with the children created by fork.
+    Device {
+        path: String,
+        offset: usize,
+        phys: PhysAddr,
+    },
+}
+
+impl RegionBacking {
+    /// Returns whether writes to the region are seen by the other processes mapping it
+    pub fn is_shared(&self) -> bool {
+        matches!(self, RegionBacking::Shared | RegionBacking::Device { .. })
     }
 }
 
@@ -46,26 +151,64 @@ pub struct MappedRegion {
     pages: usize,
     end: usize,
     flags: MappedRegionFlags,
+    backing: RegionBacking,
 }
 
-const MAX_PROCESSES: usize = 32;
+/// Size of the ELF64 file header
+const ELF64_EHDR_SIZE: usize = 64;
+
+/// Size of an ELF64 program header
+const ELF64_PHDR_SIZE: usize = 56;
+
+/// The program header table is read into kernel memory, larger ones are rejected
+const ELF_PHDR_TABLE_MAX: usize = 64 * 1024;
 
 impl MappedRegion {
-    const fn new(start: usize, pages: usize, flags: MappedRegionFlags) -> MappedRegion {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn flags(&self) -> MappedRegionFlags {
+        self.flags
+    }
+
+    pub fn backing(&self) -> &RegionBacking {
+        &self.backing
+    }
+
+    const fn new(
+        start: usize,
+        pages: usize,
+        flags: MappedRegionFlags,
+        backing: RegionBacking,
+    ) -> MappedRegion {
         MappedRegion {
             start,
             pages,
             end: start + pages * PAGE_SIZE_4KIB as usize,
             flags,
+            backing,
         }
     }
 
     fn page_flags(&self) -> PageFlags {
         let mut flags = PageFlags::USER;
+        if self.flags.contains(MappedRegionFlags::NO_ACCESS) {
+            return flags;
+        }
+
         if self.flags.contains(MappedRegionFlags::READ_WRITE) {
             flags |= PageFlags::READ_WRITE;
         }
 
+        if !self.flags.contains(MappedRegionFlags::EXECUTE) {
+            flags |= paging::no_execute();
+        }
+
         if self.flags.contains(MappedRegionFlags::ALLOC_ON_ACCESS) {
             flags |= PageFlags::ALLOC_ON_ACCESS;
         } else {
@@ -92,15 +235,58 @@ pub struct Process {
     pub egid: usize,
 
     mapped_regions: Vec<MappedRegion>,
+    /// Where mmap starts searching for free space, it is randomized on every exec
+    mmap_base: usize,
+    /// The stack of the main thread, None until the process execs
+    stack: Option<UserStack>,
 
     pub main_thread: Weak<Mutex<Thread>>,
+    /// Every thread of the process including the main thread and the ones that exited, the
+    /// process can only be freed once all of them are dead
+    threads: Vec<Weak<Mutex<Thread>>>,
+    /// Number of threads that have not exited yet
+    live_threads: usize,
+    /// Set once a thread started terminating the other threads of the process
+    group_exit: bool,
     pml4: PML4,
-    file_descriptors: SlotAllocator<Arc<Mutex<FileDescriptor>>>,
+    /// The page tables belong to the parent, the process was cloned with CLONE_VM
+    shares_pml4: bool,
+    file_descriptors: SlotAllocator<FileDescriptorSlot>,
+    /// The arguments the current executable was started with
+    cmdline: Vec<String>,
+    /// Wait status of the process once it has exited, the process stays a zombie until
+    /// its parent waits for it
+    exit_status: Option<i32>,
+    /// Set once the process execs or exits, a parent that cloned it with CLONE_VFORK
+    /// sleeps until then
+    vfork_done: bool,
 }
 
 unsafe impl Send for Process {}
 
-static PROCESSES: Mutex<SlotAllocator<Arc<Mutex<Process>>>> = Mutex::new(SlotAllocator::new(None));
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.release_regions();
+
+        // the process is only dropped once its threads are dead
+        // TODO: CLONE_VM children that outlive the parent still use the PML4
+        if !self.shares_pml4 {
+            virt::free_address_space(self.pml4.clone());
+        }
+    }
+}
+
+static PROCESSES: spin::Mutex<SlotAllocator<Arc<Mutex<Process>>>> =
+    spin::Mutex::new(SlotAllocator::new(Some(PROCESS_MAX)));
+
+// held while checking or changing the exit and exec state of processes so a parent
+// going to sleep can not miss a child exiting or execing
+static CHILD_EVENT_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static CHILD_EVENT_QUEUE: WaitQueue = WaitQueue::new();
+
+/// Signals sent to processes that have not been terminated by them yet, by PID
+static PENDING_SIGNALS: spin::Mutex<BTreeMap<usize, i32>> = spin::Mutex::new(BTreeMap::new());
+static SIGNAL_POLL_QUEUE: PollQueue = PollQueue::new();
 
 impl Process {
     fn create_base_process() -> Arc<Mutex<Process>> {
@@ -113,6 +299,7 @@ impl Process {
 
         let new_pml4 = PML4::from_phys(new_pml4);
 
+        let main_thread = SCHEDULER.create_user_thread(1, &new_pml4);
         let proc = Process {
             pid: 1,
             egid: 1,
@@ -122,9 +309,18 @@ impl Process {
             pgid: 1,
             uid: 1,
             mapped_regions: Vec::new(),
-            main_thread: SCHEDULER.create_user_thread(1),
+            mmap_base: MMAP_AREA_START + random_page_offset(MMAP_RANDOM_PAGES),
+            stack: None,
+            main_thread: main_thread.clone(),
+            threads: vec![main_thread],
+            live_threads: 1,
+            group_exit: false,
             pml4: new_pml4,
-            file_descriptors: SlotAllocator::new(None),
+            shares_pml4: false,
+            file_descriptors: SlotAllocator::new(Some(OPEN_MAX)),
+            cmdline: Vec::new(),
+            exit_status: None,
+            vfork_done: false,
         };
 
         let proc_arc = Arc::new(Mutex::new(proc));
@@ -140,15 +336,127 @@ impl Process {
         let virt_end = virt_start + VirtAddr::new(region.pages as u64 * PAGE_SIZE_4KIB);
         let flags = region.page_flags();
 
-        self.pml4.map_range(virt_start, virt_end, flags);
+        if let RegionBacking::Device { phys, .. } = &region.backing {
+            self.pml4.map_physical_range(virt_start, virt_end, *phys, flags);
+        } else if flags.intersects(PageFlags::PRESENT | PageFlags::ALLOC_ON_ACCESS) {
+            self.pml4.map_range(virt_start, virt_end, flags);
+        } else {
+            // the entries are still created so the frames can be allocated on access once
+            // the region is made accessible
+            self.pml4
+                .map_range(virt_start, virt_end, flags | PageFlags::ALLOC_ON_ACCESS);
+            self.pml4.protect_range(virt_start, virt_end, flags);
+        }
 
         debug!("map region after");
     }
 
+    /// Unmaps every region, the frames are freed once no other address space maps them.
+    /// Nothing is unmapped if the page tables are shared with the parent.
+    fn release_regions(&mut self) {
+        let regions = core::mem::take(&mut self.mapped_regions);
+        if self.shares_pml4 {
+            return;
+        }
+
+        for region in regions.iter() {
+            let virt_end = VirtAddr::new(region.end as u64);
+            self.pml4.unmap_range(region.virt_addr(), virt_end);
+        }
+    }
+
     fn clear_file_descriptors(&mut self) {
         self.file_descriptors.clear();
     }
 
+    /// Closes the file descriptors that have FD_CLOEXEC set
+    fn close_on_exec_fds(&mut self) {
+        let fds: Vec<usize> = (0..OPEN_MAX)
+            .filter(|&fd| {
+                self.file_descriptors
+                    .get(fd)
+                    .is_some_and(|slot| slot.close_on_exec)
+            })
+            .collect();
+
+        for fd in fds {
+            self.file_descriptors.deallocate(fd);
+        }
+    }
+
+    /// Closes the file descriptors and unmaps the regions of an exiting process, only what
+    /// the parent needs to wait for it is kept
+    pub fn release_resources(&mut self) {
+        self.clear_file_descriptors();
+        self.release_regions();
+    }
+
+    /// Returns the start of every page of the private regions userspace can not write
+    pub fn read_only_pages(&self) -> Vec<VirtAddr> {
+        self.mapped_regions
+            .iter()
+            .filter(|region| {
+                !region.flags.contains(MappedRegionFlags::READ_WRITE) && !region.backing.is_shared()
+            })
+            .flat_map(|region| {
+                (region.start..region.end)
+                    .step_by(PAGE_SIZE_4KIB as usize)
+                    .map(|addr| VirtAddr::new(addr as u64))
+            })
+            .collect()
+    }
+
+    /// Creates a thread in the process that is a copy of the thread __tid__, it is not
+    /// started. Returns None if the process is exiting or there are too many threads.
+    pub fn clone_thread(&mut self, tid: ThreadID) -> Option<Weak<Mutex<Thread>>> {
+        if self.group_exit || !SCHEDULER.can_create_thread() {
+            return None;
+        }
+
+        let thread = SCHEDULER.copy_user_thread(self.pid, tid, &self.pml4);
+        // the threads that have been freed are not needed anymore
+        self.threads.retain(|thread| thread.strong_count() > 0);
+        self.threads.push(thread.clone());
+        self.live_threads += 1;
+        Some(thread)
+    }
+
+    /// Accounts for a thread of the process that exits, returns false without doing so if it
+    /// is the last one, that thread has to terminate the process
+    pub fn remove_live_thread(&mut self) -> bool {
+        if self.live_threads == 1 {
+            return false;
+        }
+
+        self.live_threads -= 1;
+        true
+    }
+
+    /// Returns whether every thread of the process is dead, the address space can be freed
+    /// once no thread runs on it
+    fn threads_dead(&self) -> bool {
+        self.threads
+            .iter()
+            .filter_map(Weak::upgrade)
+            .all(|thread| thread.lock().state == ThreadState::Dead)
+    }
+
+    /// Makes __thread__ the main thread and only thread of the process, the others must have
+    /// exited
+    pub fn set_only_thread(&mut self, thread: &Arc<Mutex<Thread>>) {
+        assert!(self.live_threads == 1);
+        self.main_thread = Arc::downgrade(thread);
+        self.threads = vec![self.main_thread.clone()];
+    }
+
+    pub fn mapped_regions(&self) -> &[MappedRegion] {
+        &self.mapped_regions
+    }
+
+    pub fn pml4(&self) -> &PML4 {
+        &self.pml4
+    }
+
     // TODO: better name
     pub fn get_region(&self, region_start: usize, region_end: usize) -> Option<usize> {
         // TODO: check if addresses are aligned?
@@ -163,10 +471,11 @@ impl Process {
         region_start: usize,
         pages: usize,
         flags: MappedRegionFlags,
+        backing: RegionBacking,
     ) -> Result<(), ()> {
         debug!(
-            "add region {:#x} {:#x} pages {:?}",
-            region_start, pages, flags
+            "add region {:#x} {:#x} pages {:?} {:?}",
+            region_start, pages, flags, backing
         );
         assert!(region_start % 4096 == 0);
 
@@ -176,8 +485,15 @@ impl Process {
             return Err(());
         }
 
+        if self
+            .stack
+            .is_some_and(|stack| stack.reserves(region_start, region_end))
+        {
+            return Err(());
+        }
+
         // TODO: check for overlapping regions
-        let region = MappedRegion::new(region_start, pages, flags);
+        let region = MappedRegion::new(region_start, pages, flags, backing);
         self.map_region(&region);
         self.mapped_regions.push(region);
 
@@ -190,12 +506,12 @@ impl Process {
         desired_addr: Option<usize>,
         len: usize,
         flags: MappedRegionFlags,
+        backing: RegionBacking,
     ) -> Result<usize, ()> {
         // TODO: optimize
         let pages = len.div_ceil(4096);
         let region_start = desired_addr.unwrap_or_else(|| {
-            const REGION_SEARCH_START: usize = 0x1000;
-            let (mut start, mut end) = (REGION_SEARCH_START, REGION_SEARCH_START + len);
+            let (mut start, mut end) = (self.mmap_base, self.mmap_base + len);
 
             while let Some(idx) = self.get_region(start, end) {
                 let region = &self.mapped_regions[idx];
@@ -206,40 +522,247 @@ impl Process {
             start
         });
 
-        self.add_region(region_start, pages, flags)?;
+        self.add_region(region_start, pages, flags, backing)?;
         Ok(region_start)
     }
 
+    /// Grows the stack down to the page of __addr__ if it is in the guard gap under the stack,
+    /// the stack can not grow past the stack limit the process was started with
+    pub fn grow_stack(&mut self, addr: usize) -> StackGrowth {
+        let mut stack = match self.stack {
+            Some(stack) => stack,
+            None => return StackGrowth::NotStack,
+        };
+
+        if addr >= stack.bottom || addr < stack.bottom.saturating_sub(STACK_GUARD_GAP) {
+            return StackGrowth::NotStack;
+        }
+
+        let new_bottom = addr - addr % PAGE_SIZE_4KIB as usize;
+        if new_bottom < stack.limit {
+            return StackGrowth::LimitExceeded;
+        }
+
+        // the lowest page of the stack could have been unmapped by the program
+        let idx = match self
+            .mapped_regions
+            .iter()
+            .position(|region| region.start == stack.bottom)
+        {
+            Some(idx) => idx,
+            None => return StackGrowth::NotStack,
+        };
+
+        let region = &mut self.mapped_regions[idx];
+        let pages = (stack.bottom - new_bottom) / PAGE_SIZE_4KIB as usize;
+        let grown = MappedRegion::new(new_bottom, pages, region.flags, region.backing.clone());
+        region.start = new_bottom;
+        region.pages += pages;
+        self.map_region(&grown);
+
+        stack.bottom = new_bottom;
+        self.stack = Some(stack);
+        StackGrowth::Grown
+    }
+
+    /// Splits the region that contains `addr` into two regions at `addr`
+    fn split_region_at(&mut self, addr: usize) {
+        let idx = match self
+            .mapped_regions
+            .iter()
+            .position(|region| region.start < addr && addr < region.end)
+        {
+            Some(idx) => idx,
+            None => return,
+        };
+
+        let region = &mut self.mapped_regions[idx];
+        let pages = (addr - region.start) / PAGE_SIZE_4KIB as usize;
+        let upper_backing = match &region.backing {
+            RegionBacking::File { path, offset } => RegionBacking::File {
+                path: path.clone(),
+                offset: offset + (addr - region.start),
+            },
+            RegionBacking::Device { path, offset, phys } => RegionBacking::Device {
+                path: path.clone(),
+                offset: offset + (addr - region.start),
+                phys: *phys + PhysAddr::new((addr - region.start) as u64),
+            },
+            backing => backing.clone(),
+        };
+        let upper = MappedRegion::new(addr, region.pages - pages, region.flags, upper_backing);
+        region.pages = pages;
+        region.end = addr;
+
+        self.mapped_regions.push(upper);
+    }
+
+    /// Unmaps the pages of the range [start, start + len), the range does not have to be
+    /// mapped entirely
+    pub fn munmap(&mut self, start: usize, len: usize) {
+        assert!(start % 4096 == 0);
+        let end = start + len.div_ceil(4096) * 4096;
+
+        self.split_region_at(start);
+        self.split_region_at(end);
+
+        let (removed, kept) = self
+            .mapped_regions
+            .drain(..)
+            .partition(|region| start <= region.start && region.end <= end);
+        self.mapped_regions = kept;
+
+        for region in removed.iter() {
+            let virt_end = VirtAddr::new(region.end as u64);
+            self.pml4.unmap_range(region.virt_addr(), virt_end);
+        }
+    }
+
+    /// Changes the protection of the pages of the range [start, start + len), `flags` can
+    /// only contain READ_WRITE, EXECUTE and NO_ACCESS. Every page of the range has to be mapped.
+    pub fn mprotect(
+        &mut self,
+        start: usize,
+        len: usize,
+        flags: MappedRegionFlags,
+    ) -> Result<(), ()> {
+        assert!(start % 4096 == 0);
+        assert!(!flags.contains(MappedRegionFlags::ALLOC_ON_ACCESS));
+        let end = start + len.div_ceil(4096) * 4096;
+
+        // the regions do not overlap so the range is covered if the overlaps add up to it
+        let covered: usize = self
+            .mapped_regions
+            .iter()
+            .map(|region| region.end.min(end).saturating_sub(region.start.max(start)))
+            .sum();
+        if covered != end - start {
+            return Err(());
+        }
+
+        self.split_region_at(start);
+        self.split_region_at(end);
+
+        for region in self
+            .mapped_regions
+            .iter_mut()
+            .filter(|region| start <= region.start && region.end <= end)
+        {
+            region.flags = (region.flags & MappedRegionFlags::ALLOC_ON_ACCESS) | flags;
+
+            // frames of private regions that are shared with other processes or merged
+            // pages have to be copied before they become writable
+            let writable = region.page_flags().contains(PageFlags::READ_WRITE);
+            let virt_end = VirtAddr::new(region.end as u64);
+            if writable && !region.backing.is_shared() {
+                for addr in (region.start..region.end).step_by(PAGE_SIZE_4KIB as usize) {
+                    self.pml4.unshare_page(VirtAddr::new(addr as u64));
+                }
+            }
+
+            self.pml4
+                .protect_range(region.virt_addr(), virt_end, region.page_flags());
+        }
+
+        Ok(())
+    }
+
+    /// Adds an open file to the file descriptor table at __hint__ or at the first free slot,
+    /// O_CLOEXEC in the flags of the file sets FD_CLOEXEC on the new descriptor
     pub fn new_fd(
         &mut self,
         hint: Option<usize>,
-        file_descriptor: Arc<Mutex<FileDescriptor>>,
+        file_descriptor: Arc<spin::Mutex<FileDescriptor>>,
     ) -> Result<usize, ()> {
-        match self.file_descriptors.allocate(hint, file_descriptor) {
+        // the flag belongs to the descriptor, not to the open file that dup shares
+        let close_on_exec = {
+            let mut file_desc = file_descriptor.lock();
+            let close_on_exec = file_desc.flags.contains(FileOpenFlags::O_CLOEXEC);
+            file_desc.flags.remove(FileOpenFlags::O_CLOEXEC);
+            close_on_exec
+        };
+
+        let slot = FileDescriptorSlot {
+            file: file_descriptor,
+            close_on_exec,
+        };
+        self.allocate_fd(hint, slot)
+    }
+
+    fn allocate_fd(&mut self, hint: Option<usize>, slot: FileDescriptorSlot) -> Result<usize, ()> {
+        // the first free slot is below the limit if fewer descriptors are open
+        let limit = limits::open_max();
+        if self.file_descriptors.allocated_slots() >= limit || hint.is_some_and(|fd| fd >= limit)
+        {
+            return Err(());
+        }
+
+        match self.file_descriptors.allocate(hint, slot) {
             Some(fd) => Ok(fd),
             None => Err(()),
         }
     }
 
-    // TODO: error
-    pub fn dup_fd(&mut self, hint: Option<usize>, fd: usize) -> Result<usize, ()> {
-        let file_desc = match self.file_descriptors.get(fd) {
-            Some(f) => {
-                let val = Mutex::new(((**f).lock()).clone());
-                Arc::new(val)
-            }
+    /// Duplicates __fd__ to the lowest free descriptor that is at least __min__, the new
+    /// descriptor shares the open file and its offset with __fd__
+    pub fn dup_fd(&mut self, min: usize, fd: usize, close_on_exec: bool) -> Result<usize, ()> {
+        let file = match self.file_descriptors.get(fd) {
+            Some(slot) => slot.file.clone(),
+            None => return Err(()),
+        };
+
+        let slot = FileDescriptorSlot {
+            file,
+            close_on_exec,
+        };
+        let new_fd = self.file_descriptors.first_free_from(min);
+        self.allocate_fd(Some(new_fd), slot)
+    }
+
+    /// Makes __new_fd__ refer to the open file of __fd__, the file __new_fd__ referred to
+    /// is closed first
+    pub fn dup_fd_to(&mut self, fd: usize, new_fd: usize, close_on_exec: bool) -> Result<(), ()> {
+        let file = match self.file_descriptors.get(fd) {
+            Some(slot) => slot.file.clone(),
             None => return Err(()),
         };
 
-        self.new_fd(hint, file_desc)
+        if new_fd >= limits::open_max() {
+            return Err(());
+        }
+
+        if self.file_descriptors.get(new_fd).is_some() {
+            self.free_fd(new_fd);
+        }
+
+        let slot = FileDescriptorSlot {
+            file,
+            close_on_exec,
+        };
+        self.allocate_fd(Some(new_fd), slot).map(|_| ())
     }
 
     pub fn free_fd(&mut self, fd: usize) {
         self.file_descriptors.deallocate(fd)
     }
 
-    pub fn get_fd(&self, fd: usize) -> Option<Arc<Mutex<FileDescriptor>>> {
-        self.file_descriptors.get(fd).cloned()
+    pub fn get_fd(&self, fd: usize) -> Option<Arc<spin::Mutex<FileDescriptor>>> {
+        self.file_descriptors.get(fd).map(|slot| slot.file.clone())
+    }
+
+    /// Returns whether FD_CLOEXEC is set on __fd__
+    pub fn fd_close_on_exec(&self, fd: usize) -> Option<bool> {
+        self.file_descriptors.get(fd).map(|slot| slot.close_on_exec)
+    }
+
+    pub fn set_fd_close_on_exec(&mut self, fd: usize, close_on_exec: bool) -> Result<(), ()> {
+        match self.file_descriptors.get_mut(fd) {
+            Some(slot) => {
+                slot.close_on_exec = close_on_exec;
+                Ok(())
+            }
+            None => Err(()),
+        }
     }
 
     pub fn get_full_path_from_dirfd(&self, dirfd: Option<usize>, path: &str) -> Result<String, ()> {
@@ -261,16 +784,27 @@ impl Process {
             let file_desc = file_lock.lock();
 
             // TODO: faster way to use the base path
-            let vnode = file_desc.vnode.upgrade().unwrap();
+            let vnode = match file_desc.vnode() {
+                Some(vnode) => vnode,
+                None => return Err(()),
+            };
             let base_path = vnode.lock().get_path();
             Ok(format!("{}/{}", base_path, path))
         }
     }
 
-    pub fn clone_proc(&self, clone_args: &CloneArgs) -> Arc<Mutex<Process>> {
+    pub fn clone_proc(&self, clone_args: &CloneArgs) -> Result<Arc<Mutex<Process>>, ()> {
         let mut processes = PROCESSES.lock();
+        if processes.allocated_slots() >= PROCESS_MAX || !SCHEDULER.can_create_thread() {
+            return Err(());
+        }
 
-        let tid = self.main_thread.upgrade().unwrap().lock().id;
+        // the child is a copy of the thread that called clone
+        let tid = SCHEDULER
+            .get_current_thread()
+            .expect("No threads running")
+            .lock()
+            .id;
 
         let clone_flags = CloneFlags::from_bits_truncate(clone_args.flags);
 
@@ -278,8 +812,18 @@ impl Process {
             self.pml4.clone()
         } else {
             let new_pml4 = PHYS_ALLOCATOR.lock().alloc_single();
-            self.pml4.copy_page_tables(new_pml4);
-            PML4::from_phys(new_pml4)
+            get_current_pml4().copy_pml4_higher_half_entries(new_pml4);
+            let new_pml4 = PML4::from_phys(new_pml4);
+
+            // private regions are copied on write, shared regions keep their frames
+            for region in self.mapped_regions.iter() {
+                let copy_on_write = !region.backing.is_shared();
+                let virt_end = VirtAddr::new(region.end as u64);
+                self.pml4
+                    .share_range(&new_pml4, region.virt_addr(), virt_end, copy_on_write);
+            }
+
+            new_pml4
         };
 
         let proc = Process {
@@ -290,15 +834,23 @@ impl Process {
             euid: self.euid,
             gid: self.gid,
             egid: self.egid,
-            // TODO: mapped regions?
             mapped_regions: self.mapped_regions.clone(),
+            mmap_base: self.mmap_base,
+            stack: self.stack,
             main_thread: Weak::new(),
+            threads: Vec::new(),
+            live_threads: 1,
+            group_exit: false,
             pml4,
+            shares_pml4: clone_flags.contains(CloneFlags::CLONE_VM),
             file_descriptors: self.file_descriptors.clone(),
+            cmdline: self.cmdline.clone(),
+            exit_status: None,
+            vfork_done: false,
         };
 
         let proc_arc = Arc::new(Mutex::new(proc));
-        let pid = processes.allocate(None, proc_arc.clone()).unwrap() + 1;
+        let pid = processes.allocate(None, proc_arc.clone()).ok_or(())? + 1;
 
         // unfortunately we can't allocate a pid without setting the value and
         // adding that functionality to SlotAllocator would introduce unnecessary
@@ -309,30 +861,52 @@ impl Process {
             let mut proc = proc.lock();
 
             proc.pid = pid;
-            proc.main_thread = SCHEDULER.copy_user_thread(pid, tid);
+            proc.main_thread = SCHEDULER.copy_user_thread(pid, tid, &proc.pml4);
+            proc.threads = vec![proc.main_thread.clone()];
         }
 
-        proc_arc
+        Ok(proc_arc)
     }
 
-    pub fn execve(&mut self, exec_path: &str, args: &[&str], envvars: &[&str]) -> Result<(), ()> {
-        self.clear_file_descriptors();
-        self.load_from_file(exec_path, args, envvars)?;
+    /// Replaces the program of the process with __executable__, the old program is gone even
+    /// if it fails
+    pub fn execve(
+        &mut self,
+        executable: Executable,
+        args: &[&str],
+        envvars: &[&str],
+    ) -> Result<(), ()> {
+        self.close_on_exec_fds();
+        self.load_from_file(executable, args, envvars)?;
         self.open_default_files("/root");
 
         Ok(())
     }
 
-    fn load_normal_segment(&mut self, file: &[u8], header: &ProgramHeader) -> Result<(), ()> {
+    fn load_normal_segment(
+        &mut self,
+        file: &mut FileDescriptor,
+        header: &ProgramHeader,
+    ) -> Result<(), ()> {
         self.load_segment(file, header, VirtAddr::new(header.p_vaddr))
     }
 
     fn load_segment(
         &mut self,
-        file: &[u8],
+        file: &mut FileDescriptor,
         header: &ProgramHeader,
         virt_addr_start: VirtAddr,
     ) -> Result<(), ()> {
+        // segments userspace can not write are mapped straight from the page cache, the
+        // pages of the file have to line up with the pages of the segment for that
+        let page_size = PAGE_SIZE_4KIB;
+        if header.p_flags & PF_W == 0
+            && header.p_memsz == header.p_filesz
+            && header.p_offset % page_size == virt_addr_start.get() % page_size
+        {
+            return self.load_shared_segment(file, header, virt_addr_start);
+        }
+
         let mut flags = MappedRegionFlags::empty();
         /*if ph.p_flags & PF_W > 0 {
             flags |= MappedRegionFlags::READ_WRITE;
@@ -349,26 +923,71 @@ impl Process {
         let page_offset = virt_addr_start.page_offset();
         let seg_page_start = VirtAddr::new(virt_addr_start.get() - page_offset);
         let pages = (mem_size + page_offset as usize).div_ceil(PAGE_SIZE_4KIB as usize);
-        self.add_region(seg_page_start.get() as usize, pages, flags)
-            .unwrap();
+        let backing = RegionBacking::File {
+            path: file.vnode().unwrap().lock().get_path(),
+            offset: header.p_offset as usize - page_offset as usize,
+        };
+        self.add_region(seg_page_start.get() as usize, pages, flags, backing)
+            .map_err(|_| warn!("PID {}: segment at {:#x} overlaps", self.pid, virt_addr_start))?;
+
+        // the region is already mapped in the current address space, the file is read into a
+        // kernel buffer so userspace access is only enabled while a piece of it is copied
+        let seg_mem = UserSlice::<Out>::new(virt_addr_start.get(), mem_size)
+            .map_err(|_| warn!("PID {}: segment at {:#x} is invalid", self.pid, virt_addr_start))?;
+        let mut buff = vec![0; PAGE_SIZE_4KIB as usize];
 
         let seg_size = header.p_filesz as usize;
-        if seg_size > 0 {
-            let seg_start = header.p_offset as usize;
-            let seg_end = seg_start + seg_size;
+        let mut off = 0;
+        while off < seg_size {
+            let len = buff.len().min(seg_size - off);
+            read_exact_at(file, header.p_offset as usize + off, &mut buff[..len])?;
+            seg_mem.write_at(off, &buff[..len]).map_err(|_| ())?;
+            off += len;
+        }
 
-            let proc_mem =
-                unsafe { slice::from_raw_parts_mut(virt_addr_start.get() as *mut u8, seg_size) };
-            let seg_mem = &file[seg_start..seg_end];
+        buff.fill(0);
+        while off < mem_size {
+            let len = buff.len().min(mem_size - off);
+            seg_mem.write_at(off, &buff[..len]).map_err(|_| ())?;
+            off += len;
+        }
 
-            proc_mem.copy_from_slice(seg_mem);
+        Ok(())
+    }
+
+    /// Maps the pages of a read-only segment to the frames of the page cache, every process
+    /// running the file shares them
+    fn load_shared_segment(
+        &mut self,
+        file: &mut FileDescriptor,
+        header: &ProgramHeader,
+        virt_addr_start: VirtAddr,
+    ) -> Result<(), ()> {
+        let mut flags = MappedRegionFlags::ALLOC_ON_ACCESS;
+        if header.p_flags & PF_X > 0 {
+            flags |= MappedRegionFlags::EXECUTE;
         }
 
-        let remaining = mem_size - seg_size;
-        if remaining > 0 {
-            let ptr = (virt_addr_start.get() + seg_size as u64) as *mut u8;
-            let seg_mem = unsafe { slice::from_raw_parts_mut(ptr, remaining) };
-            seg_mem.fill(0);
+        let page_size = PAGE_SIZE_4KIB as usize;
+        let page_offset = virt_addr_start.page_offset() as usize;
+        let seg_page_start = virt_addr_start.get() as usize - page_offset;
+        let file_page_start = header.p_offset as usize - page_offset;
+        let pages = (header.p_memsz as usize + page_offset).div_ceil(page_size);
+
+        let path = file.vnode().unwrap().lock().get_path();
+        let backing = RegionBacking::File {
+            path: path.clone(),
+            offset: file_page_start,
+        };
+        self.add_region(seg_page_start, pages, flags, backing)?;
+
+        for page in 0..pages {
+            let offset = file_page_start + page * page_size;
+            let phys =
+                page_cache::get_page(&path, offset, |buff| read_at_most(file, offset, buff))?;
+            let virt = VirtAddr::new((seg_page_start + page * page_size) as u64);
+            // the mapping takes over the reference get_page took for us
+            self.pml4.map_frame(virt, phys);
         }
 
         Ok(())
@@ -376,20 +995,12 @@ impl Process {
 
     fn load_segments(
         &mut self,
-        file: &[u8],
-        elf_file: &ElfBytes<'_, LittleEndian>,
+        file: &mut FileDescriptor,
+        segments: SegmentTable<'_, LittleEndian>,
     ) -> Result<(), ()> {
-        let segments = match elf_file.segments() {
-            Some(segs) => segs,
-            None => return Err(()),
-        };
-
-        // TODO TODO
-        // FIXME
-        // TODO: check if the segments are in userspace
         for ph in segments {
             match ph.p_type {
-                PT_LOAD => self.load_normal_segment(file, &ph).unwrap(),
+                PT_LOAD => self.load_normal_segment(file, &ph)?,
                 _ => {
                     warn!("ignoring segment: {:?}", ph);
                     continue;
@@ -400,89 +1011,76 @@ impl Process {
         Ok(())
     }
 
-    fn load_file_contents(&mut self, exec_path: &str) -> Result<u64, ()> {
-        let mut vfs = VFS.write();
-        let mut fd = vfs.open(exec_path, FileOpenFlags::empty()).unwrap();
-
-        let mut stat_buf = Stat::zero();
-        fd.stat(&mut stat_buf).unwrap();
-
-        let file_size = stat_buf.st_size as usize;
-
-        // TODO: perhaps we can parse the ELF header without reading the whole file
-        // and instead later reading the file to userspace
-        let layout = Layout::from_size_align(file_size, 8).unwrap();
-        let ptr = unsafe { alloc::alloc::alloc(layout) };
-
-        let entry_point = {
-            let buff = unsafe { slice::from_raw_parts_mut(ptr, file_size) };
-
-            match fd.read(&mut buff[..]) {
-                Ok(_) => {}
-                Err(err) => panic!("{:?}", err),
-            };
-
-            let elf_file = match ElfBytes::<LittleEndian>::minimal_parse(&buff[..]) {
-                Ok(file) => file,
-                Err(_) => {
-                    unsafe { alloc::alloc::dealloc(ptr, layout) };
-                    return Err(());
-                }
-            };
-
-            switch_pml4(&self.pml4);
-            self.load_segments(&buff, &elf_file).unwrap();
-
-            elf_file.ehdr.e_entry
-        };
-
-        unsafe { alloc::alloc::dealloc(ptr, layout) };
-        Ok(entry_point)
-    }
-
     pub fn load_from_file(
         &mut self,
-        exec_path: &str,
+        mut executable: Executable,
         args: &[&str],
         envvars: &[&str],
     ) -> Result<(), ()> {
         // TODO: shorten this function
+        self.release_regions();
+        self.stack = None;
+
         let current_pml4 = get_current_pml4();
         let new_pml4 = PHYS_ALLOCATOR.lock().alloc_single();
         current_pml4.copy_pml4_higher_half_entries(new_pml4);
-        self.pml4 = PML4::from_phys(new_pml4);
-        // TODO: cleanup pml4 from fork
+        let old_pml4 = core::mem::replace(&mut self.pml4, PML4::from_phys(new_pml4));
+        let owned_old_pml4 = !core::mem::replace(&mut self.shares_pml4, false);
 
-        self.mapped_regions.clear();
+        // the thread loads the new address space when it is switched to
+        if let ThreadInner::User(data) = &mut self.main_thread.upgrade().unwrap().lock().inner {
+            data.pml4 = self.pml4.clone();
+        }
 
-        let entry_point = self.load_file_contents(exec_path)?;
+        // the page tables of a CLONE_VM child belong to the parent
+        switch_pml4(&self.pml4);
+        if owned_old_pml4 {
+            virt::free_address_space(old_pml4);
+        }
+
+        // the segments were checked when the file was opened
+        let segments = SegmentTable::new(LittleEndian, Class::ELF64, &executable.phdr_buff);
+        self.load_segments(&mut executable.file, segments)?;
+        let entry_point = executable.entry;
 
         // TODO: proper flags
 
-        const STACK_BASE: u64 = 0xfffffd8000000000;
-        const STACK_SIZE_IN_PAGES: u64 = 16; // 64 KiB
-        const STACK_SIZE: u64 = STACK_SIZE_IN_PAGES * PAGE_SIZE_4KIB;
+        const STACK_SIZE: u64 = STACK_INITIAL_PAGES as u64 * PAGE_SIZE_4KIB;
+
+        self.mmap_base = MMAP_AREA_START + random_page_offset(MMAP_RANDOM_PAGES);
+        let stack_end = STACK_AREA_END - random_page_offset(STACK_RANDOM_PAGES);
+        let stack_base = (stack_end - STACK_SIZE as usize) as u64;
 
         self.add_region(
-            STACK_BASE as usize,
-            STACK_SIZE_IN_PAGES as usize,
+            stack_base as usize,
+            STACK_INITIAL_PAGES,
             MappedRegionFlags::READ_WRITE,
+            RegionBacking::Anonymous,
         )
-        .unwrap();
+        .map_err(|_| warn!("PID {}: the stack overlaps the program", self.pid))?;
+
+        // the space under the stack is reserved once the stack is mapped
+        let stack_max = limits::stack_max().max(STACK_SIZE as usize);
+        self.stack = Some(UserStack {
+            bottom: stack_base as usize,
+            limit: stack_end - stack_max,
+        });
 
         let argc_argv_envp_size = (1 + args.len() + 1 + envvars.len() + 1) * 8;
         let rem = argc_argv_envp_size % 16;
-        let stack_bottom = STACK_BASE + STACK_SIZE - rem as u64;
+        let stack_bottom = stack_base + STACK_SIZE - rem as u64;
 
-        let (argv, envp) = unsafe { write_argv_envp(stack_bottom, args, envvars) };
+        let (stack_top, argv, envp) = {
+            let _access = UserAccess::begin();
+            let (argv, envp) = unsafe { write_argv_envp(stack_bottom, args, envvars) };
 
-        let stack_top = argv - 8;
-        {
+            let stack_top = argv - 8;
             let stack_ptr = stack_top as *mut u64;
             unsafe {
                 stack_ptr.write(args.len() as u64);
             }
-        }
+            (stack_top, argv, envp)
+        };
 
         debug!(
             "stack_top: {:#x} argc: {:#x} argv: {:#x} envp: {:#x}",
@@ -515,37 +1113,43 @@ impl Process {
             unreachable!()
         }
 
+        self.cmdline = args.iter().map(|&arg| String::from(arg)).collect();
+
         Ok(())
     }
 
+    /// Opens the console as stdin, stdout and stderr and __cwd__ as descriptor 3, the
+    /// descriptors a process inherited through execve are kept
     fn open_default_files(&mut self, cwd: &str) {
         // open console
         // TODO: proper flags
         let mut vfs = VFS.write();
-        let console_fd = vfs
-            .open("/dev/console", FileOpenFlags::O_RDWR)
-            .expect("Failed to open /dev/console");
-
-        // stdin
-        let fd = self
-            .new_fd(Some(0), Arc::new(Mutex::new(*console_fd)))
-            .unwrap();
-        assert!(fd == 0);
-
-        // stdout
-        let fd = self.dup_fd(None, fd).unwrap();
-        assert!(fd == 1);
-
-        // stderr
-        let fd = self.dup_fd(None, fd).unwrap();
-        assert!(fd == 2);
+        let missing_std = (0..3).any(|fd| self.file_descriptors.get(fd).is_none());
+        if missing_std {
+            let console_fd = vfs
+                .open("/dev/console", FileOpenFlags::O_RDWR)
+                .expect("Failed to open /dev/console");
+            let console = Arc::new(spin::Mutex::new(*console_fd));
+
+            // stdin, stdout and stderr share the open file like after dup
+            for fd in 0..3 {
+                if self.file_descriptors.get(fd).is_none() {
+                    let new_fd = self.new_fd(Some(fd), console.clone()).unwrap();
+                    assert!(new_fd == fd);
+                }
+            }
+        }
 
-        let cwd_fd = vfs
-            .open(cwd, FileOpenFlags::O_RDWR)
-            .expect("Failed to open cwd");
+        if self.file_descriptors.get(3).is_none() {
+            let cwd_fd = vfs
+                .open(cwd, FileOpenFlags::O_RDWR)
+                .expect("Failed to open cwd");
 
-        let fd = self.new_fd(Some(3), Arc::new(Mutex::new(*cwd_fd))).unwrap();
-        assert!(fd == 3);
+            let fd = self
+                .new_fd(Some(3), Arc::new(spin::Mutex::new(*cwd_fd)))
+                .unwrap();
+            assert!(fd == 3);
+        }
     }
 }
 
@@ -610,6 +1214,103 @@ unsafe fn write_argv_envp(stack_bottom: u64, args: &[&str], envvars: &[&str]) ->
     (argv as u64, envp as u64)
 }
 
+/// An ELF executable whose headers were checked, it is opened before the process gives up its
+/// old program so execve can still fail with an error
+pub struct Executable {
+    file: Box<FileDescriptor>,
+    entry: u64,
+    /// The program header table, only the file header and the table are read into kernel
+    /// memory, the segments themselves are read directly into the mapped user pages
+    phdr_buff: Vec<u8>,
+}
+
+impl Executable {
+    /// Opens the executable at __path__ and checks its file header and its loadable segments
+    pub fn open(path: &str) -> Result<Executable, Errno> {
+        let file = VFS
+            .write()
+            .open(path, FileOpenFlags::empty())
+            .map_err(|err| err.into())?;
+
+        let mut ehdr_buff = [0u8; ELF64_EHDR_SIZE];
+        read_exact_at(&file, 0, &mut ehdr_buff).map_err(|_| ENOEXEC)?;
+
+        let ident = parse_ident::<LittleEndian>(&ehdr_buff).map_err(|_| ENOEXEC)?;
+        let ehdr = FileHeader::parse_tail(ident, &ehdr_buff[EI_NIDENT..]).map_err(|_| ENOEXEC)?;
+        if ehdr.class != Class::ELF64 {
+            return Err(ENOEXEC);
+        }
+
+        let mut stat_buf = Stat::zero();
+        file.stat(&mut stat_buf).map_err(|_| ENOEXEC)?;
+        let file_size = stat_buf.st_size as usize;
+
+        // the table is checked before anything is allocated for it
+        let phdr_table_size = ELF64_PHDR_SIZE * ehdr.e_phnum as usize;
+        let phdr_table_end = (ehdr.e_phoff as usize).checked_add(phdr_table_size);
+        if ehdr.e_phentsize as usize != ELF64_PHDR_SIZE
+            || phdr_table_size > ELF_PHDR_TABLE_MAX
+            || phdr_table_end.map_or(true, |end| end > file_size)
+        {
+            return Err(ENOEXEC);
+        }
+
+        let mut phdr_buff = vec![0u8; phdr_table_size];
+        read_exact_at(&file, ehdr.e_phoff as usize, &mut phdr_buff).map_err(|_| ENOEXEC)?;
+
+        let segments = SegmentTable::new(ehdr.endianness, ehdr.class, &phdr_buff);
+        if let Some(ph) = segments
+            .iter()
+            .find(|ph| ph.p_type == PT_LOAD && !is_valid_segment(ph))
+        {
+            warn!("{}: invalid segment: {:?}", path, ph);
+            return Err(ENOEXEC);
+        }
+
+        Ok(Executable {
+            file,
+            entry: ehdr.e_entry,
+            phdr_buff,
+        })
+    }
+}
+
+/// Returns whether the memory of a loadable segment is in userspace and the data read from
+/// the file fits in it
+fn is_valid_segment(header: &ProgramHeader) -> bool {
+    let page_offset = header.p_vaddr % PAGE_SIZE_4KIB;
+    header.p_filesz <= header.p_memsz
+        && header.p_offset >= page_offset
+        && is_userspace_range(header.p_vaddr, header.p_memsz as usize)
+}
+
+/// Reads exactly buff.len() bytes from the file starting at off
+fn read_exact_at(fd: &FileDescriptor, off: usize, buff: &mut [u8]) -> Result<(), ()> {
+    let mut read = 0;
+    while read < buff.len() {
+        match fd.read_at(off + read, &mut buff[read..]) {
+            Ok(0) | Err(_) => return Err(()),
+            Ok(n) => read += n,
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads from __off__ until __buff__ is full or the end of the file is reached
+fn read_at_most(fd: &FileDescriptor, off: usize, buff: &mut [u8]) -> Result<(), ()> {
+    let mut read = 0;
+    while read < buff.len() {
+        match fd.read_at(off + read, &mut buff[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(_) => return Err(()),
+        }
+    }
+
+    Ok(())
+}
+
 pub fn load_base_process(exec_path: &str) {
     let main_thread_id: ThreadID;
 
@@ -626,7 +1327,8 @@ pub fn load_base_process(exec_path: &str) {
         let argv = [<&str>::clone(&exec_path)];
         let envp = ["HOME=/root"];
 
-        proc.load_from_file(exec_path, &argv[..], &envp[..])
+        let executable = Executable::open(exec_path).expect("Failed to open base process");
+        proc.load_from_file(executable, &argv[..], &envp[..])
             .expect("Failed to load base process");
     }
 
@@ -634,8 +1336,349 @@ pub fn load_base_process(exec_path: &str) {
     enable_interrupts();
 }
 
+/// Turns the process into a zombie with __status__ as its wait status and wakes up
+/// its parent if it is waiting
+pub fn mark_exited(proc: &Arc<Mutex<Process>>, status: i32) {
+    {
+        let mut p = proc.lock();
+        p.exit_status = Some(status);
+        p.vfork_done = true;
+    }
+
+    notify_child_event();
+}
+
+/// Wakes up the parent if it cloned the process with CLONE_VFORK, this has to be called
+/// once the process execs
+pub fn mark_execed(proc: &Arc<Mutex<Process>>) {
+    proc.lock().vfork_done = true;
+    notify_child_event();
+}
+
+/// Makes init the parent of the children of __pid__ so they can still be waited for
+pub fn reparent_children(pid: usize) {
+    let _guard = CHILD_EVENT_LOCK.lock();
+    for child_lock in processes() {
+        let mut child = child_lock.lock();
+        if child.ppid == pid {
+            child.ppid = 1;
+        }
+    }
+}
+
+/// Sends __signal__ to every live process in the process group __pgid__. Signals can not be
+/// handled or blocked yet so the ones that terminate a process by default make it exit once
+/// it returns from the kernel, stop signals are ignored because processes can not be stopped.
+/// init ignores every signal.
+pub fn signal_process_group(pgid: usize, signal: i32) {
+    // TODO: stopping processes
+    if signal == SIGTSTP {
+        return;
+    }
+
+    let pids: Vec<usize> = processes()
+        .iter()
+        .filter_map(|proc_lock| {
+            let p = proc_lock.lock();
+            let alive = p.exit_status.is_none();
+            (p.pgid == pgid && p.pid != 1 && alive).then_some(p.pid)
+        })
+        .collect();
+
+    {
+        let mut pending = PENDING_SIGNALS.lock();
+        for pid in pids {
+            pending.entry(pid).or_insert(signal);
+        }
+    }
+
+    // interruptible sleeps return so the processes can be terminated
+    SIGNAL_POLL_QUEUE.wake_all();
+}
+
+/// Makes every other thread of __proc__ exit and waits until they did. The ones in userspace
+/// are removed right away, the ones in the kernel exit once they return from it, interruptible
+/// sleeps return early because the process has SIGKILL pending. Returns false without
+/// waiting if another thread of the process is doing the same, the calling thread has to
+/// exit then.
+pub fn kill_other_threads(proc: &Arc<Mutex<Process>>) -> bool {
+    let current = SCHEDULER.get_current_thread().expect("No threads running");
+    let (pid, threads) = {
+        let mut p = proc.lock();
+        if p.group_exit {
+            return false;
+        }
+
+        // no thread is created once this is set
+        p.group_exit = true;
+        if p.live_threads == 1 {
+            return true;
+        }
+        (p.pid, p.threads.clone())
+    };
+
+    PENDING_SIGNALS.lock().entry(pid).or_insert(SIGKILL);
+    SIGNAL_POLL_QUEUE.wake_all();
+
+    loop {
+        let removed = threads
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|thread| !Arc::ptr_eq(thread, &current))
+            .filter(|thread| SCHEDULER.remove_user_thread(thread))
+            .count();
+
+        {
+            let mut p = proc.lock();
+            p.live_threads -= removed;
+            if p.live_threads == 1 {
+                return true;
+            }
+        }
+
+        SCHEDULER.yield_current_thread();
+    }
+}
+
+/// Lets the process create threads again after `kill_other_threads`, the calling thread is
+/// the only thread of the process and it does not exit
+pub fn end_group_exit(proc: &Arc<Mutex<Process>>) {
+    let pid = {
+        let mut p = proc.lock();
+        p.group_exit = false;
+        p.pid
+    };
+
+    let mut pending = PENDING_SIGNALS.lock();
+    if pending.get(&pid) == Some(&SIGKILL) {
+        pending.remove(&pid);
+    }
+}
+
+/// Returns the PID of the process the current thread belongs to
+pub fn current_pid() -> Option<usize> {
+    let thread_lock = SCHEDULER.get_current_thread()?;
+    let thread = thread_lock.lock();
+    match &thread.inner {
+        ThreadInner::User(data) => Some(data.pid),
+        _ => None,
+    }
+}
+
+/// Returns the signal the process __pid__ has to be terminated with, if any. It does not lock
+/// the process so it can be called with any lock held.
+pub fn pending_signal(pid: usize) -> Option<i32> {
+    PENDING_SIGNALS.lock().get(&pid).copied()
+}
+
+/// Makes __waiter__ wake up whenever a signal is sent, a thread sleeping interruptibly checks
+/// `pending_signal` after waking up
+pub fn register_signal_waiter(waiter: &Arc<Waiter>) {
+    SIGNAL_POLL_QUEUE.register(waiter);
+}
+
+fn notify_child_event() {
+    let _guard = CHILD_EVENT_LOCK.lock();
+    CHILD_EVENT_QUEUE.wake_all();
+}
+
+/// Sleeps until __child__, which was cloned with CLONE_VFORK, execs or exits
+pub fn wait_for_vfork_child(child: &Arc<Mutex<Process>>) {
+    loop {
+        let guard = CHILD_EVENT_LOCK.lock();
+        if child.lock().vfork_done {
+            return;
+        }
+
+        CHILD_EVENT_QUEUE.sleep(guard);
+    }
+}
+
+/// Waits until a child of __parent_pid__ that matches __filter__ exits, then frees its slot
+/// and returns its PID and wait status. Returns None instead of waiting if __nohang__ is set
+/// and Err if there is no matching child.
+pub fn wait_for_child(
+    parent_pid: usize,
+    filter: impl Fn(&Process) -> bool,
+    nohang: bool,
+) -> Result<Option<(usize, i32)>, ()> {
+    loop {
+        let guard = CHILD_EVENT_LOCK.lock();
+
+        let mut has_children = false;
+        // the process has exited but its threads have not been switched away from yet
+        let mut exiting = false;
+        let mut zombie = None;
+        for child_lock in processes() {
+            let child = child_lock.lock();
+            if child.ppid != parent_pid || !filter(&child) {
+                continue;
+            }
+
+            has_children = true;
+            if let Some(status) = child.exit_status {
+                if child.threads_dead() {
+                    zombie = Some((child.pid, status));
+                    break;
+                }
+                exiting = true;
+            }
+        }
+
+        if let Some((pid, status)) = zombie {
+            drop(guard);
+            reap(pid);
+            return Ok(Some((pid, status)));
+        }
+
+        if !has_children {
+            return Err(());
+        }
+
+        if exiting {
+            drop(guard);
+            SCHEDULER.yield_current_thread();
+        } else if nohang {
+            return Ok(None);
+        } else {
+            CHILD_EVENT_QUEUE.sleep(guard);
+        }
+    }
+}
+
+/// Frees the slot of a zombie, its resources are freed once the last reference is dropped
+fn reap(pid: usize) {
+    let proc = {
+        let mut processes = PROCESSES.lock();
+        let proc = processes.get(pid - 1).cloned();
+        processes.deallocate(pid - 1);
+        proc
+    };
+    // a signal sent while the process was exiting must not hit the next one with its PID
+    PENDING_SIGNALS.lock().remove(&pid);
+
+    // the address space is torn down outside the lock
+    drop(proc);
+}
+
+/// Returns every process that exists at the moment
+pub fn processes() -> Vec<Arc<Mutex<Process>>> {
+    let processes = PROCESSES.lock();
+    processes.iter().cloned().collect()
+}
+
 pub fn get_process(pid: usize) -> Option<Arc<Mutex<Process>>> {
     let processes = PROCESSES.lock();
-    let proc = processes.get(pid - 1);
+    let proc = processes.get(pid.checked_sub(1)?);
     proc.map(Arc::clone)
 }
+
+/// Logs every process for the panic handler, the locks are broken into because the other
+/// CPUs are halted and nothing would release them
+pub fn dump_processes() {
+    let (processes, held) = unsafe { sync::force_lock(&PROCESSES) };
+    error!("processes:{}", if held { " (table was locked)" } else { "" });
+    error!("  PID  PPID STATE NAME");
+    for proc_lock in processes.iter() {
+        let (proc, held) = unsafe { proc_lock.force_lock() };
+        let state = match proc.main_thread.upgrade() {
+            _ if proc.exit_status.is_some() => 'Z',
+            Some(thread) => match unsafe { thread.force_lock() }.0.state {
+                ThreadState::Running => 'R',
+                ThreadState::None | ThreadState::Busy => 'S',
+                ThreadState::Dead => 'Z',
+            },
+            None => 'Z',
+        };
+        let name = proc.cmdline.first().map(String::as_str).unwrap_or("");
+
+        error!(
+            "{:>5} {:>5} {:>5} {}{}",
+            proc.pid,
+            proc.ppid,
+            state,
+            name,
+            if held { " (locked)" } else { "" }
+        );
+    }
+}
+
+/// Generates the contents of /proc/<pid>/maps
+pub fn proc_maps(proc: &Process) -> String {
+    let mut s = String::new();
+    for region in proc.mapped_regions() {
+        let flags = region.flags();
+        let accessible = !flags.contains(MappedRegionFlags::NO_ACCESS);
+        let perm = |allowed: bool, c: char| if accessible && allowed { c } else { '-' };
+        let (sharing, offset, path) = match region.backing() {
+            RegionBacking::Anonymous => ('p', 0, ""),
+            RegionBacking::File { path, offset } => ('p', *offset, path.as_str()),
+            RegionBacking::Shared => ('s', 0, ""),
+            RegionBacking::Device { path, offset, .. } => ('s', *offset, path.as_str()),
+        };
+        s.push_str(&format!(
+            "{:016x}-{:016x} {}{}{}{} {:08x} 00:00 0 {}\n",
+            region.start(),
+            region.end(),
+            perm(true, 'r'),
+            perm(flags.contains(MappedRegionFlags::READ_WRITE), 'w'),
+            perm(flags.contains(MappedRegionFlags::EXECUTE), 'x'),
+            sharing,
+            offset,
+            path,
+        ));
+    }
+    s
+}
+
+/// Generates the contents of /proc/<pid>/status
+pub fn proc_status(proc: &Process) -> String {
+    let name = proc
+        .cmdline
+        .first()
+        .map(|path| path.rsplit('/').next().unwrap())
+        .unwrap_or("");
+
+    let state = match proc.main_thread.upgrade() {
+        _ if proc.exit_status.is_some() => "Z (zombie)",
+        Some(thread) => match thread.lock().state {
+            ThreadState::Running => "R (running)",
+            ThreadState::None | ThreadState::Busy => "S (sleeping)",
+            ThreadState::Dead => "Z (zombie)",
+        },
+        None => "Z (zombie)",
+    };
+
+    let vm_size: usize = proc
+        .mapped_regions
+        .iter()
+        .map(|region| region.pages * PAGE_SIZE_4KIB as usize)
+        .sum();
+
+    format!(
+        "Name:\t{}\nState:\t{}\nPid:\t{}\nPPid:\t{}\nPGid:\t{}\nUid:\t{}\t{}\n\
+         Gid:\t{}\t{}\nFDSize:\t{}\nVmSize:\t{} kB\nThreads:\t{}\n",
+        name,
+        state,
+        proc.pid,
+        proc.ppid,
+        proc.pgid,
+        proc.uid,
+        proc.euid,
+        proc.gid,
+        proc.egid,
+        proc.file_descriptors.allocated_slots(),
+        vm_size / 1024,
+        proc.live_threads,
+    )
+}
+
+/// Generates the contents of /proc/<pid>/cmdline, the arguments are terminated by NUL
+pub fn proc_cmdline(proc: &Process) -> String {
+    let mut s = String::new();
+    for arg in proc.cmdline.iter() {
+        s.push_str(arg);
+        s.push('\0');
+    }
+    s
+}
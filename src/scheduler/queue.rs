@@ -15,24 +15,41 @@ impl SchedulerThreadQueue {
         self.queue.pop_front()
     }
 
+    /// Removes the thread that was queued last, it is the one other CPUs steal
+    pub fn pop_back(&mut self) -> Option<ThreadID> {
+        self.queue.pop_back()
+    }
+
     pub fn add_thread(&mut self, tid: ThreadID) {
         self.queue.push_back(tid);
     }
 
+    /// Queues the thread at the front so it runs on the next switch, the current thread of the
+    /// CPU is not in the queue
+    pub fn add_thread_next(&mut self, tid: ThreadID) {
+        self.queue.push_front(tid);
+    }
+
+    /// Removes the thread if it is queued
     pub fn remove_thread(&mut self, tid: ThreadID) {
-        let idx = self
-            .queue
-            .iter()
-            .position(|thread_id| *thread_id == tid)
-            .unwrap();
+        if let Some(idx) = self.queue.iter().position(|thread_id| *thread_id == tid) {
+            self.queue.remove(idx);
+        }
+    }
 
-        self.queue.remove(idx);
+    /// Iterates over the queued threads, the next one to run first
+    pub fn iter(&self) -> impl Iterator<Item = &ThreadID> {
+        self.queue.iter()
     }
 
     pub fn is_empty(&self) -> bool {
         self.queue.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
     pub const fn new() -> Self {
         SchedulerThreadQueue {
             queue: VecDeque::new(),
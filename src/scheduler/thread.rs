@@ -1,14 +1,18 @@
 use alloc::{boxed::Box, sync::Arc, sync::Weak, vec::Vec};
-use spin::Mutex;
 
 use crate::{
-    arch::x86_64::{interrupts_enabled, paging::PageFlags, registers::RegisterState},
+    arch::x86_64::{
+        interrupts_enabled, paging::PageFlags, registers::RegisterState, tss::IoBitmap,
+    },
+    limits::THREAD_MAX,
     mm::{
+        kalloc::HeapTag,
         phys::FRAME_SIZE,
-        virt::{KERNEL_THREAD_STACKS_START, PML4},
+        virt::{KERNEL_THREAD_STACKS_START, PML4, USER_THREAD_KERNEL_STACKS_START},
         VirtAddr,
     },
-    scheduler::remove_current_thread_wrapper,
+    scheduler::{remove_current_thread_wrapper, tls::ThreadLocalStorage},
+    sync::Mutex,
 };
 
 #[repr(transparent)]
@@ -20,11 +24,17 @@ pub enum ThreadState {
     None,
     Running,
     Busy,
+    /// The thread has exited but its resources have not been freed yet
+    Dead,
 }
 
 #[derive(Debug, Clone)]
 pub struct KernelThreadData {
     pub regs: Box<RegisterState>,
+    pub stack_bottom: u64,
+    /// Kernel threads only use the kernel half so they run in the address space of the
+    /// kernel, the address spaces of processes can be freed once no CPU runs their threads
+    pub pml4: PML4,
 }
 
 // FIXME: do not derive Clone because it won't allocate a new TLS
@@ -35,6 +45,19 @@ pub struct UserThreadData {
     pub user_regs: Box<RegisterState>,
     pub in_kernelspace: bool,
     pub tls: VirtAddr,
+    /// Bottom of the kernel stack the thread runs on while it is in kernelspace,
+    /// it is loaded into TSS.rsp0 and switched to on syscall entry
+    pub kernel_stack_bottom: u64,
+    /// I/O ports the thread can access from userspace, None denies every port
+    pub io_bitmap: Option<Box<IoBitmap>>,
+    /// Address space of the process, it is loaded when the thread is switched to
+    pub pml4: PML4,
+    /// The thread was created with CLONE_CHILD_CLEARTID, the TID at this address is cleared
+    /// and its futex is woken when the thread exits
+    pub clear_child_tid: u64,
+    /// The process is exiting, the thread is not scheduled again once it is switched away
+    /// from in userspace
+    pub exit_pending: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -47,54 +70,134 @@ pub enum ThreadInner {
 pub struct Thread {
     pub id: ThreadID,
     pub state: ThreadState,
-    pub stack_bottom: u64,
+    /// The CPU whose run queue the thread is on or the CPU it ran on last
+    pub cpu: usize,
     pub inner: ThreadInner,
+    pub tls: ThreadLocalStorage,
+    /// The tag the allocations of the thread are accounted to, saved when the thread is
+    /// switched away from
+    pub heap_tag: HeapTag,
+}
+
+impl Thread {
+    fn kernel_stack_kind(&self) -> KernelStackKind {
+        match self.inner {
+            ThreadInner::Kernel(_) => KernelStackKind::KernelThread,
+            ThreadInner::User(_) => KernelStackKind::UserThread,
+        }
+    }
+
+    /// Returns the usable range of the kernel stack of the thread, the guard page is not
+    /// included
+    pub fn kernel_stack_range(&self) -> (VirtAddr, VirtAddr) {
+        SchedulerThreadData::kernel_stack_range(self.id, self.kernel_stack_kind())
+    }
 }
 
 pub struct SchedulerThreadData {
     threads: Vec<Option<Arc<Mutex<Thread>>>>,
-    // TODO: try to fill the queue without exposing running_threads as public
-    pub running_threads: Vec<ThreadID>,
+    running_threads: Vec<ThreadID>,
     busy_threads: Vec<ThreadID>,
+    // threads that have exited but might still be running on their kernel stack
+    dead_threads: Vec<ThreadID>,
     thread_count: usize,
+    kernel_pml4: Option<PML4>,
 }
 
 // we leave the lowest page of each thread stack space unmapped so a stackoverflow triggers a pagefault
 const KERNEL_FULL_STACK_SIZE_PER_THREAD: u64 = 8 * 4096; // 32KiB
-const KERNEL_STACK_SIZE_PER_THREAD: u64 = KERNEL_FULL_STACK_SIZE_PER_THREAD - 4096; // 28 KiB
 
-const MAX_THREADS: usize = 64;
+// user threads get a bigger kernel stack because syscalls can go through
+// the VFS, the block layer and a driver before returning
+const USER_KERNEL_FULL_STACK_SIZE_PER_THREAD: u64 = 16 * 4096; // 64KiB
+
+/// Kernel threads and user threads have their kernel stacks in separate regions
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KernelStackKind {
+    KernelThread,
+    UserThread,
+}
+
+impl KernelStackKind {
+    const fn region_start(self) -> u64 {
+        match self {
+            KernelStackKind::KernelThread => KERNEL_THREAD_STACKS_START.get(),
+            KernelStackKind::UserThread => USER_THREAD_KERNEL_STACKS_START.get(),
+        }
+    }
+
+    /// Size of the stack space of a thread including the guard page
+    const fn full_size(self) -> u64 {
+        match self {
+            KernelStackKind::KernelThread => KERNEL_FULL_STACK_SIZE_PER_THREAD,
+            KernelStackKind::UserThread => USER_KERNEL_FULL_STACK_SIZE_PER_THREAD,
+        }
+    }
+}
 
 impl SchedulerThreadData {
-    fn get_kernel_stack(tid: ThreadID) -> u64 {
-        // FIXME: increase limit
-        assert!(tid.0 < MAX_THREADS);
-        KERNEL_THREAD_STACKS_START.get() + tid.0 as u64 * KERNEL_FULL_STACK_SIZE_PER_THREAD
+    fn get_kernel_stack(tid: ThreadID, kind: KernelStackKind) -> u64 {
+        assert!(tid.0 < THREAD_MAX);
+        kind.region_start() + tid.0 as u64 * kind.full_size()
+    }
+
+    /// Returns the address the stack of the thread grows down from
+    fn get_kernel_stack_bottom(tid: ThreadID, kind: KernelStackKind) -> u64 {
+        Self::get_kernel_stack(tid, kind) + kind.full_size()
+    }
+
+    /// Returns the usable range of the stack of the thread, the guard page is not included
+    fn kernel_stack_range(tid: ThreadID, kind: KernelStackKind) -> (VirtAddr, VirtAddr) {
+        let thread_stack_bottom = VirtAddr::new(Self::get_kernel_stack(tid, kind));
+
+        // leave first page unmapped so a stack overflow causes a pagefault
+        let virt_start = thread_stack_bottom + VirtAddr::new(FRAME_SIZE as u64);
+        let virt_end = VirtAddr::new(Self::get_kernel_stack_bottom(tid, kind));
+        (virt_start, virt_end)
+    }
+
+    fn map_kernel_stack(&self, tid: ThreadID, kind: KernelStackKind) {
+        let pml4 = self
+            .kernel_pml4
+            .as_ref()
+            .expect("Scheduler is not initialized");
+        let (virt_start, virt_end) = Self::kernel_stack_range(tid, kind);
+        let flags = PageFlags::READ_WRITE | PageFlags::PRESENT;
+        pml4.map_range(virt_start, virt_end, flags);
+    }
+
+    fn unmap_kernel_stack(&self, tid: ThreadID, kind: KernelStackKind) {
+        let pml4 = self
+            .kernel_pml4
+            .as_ref()
+            .expect("Scheduler is not initialized");
+        let (virt_start, virt_end) = Self::kernel_stack_range(tid, kind);
+        pml4.unmap_range(virt_start, virt_end);
     }
 
     pub fn init(&mut self, pml4: &PML4) {
         assert!(!interrupts_enabled());
 
-        // TODO: allocate stacks on demand
-        for tid in 0..MAX_THREADS {
-            // skip the first one
-            let thread_stack_bottom = VirtAddr::new(Self::get_kernel_stack(ThreadID(tid)));
-            let in_pages = KERNEL_STACK_SIZE_PER_THREAD / FRAME_SIZE as u64;
-
-            // leave first page unmapped so a stack overflow causes a pagefault
-            let virt_start = thread_stack_bottom + VirtAddr::new(FRAME_SIZE as u64);
-            let virt_end = virt_start + VirtAddr::new(in_pages * FRAME_SIZE as u64);
-            let flags = PageFlags::READ_WRITE | PageFlags::PRESENT;
-            pml4.map_range(virt_start, virt_end, flags);
-        }
+        // the stack of a thread is mapped when the thread is created and unmapped
+        // when the thread is reaped, every address space shares the kernel stacks PML4 entry
+        // so the sentinel thread's stack has to be mapped before any process is created
+        self.kernel_pml4 = Some(pml4.clone());
 
         self.threads.resize(16, None);
     }
 
+    /// Returns whether the thread limit allows creating another thread
+    pub fn can_create_thread(&self) -> bool {
+        self.thread_count < THREAD_MAX
+    }
+
     fn alloc_tid(&mut self) -> ThreadID {
-        let tid = if self.thread_count == self.threads.capacity() {
-            let old_size = self.threads.capacity() * 2;
-            self.threads.resize(old_size * 2, None);
+        assert!(self.can_create_thread(), "Thread limit reached");
+
+        let tid = if self.thread_count == self.threads.len() {
+            let old_size = self.threads.len();
+            self.threads
+                .resize(usize::min(old_size * 2, THREAD_MAX), None);
             old_size
         } else {
             self.threads.iter().position(Option::is_none).unwrap()
@@ -107,13 +210,23 @@ impl SchedulerThreadData {
 
     pub fn new_kernel_thread(&mut self) -> Thread {
         let tid = self.alloc_tid();
+        let kind = KernelStackKind::KernelThread;
+        self.map_kernel_stack(tid, kind);
+        let pml4 = self
+            .kernel_pml4
+            .clone()
+            .expect("Scheduler is not initialized");
         Thread {
             id: tid,
             state: ThreadState::None,
+            cpu: 0,
+            tls: ThreadLocalStorage::default(),
+            heap_tag: HeapTag::Other,
             inner: ThreadInner::Kernel(KernelThreadData {
                 regs: Box::new(RegisterState::new_kernel()),
+                stack_bottom: Self::get_kernel_stack_bottom(tid, kind),
+                pml4,
             }),
-            stack_bottom: Self::get_kernel_stack(tid) + KERNEL_FULL_STACK_SIZE_PER_THREAD,
         }
     }
 
@@ -127,7 +240,7 @@ impl SchedulerThreadData {
             if let ThreadInner::Kernel(data) = &mut thread.inner {
                 // push the address of remove_running_thread on the stack so the thread
                 // will return to that address and get killed
-                data.regs.rsp = thread.stack_bottom;
+                data.regs.rsp = data.stack_bottom;
                 data.regs.rsp -= core::mem::size_of::<u64>() as u64;
                 unsafe {
                     *(data.regs.rsp as *mut u64) = remove_current_thread_wrapper as usize as u64;
@@ -149,26 +262,35 @@ impl SchedulerThreadData {
         weak
     }
 
-    pub fn new_user_thread(&mut self, pid: usize) -> Thread {
+    pub fn new_user_thread(&mut self, pid: usize, pml4: &PML4) -> Thread {
         let tid = self.alloc_tid();
+        let kind = KernelStackKind::UserThread;
+        self.map_kernel_stack(tid, kind);
         Thread {
             id: tid,
             state: ThreadState::None,
-            stack_bottom: Self::get_kernel_stack(tid),
+            cpu: 0,
+            tls: ThreadLocalStorage::default(),
+            heap_tag: HeapTag::Other,
             inner: ThreadInner::User(UserThreadData {
                 pid,
                 kernel_regs: Box::new(RegisterState::new_kernel()),
                 user_regs: Box::new(RegisterState::new_user()),
                 in_kernelspace: false,
                 tls: VirtAddr::new(0),
+                kernel_stack_bottom: Self::get_kernel_stack_bottom(tid, kind),
+                io_bitmap: None,
+                pml4: pml4.clone(),
+                clear_child_tid: 0,
+                exit_pending: false,
             }),
         }
     }
 
-    pub fn create_user_thread(&mut self, pid: usize) -> Weak<Mutex<Thread>> {
+    pub fn create_user_thread(&mut self, pid: usize, pml4: &PML4) -> Weak<Mutex<Thread>> {
         let tid: ThreadID;
         let thread = Arc::new(Mutex::new({
-            let thread = self.new_user_thread(pid);
+            let thread = self.new_user_thread(pid, pml4);
             tid = thread.id;
             thread
         }));
@@ -180,8 +302,15 @@ impl SchedulerThreadData {
         weak
     }
 
-    pub fn copy_user_thread(&mut self, pid: usize, tid: ThreadID) -> Weak<Mutex<Thread>> {
+    pub fn copy_user_thread(
+        &mut self,
+        pid: usize,
+        tid: ThreadID,
+        pml4: &PML4,
+    ) -> Weak<Mutex<Thread>> {
         let new_tid = self.alloc_tid();
+        let kind = KernelStackKind::UserThread;
+        self.map_kernel_stack(new_tid, kind);
 
         let new_thread = Arc::new(Mutex::new({
             let old_thread = self.threads[tid.0].as_ref().expect("Invalid TID");
@@ -193,6 +322,10 @@ impl SchedulerThreadData {
 
             if let ThreadInner::User(data) = &mut thread.inner {
                 data.pid = pid;
+                data.kernel_stack_bottom = Self::get_kernel_stack_bottom(new_tid, kind);
+                data.pml4 = pml4.clone();
+                data.clear_child_tid = 0;
+                data.exit_pending = false;
             } else {
                 unreachable!()
             }
@@ -232,13 +365,37 @@ impl SchedulerThreadData {
         self.busy_threads.remove(idx);
     }
 
+    /// Returns whether the thread is ready to run, the state of the thread is tracked here
+    /// so the scheduler does not have to lock the thread
+    pub fn is_running(&self, tid: ThreadID) -> bool {
+        self.running_threads.contains(&tid)
+    }
+
     pub fn get_thread(&self, tid: ThreadID) -> Option<Arc<Mutex<Thread>>> {
         self.threads[tid.0].as_ref().cloned()
     }
 
-    pub fn remove_thread(&mut self, tid: ThreadID) {
+    /// Returns whether the thread belongs to an exiting process and is in userspace, it must
+    /// not run again
+    pub fn is_exit_pending(&self, tid: ThreadID) -> bool {
         let thread = self.get_thread(tid).expect("Invalid TID");
         let thread = thread.lock();
+        match &thread.inner {
+            ThreadInner::User(data) => data.exit_pending && !data.in_kernelspace,
+            ThreadInner::Kernel(_) => false,
+        }
+    }
+
+    /// Returns every thread that has not been freed yet, including the dead ones
+    pub fn threads(&self) -> impl Iterator<Item = &Arc<Mutex<Thread>>> {
+        self.threads.iter().flatten()
+    }
+
+    /// Marks the thread as dead, its resources are freed by reap_dead_threads once
+    /// it is guaranteed that the thread is not running anymore
+    pub fn remove_thread(&mut self, tid: ThreadID) {
+        let thread = self.get_thread(tid).expect("Invalid TID");
+        let mut thread = thread.lock();
 
         match thread.state {
             ThreadState::Busy => self.remove_from_busy_threads(tid),
@@ -246,8 +403,46 @@ impl SchedulerThreadData {
             _ => unreachable!(),
         };
 
-        self.threads[tid.0] = None;
-        self.thread_count -= 1;
+        if let ThreadInner::User(data) = &mut thread.inner {
+            data.tls = VirtAddr::zero();
+        }
+
+        thread.state = ThreadState::Dead;
+        self.dead_threads.push(tid);
+    }
+
+    /// Frees the kernel stacks and thread IDs of the dead threads, threads for which
+    /// __in_use__ returns true are still running on their stack and are kept for later.
+    /// The threads are returned so they can be dropped after the scheduler locks are released.
+    pub fn reap_dead_threads(
+        &mut self,
+        in_use: impl Fn(ThreadID) -> bool,
+    ) -> Vec<Arc<Mutex<Thread>>> {
+        let (busy, dead_threads): (Vec<_>, Vec<_>) = core::mem::take(&mut self.dead_threads)
+            .into_iter()
+            .partition(|&tid| in_use(tid));
+        self.dead_threads = busy;
+        let mut reaped = Vec::with_capacity(dead_threads.len());
+
+        for tid in dead_threads {
+            let thread = self.threads[tid.0].take().expect("Invalid TID");
+            let kind = thread.lock().kernel_stack_kind();
+            self.unmap_kernel_stack(tid, kind);
+
+            self.thread_count -= 1;
+
+            if cfg!(sched_debug) {
+                log!("SCHED: reaped thread {:#x}", tid.0);
+            }
+
+            reaped.push(thread);
+        }
+
+        reaped
+    }
+
+    pub fn has_dead_threads(&self) -> bool {
+        !self.dead_threads.is_empty()
     }
 
     pub fn change_thread_state(&mut self, tid: ThreadID, new_state: ThreadState) {
@@ -284,7 +479,9 @@ impl SchedulerThreadData {
             threads: Vec::new(),
             running_threads: Vec::new(),
             busy_threads: Vec::new(),
+            dead_threads: Vec::new(),
             thread_count: 0,
+            kernel_pml4: None,
         }
     }
 }
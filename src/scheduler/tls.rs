@@ -0,0 +1,85 @@
+//! Thread-local storage of the kernel. A subsystem declares a `ThreadLocal<T>` static which
+//! gets one of the slots of every thread the first time it is used, each thread stores its
+//! own value in the slot. The values are reference counted so they can be used without
+//! keeping the thread locked. Nothing here can be used in interrupt handlers because the
+//! values are allocated and freed on the heap.
+
+use alloc::sync::Arc;
+use core::{
+    any::Any,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::Once;
+
+use super::SCHEDULER;
+
+/// Number of slots every thread has
+pub const TLS_SLOTS: usize = 8;
+
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+type TlsValue = Arc<dyn Any + Send + Sync>;
+
+/// The slots of a thread, they are freed with the thread
+#[derive(Clone, Default)]
+pub struct ThreadLocalStorage {
+    slots: [Option<TlsValue>; TLS_SLOTS],
+}
+
+impl core::fmt::Debug for ThreadLocalStorage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let used = self.slots.iter().filter(|slot| slot.is_some()).count();
+        write!(f, "ThreadLocalStorage {{ used: {} }}", used)
+    }
+}
+
+pub struct ThreadLocal<T> {
+    name: &'static str,
+    slot: Once<usize>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Any + Send + Sync> ThreadLocal<T> {
+    pub const fn new(name: &'static str) -> ThreadLocal<T> {
+        ThreadLocal {
+            name,
+            slot: Once::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    fn slot(&self) -> usize {
+        *self.slot.call_once(|| {
+            let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+            assert!(
+                slot < TLS_SLOTS,
+                "no thread-local storage slot left for {}",
+                self.name
+            );
+            slot
+        })
+    }
+
+    /// Returns the value of the current thread, None if it was not set or no thread is
+    /// running yet
+    pub fn get(&self) -> Option<Arc<T>> {
+        let slot = self.slot();
+        let thread_lock = SCHEDULER.get_current_thread()?;
+        let value = thread_lock.lock().tls.slots[slot].clone()?;
+        // only this static stores values of type T in its slot
+        Some(value.downcast::<T>().unwrap())
+    }
+
+    /// Sets the value of the current thread and returns the previous one, it is dropped by
+    /// the caller with the thread unlocked
+    pub fn set(&self, value: Option<T>) -> Option<Arc<T>> {
+        let slot = self.slot();
+        let value = value.map(|value| Arc::new(value) as TlsValue);
+        let thread_lock = SCHEDULER
+            .get_current_thread()
+            .expect("thread-local storage used before the scheduler started");
+        let prev = core::mem::replace(&mut thread_lock.lock().tls.slots[slot], value);
+        prev.map(|value| value.downcast::<T>().unwrap())
+    }
+}
@@ -0,0 +1,184 @@
+//! Blocking the current thread until an event or a deadline. Every way of waiting in the
+//! kernel goes through `Waiter::block_until`: a waiter is woken by any number of event
+//! sources and by its timeout, the first wake decides why the thread returns and the later
+//! ones can not leak into the next wait.
+
+use alloc::collections::VecDeque;
+
+use crate::{sync::InterruptMutex, time};
+
+use super::{thread::ThreadID, SCHEDULER};
+
+/// Why a blocked thread was resumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeReason {
+    Woken,
+    TimedOut,
+}
+
+/// Threads waiting for a condition, e.g. data becoming available in a pipe
+pub struct WaitQueue {
+    // addresses of the waiters of the sleeping threads, they are on the stacks of the
+    // threads which remove them before returning
+    waiters: InterruptMutex<VecDeque<usize>>,
+}
+
+impl WaitQueue {
+    /// Puts the current thread to sleep until the queue is woken up. __guard__ is the lock
+    /// protecting the condition the thread waits for, it is released once the thread is on
+    /// the queue so a wakeup between checking the condition and sleeping is not lost.
+    /// The caller has to check the condition again after waking up.
+    pub fn sleep<G>(&self, guard: G) {
+        self.sleep_until(guard, None);
+    }
+
+    /// Same as `sleep` but the thread is also woken once the system clock reaches
+    /// __deadline__ milliseconds. If the queue wakes the thread at the same time as the
+    /// deadline passes it counts as woken, so the wakeup is not lost for the other threads.
+    pub fn sleep_until<G>(&self, guard: G, deadline: Option<u64>) -> WakeReason {
+        let waiter = Waiter::new();
+        let addr = &waiter as *const Waiter as usize;
+        self.waiters.lock().push_back(addr);
+
+        // the waiter remembers a wakeup that arrives before the thread blocks
+        drop(guard);
+        let reason = waiter.block_until(deadline);
+
+        let mut waiters = self.waiters.lock();
+        match waiters.iter().position(|&entry| entry == addr) {
+            Some(pos) => {
+                waiters.remove(pos);
+                reason
+            }
+            // it was taken off the queue so it was woken by the queue
+            None => WakeReason::Woken,
+        }
+    }
+
+    /// Wakes up the thread that has been waiting the longest, returns whether there was one
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters.pop_front() {
+            Some(addr) => {
+                // the sleeping thread removes its waiter with the queue locked so it is
+                // still alive
+                unsafe { &*(addr as *const Waiter) }.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn wake_all(&self) {
+        let mut waiters = self.waiters.lock();
+        for addr in waiters.drain(..) {
+            unsafe { &*(addr as *const Waiter) }.wake();
+        }
+    }
+
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: InterruptMutex::new(VecDeque::new()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum WaiterState {
+    Idle,
+    Blocked(ThreadID),
+    Woken(WakeReason),
+}
+
+struct WaiterInner {
+    state: WaiterState,
+    /// Incremented every time a wait ends, a timeout armed for an earlier wait carries an
+    /// older value and is ignored
+    sequence: u64,
+}
+
+/// A thread waiting for any of several events, e.g. a file descriptor in poll becoming ready.
+/// Unlike a WaitQueue it can be woken by any number of sources but the thread is only
+/// woken up once, the wakeups that arrive before it blocks are not lost.
+pub struct Waiter {
+    inner: InterruptMutex<WaiterInner>,
+}
+
+impl Waiter {
+    /// Blocks the current thread until the waiter is woken, returns right away if it was
+    /// woken since the last call
+    pub fn block(&self) {
+        self.block_until(None);
+    }
+
+    /// Blocks the current thread until the waiter is woken or the system clock reaches
+    /// __deadline__ milliseconds, without a deadline it only returns once the waiter is woken.
+    /// Returns right away if it was woken since the last call or the deadline has passed.
+    pub fn block_until(&self, deadline: Option<u64>) -> WakeReason {
+        let sequence = self.inner.lock().sequence;
+        if let Some(deadline) = deadline {
+            time::add_waiter_timeout(self, deadline, sequence);
+        }
+
+        let reason = loop {
+            {
+                let mut inner = self.inner.lock();
+                if let WaiterState::Woken(reason) = inner.state {
+                    inner.state = WaiterState::Idle;
+                    inner.sequence += 1;
+                    break reason;
+                }
+
+                let tid = SCHEDULER.prepare_to_block();
+                inner.state = WaiterState::Blocked(tid);
+            }
+
+            // the thread is only resumed early if it is woken by something other than the
+            // waiter, then it blocks again
+            SCHEDULER.yield_current_thread();
+        };
+
+        // the timeout can not fire anymore once it is removed, the waiter can be dropped
+        if deadline.is_some() {
+            time::cancel_waiter_timeout(self);
+        }
+
+        reason
+    }
+
+    /// Wakes up the thread if it is blocked, otherwise its next block returns right away.
+    /// This can be called from an interrupt handler.
+    pub fn wake(&self) {
+        Self::wake_locked(&mut self.inner.lock(), WakeReason::Woken);
+    }
+
+    /// Called when the timeout armed for the wait __sequence__ expires, it does nothing if
+    /// that wait has already ended
+    pub fn expire(&self, sequence: u64) {
+        let mut inner = self.inner.lock();
+        if inner.sequence == sequence {
+            Self::wake_locked(&mut inner, WakeReason::TimedOut);
+        }
+    }
+
+    fn wake_locked(inner: &mut WaiterInner, reason: WakeReason) {
+        match inner.state {
+            WaiterState::Blocked(tid) => {
+                SCHEDULER.wake_thread(tid);
+                inner.state = WaiterState::Woken(reason);
+            }
+            WaiterState::Idle => inner.state = WaiterState::Woken(reason),
+            // the first wake decides the reason
+            WaiterState::Woken(_) => {}
+        }
+    }
+
+    pub const fn new() -> Waiter {
+        Waiter {
+            inner: InterruptMutex::new(WaiterInner {
+                state: WaiterState::Idle,
+                sequence: 0,
+            }),
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! Support for the stack canaries inserted by `-Z stack-protector`. The compiler stores the
+//! guard in the frame of every function with a buffer on the stack and calls
+//! `__stack_chk_fail` if it changed by the time the function returns.
+
+use crate::entropy;
+
+/// The value until the entropy pool is seeded, it is replaced early in kernel_init
+const INITIAL_GUARD: usize = 0x595e9fbd94fda766;
+
+#[no_mangle]
+#[used]
+#[allow(non_upper_case_globals)]
+static mut __stack_chk_guard: usize = INITIAL_GUARD;
+
+#[no_mangle]
+extern "C" fn __stack_chk_fail() -> ! {
+    panic!("kernel stack smashing detected, a buffer on the stack was overrun");
+}
+
+/// Replaces the guard with a random value. Functions that are on the stack while it changes
+/// would fail their check when they return, so this must be called from a function that
+/// never returns before any such function is entered.
+#[inline(never)]
+pub fn init() {
+    // the lowest byte is zero so string functions stop before copying past the guard
+    let guard = entropy::random_u64() as usize & !0xff;
+    unsafe {
+        core::ptr::write_volatile(core::ptr::addr_of_mut!(__stack_chk_guard), guard);
+    }
+}
@@ -1,9 +1,45 @@
 use core::{
     mem::ManuallyDrop,
     ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
-use crate::arch::x86_64::{disable_interrupts, enable_interrupts, interrupts_enabled};
+use crate::{
+    arch::x86_64::{disable_interrupts, enable_interrupts, interrupts_enabled, smp},
+    limits::CPU_MAX,
+    scheduler::{self, AtomicSleepCheck},
+};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_LOCKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of InterruptMutex and Mutex guards held on each CPU, only counted while the
+/// scheduler checks for sleeping in atomic context
+static HELD_LOCKS: [AtomicUsize; CPU_MAX] = [NO_LOCKS; CPU_MAX];
+
+/// Returns the number of InterruptMutex and Mutex guards held on the current CPU, it is
+/// always 0 while the atomic sleep check is off
+pub fn held_locks() -> usize {
+    HELD_LOCKS[smp::current_cpu()].load(Ordering::Relaxed)
+}
+
+/// Counts a guard that was just acquired if the check is on, returns the CPU it was counted on
+fn count_lock() -> Option<usize> {
+    if scheduler::atomic_sleep_check() == AtomicSleepCheck::Off {
+        return None;
+    }
+
+    let cpu = smp::current_cpu();
+    HELD_LOCKS[cpu].fetch_add(1, Ordering::Relaxed);
+    Some(cpu)
+}
+
+/// Releases a guard counted by `count_lock`
+fn uncount_lock(counted_cpu: Option<usize>) {
+    if let Some(cpu) = counted_cpu {
+        HELD_LOCKS[cpu].fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 pub struct InterruptMutex<T> {
     mutex: spin::Mutex<T>,
@@ -12,6 +48,8 @@ pub struct InterruptMutex<T> {
 pub struct InterruptMutexGuard<'a, T> {
     guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
     interrupts_enabled: bool,
+    /// The CPU the guard is counted on, a guard taken while the check was off is not counted
+    counted_cpu: Option<usize>,
 }
 
 impl<T> InterruptMutex<T> {
@@ -27,11 +65,82 @@ impl<T> InterruptMutex<T> {
             disable_interrupts();
         }
 
+        // the holder could be waiting for this CPU to acknowledge a TLB shootdown
+        let guard = loop {
+            match self.mutex.try_lock() {
+                Some(guard) => break guard,
+                None => {
+                    smp::handle_pending_shootdown();
+                    core::hint::spin_loop();
+                }
+            }
+        };
+
         InterruptMutexGuard {
-            guard: ManuallyDrop::new(self.mutex.lock()),
+            guard: ManuallyDrop::new(guard),
             interrupts_enabled,
+            counted_cpu: count_lock(),
+        }
+    }
+
+    /// Returns None if the mutex is already locked
+    pub fn try_lock(&self) -> Option<InterruptMutexGuard<'_, T>> {
+        let interrupts_enabled = interrupts_enabled();
+        if interrupts_enabled {
+            disable_interrupts();
+        }
+
+        match self.mutex.try_lock() {
+            Some(guard) => Some(InterruptMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_enabled,
+                counted_cpu: count_lock(),
+            }),
+            None => {
+                if interrupts_enabled {
+                    enable_interrupts();
+                }
+                None
+            }
         }
     }
+
+    /// Unlocks the mutex regardless of who holds it
+    ///
+    /// # Safety
+    /// The previous holder must never access the value again, e.g. while panicking
+    pub unsafe fn force_unlock(&self) {
+        self.mutex.force_unlock();
+    }
+
+    /// Locks the mutex even if it is held, the second value is whether it was held
+    ///
+    /// # Safety
+    /// Same as `force_unlock`, the value might be in the middle of a change
+    pub unsafe fn force_lock(&self) -> (InterruptMutexGuard<'_, T>, bool) {
+        let held = self.mutex.is_locked();
+        if held {
+            self.force_unlock();
+        }
+
+        (self.lock(), held)
+    }
+}
+
+/// Locks __mutex__ even if it is held, the second value is whether it was held. It is meant
+/// for the panic handler which looks at the state of the kernel after the other CPUs were
+/// halted so nothing would ever release the lock.
+///
+/// # Safety
+/// The previous holder must never access the value again, the value might be in the middle
+/// of a change
+pub unsafe fn force_lock<T>(mutex: &spin::Mutex<T>) -> (spin::MutexGuard<'_, T>, bool) {
+    let held = mutex.is_locked();
+    if held {
+        mutex.force_unlock();
+    }
+
+    (mutex.lock(), held)
 }
 
 impl<'a, T> Drop for InterruptMutexGuard<'a, T> {
@@ -40,6 +149,8 @@ impl<'a, T> Drop for InterruptMutexGuard<'a, T> {
             ManuallyDrop::drop(&mut self.guard);
         }
 
+        uncount_lock(self.counted_cpu);
+
         if self.interrupts_enabled {
             enable_interrupts();
         }
@@ -58,3 +169,76 @@ impl<'a, T> DerefMut for InterruptMutexGuard<'a, T> {
         self.guard.deref_mut()
     }
 }
+
+/// A spin lock that leaves the interrupts alone, like `spin::Mutex`, but its guards are counted
+/// by the atomic sleep check. It is used for the locks of processes and threads which are
+/// held around most of the code that can block.
+pub struct Mutex<T> {
+    mutex: spin::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    /// The CPU the guard is counted on, a guard taken while the check was off is not counted
+    counted_cpu: Option<usize>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(val: T) -> Mutex<T> {
+        Mutex {
+            mutex: spin::Mutex::new(val),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        MutexGuard {
+            guard: self.mutex.lock(),
+            counted_cpu: count_lock(),
+        }
+    }
+
+    /// Returns None if the mutex is already locked
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.mutex.try_lock().map(|guard| MutexGuard {
+            guard,
+            counted_cpu: count_lock(),
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.mutex.is_locked()
+    }
+
+    /// Locks the mutex even if it is held, the second value is whether it was held, see
+    /// `force_lock`
+    ///
+    /// # Safety
+    /// Same as `force_lock`
+    pub unsafe fn force_lock(&self) -> (MutexGuard<'_, T>, bool) {
+        let held = self.mutex.is_locked();
+        if held {
+            self.mutex.force_unlock();
+        }
+
+        (self.lock(), held)
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        uncount_lock(self.counted_cpu);
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.guard.deref()
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.deref_mut()
+    }
+}
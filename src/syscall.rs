@@ -1,18 +1,24 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
     arch::x86_64::{
-        self, disable_interrupts, enable_interrupts,
+        self, clac, disable_interrupts, enable_interrupts,
+        gdt::{segment_selector, GDT_USER_CODE},
         idt::{self, IDTTypeAttr},
         registers::InterruptRegisters,
         set_fs_base, set_segment_selectors,
+        usercopy::USERSPACE_END,
+        Rflags,
     },
+    fs, latency,
+    posix::{errno::ENOSYS, SIGSEGV},
     scheduler::{
-        proc::{get_process, Process},
+        proc::{self, get_process, Process},
         thread::ThreadInner,
         SCHEDULER,
     },
+    sync::Mutex,
+    syscalls,
 };
 
 type SyscallCallback = fn(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64;
@@ -28,6 +34,9 @@ impl Syscall {
     }
 }
 
+/// The syscalls are numbered by their index, entries are only ever appended. Features that
+/// only rook has belong in `ROOK_SYSCALL_TABLE`, the rook specific calls that predate it
+/// (log, archctl, fd2path and rook_info) stay here so existing binaries keep working.
 static SYSCALL_TABLE: &[Syscall] = &[
     Syscall::new("write", x86_64::syscall::io::sys_write),
     Syscall::new("read", x86_64::syscall::io::sys_read),
@@ -53,20 +62,156 @@ static SYSCALL_TABLE: &[Syscall] = &[
     Syscall::new("gettimeofday", x86_64::syscall::proc::sys_gettimeofday),
     Syscall::new("pselect", x86_64::syscall::io::sys_pselect),
     Syscall::new("fd2path", x86_64::syscall::io::sys_fd2path),
+    Syscall::new("getdents64", x86_64::syscall::io::sys_getdents64),
+    Syscall::new("rook_info", x86_64::syscall::proc::sys_rook_info),
+    Syscall::new("unlink", x86_64::syscall::io::sys_unlink),
+    Syscall::new("rmdir", x86_64::syscall::io::sys_rmdir),
+    Syscall::new("rename", x86_64::syscall::io::sys_rename),
+    Syscall::new("mkdir", x86_64::syscall::io::sys_mkdir),
+    Syscall::new("sysconf", x86_64::syscall::proc::sys_sysconf),
+    Syscall::new("ioperm", x86_64::syscall::proc::sys_ioperm),
+    Syscall::new("iopl", x86_64::syscall::proc::sys_iopl),
+    Syscall::new("munmap", x86_64::syscall::mm::sys_munmap),
+    Syscall::new("mprotect", x86_64::syscall::mm::sys_mprotect),
+    Syscall::new("pipe2", x86_64::syscall::io::sys_pipe2),
+    Syscall::new("wait4", x86_64::syscall::proc::sys_wait4),
+    Syscall::new("exit", x86_64::syscall::proc::sys_exit),
+    Syscall::new("exit_group", x86_64::syscall::proc::sys_exit_group),
+    Syscall::new("nanosleep", x86_64::syscall::proc::sys_nanosleep),
+    Syscall::new(
+        "clock_nanosleep",
+        x86_64::syscall::proc::sys_clock_nanosleep,
+    ),
+    Syscall::new("setuid", x86_64::syscall::proc::sys_setuid),
+    Syscall::new("rook", sys_rook),
+    Syscall::new("clock_gettime", x86_64::syscall::proc::sys_clock_gettime),
+    Syscall::new("readv", x86_64::syscall::io::sys_readv),
+    Syscall::new("writev", x86_64::syscall::io::sys_writev),
+    Syscall::new("pread64", x86_64::syscall::io::sys_pread64),
+    Syscall::new("pwrite64", x86_64::syscall::io::sys_pwrite64),
+    Syscall::new("dup2", x86_64::syscall::io::sys_dup2),
+    Syscall::new("dup3", x86_64::syscall::io::sys_dup3),
+    Syscall::new("poll", x86_64::syscall::io::sys_poll),
+    Syscall::new("futex", x86_64::syscall::proc::sys_futex),
 ];
 
+/// Version of the rook specific syscall ABI, it is increased whenever a call is added or the
+/// behaviour of one changes. Calls are never removed or renumbered.
+pub const ROOK_ABI_VERSION: u64 = 2;
+
+struct RookSyscall {
+    name: &'static str,
+    /// The ABI version the call first appeared in
+    since: u64,
+    callback: SyscallCallback,
+}
+
+impl RookSyscall {
+    const fn new(name: &'static str, since: u64, callback: SyscallCallback) -> RookSyscall {
+        RookSyscall {
+            name,
+            since,
+            callback,
+        }
+    }
+}
+
+/// The calls of the `rook` syscall, the number of the call is its first argument and the
+/// arguments of the call follow it. Errors are returned as negative errnos like everywhere
+/// else, a number that is not in the table returns ENOSYS.
+///
+/// | number | call     | arguments                   | returns                           |
+/// |--------|----------|-----------------------------|-----------------------------------|
+/// | 0      | version  | highest version understood  | the version both sides use        |
+/// | 1      | query    | call number                 | the version the call appeared in  |
+/// | 2      | info     | buffer, length              | length of /proc/config            |
+/// | 3      | log      | message, length             | 0                                 |
+/// | 4      | fd2path  | fd, buffer, length          | length of the path                |
+/// | 5      | archctl  | request, argument           | 0                                 |
+/// | 6      | dmesg    | buffer, length              | bytes copied, or size of the log  |
+///
+/// Userspace negotiates the version first, passing 0 returns the version of the kernel.
+/// Whether a call is available is checked with query instead of comparing versions.
+static ROOK_SYSCALL_TABLE: &[RookSyscall] = &[
+    RookSyscall::new("version", 1, rook_version),
+    RookSyscall::new("query", 1, rook_query),
+    RookSyscall::new("info", 1, x86_64::syscall::proc::sys_rook_info),
+    RookSyscall::new("log", 1, x86_64::syscall::io::sys_log),
+    RookSyscall::new("fd2path", 1, x86_64::syscall::io::sys_fd2path),
+    RookSyscall::new("archctl", 1, x86_64::syscall::proc::sys_archctl),
+    RookSyscall::new("dmesg", 2, x86_64::syscall::io::sys_dmesg),
+];
+
+fn rook_version(_proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    match args[0] {
+        0 => ROOK_ABI_VERSION,
+        requested => u64::min(requested, ROOK_ABI_VERSION),
+    }
+}
+
+fn rook_query(_proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    match ROOK_SYSCALL_TABLE.get(args[0] as usize) {
+        Some(call) => call.since,
+        None => ENOSYS.into_inner_result() as u64,
+    }
+}
+
+fn sys_rook(proc: Arc<Mutex<Process>>, args: [u64; 6]) -> u64 {
+    let call = match ROOK_SYSCALL_TABLE.get(args[0] as usize) {
+        Some(call) => call,
+        None => return ENOSYS.into_inner_result() as u64,
+    };
+
+    debug!("rook call {}", call.name);
+    (call.callback)(proc, [args[1], args[2], args[3], args[4], args[5], 0])
+}
+
+/// Returns the name of the syscall __number__
+pub fn syscall_name(number: usize) -> Option<&'static str> {
+    SYSCALL_TABLE.get(number).map(|syscall| syscall.name)
+}
+
+/// Returns whether userspace can be resumed at __rip__ with the stack __rsp__, iretq faults in
+/// the kernel if they are not canonical
+fn is_valid_return_state(rip: u64, rsp: u64) -> bool {
+    rip < USERSPACE_END && rsp < USERSPACE_END
+}
+
 #[no_mangle]
 fn handle_syscall(interrupt_regs: &mut InterruptRegisters) {
-    let syscall_no: u64;
+    // userspace can set the AC flag, with it SMAP would not stop the kernel from accessing
+    // user pages. The flag of userspace is restored by iretq.
+    clac();
+
+    // only 64-bit code may make syscalls, in compatibility mode the upper halves of the
+    // registers are undefined so the arguments could not be trusted
+    if interrupt_regs.iret.cs != segment_selector(GDT_USER_CODE, 3) {
+        warn!(
+            "syscall from code segment {:#x} rejected",
+            interrupt_regs.iret.cs
+        );
+        interrupt_regs.general.rax = ENOSYS.into_inner_result() as u64;
+        return;
+    }
+
+    // nothing has been saved yet so the registers are returned to userspace unchanged
+    let syscall = match SYSCALL_TABLE.get(interrupt_regs.general.rax as usize) {
+        Some(syscall) => syscall,
+        None => {
+            interrupt_regs.general.rax = ENOSYS.into_inner_result() as u64;
+            return;
+        }
+    };
+
     let args: [u64; 6];
 
-    let thread_lock = SCHEDULER.get_current_thread().expect("No threads running");
     let pid: usize;
     let process = {
+        // the thread is not referenced while the syscall runs because exit never returns here
+        let thread_lock = SCHEDULER.get_current_thread().expect("No threads running");
         let mut current_thread = thread_lock.lock();
 
         if let ThreadInner::User(data) = &mut current_thread.inner {
-            syscall_no = interrupt_regs.general.rax;
             args = [
                 interrupt_regs.general.rdi,
                 interrupt_regs.general.rsi,
@@ -90,20 +235,26 @@ fn handle_syscall(interrupt_regs: &mut InterruptRegisters) {
         }
     };
 
-    let syscall_table_idx = syscall_no as usize;
-    assert!(syscall_table_idx < SYSCALL_TABLE.len());
-
     enable_interrupts();
 
-    let syscall = &SYSCALL_TABLE[syscall_table_idx];
+    fs::context::enter(&process.lock());
+
     debug!("handle syscall PID: {} {} {:?}", pid, syscall.name, args);
 
+    let timer = latency::syscall_timer(interrupt_regs.general.rax as usize);
     let res = (syscall.callback)(process, args);
+    drop(timer);
     debug!("syscall return {:#x}", res);
 
+    // signals can not be handled yet, a pending one terminates the process
+    if let Some(signal) = proc::pending_signal(pid) {
+        syscalls::proc::exit::kill(get_process(pid).unwrap(), signal);
+    }
+
     disable_interrupts();
 
-    {
+    let (can_return, user_rip, user_rsp) = {
+        let thread_lock = SCHEDULER.get_current_thread().expect("No threads running");
         let mut current_thread = thread_lock.lock();
 
         if let ThreadInner::User(data) = &mut current_thread.inner {
@@ -112,13 +263,30 @@ fn handle_syscall(interrupt_regs: &mut InterruptRegisters) {
             interrupt_regs.iret.rip = data.user_regs.rip;
             interrupt_regs.iret.rsp = data.user_regs.rsp;
 
+            // the I/O privilege level might have been changed by iopl
+            let iopl = Rflags::IOPL.bits();
+            interrupt_regs.iret.rflags =
+                (interrupt_regs.iret.rflags & !iopl) | (data.user_regs.rflags & iopl);
+
             set_segment_selectors(data.user_regs.selectors.es);
             set_fs_base(data.tls);
 
-            data.in_kernelspace = false;
+            // the thread stays in the kernel if it has to be killed
+            let can_return = is_valid_return_state(data.user_regs.rip, data.user_regs.rsp);
+            data.in_kernelspace = !can_return;
+            (can_return, data.user_regs.rip, data.user_regs.rsp)
         } else {
             unreachable!()
         }
+    };
+
+    if !can_return {
+        warn!(
+            "PID {}: can not return to {:#x} with the stack at {:#x}, killing it",
+            pid, user_rip, user_rsp
+        );
+        enable_interrupts();
+        syscalls::proc::exit::kill(get_process(pid).unwrap(), SIGSEGV);
     }
 
     interrupt_regs.general.rax = res;
@@ -1,9 +1,9 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
     posix::errno::{Errno, EBADF},
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
 pub fn close(proc: Arc<Mutex<Process>>, fd: usize) -> Result<(), Errno> {
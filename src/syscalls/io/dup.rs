@@ -0,0 +1,45 @@
+use alloc::sync::Arc;
+
+use crate::{
+    posix::{
+        errno::{Errno, EBADF, EINVAL},
+        FileOpenFlags,
+    },
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+/// Makes __new_fd__ refer to the same open file as __fd__, closing what __new_fd__ referred
+/// to before
+pub fn dup2(proc: Arc<Mutex<Process>>, fd: usize, new_fd: usize) -> Result<usize, Errno> {
+    let mut p = proc.lock();
+    p.get_fd(fd).ok_or(EBADF)?;
+
+    if fd == new_fd {
+        return Ok(new_fd);
+    }
+
+    p.dup_fd_to(fd, new_fd, false).or(Err(EBADF))?;
+    Ok(new_fd)
+}
+
+/// Like dup2 but __fd__ and __new_fd__ can not be the same and O_CLOEXEC in __flags__ sets
+/// FD_CLOEXEC on __new_fd__
+pub fn dup3(
+    proc: Arc<Mutex<Process>>,
+    fd: usize,
+    new_fd: usize,
+    flags: u32,
+) -> Result<usize, Errno> {
+    let flags = FileOpenFlags::from_bits(flags).ok_or(EINVAL)?;
+    if !FileOpenFlags::O_CLOEXEC.contains(flags) || fd == new_fd {
+        return Err(EINVAL);
+    }
+
+    let mut p = proc.lock();
+    p.get_fd(fd).ok_or(EBADF)?;
+
+    let close_on_exec = flags.contains(FileOpenFlags::O_CLOEXEC);
+    p.dup_fd_to(fd, new_fd, close_on_exec).or(Err(EBADF))?;
+    Ok(new_fd)
+}
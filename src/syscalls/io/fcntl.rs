@@ -1,32 +1,37 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
+    limits,
     posix::{
-        errno::{Errno, EBADF},
-        FileOpenFlags, F_DUPFD, F_DUPFD_CLOEXEC, F_GETFD, F_GETFL, F_SETFD, F_SETFL,
+        errno::{Errno, EBADF, EINVAL, EMFILE},
+        FileOpenFlags, FD_CLOEXEC, F_DUPFD, F_DUPFD_CLOEXEC, F_GETFD, F_GETFL, F_SETFD, F_SETFL,
     },
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
+/// The flags of an open file F_SETFL can change, the rest are only set by open
+const SETFL_MASK: FileOpenFlags = FileOpenFlags::O_APPEND.union(FileOpenFlags::O_NONBLOCK);
+
 pub fn fcntl(proc: Arc<Mutex<Process>>, fd: usize, cmd: usize, arg: usize) -> Result<usize, Errno> {
     let mut p = proc.lock();
 
     let node = p.get_fd(fd).ok_or(EBADF)?;
 
     match cmd {
-        F_DUPFD => p.dup_fd(Some(arg), fd).or(Err(EBADF)),
-        F_DUPFD_CLOEXEC => {
-            warn!("F_DUPFD_CLOEXEC cloexec ignored, doing F_DUPFD instead");
-            p.dup_fd(Some(arg), fd).or(Err(EBADF))
-        }
-        F_GETFD => {
-            warn!("fcntl F_GETFD not implemented");
-            Ok(0)
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            if arg >= limits::open_max() {
+                return Err(EINVAL);
+            }
+
+            p.dup_fd(arg, fd, cmd == F_DUPFD_CLOEXEC).or(Err(EMFILE))
         }
+        F_GETFD => match p.fd_close_on_exec(fd) {
+            Some(true) => Ok(FD_CLOEXEC),
+            _ => Ok(0),
+        },
         F_SETFD => {
-            // TODO
-            warn!("fcntl F_SETFD not implemented");
+            p.set_fd_close_on_exec(fd, arg & FD_CLOEXEC != 0).unwrap();
             Ok(0)
         }
         F_GETFL => {
@@ -35,10 +40,11 @@ pub fn fcntl(proc: Arc<Mutex<Process>>, fd: usize, cmd: usize, arg: usize) -> Re
             Ok(flags.bits() as usize)
         }
         F_SETFL => {
-            let flags = FileOpenFlags::from_bits_truncate(arg as u32);
-            node.lock().flags = flags;
+            let new_flags = FileOpenFlags::from_bits_truncate(arg as u32) & SETFL_MASK;
+            let mut file_desc = node.lock();
+            file_desc.flags = (file_desc.flags - SETFL_MASK) | new_flags;
             Ok(0)
         }
-        _ => todo!(),
+        _ => Err(EINVAL),
     }
 }
@@ -1,9 +1,10 @@
-use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::{format, sync::Arc};
 
 use crate::{
+    fs::fd::FileDescriptorTarget,
     posix::errno::{Errno, EBADF, EINVAL},
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
 pub fn fd2path(proc: Arc<Mutex<Process>>, fd: usize, buff: &mut [u8]) -> Result<usize, Errno> {
@@ -12,10 +13,10 @@ pub fn fd2path(proc: Arc<Mutex<Process>>, fd: usize, buff: &mut [u8]) -> Result<
     let file = p.get_fd(fd).ok_or(EBADF)?;
 
     let file = file.lock();
-    let vnode = file.vnode.upgrade().unwrap();
-    let vnode = vnode.lock();
-
-    let path = vnode.get_path();
+    let path = match &file.target {
+        FileDescriptorTarget::Node(vnode) => vnode.upgrade().unwrap().lock().get_path(),
+        FileDescriptorTarget::Pipe(end) => format!("pipe:[{}]", end.id()),
+    };
 
     if buff.len() < path.len() {
         return Err(EINVAL);
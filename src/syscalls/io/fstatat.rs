@@ -1,5 +1,4 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
     fs::{errors::FsStatError, VFS},
@@ -8,6 +7,7 @@ use crate::{
         Stat,
     },
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
 pub fn fstatat(
@@ -0,0 +1,57 @@
+use alloc::sync::Arc;
+
+use crate::{
+    fs::VFS,
+    posix::errno::{Errno, EBADF, EINVAL, ENOTDIR},
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+/// Offset of d_name in struct linux_dirent64
+const DIRENT_NAME_OFFSET: usize = 19;
+
+/// Fills the buffer with linux_dirent64 structures starting from the entry at the
+/// offset of the file descriptor, returns the number of bytes written
+pub fn getdents64(proc: Arc<Mutex<Process>>, fd: usize, buff: &mut [u8]) -> Result<usize, Errno> {
+    // the process is not kept locked because listing /proc locks every process
+    let file_lock = proc.lock().get_fd(fd).ok_or(EBADF)?;
+    let mut file = file_lock.lock();
+
+    let path = match file.vnode() {
+        Some(vnode) => vnode.lock().get_path(),
+        None => return Err(ENOTDIR),
+    };
+
+    let entries = VFS.write().read_dir(&path).map_err(|err| err.into())?;
+
+    let mut written = 0;
+    let mut idx = file.offset;
+    for ent in entries.iter().skip(file.offset) {
+        let name = ent.name.as_bytes();
+        let reclen = (DIRENT_NAME_OFFSET + name.len() + 1).next_multiple_of(8);
+        if written + reclen > buff.len() {
+            break;
+        }
+
+        let rec = &mut buff[written..written + reclen];
+        rec.fill(0);
+        rec[0..8].copy_from_slice(&ent.inode.to_ne_bytes());
+        // d_off is the offset of the next entry
+        rec[8..16].copy_from_slice(&(idx as i64 + 1).to_ne_bytes());
+        rec[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+        rec[18] = ent.file_type.dirent_type();
+        rec[DIRENT_NAME_OFFSET..DIRENT_NAME_OFFSET + name.len()].copy_from_slice(name);
+
+        written += reclen;
+        idx += 1;
+    }
+
+    // the buffer is too small for the next entry
+    if written == 0 && idx < entries.len() {
+        return Err(EINVAL);
+    }
+
+    file.offset = idx;
+
+    Ok(written)
+}
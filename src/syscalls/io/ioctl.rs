@@ -1,19 +1,19 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
     posix::errno::{Errno, EBADF},
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
 pub fn ioctl(proc: Arc<Mutex<Process>>, fd: usize, req: usize, arg: usize) -> Result<usize, Errno> {
-    let p = proc.lock();
-
-    let file_lock = p.get_fd(fd).ok_or(EBADF)?;
+    // the process is not kept locked because the request can copy user memory, a copy that
+    // grows the stack locks it
+    let file_lock = proc.lock().get_fd(fd).ok_or(EBADF)?;
 
     let file_desc = file_lock.lock();
     match file_desc.ioctl(req, arg) {
         Ok(ret) => Ok(ret),
-        Err(_) => todo!(),
+        Err(err) => Err(err.into()),
     }
 }
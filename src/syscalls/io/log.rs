@@ -1,7 +1,6 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
-use crate::{posix::errno::Errno, scheduler::proc::Process};
+use crate::{posix::errno::Errno, scheduler::proc::Process, sync::Mutex};
 
 pub fn log(proc: Arc<Mutex<Process>>, message: &str) -> Result<(), Errno> {
     let p = proc.lock();
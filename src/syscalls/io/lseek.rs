@@ -1,32 +1,30 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
     fs::SeekWhence,
-    posix::errno::{Errno, EBADF},
+    posix::{
+        errno::{Errno, EBADF, EINVAL},
+        SEEK_CUR, SEEK_END, SEEK_SET,
+    },
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
 pub fn lseek(
     proc: Arc<Mutex<Process>>,
     fd: usize,
-    offset: usize,
+    offset: isize,
     whence: usize,
 ) -> Result<usize, Errno> {
-    let p = proc.lock();
-
-    let file_lock = p.get_fd(fd).ok_or(EBADF)?;
+    let file_lock = proc.lock().get_fd(fd).ok_or(EBADF)?;
 
     let whence = match whence {
-        0 => SeekWhence::Set,
-        1 => SeekWhence::Cur,
-        2 => SeekWhence::End,
-        _ => todo!(),
+        SEEK_SET => SeekWhence::Set,
+        SEEK_CUR => SeekWhence::Cur,
+        SEEK_END => SeekWhence::End,
+        _ => return Err(EINVAL),
     };
 
     let mut file_desc = file_lock.lock();
-    match file_desc.lseek(offset, whence) {
-        Ok(ret) => Ok(ret),
-        Err(_) => todo!(),
-    }
+    file_desc.lseek(offset, whence).map_err(|err| err.into())
 }
@@ -1,4 +1,5 @@
 pub mod close;
+pub mod dup;
 pub mod fcntl;
 pub mod fstatat;
 pub mod ioctl;
@@ -8,3 +9,13 @@ pub mod openat;
 pub mod read;
 pub mod write;
 pub mod fd2path;
+pub mod getdents;
+pub mod unlink;
+pub mod rmdir;
+pub mod rename;
+pub mod mkdir;
+pub mod pipe2;
+pub mod poll;
+pub mod pselect;
+pub mod pread;
+pub mod pwrite;
@@ -1,10 +1,14 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
-    fs::{errors::FsOpenError, VFS},
-    posix::{errno::{Errno, EBADF}, FileOpenFlags, FileOpenMode},
+    audit::{self, AuditEvent},
+    fs::VFS,
+    posix::{
+        errno::{Errno, EBADF, EMFILE},
+        FileOpenFlags, FileOpenMode,
+    },
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
 pub fn openat(
@@ -20,30 +24,37 @@ pub fn openat(
 
     // TODO: validate path
 
-    let fd =   if dirfd == -1 {
+    let fd = if dirfd == -1 {
         None
-    } else if dirfd > 0 {
+    } else if dirfd >= 0 {
         Some(dirfd as usize)
     } else {
         return Err(EBADF);
     };
 
-    let full_path = match p.get_full_path_from_dirfd(fd, path) {
-        Ok(path) => path,
-        Err(_) => todo!(),
-    };
-
-    let file_desc = {
-        let mut vfs = VFS.write();
-        let desc = vfs
-            .open(full_path.as_str(), flags)
-            .map_err(|err| match err {
-                FsOpenError::BadPath(path) => path.into(),
-            })?;
-        Arc::new(Mutex::new(*desc))
-    };
-
-    let fd = p.new_fd(None, file_desc).unwrap();
+    let full_path = p.get_full_path_from_dirfd(fd, path).map_err(|_| EBADF)?;
+
+    let res: Result<_, Errno> = VFS
+        .write()
+        .open(full_path.as_str(), flags)
+        .map_err(|err| err.into());
+
+    let writes =
+        flags.writable() || flags.intersects(FileOpenFlags::O_CREAT | FileOpenFlags::O_TRUNC);
+    if writes && audit::is_sensitive_path(&full_path) {
+        let event = AuditEvent::OpenWrite {
+            path: full_path.clone(),
+        };
+        audit::record(
+            Some(&p),
+            event,
+            res.as_ref().map(|_| ()).map_err(|err| *err),
+        );
+    }
+
+    let file_desc = Arc::new(spin::Mutex::new(*res?));
+
+    let fd = p.new_fd(None, file_desc).or(Err(EMFILE))?;
 
     Ok(fd)
 }
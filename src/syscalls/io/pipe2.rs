@@ -0,0 +1,48 @@
+use alloc::sync::Arc;
+
+use crate::{
+    fs::{
+        fd::{FileDescriptor, FileDescriptorTarget},
+        pipe,
+    },
+    posix::{
+        errno::{Errno, EINVAL, EMFILE},
+        FileOpenFlags,
+    },
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+/// Creates a pipe, returns the file descriptors of the read and the write end
+pub fn pipe2(proc: Arc<Mutex<Process>>, flags: u32) -> Result<[usize; 2], Errno> {
+    let flags = FileOpenFlags::from_bits(flags).ok_or(EINVAL)?;
+    if !(FileOpenFlags::O_NONBLOCK | FileOpenFlags::O_CLOEXEC).contains(flags) {
+        return Err(EINVAL);
+    }
+
+    let (read_end, write_end) = pipe::create();
+    let read_desc = FileDescriptor {
+        target: FileDescriptorTarget::Pipe(read_end),
+        offset: 0,
+        flags: flags | FileOpenFlags::O_RDONLY,
+    };
+    let write_desc = FileDescriptor {
+        target: FileDescriptorTarget::Pipe(write_end),
+        offset: 0,
+        flags: flags | FileOpenFlags::O_WRONLY,
+    };
+
+    let mut p = proc.lock();
+    let read_fd = p
+        .new_fd(None, Arc::new(spin::Mutex::new(read_desc)))
+        .map_err(|_| EMFILE)?;
+    let write_fd = match p.new_fd(None, Arc::new(spin::Mutex::new(write_desc))) {
+        Ok(fd) => fd,
+        Err(_) => {
+            p.free_fd(read_fd);
+            return Err(EMFILE);
+        }
+    };
+
+    Ok([read_fd, write_fd])
+}
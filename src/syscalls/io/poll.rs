@@ -0,0 +1,93 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    fs::fd::FileDescriptor,
+    limits::OPEN_MAX,
+    posix::{
+        errno::{Errno, EINTR, EINVAL},
+        PollEvents, PollFd,
+    },
+    scheduler::{
+        proc::{self, Process},
+        wait_queue::Waiter,
+    },
+    sync::Mutex,
+    time,
+};
+
+/// Sets the revents of every entry of __fds__, registers __waiter__ on the files if it is
+/// given. Returns the number of entries with events.
+fn poll_fds(
+    files: &[Option<Arc<spin::Mutex<FileDescriptor>>>],
+    fds: &mut [PollFd],
+    waiter: Option<&Arc<Waiter>>,
+) -> usize {
+    let mut ready = 0;
+    for (pollfd, file) in fds.iter_mut().zip(files) {
+        let revents = match file {
+            Some(file) => {
+                // errors and hangups are reported even if they were not asked for
+                let events = PollEvents::from_bits_truncate(pollfd.events)
+                    | PollEvents::POLLERR
+                    | PollEvents::POLLHUP;
+                file.lock().poll(waiter) & events
+            }
+            // negative fds are ignored
+            None if pollfd.fd < 0 => PollEvents::empty(),
+            None => PollEvents::POLLNVAL,
+        };
+
+        pollfd.revents = revents.bits();
+        if !revents.is_empty() {
+            ready += 1;
+        }
+    }
+
+    ready
+}
+
+/// Waits until any file in __fds__ has an event it asked for or __timeout__ milliseconds pass,
+/// forever if it is None. Returns the number of entries with events, 0 on a timeout.
+pub fn poll(
+    proc: Arc<Mutex<Process>>,
+    fds: &mut [PollFd],
+    timeout: Option<u64>,
+) -> Result<usize, Errno> {
+    if fds.len() > OPEN_MAX {
+        return Err(EINVAL);
+    }
+
+    // the files are looked up once so the process is not kept locked while waiting
+    let (pid, files): (usize, Vec<_>) = {
+        let p = proc.lock();
+        let files = fds
+            .iter()
+            .map(|pollfd| match usize::try_from(pollfd.fd) {
+                Ok(fd) => p.get_fd(fd),
+                Err(_) => None,
+            })
+            .collect();
+        (p.pid, files)
+    };
+
+    let deadline = timeout.map(|ms| time::elapsed().as_milliseconds().saturating_add(ms));
+    let waiter = Arc::new(Waiter::new());
+    loop {
+        let ready = poll_fds(&files, fds, Some(&waiter));
+        if ready > 0 {
+            return Ok(ready);
+        }
+
+        if deadline.is_some_and(|deadline| time::elapsed().as_milliseconds() >= deadline) {
+            return Ok(0);
+        }
+
+        proc::register_signal_waiter(&waiter);
+        if proc::pending_signal(pid).is_some() {
+            return Err(EINTR);
+        }
+
+        // a wakeup between checking the files and blocking makes block return right away
+        waiter.block_until(deadline);
+    }
+}
@@ -0,0 +1,17 @@
+use alloc::sync::Arc;
+
+use crate::{posix::errno::Errno, scheduler::proc::Process, sync::Mutex};
+
+use super::read::read_chunked;
+
+/// Reads at most __len__ bytes from __off__ without moving the offset of the file descriptor,
+/// the data is passed to __copy_out__ like `read_chunked` does
+pub fn pread(
+    proc: Arc<Mutex<Process>>,
+    fd: usize,
+    len: usize,
+    off: usize,
+    copy_out: impl FnMut(usize, &[u8]) -> Result<(), Errno>,
+) -> Result<usize, Errno> {
+    read_chunked(proc, fd, len, Some(off), copy_out)
+}
@@ -0,0 +1,98 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    posix::{
+        errno::{Errno, EBADF, EINVAL},
+        PollEvents, PollFd, FD_SETSIZE,
+    },
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+use super::poll;
+
+/// An fd_set of select, bit n is file descriptor n
+pub type FdSet = [u64; FD_SETSIZE / 64];
+
+fn fd_is_set(set: &Option<FdSet>, fd: usize) -> bool {
+    match set {
+        Some(set) => set[fd / 64] & (1 << (fd % 64)) != 0,
+        None => false,
+    }
+}
+
+/// Waits until any file descriptor below __nfds__ in the sets is ready or __timeout__
+/// milliseconds pass, forever if it is None. The sets are replaced with the ready file
+/// descriptors, returns the number of bits set in them.
+pub fn pselect(
+    proc: Arc<Mutex<Process>>,
+    nfds: usize,
+    read_set: &mut Option<FdSet>,
+    write_set: &mut Option<FdSet>,
+    except_set: &mut Option<FdSet>,
+    timeout: Option<u64>,
+) -> Result<usize, Errno> {
+    if nfds > FD_SETSIZE {
+        return Err(EINVAL);
+    }
+
+    // select is implemented on top of poll, POLLHUP and POLLERR make a file readable and
+    // writable so a reader does not wait forever on a closed pipe
+    let mut fds = Vec::new();
+    for fd in 0..nfds {
+        let mut events = PollEvents::empty();
+        if fd_is_set(read_set, fd) {
+            events |= PollEvents::POLLIN;
+        }
+        if fd_is_set(write_set, fd) {
+            events |= PollEvents::POLLOUT;
+        }
+        if fd_is_set(except_set, fd) {
+            events |= PollEvents::POLLPRI;
+        }
+
+        if !events.is_empty() {
+            fds.push(PollFd {
+                fd: fd as i32,
+                events: events.bits(),
+                revents: 0,
+            });
+        }
+    }
+
+    poll::poll(proc, &mut fds, timeout)?;
+
+    for set in [&mut *read_set, &mut *write_set, &mut *except_set]
+        .into_iter()
+        .flatten()
+    {
+        set.fill(0);
+    }
+
+    let mut ready = 0;
+    for pollfd in fds.iter() {
+        let revents = PollEvents::from_bits_truncate(pollfd.revents);
+        if revents.contains(PollEvents::POLLNVAL) {
+            return Err(EBADF);
+        }
+
+        let fd = pollfd.fd as usize;
+        let events = PollEvents::from_bits_truncate(pollfd.events);
+        let failed = revents.intersects(PollEvents::POLLERR | PollEvents::POLLHUP);
+        for (set, event) in [
+            (&mut *read_set, PollEvents::POLLIN),
+            (&mut *write_set, PollEvents::POLLOUT),
+            (&mut *except_set, PollEvents::POLLPRI),
+        ] {
+            let hit = revents.contains(event) || (failed && event != PollEvents::POLLPRI);
+            if let Some(set) = set {
+                if events.contains(event) && hit {
+                    set[fd / 64] |= 1 << (fd % 64);
+                    ready += 1;
+                }
+            }
+        }
+    }
+
+    Ok(ready)
+}
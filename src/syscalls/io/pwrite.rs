@@ -0,0 +1,17 @@
+use alloc::sync::Arc;
+
+use crate::{posix::errno::Errno, scheduler::proc::Process, sync::Mutex};
+
+use super::write::write_chunked;
+
+/// Writes __len__ bytes to __off__ without moving the offset of the file descriptor, O_APPEND
+/// is ignored. The data is taken from __copy_in__ like `write_chunked` does.
+pub fn pwrite(
+    proc: Arc<Mutex<Process>>,
+    fd: usize,
+    len: usize,
+    off: usize,
+    copy_in: impl FnMut(usize, &mut [u8]) -> Result<(), Errno>,
+) -> Result<usize, Errno> {
+    write_chunked(proc, fd, len, Some(off), copy_in)
+}
@@ -1,15 +1,57 @@
-use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::{sync::Arc, vec};
 
 use crate::{
+    limits::PAGE_SIZE,
     posix::errno::{Errno, EBADF},
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
-pub fn read(proc: Arc<Mutex<Process>>, fd: usize, buff: &mut [u8]) -> Result<usize, Errno> {
-    let p = proc.lock();
-    let file_lock = p.get_fd(fd).ok_or(EBADF)?;
+/// Size of the kernel buffer the data of a read or a write is copied through, the length
+/// comes from userspace so the data is never buffered whole
+pub const BOUNCE_BUFFER_SIZE: usize = PAGE_SIZE;
+
+/// Reads at most __len__ bytes through a bounce buffer and passes the data to __copy_out__
+/// along with its offset. Without __off__ the file is read at the offset of the file
+/// descriptor and the offset is moved, otherwise it is left alone. Regular files are read
+/// until __len__ bytes or the end of the file, anything else stops after the first chunk
+/// because reading another one could block.
+pub fn read_chunked(
+    proc: Arc<Mutex<Process>>,
+    fd: usize,
+    len: usize,
+    off: Option<usize>,
+    mut copy_out: impl FnMut(usize, &[u8]) -> Result<(), Errno>,
+) -> Result<usize, Errno> {
+    // the process is not kept locked because procfs files can lock it while being generated
+    let file_lock = proc.lock().get_fd(fd).ok_or(EBADF)?;
 
     let mut file_desc = file_lock.lock();
-    file_desc.read(buff).map_err(|_| todo!())
+    let regular = file_desc.is_regular_file();
+    let mut buff = vec![0; len.min(BOUNCE_BUFFER_SIZE)];
+    let mut total = 0;
+
+    loop {
+        let count = (len - total).min(buff.len());
+        let result = match off {
+            Some(off) => file_desc.read_at(off + total, &mut buff[..count]),
+            None => file_desc.read(&mut buff[..count]),
+        };
+
+        let n = match result {
+            Ok(n) => n,
+            // the data that was read is returned, the next read reports the error
+            Err(_) if total > 0 => break,
+            Err(err) => return Err(err.into()),
+        };
+
+        copy_out(total, &buff[..n])?;
+        total += n;
+
+        if n < count || total == len || !regular {
+            break;
+        }
+    }
+
+    Ok(total)
 }
@@ -0,0 +1,42 @@
+use alloc::{string::String, sync::Arc};
+
+use crate::{
+    fs::VFS,
+    posix::errno::{Errno, EBADF},
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+fn get_full_path(p: &Process, dirfd: isize, path: &str) -> Result<String, Errno> {
+    let fd = if dirfd == -1 {
+        None
+    } else if dirfd > 0 {
+        Some(dirfd as usize)
+    } else {
+        return Err(EBADF);
+    };
+
+    p.get_full_path_from_dirfd(fd, path).map_err(|_| EBADF)
+}
+
+/// Moves a file or a directory, paths are resolved like in openat
+pub fn rename(
+    proc: Arc<Mutex<Process>>,
+    old_dirfd: isize,
+    old_path: &str,
+    new_dirfd: isize,
+    new_path: &str,
+) -> Result<(), Errno> {
+    debug!(
+        "rename {} {} {} {}",
+        old_dirfd, old_path, new_dirfd, new_path
+    );
+    let p = proc.lock();
+
+    let old_full_path = get_full_path(&p, old_dirfd, old_path)?;
+    let new_full_path = get_full_path(&p, new_dirfd, new_path)?;
+
+    VFS.write()
+        .rename(old_full_path.as_str(), new_full_path.as_str())
+        .map_err(|err| err.into())
+}
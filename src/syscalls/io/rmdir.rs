@@ -0,0 +1,28 @@
+use alloc::sync::Arc;
+
+use crate::{
+    fs::VFS,
+    posix::errno::{Errno, EBADF},
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+/// Removes an empty directory, paths are resolved like in openat
+pub fn rmdir(proc: Arc<Mutex<Process>>, dirfd: isize, path: &str) -> Result<(), Errno> {
+    debug!("rmdir {} {}", dirfd, path);
+    let p = proc.lock();
+
+    let fd = if dirfd == -1 {
+        None
+    } else if dirfd > 0 {
+        Some(dirfd as usize)
+    } else {
+        return Err(EBADF);
+    };
+
+    let full_path = p.get_full_path_from_dirfd(fd, path).map_err(|_| EBADF)?;
+
+    VFS.write()
+        .rmdir(full_path.as_str())
+        .map_err(|err| err.into())
+}
@@ -1,15 +1,53 @@
-use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::{sync::Arc, vec};
 
 use crate::{
     posix::errno::{Errno, EBADF},
     scheduler::proc::Process,
+    sync::Mutex,
 };
 
-pub fn write(proc: Arc<Mutex<Process>>, fd: usize, buff: &[u8]) -> Result<usize, Errno> {
-    let p = proc.lock();
-    let file_lock = p.get_fd(fd).ok_or(EBADF)?;
+use super::read::BOUNCE_BUFFER_SIZE;
+
+/// Writes __len__ bytes through a bounce buffer, __copy_in__ fills the buffer with the data
+/// at the offset it is given. Without __off__ the data is written at the offset of the file
+/// descriptor and the offset is moved, otherwise it is left alone. Stops at the first chunk
+/// that was not written completely.
+pub fn write_chunked(
+    proc: Arc<Mutex<Process>>,
+    fd: usize,
+    len: usize,
+    off: Option<usize>,
+    mut copy_in: impl FnMut(usize, &mut [u8]) -> Result<(), Errno>,
+) -> Result<usize, Errno> {
+    // the process is not kept locked because writing to a pipe can block
+    let file_lock = proc.lock().get_fd(fd).ok_or(EBADF)?;
 
     let mut file_desc = file_lock.lock();
-    file_desc.write(buff).map_err(|_| todo!())
+    let mut buff = vec![0; len.min(BOUNCE_BUFFER_SIZE)];
+    let mut total = 0;
+
+    loop {
+        let count = (len - total).min(buff.len());
+        let result = copy_in(total, &mut buff[..count]).and_then(|()| {
+            let written = match off {
+                Some(off) => file_desc.write_at(off + total, &buff[..count]),
+                None => file_desc.write(&buff[..count]),
+            };
+            written.map_err(|err| err.into())
+        });
+
+        let n = match result {
+            Ok(n) => n,
+            // the data that was written is reported, the next write returns the error
+            Err(_) if total > 0 => break,
+            Err(err) => return Err(err),
+        };
+        total += n;
+
+        if n < count || total == len {
+            break;
+        }
+    }
+
+    Ok(total)
 }
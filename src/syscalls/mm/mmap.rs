@@ -1,11 +1,98 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
-    posix::errno::Errno,
-    scheduler::proc::{MappedRegionFlags, Process},
+    arch::x86_64::usercopy::is_userspace_range,
+    mm::{self, virt::PAGE_SIZE_4KIB},
+    posix::{
+        errno::{Errno, EACCES, EBADF, EINVAL, ENODEV, ENOMEM},
+        MapFlags, MemoryProtection,
+    },
+    scheduler::proc::{MappedRegionFlags, Process, RegionBacking},
+    sync::Mutex,
 };
 
+/// Turns the protection userspace asked for into region flags, the pages of a region are
+/// always readable so PROT_READ and PROT_EXEC do not restrict anything yet
+pub fn region_flags(prot: u32) -> Result<MappedRegionFlags, Errno> {
+    let prot = MemoryProtection::from_bits(prot).ok_or(EINVAL)?;
+
+    if prot.is_empty() {
+        return Ok(MappedRegionFlags::NO_ACCESS);
+    }
+
+    let mut flags = MappedRegionFlags::empty();
+    if prot.contains(MemoryProtection::PROT_WRITE) {
+        flags |= MappedRegionFlags::READ_WRITE;
+    }
+
+    if prot.contains(MemoryProtection::PROT_EXEC) {
+        flags |= MappedRegionFlags::EXECUTE;
+    }
+
+    Ok(flags)
+}
+
+/// Returns the length rounded up to whole pages if [addr, addr + len) is a valid userspace range
+pub fn page_range_len(addr: usize, len: usize) -> Result<usize, Errno> {
+    if len == 0 || addr % PAGE_SIZE_4KIB as usize != 0 {
+        return Err(EINVAL);
+    }
+
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE_4KIB as usize)
+        .ok_or(EINVAL)?;
+
+    if !is_userspace_range(addr as u64, len) {
+        return Err(EINVAL);
+    }
+
+    Ok(len)
+}
+
+/// Returns the backing of a mapping of __fd__, only devices whose memory can be mapped
+/// directly are supported, e.g. the framebuffer
+fn file_backing(
+    proc: &Arc<Mutex<Process>>,
+    fd: isize,
+    off: u64,
+    len: usize,
+    prot: u32,
+    map_flags: MapFlags,
+) -> Result<RegionBacking, Errno> {
+    let fd = usize::try_from(fd).map_err(|_| EBADF)?;
+    let file = proc.lock().get_fd(fd).ok_or(EBADF)?;
+    let file = file.lock();
+
+    // the file has to be readable and writable pages can only be mapped from a file opened
+    // for writing
+    let writable = prot & MemoryProtection::PROT_WRITE.bits() != 0;
+    if !file.flags.readable() || (writable && !file.flags.writable()) {
+        return Err(EACCES);
+    }
+
+    // device memory can not be copied on write
+    if !map_flags.contains(MapFlags::MAP_SHARED) {
+        return Err(ENODEV);
+    }
+
+    let offset = usize::try_from(off).map_err(|_| EINVAL)?;
+    if len == 0 || offset % PAGE_SIZE_4KIB as usize != 0 {
+        return Err(EINVAL);
+    }
+
+    let len = len
+        .checked_next_multiple_of(PAGE_SIZE_4KIB as usize)
+        .ok_or(EINVAL)?;
+    let phys = file.mmap(offset, len).map_err(|err| err.into())?;
+    let path = file.vnode().map(|vnode| vnode.lock().get_path());
+
+    Ok(RegionBacking::Device {
+        path: path.unwrap_or_default(),
+        offset,
+        phys,
+    })
+}
+
 pub fn mmap(
     proc: Arc<Mutex<Process>>,
     hint: usize,
@@ -16,26 +103,64 @@ pub fn mmap(
     off: u64,
 ) -> Result<u64, Errno> {
     debug!("{} {} {} {} {} {}", hint, len, prot, flags, fd, off);
-    if prot != 0 || flags != 0 || fd >= 0 || off != 0 {
-        todo!()
+    let map_flags = MapFlags::from_bits(flags).ok_or(EINVAL)?;
+    let mut region_flags = region_flags(prot)?;
+
+    // exactly one of MAP_SHARED and MAP_PRIVATE has to be set
+    let sharing = map_flags & (MapFlags::MAP_SHARED | MapFlags::MAP_PRIVATE);
+    if sharing.is_empty() || sharing.is_all() {
+        return Err(EINVAL);
     }
 
-    let hint = match hint {
-        0 => None,
-        addr if addr % 4096 == 0 => Some(addr),
-        _ => todo!(),
+    let backing = if map_flags.contains(MapFlags::MAP_ANONYMOUS) {
+        if fd != -1 || off != 0 {
+            return Err(EINVAL);
+        }
+
+        if !mm::can_commit(len) {
+            return Err(ENOMEM);
+        }
+
+        // anonymous pages get their frames on the first access
+        region_flags |= MappedRegionFlags::ALLOC_ON_ACCESS;
+        if map_flags.contains(MapFlags::MAP_SHARED) {
+            RegionBacking::Shared
+        } else {
+            RegionBacking::Anonymous
+        }
+    } else {
+        // the device memory is mapped right away
+        file_backing(&proc, fd, off, len, prot, map_flags)?
     };
 
+    let mut p = proc.lock();
+
+    if map_flags.contains(MapFlags::MAP_FIXED) {
+        let len = page_range_len(hint, len)?;
+
+        // the fixed mapping replaces whatever was mapped in the range
+        p.munmap(hint, len);
+        return match p.mmap(Some(hint), len, region_flags, backing) {
+            Ok(addr) => Ok(addr as u64),
+            Err(_) => Err(ENOMEM),
+        };
+    }
+
     if len == 0 {
-        todo!()
+        return Err(EINVAL);
     }
 
-    let flags = MappedRegionFlags::READ_WRITE | MappedRegionFlags::ALLOC_ON_ACCESS;
+    // the address is only a hint, a new address is picked if the range is not free
+    let hint = match page_range_len(hint, len) {
+        Ok(_) => p.mmap(Some(hint), len, region_flags, backing.clone()).ok(),
+        Err(_) => None,
+    };
 
-    // TODO: turn flags into MappedRegionFlags
-    let mut p = proc.lock();
-    match p.mmap(hint, len, flags) {
-        Ok(addr) => Ok(addr as u64),
-        Err(_) => todo!(),
+    match hint {
+        Some(addr) => Ok(addr as u64),
+        None => match p.mmap(None, len, region_flags, backing) {
+            Ok(addr) => Ok(addr as u64),
+            Err(_) => Err(ENOMEM),
+        },
     }
 }
@@ -1 +1,3 @@
 pub mod mmap;
+pub mod mprotect;
+pub mod munmap;
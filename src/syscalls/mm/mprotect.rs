@@ -0,0 +1,22 @@
+use alloc::sync::Arc;
+
+use crate::{
+    posix::errno::{Errno, ENOMEM},
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+use super::mmap::{page_range_len, region_flags};
+
+pub fn mprotect(
+    proc: Arc<Mutex<Process>>,
+    addr: usize,
+    len: usize,
+    prot: u32,
+) -> Result<(), Errno> {
+    let len = page_range_len(addr, len)?;
+    let flags = region_flags(prot)?;
+
+    let mut p = proc.lock();
+    p.mprotect(addr, len, flags).map_err(|_| ENOMEM)
+}
@@ -0,0 +1,14 @@
+use alloc::sync::Arc;
+
+use crate::{posix::errno::Errno, scheduler::proc::Process, sync::Mutex};
+
+use super::mmap::page_range_len;
+
+pub fn munmap(proc: Arc<Mutex<Process>>, addr: usize, len: usize) -> Result<(), Errno> {
+    let len = page_range_len(addr, len)?;
+
+    let mut p = proc.lock();
+    p.munmap(addr, len);
+
+    Ok(())
+}
@@ -1,29 +1,33 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
+    arch::x86_64::usercopy::USERSPACE_END,
     mm::VirtAddr,
-    posix::errno::Errno,
-    scheduler::{proc::Process, thread::ThreadInner},
+    posix::errno::{Errno, EINVAL, EPERM},
+    scheduler::{proc::Process, thread::ThreadInner, SCHEDULER},
+    sync::Mutex,
 };
 
-pub fn archctl(proc: Arc<Mutex<Process>>, req: usize, arg: usize) -> Result<(), Errno> {
+pub fn archctl(_proc: Arc<Mutex<Process>>, req: usize, arg: usize) -> Result<(), Errno> {
     const SET_FS: usize = 0x1000;
 
-    let p = proc.lock();
-
-    let main_thread_lock = p.main_thread.upgrade().unwrap();
-    let mut main_thread = main_thread_lock.lock();
+    // every thread has its own FS base
+    let thread_lock = SCHEDULER.get_current_thread().expect("No threads running");
+    let mut thread = thread_lock.lock();
 
     // TODO
     match req {
         SET_FS => {
-            // TODO: check if fs is valid
-            if let ThreadInner::User(data) = &mut main_thread.inner {
+            // loading a non-canonical FS base would fault in the kernel
+            if arg as u64 >= USERSPACE_END {
+                return Err(EPERM);
+            }
+
+            if let ThreadInner::User(data) = &mut thread.inner {
                 data.tls = VirtAddr::new(arg as u64);
             }
             Ok(())
         }
-        _ => todo!(),
+        _ => Err(EINVAL),
     }
 }
@@ -0,0 +1,29 @@
+use alloc::sync::Arc;
+
+use crate::{
+    posix::{
+        errno::{Errno, EINVAL},
+        Timespec, CLOCK_MONOTONIC, CLOCK_REALTIME,
+    },
+    scheduler::proc::Process,
+    sync::Mutex,
+    time::{self, NSEC_PER_SEC},
+};
+
+/// Writes the current time of __clock_id__ to __tp__
+pub fn clock_gettime(
+    _proc: Arc<Mutex<Process>>,
+    clock_id: u32,
+    tp: &mut Timespec,
+) -> Result<(), Errno> {
+    let ns = match clock_id {
+        CLOCK_REALTIME => time::realtime_ns(),
+        CLOCK_MONOTONIC => time::monotonic_ns(),
+        _ => return Err(EINVAL),
+    };
+
+    tp.tv_sec = ns / NSEC_PER_SEC;
+    tp.tv_nsec = ns % NSEC_PER_SEC;
+
+    Ok(())
+}
@@ -1,57 +1,108 @@
-use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::sync::{Arc, Weak};
 
 use crate::{
-    arch::x86_64::syscall::proc::{CloneArgs, CloneFlags},
-    posix::errno::Errno,
+    arch::x86_64::{
+        syscall::proc::{CloneArgs, CloneFlags},
+        usercopy::{In, UserPtr, USERSPACE_END},
+    },
+    mm::VirtAddr,
+    posix::errno::{Errno, EAGAIN, EINVAL},
     scheduler::{
-        proc::Process,
-        thread::{ThreadID, ThreadInner},
+        proc::{self, Process},
+        thread::{Thread, ThreadID, ThreadInner},
         SCHEDULER,
     },
+    sync::Mutex,
 };
 
+/// Sets up the registers of a new thread, it returns 0 from clone on the stack and with the
+/// TLS passed to clone
+fn init_child_thread(thread: &Weak<Mutex<Thread>>, clone_args: &CloneArgs) -> ThreadID {
+    let clone_flags = CloneFlags::from_bits_truncate(clone_args.flags);
+    let thread = thread.upgrade().unwrap();
+    let mut thread = thread.lock();
+
+    if let ThreadInner::User(data) = &mut thread.inner {
+        data.user_regs.general.rax = 0;
+        data.in_kernelspace = false;
+
+        // without a stack the child keeps using the stack of the parent
+        if clone_args.stack != 0 {
+            data.user_regs.rsp = clone_args.stack + clone_args.stack_size;
+        }
+
+        if clone_flags.contains(CloneFlags::CLONE_SETTLS) {
+            data.tls = VirtAddr::new(clone_args.tls);
+        }
+
+        if clone_flags.contains(CloneFlags::CLONE_CHILD_CLEARTID) {
+            data.clear_child_tid = clone_args.child_tid;
+        }
+    }
+
+    thread.id
+}
+
+/// Creates a thread in the process of the calling thread, returns the TID of the new thread
+fn clone_thread(proc: Arc<Mutex<Process>>, clone_args: &CloneArgs) -> Result<usize, Errno> {
+    let current_tid = SCHEDULER
+        .get_current_thread()
+        .expect("No threads running")
+        .lock()
+        .id;
+
+    let thread = proc.lock().clone_thread(current_tid).ok_or(EAGAIN)?;
+    let tid = init_child_thread(&thread, clone_args);
+
+    SCHEDULER.run_thread(tid);
+    Ok(tid.0)
+}
+
 pub fn clone(
     proc: Arc<Mutex<Process>>,
-    clone_args: *const CloneArgs,
+    clone_args: UserPtr<CloneArgs, In>,
     _size: usize,
 ) -> Result<usize, Errno> {
     // TODO: check if sizeof(clone_args) == size???
-    // TODO: validate clone_args
-
-    let child_tid: ThreadID;
-    let child_pid: usize;
-    let block_wait_for_child: bool;
-
-    {
-        let clone_args = unsafe { clone_args.as_ref() }.unwrap();
-        let p = proc.lock();
+    let clone_args = clone_args.read()?;
+    let clone_flags = CloneFlags::from_bits(clone_args.flags).ok_or(EINVAL)?;
 
-        let child = p.clone_proc(clone_args);
-        let child = child.lock();
-        child_pid = child.pid;
-
-        {
-            let thread = child.main_thread.upgrade().unwrap();
-            let mut thread = thread.lock();
-
-            child_tid = thread.id;
+    // the stack grows down from the end of the range and the TLS is loaded into the FS base,
+    // neither can be outside of userspace
+    let stack_end = clone_args.stack.checked_add(clone_args.stack_size);
+    if clone_args.stack != 0 && !matches!(stack_end, Some(end) if end <= USERSPACE_END) {
+        return Err(EINVAL);
+    }
+    if clone_flags.contains(CloneFlags::CLONE_SETTLS) && clone_args.tls >= USERSPACE_END {
+        return Err(EINVAL);
+    }
 
-            if let ThreadInner::User(data) = &mut thread.inner {
-                data.user_regs.general.rax = 0;
-                data.in_kernelspace = false;
-            }
+    if clone_flags.contains(CloneFlags::CLONE_THREAD) {
+        // the threads of a process share its memory and file descriptors, a thread has no
+        // parent that could wait for it
+        let required = CloneFlags::CLONE_VM | CloneFlags::CLONE_FILES;
+        if !clone_flags.contains(required) || clone_flags.contains(CloneFlags::CLONE_VFORK) {
+            return Err(EINVAL);
         }
 
-        let clone_flags = CloneFlags::from_bits(clone_args.flags).unwrap();
-        block_wait_for_child = clone_flags.contains(CloneFlags::CLONE_VFORK);
+        return clone_thread(proc, &clone_args);
     }
 
+    let child_lock = proc.lock().clone_proc(&clone_args).map_err(|_| EAGAIN)?;
+    let (child_pid, child_tid) = {
+        let child = child_lock.lock();
+        (
+            child.pid,
+            init_child_thread(&child.main_thread, &clone_args),
+        )
+    };
+    let block_wait_for_child = clone_flags.contains(CloneFlags::CLONE_VFORK);
+
     // TODO: disable interrupts?, maybe scheduler interrupt mutex already does that for us
     SCHEDULER.run_thread(child_tid);
 
     if block_wait_for_child {
-        SCHEDULER.block_current_thread();
+        proc::wait_for_vfork_child(&child_lock);
     }
 
     Ok(child_pid)
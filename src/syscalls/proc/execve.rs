@@ -1,46 +1,99 @@
 use alloc::{string::String, sync::Arc, vec::Vec};
-use spin::Mutex;
 
 use crate::{
-    arch::x86_64::disable_interrupts,
-    posix::errno::Errno,
-    scheduler::{proc::Process, thread::ThreadInner},
+    arch::x86_64::{disable_interrupts, enable_interrupts},
+    audit::{self, AuditEvent},
+    posix::{
+        errno::{Errno, EINTR, ENOEXEC},
+        SIGKILL,
+    },
+    scheduler::{
+        proc::{self, Executable, Process},
+        thread::ThreadInner,
+        SCHEDULER,
+    },
+    sync::Mutex,
 };
 
+use super::exit;
+
 pub fn execve(
     proc: Arc<Mutex<Process>>,
     path: &str,
     argv: &[String],
     envp: &[String],
 ) -> Result<(), Errno> {
-    // TODO: errors
+    // nothing of the old program is given up before the executable is checked
+    let executable = match Executable::open(path) {
+        Ok(executable) => executable,
+        Err(err) => {
+            let event = AuditEvent::Exec {
+                path: String::from(path),
+            };
+            audit::record(Some(&proc.lock()), event, Err(err));
+            return Err(err);
+        }
+    };
+
+    // the new program starts with the calling thread only
+    if !proc::kill_other_threads(&proc) {
+        // another thread is terminating the process, this one exits on the way out
+        return Err(EINTR);
+    }
+    proc::end_group_exit(&proc);
+    let current = SCHEDULER.get_current_thread().expect("No threads running");
+
     disable_interrupts();
-    let mut p = proc.lock();
-
-    let argv: Vec<&str> = argv.iter().map(String::as_ref).collect();
-    let envp: Vec<&str> = envp.iter().map(String::as_ref).collect();
-
-    p.execve(path, &argv, &envp)
-        .expect("Failed to load process");
-
-    let main_thread_lock = p.main_thread.upgrade().unwrap();
-    let mut main_thread = main_thread_lock.lock();
-
-    // load_from_file already sets rip, rsp and (argc)rdi, (argv)rsi, (envp)rdx
-    if let ThreadInner::User(data) = &mut main_thread.inner {
-        data.user_regs.general.rax = 0;
-        data.user_regs.general.rbx = 0;
-        data.user_regs.general.rcx = 0;
-        data.user_regs.general.r8 = 0;
-        data.user_regs.general.r9 = 0;
-        data.user_regs.general.r10 = 0;
-        data.user_regs.general.r11 = 0;
-        data.user_regs.general.r12 = 0;
-        data.user_regs.general.r13 = 0;
-        data.user_regs.general.r14 = 0;
-        data.user_regs.general.r15 = 0;
-        data.user_regs.general.rbp = 0;
+    let loaded = {
+        let mut p = proc.lock();
+        p.set_only_thread(&current);
+
+        let argv: Vec<&str> = argv.iter().map(String::as_ref).collect();
+        let envp: Vec<&str> = envp.iter().map(String::as_ref).collect();
+
+        let res = p.execve(executable, &argv, &envp).map_err(|_| ENOEXEC);
+        audit::record(
+            Some(&p),
+            AuditEvent::Exec {
+                path: String::from(path),
+            },
+            res,
+        );
+        if res.is_err() {
+            // the segments could not be read or overlap, the old address space is gone
+            warn!("PID {}: failed to load {}", p.pid, path);
+        }
+
+        let main_thread_lock = p.main_thread.upgrade().unwrap();
+        let mut main_thread = main_thread_lock.lock();
+
+        // load_from_file already sets rip, rsp and (argc)rdi, (argv)rsi, (envp)rdx
+        if let ThreadInner::User(data) = &mut main_thread.inner {
+            data.user_regs.general.rax = 0;
+            data.user_regs.general.rbx = 0;
+            data.user_regs.general.rcx = 0;
+            data.user_regs.general.r8 = 0;
+            data.user_regs.general.r9 = 0;
+            data.user_regs.general.r10 = 0;
+            data.user_regs.general.r11 = 0;
+            data.user_regs.general.r12 = 0;
+            data.user_regs.general.r13 = 0;
+            data.user_regs.general.r14 = 0;
+            data.user_regs.general.r15 = 0;
+            data.user_regs.general.rbp = 0;
+        }
+
+        res.is_ok()
+    };
+
+    // the parent is woken up with interrupts enabled and no locks held because it locks
+    // the processes while checking its children
+    enable_interrupts();
+    if !loaded {
+        // there is no program left to return to
+        exit::kill(proc, SIGKILL);
     }
+    proc::mark_execed(&proc);
 
     Ok(())
 }
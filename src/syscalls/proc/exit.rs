@@ -0,0 +1,92 @@
+use alloc::sync::Arc;
+
+use crate::{
+    arch::x86_64::{
+        disable_interrupts,
+        usercopy::{Out, UserPtr},
+    },
+    framebuffer,
+    scheduler::{
+        proc::{self, Process},
+        thread::ThreadInner,
+        SCHEDULER,
+    },
+    sync::Mutex,
+};
+
+use super::futex;
+
+/// Terminates the process and every thread of it, it stays a zombie holding its PID and wait
+/// status until its parent waits for it. The kernel stacks of the threads are freed by the
+/// reaper thread.
+pub fn exit(proc: Arc<Mutex<Process>>, status: i32) -> ! {
+    terminate(proc, (status & 0xff) << 8)
+}
+
+/// Terminates the calling thread, the process exits with __status__ if it was the last one
+pub fn exit_thread(proc: Arc<Mutex<Process>>, status: i32) -> ! {
+    let last = !proc.lock().remove_live_thread();
+    if last {
+        exit(proc, status);
+    }
+
+    exit_current_thread(proc)
+}
+
+/// Terminates the process as if it was killed by __signal__
+pub fn kill(proc: Arc<Mutex<Process>>, signal: i32) -> ! {
+    terminate(proc, signal & 0x7f)
+}
+
+fn terminate(proc: Arc<Mutex<Process>>, wait_status: i32) -> ! {
+    if !proc::kill_other_threads(&proc) {
+        // another thread is terminating the process, it waits for this one to exit
+        let removed = proc.lock().remove_live_thread();
+        assert!(removed);
+        exit_current_thread(proc);
+    }
+
+    let pid = {
+        let mut p = proc.lock();
+        if p.pid == 1 {
+            panic!("init terminated with wait status {:#x}", wait_status);
+        }
+
+        p.release_resources();
+        p.pid
+    };
+
+    proc::reparent_children(pid);
+    // the process might have taken over the framebuffer, if it did not this fails
+    let _ = framebuffer::release_ownership(pid);
+
+    proc::mark_exited(&proc, wait_status);
+
+    // remove_current_thread never returns so nothing on the stack would be dropped
+    drop(proc);
+    disable_interrupts();
+    SCHEDULER.remove_current_thread();
+}
+
+/// Removes the calling thread from a process that keeps running, the live threads of the
+/// process have to be updated already
+fn exit_current_thread(proc: Arc<Mutex<Process>>) -> ! {
+    let clear_child_tid = {
+        let thread = SCHEDULER.get_current_thread().expect("No threads running");
+        let thread = thread.lock();
+        match &thread.inner {
+            ThreadInner::User(data) => data.clear_child_tid,
+            ThreadInner::Kernel(_) => unreachable!(),
+        }
+    };
+
+    // a thread joining this one waits on the TID with FUTEX_WAIT, the memory could have been
+    // unmapped by userspace so the errors are ignored
+    if clear_child_tid != 0 && UserPtr::<u32, Out>::new(clear_child_tid).write(&0).is_ok() {
+        let _ = futex::futex_wake(proc.clone(), clear_child_tid, 1);
+    }
+
+    drop(proc);
+    disable_interrupts();
+    SCHEDULER.remove_current_thread();
+}
@@ -0,0 +1,231 @@
+//! Futexes, a thread blocks on a word of its memory until another thread changes the word and
+//! wakes it up. The waiters are kept in a hash table keyed by the physical address of the
+//! word, a futex in memory shared between processes is the same futex in all of them.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    arch::x86_64::{
+        paging::PageFlags,
+        usercopy::{In, UserPtr},
+    },
+    mm::{
+        phys::{FRAME_SIZE, PAGE_DESCRIPTOR_MANAGER},
+        virt::PAGE_SIZE_4KIB,
+        PhysAddr, VirtAddr,
+    },
+    posix::{
+        errno::{Errno, EAGAIN, EFAULT, EINTR, EINVAL, ENOSYS, ETIMEDOUT},
+        FUTEX_CLOCK_REALTIME, FUTEX_PRIVATE_FLAG, FUTEX_WAIT, FUTEX_WAKE,
+    },
+    scheduler::{
+        proc::{self, MappedRegionFlags, Process},
+        wait_queue::{Waiter, WakeReason},
+    },
+    sync::{InterruptMutex, Mutex},
+    time,
+};
+
+/// Number of buckets of the hash table, futexes in the same bucket share its lock
+const FUTEX_BUCKETS: usize = 64;
+
+/// A thread waiting on the futex __key__
+struct FutexWaiter {
+    key: PhysAddr,
+    waiter: Arc<Waiter>,
+}
+
+type FutexBucket = InterruptMutex<VecDeque<FutexWaiter>>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BUCKET: FutexBucket = InterruptMutex::new(VecDeque::new());
+static FUTEX_TABLE: [FutexBucket; FUTEX_BUCKETS] = [EMPTY_BUCKET; FUTEX_BUCKETS];
+
+fn bucket(key: PhysAddr) -> &'static FutexBucket {
+    // the words are 4 byte aligned
+    &FUTEX_TABLE[(key.get() >> 2) as usize % FUTEX_BUCKETS]
+}
+
+/// Returns the key of the futex word at __addr__, the physical address of the word. The word
+/// has to be in a region of the process userspace can access and its page has to be present.
+fn futex_key(proc: &Process, addr: u64) -> Result<PhysAddr, Errno> {
+    let addr = addr as usize;
+    let region = proc
+        .mapped_regions()
+        .iter()
+        .find(|region| region.start() <= addr && addr + 4 <= region.end())
+        .ok_or(EFAULT)?;
+    if region.flags().contains(MappedRegionFlags::NO_ACCESS) {
+        return Err(EFAULT);
+    }
+
+    let pml4 = proc.pml4();
+    let page = VirtAddr::new((addr - addr % PAGE_SIZE_4KIB as usize) as u64);
+    let (_, flags) = pml4.get_page_entry_from_virt(page).ok_or(EFAULT)?;
+    if !flags.contains(PageFlags::PRESENT) {
+        return Err(EFAULT);
+    }
+
+    // the page gets its own frame on the first write, a key taken before that would not be
+    // the key of the word anymore
+    if flags.contains(PageFlags::COPY_ON_WRITE) {
+        pml4.break_copy_on_write(page);
+    }
+
+    let (phys, _) = pml4
+        .get_page_entry_from_virt(VirtAddr::new(addr as u64))
+        .ok_or(EFAULT)?;
+    Ok(phys)
+}
+
+/// The frame of a futex word, it is not freed while the word is pinned even if userspace
+/// unmaps the page
+struct PinnedWord {
+    key: PhysAddr,
+}
+
+impl PinnedWord {
+    /// The page of the word has to be mapped by the process, which has to be locked
+    fn new(key: PhysAddr) -> PinnedWord {
+        PAGE_DESCRIPTOR_MANAGER
+            .lock()
+            .inc_used_count(Self::frame(key));
+        PinnedWord { key }
+    }
+
+    fn frame(key: PhysAddr) -> PhysAddr {
+        PhysAddr::new(key.get() - key.get() % FRAME_SIZE as u64)
+    }
+
+    /// Reads the word through the kernel mapping of its frame, unlike a usercopy it can not
+    /// fault so the word can be read with a lock held and interrupts disabled
+    fn read(&self) -> u32 {
+        let word = unsafe { &*(self.key.virt_addr().get() as *const AtomicU32) };
+        word.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for PinnedWord {
+    fn drop(&mut self) {
+        PAGE_DESCRIPTOR_MANAGER
+            .lock()
+            .dec_used_count(Self::frame(self.key));
+    }
+}
+
+/// Pins the futex word at __addr__, reading it first maps the page if it is allocated on
+/// access
+fn pin_futex(proc: &Arc<Mutex<Process>>, addr: u64) -> Result<PinnedWord, Errno> {
+    if addr % 4 != 0 {
+        return Err(EINVAL);
+    }
+
+    UserPtr::<u32, In>::new(addr).read()?;
+    // the frame is pinned before the process is unlocked, the page can not be unmapped
+    // in between
+    let p = proc.lock();
+    let key = futex_key(&p, addr)?;
+    Ok(PinnedWord::new(key))
+}
+
+/// Blocks until the futex at __addr__ is woken if it still holds __val__, otherwise returns
+/// EAGAIN. Returns ETIMEDOUT if __timeout__ milliseconds pass first and EINTR if a signal is
+/// sent to the process. A thread can also return without being woken, userspace checks the
+/// word again anyway.
+pub fn futex_wait(
+    proc: Arc<Mutex<Process>>,
+    addr: u64,
+    val: u32,
+    timeout: Option<u64>,
+) -> Result<(), Errno> {
+    let word = pin_futex(&proc, addr)?;
+    let key = word.key;
+    let pid = proc.lock().pid;
+    drop(proc);
+
+    let deadline = timeout.map(|ms| time::elapsed().as_milliseconds().saturating_add(ms));
+    let waiter = Arc::new(Waiter::new());
+    proc::register_signal_waiter(&waiter);
+
+    {
+        // the word is compared with the bucket locked, a thread that changes it and wakes the
+        // futex afterwards finds this one on the bucket
+        let mut waiters = bucket(key).lock();
+        if word.read() != val {
+            return Err(EAGAIN);
+        }
+
+        waiters.push_back(FutexWaiter {
+            key,
+            waiter: waiter.clone(),
+        });
+    }
+    drop(word);
+
+    let reason = match proc::pending_signal(pid) {
+        Some(_) => WakeReason::Woken,
+        None => waiter.block_until(deadline),
+    };
+
+    let mut waiters = bucket(key).lock();
+    match waiters
+        .iter()
+        .position(|entry| Arc::ptr_eq(&entry.waiter, &waiter))
+    {
+        Some(pos) => {
+            waiters.remove(pos);
+        }
+        // it was taken off the bucket so it was woken by futex_wake
+        None => return Ok(()),
+    }
+    drop(waiters);
+
+    if proc::pending_signal(pid).is_some() {
+        return Err(EINTR);
+    }
+
+    match reason {
+        WakeReason::TimedOut => Err(ETIMEDOUT),
+        WakeReason::Woken => Ok(()),
+    }
+}
+
+/// Wakes at most __count__ threads waiting on the futex at __addr__, the ones that have been
+/// waiting the longest first. Returns the number of threads woken.
+pub fn futex_wake(proc: Arc<Mutex<Process>>, addr: u64, count: usize) -> Result<usize, Errno> {
+    let key = pin_futex(&proc, addr)?.key;
+    drop(proc);
+
+    let mut woken = 0;
+    bucket(key).lock().retain(|entry| {
+        if woken == count || entry.key != key {
+            return true;
+        }
+
+        entry.waiter.wake();
+        woken += 1;
+        false
+    });
+
+    Ok(woken)
+}
+
+/// The futex syscall, only FUTEX_WAIT and FUTEX_WAKE are supported. Every futex is looked up
+/// by its physical address so FUTEX_PRIVATE_FLAG makes no difference, the timeout of
+/// FUTEX_WAIT is relative so FUTEX_CLOCK_REALTIME does not either.
+pub fn futex(
+    proc: Arc<Mutex<Process>>,
+    addr: u64,
+    op: u32,
+    val: u32,
+    timeout: Option<u64>,
+) -> Result<usize, Errno> {
+    match op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) {
+        FUTEX_WAIT => futex_wait(proc, addr, val, timeout).map(|_| 0),
+        // a negative count wakes nobody
+        FUTEX_WAKE => futex_wake(proc, addr, usize::try_from(val as i32).unwrap_or(0)),
+        _ => Err(ENOSYS),
+    }
+}
@@ -1,9 +1,9 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
     posix::errno::Errno,
     scheduler::{self, proc::Process},
+    sync::Mutex,
 };
 
 pub fn getpgid(proc: Arc<Mutex<Process>>, pid: isize) -> Result<usize, Errno> {
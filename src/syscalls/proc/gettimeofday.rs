@@ -1,9 +1,9 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
     posix::{errno::Errno, Timeval},
     scheduler::proc::Process,
+    sync::Mutex,
     time,
 };
 
@@ -0,0 +1,82 @@
+use alloc::{boxed::Box, sync::Arc};
+
+use crate::{
+    arch::x86_64::{
+        tss::{self, IO_BITMAP_DENY_ALL, IO_PORT_COUNT},
+        Rflags,
+    },
+    posix::errno::{Errno, EINVAL, EPERM},
+    scheduler::{proc::Process, thread::ThreadInner, SCHEDULER},
+    sync::Mutex,
+};
+
+/// Allows or denies access to `count` ports starting at `from` from userspace, only root
+/// can allow access
+pub fn ioperm(
+    proc: Arc<Mutex<Process>>,
+    from: usize,
+    count: usize,
+    turn_on: bool,
+) -> Result<(), Errno> {
+    match from.checked_add(count) {
+        Some(end) if end <= IO_PORT_COUNT => {}
+        _ => return Err(EINVAL),
+    }
+
+    if turn_on && proc.lock().euid != 0 {
+        return Err(EPERM);
+    }
+
+    // the ports and the privilege level are per thread
+    let thread_lock = SCHEDULER.get_current_thread().expect("No threads running");
+    let mut thread = thread_lock.lock();
+
+    if let ThreadInner::User(data) = &mut thread.inner {
+        if data.io_bitmap.is_none() && !turn_on {
+            return Ok(());
+        }
+
+        let bitmap = data
+            .io_bitmap
+            .get_or_insert_with(|| Box::new(IO_BITMAP_DENY_ALL));
+        tss::io_bitmap_set_range(bitmap, from, count, turn_on);
+
+        if bitmap.iter().all(|&byte| byte == 0xFF) {
+            data.io_bitmap = None;
+        }
+
+        // the thread is running so the TSS has to be updated now
+        unsafe {
+            tss::load_io_bitmap(data.io_bitmap.as_deref());
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the I/O privilege level of the calling thread, level 3 allows every port. Only root
+/// can raise it.
+pub fn iopl(proc: Arc<Mutex<Process>>, level: usize) -> Result<(), Errno> {
+    if level > 3 {
+        return Err(EINVAL);
+    }
+
+    let is_root = proc.lock().euid == 0;
+
+    // the ports and the privilege level are per thread
+    let thread_lock = SCHEDULER.get_current_thread().expect("No threads running");
+    let mut thread = thread_lock.lock();
+
+    // the syscall handler copies the level into RFLAGS when returning to userspace
+    if let ThreadInner::User(data) = &mut thread.inner {
+        let iopl = Rflags::IOPL.bits();
+        let current = (data.user_regs.rflags & iopl) >> 12;
+        if level as u64 > current && !is_root {
+            return Err(EPERM);
+        }
+
+        data.user_regs.rflags = (data.user_regs.rflags & !iopl) | ((level as u64) << 12);
+    }
+
+    Ok(())
+}
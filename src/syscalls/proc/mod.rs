@@ -1,7 +1,16 @@
 pub mod archctl;
+pub mod clock_gettime;
 pub mod clone;
 pub mod execve;
+pub mod exit;
+pub mod futex;
 pub mod getpgid;
 pub mod gettimeofday;
+pub mod ioperm;
+pub mod nanosleep;
 pub mod pid;
+pub mod rook_info;
 pub mod setpgid;
+pub mod setuid;
+pub mod sysconf;
+pub mod wait4;
@@ -0,0 +1,65 @@
+use alloc::sync::Arc;
+
+use crate::{
+    posix::{
+        errno::{Errno, EINVAL},
+        Timespec, CLOCK_MONOTONIC, CLOCK_REALTIME, TIMER_ABSTIME,
+    },
+    scheduler::{proc::Process, SCHEDULER},
+    sync::Mutex,
+    time::{self, NSEC_PER_MSEC, NSEC_PER_SEC},
+};
+
+/// Converts __ts__ to milliseconds, rounded up so a sleep is never shorter than asked
+pub fn timespec_to_ms(ts: &Timespec) -> Result<u64, Errno> {
+    let (sec, nsec) = (ts.tv_sec, ts.tv_nsec);
+    if nsec >= NSEC_PER_SEC {
+        return Err(EINVAL);
+    }
+
+    let ms = sec.checked_mul(1000).ok_or(EINVAL)?;
+    Ok(ms.saturating_add(nsec.div_ceil(NSEC_PER_MSEC)))
+}
+
+pub fn nanosleep(_proc: Arc<Mutex<Process>>, req: &Timespec) -> Result<(), Errno> {
+    let ms = timespec_to_ms(req)?;
+    SCHEDULER.sleep_current(ms);
+    Ok(())
+}
+
+/// Sleeps for the duration in __req__ or, if TIMER_ABSTIME is set, until __clock_id__
+/// reaches __req__
+pub fn clock_nanosleep(
+    _proc: Arc<Mutex<Process>>,
+    clock_id: u32,
+    flags: u32,
+    req: &Timespec,
+) -> Result<(), Errno> {
+    if flags & !TIMER_ABSTIME != 0 {
+        return Err(EINVAL);
+    }
+
+    let ms = timespec_to_ms(req)?;
+    if flags & TIMER_ABSTIME == 0 {
+        if clock_id != CLOCK_REALTIME && clock_id != CLOCK_MONOTONIC {
+            return Err(EINVAL);
+        }
+
+        SCHEDULER.sleep_current(ms);
+        return Ok(());
+    }
+
+    // deadlines are kept on the monotonic clock, the realtime clock only adds the boot time
+    let deadline = match clock_id {
+        CLOCK_MONOTONIC => ms,
+        CLOCK_REALTIME => {
+            let boot_time =
+                time::global_time().as_milliseconds() - time::elapsed().as_milliseconds();
+            ms.saturating_sub(boot_time)
+        }
+        _ => return Err(EINVAL),
+    };
+
+    time::sleep_until(deadline);
+    Ok(())
+}
@@ -0,0 +1,15 @@
+use alloc::sync::Arc;
+
+use crate::{kconfig, posix::errno::Errno, scheduler::proc::Process, sync::Mutex};
+
+/// Copies the kernel configuration in the format of /proc/config to the buffer, returns
+/// the length of the whole text so the caller can retry with a bigger buffer if it did not fit
+pub fn rook_info(_proc: Arc<Mutex<Process>>, buff: &mut [u8]) -> Result<usize, Errno> {
+    let text = kconfig::config_text();
+    let text = text.as_bytes();
+
+    let size = usize::min(buff.len(), text.len());
+    buff[..size].copy_from_slice(&text[..size]);
+
+    Ok(text.len())
+}
@@ -1,9 +1,9 @@
 use alloc::sync::Arc;
-use spin::Mutex;
 
 use crate::{
     posix::errno::Errno,
     scheduler::proc::{get_process, Process},
+    sync::Mutex,
 };
 
 pub fn setpgid(proc: Arc<Mutex<Process>>, pid: usize, pgid: usize) -> Result<(), Errno> {
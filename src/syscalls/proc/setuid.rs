@@ -0,0 +1,28 @@
+use alloc::sync::Arc;
+
+use crate::{
+    audit::{self, AuditEvent},
+    posix::errno::{Errno, EPERM},
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+/// Sets both user IDs if the caller is root, otherwise only the effective user ID can be
+/// set and only back to the real one
+pub fn setuid(proc: Arc<Mutex<Process>>, uid: usize) -> Result<(), Errno> {
+    let mut p = proc.lock();
+
+    let res = if p.euid == 0 {
+        p.uid = uid;
+        p.euid = uid;
+        Ok(())
+    } else if uid == p.uid {
+        p.euid = uid;
+        Ok(())
+    } else {
+        Err(EPERM)
+    };
+
+    audit::record(Some(&p), AuditEvent::SetUid { uid }, res);
+    res
+}
@@ -0,0 +1,12 @@
+use alloc::sync::Arc;
+
+use crate::{
+    limits,
+    posix::errno::{Errno, EINVAL},
+    scheduler::proc::Process,
+    sync::Mutex,
+};
+
+pub fn sysconf(_proc: Arc<Mutex<Process>>, name: usize) -> Result<usize, Errno> {
+    limits::sysconf(name).ok_or(EINVAL)
+}
@@ -0,0 +1,49 @@
+use alloc::sync::Arc;
+
+use crate::{
+    posix::{
+        errno::{Errno, ECHILD, EINVAL},
+        WaitOptions,
+    },
+    scheduler::proc::{self, Process},
+    sync::Mutex,
+};
+
+/// Waits for a child to exit and returns its PID and wait status. __pid__ selects the children:
+/// -1 means any child, 0 any child in the process group of the caller, less than -1 any child
+/// in the process group -__pid__ and greater than 0 the child with that PID.
+/// Returns a PID of 0 if WNOHANG is set and no matching child has exited yet.
+pub fn wait4(proc: Arc<Mutex<Process>>, pid: isize, options: u32) -> Result<(usize, i32), Errno> {
+    let options = WaitOptions::from_bits(options).ok_or(EINVAL)?;
+    if options.intersects(WaitOptions::WUNTRACED | WaitOptions::WCONTINUED) {
+        // there are no signals so children are never stopped or continued
+        warn!("wait4: WUNTRACED and WCONTINUED are ignored");
+    }
+
+    let (parent_pid, parent_pgid) = {
+        let p = proc.lock();
+        (p.pid, p.pgid)
+    };
+    // the lock of the caller is not held while waiting because the child locks the processes
+    drop(proc);
+
+    let nohang = options.contains(WaitOptions::WNOHANG);
+    let res = match pid {
+        -1 => proc::wait_for_child(parent_pid, |_| true, nohang),
+        0 => proc::wait_for_child(parent_pid, |child| child.pgid == parent_pgid, nohang),
+        pid if pid < -1 => {
+            let pgid = -pid as usize;
+            proc::wait_for_child(parent_pid, |child| child.pgid == pgid, nohang)
+        }
+        pid => {
+            let pid = pid as usize;
+            proc::wait_for_child(parent_pid, |child| child.pid == pid, nohang)
+        }
+    };
+
+    match res {
+        Ok(Some(child)) => Ok(child),
+        Ok(None) => Ok((0, 0)),
+        Err(()) => Err(ECHILD),
+    }
+}
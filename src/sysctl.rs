@@ -0,0 +1,366 @@
+//! Runtime tunables of the kernel, every registered key is a file in /proc/sys.
+//! The key kernel/log_level is the file /proc/sys/kernel/log_level.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+use spin::Mutex;
+
+use crate::{audit, latency, limits, logger, mm, scheduler};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysctlValue {
+    /// Written and read as 0 or 1
+    Bool(bool),
+    Int(i64),
+}
+
+impl SysctlValue {
+    /// Parses __text__ as a value of the same type as self
+    fn parse_same_type(&self, text: &str) -> Option<SysctlValue> {
+        let text = text.trim();
+        match self {
+            SysctlValue::Bool(_) => match text {
+                "0" => Some(SysctlValue::Bool(false)),
+                "1" => Some(SysctlValue::Bool(true)),
+                _ => None,
+            },
+            SysctlValue::Int(_) => text.parse().ok().map(SysctlValue::Int),
+        }
+    }
+}
+
+impl fmt::Display for SysctlValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SysctlValue::Bool(val) => write!(f, "{}", *val as u8),
+            SysctlValue::Int(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SysctlError {
+    AlreadyExists,
+    /// The key is a file of a directory that is already a key or the other way around
+    InvalidName,
+    /// The value could not be parsed or was rejected by the write callback
+    InvalidValue,
+    ReadOnly,
+}
+
+/// Returns the current value of a sysctl
+pub type SysctlRead = fn() -> SysctlValue;
+
+/// Changes the value of a sysctl, the value always has the type `SysctlRead` returns
+pub type SysctlWrite = fn(SysctlValue) -> Result<(), SysctlError>;
+
+struct Sysctl {
+    name: String,
+    read: SysctlRead,
+    write: Option<SysctlWrite>,
+}
+
+static SYSCTLS: Mutex<Vec<Sysctl>> = Mutex::new(Vec::new());
+
+/// An entry of a directory in /proc/sys
+pub struct SysctlDirEntry {
+    pub name: String,
+    /// The index of the sysctl if the entry is a file, directories have none
+    pub sysctl: Option<usize>,
+}
+
+/// Registers a sysctl, __name__ is a path relative to /proc/sys. Read-only sysctls have
+/// no write callback.
+pub fn register(
+    name: &str,
+    read: SysctlRead,
+    write: Option<SysctlWrite>,
+) -> Result<(), SysctlError> {
+    if name.is_empty() || name.split('/').any(str::is_empty) {
+        return Err(SysctlError::InvalidName);
+    }
+
+    let mut sysctls = SYSCTLS.lock();
+    if sysctls.iter().any(|sysctl| sysctl.name == name) {
+        return Err(SysctlError::AlreadyExists);
+    }
+
+    let dir_prefix = format!("{}/", name);
+    let conflicts = sysctls.iter().any(|sysctl| {
+        sysctl.name.starts_with(&dir_prefix) || name.starts_with(&format!("{}/", sysctl.name))
+    });
+    if conflicts {
+        return Err(SysctlError::InvalidName);
+    }
+
+    sysctls.push(Sysctl {
+        name: name.to_string(),
+        read,
+        write,
+    });
+
+    Ok(())
+}
+
+/// Returns the index of the sysctl named __name__
+pub fn find(name: &str) -> Option<usize> {
+    SYSCTLS.lock().iter().position(|sysctl| sysctl.name == name)
+}
+
+/// Returns the entries of the directory __dir__, the empty string is /proc/sys itself.
+/// Returns None if there is no such directory.
+pub fn read_dir(dir: &str) -> Option<Vec<SysctlDirEntry>> {
+    let sysctls = SYSCTLS.lock();
+    let mut entries: Vec<SysctlDirEntry> = Vec::new();
+
+    for (idx, sysctl) in sysctls.iter().enumerate() {
+        let rest = if dir.is_empty() {
+            sysctl.name.as_str()
+        } else {
+            match sysctl
+                .name
+                .strip_prefix(dir)
+                .and_then(|s| s.strip_prefix('/'))
+            {
+                Some(rest) => rest,
+                None => continue,
+            }
+        };
+
+        let entry = match rest.split_once('/') {
+            Some((subdir, _)) => {
+                if entries.iter().any(|entry| entry.name == subdir) {
+                    continue;
+                }
+
+                SysctlDirEntry {
+                    name: subdir.to_string(),
+                    sysctl: None,
+                }
+            }
+            None => SysctlDirEntry {
+                name: rest.to_string(),
+                sysctl: Some(idx),
+            },
+        };
+        entries.push(entry);
+    }
+
+    if entries.is_empty() && !dir.is_empty() {
+        return None;
+    }
+
+    Some(entries)
+}
+
+/// Returns the index of the first sysctl in the directory __dir__ or its subdirectories,
+/// None if there is no such directory. Directories have no index of their own so this
+/// identifies them instead.
+pub fn dir_id(dir: &str) -> Option<usize> {
+    if dir.is_empty() {
+        return Some(0);
+    }
+
+    SYSCTLS.lock().iter().position(|sysctl| {
+        sysctl
+            .name
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+pub fn is_writable(idx: usize) -> bool {
+    SYSCTLS.lock()[idx].write.is_some()
+}
+
+/// Returns the value of the sysctl as it appears in its file
+pub fn read_text(idx: usize) -> String {
+    let read = SYSCTLS.lock()[idx].read;
+    format!("{}\n", read())
+}
+
+/// Parses __text__ and sets the value of the sysctl to it
+pub fn write_text(idx: usize, text: &str) -> Result<(), SysctlError> {
+    let (read, write) = {
+        let sysctls = SYSCTLS.lock();
+        (sysctls[idx].read, sysctls[idx].write)
+    };
+
+    // the callbacks are called with the sysctls unlocked so they can use them too
+    let write = write.ok_or(SysctlError::ReadOnly)?;
+    let val = read()
+        .parse_same_type(text)
+        .ok_or(SysctlError::InvalidValue)?;
+    write(val)
+}
+
+fn int_value(val: SysctlValue) -> i64 {
+    match val {
+        SysctlValue::Int(val) => val,
+        SysctlValue::Bool(_) => unreachable!(),
+    }
+}
+
+/// Registers the sysctls of the core kernel, drivers may register their own later
+pub fn init() {
+    register(
+        "kernel/log_level",
+        || SysctlValue::Int(logger::log_level() as i64),
+        Some(|val| {
+            let level = u8::try_from(int_value(val)).map_err(|_| SysctlError::InvalidValue)?;
+            match logger::set_log_level(level) {
+                true => Ok(()),
+                false => Err(SysctlError::InvalidValue),
+            }
+        }),
+    )
+    .unwrap();
+
+    register(
+        "kernel/serial_log",
+        || SysctlValue::Bool(logger::serial_output_enabled()),
+        Some(|val| {
+            logger::set_serial_output(val == SysctlValue::Bool(true));
+            Ok(())
+        }),
+    )
+    .unwrap();
+
+    register(
+        "kernel/sched_tick_trace",
+        || SysctlValue::Bool(scheduler::tick_trace_enabled()),
+        Some(|val| {
+            scheduler::set_tick_trace(val == SysctlValue::Bool(true));
+            Ok(())
+        }),
+    )
+    .unwrap();
+
+    register(
+        "kernel/sched_slice_us",
+        || SysctlValue::Int(scheduler::time_slice_us() as i64),
+        Some(|val| {
+            let us = usize::try_from(int_value(val)).map_err(|_| SysctlError::InvalidValue)?;
+            match scheduler::set_time_slice_us(us) {
+                true => Ok(()),
+                false => Err(SysctlError::InvalidValue),
+            }
+        }),
+    )
+    .unwrap();
+
+    register(
+        "kernel/atomic_sleep_check",
+        || SysctlValue::Int(scheduler::atomic_sleep_check() as i64),
+        Some(|val| {
+            let mode = u8::try_from(int_value(val)).map_err(|_| SysctlError::InvalidValue)?;
+            match scheduler::set_atomic_sleep_check(mode) {
+                true => Ok(()),
+                false => Err(SysctlError::InvalidValue),
+            }
+        }),
+    )
+    .unwrap();
+
+    register(
+        "kernel/audit",
+        || SysctlValue::Bool(audit::is_enabled()),
+        Some(|val| {
+            audit::set_enabled(val == SysctlValue::Bool(true));
+            Ok(())
+        }),
+    )
+    .unwrap();
+
+    register(
+        "kernel/latency_stats",
+        || SysctlValue::Bool(latency::enabled()),
+        Some(|val| {
+            latency::set_enabled(val == SysctlValue::Bool(true));
+            Ok(())
+        }),
+    )
+    .unwrap();
+
+    register(
+        "kernel/latency_stats_reset",
+        || SysctlValue::Bool(false),
+        Some(|val| {
+            if val == SysctlValue::Bool(true) {
+                latency::reset();
+            }
+            Ok(())
+        }),
+    )
+    .unwrap();
+
+    register(
+        "fs/open_max",
+        || SysctlValue::Int(limits::open_max() as i64),
+        Some(|val| {
+            let max = usize::try_from(int_value(val)).map_err(|_| SysctlError::InvalidValue)?;
+            match limits::set_open_max(max) {
+                true => Ok(()),
+                false => Err(SysctlError::InvalidValue),
+            }
+        }),
+    )
+    .unwrap();
+
+    register(
+        "vm/stack_max_kb",
+        || SysctlValue::Int((limits::stack_max() / 1024) as i64),
+        Some(|val| {
+            let kb = usize::try_from(int_value(val)).map_err(|_| SysctlError::InvalidValue)?;
+            match limits::set_stack_max(kb.saturating_mul(1024)) {
+                true => Ok(()),
+                false => Err(SysctlError::InvalidValue),
+            }
+        }),
+    )
+    .unwrap();
+
+    register(
+        "vm/kernel_heap_max_kb",
+        || SysctlValue::Int((mm::kalloc::heap_limit() / 1024) as i64),
+        Some(|val| {
+            let kb = usize::try_from(int_value(val)).map_err(|_| SysctlError::InvalidValue)?;
+            match mm::kalloc::set_heap_limit(kb.saturating_mul(1024)) {
+                true => Ok(()),
+                false => Err(SysctlError::InvalidValue),
+            }
+        }),
+    )
+    .unwrap();
+
+    // reads as 0, writing 1 gives the free end of the kernel heap back
+    register(
+        "vm/kernel_heap_trim",
+        || SysctlValue::Bool(false),
+        Some(|val| {
+            if val == SysctlValue::Bool(true) {
+                let released = mm::kalloc::trim_heap();
+                log!("KALLOC: trimmed {} KiB from the heap", released / 1024);
+            }
+            Ok(())
+        }),
+    )
+    .unwrap();
+
+    register(
+        "vm/overcommit_policy",
+        || SysctlValue::Int(mm::overcommit_policy() as i64),
+        Some(|val| {
+            let policy = u8::try_from(int_value(val)).map_err(|_| SysctlError::InvalidValue)?;
+            match mm::set_overcommit_policy(policy) {
+                true => Ok(()),
+                false => Err(SysctlError::InvalidValue),
+            }
+        }),
+    )
+    .unwrap();
+}
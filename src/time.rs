@@ -1,6 +1,21 @@
-use alloc::fmt;
+use core::{
+    cmp::Reverse,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::sync::InterruptMutex;
+use alloc::{collections::BinaryHeap, fmt};
+
+use crate::{arch::x86_64::hpet, kgdb, kmsg, scheduler::wait_queue::Waiter, sync::InterruptMutex};
+
+/// Timer interrupts per second on every CPU, both the PIT and the local APIC timers run
+/// at this rate
+pub const TICK_FREQUENCY: usize = 1000;
+
+/// Length of a timer tick in microseconds
+pub const TICK_US: usize = 1_000_000 / TICK_FREQUENCY;
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+pub const NSEC_PER_MSEC: u64 = 1_000_000;
 
 // TODO: use a mutex or something?
 static mut BOOT_TIME: u64 = 0;
@@ -11,6 +26,19 @@ pub struct Time {
     pub milliseconds: u64, // between 0 and 1000
 }
 
+impl Time {
+    fn from_nanoseconds(ns: u64) -> Time {
+        Time {
+            seconds: ns / NSEC_PER_SEC,
+            milliseconds: ns % NSEC_PER_SEC / NSEC_PER_MSEC,
+        }
+    }
+
+    pub fn as_milliseconds(&self) -> u64 {
+        self.seconds * 1000 + self.milliseconds
+    }
+}
+
 impl fmt::Display for Time {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let seconds = self.seconds + self.milliseconds / 1000;
@@ -24,23 +52,115 @@ static SYSTEM_CLOCK: InterruptMutex<Time> = InterruptMutex::new(Time {
     milliseconds: 0,
 });
 
+/// Waiters woken when the system clock reaches their deadline in milliseconds with the
+/// sequence of the wait the timeout is for, the earliest deadline is on the top. They are
+/// stored by address because the tick must not free memory. The owner of a waiter removes
+/// it with `cancel_waiter_timeout` before dropping it.
+static WAITER_TIMEOUTS: InterruptMutex<BinaryHeap<Reverse<(u64, usize, u64)>>> =
+    InterruptMutex::new(BinaryHeap::new());
+
+/// Value of the tick clock in nanoseconds when the HPET took over as the clock source,
+/// the HPET counter starts from zero
+static HPET_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
 pub fn init(boot_time: u64) {
     unsafe {
         BOOT_TIME = boot_time;
     }
 }
 
+/// Makes the HPET the source of the monotonic clock if the system has one, otherwise the
+/// clock only advances with the timer ticks. It has to be called before the APs are started.
+pub fn init_clock_source() {
+    HPET_OFFSET_NS.store(tick_clock_ns(), Ordering::Relaxed);
+    if hpet::init() {
+        log!("TIME: using the HPET as the clock source");
+    } else {
+        log!("TIME: using the timer ticks as the clock source");
+    }
+}
+
+/// Advances the tick clock, it is called on every tick of the PIT
 pub fn advance(ms: u64) {
-    let mut clock = SYSTEM_CLOCK.lock();
-    clock.milliseconds += ms;
-    clock.seconds += clock.milliseconds / 1000;
-    clock.milliseconds %= 1000;
+    {
+        let mut clock = SYSTEM_CLOCK.lock();
+        clock.milliseconds += ms;
+        clock.seconds += clock.milliseconds / 1000;
+        clock.milliseconds %= 1000;
+    }
+
+    expire_timeouts(elapsed().as_milliseconds());
+    kmsg::wake_readers();
+    kgdb::poll_break_in();
+}
+
+fn tick_clock_ns() -> u64 {
+    SYSTEM_CLOCK.lock().as_milliseconds() * NSEC_PER_MSEC
+}
+
+/// Returns the nanoseconds since boot, the clock never goes backwards and it is the same on
+/// every CPU
+pub fn monotonic_ns() -> u64 {
+    match hpet::nanoseconds() {
+        Some(ns) => HPET_OFFSET_NS.load(Ordering::Relaxed) + ns,
+        None => tick_clock_ns(),
+    }
+}
+
+/// Returns the nanoseconds since the Unix epoch
+pub fn realtime_ns() -> u64 {
+    let boot_time = unsafe { BOOT_TIME };
+    boot_time * NSEC_PER_SEC + monotonic_ns()
+}
+
+/// Expires the timeouts whose deadline is at or before __now__
+fn expire_timeouts(now: u64) {
+    let mut timeouts = WAITER_TIMEOUTS.lock();
+    while let Some(&Reverse((deadline, waiter, sequence))) = timeouts.peek() {
+        if deadline > now {
+            break;
+        }
+
+        timeouts.pop();
+        // the owner can not drop the waiter while it is on the list
+        unsafe { &*(waiter as *const Waiter) }.expire(sequence);
+    }
+}
+
+/// Expires the wait __sequence__ of __waiter__ once the system clock reaches __deadline__
+/// milliseconds, right away if it already has. It is armed by `Waiter::block_until`.
+pub fn add_waiter_timeout(waiter: &Waiter, deadline: u64, sequence: u64) {
+    let mut timeouts = WAITER_TIMEOUTS.lock();
+    if elapsed().as_milliseconds() >= deadline {
+        waiter.expire(sequence);
+        return;
+    }
+
+    timeouts.push(Reverse((
+        deadline,
+        waiter as *const Waiter as usize,
+        sequence,
+    )));
+}
+
+/// Removes the timeouts of __waiter__ that have not expired yet
+pub fn cancel_waiter_timeout(waiter: &Waiter) {
+    let addr = waiter as *const Waiter as usize;
+    WAITER_TIMEOUTS
+        .lock()
+        .retain(|&Reverse((_, entry, _))| entry != addr);
+}
+
+/// Blocks the current thread until the system clock reaches __deadline__ milliseconds,
+/// returns right away if it already has
+pub fn sleep_until(deadline: u64) {
+    // nothing else knows about the waiter so only the timeout wakes it
+    Waiter::new().block_until(Some(deadline));
 }
 
-// TODO: consider returning a reference
+/// Returns the time since boot on the monotonic clock
 pub fn elapsed() -> Time {
-    let clock = SYSTEM_CLOCK.lock();
-    *clock
+    Time::from_nanoseconds(monotonic_ns())
 }
 
 pub fn global_time() -> Time {
@@ -0,0 +1,160 @@
+//! Parser of the VT100/ANSI escape sequences in the output of programs, the terminals that
+//! draw the output themselves use it
+
+/// Maximum number of parameters of a control sequence, the rest are ignored
+const MAX_PARAMS: usize = 16;
+
+const ESC: u8 = 0x1B;
+const DEL: u8 = 0x7F;
+
+/// A control sequence, ESC [ followed by parameters separated by semicolons and a final byte
+#[derive(Debug, Clone, Copy)]
+pub struct ControlSequence {
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+    /// The parameters started with a private marker, e.g. ESC [ ? 25 h
+    pub private: bool,
+    /// The final byte that selects the function
+    pub command: u8,
+}
+
+impl ControlSequence {
+    const fn new() -> ControlSequence {
+        ControlSequence {
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+            private: false,
+            command: 0,
+        }
+    }
+
+    /// Returns the parameter at __idx__, __default__ if it is missing or 0
+    pub fn param(&self, idx: usize, default: u16) -> u16 {
+        match self.params().get(idx) {
+            Some(&param) if param != 0 => param,
+            _ => default,
+        }
+    }
+
+    /// Returns the parameters, missing ones are 0
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.param_count]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AnsiAction {
+    /// A character that has to be drawn
+    Print(u8),
+    /// A C0 control character, e.g. a newline
+    Control(u8),
+    /// An escape sequence that is not a control sequence, e.g. ESC 7, the final byte is given
+    Escape(u8),
+    ControlSequence(ControlSequence),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ParserState {
+    Ground,
+    Escape,
+    /// The intermediate bytes of an escape sequence, e.g. the ( of ESC ( B
+    EscapeIntermediate,
+    ControlSequence,
+}
+
+/// Splits a stream of bytes into characters and escape sequences, the sequences can be split
+/// across writes
+#[derive(Debug)]
+pub struct AnsiParser {
+    state: ParserState,
+    sequence: ControlSequence,
+}
+
+impl AnsiParser {
+    pub const fn new() -> AnsiParser {
+        AnsiParser {
+            state: ParserState::Ground,
+            sequence: ControlSequence::new(),
+        }
+    }
+
+    /// Feeds the next byte to the parser, returns an action once a character or a whole
+    /// sequence has been read. Malformed sequences are dropped.
+    pub fn advance(&mut self, byte: u8) -> Option<AnsiAction> {
+        // an escape always starts a new sequence, even in the middle of another one
+        if byte == ESC {
+            self.state = ParserState::Escape;
+            return None;
+        }
+
+        match self.state {
+            ParserState::Ground => match byte {
+                DEL => None,
+                0..=0x1F => Some(AnsiAction::Control(byte)),
+                _ => Some(AnsiAction::Print(byte)),
+            },
+            ParserState::Escape | ParserState::EscapeIntermediate => match byte {
+                b'[' if matches!(self.state, ParserState::Escape) => {
+                    self.sequence = ControlSequence::new();
+                    self.state = ParserState::ControlSequence;
+                    None
+                }
+                // control characters are executed in the middle of sequences
+                0..=0x1F => Some(AnsiAction::Control(byte)),
+                0x20..=0x2F => {
+                    self.state = ParserState::EscapeIntermediate;
+                    None
+                }
+                _ => {
+                    let escape = matches!(self.state, ParserState::Escape);
+                    self.state = ParserState::Ground;
+                    // sequences with intermediate bytes select character sets, they are ignored
+                    match escape && byte != DEL {
+                        true => Some(AnsiAction::Escape(byte)),
+                        false => None,
+                    }
+                }
+            },
+            ParserState::ControlSequence => self.advance_control_sequence(byte),
+        }
+    }
+
+    fn advance_control_sequence(&mut self, byte: u8) -> Option<AnsiAction> {
+        let seq = &mut self.sequence;
+        match byte {
+            b'0'..=b'9' => {
+                if seq.param_count == 0 {
+                    seq.param_count = 1;
+                }
+                let param = &mut seq.params[seq.param_count - 1];
+                *param = param
+                    .saturating_mul(10)
+                    .saturating_add((byte - b'0') as u16);
+                None
+            }
+            b';' => {
+                if seq.param_count == 0 {
+                    seq.param_count = 1;
+                }
+                seq.param_count = usize::min(seq.param_count + 1, MAX_PARAMS);
+                None
+            }
+            b'<'..=b'?' => {
+                seq.private = true;
+                None
+            }
+            0..=0x1F => Some(AnsiAction::Control(byte)),
+            // intermediate bytes are not used by any supported sequence
+            0x20..=0x2F => None,
+            0x40..=0x7E => {
+                seq.command = byte;
+                self.state = ParserState::Ground;
+                Some(AnsiAction::ControlSequence(*seq))
+            }
+            _ => {
+                self.state = ParserState::Ground;
+                None
+            }
+        }
+    }
+}
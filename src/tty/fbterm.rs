@@ -0,0 +1,410 @@
+use spin::Mutex;
+
+use crate::{
+    framebuffer::{self, Color, DEFAULT_BACKGROUND, DEFAULT_FOREGROUND},
+    fs::errors::FsIoctlError,
+    posix::termios::{KDGETMODE, KDSETMODE, KD_GRAPHICS, KD_TEXT},
+    scheduler::proc,
+};
+
+use super::{
+    ansi::{AnsiAction, AnsiParser, ControlSequence},
+    TtyBackend,
+};
+
+/// The 16 colors of the SGR parameters, the first 8 are the normal colors and the rest are
+/// their bright variants
+const PALETTE: [Color; 16] = [
+    Color::new(0x00, 0x00, 0x00),
+    Color::new(0xaa, 0x00, 0x00),
+    Color::new(0x00, 0xaa, 0x00),
+    Color::new(0xaa, 0x55, 0x00),
+    Color::new(0x00, 0x00, 0xaa),
+    Color::new(0xaa, 0x00, 0xaa),
+    Color::new(0x00, 0xaa, 0xaa),
+    Color::new(0xaa, 0xaa, 0xaa),
+    Color::new(0x55, 0x55, 0x55),
+    Color::new(0xff, 0x55, 0x55),
+    Color::new(0x55, 0xff, 0x55),
+    Color::new(0xff, 0xff, 0x55),
+    Color::new(0x55, 0x55, 0xff),
+    Color::new(0xff, 0x55, 0xff),
+    Color::new(0x55, 0xff, 0xff),
+    Color::new(0xff, 0xff, 0xff),
+];
+
+const TAB_WIDTH: usize = 8;
+
+/// Graphic rendition set by SGR sequences
+#[derive(Clone, Copy)]
+struct Attributes {
+    fg: Color,
+    bg: Color,
+    /// Index of the foreground in the palette, bold makes the normal colors bright
+    fg_index: Option<usize>,
+    bold: bool,
+    reverse: bool,
+}
+
+struct Terminal {
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    /// A char was written to the last column, the next one starts a new line. The cursor
+    /// stays on the last column so the line is not scrolled too early.
+    wrap_pending: bool,
+    /// Cursor position saved by ESC 7 or CSI s
+    saved_cursor: (usize, usize),
+    attributes: Attributes,
+    parser: AnsiParser,
+}
+
+/// TTY backend that draws the text on the framebuffer
+pub struct FramebufferTerminal {
+    terminal: Mutex<Terminal>,
+}
+
+impl Attributes {
+    const fn new() -> Attributes {
+        Attributes {
+            fg: DEFAULT_FOREGROUND,
+            bg: DEFAULT_BACKGROUND,
+            fg_index: None,
+            bold: false,
+            reverse: false,
+        }
+    }
+
+    /// Returns the colors the text is drawn with
+    fn colors(&self) -> (Color, Color) {
+        let fg = match self.fg_index {
+            Some(idx) if self.bold && idx < 8 => PALETTE[idx + 8],
+            _ => self.fg,
+        };
+
+        match self.reverse {
+            true => (self.bg, fg),
+            false => (fg, self.bg),
+        }
+    }
+
+    fn set_foreground(&mut self, idx: usize) {
+        self.fg = PALETTE[idx];
+        self.fg_index = Some(idx);
+    }
+
+    /// Applies the parameters of an SGR sequence
+    fn apply_sgr(&mut self, params: &[u16]) {
+        // no parameters is the same as a reset
+        if params.is_empty() {
+            *self = Attributes::new();
+            return;
+        }
+
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                0 => *self = Attributes::new(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                param @ 30..=37 => self.set_foreground((param - 30) as usize),
+                param @ 90..=97 => self.set_foreground((param - 90) as usize + 8),
+                39 => {
+                    self.fg = DEFAULT_FOREGROUND;
+                    self.fg_index = None;
+                }
+                param @ 40..=47 => self.bg = PALETTE[(param - 40) as usize],
+                param @ 100..=107 => self.bg = PALETTE[(param - 100) as usize + 8],
+                49 => self.bg = DEFAULT_BACKGROUND,
+                param @ (38 | 48) => {
+                    let (color, used) = extended_color(&params[i + 1..]);
+                    i += used;
+                    if let Some(color) = color {
+                        if param == 38 {
+                            self.fg = color;
+                            self.fg_index = None;
+                        } else {
+                            self.bg = color;
+                        }
+                    }
+                }
+                // other attributes, e.g. underline, can not be drawn
+                _ => (),
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Parses the color of an extended SGR color parameter, either 5;n for a color of the 256
+/// color palette or 2;r;g;b. Returns the color and the number of parameters it took.
+fn extended_color(params: &[u16]) -> (Option<Color>, usize) {
+    match params {
+        [5, idx, ..] => (Some(palette_256(*idx as u8)), 2),
+        [2, r, g, b, ..] => (Some(Color::new(*r as u8, *g as u8, *b as u8)), 4),
+        _ => (None, params.len()),
+    }
+}
+
+/// Returns a color of the xterm 256 color palette, the first 16 are the normal palette,
+/// then a 6x6x6 color cube and a grayscale ramp follow
+fn palette_256(idx: u8) -> Color {
+    match idx {
+        0..=15 => PALETTE[idx as usize],
+        16..=231 => {
+            let idx = idx - 16;
+            let level = |n: u8| if n == 0 { 0 } else { 55 + n * 40 };
+            Color::new(level(idx / 36), level(idx / 6 % 6), level(idx % 6))
+        }
+        _ => {
+            let level = 8 + (idx - 232) * 10;
+            Color::new(level, level, level)
+        }
+    }
+}
+
+impl Terminal {
+    /// Creates a new Terminal instance that fills the screen
+    fn new() -> Self {
+        let (width, height) = framebuffer::text_size();
+        Terminal {
+            x: 0,
+            y: 0,
+            width: usize::max(width, 1),
+            height: usize::max(height, 1),
+            wrap_pending: false,
+            saved_cursor: (0, 0),
+            attributes: Attributes::new(),
+            parser: AnsiParser::new(),
+        }
+    }
+
+    /// Passes a byte of the output through the escape sequence parser
+    fn write_byte(&mut self, byte: u8) {
+        match self.parser.advance(byte) {
+            Some(AnsiAction::Print(ch)) => self.write_char(ch),
+            Some(AnsiAction::Control(ch)) => self.control(ch),
+            Some(AnsiAction::Escape(ch)) => self.escape(ch),
+            Some(AnsiAction::ControlSequence(seq)) => self.control_sequence(&seq),
+            None => (),
+        }
+    }
+
+    /// Writes a char to the screen, the line wraps once the end of it is reached
+    fn write_char(&mut self, ch: u8) {
+        if self.wrap_pending {
+            self.x = 0;
+            self.line_feed();
+        }
+
+        let (fg, bg) = self.attributes.colors();
+        framebuffer::draw_character(ch as char, self.x, self.y, fg, bg);
+
+        if self.x + 1 < self.width {
+            self.x += 1;
+        } else {
+            self.wrap_pending = true;
+        }
+    }
+
+    /// Moves the cursor down by a line, the screen is scrolled at the bottom
+    fn line_feed(&mut self) {
+        self.wrap_pending = false;
+        if self.y + 1 < self.height {
+            self.y += 1;
+        } else {
+            framebuffer::scroll_text(1, self.attributes.bg);
+        }
+    }
+
+    fn control(&mut self, ch: u8) {
+        match ch {
+            // the TTY does not translate newlines so a line feed also returns the carriage
+            b'\n' | 0x0B | 0x0C => {
+                self.x = 0;
+                self.line_feed();
+            }
+            b'\r' => self.move_to(0, self.y),
+            0x08 => self.move_to(self.x.saturating_sub(1), self.y),
+            b'\t' => {
+                let next = (self.x / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.move_to(usize::min(next, self.width - 1), self.y);
+            }
+            // the bell and the rest are ignored
+            _ => (),
+        }
+    }
+
+    fn escape(&mut self, ch: u8) {
+        match ch {
+            b'7' => self.saved_cursor = (self.x, self.y),
+            b'8' => self.move_to(self.saved_cursor.0, self.saved_cursor.1),
+            // index, moves down and scrolls at the bottom
+            b'D' => self.line_feed(),
+            // next line
+            b'E' => {
+                self.x = 0;
+                self.line_feed();
+            }
+            // full reset
+            b'c' => {
+                self.attributes = Attributes::new();
+                self.erase_display(2);
+                self.move_to(0, 0);
+            }
+            _ => (),
+        }
+    }
+
+    fn control_sequence(&mut self, seq: &ControlSequence) {
+        // e.g. showing and hiding the cursor, there is no cursor drawn
+        if seq.private {
+            return;
+        }
+
+        let n = seq.param(0, 1) as usize;
+        match seq.command {
+            b'A' => self.move_to(self.x, self.y.saturating_sub(n)),
+            b'B' => self.move_to(self.x, self.y + n),
+            b'C' => self.move_to(self.x + n, self.y),
+            b'D' => self.move_to(self.x.saturating_sub(n), self.y),
+            b'E' => self.move_to(0, self.y + n),
+            b'F' => self.move_to(0, self.y.saturating_sub(n)),
+            b'G' => self.move_to(n - 1, self.y),
+            b'd' => self.move_to(self.x, n - 1),
+            b'H' | b'f' => {
+                let col = seq.param(1, 1) as usize;
+                self.move_to(col - 1, n - 1);
+            }
+            b'J' => self.erase_display(seq.param(0, 0)),
+            b'K' => self.erase_line(seq.param(0, 0)),
+            b'm' => self.attributes.apply_sgr(seq.params()),
+            b's' => self.saved_cursor = (self.x, self.y),
+            b'u' => self.move_to(self.saved_cursor.0, self.saved_cursor.1),
+            _ => (),
+        }
+    }
+
+    /// Moves the cursor, the position is clamped to the screen
+    fn move_to(&mut self, x: usize, y: usize) {
+        self.x = usize::min(x, self.width - 1);
+        self.y = usize::min(y, self.height - 1);
+        self.wrap_pending = false;
+    }
+
+    /// Erases from the cursor to the end of the screen if __mode__ is 0, from the start of
+    /// the screen to the cursor if it is 1, the whole screen if it is 2
+    fn erase_display(&mut self, mode: u16) {
+        let bg = self.attributes.bg;
+        let rows = match mode {
+            0 => self.y + 1..self.height,
+            1 => 0..self.y,
+            2 | 3 => 0..self.height,
+            _ => return,
+        };
+        for row in rows {
+            framebuffer::clear_characters(0, row, self.width, bg);
+        }
+
+        if mode < 2 {
+            self.erase_line(mode);
+        }
+    }
+
+    /// Erases from the cursor to the end of the line if __mode__ is 0, from the start of the
+    /// line to the cursor if it is 1, the whole line if it is 2
+    fn erase_line(&mut self, mode: u16) {
+        let (start, end) = match mode {
+            0 => (self.x, self.width),
+            1 => (0, self.x + 1),
+            2 => (0, self.width),
+            _ => return,
+        };
+
+        framebuffer::clear_characters(start, self.y, end - start, self.attributes.bg);
+    }
+
+    /// Remove the char at the cursor and moves the cursor back by 1
+    fn backspace(&mut self) {
+        if self.wrap_pending {
+            self.wrap_pending = false;
+        } else if self.x == 0 && self.y > 0 {
+            self.y -= 1;
+            self.x = self.width - 1;
+        } else if self.x > 0 {
+            self.x -= 1;
+        }
+
+        framebuffer::clear_characters(self.x, self.y, 1, self.attributes.bg);
+    }
+}
+
+impl FramebufferTerminal {
+    pub fn new() -> FramebufferTerminal {
+        FramebufferTerminal {
+            terminal: Mutex::new(Terminal::new()),
+        }
+    }
+}
+
+impl TtyBackend for FramebufferTerminal {
+    fn write(&self, buff: &[u8]) {
+        let mut terminal = self.terminal.lock();
+        for &ch in buff {
+            terminal.write_byte(ch);
+        }
+    }
+
+    fn erase_char(&self) {
+        let mut terminal = self.terminal.lock();
+        terminal.backspace();
+    }
+
+    fn resize(&self, cols: usize, rows: usize) {
+        // the terminal can not be larger than the screen
+        let (max_cols, max_rows) = framebuffer::text_size();
+        let mut terminal = self.terminal.lock();
+        terminal.width = cols.clamp(1, usize::max(max_cols, 1));
+        terminal.height = rows.clamp(1, usize::max(max_rows, 1));
+        let (x, y) = (terminal.x, terminal.y);
+        terminal.move_to(x, y);
+    }
+
+    fn size(&self) -> Option<(usize, usize)> {
+        let terminal = self.terminal.lock();
+        Some((terminal.width, terminal.height))
+    }
+
+    fn ioctl(&self, req: usize, arg: usize) -> Option<Result<usize, FsIoctlError>> {
+        let res = match req {
+            KDSETMODE => set_mode(arg),
+            KDGETMODE => {
+                let mode = match framebuffer::owner() {
+                    Some(_) => KD_GRAPHICS,
+                    None => KD_TEXT,
+                };
+                Ok(mode)
+            }
+            _ => return None,
+        };
+
+        Some(res)
+    }
+}
+
+/// Gives the framebuffer to the calling process or gives it back to the console
+fn set_mode(mode: usize) -> Result<usize, FsIoctlError> {
+    let pid = proc::current_pid().ok_or(FsIoctlError::PermissionDenied)?;
+    match mode {
+        KD_GRAPHICS => framebuffer::acquire_ownership(pid).map_err(|_| FsIoctlError::DeviceBusy)?,
+        KD_TEXT => {
+            framebuffer::release_ownership(pid).map_err(|_| FsIoctlError::PermissionDenied)?
+        }
+        _ => return Err(FsIoctlError::InvalidArgument),
+    }
+
+    Ok(0)
+}
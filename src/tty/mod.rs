@@ -0,0 +1,505 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    arch::x86_64::usercopy::{In, InOut, Out, UserPtr},
+    fs::{
+        devfs::DevFsDevice,
+        errors::{FsIoctlError, FsReadError, FsStatError, FsWriteError},
+        poll::PollQueue,
+    },
+    posix::{
+        termios::{
+            Termios, Winsize, ECHO, ECHOE, ECHOK, ECHONL, ICANON, ISIG, NCCS, NOFLSH, TCGETS,
+            TCSETS, TIOCGPGRP, TIOCGWINSZ, TIOCSPGRP, TIOCSWINSZ, VEOF, VERASE, VINTR, VKILL, VMIN,
+            VQUIT, VSUSP, VTIME,
+        },
+        PollEvents, Stat, SIGINT, SIGQUIT, SIGTSTP, S_IFCHR,
+    },
+    scheduler::{proc, wait_queue::Waiter},
+    sync::InterruptMutex,
+    time,
+    utils::ring_buffer::{ByteRingBuffer, RingBuffer},
+};
+
+pub mod ansi;
+pub mod fbterm;
+pub mod serial;
+
+/// Output side of a TTY, everything written to or echoed by the TTY is passed to each backend
+pub trait TtyBackend: Send + Sync {
+    /// Outputs the bytes
+    fn write(&self, buff: &[u8]);
+
+    /// Removes the last character that was output
+    fn erase_char(&self);
+
+    /// Called when the window size of the TTY is changed
+    fn resize(&self, _cols: usize, _rows: usize) {}
+
+    /// Returns the number of columns and rows the backend can show, None if it does not
+    /// know, e.g. a serial line
+    fn size(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Handles an ioctl request that the TTY core does not know about,
+    /// returns None if the backend does not know about it either
+    fn ioctl(&self, _req: usize, _arg: usize) -> Option<Result<usize, FsIoctlError>> {
+        None
+    }
+}
+
+/// Number of bytes of input the TTY holds until they are read
+const STDIN_BUFFER_SIZE: usize = 4096;
+
+/// Maximum length of the line being edited in canonical mode
+const MAX_CANON: usize = 255;
+
+/// Ends a line completed with VEOF in the input, NUL can not be typed so it is never data
+const EOF_MARKER: u8 = 0;
+
+/// A control character set to this is disabled
+const POSIX_VDISABLE: u8 = 0;
+
+/// Length of a unit of VTIME in milliseconds
+const VTIME_UNIT_MS: u64 = 100;
+
+/// Signals generated by the TTYs and the process groups they are for. The processes can not
+/// be locked in the interrupt handlers that generate them so the signal thread sends them.
+static PENDING_SIGNALS: InterruptMutex<RingBuffer<(usize, i32), 16>> =
+    InterruptMutex::new(RingBuffer::new((0, 0)));
+static SIGNAL_THREAD_WAITER: Waiter = Waiter::new();
+
+struct StdinBuffer {
+    /// The line being edited in canonical mode
+    current_line: ByteRingBuffer<MAX_CANON>,
+    /// Input that can be read, in canonical mode only completed lines are added
+    buffer: ByteRingBuffer<STDIN_BUFFER_SIZE>,
+}
+
+struct TtyState {
+    termios: Termios,
+    controlling_process_group: usize,
+    columns: usize,
+    rows: usize,
+}
+
+/// TTY core, handles the line discipline, termios and process group state
+/// and passes the output to its backends
+pub struct Tty {
+    // the input is processed in interrupt handlers so the state is locked with interrupts off
+    state: InterruptMutex<TtyState>,
+    stdin_buffer: InterruptMutex<StdinBuffer>,
+    // readers and pollers waiting for input
+    poll_queue: PollQueue,
+    backends: Vec<Arc<dyn TtyBackend>>,
+}
+
+impl StdinBuffer {
+    /// Creates a new StdinBuffer instance
+    fn new() -> Self {
+        StdinBuffer {
+            current_line: ByteRingBuffer::new(0),
+            buffer: ByteRingBuffer::new(0),
+        }
+    }
+
+    /// Appends the current line and __end__ to the buffer, clears the current line.
+    /// If the buffer is full the line is lost
+    fn complete_line(&mut self, end: u8) {
+        if self.buffer.free() > self.current_line.len() {
+            for &ch in self.current_line.iter() {
+                let _ = self.buffer.push(ch);
+            }
+            let _ = self.buffer.push(end);
+        }
+
+        self.current_line.clear();
+    }
+
+    /// Returns whether a completed line can be read in canonical mode
+    fn has_line(&self) -> bool {
+        self.buffer
+            .iter()
+            .any(|&ch| ch == b'\n' || ch == EOF_MARKER)
+    }
+
+    /// Moves the first line to __buff__, the newline is kept but the EOF marker is not.
+    /// If the line does not fit the rest of it is left for the next read.
+    fn read_line(&mut self, buff: &mut [u8]) -> usize {
+        let mut len = 0;
+        while len < buff.len() {
+            match self.buffer.pop() {
+                Some(EOF_MARKER) | None => break,
+                Some(ch) => {
+                    buff[len] = ch;
+                    len += 1;
+                    if ch == b'\n' {
+                        break;
+                    }
+                }
+            }
+        }
+
+        len
+    }
+
+    /// Makes the input readable in non-canonical mode, the line being edited is added to
+    /// the buffer and the EOF markers are dropped
+    fn leave_canonical_mode(&mut self) {
+        for _ in 0..self.buffer.len() {
+            let ch = self.buffer.pop().unwrap();
+            if ch != EOF_MARKER {
+                let _ = self.buffer.push(ch);
+            }
+        }
+
+        for &ch in self.current_line.iter() {
+            let _ = self.buffer.push(ch);
+        }
+        self.current_line.clear();
+    }
+
+    /// Discards all input
+    fn flush(&mut self) {
+        self.current_line.clear();
+        self.buffer.clear();
+    }
+}
+
+impl TtyState {
+    fn new() -> Self {
+        let mut c_cc = [POSIX_VDISABLE; NCCS];
+        c_cc[VINTR] = 0x03;
+        c_cc[VQUIT] = 0x1C;
+        c_cc[VERASE] = 0x7F;
+        c_cc[VKILL] = 0x15;
+        c_cc[VEOF] = 0x04;
+        c_cc[VTIME] = 0;
+        c_cc[VMIN] = 1;
+        c_cc[VSUSP] = 0x1A;
+
+        TtyState {
+            termios: Termios {
+                c_iflag: 0,
+                c_oflag: 0,
+                c_cflag: 0,
+                c_lflag: (ISIG | ICANON | ECHO | ECHOE | ECHOK) as u32,
+                c_cc,
+            },
+            controlling_process_group: 1,
+            columns: 80,
+            rows: 25,
+        }
+    }
+}
+
+impl Tty {
+    /// Creates a new TTY that outputs to __backends__
+    pub fn new(backends: Vec<Arc<dyn TtyBackend>>) -> Tty {
+        let mut state = TtyState::new();
+        if let Some((columns, rows)) = backends.iter().find_map(|backend| backend.size()) {
+            state.columns = columns;
+            state.rows = rows;
+        }
+
+        Tty {
+            state: InterruptMutex::new(state),
+            stdin_buffer: InterruptMutex::new(StdinBuffer::new()),
+            poll_queue: PollQueue::new(),
+            backends,
+        }
+    }
+
+    /// Passes a char typed on any input device through the line discipline, it can be
+    /// called from an interrupt handler
+    pub fn input_char(&self, ch: u8) {
+        let (termios, pgrp) = {
+            let state = self.state.lock();
+            (state.termios, state.controlling_process_group)
+        };
+        let lflag = termios.c_lflag as usize;
+        let is_control_char =
+            |idx: usize| termios.c_cc[idx] != POSIX_VDISABLE && ch == termios.c_cc[idx];
+        let echo = lflag & ECHO != 0;
+
+        if lflag & ISIG != 0 {
+            let signal = if is_control_char(VINTR) {
+                Some(SIGINT)
+            } else if is_control_char(VQUIT) {
+                Some(SIGQUIT)
+            } else if is_control_char(VSUSP) {
+                Some(SIGTSTP)
+            } else {
+                None
+            };
+
+            if let Some(signal) = signal {
+                if lflag & NOFLSH == 0 {
+                    self.stdin_buffer.lock().flush();
+                }
+                if echo {
+                    match ch {
+                        0..=0x1F => self.output(&[b'^', ch + b'@']),
+                        _ => self.output(&[ch]),
+                    }
+                }
+
+                raise_signal(pgrp, signal);
+                return;
+            }
+        }
+
+        let mut input = self.stdin_buffer.lock();
+        if lflag & ICANON == 0 {
+            if input.buffer.push(ch).is_ok() && echo {
+                self.output(&[ch]);
+            }
+
+            drop(input);
+            self.poll_queue.wake_all();
+            return;
+        }
+
+        if is_control_char(VERASE) {
+            if input.current_line.pop_newest().is_some() && echo {
+                match lflag & ECHOE != 0 {
+                    true => self.erase_chars(1),
+                    false => self.output(&[ch]),
+                }
+            }
+        } else if is_control_char(VKILL) {
+            let len = input.current_line.len();
+            input.current_line.clear();
+            if echo && lflag & ECHOK != 0 {
+                self.erase_chars(len);
+            }
+        } else if is_control_char(VEOF) {
+            input.complete_line(EOF_MARKER);
+            drop(input);
+            self.poll_queue.wake_all();
+        } else if ch == b'\n' {
+            input.complete_line(ch);
+            drop(input);
+            if echo || lflag & ECHONL != 0 {
+                self.output(&[ch]);
+            }
+            self.poll_queue.wake_all();
+        } else if input.current_line.push(ch).is_ok() && echo {
+            self.output(&[ch]);
+        }
+    }
+
+    /// Changes the termios of the TTY, the input that was typed so far is kept
+    fn set_termios(&self, termios: Termios) {
+        {
+            let mut state = self.state.lock();
+            let was_canonical = state.termios.c_lflag as usize & ICANON != 0;
+            state.termios = termios;
+
+            if was_canonical && termios.c_lflag as usize & ICANON == 0 {
+                self.stdin_buffer.lock().leave_canonical_mode();
+            }
+        }
+
+        // the mode, VMIN or VTIME might have changed so the readers check again
+        self.poll_queue.wake_all();
+    }
+
+    fn erase_chars(&self, count: usize) {
+        for _ in 0..count {
+            for backend in self.backends.iter() {
+                backend.erase_char();
+            }
+        }
+    }
+
+    fn output(&self, buff: &[u8]) {
+        for backend in self.backends.iter() {
+            backend.write(buff);
+        }
+    }
+}
+
+/// Queues __signal__ for the signal thread to send to the process group __pgrp__, it can be
+/// called from an interrupt handler. The signal is lost if too many are queued.
+fn raise_signal(pgrp: usize, signal: i32) {
+    if PENDING_SIGNALS.lock().push((pgrp, signal)).is_err() {
+        return;
+    }
+
+    SIGNAL_THREAD_WAITER.wake();
+}
+
+/// Sends the signals the TTYs generate, it runs as a kernel thread
+pub fn signal_thread() {
+    loop {
+        SIGNAL_THREAD_WAITER.block();
+
+        loop {
+            let next = PENDING_SIGNALS.lock().pop();
+            match next {
+                Some((pgrp, signal)) => proc::signal_process_group(pgrp, signal),
+                None => break,
+            }
+        }
+    }
+}
+
+impl DevFsDevice for Tty {
+    /// In canonical mode a read returns at most one line once it is completed. Otherwise it
+    /// waits until VMIN bytes are available, if VTIME is set it returns what is available
+    /// VTIME tenths of a second after the last byte arrived, or after the read started if
+    /// VMIN is 0.
+    fn read(&self, _minor: u16, _off: usize, buff: &mut [u8]) -> Result<usize, FsReadError> {
+        let pid = proc::current_pid();
+        let waiter = Arc::new(Waiter::new());
+        let start = time::elapsed().as_milliseconds();
+        let mut last_len = 0;
+        let mut deadline = None;
+
+        loop {
+            // registered before checking so input arriving in between is not missed
+            self.poll_queue.register(&waiter);
+            proc::register_signal_waiter(&waiter);
+
+            let termios = self.state.lock().termios;
+            let (vmin, vtime) = (
+                termios.c_cc[VMIN] as usize,
+                termios.c_cc[VTIME] as u64 * VTIME_UNIT_MS,
+            );
+
+            {
+                let mut input = self.stdin_buffer.lock();
+                if termios.c_lflag as usize & ICANON != 0 {
+                    if input.has_line() {
+                        return Ok(input.read_line(buff));
+                    }
+                } else {
+                    let len = input.buffer.len();
+                    let now = time::elapsed().as_milliseconds();
+                    if vtime > 0 && vmin == 0 {
+                        deadline = Some(start + vtime);
+                    } else if vtime > 0 && len > last_len {
+                        deadline = Some(now + vtime);
+                    }
+                    last_len = len;
+
+                    let timed_out = deadline.is_some_and(|deadline| now >= deadline);
+                    let enough = len >= vmin.clamp(1, buff.len());
+                    if enough || timed_out || (vmin == 0 && vtime == 0) {
+                        return Ok(input.buffer.read(buff));
+                    }
+                }
+            }
+
+            if pid.is_some_and(|pid| proc::pending_signal(pid).is_some()) {
+                return Err(FsReadError::Interrupted);
+            }
+
+            waiter.block_until(deadline);
+        }
+    }
+
+    fn write(&self, _minor: u16, _off: usize, buff: &[u8]) -> Result<usize, FsWriteError> {
+        self.output(buff);
+        Ok(buff.len())
+    }
+
+    fn ioctl(&self, _minor: u16, req: usize, arg: usize) -> Result<usize, FsIoctlError> {
+        // the state is locked with interrupts disabled so userspace is not accessed with it held
+        match req {
+            TCGETS => {
+                let ptr = UserPtr::<Termios, Out>::new(arg as u64);
+                let termios = self.state.lock().termios;
+                ptr.write(&termios).map_err(|_| FsIoctlError::BadAddress)?;
+            }
+            TCSETS => {
+                let ptr = UserPtr::<Termios, In>::new(arg as u64);
+                let termios = ptr.read().map_err(|_| FsIoctlError::BadAddress)?;
+                self.set_termios(termios);
+            }
+            TIOCGPGRP => {
+                let ptr = UserPtr::<u32, Out>::new(arg as u64);
+                let pgrp = self.state.lock().controlling_process_group;
+                ptr.write(&(pgrp as u32))
+                    .map_err(|_| FsIoctlError::BadAddress)?;
+            }
+            TIOCSPGRP => {
+                let ptr = UserPtr::<u32, In>::new(arg as u64);
+                let pgrp = ptr.read().map_err(|_| FsIoctlError::BadAddress)?;
+                self.state.lock().controlling_process_group = pgrp as usize;
+            }
+            TIOCGWINSZ => {
+                let ptr = UserPtr::<Winsize, InOut>::new(arg as u64);
+                let mut winsize = ptr.read().map_err(|_| FsIoctlError::BadAddress)?;
+                {
+                    let state = self.state.lock();
+                    winsize.ws_col = state.columns as u16;
+                    winsize.ws_row = state.rows as u16;
+                }
+                ptr.write(&winsize).map_err(|_| FsIoctlError::BadAddress)?;
+            }
+            TIOCSWINSZ => {
+                let ptr = UserPtr::<Winsize, In>::new(arg as u64);
+                let winsize = ptr.read().map_err(|_| FsIoctlError::BadAddress)?;
+                let (columns, rows) = (winsize.ws_col as usize, winsize.ws_row as usize);
+                {
+                    let mut state = self.state.lock();
+                    state.columns = columns;
+                    state.rows = rows;
+                }
+
+                for backend in self.backends.iter() {
+                    backend.resize(columns, rows);
+                }
+            }
+            _ => {
+                return self
+                    .backends
+                    .iter()
+                    .find_map(|backend| backend.ioctl(req, arg))
+                    .unwrap_or_else(|| {
+                        debug!("TTY: unimplemented ioctl req {:#x}", req);
+                        Err(FsIoctlError::InvalidArgument)
+                    });
+            }
+        }
+
+        Ok(0)
+    }
+
+    // in canonical mode the input can be read once a line is completed, the output never
+    // blocks
+    fn poll(&self, _minor: u16, _off: usize, waiter: Option<&Arc<Waiter>>) -> PollEvents {
+        if let Some(waiter) = waiter {
+            self.poll_queue.register(waiter);
+        }
+
+        let canonical = self.state.lock().termios.c_lflag as usize & ICANON != 0;
+        let input = self.stdin_buffer.lock();
+        let readable = match canonical {
+            true => input.has_line(),
+            false => !input.buffer.is_empty(),
+        };
+
+        match readable {
+            true => PollEvents::POLLIN | PollEvents::POLLOUT,
+            false => PollEvents::POLLOUT,
+        }
+    }
+
+    fn stat(&self, _minor: u16, stat_buf: &mut Stat) -> Result<(), FsStatError> {
+        // TODO
+        stat_buf.st_blksize = 4096;
+        stat_buf.st_blocks = 0;
+        stat_buf.st_size = 0;
+        stat_buf.st_dev = 0;
+        stat_buf.st_gid = 0;
+        stat_buf.st_uid = 0;
+        stat_buf.st_nlink = 1;
+        stat_buf.st_mode = S_IFCHR | 0o666;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+use crate::{drivers::serial, logger};
+
+use super::TtyBackend;
+
+/// TTY backend that outputs to COM1 so the console can be used without a screen
+pub struct SerialTerminal;
+
+impl SerialTerminal {
+    /// Writes the bytes unless serial output was turned off
+    fn output(&self, buff: &[u8]) {
+        if !logger::serial_output_enabled() {
+            return;
+        }
+
+        for &ch in buff {
+            serial::write(ch);
+        }
+    }
+}
+
+impl TtyBackend for SerialTerminal {
+    fn write(&self, buff: &[u8]) {
+        self.output(buff);
+    }
+
+    fn erase_char(&self) {
+        self.output(b"\x08 \x08");
+    }
+}
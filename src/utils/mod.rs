@@ -1,3 +1,4 @@
+pub mod ring_buffer;
 pub mod slot_allocator;
 
 pub fn align(n: usize, align_by: usize) -> usize {
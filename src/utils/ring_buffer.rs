@@ -0,0 +1,384 @@
+/// A watermark of a `RingBuffer`, the callback is called when the number of elements
+/// crosses the level
+#[derive(Debug, Clone, Copy)]
+struct Watermark {
+    level: usize,
+    callback: fn(),
+}
+
+/// A fixed-capacity FIFO queue that never allocates, so it can be used in interrupt handlers.
+/// Elements are written at the tail and read from the head, when the buffer is full writes
+/// fail unless the oldest elements are overwritten explicitly.
+///
+/// The stored elements are not necessarily contiguous, `read_slices` and `write_slices` return
+/// the two parts of the used and the free space so elements can be copied in bulk.
+#[derive(Debug, Clone)]
+pub struct RingBuffer<T: Copy, const N: usize> {
+    data: [T; N],
+    /// Index of the oldest element
+    head: usize,
+    /// Number of elements in the buffer
+    len: usize,
+    /// Called when a write makes the number of elements reach the level
+    high_watermark: Option<Watermark>,
+    /// Called when a read makes the number of elements drop to the level
+    low_watermark: Option<Watermark>,
+}
+
+/// A `RingBuffer` of bytes, e.g. the input of a TTY
+pub type ByteRingBuffer<const N: usize> = RingBuffer<u8, N>;
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /// Creates an empty buffer, the unused slots are filled with __fill__
+    pub const fn new(fill: T) -> RingBuffer<T, N> {
+        assert!(N > 0);
+        RingBuffer {
+            data: [fill; N],
+            head: 0,
+            len: 0,
+            high_watermark: None,
+            low_watermark: None,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Returns the number of elements that can be written before the buffer is full
+    pub const fn free(&self) -> usize {
+        N - self.len
+    }
+
+    /// Index of the slot after the newest element
+    fn tail(&self) -> usize {
+        (self.head + self.len) % N
+    }
+
+    /// Calls __callback__ when a write makes the buffer hold at least __level__ elements,
+    /// e.g. to wake up readers. The callback runs with the buffer borrowed by the writer so it
+    /// must not access the buffer itself.
+    pub fn set_high_watermark(&mut self, level: usize, callback: fn()) {
+        assert!(level <= N);
+        self.high_watermark = Some(Watermark { level, callback });
+    }
+
+    /// Calls __callback__ when a read makes the buffer hold at most __level__ elements,
+    /// e.g. to wake up writers. The callback runs with the buffer borrowed by the reader so it
+    /// must not access the buffer itself.
+    pub fn set_low_watermark(&mut self, level: usize, callback: fn()) {
+        assert!(level <= N);
+        self.low_watermark = Some(Watermark { level, callback });
+    }
+
+    pub fn clear_watermarks(&mut self) {
+        self.high_watermark = None;
+        self.low_watermark = None;
+    }
+
+    /// Notifies the watermark callbacks if the number of elements crossed their level
+    fn check_watermarks(&self, prev_len: usize) {
+        if let Some(mark) = self.high_watermark {
+            if prev_len < mark.level && self.len >= mark.level {
+                (mark.callback)();
+            }
+        }
+
+        if let Some(mark) = self.low_watermark {
+            if prev_len > mark.level && self.len <= mark.level {
+                (mark.callback)();
+            }
+        }
+    }
+
+    /// Appends an element, returns it back if the buffer is full
+    pub fn push(&mut self, val: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(val);
+        }
+
+        let tail = self.tail();
+        self.data[tail] = val;
+        self.len += 1;
+        self.check_watermarks(self.len - 1);
+        Ok(())
+    }
+
+    /// Appends an element, if the buffer is full the oldest element is removed and returned
+    pub fn push_overwrite(&mut self, val: T) -> Option<T> {
+        match self.push(val) {
+            Ok(()) => None,
+            Err(val) => {
+                // the newest element takes the slot of the oldest one
+                let oldest = self.data[self.head];
+                self.data[self.head] = val;
+                self.head = (self.head + 1) % N;
+                Some(oldest)
+            }
+        }
+    }
+
+    /// Removes the oldest element
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let val = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        self.check_watermarks(self.len + 1);
+        Some(val)
+    }
+
+    /// Returns the oldest element without removing it
+    pub fn peek(&self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.data[self.head])
+        }
+    }
+
+    /// Returns the newest element without removing it
+    pub fn peek_newest(&self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.data[(self.head + self.len - 1) % N])
+        }
+    }
+
+    /// Removes the newest element, e.g. to erase the last typed character
+    pub fn pop_newest(&mut self) -> Option<T> {
+        let val = self.peek_newest()?;
+        self.len -= 1;
+        self.check_watermarks(self.len + 1);
+        Some(val)
+    }
+
+    /// Returns the elements in the order they were written, the second slice is only
+    /// non-empty if the elements wrap around the end of the storage
+    pub fn read_slices(&self) -> (&[T], &[T]) {
+        let first_len = usize::min(self.len, N - self.head);
+        let (wrapped, rest) = self.data.split_at(self.head);
+        (&rest[..first_len], &wrapped[..self.len - first_len])
+    }
+
+    /// Removes the __count__ oldest elements, usually after reading them with `read_slices`
+    pub fn consume(&mut self, count: usize) {
+        assert!(count <= self.len);
+        let prev_len = self.len;
+        self.head = (self.head + count) % N;
+        self.len -= count;
+        self.check_watermarks(prev_len);
+    }
+
+    /// Returns the free slots in the order they are written, the second slice is only
+    /// non-empty if the free space wraps around the end of the storage
+    pub fn write_slices(&mut self) -> (&mut [T], &mut [T]) {
+        let tail = self.tail();
+        let free = self.free();
+        let first_len = usize::min(free, N - tail);
+        let (wrapped, rest) = self.data.split_at_mut(tail);
+        (&mut rest[..first_len], &mut wrapped[..free - first_len])
+    }
+
+    /// Adds the __count__ elements written with `write_slices` to the buffer
+    pub fn commit(&mut self, count: usize) {
+        assert!(count <= self.free());
+        let prev_len = self.len;
+        self.len += count;
+        self.check_watermarks(prev_len);
+    }
+
+    /// Copies as many elements from __src__ as fit, returns the number of copied elements
+    pub fn write(&mut self, src: &[T]) -> usize {
+        let (first, second) = self.write_slices();
+        let first_count = usize::min(first.len(), src.len());
+        first[..first_count].copy_from_slice(&src[..first_count]);
+
+        let second_count = usize::min(second.len(), src.len() - first_count);
+        second[..second_count].copy_from_slice(&src[first_count..first_count + second_count]);
+
+        let count = first_count + second_count;
+        self.commit(count);
+        count
+    }
+
+    /// Moves the oldest elements to __dst__, returns the number of moved elements
+    pub fn read(&mut self, dst: &mut [T]) -> usize {
+        let (first, second) = self.read_slices();
+        let first_count = usize::min(first.len(), dst.len());
+        dst[..first_count].copy_from_slice(&first[..first_count]);
+
+        let second_count = usize::min(second.len(), dst.len() - first_count);
+        dst[first_count..first_count + second_count].copy_from_slice(&second[..second_count]);
+
+        let count = first_count + second_count;
+        self.consume(count);
+        count
+    }
+
+    /// Removes every element, the watermark callbacks are not called
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Returns an iterator over the elements from the oldest to the newest
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let (first, second) = self.read_slices();
+        first.iter().chain(second.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::RingBuffer;
+
+    /// Moves the head to __offset__ so the next writes wrap around the end of the storage
+    fn rotated<const N: usize>(offset: usize) -> RingBuffer<u8, N> {
+        let mut buff = RingBuffer::new(0);
+        for _ in 0..offset {
+            buff.push(0).unwrap();
+            buff.pop().unwrap();
+        }
+        buff
+    }
+
+    #[test]
+    fn empty_and_full() {
+        let mut buff = RingBuffer::<u8, 3>::new(0);
+        assert!(buff.is_empty());
+        assert_eq!(buff.pop(), None);
+        assert_eq!(buff.peek(), None);
+        assert_eq!(buff.pop_newest(), None);
+
+        for val in 1..=3 {
+            buff.push(val).unwrap();
+        }
+        assert!(buff.is_full());
+        assert_eq!(buff.free(), 0);
+        assert_eq!(buff.push(4), Err(4));
+        assert_eq!(buff.len(), 3);
+    }
+
+    #[test]
+    fn wrap_around() {
+        let mut buff = rotated::<4>(3);
+        for val in 1..=4 {
+            buff.push(val).unwrap();
+        }
+
+        assert_eq!(buff.peek(), Some(1));
+        assert_eq!(buff.peek_newest(), Some(4));
+        assert!(buff.iter().copied().eq(1..=4));
+        for val in 1..=4 {
+            assert_eq!(buff.pop(), Some(val));
+        }
+        assert!(buff.is_empty());
+    }
+
+    #[test]
+    fn push_overwrite() {
+        let mut buff = RingBuffer::<u8, 3>::new(0);
+        for val in 1..=3 {
+            assert_eq!(buff.push_overwrite(val), None);
+        }
+
+        assert_eq!(buff.push_overwrite(4), Some(1));
+        assert_eq!(buff.push_overwrite(5), Some(2));
+        assert_eq!(buff.len(), 3);
+        assert!(buff.iter().copied().eq(3..=5));
+    }
+
+    #[test]
+    fn pop_newest() {
+        let mut buff = rotated::<3>(2);
+        for val in 1..=3 {
+            buff.push(val).unwrap();
+        }
+
+        assert_eq!(buff.pop_newest(), Some(3));
+        assert_eq!(buff.pop_newest(), Some(2));
+        buff.push(4).unwrap();
+        assert!(buff.iter().copied().eq([1, 4]));
+    }
+
+    #[test]
+    fn slices_across_wrap_point() {
+        let mut buff = rotated::<5>(3);
+        {
+            let (first, second) = buff.write_slices();
+            assert_eq!((first.len(), second.len()), (2, 3));
+            first.copy_from_slice(&[1, 2]);
+            second[0] = 3;
+        }
+        buff.commit(3);
+
+        let (first, second) = buff.read_slices();
+        assert_eq!(first, &[1, 2]);
+        assert_eq!(second, &[3]);
+
+        buff.consume(2);
+        assert_eq!(buff.read_slices(), (&[3][..], &[][..]));
+    }
+
+    #[test]
+    fn bulk_read_and_write() {
+        let mut buff = rotated::<4>(2);
+        assert_eq!(buff.write(&[1, 2, 3, 4, 5]), 4);
+
+        let mut dst = [0; 3];
+        assert_eq!(buff.read(&mut dst), 3);
+        assert_eq!(dst, [1, 2, 3]);
+        assert_eq!(buff.write(&[6, 7]), 2);
+        assert!(buff.iter().copied().eq([4, 6, 7]));
+    }
+
+    static HIGH_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static LOW_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn watermarks() {
+        let mut buff = RingBuffer::<u8, 4>::new(0);
+        buff.set_high_watermark(3, || {
+            HIGH_CALLS.fetch_add(1, Ordering::Relaxed);
+        });
+        buff.set_low_watermark(1, || {
+            LOW_CALLS.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // only crossing the level calls the callback
+        buff.write(&[1, 2, 3]);
+        buff.push(4).unwrap();
+        assert_eq!(HIGH_CALLS.load(Ordering::Relaxed), 1);
+
+        buff.consume(2);
+        assert_eq!(LOW_CALLS.load(Ordering::Relaxed), 0);
+        buff.pop_newest();
+        buff.pop();
+        assert_eq!(LOW_CALLS.load(Ordering::Relaxed), 1);
+
+        buff.clear_watermarks();
+        buff.write(&[1, 2, 3]);
+        buff.clear();
+        assert_eq!(HIGH_CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(LOW_CALLS.load(Ordering::Relaxed), 1);
+    }
+}
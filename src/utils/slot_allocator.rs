@@ -138,6 +138,19 @@ impl<T> SlotAllocator<T> {
         }
     }
 
+    /// Returns the index of the first unallocated slot at or after `from`, the index can be
+    /// past the end of the inner `Vec<T>`
+    pub fn first_free_from(&self, from: usize) -> usize {
+        (from..self.inner.len())
+            .find(|&index| self.inner[index].is_none())
+            .unwrap_or(usize::max(from, self.inner.len()))
+    }
+
+    /// Returns an iterator over the values of the allocated slots
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.inner.iter().filter_map(Option::as_ref)
+    }
+
     /// Deallocates all slots
     pub fn clear(&mut self) {
         // TODO: maybe free the memory
@@ -162,6 +175,10 @@ impl<T> SlotAllocator<T> {
             }
         }
 
+        if hint.is_some_and(|hint| self.get(hint).is_some()) {
+            return None;
+        }
+
         Some(self.allocate_slot(val, hint))
     }
 